@@ -0,0 +1,42 @@
+/*
+ SPDX-License-Identifier: MIT
+ Copyright (c) 2022 BitFlux, Inc.
+
+ C ABI of the bitflux installer library (libinstaller.so).
+
+ Every function returns a NUL terminated JSON document:
+   {"ok": true, "result": ...}   or   {"ok": false, "error": "...", "code": N}
+ where code is the installer's exit code for the error, and which must be released with bitflux_free().
+*/
+
+#ifndef BITFLUX_INSTALLER_H
+#define BITFLUX_INSTALLER_H
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/* Installer library version. */
+char *bitflux_version(void);
+/* Drift against the install receipt, same as `installer verify --json`. */
+char *bitflux_status(void);
+/* Compliance report, same as `installer --audit`, changes nothing. */
+char *bitflux_audit(void);
+/* Re-apply whatever drifted from the install receipt, same as `installer repair`.  Fails with
+   code 16 while another installer runs. */
+char *bitflux_repair(void);
+/* What an install with `options` would change, step by step, same as
+   `installer install --check --output json`.  `options` is JSON in the form of the answers
+   file, {"profile": "agent", "license_key": "..."}, or NULL for the defaults. */
+char *bitflux_plan(const char *options);
+/* Install with `options`, what each step did, same as `installer install --output json`.
+   Fails with code 16 while another installer runs. */
+char *bitflux_apply(const char *options);
+/* Release a string returned by any of the above, NULL is ignored. */
+void bitflux_free(char *s);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
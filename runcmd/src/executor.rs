@@ -0,0 +1,217 @@
+use std::cell::RefCell;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+
+use crate::{RunCmd, RunCmdError, RunCmdOutput};
+
+thread_local! {
+    /// What runs the commands built on this thread, SystemExecutor when None.
+    static EXECUTOR: RefCell<Option<Arc<dyn CommandExecutor>>> = const { RefCell::new(None) };
+}
+
+/// Runs the commands RunCmd builds, once per attempt: retries, dry runs and exit code checks
+/// stay with RunCmd.  SystemExecutor starts the processes, MockExecutor only pretends to.
+pub trait CommandExecutor: Send + Sync {
+
+    fn execute(&self, cmd: &mut RunCmd) -> Result<RunCmdOutput, RunCmdError>;
+
+    /// What -vvv calls it.
+    fn name(&self) -> &'static str {
+        "system"
+    }
+
+}
+
+/// Runs the commands on this host, through sudo, doas or pkexec when they need root.
+pub struct SystemExecutor;
+
+impl CommandExecutor for SystemExecutor {
+
+    fn execute(&self, cmd: &mut RunCmd) -> Result<RunCmdOutput, RunCmdError> {
+        let escalation = cmd.escalation()?;
+        cmd.run_once(escalation.as_deref())
+    }
+
+}
+
+/// A command as it was asked to run.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Invocation {
+    /// The command line as it runs, with the values the log masks.
+    pub cmd: String,
+    /// The program and its arguments, None for a command line split or run by a shell.
+    pub argv: Option<Vec<String>>,
+    pub root: bool,
+    pub cwd: Option<PathBuf>,
+    pub env: Vec<(String, String)>,
+    /// What it was given on stdin, secrets included.
+    pub stdin: Option<String>,
+}
+
+/// What a MockExecutor answers a command with.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Reply {
+    pub exitcode: i32,
+    pub stdout: String,
+    pub stderr: String,
+}
+
+impl Reply {
+
+    pub fn ok(stdout: &str) -> Reply {
+        Reply { exitcode: 0, stdout: String::from(stdout), stderr: String::new() }
+    }
+
+    pub fn exit(exitcode: i32, stderr: &str) -> Reply {
+        Reply { exitcode, stdout: String::new(), stderr: String::from(stderr) }
+    }
+
+}
+
+struct Rule {
+    prefix: String,
+    reply: Reply,
+    once: bool,
+}
+
+/// Records every command instead of running it and answers with the replies scripted for
+/// it, so install flows can be tested without root or the packages.
+///
+/// # Examples
+///
+/// ```
+/// use std::sync::Arc;
+/// use bitflux_runcmd::executor::{self, MockExecutor, Reply};
+/// use bitflux_runcmd::RunCmd;
+///
+/// let mock = Arc::new(MockExecutor::new());
+/// mock.on("dpkg-query", Reply::exit(1, "no packages found"));
+/// let _using = executor::using(mock.clone());
+/// assert_eq!(RunCmd::args("dpkg-query", &["-W", "bitfluxcollector"]).execute_output().exitcode, 1);
+/// assert_eq!(mock.commands(), ["dpkg-query -W bitfluxcollector"]);
+/// ```
+#[derive(Default)]
+pub struct MockExecutor {
+    rules: Mutex<Vec<Rule>>,
+    calls: Mutex<Vec<Invocation>>,
+}
+
+impl MockExecutor {
+
+    /// A mock where every command succeeds without output until told otherwise.
+    pub fn new() -> MockExecutor {
+        MockExecutor::default()
+    }
+
+    /// Answers every command whose command line starts with `prefix` with `reply`.  The rule
+    /// added first wins.
+    pub fn on(&self, prefix: &str, reply: Reply) -> &MockExecutor {
+        self.add(prefix, reply, false)
+    }
+
+    /// Like on(), for the next matching command only, the one after gets the next rule.
+    pub fn once(&self, prefix: &str, reply: Reply) -> &MockExecutor {
+        self.add(prefix, reply, true)
+    }
+
+    fn add(&self, prefix: &str, reply: Reply, once: bool) -> &MockExecutor {
+        self.rules.lock().unwrap_or_else(|e| e.into_inner()).push(Rule { prefix: String::from(prefix), reply, once });
+        self
+    }
+
+    /// Every command asked to run so far, in order.
+    pub fn calls(&self) -> Vec<Invocation> {
+        self.calls.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    /// The command lines of calls().
+    pub fn commands(&self) -> Vec<String> {
+        self.calls().into_iter().map(|c| c.cmd).collect()
+    }
+
+}
+
+impl CommandExecutor for MockExecutor {
+
+    fn execute(&self, cmd: &mut RunCmd) -> Result<RunCmdOutput, RunCmdError> {
+        let invocation = cmd.invocation();
+        let reply = {
+            let mut rules = self.rules.lock().unwrap_or_else(|e| e.into_inner());
+            match rules.iter().position(|r| invocation.cmd.starts_with(&r.prefix)) {
+                Some(i) if rules[i].once => rules.remove(i).reply,
+                Some(i) => rules[i].reply.clone(),
+                None => Reply::default(),
+            }
+        };
+        self.calls.lock().unwrap_or_else(|e| e.into_inner()).push(invocation);
+        Ok(cmd.replied(reply.exitcode, &reply.stdout, &reply.stderr))
+    }
+
+    fn name(&self) -> &'static str {
+        "mock"
+    }
+
+}
+
+/// Restores the executor in use before using() when dropped.
+pub struct Using {
+    previous: Option<Arc<dyn CommandExecutor>>,
+}
+
+impl Drop for Using {
+
+    fn drop(&mut self) {
+        let previous = self.previous.take();
+        EXECUTOR.with(|e| *e.borrow_mut() = previous);
+    }
+
+}
+
+/// Runs the commands of this thread, and the batch jobs it starts, with `executor` until the
+/// guard is dropped.
+pub fn using(executor: Arc<dyn CommandExecutor>) -> Using {
+    Using { previous: EXECUTOR.with(|e| e.borrow_mut().replace(executor)) }
+}
+
+/// What runs the commands built on this thread.
+pub fn current() -> Arc<dyn CommandExecutor> {
+    EXECUTOR.with(|e| e.borrow().clone()).unwrap_or_else(|| Arc::new(SystemExecutor))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scripted_replies() {
+        let mock = Arc::new(MockExecutor::new());
+        mock.once("apt-get install", Reply::exit(100, "E: Could not get lock"))
+            .on("apt-get install", Reply::ok("Setting up bitfluxcollector"));
+        let _using = using(mock.clone());
+        let mut install = RunCmd::args("apt-get", &["install", "-y", "bitfluxcollector"]);
+        let e = install.as_root().try_execute().unwrap_err();
+        assert_eq!(e.to_string(), "'apt-get install -y bitfluxcollector' failed with exit code 100: E: Could not get lock");
+        let out = RunCmd::args("apt-get", &["install", "-y", "bitfluxcollector"]).retries(1).try_execute().unwrap();
+        assert_eq!(out.stdout, "Setting up bitfluxcollector");
+        assert_eq!(RunCmd::new("systemctl is-enabled bitfluxcollector").execute_output().exitcode, 0);
+        let calls = mock.calls();
+        assert_eq!(calls.len(), 3);
+        assert!(calls[0].root);
+        assert_eq!(calls[2], Invocation {
+            cmd: String::from("systemctl is-enabled bitfluxcollector"), argv: None, root: false, cwd: None, env: Vec::new(), stdin: None,
+        });
+    }
+
+    #[test]
+    fn scoped_to_the_guard() {
+        let mock = Arc::new(MockExecutor::new());
+        {
+            let _using = using(mock.clone());
+            RunCmd::args("/nonexistent/bitflux", &[]).execute_output();
+        }
+        assert_eq!(mock.commands(), ["/nonexistent/bitflux"]);
+        assert_eq!(RunCmd::args("/nonexistent/bitflux", &[]).execute_output().exitcode, -1);
+    }
+
+}
@@ -0,0 +1,94 @@
+use std::any::Any;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use crate::executor::Invocation;
+use crate::sensitive;
+use crate::RunCmdOutput;
+
+/// What the program using RunCmd plugs in around its commands: where their log goes, what a
+/// dry run records, how sudo gets its password.  Every method has a default that does without.
+pub trait Hooks: Send + Sync {
+
+    /// A dry run of `what`, a change made without a command.  True when it's been taken care
+    /// of, nothing is printed then.
+    fn dry_run(&self, _what: &str) -> bool {
+        false
+    }
+
+    /// A dry run of `cmd`, `may_fail` when it's allowed to.  True when it's been taken care of.
+    fn dry_run_command(&self, _cmd: &Invocation, _may_fail: bool) -> bool {
+        false
+    }
+
+    /// Keeps the terminal for a line printed while the guard lives, with any progress line out
+    /// of the way.
+    fn hold(&self) -> Box<dyn Any> {
+        Box::new(())
+    }
+
+    /// Where tee() shows stdout, or stderr, as it comes.
+    fn echo(&self, stderr: bool) -> Box<dyn Write + Send> {
+        match stderr {
+            true => Box::new(io::stderr()),
+            false => Box::new(io::stdout()),
+        }
+    }
+
+    /// Variables to pass through `tool`, since sudo and pkexec reset the environment.
+    fn escalation_env(&self) -> Vec<(String, String)> {
+        Vec::new()
+    }
+
+    /// About to run a command through `tool` for root, a chance to ask for the password once.
+    fn escalating(&self, _tool: &Path) {}
+
+    /// True when `tool` won't ask for a password, so it isn't kept from asking either.
+    fn credentials_cached(&self) -> bool {
+        false
+    }
+
+    /// A command is about to start, what it returns is handed to finished().
+    fn started(&self) -> Box<dyn Any> {
+        Box::new(())
+    }
+
+    /// `out` finished after `took`, `captured` when its output may be logged: it wasn't shown
+    /// on the terminal and wasn't fed a secret.  `name` is short for profiling, "apt-get
+    /// install" or "curl", `started` what started() returned, None for replies and pipeline
+    /// stages.
+    fn finished(&self, _name: &str, _out: &RunCmdOutput, _took: Duration, _captured: bool, _started: Option<Box<dyn Any>>) {}
+
+    /// What verbose() shows of a finished command, with its output unless it was shown already.
+    fn verbose(&self, out: &RunCmdOutput, took: Duration, output: bool) {
+        eprintln!("cmd: {}\nexitcode: {} after {:.2?}", out.cmd, out.exitcode, took);
+        if output {
+            eprint!("{}", sensitive::mask(&format!("{}{}", out.stdout, out.stderr)));
+        }
+    }
+
+    /// Something went wrong that doesn't stop the command.
+    fn warn(&self, message: &str) {
+        eprintln!("{}", sensitive::mask(message));
+    }
+
+}
+
+/// The defaults of Hooks.
+struct Defaults;
+
+impl Hooks for Defaults {}
+
+static HOOKS: RwLock<Option<Arc<dyn Hooks>>> = RwLock::new(None);
+
+/// Runs every command of the process with `hooks` from now on.
+pub fn set(hooks: Arc<dyn Hooks>) {
+    *HOOKS.write().unwrap_or_else(|e| e.into_inner()) = Some(hooks);
+}
+
+/// The hooks set, the defaults when none were.
+pub fn current() -> Arc<dyn Hooks> {
+    HOOKS.read().unwrap_or_else(|e| e.into_inner()).clone().unwrap_or_else(|| Arc::new(Defaults))
+}
@@ -0,0 +1,183 @@
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicI32, Ordering};
+
+/// Children running at once that signals can be forwarded to, more run unforwarded.
+const SLOTS: usize = 64;
+const FREE: i32 = 0;
+/// Taken by a child that's about to be spawned.
+const CLAIMED: i32 = i32::MIN;
+
+/// The signal that interrupted the run, 0 while none did.
+static SIGNAL: AtomicI32 = AtomicI32::new(0);
+/// What kill() gets to reach each running child: its pid, or minus its process group.
+static CHILDREN: [AtomicI32; SLOTS] = [const { AtomicI32::new(FREE) }; SLOTS];
+/// Set from cancel() until resume(), children started meanwhile are killed right away.
+static CANCELLED: AtomicBool = AtomicBool::new(false);
+
+extern "C" fn handle(sig: libc::c_int) {
+    if SIGNAL.swap(sig, Ordering::SeqCst) != 0 {
+        // A second one, whoever sent it doesn't want to wait for the cleanup.
+        let message = b"\nInterrupted again, exiting without cleaning up.\n";
+        unsafe {
+            libc::write(libc::STDERR_FILENO, message.as_ptr().cast(), message.len());
+            libc::_exit(128 + sig);
+        }
+    }
+    for slot in &CHILDREN {
+        forward(slot.load(Ordering::SeqCst), sig);
+    }
+}
+
+fn forward(target: i32, sig: i32) {
+    // A child in our process group got the terminal's SIGINT along with us.
+    if target == FREE || target == CLAIMED || (target > 0 && sig == libc::SIGINT) {
+        return;
+    }
+    unsafe { libc::kill(target, sig) };
+}
+
+/// Catches SIGINT and SIGTERM from now on instead of dying on them: they're passed on to the
+/// running children and the run stops at the next step, see check().  A second one exits at once.
+pub fn catch() -> io::Result<()> {
+    for sig in [libc::SIGINT, libc::SIGTERM] {
+        let mut action: libc::sigaction = unsafe { std::mem::zeroed() };
+        action.sa_sigaction = handle as extern "C" fn(libc::c_int) as libc::sighandler_t;
+        action.sa_flags = libc::SA_RESTART;
+        unsafe { libc::sigemptyset(&mut action.sa_mask) };
+        if unsafe { libc::sigaction(sig, &action, ptr::null_mut()) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// The signal that interrupted the run, if one did.
+pub fn received() -> Option<i32> {
+    match SIGNAL.load(Ordering::SeqCst) {
+        0 => None,
+        sig => Some(sig),
+    }
+}
+
+/// Fails once the run was interrupted, for the places it can stop at cleanly.
+pub fn check() -> io::Result<()> {
+    match received() {
+        Some(sig) => Err(io::Error::new(io::ErrorKind::Interrupted, format!("Interrupted by {}.", name(sig)))),
+        None => Ok(()),
+    }
+}
+
+/// The pids of the children running now.
+pub fn running() -> Vec<u32> {
+    CHILDREN.iter()
+        .map(|slot| slot.load(Ordering::SeqCst))
+        .filter(|target| *target != FREE && *target != CLAIMED)
+        .map(|target| target.unsigned_abs())
+        .collect()
+}
+
+/// Sends `sig` to every running child and, with their own process group, what they started.
+pub fn kill_all(sig: i32) {
+    for slot in &CHILDREN {
+        let target = slot.load(Ordering::SeqCst);
+        if target != FREE && target != CLAIMED {
+            unsafe { libc::kill(target, sig) };
+        }
+    }
+}
+
+/// Stops the running children with SIGTERM, and kills the ones started until resume(), for a
+/// step that ran out of time.  Unlike a signal the run goes on after resume().
+pub fn cancel() {
+    CANCELLED.store(true, Ordering::SeqCst);
+    kill_all(libc::SIGTERM);
+}
+
+pub fn resume() {
+    CANCELLED.store(false, Ordering::SeqCst);
+}
+
+/// True between cancel() and resume().
+pub fn cancelled() -> bool {
+    CANCELLED.load(Ordering::SeqCst)
+}
+
+pub fn name(sig: i32) -> &'static str {
+    match sig {
+        libc::SIGINT => "SIGINT",
+        libc::SIGTERM => "SIGTERM",
+        _ => "a signal",
+    }
+}
+
+/// A child the signals are forwarded to, from prepare() until it's dropped.
+pub struct Forward {
+    slot: Option<usize>,
+    group: bool,
+}
+
+/// Makes room to forward the signals to the child `cmd` is about to spawn.  With `group` it
+/// gets a process group of its own, and everything it starts gets them too, but no SIGINT
+/// from the terminal; only for children that never read the terminal.
+pub fn prepare(cmd: &mut Command, group: bool) -> Forward {
+    let slot = CHILDREN.iter().position(|s| s.compare_exchange(FREE, CLAIMED, Ordering::SeqCst, Ordering::SeqCst).is_ok());
+    // Without a slot nothing would forward the terminal's SIGINT to a group of its own.
+    let group = group && slot.is_some();
+    if group {
+        cmd.process_group(0);
+    }
+    Forward { slot, group }
+}
+
+impl Forward {
+
+    /// The child was spawned as `pid`.
+    pub fn started(&self, pid: u32) {
+        let Some(slot) = self.slot else {
+            return;
+        };
+        let target = if self.group { -(pid as i32) } else { pid as i32 };
+        CHILDREN[slot].store(target, Ordering::SeqCst);
+        // The signal may have come in between prepare() and now.
+        if let Some(sig) = received() {
+            forward(target, sig);
+        }
+        if cancelled() {
+            unsafe { libc::kill(target, libc::SIGKILL) };
+        }
+    }
+
+}
+
+impl Drop for Forward {
+
+    fn drop(&mut self) {
+        if let Some(slot) = self.slot {
+            CHILDREN[slot].store(FREE, Ordering::SeqCst);
+        }
+    }
+
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn slots_are_given_back() {
+        let mut cmd = Command::new("true");
+        let forward = prepare(&mut cmd, true);
+        let slot = forward.slot.unwrap();
+        forward.started(4242);
+        assert_eq!(CHILDREN[slot].load(Ordering::SeqCst), -4242);
+        assert!(running().contains(&4242));
+        drop(forward);
+        assert_eq!(CHILDREN[slot].load(Ordering::SeqCst), FREE);
+        assert!(check().is_ok());
+    }
+
+}
@@ -0,0 +1,103 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// PATH for commands run as root, whatever the caller's was.
+pub const SAFE_PATH: &str = "/usr/local/sbin:/usr/local/bin:/usr/sbin:/usr/bin:/sbin:/bin";
+/// Variables that change what a command loads or runs before it starts, never passed to root.
+pub const STRIPPED: &[&str] = &["LD_PRELOAD", "LD_LIBRARY_PATH", "LD_AUDIT", "BASH_ENV", "ENV"];
+/// The programs the install trusts with root, resolved once to absolute paths.
+pub const CRITICAL: &[&str] = &[
+    "apt-get", "dpkg", "dpkg-query", "dnf", "yum", "rpm", "zypper", "transactional-update",
+    "systemctl", "curl", "gpg", "gpgv", "modprobe", "dkms", "semodule", "restorecon",
+    "apparmor_parser", "mokutil", "tar",
+];
+
+/// Where resolve() found the critical programs.
+static RESOLVED: Mutex<Option<HashMap<String, PathBuf>>> = Mutex::new(None);
+
+/// Why `path` can't be trusted to run as root: someone other than root can replace it or the
+/// directory it's in.  None when only root can.
+pub fn untrusted(path: &Path) -> Option<String> {
+    let mut at = Some(path);
+    while let Some(p) = at {
+        let meta = fs::metadata(p).ok()?;
+        if meta.uid() != 0 {
+            return Some(format!("{} is owned by uid {}", p.display(), meta.uid()));
+        }
+        // Sticky directories like /tmp only let owners replace their files.
+        if meta.mode() & 0o022 != 0 && !(meta.is_dir() && meta.mode() & 0o1000 != 0) {
+            return Some(format!("{} is writable by more than root", p.display()));
+        }
+        at = p.parent();
+    }
+    None
+}
+
+/// `program` in SAFE_PATH, never where the caller's PATH points.
+pub fn find(program: &str) -> Option<PathBuf> {
+    env::split_paths(SAFE_PATH).map(|dir| dir.join(program)).find(|p| p.is_file())
+}
+
+/// Looks up every CRITICAL program in SAFE_PATH once, for commands as root to run by absolute
+/// path.  Returns the ones someone other than root could replace, with why.
+pub fn resolve() -> Vec<String> {
+    let mut found = HashMap::new();
+    let mut untrusted_ones = Vec::new();
+    for program in CRITICAL {
+        let Some(path) = find(program) else {
+            continue;
+        };
+        match untrusted(&path) {
+            Some(why) => untrusted_ones.push(why),
+            None => {
+                found.insert(String::from(*program), path);
+            }
+        }
+    }
+    *RESOLVED.lock().unwrap_or_else(|e| e.into_inner()) = Some(found);
+    untrusted_ones
+}
+
+/// The absolute path to run `program` by, as resolve() found it, else in SAFE_PATH.  A path
+/// stays as it is, as does a program that isn't there.
+pub fn program(program: &str) -> String {
+    if program.contains('/') {
+        return String::from(program);
+    }
+    let resolved = RESOLVED.lock().unwrap_or_else(|e| e.into_inner()).as_ref().and_then(|r| r.get(program).cloned());
+    resolved.or_else(|| find(program)).map_or_else(|| String::from(program), |p| p.to_string_lossy().into_owned())
+}
+
+/// The entries of the caller's `path` that would let someone else pick what runs: relative
+/// ones, like "." or an empty entry, and directories others can write to.
+pub fn risky_entries(path: &str) -> Vec<String> {
+    path.split(':')
+        .filter(|dir| !Path::new(dir).is_absolute() || untrusted(Path::new(dir)).is_some())
+        .map(|dir| if dir.is_empty() { String::from("(empty)") } else { String::from(dir) })
+        .collect()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn trusted_paths() {
+        assert_eq!(untrusted(Path::new("/usr/bin")), None);
+        assert!(untrusted(Path::new("/tmp")).is_none());
+        let dir = env::temp_dir().join(format!("bitflux-path-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::set_permissions(&dir, std::os::unix::fs::PermissionsExt::from_mode(0o777)).unwrap();
+        assert_eq!(risky_entries(&format!("/usr/bin::.:{}", dir.display())), ["(empty)", ".", dir.to_str().unwrap()]);
+        fs::remove_dir(&dir).unwrap();
+        assert_eq!(program("/opt/bin/tool"), "/opt/bin/tool");
+        assert_eq!(program("no-such-bitflux-tool"), "no-such-bitflux-tool");
+        assert!(program("sh").starts_with('/'));
+    }
+
+}
@@ -0,0 +1,82 @@
+use std::sync::Mutex;
+
+/// What a sensitive value shows as.
+pub const MASK: &str = "[redacted]";
+/// Values shorter than this aren't masked, they'd turn up in every other word.
+const MIN_LEN: usize = 4;
+
+/// The values add() was given, longest first so one that holds another is masked whole.
+static VALUES: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// Masks `value` wherever RunCmd shows a command from now on, and in whatever goes through
+/// mask().  For license keys, passwords and tokens.
+pub fn add(value: &str) {
+    let value = value.trim();
+    if value.len() < MIN_LEN {
+        return;
+    }
+    let mut values = VALUES.lock().unwrap_or_else(|e| e.into_inner());
+    if !values.iter().any(|v| v == value) {
+        values.push(String::from(value));
+        values.sort_by_key(|v| std::cmp::Reverse(v.len()));
+    }
+}
+
+/// Masks `value` like add() until the Scoped it returns is dropped, for a password only the
+/// commands run meanwhile see.  One add() already had stays masked.
+pub fn scoped(value: &str) -> Scoped {
+    let value = value.trim();
+    let added = value.len() >= MIN_LEN && !VALUES.lock().unwrap_or_else(|e| e.into_inner()).iter().any(|v| v == value);
+    add(value);
+    Scoped(added.then(|| String::from(value)))
+}
+
+/// From scoped(), stops masking its value when dropped.
+pub struct Scoped(Option<String>);
+
+impl Drop for Scoped {
+
+    fn drop(&mut self) {
+        if let Some(value) = self.0.take() {
+            VALUES.lock().unwrap_or_else(|e| e.into_inner()).retain(|v| *v != value);
+        }
+    }
+
+}
+
+/// `text` with every value add() was given replaced by MASK.
+pub fn mask(text: &str) -> String {
+    let values = VALUES.lock().unwrap_or_else(|e| e.into_inner());
+    values.iter().fold(String::from(text), |text, value| text.replace(value.as_str(), MASK))
+}
+
+/// Whether `text` has any of the values in it.
+pub fn contains(text: &str) -> bool {
+    VALUES.lock().unwrap_or_else(|e| e.into_inner()).iter().any(|v| text.contains(v.as_str()))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn masked_everywhere() {
+        add("BFX-2F4K-9QZ7");
+        add("BFX-2F4K-9QZ7-ABCD");
+        add("pw");
+        assert_eq!(mask("licensekey=BFX-2F4K-9QZ7-ABCD and BFX-2F4K-9QZ7"), "licensekey=[redacted] and [redacted]");
+        assert!(contains("key BFX-2F4K-9QZ7"));
+        assert_eq!(mask("pw stays"), "pw stays");
+    }
+
+    #[test]
+    fn scoped_until_dropped() {
+        add("kept-license-key");
+        let masked = (scoped("s3cret pass"), scoped("kept-license-key"));
+        assert_eq!(mask("s3cret pass kept-license-key"), "[redacted] [redacted]");
+        drop(masked);
+        assert_eq!(mask("s3cret pass kept-license-key"), "s3cret pass [redacted]");
+    }
+
+}
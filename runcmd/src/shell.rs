@@ -0,0 +1,146 @@
+use std::process::Command;
+use std::sync::Mutex;
+
+/// The shells RunCmd::shell() runs a command line with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Shell {
+    /// The POSIX sh, Linux's.
+    Sh,
+    Bash,
+    /// macOS's login shell since Catalina.
+    Zsh,
+    /// cmd.exe, Windows'.
+    Cmd,
+    /// Windows PowerShell, or pwsh where that's all there is.
+    PowerShell,
+}
+
+/// The shell set(), None when the platform's.
+static SHELL: Mutex<Option<Shell>> = Mutex::new(None);
+
+impl Shell {
+
+    /// The shell of the platform the crate was built for: zsh on macOS, cmd on Windows, sh
+    /// everywhere else.
+    pub const fn native() -> Shell {
+        if cfg!(windows) {
+            Shell::Cmd
+        } else if cfg!(target_os = "macos") {
+            Shell::Zsh
+        } else {
+            Shell::Sh
+        }
+    }
+
+    /// The shell called `name`, as in sh, bash, zsh, cmd or powershell.
+    pub fn from_name(name: &str) -> Option<Shell> {
+        match name.trim_end_matches(".exe") {
+            "sh" => Some(Shell::Sh),
+            "bash" => Some(Shell::Bash),
+            "zsh" => Some(Shell::Zsh),
+            "cmd" => Some(Shell::Cmd),
+            "powershell" | "pwsh" => Some(Shell::PowerShell),
+            _ => None,
+        }
+    }
+
+    /// The program to start.
+    pub fn program(&self) -> &'static str {
+        match self {
+            Shell::Sh => "sh",
+            Shell::Bash => "bash",
+            Shell::Zsh => "zsh",
+            Shell::Cmd => "cmd.exe",
+            Shell::PowerShell if cfg!(windows) => "powershell.exe",
+            Shell::PowerShell => "pwsh",
+        }
+    }
+
+    /// The arguments that have the program run `line` and exit with its exit code.
+    pub fn args(&self, line: &str) -> Vec<String> {
+        let mut args: Vec<String> = match self {
+            Shell::Sh | Shell::Bash | Shell::Zsh => vec![String::from("-c")],
+            Shell::Cmd => ["/D", "/S", "/C"].map(String::from).to_vec(),
+            Shell::PowerShell => ["-NoLogo", "-NoProfile", "-NonInteractive", "-Command"].map(String::from).to_vec(),
+        };
+        args.push(String::from(line));
+        args
+    }
+
+    /// The process that runs `line`, started as `program`: program(), or where it was found.
+    pub fn command(&self, program: &str, line: &str) -> Command {
+        let mut command = Command::new(program);
+        let mut args = self.args(line);
+        let line = args.pop().unwrap_or_default();
+        command.args(args);
+        arg_line(&mut command, *self, line);
+        command
+    }
+
+    /// Quotes `arg` for a command line of this shell only when it needs it.
+    pub fn quote(&self, arg: &str) -> String {
+        match self {
+            Shell::Sh | Shell::Bash | Shell::Zsh => crate::shell_quote(arg),
+            Shell::Cmd if !arg.is_empty() && !arg.contains(|c: char| c.is_whitespace() || "&|<>()^\"%!".contains(c)) => String::from(arg),
+            // cmd has no escape inside quotes, a quote is doubled for the program's parser.
+            Shell::Cmd => format!("\"{}\"", arg.replace('"', "\"\"")),
+            Shell::PowerShell if !arg.is_empty() && arg.chars().all(|c| c.is_ascii_alphanumeric() || "-_./:\\".contains(c)) => String::from(arg),
+            Shell::PowerShell => format!("'{}'", arg.replace('\'', "''")),
+        }
+    }
+
+}
+
+/// Adds `line` to the arguments: as it is for cmd, which parses its command line itself and
+/// not the way Rust quotes it.
+#[cfg(windows)]
+fn arg_line(command: &mut Command, shell: Shell, line: String) {
+    use std::os::windows::process::CommandExt;
+    match shell {
+        Shell::Cmd => command.raw_arg(format!("\"{}\"", line)),
+        _ => command.arg(line),
+    };
+}
+
+#[cfg(not(windows))]
+fn arg_line(command: &mut Command, _shell: Shell, line: String) {
+    command.arg(line);
+}
+
+/// Runs shell() commands with `shell` from now on, unless they ask for another.
+pub fn set(shell: Shell) {
+    *SHELL.lock().unwrap_or_else(|e| e.into_inner()) = Some(shell);
+}
+
+/// The shell set(), the platform's when none was.
+pub fn current() -> Shell {
+    SHELL.lock().unwrap_or_else(|e| e.into_inner()).unwrap_or(Shell::native())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{which, RunCmd};
+
+    #[test]
+    fn shells_run_a_line() {
+        if cfg!(target_os = "linux") {
+            assert_eq!(Shell::native(), Shell::Sh);
+        }
+        assert_eq!(Shell::from_name("pwsh.exe"), Some(Shell::PowerShell));
+        assert_eq!(Shell::Cmd.args("dir C:\\"), ["/D", "/S", "/C", "dir C:\\"]);
+        assert_eq!(Shell::PowerShell.quote("it's"), "'it''s'");
+        assert_eq!(Shell::Cmd.quote("C:\\Program Files\\x"), "\"C:\\Program Files\\x\"");
+        assert_eq!(Shell::Cmd.quote("C:\\bitflux"), "C:\\bitflux");
+        for shell in [Shell::Sh, Shell::Bash, Shell::Zsh] {
+            if which(shell.program()).is_none() {
+                continue;
+            }
+            let line = format!("printf %s {}", shell.quote("a 'b' $c"));
+            let retval = RunCmd::new(&line).shell_with(shell).execute_output();
+            assert_eq!((retval.exitcode, retval.stdout.as_str()), (0, "a 'b' $c"), "{:?}", shell);
+        }
+    }
+
+}
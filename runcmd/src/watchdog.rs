@@ -0,0 +1,270 @@
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::sync::atomic::{AtomicU64, AtomicU8, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+#[cfg(feature = "clap")]
+use clap::ValueEnum;
+
+use crate::interactive;
+
+/// How long a command may go without output or CPU time before the watchdog steps in.
+pub const DEFAULT_IDLE_SECS: u64 = 300;
+const CHECK_INTERVAL: Duration = Duration::from_secs(1);
+/// Between SIGTERM and SIGKILL.
+const KILL_GRACE: Duration = Duration::from_secs(5);
+/// Package managers, and what runs them unattended, that hold the package database lock.
+const LOCK_HOLDERS: &[&str] = &["apt", "apt-get", "dpkg", "unattended-upgr", "packagekitd", "dnf", "dnf-automatic", "yum", "rpm", "zypper"];
+#[cfg(target_os = "linux")]
+#[allow(clippy::unnecessary_cast)]
+const SYS_READ: i64 = libc::SYS_read as i64;
+#[cfg(not(target_os = "linux"))]
+const SYS_READ: i64 = -1;
+
+static IDLE_SECS: AtomicU64 = AtomicU64::new(DEFAULT_IDLE_SECS);
+static POLICY: AtomicU8 = AtomicU8::new(Policy::Ask as u8);
+
+/// What to do with a command that looks hung.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "clap", derive(ValueEnum))]
+#[repr(u8)]
+pub enum Policy {
+    /// Ask on a terminal whether to kill it, keep waiting otherwise.
+    Ask,
+    /// Kill it, the command fails.
+    Kill,
+    /// Report it and keep waiting.
+    Warn,
+}
+
+/// Sets how long a command may be idle, zero turns the watchdog off, and what happens then.
+pub fn configure(idle: Duration, policy: Policy) {
+    IDLE_SECS.store(idle.as_secs(), Ordering::Relaxed);
+    POLICY.store(policy as u8, Ordering::Relaxed);
+}
+
+fn policy() -> Policy {
+    match POLICY.load(Ordering::Relaxed) {
+        p if p == Policy::Kill as u8 => Policy::Kill,
+        p if p == Policy::Warn as u8 => Policy::Warn,
+        _ => Policy::Ask,
+    }
+}
+
+/// A process from /proc/<pid>/stat.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Proc {
+    pub pid: u32,
+    pub comm: String,
+    pub ppid: u32,
+    /// utime + stime in clock ticks.
+    pub cpu: u64,
+}
+
+/// Parses a /proc/<pid>/stat line, the command name may contain spaces and parentheses.
+pub fn parse_stat(line: &str) -> Option<Proc> {
+    let (pid, rest) = line.split_once(" (")?;
+    let (comm, rest) = rest.rsplit_once(") ")?;
+    let fields: Vec<&str> = rest.split_whitespace().collect();
+    Some(Proc {
+        pid: pid.trim().parse().ok()?,
+        comm: String::from(comm),
+        ppid: fields.get(1)?.parse().ok()?,
+        cpu: fields.get(11)?.parse::<u64>().ok()? + fields.get(12)?.parse::<u64>().ok()?,
+    })
+}
+
+fn processes() -> Vec<Proc> {
+    let Ok(entries) = fs::read_dir("/proc") else {
+        return Vec::new();
+    };
+    entries.filter_map(|e| e.ok())
+        .filter(|e| e.file_name().to_string_lossy().bytes().all(|b| b.is_ascii_digit()))
+        .filter_map(|e| fs::read_to_string(e.path().join("stat")).ok())
+        .filter_map(|s| parse_stat(&s))
+        .collect()
+}
+
+/// `root` and everything it started, directly or not.
+pub fn tree(procs: &[Proc], root: u32) -> Vec<&Proc> {
+    let mut tree: Vec<&Proc> = procs.iter().filter(|p| p.pid == root).collect();
+    let mut i = 0;
+    while i < tree.len() {
+        let parent = tree[i].pid;
+        tree.extend(procs.iter().filter(|p| p.ppid == parent && p.pid != root));
+        i += 1;
+    }
+    tree
+}
+
+/// The likeliest reasons the processes in `tree` are stuck, from what else runs on the host
+/// and what each of them is blocked in.
+pub fn likely_causes(procs: &[Proc], tree: &[&Proc]) -> Vec<String> {
+    let ours = |pid: u32| pid == std::process::id() || tree.iter().any(|p| p.pid == pid);
+    let mut causes: Vec<String> = procs.iter()
+        .filter(|p| !ours(p.pid) && LOCK_HOLDERS.contains(&p.comm.as_str()))
+        .map(|p| format!("waiting for the package manager lock, held by {} (pid {})", p.comm, p.pid))
+        .collect();
+    for p in tree {
+        let syscall = fs::read_to_string(format!("/proc/{}/syscall", p.pid)).unwrap_or_default();
+        let mut call = syscall.split_whitespace();
+        if call.next().and_then(|n| n.parse::<i64>().ok()) == Some(SYS_READ) && call.next() == Some("0x0") {
+            causes.push(format!("{} (pid {}) is waiting for input on stdin", p.comm, p.pid));
+            continue;
+        }
+        let wchan = fs::read_to_string(format!("/proc/{}/wchan", p.pid)).unwrap_or_default();
+        if !wchan.is_empty() && wchan != "0" {
+            causes.push(format!("{} (pid {}) is blocked in {}", p.comm, p.pid, wchan.trim()));
+        }
+    }
+    causes
+}
+
+fn signal(pids: &[u32], sig: i32) {
+    for pid in pids {
+        unsafe { libc::kill(*pid as i32, sig) };
+    }
+}
+
+/// Asks on the terminal whether to kill `name`, false when we can't ask.
+fn ask_kill(name: &str) -> bool {
+    if !interactive() {
+        return false;
+    }
+    eprint!("Kill '{}'? [k]ill/[c]ontinue waiting: ", name);
+    let _ = io::stderr().flush();
+    let mut answer = String::new();
+    let _ = io::stdin().lock().read_line(&mut answer);
+    answer.trim().to_lowercase().starts_with('k')
+}
+
+/// Watches a running command for output and CPU activity.
+pub struct Watchdog {
+    name: String,
+    pid: u32,
+    output: Arc<AtomicU64>,
+    idle: Duration,
+    policy: Policy,
+    last_check: Instant,
+    last_activity: Instant,
+    last_output: u64,
+    last_cpu: u64,
+    killed: Option<Instant>,
+}
+
+impl Watchdog {
+
+    /// Watches the command `name` running as `pid`, `output` counts the bytes it wrote.
+    pub fn new(name: &str, pid: u32, output: Arc<AtomicU64>) -> Watchdog {
+        let now = Instant::now();
+        Watchdog {
+            name: String::from(name),
+            pid,
+            output,
+            idle: Duration::from_secs(IDLE_SECS.load(Ordering::Relaxed)),
+            policy: policy(),
+            last_check: now,
+            last_activity: now,
+            last_output: 0,
+            last_cpu: 0,
+            killed: None,
+        }
+    }
+
+    /// True once the command was killed.
+    pub fn killed(&self) -> bool {
+        self.killed.is_some()
+    }
+
+    /// Called while the command runs, acts on it at most once a second.
+    pub fn tick(&mut self) {
+        if self.idle.is_zero() || self.last_check.elapsed() < CHECK_INTERVAL {
+            return;
+        }
+        self.last_check = Instant::now();
+        let procs = processes();
+        let tree = tree(&procs, self.pid);
+        let pids: Vec<u32> = tree.iter().map(|p| p.pid).collect();
+
+        if let Some(killed) = self.killed {
+            if killed.elapsed() >= KILL_GRACE {
+                signal(&pids, libc::SIGKILL);
+            }
+            return;
+        }
+
+        let output = self.output.load(Ordering::Relaxed);
+        let cpu = tree.iter().map(|p| p.cpu).sum();
+        if output != self.last_output || cpu != self.last_cpu {
+            self.last_output = output;
+            self.last_cpu = cpu;
+            self.last_activity = Instant::now();
+            return;
+        }
+        if self.last_activity.elapsed() < self.idle {
+            return;
+        }
+
+        eprintln!("'{}' produced no output and used no CPU for {}s, it may be hung:", self.name, self.last_activity.elapsed().as_secs());
+        let causes = likely_causes(&procs, &tree);
+        if causes.is_empty() {
+            eprintln!("    no likely cause found");
+        }
+        for cause in causes {
+            eprintln!("    {}", cause);
+        }
+        let kill = match self.policy {
+            Policy::Kill => true,
+            Policy::Warn => false,
+            Policy::Ask => ask_kill(&self.name),
+        };
+        if kill {
+            eprintln!("Killing '{}'.", self.name);
+            signal(&pids, libc::SIGTERM);
+            self.killed = Some(Instant::now());
+        } else {
+            self.last_activity = Instant::now();
+        }
+    }
+
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_proc_stat() {
+        let stat = "4242 (dpkg (deb)) S 4200 4242 4200 0 -1 4194560 1561 0 0 0 17 5 0 0 20 0 1 0 18446 11423744 1379 18446744073709551615";
+        assert_eq!(parse_stat(stat), Some(Proc { pid: 4242, comm: String::from("dpkg (deb)"), ppid: 4200, cpu: 22 }));
+        assert_eq!(parse_stat("garbage"), None);
+    }
+
+    #[test]
+    fn tree_and_lock_holders() {
+        let p = |pid, comm: &str, ppid| Proc { pid, comm: String::from(comm), ppid, cpu: 0 };
+        let procs = vec![p(1, "systemd", 0), p(10, "apt-get", 1), p(11, "dpkg", 10), p(12, "sh", 11), p(20, "unattended-upgr", 1)];
+        let pids: Vec<u32> = tree(&procs, 10).iter().map(|p| p.pid).collect();
+        assert_eq!(pids, [10, 11, 12]);
+        let causes = likely_causes(&procs, &tree(&procs, 10));
+        assert_eq!(causes[0], "waiting for the package manager lock, held by unattended-upgr (pid 20)");
+    }
+
+    #[test]
+    fn kills_idle_command() {
+        let mut child = std::process::Command::new("sleep").arg("30").spawn().unwrap();
+        let mut watchdog = Watchdog::new("sleep 30", child.id(), Arc::new(AtomicU64::new(0)));
+        watchdog.idle = Duration::from_secs(1);
+        watchdog.policy = Policy::Kill;
+        let start = Instant::now();
+        while child.try_wait().unwrap().is_none() {
+            assert!(start.elapsed() < Duration::from_secs(10), "watchdog didn't kill the command");
+            watchdog.tick();
+            std::thread::sleep(Duration::from_millis(100));
+        }
+        assert!(watchdog.killed());
+    }
+
+}
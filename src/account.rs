@@ -0,0 +1,116 @@
+use std::fs;
+use std::io;
+use std::os::unix::fs::MetadataExt;
+
+use crate::data::DATA_DIRS;
+use crate::perms::{group_id, AGENT_GROUP};
+use crate::privsep::{self, Ids};
+use crate::runcmd::{self, RunCmd};
+
+/// The system account the agent service runs as, its group is perms::AGENT_GROUP.
+pub const AGENT_USER: &str = "bitflux";
+/// The account's home, it gets no files of its own there.
+pub const HOME: &str = "/opt/bitflux";
+/// Groups the agent needs besides its own, where the host has them: systemd-journal to read
+/// the journal of the services it watches.
+pub const SUPPLEMENTARY_GROUPS: &[&str] = &["systemd-journal"];
+/// What the agent writes to, owned by the account.
+pub const STATE_DIRS: &[&str] = DATA_DIRS;
+const PASSWD: &str = "/etc/passwd";
+const GROUP: &str = "/etc/group";
+
+/// The members of `group` in group formatted `data`.
+pub fn members<'a>(data: &'a str, group: &str) -> Vec<&'a str> {
+    data.lines()
+        .map(|line| line.split(':').collect::<Vec<&str>>())
+        .find(|fields| fields.len() >= 4 && fields[0] == group)
+        .map(|fields| fields[3].split(',').filter(|m| !m.is_empty()).collect())
+        .unwrap_or_default()
+}
+
+/// The SUPPLEMENTARY_GROUPS of the host in group formatted `data` that AGENT_USER isn't in.
+pub fn missing_groups(data: &str) -> Vec<&'static str> {
+    SUPPLEMENTARY_GROUPS.iter().copied()
+        .filter(|g| group_id(data, g).is_some() && !members(data, g).contains(&AGENT_USER))
+        .collect()
+}
+
+/// The account's ids, None while there's no account.
+pub fn lookup() -> Option<Ids> {
+    privsep::lookup(&fs::read_to_string(PASSWD).unwrap_or_default(), AGENT_USER)
+}
+
+/// Whether the account is there, in its groups and owning the STATE_DIRS.
+pub fn provisioned() -> bool {
+    let Some(ids) = lookup() else {
+        return false;
+    };
+    let groups = fs::read_to_string(GROUP).unwrap_or_default();
+    missing_groups(&groups).is_empty()
+        && STATE_DIRS.iter().all(|d| fs::metadata(d).is_ok_and(|m| m.uid() == ids.uid && m.gid() == ids.gid))
+}
+
+fn run(program: &str, args: &[&str]) -> io::Result<()> {
+    RunCmd::args(program, args).try_execute().map(|_| ()).map_err(io::Error::from)
+}
+
+/// Creates the group and the account unless they exist, adds the account to the
+/// SUPPLEMENTARY_GROUPS and hands it the STATE_DIRS.
+pub fn provision() -> io::Result<()> {
+    if runcmd::dry_run(&format!("create the {} system user and group, add it to {} and chown {} to it", AGENT_USER, SUPPLEMENTARY_GROUPS.join(", "), STATE_DIRS.join(", "))) {
+        return Ok(());
+    }
+    let groups = fs::read_to_string(GROUP).unwrap_or_default();
+    if group_id(&groups, AGENT_GROUP).is_none() {
+        run("groupadd", &["--system", AGENT_GROUP])?;
+    }
+    if lookup().is_none() {
+        run("useradd", &["--system", "--gid", AGENT_GROUP, "--home-dir", HOME, "--no-create-home", "--shell", "/usr/sbin/nologin", "--comment", "bitflux agent", AGENT_USER])?;
+    }
+    for group in missing_groups(&groups) {
+        run("usermod", &["--append", "--groups", group, AGENT_USER])?;
+    }
+    let owner = format!("{}:{}", AGENT_USER, AGENT_GROUP);
+    for dir in STATE_DIRS {
+        run("install", &["-d", "-m", "0750", "-o", AGENT_USER, "-g", AGENT_GROUP, dir])?;
+        run("chown", &["-R", &owner, dir])?;
+    }
+    Ok(())
+}
+
+/// Removes the account and its group, for `uninstall --purge`.  Returns what it removed.
+pub fn remove() -> io::Result<Vec<String>> {
+    let mut removed = Vec::new();
+    if lookup().is_some() {
+        if !runcmd::dry_run(&format!("userdel {}", AGENT_USER)) {
+            run("userdel", &[AGENT_USER])?;
+        }
+        removed.push(format!("user {}", AGENT_USER));
+    }
+    // userdel takes the group along where USERGROUPS_ENAB is set.
+    if group_id(&fs::read_to_string(GROUP).unwrap_or_default(), AGENT_GROUP).is_some() {
+        if !runcmd::dry_run(&format!("groupdel {}", AGENT_GROUP)) {
+            run("groupdel", &[AGENT_GROUP])?;
+        }
+        removed.push(format!("group {}", AGENT_GROUP));
+    }
+    Ok(removed)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn groups_to_join() {
+        let groups = "root:x:0:\nsystemd-journal:x:190:syslog,bitflux\nbitflux:x:998:\n";
+        assert_eq!(members(groups, "systemd-journal"), ["syslog", "bitflux"]);
+        assert!(members(groups, "bitflux").is_empty());
+        assert!(missing_groups(groups).is_empty());
+        assert_eq!(missing_groups("systemd-journal:x:190:syslog\n"), ["systemd-journal"]);
+        // A host without the group has nothing to join.
+        assert!(missing_groups("root:x:0:\n").is_empty());
+    }
+
+}
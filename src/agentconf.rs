@@ -0,0 +1,249 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use crate::device::DEVICE_ID_KEY;
+use crate::log::{self, Level};
+use crate::perms::{self, FileKind, PermissionRecord};
+use crate::pkg::AGENT_PACKAGE;
+use crate::runcmd;
+use crate::script;
+use crate::service::Service;
+
+/// The bitflux agent's key=value config file.
+pub const AGENT_CONFIG: &str = "/opt/bitflux/config/bitflux/bitfluxcollector.conf";
+/// Settings the agent package manages itself, a new release may change them.
+const PACKAGE_SETTINGS: &[&str] = &["config_version"];
+/// Settings configure has flags of its own for, that check them first.
+const FLAG_SETTINGS: &[(&str, &str)] = &[("licensekey", "--license-key"), (DEVICE_ID_KEY, "--device-id")];
+
+/// Parses key=value lines, ignoring blanks and # comments.
+pub fn parse(data: &str) -> BTreeMap<String, String> {
+    data.lines()
+        .map(str::trim)
+        .filter(|l| !l.is_empty() && !l.starts_with('#'))
+        .filter_map(|l| l.split_once('='))
+        .map(|(k, v)| (k.trim().to_string(), v.trim().to_string()))
+        .collect()
+}
+
+pub fn load<P: AsRef<Path>>(path: P) -> io::Result<BTreeMap<String, String>> {
+    Ok(parse(&fs::read_to_string(path)?))
+}
+
+/// `data` with every key of `values` set, in place where it already is and appended where it
+/// isn't.  Comments and the other lines stay as they are.
+pub fn update<K: AsRef<str>, V: AsRef<str>>(data: &str, values: &[(K, V)]) -> String {
+    let mut pending: Vec<(&str, &str)> = values.iter().map(|(k, v)| (k.as_ref(), v.as_ref())).collect();
+    let mut out = String::new();
+    for line in data.lines() {
+        let key = line.split_once('=').map(|(k, _)| k.trim()).filter(|_| !line.trim_start().starts_with('#'));
+        match key.and_then(|k| pending.iter().position(|(p, _)| *p == k)) {
+            Some(i) => {
+                let (k, v) = pending.remove(i);
+                out.push_str(&format!("{}={}\n", k, v));
+            }
+            None => out.push_str(&format!("{}\n", line)),
+        }
+    }
+    for (k, v) in pending {
+        out.push_str(&format!("{}={}\n", k, v));
+    }
+    out
+}
+
+/// Sets `values` in the config file at `path`, creating it if needed.  The file is replaced in
+/// one rename and gets the Config owner and mode.  The values aren't printed, they can be keys.
+pub fn set<P: AsRef<Path>, K: AsRef<str>, V: AsRef<str>>(path: P, values: &[(K, V)]) -> io::Result<PermissionRecord> {
+    let path = path.as_ref();
+    let keys: Vec<&str> = values.iter().map(|(k, _)| k.as_ref()).collect();
+    if script::recording() {
+        script::file(path, &update(&fs::read_to_string(path).unwrap_or_default(), values));
+        return perms::apply(path, FileKind::Config);
+    }
+    if runcmd::dry_run(&format!("set {} in {}", keys.join(", "), path.display())) {
+        return perms::apply(path, FileKind::Config);
+    }
+    let data = match fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => return Err(e),
+    };
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    // Private until it has its final owner and mode, it may hold the license key.
+    let tmp = path.with_extension("tmp");
+    let _ = fs::remove_file(&tmp);
+    fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(&tmp)?
+        .write_all(update(&data, values).as_bytes())?;
+    let mut record = perms::apply(&tmp, FileKind::Config)?;
+    fs::rename(&tmp, path)?;
+    record.path = path.to_string_lossy().into_owned();
+    Ok(record)
+}
+
+/// The setting of a `KEY=VALUE` argument, as in `configure --set reclaim.enabled=true`.
+pub fn assignment(arg: &str) -> io::Result<(String, String)> {
+    let invalid = |why: &str| io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid setting '{}', {}.", arg, why));
+    let (key, value) = arg.split_once('=').ok_or_else(|| invalid("expected KEY=VALUE"))?;
+    let (key, value) = (key.trim(), value.trim());
+    if !key.starts_with(|c: char| c.is_ascii_alphabetic()) || !key.chars().all(|c| c.is_ascii_alphanumeric() || "_.-".contains(c)) {
+        return Err(invalid("keys are letters, digits, '_', '.' and '-'"));
+    }
+    if value.chars().any(char::is_control) {
+        return Err(invalid("the value has a control character"));
+    }
+    Ok((String::from(key), String::from(value)))
+}
+
+/// What kind of value `value` is, for the ones the agent reads as something else than text.
+fn kind(value: &str) -> Option<&'static str> {
+    match value {
+        "true" | "false" => Some("true or false"),
+        _ if value.parse::<i64>().is_ok() => Some("a whole number"),
+        _ => None,
+    }
+}
+
+/// Checks `values` can go into a config that has `current`: the package's own settings and the
+/// ones with flags are refused, and a setting that's true or false or a number has to stay one.
+pub fn validate(current: &BTreeMap<String, String>, values: &[(String, String)]) -> io::Result<()> {
+    for (key, value) in values {
+        let invalid = |why: String| io::Error::new(io::ErrorKind::InvalidInput, format!("Can't set {}: {}.", key, why));
+        if PACKAGE_SETTINGS.contains(&key.as_str()) {
+            return Err(invalid(format!("the {} package manages it", AGENT_PACKAGE)));
+        }
+        if let Some((_, flag)) = FLAG_SETTINGS.iter().find(|(k, _)| k == key) {
+            return Err(invalid(format!("use {}", flag)));
+        }
+        if let Some(expected) = current.get(key).and_then(|v| kind(v)) {
+            if kind(value) != Some(expected) {
+                return Err(invalid(format!("it's {}, not '{}'", expected, value)));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The settings of `configure --set` arguments, checked against the agent config as it is.
+pub fn settings(args: &[String]) -> io::Result<Vec<(String, String)>> {
+    let settings = args.iter().map(|a| assignment(a)).collect::<io::Result<Vec<_>>>()?;
+    let current = match load(AGENT_CONFIG) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+        loaded => loaded?,
+    };
+    validate(&current, &settings)?;
+    Ok(settings)
+}
+
+/// Sets `values` in the agent config and, with `restart`, restarts the agent if it's running
+/// so it picks them up.
+pub fn configure(values: &[(String, String)], restart: bool) -> io::Result<()> {
+    if values.is_empty() {
+        return Err(io::Error::other("Nothing to configure, see configure --help."));
+    }
+    set(AGENT_CONFIG, values)?;
+    // What went in is what the agent reads back.
+    if !runcmd::Context::current().dry_run && !script::recording() {
+        let written = load(AGENT_CONFIG)?;
+        if let Some((key, _)) = values.iter().find(|(k, v)| written.get(k) != Some(v)) {
+            return Err(io::Error::other(format!("{} didn't take {}, check the file.", AGENT_CONFIG, key)));
+        }
+    }
+    match restart {
+        true => Service::new(AGENT_PACKAGE).try_restart(),
+        false => {
+            log::log(Level::Info, &format!("Restart {} for the agent to pick up the settings.", AGENT_PACKAGE));
+            Ok(())
+        }
+    }
+}
+
+/// The settings of `before` that `after` lost or has different values for, leaving out the
+/// package's own.
+pub fn lost(before: &BTreeMap<String, String>, after: &BTreeMap<String, String>) -> Vec<(String, String)> {
+    before.iter()
+        .filter(|(k, v)| !PACKAGE_SETTINGS.contains(&k.as_str()) && after.get(*k) != Some(*v))
+        .map(|(k, v)| (k.clone(), v.clone()))
+        .collect()
+}
+
+/// Puts the settings of `before` back into the agent config where a package upgrade replaced
+/// them, the config belongs to the operator.
+pub fn restore(before: &BTreeMap<String, String>) -> io::Result<()> {
+    let lost = lost(before, &load(AGENT_CONFIG).unwrap_or_default());
+    if lost.is_empty() {
+        return Ok(());
+    }
+    set(AGENT_CONFIG, &lost).map(|_| ())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_skips_comments() {
+        let conf = parse("# comment\nlicensekey=abc\n\ndeviceid = host1 \nbogus\n");
+        assert_eq!(conf.len(), 2);
+        assert_eq!(conf["licensekey"], "abc");
+        assert_eq!(conf["deviceid"], "host1");
+    }
+
+    #[test]
+    fn update_keeps_other_lines() {
+        let data = "# licensekey=old\nlicensekey = old\npin=abc\n";
+        let updated = update(data, &[("deviceid", "host2"), ("licensekey", "new")]);
+        assert_eq!(updated, "# licensekey=old\nlicensekey=new\npin=abc\ndeviceid=host2\n");
+        assert_eq!(parse(&updated)["licensekey"], "new");
+    }
+
+    #[test]
+    fn upgrade_keeps_settings() {
+        let before = parse("licensekey=abc\ndeviceid=web1\nconfig_version=2\n");
+        let after = parse("licensekey=abc\nconfig_version=3\npolling=10\n");
+        assert_eq!(lost(&before, &after), [(String::from("deviceid"), String::from("web1"))]);
+    }
+
+    #[test]
+    fn settings_checked() {
+        assert_eq!(assignment(" reclaim.enabled = true").unwrap(), (String::from("reclaim.enabled"), String::from("true")));
+        assert_eq!(assignment("agent.url=http://a/?b=c").unwrap().1, "http://a/?b=c");
+        assert!(assignment("poll_interval").is_err());
+        assert!(assignment("1key=x").is_err());
+        assert!(assignment("key=a\nlicensekey=b").is_err());
+
+        let current = parse("agent.poll_interval=30
+reclaim.enabled=false
+config_version=2
+");
+        let set = |k: &str, v: &str| vec![(String::from(k), String::from(v))];
+        assert!(validate(&current, &set("agent.poll_interval", "60")).is_ok());
+        assert!(validate(&current, &set("new.setting", "anything")).is_ok());
+        assert_eq!(validate(&current, &set("reclaim.enabled", "yes")).unwrap_err().to_string(), "Can't set reclaim.enabled: it's true or false, not 'yes'.");
+        assert!(validate(&current, &set("agent.poll_interval", "30s")).is_err());
+        assert!(validate(&current, &set("config_version", "3")).is_err());
+        assert_eq!(validate(&current, &set("licensekey", "x")).unwrap_err().to_string(), "Can't set licensekey: use --license-key.");
+    }
+
+    #[test]
+    fn set_creates_the_file() {
+        // The config gets root's owner, only root can give it away.
+        if !crate::privsep::is_root() {
+            return;
+        }
+        let dir = std::env::temp_dir().join(format!("bitflux-agentconf-{}", std::process::id()));
+        let path = dir.join("bitfluxcollector.conf");
+        set(&path, &[("licensekey", "abc")]).unwrap();
+        let record = set(&path, &[("deviceid", "host1")]).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "licensekey=abc\ndeviceid=host1\n");
+        assert_eq!(record.path, path.to_string_lossy());
+        assert_eq!(record.mode, 0o640);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+}
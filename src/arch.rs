@@ -0,0 +1,162 @@
+use std::env;
+use std::ffi::CStr;
+
+/// CPU architectures bitflux ships for.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Arch {
+    X86_64,
+    /// Graviton, Ampere and the other ARM64 servers, and 64-bit Raspberry Pi OS.
+    Aarch64,
+    /// 32-bit ARM, Raspberry Pi OS and other single-board computer distros.
+    Armhf,
+}
+
+/// Every architecture, for messages and for looking up per-architecture builds.
+pub const ALL: [Arch; 3] = [Arch::X86_64, Arch::Aarch64, Arch::Armhf];
+
+impl Arch {
+
+    /// Parses `uname -m`, Debian and RPM spellings.
+    pub fn from_name(name: &str) -> Option<Arch> {
+        match name {
+            "x86_64" | "amd64" => Some(Arch::X86_64),
+            "aarch64" | "arm64" => Some(Arch::Aarch64),
+            "arm" | "armv7l" | "armhf" | "armv7hl" => Some(Arch::Armhf),
+            _ => None,
+        }
+    }
+
+    /// The architecture this installer was built for, None if bitflux doesn't support it.
+    pub fn current() -> Option<Arch> {
+        Arch::from_name(env::consts::ARCH)
+    }
+
+    /// The architecture of an ELF executable, from e_machine in its header.
+    pub fn of_elf(header: &[u8]) -> Option<Arch> {
+        if header.len() < 20 || &header[..4] != b"\x7fELF" {
+            return None;
+        }
+        // EI_DATA 1 is little endian, every architecture here is.
+        let machine = match header[5] {
+            1 => u16::from_le_bytes([header[18], header[19]]),
+            _ => u16::from_be_bytes([header[18], header[19]]),
+        };
+        match machine {
+            62 => Some(Arch::X86_64),
+            183 => Some(Arch::Aarch64),
+            40 => Some(Arch::Armhf),
+            _ => None,
+        }
+    }
+
+    /// How `uname -m` names it, and the installer builds are named.
+    pub fn name(self) -> &'static str {
+        match self {
+            Arch::X86_64 => "x86_64",
+            Arch::Aarch64 => "aarch64",
+            Arch::Armhf => "armhf",
+        }
+    }
+
+    pub fn deb(self) -> &'static str {
+        match self {
+            Arch::X86_64 => "amd64",
+            Arch::Aarch64 => "arm64",
+            Arch::Armhf => "armhf",
+        }
+    }
+
+    pub fn rpm(self) -> &'static str {
+        match self {
+            Arch::X86_64 => "x86_64",
+            Arch::Aarch64 => "aarch64",
+            Arch::Armhf => "armv7hl",
+        }
+    }
+
+    /// Memory preflight wants before installing.  ARM64 kernels are often built with 64K pages,
+    /// so the same workload needs more memory.
+    pub fn min_memory_mib(self) -> u64 {
+        match self {
+            Arch::X86_64 => 1024,
+            Arch::Aarch64 => 2048,
+            // Boards with 512M still run the agent, just not much else.
+            Arch::Armhf => 512,
+        }
+    }
+
+    /// Free space preflight wants in /boot for one more kernel.  ARM64 images are not
+    /// self-decompressing so they are several times the size of a bzImage.
+    pub fn min_boot_mib(self) -> u64 {
+        match self {
+            Arch::X86_64 => 150,
+            Arch::Aarch64 => 300,
+            Arch::Armhf => 100,
+        }
+    }
+
+}
+
+/// The running kernel's architecture as `uname -m` prints it.
+pub fn machine() -> String {
+    let mut name: libc::utsname = unsafe { std::mem::zeroed() };
+    if unsafe { libc::uname(&mut name) } != 0 {
+        return String::from(env::consts::ARCH);
+    }
+    unsafe { CStr::from_ptr(name.machine.as_ptr()) }.to_string_lossy().into_owned()
+}
+
+/// Checks that bitflux ships for `machine`, the kernel's `uname -m`, and that the installer,
+/// built for `built`, isn't running emulated on it.  A 32-bit ARM userland on a 64-bit kernel,
+/// like Raspberry Pi OS has, installs the 32-bit packages.
+pub fn check(machine: &str, built: Option<Arch>) -> Result<Arch, String> {
+    let host = Arch::from_name(machine).ok_or_else(|| unsupported(machine))?;
+    match built {
+        Some(built) if built == host => Ok(host),
+        Some(Arch::Armhf) if host == Arch::Aarch64 => Ok(Arch::Armhf),
+        Some(built) => Err(format!("This is the {} installer running emulated on a {} host, use the {} build.", built.name(), machine, host.name())),
+        None => Err(format!("This installer was built for {}, bitflux ships for {}.", env::consts::ARCH, ALL.map(Arch::name).join(", "))),
+    }
+}
+
+/// The error for a host `machine` bitflux has no packages for.
+pub fn unsupported(machine: &str) -> String {
+    format!("bitflux ships for {}, this host is {}.", ALL.map(Arch::name).join(", "), machine)
+}
+
+/// Page size of the running kernel.
+pub fn page_size() -> u64 {
+    let size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+    if size > 0 { size as u64 } else { 4096 }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn arch_names() {
+        assert_eq!(Arch::from_name("arm64"), Some(Arch::Aarch64));
+        assert_eq!(Arch::from_name("aarch64").map(Arch::deb), Some("arm64"));
+        assert_eq!(Arch::from_name("amd64").map(Arch::rpm), Some("x86_64"));
+        assert_eq!(Arch::from_name("armv7l").map(Arch::deb), Some("armhf"));
+        assert_eq!(Arch::from_name("riscv64"), None);
+        assert!(Arch::Aarch64.min_boot_mib() > Arch::X86_64.min_boot_mib());
+        assert!(page_size() >= 4096);
+    }
+
+    #[test]
+    fn host_architecture() {
+        assert_eq!(check("aarch64", Some(Arch::Aarch64)), Ok(Arch::Aarch64));
+        assert_eq!(check("aarch64", Some(Arch::Armhf)), Ok(Arch::Armhf));
+        assert_eq!(check("riscv64", Some(Arch::X86_64)).unwrap_err(), "bitflux ships for x86_64, aarch64, armhf, this host is riscv64.");
+        assert!(check("aarch64", Some(Arch::X86_64)).unwrap_err().contains("use the aarch64 build"));
+        let mut header = vec![0x7f, b'E', b'L', b'F', 2, 1];
+        header.resize(20, 0);
+        header[18] = 183;
+        assert_eq!(Arch::of_elf(&header), Some(Arch::Aarch64));
+        assert_eq!(Arch::of_elf(b"#!/bin/sh\necho hi\n\n\n"), None);
+    }
+
+}
@@ -0,0 +1,162 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::compat::{self, Installed, Severity};
+use crate::output;
+use crate::pkg::{PackageManager, AGENT_PACKAGE};
+use crate::profile::Profile;
+use crate::receipt::{stashed_file, Receipt, RECEIPT_DIR};
+use crate::runcmd::RunCmd;
+use crate::service::Service;
+use crate::unit::{self, DROPIN_DIR, HARDENING_DROPIN};
+use crate::verify::verify;
+
+/// Something on the system that differs from what the installer would leave behind.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Deviation {
+    /// "receipt", "file_diff", "package", "service", "hardening" or "compatibility".
+    pub area: String,
+    pub item: String,
+    pub detail: String,
+}
+
+/// Compliance report, nothing is changed while producing it.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct AuditReport {
+    pub checked: usize,
+    pub deviations: Vec<Deviation>,
+}
+
+impl AuditReport {
+
+    fn deviate(&mut self, area: &str, item: &str, detail: String) {
+        self.deviations.push(Deviation { area: String::from(area), item: String::from(item), detail });
+    }
+
+    pub fn print(&self) -> io::Result<()> {
+        let mut out = String::from("=== bitflux compliance audit ===\n");
+        for d in &self.deviations {
+            out.push_str(&format!("[{}] {}: {}\n", d.area, d.item, d.detail));
+        }
+        out.push_str(&format!("Checked {} items, {} deviations.\n", self.checked, self.deviations.len()));
+        output::print(&out)
+    }
+
+}
+
+/// Unified diff of the install time copy against the file as it is now.
+fn file_diff(stashed: &Path, path: &str) -> Option<String> {
+    if !stashed.exists() || !Path::new(path).exists() {
+        return None;
+    }
+    // 1 is a difference, 2 is trouble.
+    let out = RunCmd::args("diff", &["-u", &stashed.to_string_lossy(), path]).allow_exit_codes(&[1]).try_execute().ok()?;
+    if out.exitcode == 1 { Some(out.stdout) } else { None }
+}
+
+fn audit_receipt(report: &mut AuditReport, receipt: &Receipt, dir: &Path) {
+    let drift = verify(receipt);
+    report.checked += drift.checked;
+    for d in drift.drift {
+        if d.kind == "file" {
+            if let Some(diff) = file_diff(&stashed_file(dir, &d.expected), &d.item) {
+                report.deviate("file_diff", &d.item, diff);
+                continue;
+            }
+        }
+        report.deviate("receipt", &format!("{} {}", d.kind, d.item), format!("expected '{}', found '{}'", d.expected, d.actual));
+    }
+}
+
+fn audit_service(report: &mut AuditReport) {
+    report.checked += 1;
+    let status = Service::new(AGENT_PACKAGE).status();
+    if status.active != "active" {
+        report.deviate("service", AGENT_PACKAGE, format!("not active ({})", status.active));
+    }
+}
+
+fn audit_hardening(report: &mut AuditReport) {
+    let path = Path::new(DROPIN_DIR).join(HARDENING_DROPIN);
+    report.checked += 1;
+    match fs::read_to_string(&path) {
+        Ok(current) if Profile::value_variants().iter().any(|p| unit::render(*p) == current) => {}
+        Ok(_) => report.deviate("hardening", &path.to_string_lossy(), String::from("differs from every generated profile")),
+        Err(_) => report.deviate("hardening", &path.to_string_lossy(), String::from("missing, the service runs without sandboxing")),
+    }
+}
+
+fn audit_compat(report: &mut AuditReport) {
+    report.checked += 1;
+    let findings = compat::fetch_requirements().and_then(|req| Ok(compat::assess(&Installed::detect()?, &req)));
+    match findings {
+        Ok(findings) => {
+            for f in findings {
+                let severity = if f.severity == Severity::Critical { "critical" } else { "warning" };
+                report.deviate("compatibility", severity, f.message);
+            }
+        }
+        Err(e) => report.deviate("compatibility", "requirements", format!("could not assess: {}", e)),
+    }
+}
+
+/// Runs every check the installer would do, changing nothing.
+pub fn audit() -> AuditReport {
+    let mut report = AuditReport::default();
+    let dir = Path::new(RECEIPT_DIR);
+
+    report.checked += 1;
+    match Receipt::load_verified(dir) {
+        Ok(receipt) => audit_receipt(&mut report, &receipt, dir),
+        Err(e) => report.deviate("receipt", RECEIPT_DIR, e.to_string()),
+    }
+
+    if let Some(pm) = PackageManager::detect() {
+        for name in [AGENT_PACKAGE, pm.kernel_package()] {
+            report.checked += 1;
+            if pm.installed_version(name).is_none() {
+                report.deviate("package", name, String::from("not installed"));
+            }
+        }
+    }
+
+    audit_service(&mut report);
+    audit_hardening(&mut report);
+    audit_compat(&mut report);
+    report
+}
+
+/// `--audit`: prints the report and fails if anything deviates.
+pub fn run() -> io::Result<()> {
+    let report = audit();
+    report.print()?;
+    if !report.deviations.is_empty() {
+        return Err(io::Error::other(format!("Audit found {} deviations.", report.deviations.len())));
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_of_changed_file() {
+        let dir = std::env::temp_dir().join(format!("bitflux-audit-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("stashed"), "licensekey=abc\n").unwrap();
+        fs::write(dir.join("current"), "licensekey=xyz\n").unwrap();
+
+        let diff = file_diff(&dir.join("stashed"), &dir.join("current").to_string_lossy()).unwrap();
+        assert!(diff.contains("-licensekey=abc\n+licensekey=xyz\n"));
+        assert_eq!(file_diff(&dir.join("stashed"), &dir.join("stashed").to_string_lossy()), None);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+}
@@ -0,0 +1,203 @@
+use std::io;
+use std::sync::mpsc;
+use std::sync::Mutex;
+use std::thread;
+
+use crate::engine;
+use crate::executor;
+
+/// A unit of work for `run`.
+pub type Job<'a, T> = Box<dyn FnOnce() -> T + Send + 'a>;
+
+/// Runs every job on its own thread and returns their results in the order the jobs were given.
+/// For independent work that mostly waits, like commands and network probes.  A panicking job
+/// panics the caller once all the others are done.  The jobs run their commands with the
+/// caller's executor.
+pub fn run<'a, T: Send>(jobs: Vec<Job<'a, T>>) -> Vec<T> {
+    let executor = executor::current();
+    thread::scope(|scope| {
+        let handles: Vec<_> = jobs.into_iter().map(|job| {
+            let executor = executor.clone();
+            scope.spawn(move || {
+                let _using = executor::using(executor);
+                job()
+            })
+        }).collect();
+        handles.into_iter().map(|h| h.join().unwrap_or_else(|e| std::panic::resume_unwind(e))).collect()
+    })
+}
+
+/// Like run() with at most `limit` jobs running at a time, for fanning out to many hosts.
+pub fn run_limited<'a, T: Send>(jobs: Vec<Job<'a, T>>, limit: usize) -> Vec<T> {
+    let count = jobs.len();
+    let queue = Mutex::new(jobs.into_iter().enumerate());
+    let results = Mutex::new((0..count).map(|_| None).collect::<Vec<Option<T>>>());
+    let executor = executor::current();
+    thread::scope(|scope| {
+        for _ in 0..limit.clamp(1, count.max(1)) {
+            let (queue, results, executor) = (&queue, &results, executor.clone());
+            scope.spawn(move || {
+                let _using = executor::using(executor);
+                loop {
+                    let next = queue.lock().unwrap_or_else(|e| e.into_inner()).next();
+                    let Some((i, job)) = next else {
+                        break;
+                    };
+                    let result = job();
+                    results.lock().unwrap_or_else(|e| e.into_inner())[i] = Some(result);
+                }
+            });
+        }
+    });
+    results.into_inner().unwrap_or_else(|e| e.into_inner()).into_iter().flatten().collect()
+}
+
+/// A job for run_tasks() that only starts once the tasks named in `after` succeeded.
+pub struct Task<'a, T> {
+    pub name: &'static str,
+    pub after: Vec<&'static str>,
+    pub job: Job<'a, io::Result<T>>,
+}
+
+enum State<'a, T> {
+    Waiting(Job<'a, io::Result<T>>),
+    Running,
+    Done(bool),
+}
+
+/// Runs independent tasks at the same time, at most `limit` at once, each one as soon as the
+/// tasks it comes after succeeded and otherwise in engine::order_names() order.  A task after
+/// one that failed fails without running.  Returns the results in the order the tasks were
+/// given, or why the tasks can't be ordered.  Threads rather than an async runtime, the
+/// installer isn't async; callers that are have RunCmd::execute_async().
+pub fn run_tasks<'a, T: Send>(tasks: Vec<Task<'a, T>>, limit: usize) -> io::Result<Vec<io::Result<T>>> {
+    let names: Vec<&str> = tasks.iter().map(|t| t.name).collect();
+    let after: Vec<Vec<&str>> = tasks.iter().map(|t| t.after.clone()).collect();
+    let ordered = engine::order_names(&names, &after)?;
+    let deps: Vec<Vec<usize>> = after.iter().map(|after| {
+        after.iter().filter_map(|dep| names.iter().position(|n| n == dep)).collect()
+    }).collect();
+    let mut states: Vec<State<T>> = tasks.into_iter().map(|t| State::Waiting(t.job)).collect();
+    let mut results: Vec<Option<io::Result<T>>> = names.iter().map(|_| None).collect();
+    let (tx, rx) = mpsc::channel();
+    let executor = executor::current();
+    thread::scope(|scope| {
+        let mut running = 0;
+        loop {
+            // In order, so a task skipped or started here is seen by the ones after it.
+            for &i in &ordered {
+                if !matches!(states[i], State::Waiting(_)) {
+                    continue;
+                }
+                if let Some(&failed) = deps[i].iter().find(|&&d| matches!(states[d], State::Done(false))) {
+                    results[i] = Some(Err(io::Error::other(format!("Skipped {}, {} failed.", names[i], names[failed]))));
+                    states[i] = State::Done(false);
+                } else if running < limit.max(1) && deps[i].iter().all(|&d| matches!(states[d], State::Done(true))) {
+                    let State::Waiting(job) = std::mem::replace(&mut states[i], State::Running) else {
+                        unreachable!()
+                    };
+                    let (tx, executor) = (tx.clone(), executor.clone());
+                    scope.spawn(move || {
+                        let _using = executor::using(executor);
+                        tx.send((i, job()))
+                    });
+                    running += 1;
+                }
+            }
+            if running == 0 {
+                break;
+            }
+            let Ok((i, result)) = rx.recv() else {
+                break;
+            };
+            states[i] = State::Done(result.is_ok());
+            results[i] = Some(result);
+            running -= 1;
+        }
+    });
+    Ok(results.into_iter().flatten().collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn runs_concurrently_in_order() {
+        let start = Instant::now();
+        let jobs: Vec<Job<u32>> = (0..4)
+            .map(|i| -> Job<u32> {
+                Box::new(move || {
+                    thread::sleep(Duration::from_millis(200));
+                    i
+                })
+            })
+            .collect();
+        assert_eq!(run(jobs), vec![0, 1, 2, 3]);
+        assert!(start.elapsed() < Duration::from_millis(700));
+    }
+
+    #[test]
+    fn jobs_use_the_callers_executor() {
+        let mock = std::sync::Arc::new(executor::MockExecutor::new());
+        let _using = executor::using(mock.clone());
+        let missing = || crate::runcmd::RunCmd::args("/nonexistent/bitflux", &[]).execute_output().exitcode;
+        assert_eq!(run(vec![Box::new(missing)]), [0]);
+        assert_eq!(run_limited(vec![Box::new(missing)], 1), [0]);
+        assert_eq!(mock.commands(), ["/nonexistent/bitflux", "/nonexistent/bitflux"]);
+    }
+
+    #[test]
+    fn limited_keeps_order() {
+        let running = AtomicUsize::new(0);
+        let peak = AtomicUsize::new(0);
+        let jobs: Vec<Job<u32>> = (0..8)
+            .map(|i| -> Job<u32> {
+                let (running, peak) = (&running, &peak);
+                Box::new(move || {
+                    let now = running.fetch_add(1, Ordering::SeqCst) + 1;
+                    peak.fetch_max(now, Ordering::SeqCst);
+                    thread::sleep(Duration::from_millis(50));
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    i
+                })
+            })
+            .collect();
+        assert_eq!(run_limited(jobs, 3), (0..8).collect::<Vec<u32>>());
+        assert!(peak.load(Ordering::SeqCst) <= 3);
+    }
+
+    #[test]
+    fn tasks_wait_for_their_dependencies() {
+        let order = Mutex::new(Vec::new());
+        let task = |name: &'static str, after: Vec<&'static str>, ok: bool| -> Task<()> {
+            let order = &order;
+            Task {
+                name,
+                after,
+                job: Box::new(move || {
+                    thread::sleep(Duration::from_millis(20));
+                    order.lock().unwrap().push(name);
+                    if ok { Ok(()) } else { Err(io::Error::other("boom")) }
+                }),
+            }
+        };
+        let results = run_tasks(vec![
+            task("enable", vec!["unit", "config"], true),
+            task("unit", vec![], true),
+            task("config", vec![], true),
+            task("broken", vec![], false),
+            task("after-broken", vec!["broken"], true),
+        ], 2).unwrap();
+        assert!(results[..3].iter().all(|r| r.is_ok()));
+        let position = |name| order.lock().unwrap().iter().position(|n| *n == name);
+        assert!(position("enable") > position("unit") && position("enable") > position("config"));
+        assert!(results[4].as_ref().unwrap_err().to_string().contains("broken failed"));
+        assert!(!order.lock().unwrap().contains(&"after-broken"));
+        assert!(run_tasks(vec![task("loop", vec!["loop"], true)], 2).is_err());
+        assert!(!order.lock().unwrap().contains(&"loop"));
+    }
+
+}
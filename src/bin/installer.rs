@@ -0,0 +1,1022 @@
+/*
+ SPDX-License-Identifier: MIT
+ Copyright (c) 2022 BitFlux, Inc.
+
+ Installer script for bitflux
+*/
+
+use std::path::PathBuf;
+use std::process::exit;
+use std::time::Duration;
+#[cfg(target_os = "linux")]
+use std::time::Instant;
+
+use clap::{CommandFactory, Parser, Subcommand};
+
+use installer::runcmd;
+use installer::{budget, cloud, completions, config, exitcode, generate, i18n, interrupt, lock, log, output, perms, profiling, proxy, selfupdate, signature, state, sudo, tls, watchdog, workspace, writable};
+#[cfg(target_os = "linux")]
+use installer::{agentconf, audit, compat, data, device, diagnose, engine, events, fleet, history, image, install, journal, kernel, license, metrics, notify, plan, preflight, profile, reboot, repair, resume, sbom, script, selftest, serve, staged, status, summary, transcript, tui, uninstall, verify};
+#[cfg(target_os = "linux")]
+use installer::prompt::Prompt;
+
+#[derive(Parser)]
+#[command(version, about = "Installer for bitflux", arg_required_else_help = true)]
+struct Cli {
+    /// Update the installer to the latest release before running.
+    #[arg(long, global = true)]
+    auto_update: bool,
+
+    /// Only report how the system deviates from what the installer would set up, change nothing.
+    /// The command is not run.
+    #[arg(long, global = true)]
+    audit: bool,
+
+    /// Print every command the installer would run and every change it would make, with the
+    /// working directory and environment, without running or changing anything.
+    #[arg(long, global = true)]
+    dry_run: bool,
+
+    /// Print every step and external command before it runs, -vv also their output as it
+    /// comes, -vvv their environment, how they ran and how long they took.
+    #[arg(short, long, global = true, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Print nothing but a summary at the end of an install, upgrade, uninstall or repair:
+    /// the steps, warnings, error and whether to reboot.  The log file still gets everything,
+    /// nothing is asked.
+    #[cfg(target_os = "linux")]
+    #[arg(short, long, global = true, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Never ask anything on the terminal, take the default or fail instead.
+    #[arg(long, global = true)]
+    non_interactive: bool,
+
+    /// When another installer is running, wait for it to finish instead of failing, for at most
+    /// SECS when given.
+    #[arg(long, global = true, value_name = "SECS", num_args = 0..=1)]
+    wait: Option<Option<u64>>,
+
+    /// Give commands run as root the caller's PATH, LD_PRELOAD and LD_LIBRARY_PATH instead of a
+    /// safe PATH and none of them.
+    #[arg(long, global = true)]
+    keep_env: bool,
+
+    /// Ask for the sudo password before anything runs, from stdin when it isn't a terminal,
+    /// instead of when the first command needs root.  For runs as a user with sudo.
+    #[arg(short = 'K', long, global = true)]
+    ask_become_pass: bool,
+
+    /// Language of the prompts and messages, "de" or "pt_BR", instead of the locale's.  The
+    /// translations are read from /etc/bitflux/locale or /usr/share/bitflux/locale.
+    #[arg(long, global = true, value_name = "LANG")]
+    lang: Option<String>,
+
+    /// HTTP and HTTPS proxy for every download and package manager run, instead of the one in
+    /// /etc/bitflux/installer.toml or http_proxy and https_proxy.
+    #[arg(long, global = true, value_name = "URL")]
+    proxy: Option<String>,
+
+    /// Comma separated hosts and domains to reach without the proxy.
+    #[arg(long, global = true, value_name = "HOSTS")]
+    no_proxy: Option<String>,
+
+    /// Most a download takes of the link, bytes a second like 500K or 2M, instead of the
+    /// limit_rate in /etc/bitflux/installer.toml.
+    #[arg(long, global = true, value_name = "RATE")]
+    limit_rate: Option<String>,
+
+    /// Print debugging details, such as the TLS parameters negotiated with the backend.
+    #[arg(long, global = true)]
+    debug: bool,
+
+    /// Temporarily clear immutable attributes and remount read-only filesystems read-write
+    /// where the installer has to write.
+    #[arg(long, global = true)]
+    unlock: bool,
+
+    /// Print where the run spent its wall time, CPU and downloads, per step and per external
+    /// command, when it's done.
+    #[arg(long, global = true)]
+    profile_run: bool,
+
+    /// Seconds an external command may go without output or CPU time before it's considered
+    /// hung, 0 turns the watchdog off.
+    #[arg(long, global = true, value_name = "SECS", default_value_t = watchdog::DEFAULT_IDLE_SECS)]
+    hang_timeout: u64,
+
+    /// What to do with a hung command.
+    #[arg(long, global = true, value_enum, default_value = "ask")]
+    on_hang: watchdog::Policy,
+
+    /// Seconds any one step may take before its commands are killed and it fails, instead of
+    /// step_secs in /etc/bitflux/installer.toml.
+    #[arg(long, global = true, value_name = "SECS")]
+    step_timeout: Option<u64>,
+
+    /// Seconds all the steps of a run may take together, instead of install_secs.
+    #[arg(long, global = true, value_name = "SECS")]
+    install_timeout: Option<u64>,
+
+    /// What to do with the steps applied before one ran out of time, instead of on_timeout.
+    #[arg(long, global = true, value_enum, value_name = "POLICY")]
+    on_timeout: Option<budget::Policy>,
+
+    /// Write a CycloneDX SBOM of everything the installer put on the system to PATH.
+    #[arg(long, global = true, value_name = "PATH")]
+    sbom: Option<PathBuf>,
+
+    /// POST the outcome of an install or upgrade as JSON to URL when it finishes.
+    #[cfg(target_os = "linux")]
+    #[arg(long, global = true, value_name = "URL")]
+    notify_url: Option<String>,
+
+    /// Sign the webhook payload with the HMAC-SHA256 key in PATH.
+    #[cfg(target_os = "linux")]
+    #[arg(long, global = true, value_name = "PATH", requires = "notify_url")]
+    notify_secret_file: Option<PathBuf>,
+
+    /// Write the metrics of an install or upgrade for node_exporter's textfile collector to
+    /// PATH, instead of the [metrics] textfile of installer.toml.
+    #[cfg(target_os = "linux")]
+    #[arg(long, global = true, value_name = "PATH")]
+    metrics_textfile: Option<PathBuf>,
+
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+/// Everything that installs, changes or inspects an install only exists on Linux, other
+/// platforms get the management commands.
+#[derive(Subcommand)]
+enum Command {
+    /// Install bitflux.
+    #[cfg(target_os = "linux")]
+    Install {
+        /// What to install: agent, agent-kernel, debug or a variant defined in
+        /// /etc/bitflux/profiles.toml [default: agent-kernel].
+        #[arg(long, value_name = "NAME")]
+        profile: Option<String>,
+        /// Build the swaphints module for the running kernel with DKMS instead of installing
+        /// the bitflux kernel.
+        #[arg(long, conflicts_with = "bundle")]
+        dkms: bool,
+        /// Never access the network, install everything from --bundle.  Any step that would
+        /// need the network fails instead.
+        #[arg(long, requires = "bundle")]
+        offline: bool,
+        /// Pre-built bundle, a directory or tarball, to install the packages and license
+        /// activation from.
+        #[arg(long, value_name = "PATH")]
+        bundle: Option<PathBuf>,
+        /// License key for the agent.  Other users can see it in `ps` while the installer
+        /// runs, the answers file keeps it private.
+        #[arg(long, value_name = "KEY")]
+        license_key: Option<String>,
+        /// Name the agent reports this host as.
+        #[arg(long, value_name = "ID")]
+        device_id: Option<String>,
+        /// Take the install options from an answers file instead, and never ask anything.
+        /// Without this or any of the options above /etc/bitflux/install.toml is used if it exists.
+        #[arg(long, value_name = "PATH", conflicts_with_all = ["profile", "dkms", "offline", "bundle", "license_key", "device_id"])]
+        config: Option<PathBuf>,
+        /// Print what the install would do on this host and exit without changing anything.
+        #[arg(long)]
+        plan: bool,
+        /// Report for each step whether the install would change the host, changing nothing,
+        /// like Ansible's check mode.  `--output json` has `changed` overall and per step.
+        #[arg(long, conflicts_with_all = ["plan", "from_plan", "resume", "force", "reboot", "tui", "emit_script"])]
+        check: bool,
+        /// How to print the plan, or the outcome of the install.
+        #[arg(long, value_enum, default_value = "text")]
+        output: plan::Format,
+        /// Apply a plan saved with `--plan --output tfjson`, refusing if the host changed since.
+        #[arg(long, value_name = "FILE", conflicts_with_all = ["profile", "dkms", "offline", "bundle", "license_key", "device_id", "config", "plan", "skip_verify", "skip_mac_policy", "open_ports", "reboot", "fix_clock", "allow_unsupported_kernel", "release", "allow_downgrade"])]
+        from_plan: Option<PathBuf>,
+        /// Continue the install that failed with the options it had, skipping the steps it
+        /// completed and retrying the one it failed in.
+        #[arg(long, conflicts_with_all = ["profile", "dkms", "offline", "bundle", "license_key", "device_id", "config", "plan", "from_plan", "force", "skip_verify", "skip_mac_policy", "open_ports", "reboot", "fix_clock", "allow_unsupported_kernel", "release", "allow_downgrade", "tui", "emit_script"])]
+        resume: bool,
+        /// Redo every step, also the ones the host already has.  Without it a second install
+        /// only changes what differs.
+        #[arg(long, conflicts_with = "plan")]
+        force: bool,
+        /// Install --bundle without checking its signatures and checksums, for a bundle built
+        /// by hand.  Warns loudly.
+        #[arg(long, requires = "bundle")]
+        skip_verify: bool,
+        /// Go on without the bitflux SELinux policy or AppArmor profile where it can't be
+        /// loaded.  The agent or swaphints may then be denied, warns loudly.
+        #[arg(long)]
+        skip_mac_policy: bool,
+        /// Let connections to this port, "9100" or "514/udp", in through the active firewall:
+        /// firewalld, ufw or iptables.  Repeat for more, uninstall closes them again.
+        #[arg(long = "open-port", value_name = "PORT")]
+        open_ports: Vec<String>,
+        /// Reboot once the install is done and reported when it needs a reboot, a new kernel
+        /// or a Secure Boot key to enroll, for automated runs.
+        #[arg(long, conflicts_with = "plan")]
+        reboot: bool,
+        /// Set the clock with chrony or systemd-timesyncd first when it's too far off the
+        /// repository's or the license server's for TLS and the license activation.
+        #[arg(long, conflicts_with = "offline")]
+        fix_clock: bool,
+        /// Install even when the running kernel, for --dkms, or the bitflux kernel isn't of a
+        /// series in the compatibility matrix.
+        #[arg(long)]
+        allow_unsupported_kernel: bool,
+        /// Install this bitflux release, X.Y.Z, instead of the latest.  With --bundle it has to
+        /// be the bundle's.
+        #[arg(long = "version", value_name = "X.Y.Z")]
+        release: Option<String>,
+        /// Install --version, or the bundle's release, even when a newer one is installed.
+        #[arg(long)]
+        allow_downgrade: bool,
+        /// Write newline-delimited JSON events, step_started, command_executed, step_finished,
+        /// warning and error, to this file descriptor as the install goes.
+        #[arg(long, value_name = "N", conflicts_with = "events_file")]
+        events_fd: Option<i32>,
+        /// Append the events to this file or FIFO instead.
+        #[arg(long, value_name = "PATH")]
+        events_file: Option<PathBuf>,
+        /// Install in a full-screen terminal UI: dialogs for what's missing, then the steps
+        /// next to a live log.
+        #[arg(long, conflicts_with_all = ["plan", "from_plan"])]
+        tui: bool,
+        /// Change nothing, write a bash script of every command and file the install would run
+        /// and write to this file instead, for review and running by hand.  The license key is
+        /// read from $BITFLUX_LICENSE_KEY when the script runs.
+        #[arg(long, value_name = "PATH", conflicts_with_all = ["plan", "from_plan", "tui"])]
+        emit_script: Option<PathBuf>,
+        /// Install without showing what will change and asking to go ahead first.
+        #[arg(short, long)]
+        yes: bool,
+        /// Install into the root filesystem of an image mounted at this directory instead of
+        /// this host, for image builders.  The agent starts on the image's first boot.
+        #[arg(long, value_name = "DIR", conflicts_with_all = ["dkms", "plan", "check", "from_plan", "resume", "tui", "emit_script", "reboot", "fix_clock", "open_ports", "release", "allow_downgrade"])]
+        root: Option<PathBuf>,
+    },
+    /// Stop and remove the bitflux agent, its kernel module and the files, config and state the
+    /// installer put in place.
+    #[cfg(target_os = "linux")]
+    Uninstall {
+        /// What to do with the agent data.
+        #[arg(long, value_enum, default_value = "preserve")]
+        data: data::DataPolicy,
+        /// Also remove the agent data, its backups and the installer settings in /etc/bitflux.
+        #[arg(long, conflicts_with = "data")]
+        purge: bool,
+        /// Uninstall even when other modules, units or mounts still use bitflux.
+        #[arg(long)]
+        force: bool,
+    },
+    /// Show what of bitflux is installed and whether the agent runs.
+    #[cfg(target_os = "linux")]
+    Status {
+        #[arg(long, value_enum, default_value = "text")]
+        output: output::Format,
+    },
+    /// Change the agent's settings and restart it.
+    #[cfg(target_os = "linux")]
+    Configure {
+        /// License key for the agent.
+        #[arg(long, value_name = "KEY")]
+        license_key: Option<String>,
+        /// Name the agent reports this host as.
+        #[arg(long, value_name = "ID")]
+        device_id: Option<String>,
+        /// Set an agent setting, like `--set reclaim.enabled=true`.  Repeat for more, a
+        /// setting that's true or false or a number has to stay one.
+        #[arg(long = "set", value_name = "KEY=VALUE")]
+        settings: Vec<String>,
+        /// Leave the agent running with the settings it had, they apply when it's restarted.
+        #[arg(long)]
+        no_restart: bool,
+    },
+    /// Install bitflux on many hosts at once over SSH.
+    #[cfg(target_os = "linux")]
+    Fleet {
+        #[command(subcommand)]
+        command: FleetCommand,
+    },
+    /// Install bitflux on many hosts over SSH with any install options, showing each host's
+    /// progress as it goes.
+    #[cfg(target_os = "linux")]
+    Remote {
+        /// One `[user@]host[:port]` per line.  Logins other than root need passwordless sudo.
+        #[arg(long, value_name = "FILE")]
+        hosts: PathBuf,
+        /// Installer binary to copy instead of this one, or a directory of `installer-<arch>`
+        /// builds, as for `fleet install`.
+        #[arg(long, value_name = "PATH")]
+        installer: Option<PathBuf>,
+        /// Hosts installed at the same time.
+        #[arg(long, default_value_t = fleet::DEFAULT_PARALLEL)]
+        parallel: usize,
+        /// Also write the per-host results as JSON to PATH.
+        #[arg(long, value_name = "PATH")]
+        report: Option<PathBuf>,
+        #[command(subcommand)]
+        command: RemoteCommand,
+    },
+    /// Undo the changes of every install since the last rollback, newest first.
+    #[cfg(target_os = "linux")]
+    Rollback,
+    /// Make the kernel that ran before the bitflux install the default again and reboot.
+    #[cfg(target_os = "linux")]
+    RollbackKernel,
+    /// Replace this installer with the latest signed release.
+    SelfUpdate,
+    /// Print cloud-init user-data or a kickstart %post section that installs bitflux unattended
+    /// on first boot.  Without --license-key it's left as {{ license_key }} to fill in.
+    Generate {
+        #[arg(value_enum)]
+        format: generate::Format,
+        /// What to install: agent, agent-kernel, debug or a variant defined in
+        /// /etc/bitflux/profiles.toml here [default: agent-kernel].
+        #[arg(long, value_name = "NAME")]
+        profile: Option<String>,
+        /// License key to embed.  Anyone who can read the user-data can read it.
+        #[arg(long, value_name = "KEY")]
+        license_key: Option<String>,
+        /// Let connections to this port in through the firewall, like `install --open-port`.
+        #[arg(long = "open-port", value_name = "PORT")]
+        open_ports: Vec<String>,
+        /// Reboot when the install needs it.
+        #[arg(long)]
+        reboot: bool,
+        /// Embed this answers file instead.
+        #[arg(long, value_name = "PATH", conflicts_with_all = ["profile", "license_key", "open_ports", "reboot"])]
+        config: Option<PathBuf>,
+    },
+    /// Print the completion script for bash, zsh or fish, for packagers to ship or to source.
+    Completions {
+        #[arg(value_enum)]
+        shell: completions::Shell,
+    },
+    /// Print the man page, every command and option, in roff.
+    Manpage,
+    /// Upgrade the bitflux kernel and agent.
+    #[cfg(target_os = "linux")]
+    Upgrade {
+        /// Only report whether a newer release is available, change nothing.
+        #[arg(long, conflicts_with_all = ["staged", "force"])]
+        check: bool,
+        /// Install the new versions next to the current ones without switching, see promote/abort.
+        #[arg(long)]
+        staged: bool,
+        /// Upgrade despite non-critical incompatibilities.
+        #[arg(long)]
+        force: bool,
+        /// What to do with the agent data.
+        #[arg(long, value_enum, default_value = "migrate")]
+        data: data::DataPolicy,
+    },
+    /// Switch to a staged upgrade.
+    #[cfg(target_os = "linux")]
+    Promote,
+    /// Back out a staged upgrade.
+    #[cfg(target_os = "linux")]
+    Abort,
+    /// Re-apply only the parts of the install that drifted from the install receipt.
+    #[cfg(target_os = "linux")]
+    Repair,
+    /// Serve a local API over a Unix socket to plan, start and follow installs.
+    #[cfg(target_os = "linux")]
+    Serve {
+        #[arg(long, value_name = "PATH", default_value = serve::SOCKET_PATH)]
+        socket: PathBuf,
+        /// Also let members of this group use the API, only root can otherwise.
+        #[arg(long, value_name = "NAME")]
+        group: Option<String>,
+    },
+    /// Check that this host can take bitflux, change nothing.
+    #[cfg(target_os = "linux")]
+    Preflight {
+        /// Skip the checks that need the network.
+        #[arg(long)]
+        offline: bool,
+        /// Size the disk and memory checks for this profile: agent, agent-kernel, debug or a
+        /// variant defined in /etc/bitflux/profiles.toml [default: agent-kernel].
+        #[arg(long, value_name = "NAME")]
+        profile: Option<String>,
+        #[arg(long, value_enum, default_value = "text")]
+        output: output::Format,
+    },
+    /// Try the executor, shell, privilege escalation, downloads and templates on this host
+    /// before a real install, change nothing.
+    #[cfg(target_os = "linux")]
+    Selftest {
+        /// Skip the download.
+        #[arg(long)]
+        offline: bool,
+        #[arg(long, value_enum, default_value = "text")]
+        output: output::Format,
+    },
+    /// Check the system against the receipt written at install time and report drift.
+    #[cfg(target_os = "linux")]
+    Verify {
+        /// Print the report as JSON.
+        #[arg(long)]
+        json: bool,
+    },
+    /// List the runs that changed this host, the commands of one, or all about one command.
+    #[cfg(target_os = "linux")]
+    History {
+        /// The run, as `installer history` lists it.
+        run: Option<String>,
+        /// Show the command with this number.
+        #[arg(long, value_name = "N", requires = "run")]
+        step: Option<usize>,
+    },
+    /// Run a command of an earlier run again, as it was run then, for support sessions.
+    #[cfg(target_os = "linux")]
+    Replay {
+        /// The run, as `installer history` lists it.
+        run: String,
+        /// The command with this number, the last one that failed by default.
+        #[arg(long, value_name = "N")]
+        step: Option<usize>,
+    },
+    /// Collect the install log, journal, platform info, dmesg and the agent's journal into a
+    /// tarball for support, license keys and host names redacted.
+    #[cfg(target_os = "linux")]
+    Diagnose {
+        /// Where to write it, /var/tmp/bitflux-diagnose-<time>.tar.gz by default.
+        #[arg(long, value_name = "PATH")]
+        output: Option<PathBuf>,
+    },
+}
+
+#[cfg(target_os = "linux")]
+#[derive(Subcommand)]
+enum FleetCommand {
+    /// Copy this installer and the answers file to every host and install there.
+    Install {
+        /// One `[user@]host[:port]` per line.  Logins other than root need passwordless sudo.
+        #[arg(long, value_name = "FILE")]
+        hosts: PathBuf,
+        /// Answers file for the installs, see `install --config`.
+        #[arg(long, value_name = "PATH")]
+        config: Option<PathBuf>,
+        /// Installer binary to copy instead of this one, like the static build for old distros, or
+        /// a directory of `installer-<arch>` builds, picked by each host's `uname -m`.
+        #[arg(long, value_name = "PATH")]
+        installer: Option<PathBuf>,
+        /// Hosts installed at the same time.
+        #[arg(long, default_value_t = fleet::DEFAULT_PARALLEL)]
+        parallel: usize,
+        /// Also write the per-host results as JSON to PATH.
+        #[arg(long, value_name = "PATH")]
+        report: Option<PathBuf>,
+    },
+}
+
+#[cfg(target_os = "linux")]
+#[derive(Subcommand)]
+enum RemoteCommand {
+    /// Run `install` with these options on every host, never asking anything.  The files
+    /// --config, --bundle and --from-plan name here are copied along.
+    Install {
+        #[arg(trailing_var_arg = true, allow_hyphen_values = true, value_name = "INSTALL_OPTIONS")]
+        args: Vec<String>,
+    },
+}
+
+impl Command {
+
+    /// Commands that only look, they don't take the installer lock.
+    fn read_only(&self) -> bool {
+        match self {
+            #[cfg(target_os = "linux")]
+            Command::Verify { .. } => true,
+            #[cfg(target_os = "linux")]
+            Command::Install { plan, check, .. } => *plan || *check,
+            #[cfg(target_os = "linux")]
+            Command::Status { .. } => true,
+            #[cfg(target_os = "linux")]
+            Command::Upgrade { check, .. } => *check,
+            #[cfg(target_os = "linux")]
+            Command::Preflight { .. } => true,
+            #[cfg(target_os = "linux")]
+            Command::Selftest { .. } => true,
+            #[cfg(target_os = "linux")]
+            Command::History { .. } => true,
+            // Looking into a hung install mustn't wait for it.
+            #[cfg(target_os = "linux")]
+            Command::Diagnose { .. } => true,
+            // Changes other hosts, not this one.
+            #[cfg(target_os = "linux")]
+            Command::Fleet { .. } | Command::Remote { .. } => true,
+            // Only print.
+            Command::Generate { .. } | Command::Completions { .. } | Command::Manpage => true,
+            // Installs started over the API take the lock themselves.
+            #[cfg(target_os = "linux")]
+            Command::Serve { .. } => true,
+            _ => false,
+        }
+    }
+
+    /// The subcommand as typed, which the lock file tells other installers is running.
+    fn name(&self) -> &'static str {
+        match self {
+            #[cfg(target_os = "linux")]
+            Command::Install { .. } => "install",
+            #[cfg(target_os = "linux")]
+            Command::Uninstall { .. } => "uninstall",
+            #[cfg(target_os = "linux")]
+            Command::Status { .. } => "status",
+            #[cfg(target_os = "linux")]
+            Command::Configure { .. } => "configure",
+            #[cfg(target_os = "linux")]
+            Command::Fleet { .. } => "fleet",
+            #[cfg(target_os = "linux")]
+            Command::Remote { .. } => "remote",
+            #[cfg(target_os = "linux")]
+            Command::Rollback => "rollback",
+            #[cfg(target_os = "linux")]
+            Command::RollbackKernel => "rollback-kernel",
+            Command::SelfUpdate => "self-update",
+            Command::Generate { .. } => "generate",
+            Command::Completions { .. } => "completions",
+            Command::Manpage => "manpage",
+            #[cfg(target_os = "linux")]
+            Command::Upgrade { .. } => "upgrade",
+            #[cfg(target_os = "linux")]
+            Command::Promote => "promote",
+            #[cfg(target_os = "linux")]
+            Command::Abort => "abort",
+            #[cfg(target_os = "linux")]
+            Command::Repair => "repair",
+            #[cfg(target_os = "linux")]
+            Command::Serve { .. } => "serve",
+            #[cfg(target_os = "linux")]
+            Command::Preflight { .. } => "preflight",
+            #[cfg(target_os = "linux")]
+            Command::Selftest { .. } => "selftest",
+            #[cfg(target_os = "linux")]
+            Command::Verify { .. } => "verify",
+            #[cfg(target_os = "linux")]
+            Command::History { .. } => "history",
+            #[cfg(target_os = "linux")]
+            Command::Replay { .. } => "replay",
+            #[cfg(target_os = "linux")]
+            Command::Diagnose { .. } => "diagnose",
+        }
+    }
+
+    /// Runs on the defaults when installer.toml is broken, the commands to look into it or to
+    /// get rid of the installer mustn't fail on it.
+    fn tolerates_bad_config(&self) -> bool {
+        match self {
+            #[cfg(target_os = "linux")]
+            Command::Uninstall { .. } => true,
+            _ => self.read_only(),
+        }
+    }
+
+    /// What --quiet sums up the run as, None for the commands that only look and print it.
+    #[cfg(target_os = "linux")]
+    fn summarized(&self) -> Option<&'static str> {
+        match self {
+            Command::Install { plan: false, check: false, tui: false, .. } => Some("install"),
+            Command::Upgrade { check: false, .. } => Some("upgrade"),
+            Command::Uninstall { .. } => Some("uninstall"),
+            Command::Repair => Some("repair"),
+            _ => None,
+        }
+    }
+
+    /// Commands asked for a JSON result, stdout is kept for it.
+    fn json(&self) -> bool {
+        match self {
+            #[cfg(target_os = "linux")]
+            Command::Install { output, .. } => *output != plan::Format::Text,
+            #[cfg(target_os = "linux")]
+            Command::Status { output: format } | Command::Preflight { output: format, .. } | Command::Selftest { output: format, .. } => *format == output::Format::Json,
+            _ => false,
+        }
+    }
+
+}
+
+fn exit_with(e: &std::io::Error) -> ! {
+    eprintln!("{}", i18n::tr(&e.to_string()));
+    workspace::cleanup();
+    exit(exitcode::of(e));
+}
+
+#[cfg(target_os = "linux")]
+fn print_plan(opts: &install::Options, format: plan::Format) -> std::io::Result<()> {
+    match format {
+        plan::Format::Text => print!("{}", install::plan(opts)?.render()),
+        plan::Format::Tfjson | plan::Format::Json => output::json(&install::machine_plan(opts)?)?,
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn print_check(opts: &install::Options, format: plan::Format) -> std::io::Result<()> {
+    install::check(opts)?;
+    let steps = engine::steps();
+    match format {
+        plan::Format::Text => print!("{}", engine::changes(&steps)),
+        plan::Format::Json => output::json(&output::CheckResult { changed: steps.iter().any(|s| s.changed), steps })?,
+        plan::Format::Tfjson => return Err(std::io::Error::other("--output tfjson prints a plan, use --output json with --check.")),
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+fn audit() -> std::io::Result<()> {
+    audit::run()
+}
+
+#[cfg(target_os = "linux")]
+fn write_sbom(path: &std::path::Path) -> std::io::Result<()> {
+    sbom::write(path)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn audit() -> std::io::Result<()> {
+    Err(std::io::Error::other("--audit inspects a bitflux install and is only available on Linux."))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn write_sbom(_path: &std::path::Path) -> std::io::Result<()> {
+    Err(std::io::Error::other("--sbom describes a bitflux install and is only available on Linux."))
+}
+
+fn main() {
+    let cli = Cli::parse();
+    runcmd::init();
+    if cli.command.as_ref().is_some_and(Command::json) {
+        output::reserve_stdout().unwrap_or_else(|e| exit_with(&e));
+    }
+    perms::set_umask();
+    i18n::set_lang(cli.lang.as_deref()).unwrap_or_else(|e| exit_with(&e));
+    // Writing a script is a dry run that keeps what it would have done.
+    #[cfg(target_os = "linux")]
+    let dry_run = cli.dry_run || matches!(cli.command, Some(Command::Install { emit_script: Some(_), .. }));
+    #[cfg(not(target_os = "linux"))]
+    let dry_run = cli.dry_run;
+    // Quiet runs are unattended ones, the JSON result stands in for the summary.
+    #[cfg(target_os = "linux")]
+    let summarized = match &cli.command {
+        Some(command) if cli.quiet && !cli.audit && !dry_run && !command.json() => command.summarized(),
+        _ => None,
+    };
+    #[cfg(target_os = "linux")]
+    let non_interactive = cli.non_interactive || cli.quiet;
+    #[cfg(not(target_os = "linux"))]
+    let non_interactive = cli.non_interactive;
+    runcmd::Context { dry_run, verbosity: cli.verbose, non_interactive, keep_env: cli.keep_env }.set();
+    writable::allow_unlock(cli.unlock);
+    tls::set_debug(cli.debug);
+    log::set_level(if cli.verbose > 0 { log::Level::Debug } else { log::Level::Info });
+    // Running without root only keeps the console output, a dry run changes nothing.
+    if !dry_run {
+        let _ = log::open(log::LOG_PATH);
+    }
+    if cli.ask_become_pass {
+        sudo::ask_become_pass().unwrap_or_else(|e| exit_with(&e));
+    }
+    profiling::set_enabled(cli.profile_run);
+    watchdog::configure(Duration::from_secs(cli.hang_timeout), cli.on_hang);
+    let configured = match cli.audit || dry_run || cli.command.as_ref().is_none_or(Command::tolerates_bad_config) {
+        true => config::Config::load_or_default(config::INSTALLER_CONFIG),
+        false => config::Config::load(config::INSTALLER_CONFIG).unwrap_or_else(|e| exit_with(&e)),
+    };
+    proxy::Proxy { http: cli.proxy.clone(), https: cli.proxy.clone(), no_proxy: cli.no_proxy.clone() }
+        .or(configured.proxy.clone())
+        .or(proxy::Proxy::from_env())
+        .export();
+    configured.download.clone().or_rate(cli.limit_rate.clone()).set().unwrap_or_else(|e| exit_with(&e));
+    let timeouts = configured.timeouts.clone();
+    budget::Budgets {
+        install_secs: cli.install_timeout.or(timeouts.install_secs),
+        step_secs: cli.step_timeout.or(timeouts.step_secs),
+        on_timeout: cli.on_timeout.unwrap_or(timeouts.on_timeout),
+        ..timeouts
+    }.set();
+    #[cfg(target_os = "linux")]
+    let started = Instant::now();
+    #[cfg(target_os = "linux")]
+    let notified = match &cli.command {
+        _ if cli.audit || dry_run => None,
+        Some(Command::Install { plan: false, check: false, .. }) => Some("install"),
+        Some(Command::Upgrade { check: false, .. }) => Some("upgrade"),
+        _ => None,
+    };
+    #[cfg(target_os = "linux")]
+    let mut webhook = cli.notify_url.clone().map(|url| notify::Webhook { url, secret_file: cli.notify_secret_file.clone() });
+    #[cfg(target_os = "linux")]
+    let settings = match notified {
+        Some(_) => configured,
+        None => config::Config::default(),
+    };
+    #[cfg(target_os = "linux")]
+    let settings = config::Config { metrics: settings.metrics.clone().or_textfile(cli.metrics_textfile.clone()), ..settings };
+    #[cfg(target_os = "linux")]
+    if settings.metrics.textfile.is_some() {
+        profiling::collect();
+    }
+    let run = profiling::step("installer");
+
+    // Everything but the read-only reports keeps other installers out until we're done.
+    let read_only = cli.audit || dry_run || cli.command.as_ref().is_none_or(Command::read_only);
+    let _lock = match read_only {
+        true => None,
+        false => {
+            let wait = cli.wait.map(|secs| secs.map(Duration::from_secs));
+            if let Some(command) = &cli.command {
+                lock::set_command(command.name());
+            }
+            Some(state::lock(wait).unwrap_or_else(|e| exit_with(&e)))
+        }
+    };
+    if read_only && !cli.audit && !dry_run {
+        state::migrate_unlocked();
+    }
+    if !read_only {
+        // Ctrl-C reaches the running command and the run stops cleanly after it.
+        interrupt::catch().unwrap_or_else(|e| exit_with(&e));
+        cloud::settle();
+    }
+    // Runs that change the host keep what each command did, a replay would push out the run it
+    // replays.
+    #[cfg(target_os = "linux")]
+    if !read_only && !dry_run && !matches!(cli.command, Some(Command::Replay { .. })) {
+        let _ = history::start();
+    }
+
+    if cli.auto_update {
+        match selfupdate::auto_update() {
+            Err(e) if signature::is_signature_error(&e) => exit_with(&e),
+            Err(e) => eprintln!("Auto update failed, continuing with the current installer: {}", e),
+            Ok(()) => {}
+        }
+    }
+
+    // Scratch space for this run, children get it as TMPDIR instead of writing to /tmp.  What
+    // runs killed before they could clean up left behind goes first.
+    workspace::prune_in(workspace::WORKSPACE_ROOT);
+    let scratch = workspace::Workspace::create();
+    if let Ok(ws) = &scratch {
+        ws.export();
+    }
+
+    // Operators hear about unattended runs, along with where to find everything they printed.
+    #[cfg(target_os = "linux")]
+    let unattended = !runcmd::interactive();
+    #[cfg(target_os = "linux")]
+    let transcript = match notified {
+        Some(command) if unattended && !settings.notify.is_empty() => transcript::Transcript::start(transcript::TRANSCRIPT_DIR, command)
+            .map_err(|e| eprintln!("Not keeping a transcript of this run: {}", e))
+            .ok(),
+        _ => None,
+    };
+
+    // Before anything of the install runs, a dashboard sees it all.
+    #[cfg(target_os = "linux")]
+    if let Some(Command::Install { events_fd, events_file, .. }) = &cli.command {
+        let opened = match (events_fd, events_file) {
+            (Some(fd), _) => events::open_fd(*fd),
+            (None, Some(path)) => events::open_file(path),
+            (None, None) => Ok(()),
+        };
+        opened.unwrap_or_else(|e| exit_with(&e));
+    }
+    #[cfg(target_os = "linux")]
+    let install_json = matches!(cli.command, Some(Command::Install { plan: false, check: false, output: plan::Format::Json, .. }));
+    // Once the run can't fail to start anymore, everything else can go to the log only.
+    #[cfg(target_os = "linux")]
+    if summarized.is_some() {
+        output::silence().unwrap_or_else(|e| exit_with(&e));
+    }
+    let result = match cli.command {
+        _ if cli.audit => audit(),
+        #[cfg(target_os = "linux")]
+        Some(Command::Install { from_plan: Some(path), .. }) => install::apply_plan(path),
+        #[cfg(target_os = "linux")]
+        Some(Command::Install { resume: true, .. }) => resume::run(),
+        #[cfg(target_os = "linux")]
+        Some(Command::Install { profile, dkms, offline, bundle, license_key, device_id, config, plan, check, output, from_plan: None, force, skip_verify, skip_mac_policy, open_ports, reboot, fix_clock, allow_unsupported_kernel, release, allow_downgrade, tui, emit_script, yes, root, .. }) => {
+            let given = profile.is_some() || dkms || offline || bundle.is_some() || license_key.is_some() || device_id.is_some();
+            let opts = match config.or_else(|| install::default_answers().filter(|_| !given)) {
+                Some(path) => install::Options::load(path).inspect(|_| {
+                    // Everything was answered ahead of time, an unattended install mustn't wait for a reply.
+                    runcmd::Context { non_interactive: true, ..runcmd::Context::current() }.set();
+                }),
+                // Asked on a terminal when there's nothing to go by, a plan goes with the defaults.
+                // The TUI asks in its dialogs.
+                None if !given && !plan && !check && tui => Ok(install::Options::default()),
+                None if !given && !plan && !check && runcmd::interactive() => install::Options::ask(&mut Prompt::terminal()),
+                None => profile.as_deref().map(|name| profile::variant(name, profile::PROFILES_PATH)).transpose().map(|variant| {
+                    let mut opts = install::Options { dkms, offline, bundle, license_key, device_id, ..Default::default() };
+                    if let Some(variant) = variant {
+                        variant.apply(&mut opts);
+                    }
+                    opts
+                }),
+            };
+            match plan {
+                true => opts.and_then(|mut opts| {
+                    opts.version = release.or(opts.version.take());
+                    print_plan(&opts, output)
+                }),
+                false if check => opts.and_then(|mut opts| {
+                    opts.skip_mac_policy |= skip_mac_policy;
+                    opts.open_ports.extend(open_ports);
+                    opts.fix_clock |= fix_clock;
+                    opts.allow_unsupported_kernel |= allow_unsupported_kernel;
+                    opts.version = release.or(opts.version.take());
+                    opts.allow_downgrade |= allow_downgrade;
+                    print_check(&opts, output)
+                }),
+                false if output == plan::Format::Tfjson => Err(std::io::Error::other("--output tfjson prints a plan, add --plan or use --output json.")),
+                false if tui && output != plan::Format::Text => Err(std::io::Error::other("--tui draws on the terminal, leave out --output.")),
+                false => opts.and_then(|mut opts| {
+                    opts.force |= force;
+                    opts.skip_verify |= skip_verify;
+                    opts.skip_mac_policy |= skip_mac_policy;
+                    opts.open_ports.extend(open_ports);
+                    opts.reboot |= reboot;
+                    opts.fix_clock |= fix_clock;
+                    opts.allow_unsupported_kernel |= allow_unsupported_kernel;
+                    opts.version = release.or(opts.version.take());
+                    opts.allow_downgrade |= allow_downgrade;
+                    // The command line wins over the answers file.
+                    webhook = webhook.take().or_else(|| opts.webhook());
+                    if let Some(root) = root {
+                        return image::install(&opts, &root);
+                    }
+                    match (tui, emit_script) {
+                        (true, _) => tui::run(opts),
+                        (false, Some(path)) => {
+                            let secrets = opts.license_key.iter().map(|k| (k.clone(), String::from(script::LICENSE_KEY_VAR))).collect();
+                            script::start(secrets);
+                            // A fresh host gets every step, none is checked off.
+                            opts.force = true;
+                            install::run(&opts).and_then(|_| script::finish(&path)).map(|_| {
+                                println!("Wrote {}, review it and run it as root.", path.display());
+                            })
+                        }
+                        // Asked on a terminal, unless the answers came ahead of time.
+                        (false, None) if !yes && !dry_run && runcmd::interactive() => {
+                            install::confirm(&opts, &mut Prompt::terminal()).and_then(|_| install::run(&opts))
+                        }
+                        (false, None) => install::run(&opts),
+                    }
+                }),
+            }
+        }
+        #[cfg(target_os = "linux")]
+        Some(Command::Uninstall { data, purge, force }) => uninstall::run(data, purge, force),
+        #[cfg(target_os = "linux")]
+        Some(Command::Status { output }) => status::run(output),
+        #[cfg(target_os = "linux")]
+        Some(Command::Configure { license_key, device_id, settings, no_restart }) => {
+            let opts = install::Options { license_key, device_id, ..Default::default() };
+            // A new key is activated first, one the backend turns down never reaches the agent;
+            // the device id and the other settings are checked before that.
+            let valid = opts.device_id.as_deref().map_or(Ok(()), device::validate).map_err(std::io::Error::other);
+            let activated = valid.and_then(|_| agentconf::settings(&settings)).and_then(|settings| match &opts.license_key {
+                Some(key) => license::activate(key, opts.device_id.as_deref()).map(|_| settings),
+                None => Ok(settings),
+            });
+            activated.and_then(|settings| agentconf::configure(&[opts.agent_settings(), settings].concat(), !no_restart))
+        }
+        #[cfg(target_os = "linux")]
+        Some(Command::Fleet { command: FleetCommand::Install { hosts, config, installer, parallel, report } }) => {
+            let args = config.map(|c| vec![String::from("--config"), c.to_string_lossy().into_owned()]).unwrap_or_default();
+            fleet::install(&fleet::Options { hosts, args, installer, parallel, report, stream: false })
+        }
+        #[cfg(target_os = "linux")]
+        Some(Command::Remote { hosts, installer, parallel, report, command: RemoteCommand::Install { args } }) => {
+            fleet::install(&fleet::Options { hosts, args, installer, parallel, report, stream: true })
+        }
+        #[cfg(target_os = "linux")]
+        Some(Command::Rollback) => journal::run(),
+        #[cfg(target_os = "linux")]
+        Some(Command::RollbackKernel) => kernel::rollback(),
+        Some(Command::SelfUpdate) => selfupdate::self_update().map(|_| ()),
+        Some(Command::Generate { format, profile, license_key, open_ports, reboot, config }) => {
+            let opts = match config {
+                Some(path) => installer::install::Options::load(path),
+                None => generate::options(profile.as_deref(), installer::profile::PROFILES_PATH, license_key, open_ports, reboot),
+            };
+            opts.and_then(|opts| generate::run(format, &opts))
+        }
+        Some(Command::Completions { shell }) => {
+            print!("{}", completions::render(shell, &Cli::command()));
+            Ok(())
+        }
+        Some(Command::Manpage) => {
+            print!("{}", completions::manpage(&Cli::command()));
+            Ok(())
+        }
+        #[cfg(target_os = "linux")]
+        Some(Command::Serve { socket, group }) => {
+            serve::bind(&socket, group.as_deref()).and_then(|(listener, gid)| serve::run(listener, gid))
+        }
+        #[cfg(target_os = "linux")]
+        Some(Command::Verify { json }) => verify::run(json),
+        #[cfg(target_os = "linux")]
+        Some(Command::Diagnose { output }) => diagnose::run(output).map(|_| ()),
+        #[cfg(target_os = "linux")]
+        Some(Command::Preflight { offline, profile, output }) => profile.as_deref()
+            .map(|name| profile::variant(name, profile::PROFILES_PATH).map(|variant| variant.base))
+            .transpose()
+            .and_then(|profile| preflight::report(offline, profile.unwrap_or_default(), output)),
+        #[cfg(target_os = "linux")]
+        Some(Command::Selftest { offline, output }) => selftest::report(offline, output),
+        #[cfg(target_os = "linux")]
+        Some(Command::History { run, step }) => history::show(history::HISTORY_DIR, run.as_deref(), step),
+        #[cfg(target_os = "linux")]
+        Some(Command::Replay { run, step }) => history::replay(history::HISTORY_DIR, &run, step),
+        #[cfg(target_os = "linux")]
+        Some(Command::Repair) => repair::run(),
+        #[cfg(target_os = "linux")]
+        Some(Command::Upgrade { check: true, .. }) => staged::check(),
+        #[cfg(target_os = "linux")]
+        Some(Command::Upgrade { staged, force, data, .. }) => compat::check_upgrade(force).and_then(|_| {
+            if staged { staged::stage(data) } else { staged::upgrade(data) }
+        }),
+        #[cfg(target_os = "linux")]
+        Some(Command::Promote) => staged::promote(),
+        #[cfg(target_os = "linux")]
+        Some(Command::Abort) => staged::abort(),
+        None if cli.sbom.is_some() => Ok(()),
+        None => Err(std::io::Error::other("No command given, see --help.")),
+    };
+    let result = result.and_then(|_| match &cli.sbom {
+        Some(path) => write_sbom(path),
+        None => Ok(()),
+    });
+
+    drop(run);
+    profiling::report();
+
+    if let Err(e) = &result {
+        eprintln!("{}", i18n::tr(&e.to_string()));
+        #[cfg(target_os = "linux")]
+        events::emit(events::Event::Error { message: &e.to_string() });
+    }
+    #[cfg(target_os = "linux")]
+    if install_json {
+        let code = result.as_ref().err().map(exitcode::of).unwrap_or(0);
+        let outcome = notify::Payload::new("install", started.elapsed(), &result, code);
+        if let Err(e) = output::json(&output::InstallResult { outcome, changed: engine::steps().iter().any(|s| s.changed), steps: engine::steps(), reboot_required: reboot::required(), reboot_reasons: reboot::reasons() }) {
+            eprintln!("{}", e);
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    if let Some(command) = summarized {
+        let reboot_reasons = reboot::reasons();
+        let notice = reboot::notice(&reboot_reasons);
+        let summary = summary::Summary {
+            command: String::from(command),
+            duration: started.elapsed(),
+            steps: engine::steps(),
+            warnings: log::warnings().into_iter().filter(|w| *w != notice).collect(),
+            error: result.as_ref().err().map(|e| i18n::tr(&e.to_string())),
+            reboot_reasons,
+        };
+        if let Err(e) = summary.print() {
+            log::file(log::Level::Error, &e.to_string());
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    if let Some(command) = notified {
+        let code = result.as_ref().err().map(exitcode::of).unwrap_or(0);
+        let payload = notify::Payload::new(command, started.elapsed(), &result, code);
+        let transcript = transcript.map(transcript::Transcript::finish);
+        if let Some(Err(e)) = webhook.as_ref().map(|webhook| notify::send(webhook, &payload)) {
+            eprintln!("{}", e);
+        }
+        if let Some(Err(e)) = settings.metrics.textfile.as_ref().map(|path| metrics::write(path, &payload, &engine::steps(), &profiling::samples())) {
+            eprintln!("{}", e);
+        }
+        if unattended {
+            if let Err(e) = settings.notify.send(&payload, transcript.as_deref()) {
+                eprintln!("{}", e);
+            }
+        }
+    }
+
+    if let Err(e) = result {
+        workspace::cleanup();
+        exit(exitcode::of(&e));
+    }
+    #[cfg(target_os = "linux")]
+    if reboot::scheduled() {
+        if let Err(e) = reboot::now() {
+            eprintln!("Couldn't reboot: {}", e);
+            exit(exitcode::of(&e));
+        }
+    }
+}
@@ -0,0 +1,180 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, RwLock};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use clap::ValueEnum;
+use serde::Deserialize;
+
+use crate::interrupt;
+use crate::log::{self, Level};
+
+/// Between stopping a step's commands with SIGTERM and killing them.
+const KILL_GRACE: Duration = Duration::from_secs(5);
+
+/// What a run does with the steps it applied when one runs out of time.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum Policy {
+    /// Roll them back, as when a step fails.
+    #[default]
+    Rollback,
+    /// Leave them for `installer rollback` or for the run to resume.
+    Abort,
+}
+
+/// How long steps and whole runs may take, the [timeouts] table of installer.toml.  None of
+/// them has a limit by default.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Budgets {
+    /// Seconds all the steps of a run may take together.
+    pub install_secs: Option<u64>,
+    /// Seconds a step may take, unless `steps` gives it its own.
+    pub step_secs: Option<u64>,
+    /// Seconds by step name, `kernel = 1800`.
+    pub steps: BTreeMap<String, u64>,
+    pub on_timeout: Policy,
+}
+
+/// The budgets set(), none until then.
+static BUDGETS: RwLock<Budgets> = RwLock::new(Budgets { install_secs: None, step_secs: None, steps: BTreeMap::new(), on_timeout: Policy::Rollback });
+
+impl Budgets {
+
+    /// Makes these the budgets of every run from now on.
+    pub fn set(self) {
+        *BUDGETS.write().unwrap_or_else(|e| e.into_inner()) = self;
+    }
+
+    /// How long `step` may take when the run began `elapsed` ago: its own budget, or no more
+    /// than what's left of the run's.  None without either.
+    pub fn of(&self, step: &str, elapsed: Duration) -> Option<Duration> {
+        let own = self.steps.get(step).copied().or(self.step_secs).map(Duration::from_secs);
+        let left = self.install_secs.map(|secs| Duration::from_secs(secs).saturating_sub(elapsed));
+        match (own, left) {
+            (Some(own), Some(left)) => Some(own.min(left)),
+            (own, left) => own.or(left),
+        }
+    }
+
+}
+
+pub fn current() -> Budgets {
+    BUDGETS.read().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// The command lines of `pids`, from /proc.
+fn commands(pids: &[u32]) -> Vec<String> {
+    pids.iter()
+        .filter_map(|pid| {
+            let cmdline = fs::read(format!("/proc/{}/cmdline", pid)).ok()?;
+            let args: Vec<String> = cmdline.split(|b| *b == 0).filter(|a| !a.is_empty()).map(|a| String::from_utf8_lossy(a).into_owned()).collect();
+            Some(format!("{} (pid {})", args.join(" "), pid))
+        })
+        .collect()
+}
+
+/// Watches a step for running out of its budget: then its commands are stopped, the ones
+/// it starts afterwards killed, and finish() fails.
+pub struct Deadline {
+    step: String,
+    budget: Duration,
+    expired: Arc<AtomicBool>,
+    /// What was running when time ran out.
+    running: Arc<Mutex<Vec<String>>>,
+    stop: Option<Sender<()>>,
+    watcher: Option<JoinHandle<()>>,
+}
+
+impl Deadline {
+
+    /// Starts watching `step`, nothing to watch without a `budget`.
+    pub fn start(step: &str, budget: Option<Duration>) -> Deadline {
+        let expired = Arc::new(AtomicBool::new(false));
+        let running = Arc::new(Mutex::new(Vec::new()));
+        let mut deadline = Deadline { step: String::from(step), budget: budget.unwrap_or_default(), expired, running, stop: None, watcher: None };
+        let Some(budget) = budget else {
+            return deadline;
+        };
+        let (stop, stopped) = mpsc::channel::<()>();
+        let (expired, running, name) = (deadline.expired.clone(), deadline.running.clone(), String::from(step));
+        deadline.watcher = Some(thread::spawn(move || {
+            if stopped.recv_timeout(budget) != Err(RecvTimeoutError::Timeout) {
+                return;
+            }
+            let commands = commands(&interrupt::running());
+            log::log(Level::Error, &format!("Step {} ran out of its {}s, stopping: {}", name, budget.as_secs(), match commands.is_empty() {
+                true => String::from("no command was running"),
+                false => commands.join(", "),
+            }));
+            *running.lock().unwrap_or_else(|e| e.into_inner()) = commands;
+            expired.store(true, Ordering::SeqCst);
+            interrupt::cancel();
+            if stopped.recv_timeout(KILL_GRACE) == Err(RecvTimeoutError::Timeout) {
+                interrupt::kill_all(libc::SIGKILL);
+            }
+        }));
+        deadline.stop = Some(stop);
+        deadline
+    }
+
+    /// Stops watching.  Fails when the step ran out of time, whatever it returned.
+    pub fn finish(mut self) -> io::Result<()> {
+        self.stop_watching();
+        if !self.expired.load(Ordering::SeqCst) {
+            return Ok(());
+        }
+        let running = self.running.lock().unwrap_or_else(|e| e.into_inner()).clone();
+        let mut message = format!("Step {} didn't finish within its {}s budget", self.step, self.budget.as_secs());
+        if !running.is_empty() {
+            message.push_str(&format!(", it was running {}", running.join(", ")));
+        }
+        message.push('.');
+        Err(io::Error::new(io::ErrorKind::TimedOut, message))
+    }
+
+    fn stop_watching(&mut self) {
+        drop(self.stop.take());
+        if let Some(watcher) = self.watcher.take() {
+            let _ = watcher.join();
+        }
+        if self.expired.load(Ordering::SeqCst) {
+            interrupt::resume();
+        }
+    }
+
+}
+
+impl Drop for Deadline {
+
+    fn drop(&mut self) {
+        self.stop_watching();
+    }
+
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn step_budgets() {
+        let budgets = Budgets { install_secs: Some(600), step_secs: Some(120), steps: BTreeMap::from([(String::from("kernel"), 300)]), ..Default::default() };
+        assert_eq!(budgets.of("agent", Duration::ZERO), Some(Duration::from_secs(120)));
+        assert_eq!(budgets.of("kernel", Duration::from_secs(100)), Some(Duration::from_secs(300)));
+        assert_eq!(budgets.of("kernel", Duration::from_secs(500)), Some(Duration::from_secs(100)));
+        assert_eq!(budgets.of("kernel", Duration::from_secs(700)), Some(Duration::ZERO));
+        assert_eq!(Budgets::default().of("kernel", Duration::ZERO), None);
+
+        // One that runs out would stop the commands of the other tests.
+        assert!(Deadline::start("agent", None).finish().is_ok());
+        assert!(Deadline::start("agent", Some(Duration::from_secs(60))).finish().is_ok());
+    }
+
+}
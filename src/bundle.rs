@@ -0,0 +1,262 @@
+use std::fs;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::checksum::sha256_file;
+use crate::license::LICENSE_PATH;
+use crate::log::{self, Level};
+use crate::perms::{self, FileKind};
+use crate::pkg::PackageManager;
+use crate::runcmd::{self, RunCmd};
+use crate::signature::{self, SignatureError};
+use crate::workspace::Workspace;
+
+/// Index of an offline bundle, signed by the release key in bundle.json.minisig.
+pub const INDEX: &str = "bundle.json";
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct BundlePackage {
+    pub name: String,
+    /// Path inside the bundle.
+    pub file: String,
+    pub sha256: String,
+    /// True for the kernel packages, they only get installed with a kernel profile.
+    #[serde(default)]
+    pub kernel: bool,
+    /// Detached GPG signature of `file`, checked against the index's keyring.
+    #[serde(default)]
+    pub signature: Option<String>,
+}
+
+/// A file in the bundle and its checksum.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+pub struct BundleFile {
+    pub file: String,
+    pub sha256: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+pub struct BundleIndex {
+    pub version: String,
+    pub packages: Vec<BundlePackage>,
+    /// License activation file produced for this host by the BitFlux portal, for hosts that
+    /// can't activate online.
+    #[serde(default)]
+    pub activation: Option<String>,
+    /// sha256sum(1) style manifest of the rest of the bundle, kernel module sources, configs
+    /// and the activation.
+    #[serde(default)]
+    pub manifest: Option<BundleFile>,
+    /// Keyring the packages' detached signatures verify against, trusted through its checksum
+    /// in the signed index.
+    #[serde(default)]
+    pub keyring: Option<BundleFile>,
+}
+
+/// A pre-built bundle with everything an offline install needs: packages for each distro
+/// family, and optionally the license activation.
+pub struct Bundle {
+    pub dir: PathBuf,
+    pub index: BundleIndex,
+    /// Where a tarball bundle got unpacked, removed with the bundle.
+    _unpacked: Option<Workspace>,
+}
+
+impl Bundle {
+
+    /// Opens a bundle directory or unpacks a bundle tarball, then checks the index signature,
+    /// the checksum of every file it and the manifest list and the packages' GPG signatures.
+    /// Nothing from the bundle is used unverified, unless not `verify`.
+    pub fn open(path: &Path, verify: bool) -> io::Result<Bundle> {
+        let (dir, unpacked) = if path.is_dir() {
+            (path.to_path_buf(), None)
+        } else {
+            let ws = Workspace::create()?;
+            let out = RunCmd::args("tar", &["-xf", &path.to_string_lossy(), "-C", &ws.path().to_string_lossy()]).execute_output();
+            if out.exitcode != 0 {
+                return Err(io::Error::other(format!("Can't unpack bundle '{}': {}", path.display(), out.stderr.trim())));
+            }
+            (ws.path().to_path_buf(), Some(ws))
+        };
+
+        let data = fs::read(dir.join(INDEX))
+            .map_err(|e| io::Error::new(e.kind(), format!("'{}' is not a bitflux bundle, no {}: {}", path.display(), INDEX, e)))?;
+        if verify {
+            let sig = fs::read_to_string(dir.join(format!("{}.minisig", INDEX)))
+                .map_err(|e| io::Error::new(e.kind(), format!("Bundle '{}' is not signed: {}", path.display(), e)))?;
+            signature::verify_release(INDEX, &data, &sig)?;
+        }
+        let index: BundleIndex = serde_json::from_slice(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+
+        let bundle = Bundle { dir, index, _unpacked: unpacked };
+        // Unverified or not, nothing outside the bundle is read.
+        for entry in &bundle.index.packages {
+            bundle.path(&entry.file)?;
+        }
+        if let Some(file) = &bundle.index.activation {
+            bundle.path(file)?;
+        }
+        match verify {
+            true => bundle.verify()?,
+            false => log::log(Level::Warn, &format!(
+                "WARNING: --skip-verify, bundle {} is installed WITHOUT checking its signatures and checksums. \
+                 A tampered or corrupt bundle goes on this host as it is.", path.display()
+            )),
+        }
+        Ok(bundle)
+    }
+
+    /// Where `file` in the bundle is, refusing one outside it.
+    fn path(&self, file: &str) -> io::Result<PathBuf> {
+        // The index is signed, but a path escaping the bundle still has no business being read.
+        if Path::new(file).components().any(|c| !matches!(c, Component::Normal(_))) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Bundle file '{}' is outside the bundle.", file)));
+        }
+        Ok(self.dir.join(file))
+    }
+
+    fn check(&self, file: &str, sha256: &str) -> io::Result<()> {
+        let actual = sha256_file(self.path(file)?)?;
+        if !actual.eq_ignore_ascii_case(sha256) {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, format!("Bundle file '{}' is corrupt, checksum mismatch.", file)));
+        }
+        Ok(())
+    }
+
+    /// Checks every file the index and the manifest list and the packages' signatures.
+    fn verify(&self) -> io::Result<()> {
+        if let Some(keyring) = &self.index.keyring {
+            self.check(&keyring.file, &keyring.sha256)?;
+        }
+        for entry in &self.index.packages {
+            self.check(&entry.file, &entry.sha256)?;
+            if let Some(sig) = &entry.signature {
+                let keyring = self.index.keyring.as_ref().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData,
+                    format!("Bundle file '{}' has a GPG signature but the bundle has no keyring.", entry.file)))?;
+                self.gpgv(&keyring.file, sig, &entry.file)?;
+            }
+        }
+        if let Some(manifest) = &self.index.manifest {
+            self.check(&manifest.file, &manifest.sha256)?;
+            for (sha256, file) in parse_manifest(&fs::read_to_string(self.path(&manifest.file)?)?)? {
+                self.check(&file, &sha256)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the detached signature `sig` of `file` against `keyring`, all in the bundle.
+    fn gpgv(&self, keyring: &str, sig: &str, file: &str) -> io::Result<()> {
+        let (keyring, sig, data) = (self.path(keyring)?, self.path(sig)?, self.path(file)?);
+        let out = RunCmd::args("gpgv", &["--keyring", &keyring.to_string_lossy(), &sig.to_string_lossy(), &data.to_string_lossy()])
+            .try_execute_output()
+            .map_err(io::Error::from)?;
+        if out.exitcode != 0 {
+            return Err(SignatureError::io(file, out.stderr.trim()));
+        }
+        Ok(())
+    }
+
+    /// The bundle's packages in `pm`'s format.
+    pub fn packages(&self, pm: &PackageManager, kernel: bool) -> Vec<(&BundlePackage, PathBuf)> {
+        let ext = match pm {
+            PackageManager::Apt => "deb",
+            _ => "rpm",
+        };
+        self.index.packages.iter()
+            .filter(|p| kernel || !p.kernel)
+            .map(|p| (p, self.dir.join(&p.file)))
+            .filter(|(_, path)| path.extension().and_then(|e| e.to_str()) == Some(ext))
+            .collect()
+    }
+
+    /// Installs the offline license activation, if the bundle has one.
+    pub fn install_activation(&self) -> io::Result<bool> {
+        let Some(file) = &self.index.activation else {
+            return Ok(false);
+        };
+        let target = Path::new(LICENSE_PATH);
+        if runcmd::dry_run(&format!("install the license activation as {}", LICENSE_PATH)) {
+            return Ok(true);
+        }
+        if let Some(parent) = target.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::copy(self.dir.join(file), target)?;
+        perms::apply(target, FileKind::Secret)?;
+        Ok(true)
+    }
+
+}
+
+/// The checksums and paths in a sha256sum(1) manifest, text or binary mode.
+pub fn parse_manifest(data: &str) -> io::Result<Vec<(String, String)>> {
+    let mut entries = Vec::new();
+    for line in data.lines().filter(|l| !l.trim().is_empty()) {
+        let entry = line.split_once(' ').and_then(|(sha256, rest)| {
+            let file = rest.strip_prefix(' ').or_else(|| rest.strip_prefix('*'))?;
+            (sha256.len() == 64 && sha256.chars().all(|c| c.is_ascii_hexdigit())).then(|| (String::from(sha256), String::from(file)))
+        });
+        entries.push(entry.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, format!("Bad manifest line '{}'.", line)))?);
+    }
+    Ok(entries)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn packages_by_format() {
+        let index: BundleIndex = serde_json::from_str(r#"{
+            "version": "1.4.0",
+            "packages": [
+                {"name": "bitfluxcollector", "file": "deb/bitfluxcollector_1.4.0_amd64.deb", "sha256": "aa"},
+                {"name": "linux-image-swaphints", "file": "deb/linux-image-swaphints_6.1_amd64.deb", "sha256": "bb", "kernel": true},
+                {"name": "bitfluxcollector", "file": "rpm/bitfluxcollector-1.4.0.x86_64.rpm", "sha256": "cc"}
+            ]
+        }"#).unwrap();
+        let bundle = Bundle { dir: PathBuf::from("/media/bundle"), index, _unpacked: None };
+
+        let debs = bundle.packages(&PackageManager::Apt, true);
+        assert_eq!(debs.len(), 2);
+        assert_eq!(debs[1].1, PathBuf::from("/media/bundle/deb/linux-image-swaphints_6.1_amd64.deb"));
+        assert_eq!(bundle.packages(&PackageManager::Apt, false).len(), 1);
+        assert_eq!(bundle.packages(&PackageManager::Dnf, true)[0].0.file, "rpm/bitfluxcollector-1.4.0.x86_64.rpm");
+        assert!(bundle.check("../etc/shadow", "aa").is_err());
+    }
+
+    #[test]
+    fn manifests() {
+        let sha256 = "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855";
+        let entries = parse_manifest(&format!("{0}  dkms/swaphints-1.4.0.tar.gz\n{0} *config/agent.conf\n", sha256)).unwrap();
+        assert_eq!(entries, [
+            (String::from(sha256), String::from("dkms/swaphints-1.4.0.tar.gz")),
+            (String::from(sha256), String::from("config/agent.conf")),
+        ]);
+        assert!(parse_manifest("abc  file\n").is_err());
+        assert!(parse_manifest(&format!("{}\n", sha256)).is_err());
+    }
+
+    #[test]
+    fn verifies_the_manifest() {
+        let dir = std::env::temp_dir().join(format!("bitflux-bundle-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("agent.conf"), "interval = 30\n").unwrap();
+        let manifest = format!("{}  agent.conf\n", crate::checksum::sha256_hex(b"interval = 30\n"));
+        fs::write(dir.join("SHA256SUMS"), &manifest).unwrap();
+        let index = BundleIndex {
+            manifest: Some(BundleFile { file: String::from("SHA256SUMS"), sha256: crate::checksum::sha256_hex(manifest.as_bytes()) }),
+            ..Default::default()
+        };
+        let bundle = Bundle { dir: dir.clone(), index, _unpacked: None };
+        assert!(bundle.verify().is_ok());
+        fs::write(dir.join("agent.conf"), "interval = 1\n").unwrap();
+        assert!(bundle.verify().is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+}
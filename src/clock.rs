@@ -0,0 +1,70 @@
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::preflight::{self, MAX_SKEW_SECS};
+use crate::runcmd::{self, RunCmd};
+
+/// How long NTP gets to bring the clock in line.
+const SYNC_TIMEOUT: Duration = Duration::from_secs(30);
+const POLL: Duration = Duration::from_secs(1);
+
+/// Steps the clock to NTP time: chronyd steps it right away when it runs, otherwise NTP is
+/// turned on for systemd-timesyncd and it gets SYNC_TIMEOUT to sync.
+pub fn sync() -> io::Result<()> {
+    if RunCmd::args("systemctl", &["is-active", "--quiet", "chronyd"]).execute_output().exitcode == 0 {
+        // waitsync has it take a few samples first, makestep would otherwise slew on what it had.
+        RunCmd::args("chronyc", &["waitsync", "5", "1"]).execute_output();
+        return RunCmd::args("chronyc", &["-a", "makestep"]).try_execute().map(|_| ()).map_err(io::Error::from);
+    }
+    RunCmd::args("timedatectl", &["set-ntp", "true"]).try_execute().map_err(io::Error::from)?;
+    let started = Instant::now();
+    while started.elapsed() < SYNC_TIMEOUT {
+        let synced = RunCmd::args("timedatectl", &["show", "-p", "NTPSynchronized", "--value"]).execute_output();
+        if synced.stdout.trim() == "yes" {
+            return Ok(());
+        }
+        thread::sleep(POLL);
+    }
+    Err(io::Error::other(format!("The clock didn't sync with NTP within {}s, check that the NTP servers can be reached.", SYNC_TIMEOUT.as_secs())))
+}
+
+/// Sets the clock with sync() and makes sure it's then within MAX_SKEW_SECS of `url`'s.
+pub fn fix(url: &str) -> io::Result<()> {
+    if runcmd::dry_run(&format!("step the clock to NTP time with chronyc or timedatectl, when it's off {}", url)) {
+        return Ok(());
+    }
+    sync()?;
+    match preflight::skew(url) {
+        Some(skew) if skew > MAX_SKEW_SECS => Err(io::Error::other(format!(
+            "The clock is still {}s off {} after syncing with NTP, set it with `timedatectl set-time` and run the install again.",
+            skew, url
+        ))),
+        _ => Ok(()),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::executor::{self, MockExecutor, Reply};
+
+    #[test]
+    fn chrony_or_timesyncd() {
+        let mock = Arc::new(MockExecutor::new());
+        mock.on("systemctl is-active", Reply::ok(""));
+        let _using = executor::using(mock.clone());
+        sync().unwrap();
+        assert_eq!(mock.commands().last().map(String::as_str), Some("chronyc -a makestep"));
+
+        let mock = Arc::new(MockExecutor::new());
+        mock.on("systemctl is-active", Reply::exit(3, ""))
+            .on("timedatectl show", Reply::ok("yes\n"));
+        let _using = executor::using(mock.clone());
+        sync().unwrap();
+        assert!(mock.commands().iter().any(|c| c == "timedatectl set-ntp true"));
+    }
+
+}
@@ -0,0 +1,198 @@
+use std::fs;
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::log::{self, Level};
+use crate::offline;
+use crate::privsep::unprivileged_cmd;
+use crate::runcmd::{which, RunCmd};
+
+pub const DMI_DIR: &str = "/sys/class/dmi/id";
+/// Azure marks its VMs with this chassis asset tag, sys_vendor is plain Microsoft on Hyper-V too.
+pub const AZURE_ASSET_TAG: &str = "7783-7084-3265-9085-8269-3286-77";
+const IMDS_TIMEOUT_SECS: &str = "2";
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Cloud {
+    Aws,
+    Gcp,
+    Azure,
+}
+
+impl Cloud {
+
+    /// Recognizes the cloud from the DMI sys_vendor, product_name and chassis_asset_tag.
+    pub fn from_dmi(vendor: &str, product: &str, asset_tag: &str) -> Option<Cloud> {
+        if vendor.trim() == "Amazon EC2" || product.trim().starts_with("Amazon EC2") {
+            Some(Cloud::Aws)
+        } else if product.trim() == "Google Compute Engine" {
+            Some(Cloud::Gcp)
+        } else if asset_tag.trim() == AZURE_ASSET_TAG {
+            Some(Cloud::Azure)
+        } else {
+            None
+        }
+    }
+
+    pub fn detect() -> Option<Cloud> {
+        let read = |name: &str| fs::read_to_string(format!("{}/{}", DMI_DIR, name)).unwrap_or_default();
+        Cloud::from_dmi(&read("sys_vendor"), &read("product_name"), &read("chassis_asset_tag"))
+    }
+
+    /// Ubuntu's kernel flavor for this cloud, "5.15.0-1051-aws".
+    pub fn kernel_flavor(self) -> &'static str {
+        match self {
+            Cloud::Aws => "aws",
+            Cloud::Gcp => "gcp",
+            Cloud::Azure => "azure",
+        }
+    }
+
+    /// The bitflux kernel built from the cloud flavor, keeping its drivers (ENA, gVNIC, Hyper-V).
+    pub fn apt_kernel_package(self) -> &'static str {
+        match self {
+            Cloud::Aws => "linux-image-swaphints-aws",
+            Cloud::Gcp => "linux-image-swaphints-gcp",
+            Cloud::Azure => "linux-image-swaphints-azure",
+        }
+    }
+
+}
+
+/// The cloud whose kernel flavor `osrelease` is, None for generic kernels.
+pub fn running_flavor(osrelease: &str) -> Option<Cloud> {
+    [Cloud::Aws, Cloud::Gcp, Cloud::Azure]
+        .into_iter()
+        .find(|c| osrelease.trim().ends_with(&format!("-{}", c.kernel_flavor())))
+}
+
+/// What the instance metadata service says about this machine, added to device registration.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Metadata {
+    pub cloud: Option<Cloud>,
+    pub instance_id: String,
+    pub region: String,
+    pub instance_type: String,
+}
+
+fn text(doc: &Value, key: &str) -> String {
+    doc[key].as_str().unwrap_or("").to_string()
+}
+
+/// Last path element, GCP reports "projects/123/zones/us-central1-a".
+fn last(value: String) -> String {
+    value.rsplit('/').next().unwrap_or("").to_string()
+}
+
+/// Parses the instance description from `cloud`'s IMDS.
+pub fn parse_metadata(cloud: Cloud, body: &str) -> Option<Metadata> {
+    let doc: Value = serde_json::from_str(body).ok()?;
+    let (instance_id, region, instance_type) = match cloud {
+        Cloud::Aws => (text(&doc, "instanceId"), text(&doc, "region"), text(&doc, "instanceType")),
+        Cloud::Gcp => (doc["id"].to_string().trim_matches('"').to_string(), last(text(&doc, "zone")), last(text(&doc, "machineType"))),
+        Cloud::Azure => (text(&doc, "vmId"), text(&doc, "location"), text(&doc, "vmSize")),
+    };
+    if instance_id.is_empty() {
+        return None;
+    }
+    Some(Metadata { cloud: Some(cloud), instance_id, region, instance_type })
+}
+
+/// The body of an IMDS request with `args`, `token` masked in it where it's logged.
+fn imds(args: &[&str], token: Option<&str>) -> Option<String> {
+    offline::guard("the instance metadata service").ok()?;
+    let argv = [&["-fsS", "--max-time", IMDS_TIMEOUT_SECS, "--noproxy", "*"], args].concat();
+    let mut cmd = unprivileged_cmd("curl", &argv);
+    if let Some(token) = token {
+        cmd.sensitive(token);
+    }
+    let out = cmd.execute_output();
+    if out.exitcode != 0 {
+        return None;
+    }
+    Some(out.stdout)
+}
+
+/// Asks the instance metadata service about this machine, None off-cloud or when IMDS is
+/// blocked.  AWS gets an IMDSv2 session token first, IMDSv1 is often disabled.
+pub fn metadata() -> Option<Metadata> {
+    let cloud = Cloud::detect()?;
+    let body = match cloud {
+        Cloud::Aws => {
+            let token = imds(&["-X", "PUT", "-H", "X-aws-ec2-metadata-token-ttl-seconds: 60", "http://169.254.169.254/latest/api/token"], None)?;
+            let token = token.trim();
+            imds(&["-H", &format!("X-aws-ec2-metadata-token: {}", token), "http://169.254.169.254/latest/dynamic/instance-identity/document"], Some(token))?
+        }
+        Cloud::Gcp => imds(&["-H", "Metadata-Flavor: Google", "http://169.254.169.254/computeMetadata/v1/instance/?recursive=true"], None)?,
+        Cloud::Azure => imds(&["-H", "Metadata: true", "http://169.254.169.254/metadata/instance/compute?api-version=2021-02-01"], None)?,
+    };
+    parse_metadata(cloud, &body)
+}
+
+/// True if `pid` or one of its ancestors is cloud-init, from /proc/<pid>/stat.
+fn under_cloud_init(mut pid: u32) -> bool {
+    while pid > 1 {
+        let Ok(stat) = fs::read_to_string(format!("/proc/{}/stat", pid)) else {
+            return false;
+        };
+        // "pid (comm) state ppid ...", comm may contain spaces and parentheses.
+        let Some((head, tail)) = stat.rsplit_once(')') else {
+            return false;
+        };
+        if head.contains("(cloud-init") {
+            return true;
+        }
+        pid = tail.split_whitespace().nth(1).and_then(|p| p.parse().ok()).unwrap_or(0);
+    }
+    false
+}
+
+/// Waits for cloud-init to finish first boot, its package installs would otherwise fight ours
+/// for the apt/dnf lock.  Doesn't wait when cloud-init itself runs the installer from
+/// user-data, that would never finish.
+pub fn settle() {
+    if which("cloud-init").is_none() || under_cloud_init(std::process::id()) {
+        return;
+    }
+    let out = RunCmd::args("cloud-init", &["status"]).execute_output();
+    if out.stdout.contains("running") {
+        log::log(Level::Info, "Waiting for cloud-init to finish.");
+        RunCmd::args("cloud-init", &["status", "--wait"]).execute_output();
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect_cloud() {
+        assert_eq!(Cloud::from_dmi("Amazon EC2\n", "m6g.large\n", ""), Some(Cloud::Aws));
+        assert_eq!(Cloud::from_dmi("Google\n", "Google Compute Engine\n", ""), Some(Cloud::Gcp));
+        assert_eq!(Cloud::from_dmi("Microsoft Corporation\n", "Virtual Machine\n", "7783-7084-3265-9085-8269-3286-77\n"), Some(Cloud::Azure));
+        assert_eq!(Cloud::from_dmi("Microsoft Corporation\n", "Virtual Machine\n", "\n"), None);
+        assert_eq!(running_flavor("5.15.0-1051-aws"), Some(Cloud::Aws));
+        assert_eq!(running_flavor("6.5.0-1014-azure"), Some(Cloud::Azure));
+        assert_eq!(running_flavor("5.15.0-91-generic"), None);
+        assert!(!under_cloud_init(std::process::id()));
+    }
+
+    #[test]
+    fn imds_documents() {
+        let aws = r#"{"instanceId": "i-0abc", "region": "us-east-1", "instanceType": "c7g.xlarge"}"#;
+        let meta = parse_metadata(Cloud::Aws, aws).unwrap();
+        assert_eq!((meta.instance_id.as_str(), meta.region.as_str(), meta.instance_type.as_str()), ("i-0abc", "us-east-1", "c7g.xlarge"));
+
+        let gcp = r#"{"id": 4520031799277581759, "zone": "projects/1/zones/us-central1-a", "machineType": "projects/1/machineTypes/t2a-standard-4"}"#;
+        let meta = parse_metadata(Cloud::Gcp, gcp).unwrap();
+        assert_eq!((meta.instance_id.as_str(), meta.region.as_str(), meta.instance_type.as_str()), ("4520031799277581759", "us-central1-a", "t2a-standard-4"));
+
+        let azure = r#"{"vmId": "02aab8a4", "location": "westeurope", "vmSize": "Standard_D4ps_v5"}"#;
+        assert_eq!(parse_metadata(Cloud::Azure, azure).unwrap().instance_type, "Standard_D4ps_v5");
+        assert_eq!(parse_metadata(Cloud::Aws, "{}"), None);
+    }
+
+}
@@ -0,0 +1,223 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::journal::Journal;
+use crate::kernel::{Bootloader, EXTLINUX_CFG, GRUB_DEFAULTS};
+use crate::reboot;
+use crate::runcmd::{self, RunCmd};
+use crate::sbc;
+
+/// The command line the running kernel booted with.
+pub const PROC_CMDLINE: &str = "/proc/cmdline";
+/// What the kernel boots with for the agent's reclaim.
+pub const RECLAIM_PARAMS: &[&str] = &["swaphints.reclaim=1"];
+
+/// The name part of a kernel parameter, "swaphints.reclaim" of "swaphints.reclaim=1".
+fn key(param: &str) -> &str {
+    param.split_once('=').map_or(param, |(k, _)| k)
+}
+
+/// `cmdline` with each of `add` in place of any parameter of the same name, at the end when
+/// there's none, and the parameters named like `remove` taken out.
+pub fn edit_params(cmdline: &str, add: &[&str], remove: &[&str]) -> String {
+    let mut params: Vec<&str> = cmdline.split_whitespace()
+        .filter(|p| !remove.iter().any(|r| key(r) == key(p)))
+        .collect();
+    for param in add {
+        match params.iter().position(|p| key(p) == key(param)) {
+            Some(i) => params[i] = param,
+            None => params.push(param),
+        }
+    }
+    params.join(" ")
+}
+
+/// Edits GRUB_CMDLINE_LINUX in the contents of /etc/default/grub with edit_params(), the last
+/// one when it's set more than once, as the shell that reads it would.  Added at the end when
+/// missing.
+pub fn edit_grub_defaults(defaults: &str, add: &[&str], remove: &[&str]) -> String {
+    const VAR: &str = "GRUB_CMDLINE_LINUX=";
+    let mut lines: Vec<String> = defaults.lines().map(String::from).collect();
+    match lines.iter().rposition(|l| l.starts_with(VAR)) {
+        Some(i) => {
+            let value = &lines[i][VAR.len()..];
+            let quote = if value.starts_with('\'') { '\'' } else { '"' };
+            let value = value.trim_matches(|c| c == '"' || c == '\'');
+            lines[i] = format!("{}{}{}{}", VAR, quote, edit_params(value, add, remove), quote);
+        }
+        None => lines.push(format!("{}\"{}\"", VAR, edit_params("", add, remove))),
+    }
+    lines.join("\n") + "\n"
+}
+
+/// True when edit_params() changes `cmdline`, spacing aside.
+fn differs(cmdline: &str, add: &[&str], remove: &[&str]) -> bool {
+    edit_params(cmdline, add, remove) != edit_params(cmdline, &[], &[])
+}
+
+/// The options of the BLS entries from `grubby --info=ALL` output.
+pub fn grubby_args(info: &str) -> Vec<&str> {
+    info.lines()
+        .filter_map(|l| l.strip_prefix("args="))
+        .map(|a| a.trim_matches('"'))
+        .collect()
+}
+
+/// The GRUB_CMDLINE_LINUX of the contents of /etc/default/grub, the last one set.
+fn grub_cmdline(defaults: &str) -> &str {
+    defaults.lines().rev()
+        .find_map(|l| l.strip_prefix("GRUB_CMDLINE_LINUX="))
+        .map_or("", |v| v.trim_matches(|c| c == '"' || c == '\''))
+}
+
+/// True when update() with `add` and `remove` has something to change.
+pub fn pending(bootloader: Bootloader, add: &[&str], remove: &[&str]) -> io::Result<bool> {
+    let defaults = || fs::read_to_string(GRUB_DEFAULTS).map(|d| differs(grub_cmdline(&d), add, remove));
+    Ok(match bootloader {
+        Bootloader::Grubby => {
+            let info = RunCmd::args("grubby", &["--info=ALL"]).try_execute().map_err(io::Error::from)?.stdout;
+            grubby_args(&info).iter().any(|a| differs(a, add, remove))
+                || (Path::new(GRUB_DEFAULTS).exists() && defaults()?)
+        }
+        Bootloader::Grub | Bootloader::Grub2 => defaults()?,
+        Bootloader::RpiFirmware => {
+            let config = sbc::firmware_config().ok_or_else(|| io::Error::other("No Raspberry Pi config.txt found."))?;
+            differs(&fs::read_to_string(Path::new(config).with_file_name("cmdline.txt"))?, add, remove)
+        }
+        Bootloader::Extlinux => fs::read_to_string(EXTLINUX_CFG)?.lines()
+            .filter_map(|l| l.trim_start().strip_prefix("APPEND"))
+            .any(|a| differs(a, add, remove)),
+    })
+}
+
+/// True when the running kernel booted with every one of `params`.
+pub fn running_with(params: &[&str]) -> bool {
+    let cmdline = fs::read_to_string(PROC_CMDLINE).unwrap_or_default();
+    params.iter().all(|p| cmdline.split_whitespace().any(|c| c == *p))
+}
+
+/// True when the running kernel booted with none of the parameters named like `params`.
+pub fn running_without(params: &[&str]) -> bool {
+    let cmdline = fs::read_to_string(PROC_CMDLINE).unwrap_or_default();
+    !params.iter().any(|p| cmdline.split_whitespace().any(|c| key(c) == key(p)))
+}
+
+/// Rewrites the file at `path` with `edit` of its contents, journaled in `journal` first.
+/// False when that changes nothing.
+fn edit_file(path: &Path, journal: &mut Journal, edit: impl Fn(&str) -> String) -> io::Result<bool> {
+    let current = fs::read_to_string(path)?;
+    let edited = edit(&current);
+    if edited == current {
+        return Ok(false);
+    }
+    journal.file(path)?;
+    if !runcmd::dry_run(&format!("edit the kernel parameters in {}", path.display())) {
+        fs::write(path, edited)?;
+    }
+    Ok(true)
+}
+
+fn edit_defaults(add: &[&str], remove: &[&str], journal: &mut Journal) -> io::Result<bool> {
+    edit_file(Path::new(GRUB_DEFAULTS), journal, |d| edit_grub_defaults(d, add, remove))
+}
+
+/// Puts `add` on, and takes the parameters named like `remove` off, the command line of
+/// every kernel the bootloader boots, regenerating its config with the distro's command.
+/// The files edited go in `journal` first.  Returns whether anything changed.
+pub fn update(bootloader: Bootloader, add: &[&str], remove: &[&str], journal: &mut Journal) -> io::Result<bool> {
+    let changed = match bootloader {
+        // BLS entries carry their own options, /etc/default/grub is what new kernels get.
+        Bootloader::Grubby => {
+            let changed = Path::new(GRUB_DEFAULTS).exists() && edit_defaults(add, remove, journal)?;
+            let info = RunCmd::args("grubby", &["--info=ALL"]).try_execute().map_err(io::Error::from)?.stdout;
+            let entries = grubby_args(&info);
+            let adding = !add.is_empty() && entries.iter().any(|a| differs(a, add, &[]));
+            let removing = !remove.is_empty() && entries.iter().any(|a| differs(a, &[], remove));
+            if adding {
+                RunCmd::args("grubby", &["--update-kernel=ALL", &format!("--args={}", add.join(" "))]).try_execute().map_err(io::Error::from)?;
+            }
+            if removing {
+                RunCmd::args("grubby", &["--update-kernel=ALL", &format!("--remove-args={}", remove.join(" "))]).try_execute().map_err(io::Error::from)?;
+            }
+            changed || adding || removing
+        }
+        Bootloader::Grub | Bootloader::Grub2 => {
+            let changed = edit_defaults(add, remove, journal)?;
+            if changed {
+                bootloader.regenerate()?;
+            }
+            changed
+        }
+        // The firmware passes cmdline.txt, a single line next to config.txt, to the kernel.
+        Bootloader::RpiFirmware => {
+            let config = sbc::firmware_config().ok_or_else(|| io::Error::other("No Raspberry Pi config.txt found."))?;
+            let cmdline = Path::new(config).with_file_name("cmdline.txt");
+            edit_file(&cmdline, journal, |c| edit_params(c, add, remove) + "\n")?
+        }
+        Bootloader::Extlinux => {
+            return Err(io::Error::other(format!(
+                "Kernel parameters can't be set with extlinux, edit the APPEND lines of {}: add '{}', remove '{}'.",
+                EXTLINUX_CFG, add.join(" "), remove.join(" "))));
+        }
+    };
+    Ok(changed)
+}
+
+/// Boots every kernel with `params`, replacing any of the same name, and flags a reboot when
+/// that changed something the running kernel lacks.  Returns whether anything changed.
+pub fn set(params: &[&str], journal: &mut Journal) -> io::Result<bool> {
+    let bootloader = Bootloader::detect().ok_or_else(|| io::Error::other("Can't recognize the bootloader."))?;
+    let changed = update(bootloader, params, &[], journal)?;
+    if changed && !running_with(params) {
+        reboot::flag(&format!("the kernel parameters {} were set", params.join(" ")))?;
+    }
+    Ok(changed)
+}
+
+/// Boots every kernel without the parameters named like `params`, and flags a reboot when
+/// that changed something the running kernel has.  Returns whether anything changed.
+pub fn unset(params: &[&str], journal: &mut Journal) -> io::Result<bool> {
+    let bootloader = Bootloader::detect().ok_or_else(|| io::Error::other("Can't recognize the bootloader."))?;
+    let changed = update(bootloader, &[], params, journal)?;
+    if changed && !running_without(params) {
+        reboot::flag(&format!("the kernel parameters {} were removed", params.join(" ")))?;
+    }
+    Ok(changed)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn params_added_replaced_removed() {
+        assert_eq!(edit_params("quiet splash swaphints.reclaim=0", &["swaphints.reclaim=1", "transparent_hugepage=madvise"], &[]),
+            "quiet splash swaphints.reclaim=1 transparent_hugepage=madvise");
+        assert_eq!(edit_params("quiet swaphints.reclaim=1 splash", &[], &["swaphints.reclaim"]), "quiet splash");
+        assert_eq!(edit_params("", &["swaphints.reclaim=1"], &[]), "swaphints.reclaim=1");
+    }
+
+    #[test]
+    fn grub_cmdline_linux() {
+        let defaults = "GRUB_DEFAULT=0\nGRUB_CMDLINE_LINUX_DEFAULT=\"quiet splash\"\nGRUB_CMDLINE_LINUX=\"crashkernel=auto\"\n";
+        assert_eq!(edit_grub_defaults(defaults, &["swaphints.reclaim=1"], &[]),
+            "GRUB_DEFAULT=0\nGRUB_CMDLINE_LINUX_DEFAULT=\"quiet splash\"\nGRUB_CMDLINE_LINUX=\"crashkernel=auto swaphints.reclaim=1\"\n");
+        assert_eq!(edit_grub_defaults("GRUB_CMDLINE_LINUX='swaphints.reclaim=1 rhgb'\n", &[], &["swaphints.reclaim"]),
+            "GRUB_CMDLINE_LINUX='rhgb'\n");
+        assert_eq!(edit_grub_defaults("GRUB_TIMEOUT=5\n", &["swaphints.reclaim=1"], &[]),
+            "GRUB_TIMEOUT=5\nGRUB_CMDLINE_LINUX=\"swaphints.reclaim=1\"\n");
+        assert_eq!(grub_cmdline("GRUB_CMDLINE_LINUX=\"a\"\nGRUB_CMDLINE_LINUX='rhgb  quiet'\n"), "rhgb  quiet");
+        assert!(!differs("rhgb  quiet", &["quiet"], &[]) && differs("rhgb", &[], &["rhgb"]));
+    }
+
+    #[test]
+    fn grubby_entries() {
+        let info = "index=0\nkernel=\"/boot/vmlinuz-5.14.0\"\nargs=\"ro crashkernel=auto swaphints.reclaim=1\"\nroot=\"UUID=1\"\nindex=1\nkernel=\"/boot/vmlinuz-5.14.1\"\nargs=\"ro rhgb\"\n";
+        assert_eq!(grubby_args(info), ["ro crashkernel=auto swaphints.reclaim=1", "ro rhgb"]);
+        let stale: Vec<bool> = grubby_args(info).iter().map(|a| differs(a, RECLAIM_PARAMS, &[])).collect();
+        assert_eq!(stale, [false, true]);
+    }
+
+}
@@ -0,0 +1,193 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+
+use serde::Deserialize;
+
+use crate::agentconf::{self, AGENT_CONFIG};
+use crate::kernel::running_kernel;
+use crate::log::{self, Level};
+use crate::platform::OsRelease;
+use crate::privsep;
+
+/// Requirements of the release an upgrade would move to.
+pub const REQUIREMENTS_URL: &str = "https://mirror.bitflux.ai/repository/installer/requirements.json";
+/// Schema version of the agent's on-disk data.
+pub const DATA_VERSION_PATH: &str = "/opt/bitflux/data/VERSION";
+
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct DeprecatedSetting {
+    pub key: String,
+    pub message: String,
+    /// The target release can't run with this setting at all.
+    #[serde(default)]
+    pub critical: bool,
+}
+
+/// What the target release needs from the installed system.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+pub struct Requirements {
+    pub version: String,
+    /// Oldest agent config version the target can still migrate.
+    #[serde(default)]
+    pub min_config_version: u32,
+    /// Oldest agent data version the target can still migrate.
+    #[serde(default)]
+    pub min_data_version: u32,
+    /// Supported kernel series, "5.15" matches every 5.15.x kernel.  Empty means any.
+    #[serde(default)]
+    pub kernels: Vec<String>,
+    #[serde(default)]
+    pub deprecated_settings: Vec<DeprecatedSetting>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Severity {
+    /// Can be overridden with --force.
+    Warning,
+    /// Always blocks the upgrade.
+    Critical,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Finding {
+    pub severity: Severity,
+    pub message: String,
+}
+
+/// The installed state the assessment looks at.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Installed {
+    pub config_version: u32,
+    pub data_version: u32,
+    pub kernel: String,
+    pub config: BTreeMap<String, String>,
+}
+
+impl Installed {
+
+    pub fn detect() -> io::Result<Installed> {
+        let config = agentconf::load(AGENT_CONFIG).unwrap_or_default();
+        let config_version = config.get("config_version").and_then(|v| v.parse().ok()).unwrap_or(1);
+        let data_version = fs::read_to_string(DATA_VERSION_PATH)
+            .ok()
+            .and_then(|v| v.trim().parse().ok())
+            .unwrap_or(1);
+        Ok(Installed { config_version, data_version, kernel: running_kernel()?, config })
+    }
+
+}
+
+pub fn fetch_requirements() -> io::Result<Requirements> {
+    let data = privsep::fetch(REQUIREMENTS_URL)?;
+    serde_json::from_str(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Lists everything about `installed` that is incompatible with `req`.
+pub fn assess(installed: &Installed, req: &Requirements) -> Vec<Finding> {
+    let mut findings = Vec::new();
+
+    if installed.config_version < req.min_config_version {
+        findings.push(Finding {
+            severity: Severity::Critical,
+            message: format!("Agent config version {} is too old for {}, it needs at least {}.",
+                             installed.config_version, req.version, req.min_config_version),
+        });
+    }
+    if installed.data_version < req.min_data_version {
+        findings.push(Finding {
+            severity: Severity::Critical,
+            message: format!("Agent data version {} is too old for {}, it needs at least {}.",
+                             installed.data_version, req.version, req.min_data_version),
+        });
+    }
+
+    let series_match = |s: &String| installed.kernel == *s || installed.kernel.starts_with(&format!("{}.", s));
+    if !req.kernels.is_empty() && !req.kernels.iter().any(series_match) {
+        findings.push(Finding {
+            severity: Severity::Warning,
+            message: format!("Running kernel {} is not supported by {} (supported: {}).",
+                             installed.kernel, req.version, req.kernels.join(", ")),
+        });
+    }
+
+    for setting in &req.deprecated_settings {
+        if installed.config.contains_key(&setting.key) {
+            findings.push(Finding {
+                severity: if setting.critical { Severity::Critical } else { Severity::Warning },
+                message: format!("Setting '{}' is deprecated: {}", setting.key, setting.message),
+            });
+        }
+    }
+
+    findings
+}
+
+/// Runs the pre-upgrade assessment, printing the report.  Warnings block unless `force`,
+/// critical findings always block.
+pub fn check_upgrade(force: bool) -> io::Result<()> {
+    let req = fetch_requirements()?;
+    let mut findings = assess(&Installed::detect()?, &req);
+    // An unsupported release gets no testing, --force upgrades it anyway.
+    if let Some(Err(e)) = OsRelease::load().ok().map(|os| os.check_supported()) {
+        findings.push(Finding { severity: Severity::Warning, message: e.to_string() });
+    }
+    gate(&findings, force)
+}
+
+fn gate(findings: &[Finding], force: bool) -> io::Result<()> {
+    for f in findings {
+        let (level, label) = match f.severity {
+            Severity::Critical => (Level::Error, "CRITICAL"),
+            Severity::Warning => (Level::Warn, "WARNING"),
+        };
+        log::log(level, &format!("{}: {}", label, f.message));
+    }
+
+    let critical = findings.iter().filter(|f| f.severity == Severity::Critical).count();
+    let warnings = findings.len() - critical;
+    if critical > 0 {
+        return Err(io::Error::other(format!("Upgrade blocked by {} critical incompatibilities.", critical)));
+    }
+    if warnings > 0 && !force {
+        return Err(io::Error::other(format!("Upgrade blocked by {} incompatibilities, use --force to override.", warnings)));
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn installed() -> Installed {
+        let mut config = BTreeMap::new();
+        config.insert(String::from("legacy_mode"), String::from("1"));
+        Installed { config_version: 2, data_version: 1, kernel: String::from("5.15.0-91-generic"), config }
+    }
+
+    #[test]
+    fn compatible_has_no_findings() {
+        let req = Requirements { version: String::from("2.0"), kernels: vec![String::from("5.15")], ..Default::default() };
+        assert!(assess(&installed(), &req).is_empty());
+    }
+
+    #[test]
+    fn findings_and_gate() {
+        let req: Requirements = serde_json::from_str(r#"{
+            "version": "2.0",
+            "min_config_version": 2,
+            "kernels": ["6.8"],
+            "deprecated_settings": [{"key": "legacy_mode", "message": "removed in 2.0"}]
+        }"#).unwrap();
+        let findings = assess(&installed(), &req);
+        assert_eq!(findings.len(), 2);
+        assert!(findings.iter().all(|f| f.severity == Severity::Warning));
+        assert!(gate(&findings, false).is_err());
+        assert!(gate(&findings, true).is_ok());
+
+        let req = Requirements { version: String::from("2.0"), min_data_version: 3, ..Default::default() };
+        assert!(gate(&assess(&installed(), &req), true).is_err());
+    }
+
+}
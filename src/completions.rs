@@ -0,0 +1,341 @@
+use clap::{Arg, ArgAction, Command, ValueEnum};
+
+/// The shells `installer completions` writes a completion script for.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+}
+
+/// `cmd` with the help and version flags and the global options in every subcommand, as a
+/// parse would see it.
+fn built(cmd: &Command) -> Command {
+    let mut cmd = cmd.clone();
+    cmd.build();
+    cmd
+}
+
+/// The subcommands of `cmd`, without the `help` one clap adds, the `--help` flags do the same.
+fn subcommands(cmd: &Command) -> Vec<&Command> {
+    cmd.get_subcommands().filter(|c| !c.is_hide_set() && c.get_name() != "help").collect()
+}
+
+fn options(cmd: &Command) -> Vec<&Arg> {
+    cmd.get_arguments().filter(|a| !a.is_hide_set() && !a.is_positional()).collect()
+}
+
+fn positionals(cmd: &Command) -> Vec<&Arg> {
+    cmd.get_arguments().filter(|a| !a.is_hide_set() && a.is_positional()).collect()
+}
+
+fn takes_value(arg: &Arg) -> bool {
+    arg.get_action().takes_values()
+}
+
+/// Whether the option may be given more than once.
+fn repeats(arg: &Arg) -> bool {
+    matches!(arg.get_action(), ArgAction::Append | ArgAction::Count)
+}
+
+fn values(arg: &Arg) -> Vec<String> {
+    arg.get_possible_values().iter().filter(|v| !v.is_hide_set()).map(|v| String::from(v.get_name())).collect()
+}
+
+/// The flags of `arg`: `-v` and `--verbose`.
+fn flags(arg: &Arg) -> Vec<String> {
+    let mut flags: Vec<String> = arg.get_short().map(|s| format!("-{}", s)).into_iter().collect();
+    flags.extend(arg.get_long().map(|l| format!("--{}", l)));
+    flags
+}
+
+/// The first sentence of `help`, without its period, for the one line completions show.
+fn summary(help: Option<String>) -> String {
+    let help = help.unwrap_or_default();
+    let first = help.lines().next().unwrap_or("").trim();
+    let sentence = first.find(". ").map_or(first, |end| &first[..end]);
+    String::from(sentence.trim_end_matches('.'))
+}
+
+fn arg_summary(arg: &Arg) -> String {
+    summary(arg.get_help().map(|h| h.to_string()))
+}
+
+fn about(cmd: &Command) -> String {
+    summary(cmd.get_about().map(|h| h.to_string()))
+}
+
+/// `f` on `cmd` and every subcommand below it, with the names leading to it.
+fn walk<'a>(cmd: &'a Command, path: &mut Vec<&'a str>, f: &mut dyn FnMut(&'a Command, &[&'a str])) {
+    path.push(cmd.get_name());
+    f(cmd, path);
+    for sub in subcommands(cmd) {
+        walk(sub, path, f);
+    }
+    path.pop();
+}
+
+/// The completion script for `shell` of the command line `cmd` defines.
+pub fn render(shell: Shell, cmd: &Command) -> String {
+    let cmd = built(cmd);
+    match shell {
+        Shell::Bash => bash(&cmd),
+        Shell::Zsh => zsh(&cmd),
+        Shell::Fish => fish(&cmd),
+    }
+}
+
+fn bash(cmd: &Command) -> String {
+    let name = cmd.get_name();
+    let mut known = Vec::new();
+    let mut cases = String::new();
+    walk(cmd, &mut Vec::new(), &mut |c, path| {
+        let key = path.join("__");
+        if path.len() > 1 {
+            known.push(key.clone());
+        }
+        let mut words: Vec<String> = options(c).iter().flat_map(|a| flags(a)).collect();
+        words.extend(positionals(c).iter().flat_map(|a| values(a)));
+        words.extend(subcommands(c).iter().map(|s| String::from(s.get_name())));
+        cases.push_str(&format!("        {})\n            opts=\"{}\"\n", key, words.join(" ")));
+        let valued: Vec<&Arg> = options(c).into_iter().filter(|a| takes_value(a)).collect();
+        if valued.is_empty() {
+            cases.push_str("            ;;\n");
+            return;
+        }
+        cases.push_str("            case \"${prev}\" in\n");
+        for arg in valued {
+            let reply = match values(arg) {
+                values if values.is_empty() => String::from("compgen -f -- \"${cur}\""),
+                values => format!("compgen -W \"{}\" -- \"${{cur}}\"", values.join(" ")),
+            };
+            cases.push_str(&format!("                {})\n                    COMPREPLY=($({}))\n                    return 0\n                    ;;\n", flags(arg).join("|"), reply));
+        }
+        cases.push_str("            esac\n            ;;\n");
+    });
+    format!(
+        "# bash completion for {name}, written by `{name} completions bash`.\n\
+         _{name}() {{\n    local cur prev cmd opts i\n    COMPREPLY=()\n    cur=\"${{COMP_WORDS[COMP_CWORD]}}\"\n    prev=\"${{COMP_WORDS[COMP_CWORD-1]}}\"\n    cmd=\"{name}\"\n\
+         \x20   for ((i = 1; i < COMP_CWORD; i++)); do\n        case \"${{cmd}}__${{COMP_WORDS[i]}}\" in\n            {known})\n                cmd=\"${{cmd}}__${{COMP_WORDS[i]}}\"\n                ;;\n        esac\n    done\n\
+         \x20   case \"${{cmd}}\" in\n{cases}    esac\n    COMPREPLY=($(compgen -W \"${{opts}}\" -- \"${{cur}}\"))\n}}\n\n\
+         complete -F _{name} -o bashdefault -o default {name}\n",
+        name = name,
+        known = match known.is_empty() {
+            true => String::from("''"),
+            false => known.join("|"),
+        },
+        cases = cases,
+    )
+}
+
+/// `text` inside single quotes for zsh, where it's also between the brackets of a spec.
+fn zsh_quote(text: &str) -> String {
+    text.replace('\'', "'\\''").replace('[', "\\[").replace(']', "\\]").replace(':', "\\:")
+}
+
+fn zsh_spec(arg: &Arg) -> Vec<String> {
+    let help = zsh_quote(&arg_summary(arg));
+    let repeat = if repeats(arg) { "*" } else { "" };
+    flags(arg).into_iter().map(|flag| {
+        match takes_value(arg) {
+            false => format!("'{}{}[{}]'", repeat, flag, help),
+            true => {
+                let value = arg.get_value_names().and_then(|v| v.first()).map_or_else(|| arg.get_id().to_string().to_uppercase(), |v| v.to_string());
+                let action = match values(arg) {
+                    values if values.is_empty() => String::from("_files"),
+                    values => format!("({})", values.join(" ")),
+                };
+                let flag = if flag.starts_with("--") { format!("{}=", flag) } else { flag };
+                format!("'{}{}[{}]:{}:{}'", repeat, flag, help, zsh_quote(&value), action)
+            }
+        }
+    }).collect()
+}
+
+fn zsh(cmd: &Command) -> String {
+    let mut functions = String::new();
+    walk(cmd, &mut Vec::new(), &mut |c, path| {
+        let function = format!("_{}", path.join("__").replace('-', "_"));
+        let mut specs: Vec<String> = options(c).iter().flat_map(|a| zsh_spec(a)).collect();
+        for arg in positionals(c) {
+            let action = match values(arg) {
+                values if values.is_empty() => String::from("_files"),
+                values => format!("({})", values.join(" ")),
+            };
+            specs.push(format!("'{}:{}:{}'", if repeats(arg) { "*" } else { "" }, arg.get_id(), action));
+        }
+        let subs = subcommands(c);
+        if !subs.is_empty() {
+            specs.push(format!("':: :{}_commands'", function));
+            specs.push(format!("'*::: :->{}'", c.get_name()));
+        }
+        functions.push_str(&format!("{}() {{\n    local context curcontext=\"$curcontext\" state line\n    _arguments -s -C \\\n        {}\n", function, specs.join(" \\\n        ")));
+        if !subs.is_empty() {
+            functions.push_str(&format!("    case $state in\n        ({})\n            words=($line[1] \"${{words[@]}}\")\n            (( CURRENT += 1 ))\n            case $line[1] in\n", c.get_name()));
+            for sub in &subs {
+                functions.push_str(&format!("                ({}) {}__{} ;;\n", sub.get_name(), function, sub.get_name().replace('-', "_")));
+            }
+            functions.push_str("            esac\n            ;;\n    esac\n");
+        }
+        functions.push_str("}\n\n");
+        if !subs.is_empty() {
+            let described: Vec<String> = subs.iter().map(|s| format!("        '{}:{}'", s.get_name(), zsh_quote(&about(s)))).collect();
+            functions.push_str(&format!("{}_commands() {{\n    local commands\n    commands=(\n{}\n    )\n    _describe -t commands command commands\n}}\n\n", function, described.join("\n")));
+        }
+    });
+    format!("#compdef {name}\n# zsh completion for {name}, written by `{name} completions zsh`.\n\n{functions}if [ \"$funcstack[1]\" = \"_{name}\" ]; then\n    _{name} \"$@\"\nelse\n    compdef _{name} {name}\nfi\n", name = cmd.get_name(), functions = functions)
+}
+
+fn fish_quote(text: &str) -> String {
+    format!("'{}'", text.replace('\\', "\\\\").replace('\'', "\\'"))
+}
+
+fn fish(cmd: &Command) -> String {
+    let name = cmd.get_name();
+    let mut lines = vec![format!("# fish completion for {name}, written by `{name} completions fish`.", name = name)];
+    walk(cmd, &mut Vec::new(), &mut |c, path| {
+        let condition = match path.len() {
+            1 => String::from("__fish_use_subcommand"),
+            _ => path[1..].iter().map(|p| format!("__fish_seen_subcommand_from {}", p)).collect::<Vec<_>>().join("; and "),
+        };
+        for arg in options(c) {
+            let mut line = format!("complete -c {} -n {}", name, fish_quote(&condition));
+            if let Some(short) = arg.get_short() {
+                line.push_str(&format!(" -s {}", short));
+            }
+            if let Some(long) = arg.get_long() {
+                line.push_str(&format!(" -l {}", long));
+            }
+            if takes_value(arg) {
+                match values(arg) {
+                    values if values.is_empty() => line.push_str(" -r -F"),
+                    values => line.push_str(&format!(" -r -f -a {}", fish_quote(&values.join(" ")))),
+                }
+            }
+            line.push_str(&format!(" -d {}", fish_quote(&arg_summary(arg))));
+            lines.push(line);
+        }
+        let subs = subcommands(c);
+        let names: Vec<&str> = subs.iter().map(|s| s.get_name()).collect();
+        let condition = match path.len() {
+            1 => String::from("__fish_use_subcommand"),
+            _ => format!("{}; and not __fish_seen_subcommand_from {}", condition, names.join(" ")),
+        };
+        for sub in subs {
+            lines.push(format!("complete -c {} -n {} -f -a {} -d {}", name, fish_quote(&condition), sub.get_name(), fish_quote(&about(sub))));
+        }
+    });
+    lines.push(String::new());
+    lines.join("\n")
+}
+
+/// `text` for roff: its backslashes and dashes escaped and no line taken for a request.
+fn roff(text: &str) -> String {
+    text.replace('\\', "\\e").replace('-', "\\-")
+        .lines()
+        .map(|l| match l.trim() {
+            "" => String::from(".sp"),
+            l if l.starts_with('.') || l.starts_with('\'') => format!("\\&{}", l),
+            l => String::from(l),
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+fn man_options(args: &[&Arg], out: &mut String) {
+    for arg in args {
+        let mut flags: Vec<String> = flags(arg).iter().map(|f| format!("\\fB{}\\fR", roff(f))).collect::<Vec<String>>();
+        if takes_value(arg) {
+            let value = arg.get_value_names().and_then(|v| v.first()).map_or_else(|| arg.get_id().to_string().to_uppercase(), |v| v.to_string());
+            if let Some(last) = flags.last_mut() {
+                last.push_str(&format!(" \\fI{}\\fR", roff(&value)));
+            }
+        }
+        out.push_str(&format!(".TP\n{}\n", flags.join(", ")));
+        let help = arg.get_long_help().or(arg.get_help()).map(|h| h.to_string()).unwrap_or_default();
+        out.push_str(&roff(&help));
+        out.push('\n');
+        let values = values(arg);
+        if !values.is_empty() {
+            out.push_str(&format!(".br\n[possible values: {}]\n", roff(&values.join(", "))));
+        }
+    }
+}
+
+/// The man page, section 1, of the command line `cmd` defines: its options, then every
+/// command with its own.
+pub fn manpage(cmd: &Command) -> String {
+    let cmd = built(cmd);
+    let name = cmd.get_name();
+    let version = cmd.get_version().unwrap_or("");
+    let mut out = format!(".TH {} 1 \"\" \"{} {}\" \"User Commands\"\n", roff(&name.to_uppercase()), roff(name), roff(version));
+    out.push_str(&format!(".SH NAME\n{} \\- {}\n", roff(name), roff(&cmd.get_about().map(|a| a.to_string()).unwrap_or_default())));
+    out.push_str(&format!(".SH SYNOPSIS\n\\fB{}\\fR [\\fIOPTIONS\\fR] \\fICOMMAND\\fR\n", roff(name)));
+    if let Some(about) = cmd.get_long_about() {
+        out.push_str(&format!(".SH DESCRIPTION\n{}\n", roff(&about.to_string())));
+    }
+    out.push_str(".SH OPTIONS\n");
+    man_options(&options(&cmd), &mut out);
+    out.push_str(".SH COMMANDS\n");
+    let mut commands = String::new();
+    for sub in subcommands(&cmd) {
+        walk(sub, &mut vec![name], &mut |c, path| {
+            commands.push_str(&format!(".SS \"{}\"\n", roff(&path.join(" "))));
+            let about = c.get_long_about().or(c.get_about()).map(|a| a.to_string()).unwrap_or_default();
+            commands.push_str(&format!("{}\n", roff(&about)));
+            // The global options are in OPTIONS already.
+            let own: Vec<&Arg> = options(c).into_iter().filter(|a| !a.is_global_set() && cmd.get_arguments().all(|g| g.get_id() != a.get_id())).collect();
+            man_options(&own, &mut commands);
+        });
+    }
+    out.push_str(&commands);
+    out
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cli() -> Command {
+        Command::new("installer")
+            .version("0.1.0")
+            .about("Installer for bitflux")
+            .arg(Arg::new("verbose").short('v').long("verbose").action(ArgAction::Count).global(true).help("Print every step. And more."))
+            .subcommand(Command::new("install").about("Install bitflux.")
+                .arg(Arg::new("profile").long("profile").value_name("NAME").help("What to install."))
+                .arg(Arg::new("output").long("output").value_parser(["text", "json"]).help("How to print it.")))
+            .subcommand(Command::new("fleet").about("Install on many hosts.")
+                .subcommand(Command::new("install").about("Install on every host.")))
+    }
+
+    #[test]
+    fn completion_scripts() {
+        let bash = render(Shell::Bash, &cli());
+        assert!(bash.contains("installer__install|installer__fleet|installer__fleet__install)"));
+        assert!(bash.contains("--output)\n                    COMPREPLY=($(compgen -W \"text json\" -- \"${cur}\"))"));
+        assert!(bash.contains("complete -F _installer -o bashdefault -o default installer"));
+
+        let zsh = render(Shell::Zsh, &cli());
+        assert!(zsh.starts_with("#compdef installer\n"));
+        assert!(zsh.contains("'--output=[How to print it]:OUTPUT:(text json)'"));
+        assert!(zsh.contains("'*-v[Print every step]'"));
+        assert!(zsh.contains("(install) _installer__fleet__install ;;"));
+
+        let fish = render(Shell::Fish, &cli());
+        assert!(fish.contains("complete -c installer -n '__fish_seen_subcommand_from install' -l profile -r -F -d 'What to install'"));
+        assert!(fish.contains("complete -c installer -n '__fish_seen_subcommand_from fleet; and not __fish_seen_subcommand_from install' -f -a install -d 'Install on every host'"));
+    }
+
+    #[test]
+    fn man_page() {
+        let man = manpage(&cli());
+        assert!(man.starts_with(".TH INSTALLER 1 \"\" \"installer 0.1.0\" \"User Commands\"\n.SH NAME\ninstaller \\- Installer for bitflux\n"));
+        assert!(man.contains(".TP\n\\fB\\-v\\fR, \\fB\\-\\-verbose\\fR\nPrint every step. And more.\n"));
+        assert!(man.contains(".SS \"installer fleet install\"\nInstall on every host.\n"));
+        assert!(man.contains("\\fB\\-\\-output\\fR \\fIOUTPUT\\fR\nHow to print it.\n.br\n[possible values: text, json]\n"));
+        // The global --verbose is only under OPTIONS.
+        assert_eq!(man.matches("\\-\\-verbose").count(), 1);
+    }
+
+}
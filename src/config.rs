@@ -0,0 +1,76 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::Deserialize;
+
+use crate::budget::Budgets;
+use crate::download;
+use crate::health::Health;
+use crate::hooks::Hooks;
+use crate::log::{self, Level};
+use crate::metrics::Metrics;
+use crate::notify::Operators;
+use crate::proxy::Proxy;
+
+/// The installer's own settings, as opposed to the answers for one install.
+pub const INSTALLER_CONFIG: &str = "/etc/bitflux/installer.toml";
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Who hears about unattended runs.
+    pub notify: Operators,
+    pub metrics: Metrics,
+    /// Used unless --proxy or the environment says otherwise.
+    pub proxy: Proxy,
+    /// Commands run around the install steps.
+    pub hooks: Hooks,
+    /// When the agent counts as healthy after the install started it.
+    pub health: Health,
+    /// The rate limit and mirrors of downloads, --limit-rate wins.
+    pub download: download::Settings,
+    /// How long steps and runs may take, --step-timeout, --install-timeout and --on-timeout win.
+    pub timeouts: Budgets,
+}
+
+impl Config {
+
+    /// Reads `path`, the defaults when it doesn't exist.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Config> {
+        let path = path.as_ref();
+        let data = match fs::read_to_string(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Config::default()),
+            Err(e) => return Err(io::Error::new(e.kind(), format!("Can't read {}: {}", path.display(), e))),
+        };
+        toml::from_str(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))
+    }
+
+    /// load() for the commands that have to work with a broken config: one that can't be read
+    /// is warned about and the defaults are used.
+    pub fn load_or_default<P: AsRef<Path>>(path: P) -> Config {
+        Config::load(path).unwrap_or_else(|e| {
+            log::log(Level::Warn, &format!("Warning: {}, going on with the defaults.", e));
+            Config::default()
+        })
+    }
+
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn missing_file_is_default() {
+        let path = std::env::temp_dir().join(format!("bitflux-config-{}", std::process::id()));
+        assert_eq!(Config::load(&path).unwrap(), Config::default());
+        fs::write(&path, "[notfy]\n").unwrap();
+        assert!(Config::load(&path).is_err());
+        assert_eq!(Config::load_or_default(&path), Config::default());
+        fs::remove_file(&path).unwrap();
+    }
+
+}
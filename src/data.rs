@@ -0,0 +1,217 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::image::rooted;
+use crate::log::{self, Level};
+use crate::runcmd::{self, RunCmd};
+
+/// Directories owned by the bitflux agent that survive package upgrades.
+pub const DATA_DIRS: &[&str] = &["/opt/bitflux/data"];
+/// Executables shipped by the agent package that migrate data between versions,
+/// run in name order as `<hook> <from-version> <to-version>`.
+pub const MIGRATE_HOOKS_DIR: &str = "/opt/bitflux/hooks/migrate.d";
+/// The data archives and the copies of the files installs replaced, in state::STATE_DIR.
+pub const BACKUP_DIR: &str = "/var/lib/bitflux-installer/backups";
+/// The last data handling decision, so support can see what happened to the data.
+pub const DATA_STATE_PATH: &str = "/var/lib/bitflux/data.json";
+
+/// What to do with the agent data across an upgrade or uninstall.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "lowercase")]
+pub enum DataPolicy {
+    /// Run the agent's migration hooks on the data in place.
+    Migrate,
+    /// Back the data up first, then migrate it.
+    Backup,
+    /// Leave the data exactly as it is.
+    Preserve,
+    /// Delete the data, only meaningful on uninstall.
+    Remove,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Phase {
+    PreUpgrade,
+    PostUpgrade,
+    PreUninstall,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct DataRecord {
+    pub phase: Phase,
+    pub policy: DataPolicy,
+    pub backup: Option<String>,
+    pub migrated_from: Option<String>,
+    pub migrated_to: Option<String>,
+    /// Unix timestamp of when the hook ran.
+    pub at: u64,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+fn existing_dirs() -> Vec<&'static str> {
+    DATA_DIRS.iter().copied().filter(|d| Path::new(d).exists()).collect()
+}
+
+/// True when any of DATA_DIRS is on the system at `root`, with or without the agent package.
+pub fn has_data(root: &Path) -> bool {
+    DATA_DIRS.iter().any(|d| rooted(root, d).exists())
+}
+
+/// Tars up the data directories into BACKUP_DIR, returns the archive path.
+pub fn backup(dirs: &[&str]) -> io::Result<Option<PathBuf>> {
+    if dirs.is_empty() {
+        return Ok(None);
+    }
+    fs::create_dir_all(BACKUP_DIR)?;
+    let archive = Path::new(BACKUP_DIR).join(format!("data-{}.tar.gz", now()));
+    let archive_arg = archive.to_string_lossy();
+    let mut args = vec!["-czf", &archive_arg, "-C", "/"];
+    args.extend(dirs.iter().map(|d| d.trim_start_matches('/')));
+    let out = RunCmd::args("tar", &args).execute_output();
+    if out.exitcode != 0 {
+        return Err(io::Error::other(format!("Failed to back up agent data: {}", out.stderr.trim())));
+    }
+    Ok(Some(archive))
+}
+
+/// Migration hooks in the order they should run.
+pub fn migrate_hooks<P: AsRef<Path>>(dir: P) -> Vec<PathBuf> {
+    let mut hooks: Vec<PathBuf> = match fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(|e| e.ok()).map(|e| e.path()).filter(|p| p.is_file()).collect(),
+        Err(_) => Vec::new(),
+    };
+    hooks.sort();
+    hooks
+}
+
+fn record(rec: &DataRecord) -> io::Result<()> {
+    if runcmd::dry_run(&format!("write {}", DATA_STATE_PATH)) {
+        return Ok(());
+    }
+    if let Some(parent) = Path::new(DATA_STATE_PATH).parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let data = serde_json::to_string_pretty(rec).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    fs::write(DATA_STATE_PATH, data)
+}
+
+/// Hook point before packages are upgraded.
+pub fn pre_upgrade(policy: DataPolicy) -> io::Result<()> {
+    let backup = match policy {
+        DataPolicy::Backup => backup(&existing_dirs())?,
+        _ => None,
+    };
+    if let Some(archive) = &backup {
+        log::log(Level::Info, &format!("Backed up agent data to '{}'", archive.display()));
+    }
+    record(&DataRecord {
+        phase: Phase::PreUpgrade,
+        policy,
+        backup: backup.map(|p| p.to_string_lossy().into_owned()),
+        migrated_from: None,
+        migrated_to: None,
+        at: now(),
+    })
+}
+
+/// Hook point after packages are upgraded from agent version `from` to `to`.
+pub fn post_upgrade(policy: DataPolicy, from: &str, to: &str) -> io::Result<()> {
+    let migrate = matches!(policy, DataPolicy::Migrate | DataPolicy::Backup) && from != to;
+    if migrate {
+        for hook in migrate_hooks(MIGRATE_HOOKS_DIR) {
+            let out = RunCmd::args(&hook.to_string_lossy(), &[from, to]).execute_output();
+            if out.exitcode != 0 {
+                return Err(io::Error::other(format!("Data migration hook '{}' failed: {}", hook.display(), out.stderr.trim())));
+            }
+        }
+    }
+    record(&DataRecord {
+        phase: Phase::PostUpgrade,
+        policy,
+        backup: None,
+        migrated_from: if migrate { Some(String::from(from)) } else { None },
+        migrated_to: if migrate { Some(String::from(to)) } else { None },
+        at: now(),
+    })
+}
+
+/// Hook point before the agent is removed.  Data is deleted unless the policy keeps it.
+pub fn pre_uninstall(policy: DataPolicy) -> io::Result<()> {
+    let dirs = existing_dirs();
+    let backup = match policy {
+        DataPolicy::Backup => backup(&dirs)?,
+        _ => None,
+    };
+    if matches!(policy, DataPolicy::Remove | DataPolicy::Backup) {
+        for dir in &dirs {
+            if !runcmd::dry_run(&format!("remove {}", dir)) {
+                fs::remove_dir_all(dir)?;
+            }
+        }
+    }
+    record(&DataRecord {
+        phase: Phase::PreUninstall,
+        policy,
+        backup: backup.map(|p| p.to_string_lossy().into_owned()),
+        migrated_from: None,
+        migrated_to: None,
+        at: now(),
+    })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hooks_sorted() {
+        let dir = std::env::temp_dir().join(format!("bitflux-hooks-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("ignored-dir")).unwrap();
+        fs::write(dir.join("20-second"), "").unwrap();
+        fs::write(dir.join("10-first"), "").unwrap();
+
+        let hooks = migrate_hooks(&dir);
+        assert_eq!(hooks, vec![dir.join("10-first"), dir.join("20-second")]);
+        assert!(migrate_hooks("/nonexistent/bitflux").is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn record_serializes_lowercase() {
+        let rec = DataRecord {
+            phase: Phase::PreUninstall,
+            policy: DataPolicy::Preserve,
+            backup: None,
+            migrated_from: None,
+            migrated_to: None,
+            at: 0,
+        };
+        let json = serde_json::to_string(&rec).unwrap();
+        assert!(json.contains("\"pre-uninstall\""));
+        assert!(json.contains("\"preserve\""));
+    }
+
+    #[test]
+    fn data_without_the_package() {
+        let root = std::env::temp_dir().join(format!("bitflux-data-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(&root).unwrap();
+        assert!(!has_data(&root));
+        // What an uninstall cut short after removing the package leaves.
+        fs::create_dir_all(rooted(&root, DATA_DIRS[0]).join("agent")).unwrap();
+        assert!(has_data(&root));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+}
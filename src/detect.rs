@@ -0,0 +1,195 @@
+use std::ffi::CString;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::thread;
+
+use serde::{Deserialize, Serialize};
+
+use crate::arch::{page_size, Arch};
+use crate::checksum::sha256_hex;
+use crate::cloud::Cloud;
+use crate::exitcode::Kind;
+use crate::kernel::running_kernel;
+use crate::platform::{self, OsRelease, OS_RELEASE_PATH};
+use crate::preflight::mem_total_mib;
+use crate::runcmd;
+use crate::sbc;
+
+/// Detection results of the last run, reused until the host changes under them.
+pub const CACHE_PATH: &str = "/var/lib/bitflux/detected.json";
+const BOOT_ID_PATH: &str = "/proc/sys/kernel/random/boot_id";
+const SECURE_BOOT_VAR: &str = "/sys/firmware/efi/efivars/SecureBoot-8be4df61-93ca-11d2-aa0d-00e098032b8c";
+const CGROUP2_SUPER_MAGIC: i64 = 0x63677270;
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CgroupMode {
+    /// cgroup v1 only.
+    Legacy,
+    /// v1 controllers with a v2 hierarchy at /sys/fs/cgroup/unified.
+    Hybrid,
+    /// cgroup v2 only.
+    Unified,
+}
+
+/// What the cached results were detected on.  A different kernel, os-release or boot (Secure
+/// Boot and the cgroup mode are boot settings) means they have to be probed again, as does
+/// another installer version, which may detect more.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct CacheKey {
+    pub installer: String,
+    pub kernel: String,
+    pub os_release_sha256: String,
+    pub boot_id: String,
+}
+
+impl CacheKey {
+
+    pub fn current() -> io::Result<CacheKey> {
+        Ok(CacheKey {
+            installer: String::from(env!("CARGO_PKG_VERSION")),
+            kernel: running_kernel()?,
+            os_release_sha256: sha256_hex(&fs::read(OS_RELEASE_PATH)?),
+            boot_id: fs::read_to_string(BOOT_ID_PATH).unwrap_or_default().trim().to_string(),
+        })
+    }
+
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Detected {
+    pub key: CacheKey,
+    /// /etc/os-release as read, see os().
+    pub os_release: String,
+    pub arch: String,
+    pub page_size: u64,
+    pub memory_mib: u64,
+    pub cpus: usize,
+    pub raspberry_pi: bool,
+    pub cloud: Option<Cloud>,
+    pub transactional: bool,
+    /// None without EFI.
+    pub secure_boot: Option<bool>,
+    pub cgroup: CgroupMode,
+}
+
+impl Detected {
+
+    /// Probes the host for everything cached.
+    pub fn probe(key: CacheKey) -> io::Result<Detected> {
+        let arch = Arch::current().ok_or_else(|| Kind::Unsupported.error("No bitflux packages for this architecture."))?;
+        Ok(Detected {
+            key,
+            os_release: fs::read_to_string(OS_RELEASE_PATH)?,
+            arch: String::from(arch.rpm()),
+            page_size: page_size(),
+            memory_mib: fs::read_to_string("/proc/meminfo").ok().and_then(|m| mem_total_mib(&m)).unwrap_or(0),
+            cpus: thread::available_parallelism().map(|n| n.get()).unwrap_or(1),
+            raspberry_pi: sbc::is_raspberry_pi(),
+            cloud: Cloud::detect(),
+            transactional: platform::is_transactional(),
+            secure_boot: secure_boot(),
+            cgroup: cgroup_mode(),
+        })
+    }
+
+    pub fn os(&self) -> OsRelease {
+        OsRelease::parse(&self.os_release)
+    }
+
+    pub fn arch(&self) -> Arch {
+        Arch::from_name(&self.arch).unwrap_or(Arch::X86_64)
+    }
+
+}
+
+/// Whether Secure Boot is on, None without EFI.
+pub fn secure_boot() -> Option<bool> {
+    fs::read(SECURE_BOOT_VAR).ok().map(|v| secure_boot_enabled(&v))
+}
+
+/// True if the SecureBoot EFI variable says it's on: 4 bytes of attributes, then the value.
+pub fn secure_boot_enabled(var: &[u8]) -> bool {
+    var.get(4) == Some(&1)
+}
+
+fn fs_type(path: &str) -> Option<i64> {
+    let path = CString::new(path).ok()?;
+    let mut stat: libc::statfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    #[allow(clippy::unnecessary_cast)]
+    Some(stat.f_type as i64)
+}
+
+fn cgroup_mode() -> CgroupMode {
+    if fs_type("/sys/fs/cgroup") == Some(CGROUP2_SUPER_MAGIC) {
+        CgroupMode::Unified
+    } else if fs_type("/sys/fs/cgroup/unified") == Some(CGROUP2_SUPER_MAGIC) {
+        CgroupMode::Hybrid
+    } else {
+        CgroupMode::Legacy
+    }
+}
+
+/// The results cached at `path` if they were detected under `key`.
+pub fn cached<P: AsRef<Path>>(path: P, key: &CacheKey) -> Option<Detected> {
+    let data = fs::read_to_string(path).ok()?;
+    let detected: Detected = serde_json::from_str(&data).ok()?;
+    (detected.key == *key).then_some(detected)
+}
+
+pub fn save<P: AsRef<Path>>(path: P, detected: &Detected) -> io::Result<()> {
+    let path = path.as_ref();
+    if runcmd::dry_run(&format!("cache the detected platform in {}", path.display())) {
+        return Ok(());
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let data = serde_json::to_string_pretty(detected).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    fs::write(path, data)
+}
+
+/// The detection results for this host, from the cache when it's still valid.  Failing to
+/// update the cache, as an unprivileged user, only costs the next run a probe.
+pub fn load() -> io::Result<Detected> {
+    let key = CacheKey::current()?;
+    if let Some(detected) = cached(CACHE_PATH, &key) {
+        return Ok(detected);
+    }
+    let detected = Detected::probe(key)?;
+    let _ = save(CACHE_PATH, &detected);
+    Ok(detected)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cache_invalidated_by_key() {
+        let path = std::env::temp_dir().join(format!("bitflux-detected-{}.json", std::process::id()));
+        let key = CacheKey::current().unwrap();
+        let detected = Detected::probe(key.clone()).unwrap();
+        save(&path, &detected).unwrap();
+        assert_eq!(cached(&path, &key), Some(detected));
+
+        let upgraded = CacheKey { kernel: String::from("6.8.0-31-generic"), ..key.clone() };
+        assert_eq!(cached(&path, &upgraded), None);
+        let dist_upgraded = CacheKey { os_release_sha256: sha256_hex(b"ID=ubuntu\nVERSION_ID=\"24.04\"\n"), ..key };
+        assert_eq!(cached(&path, &dist_upgraded), None);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn secure_boot_var() {
+        assert!(secure_boot_enabled(&[0x06, 0, 0, 0, 1]));
+        assert!(!secure_boot_enabled(&[0x06, 0, 0, 0, 0]));
+        assert!(!secure_boot_enabled(&[]));
+    }
+
+}
@@ -0,0 +1,60 @@
+use std::fs;
+
+use crate::agentconf::{self, AGENT_CONFIG};
+use crate::checksum::sha256_hex;
+
+/// Agent config key of the name the host reports as.
+pub const DEVICE_ID_KEY: &str = "deviceid";
+const MACHINE_ID_PATH: &str = "/etc/machine-id";
+
+/// Checks that `id` can be a device id: not empty and fit for the agent's key=value config.
+pub fn validate(id: &str) -> Result<(), String> {
+    if id.is_empty() {
+        return Err(String::from("The device id can't be empty."));
+    }
+    match id.chars().find(|c| c.is_whitespace() || c.is_control() || "=#".contains(*c)) {
+        Some(c) => Err(format!("The device id can't contain {:?}.", c)),
+        None => Ok(()),
+    }
+}
+
+/// A device id from `hostname` and `machine_id`, the same every time for the same host and
+/// different for clones that kept the hostname but got a machine id of their own.
+pub fn generate(hostname: &str, machine_id: &str) -> String {
+    let host: String = hostname.trim().chars().filter(|c| c.is_ascii_alphanumeric() || "-_.".contains(*c)).collect();
+    let suffix = &sha256_hex(machine_id.trim().as_bytes())[..8];
+    match host.is_empty() {
+        true => format!("host-{}", suffix),
+        false => format!("{}-{}", host, suffix),
+    }
+}
+
+/// generate() for this host.
+pub fn generated() -> String {
+    let hostname = fs::read_to_string("/proc/sys/kernel/hostname").unwrap_or_default();
+    let machine_id = fs::read_to_string(MACHINE_ID_PATH).unwrap_or_default();
+    generate(&hostname, &machine_id)
+}
+
+/// The device id in the agent config, if one was set.
+pub fn current() -> Option<String> {
+    agentconf::load(AGENT_CONFIG).ok()?.remove(DEVICE_ID_KEY).filter(|id| !id.is_empty())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn generated_ids_are_stable() {
+        let id = generate("web1\n", "4c4c4544004d3510804bb2c04f4e4d32\n");
+        assert_eq!(id, generate("web1", "4c4c4544004d3510804bb2c04f4e4d32"));
+        assert!(id.starts_with("web1-"));
+        assert_ne!(id, generate("web1", "0f0e0d0c0b0a09080706050403020100"));
+        assert!(generate("", "").starts_with("host-"));
+        assert!(validate(&id).is_ok());
+        assert!(validate("web#1").is_err());
+    }
+
+}
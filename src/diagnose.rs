@@ -0,0 +1,246 @@
+use std::fs::{self, Permissions};
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::agentconf::{self, AGENT_CONFIG};
+use crate::device;
+use crate::history::{self, HISTORY_DIR};
+use crate::install::{Options, ANSWERS_PATH};
+use crate::journal::{Journal, JOURNAL_DIR};
+use crate::kernel::{running_kernel, Bootloader};
+use crate::kmod;
+use crate::license::{Activation, LICENSE_PATH};
+use crate::log::{self, Level, LOG_PATH};
+use crate::mac::Mac;
+use crate::pkg::AGENT_PACKAGE;
+use crate::platform::OS_RELEASE_PATH;
+use crate::runcmd::{self, RunCmd};
+use crate::status::Status;
+use crate::transcript::TRANSCRIPT_DIR;
+use crate::workspace::{Workspace, WORKSPACE_ROOT};
+
+/// What redacted values are replaced with.
+const LICENSE_KEY: &str = "[license key]";
+const HOSTNAME: &str = "[hostname]";
+/// Keys whose values are redacted wherever they show up, in the agent config, TOML or JSON.
+const SECRET_KEYS: &[&str] = &["licensekey", "license_key", "token"];
+/// Lines of the agent's journal kept.
+const SERVICE_LINES: &str = "2000";
+
+/// The license keys and host names on this host, to take out of everything collected.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Redactions {
+    pub secrets: Vec<String>,
+    pub hostnames: Vec<String>,
+}
+
+impl Redactions {
+
+    pub fn detect() -> Redactions {
+        let mut secrets = Vec::new();
+        if let Some(key) = agentconf::load(AGENT_CONFIG).ok().and_then(|mut c| c.remove("licensekey")) {
+            secrets.push(key);
+        }
+        if let Some(key) = Options::load(ANSWERS_PATH).ok().and_then(|o| o.license_key) {
+            secrets.push(key);
+        }
+        if let Some(activation) = fs::read_to_string(LICENSE_PATH).ok().and_then(|d| serde_json::from_str::<Activation>(&d).ok()) {
+            secrets.push(activation.token);
+        }
+        let mut hostnames: Vec<String> = ["/proc/sys/kernel/hostname", "/etc/hostname"].iter()
+            .filter_map(|p| fs::read_to_string(p).ok())
+            .map(|h| String::from(h.trim()))
+            .collect();
+        let fqdn = RunCmd::args("hostname", &["-f"]).execute_output();
+        if fqdn.exitcode == 0 {
+            hostnames.push(String::from(fqdn.stdout.trim()));
+        }
+        hostnames.extend(device::current());
+        Redactions { secrets, hostnames }
+    }
+
+    /// `text` without any of the secrets or host names, nor the values of SECRET_KEYS.  Names
+    /// too short to tell from other words, and localhost, stay.
+    pub fn apply(&self, text: &str) -> String {
+        let text = SECRET_KEYS.iter().fold(String::from(text), |t, key| redact_values(&t, key));
+        let mut values: Vec<(&str, &str)> = self.secrets.iter().filter(|s| s.len() >= 8).map(|s| (s.as_str(), LICENSE_KEY))
+            .chain(self.hostnames.iter().filter(|h| h.len() >= 3 && *h != "localhost").map(|h| (h.as_str(), HOSTNAME)))
+            .collect();
+        // The longest first, an FQDN before the hostname in it.
+        values.sort_by_key(|(v, _)| std::cmp::Reverse(v.len()));
+        // In one pass, so what replaced a value isn't searched again.
+        let mut out = String::new();
+        let mut rest = text.as_str();
+        while let Some(c) = rest.chars().next() {
+            match values.iter().find(|(v, _)| rest.starts_with(v)) {
+                Some((value, with)) => {
+                    out.push_str(with);
+                    rest = &rest[value.len()..];
+                }
+                None => {
+                    out.push(c);
+                    rest = &rest[c.len_utf8()..];
+                }
+            }
+        }
+        // And what this run was told is sensitive, a sudo password.
+        runcmd::sensitive::mask(&out)
+    }
+
+}
+
+/// `text` with the value after each `key`, and then `=` or `:` between optional quotes and
+/// blanks, redacted.
+fn redact_values(text: &str, key: &str) -> String {
+    let is_sep = |c: char| c == '"' || c == '\'' || c == ' ' || c == '\t';
+    let mut out = String::new();
+    let mut rest = text;
+    while let Some(at) = rest.find(key) {
+        let (before, after) = rest.split_at(at + key.len());
+        out.push_str(before);
+        let value = after.trim_start_matches(is_sep);
+        let Some(value) = value.strip_prefix(['=', ':']) else {
+            rest = after;
+            continue;
+        };
+        let value = value.trim_start_matches(is_sep);
+        let len = value.find(|c: char| c.is_whitespace() || "\"',}".contains(c)).unwrap_or(value.len());
+        if len == 0 {
+            rest = after;
+            continue;
+        }
+        out.push_str(&after[..after.len() - value.len()]);
+        out.push_str(LICENSE_KEY);
+        rest = &value[len..];
+    }
+    out.push_str(rest);
+    out
+}
+
+/// The kernel log lines about the swaphints module: loading it, its signature, taint.
+pub fn dmesg_excerpt(dmesg: &str) -> String {
+    dmesg.lines()
+        .filter(|l| l.contains(kmod::MODULE) || l.contains("bitflux") || l.contains("module verification") || l.contains("taint"))
+        .map(|l| format!("{}\n", l))
+        .collect()
+}
+
+fn command(program: &str, args: &[&str]) -> String {
+    let out = RunCmd::args(program, args).execute_output();
+    let mut text = format!("$ {}\n{}", out.cmd, out.stdout);
+    if out.exitcode != 0 {
+        text.push_str(&format!("exit code {}: {}", out.exitcode, out.stderr));
+    }
+    text
+}
+
+fn platform() -> String {
+    let mut text = format!("installer {}\n", env!("CARGO_PKG_VERSION"));
+    text.push_str(&format!("running kernel: {}\n", running_kernel().unwrap_or_else(|e| e.to_string())));
+    text.push_str(&format!("bootloader: {}\n", Bootloader::detect().map_or(String::from("unknown"), |b| format!("{:?}", b))));
+    text.push_str(&format!("access control: {}\n", Mac::detect().name()));
+    text.push_str(&format!("cmdline: {}\n", fs::read_to_string("/proc/cmdline").unwrap_or_default().trim()));
+    match Status::detect() {
+        Ok(status) => text.push_str(&format!("\n{}", status.render())),
+        Err(e) => text.push_str(&format!("\nstatus: {}\n", e)),
+    }
+    text.push_str(&format!("\n{}:\n{}\n", OS_RELEASE_PATH, fs::read_to_string(OS_RELEASE_PATH).unwrap_or_default()));
+    text.push_str(&command("uname", &["-a"]));
+    text
+}
+
+/// The files that go in the bundle, by name, before redaction.
+fn collect() -> Vec<(String, String)> {
+    let mut files = Vec::new();
+    // Every command the installer ran is in there, with its exit code and output.
+    if let Ok(data) = fs::read_to_string(LOG_PATH) {
+        files.push((String::from("install.log"), data));
+    }
+    let mut transcripts: Vec<PathBuf> = fs::read_dir(TRANSCRIPT_DIR).into_iter().flatten()
+        .filter_map(|e| e.ok().map(|e| e.path()))
+        .filter(|p| p.extension().is_some_and(|e| e == "log"))
+        .collect();
+    transcripts.sort();
+    for path in transcripts {
+        if let (Some(name), Ok(data)) = (path.file_name(), fs::read_to_string(&path)) {
+            files.push((format!("transcripts/{}", name.to_string_lossy()), data));
+        }
+    }
+    for id in history::ids(HISTORY_DIR).unwrap_or_default() {
+        if let Ok(data) = fs::read_to_string(history::path(Path::new(HISTORY_DIR), &id)) {
+            files.push((format!("history/{}.jsonl", id), data));
+        }
+    }
+    let journal = Journal::open(JOURNAL_DIR)
+        .and_then(|j| serde_json::to_string_pretty(&j.changes).map_err(io::Error::other))
+        .unwrap_or_else(|e| format!("{}\n", e));
+    files.push((String::from("journal.json"), journal));
+    files.push((String::from("platform.txt"), platform()));
+    files.push((String::from("dmesg.txt"), dmesg_excerpt(&RunCmd::args("dmesg", &[]).execute_output().stdout)));
+    if let Ok(data) = fs::read_to_string(Path::new(TRANSCRIPT_DIR).join(kmod::LOAD_LOG)) {
+        files.push((String::from(kmod::LOAD_LOG), data));
+    }
+    files.push((String::from("service.txt"), command("journalctl", &["-u", AGENT_PACKAGE, "--no-pager", "-n", SERVICE_LINES])));
+    files
+}
+
+/// Collects what support needs to look into an install, with license keys and host names
+/// redacted, into a root only tarball at `output`, /var/tmp/bitflux-diagnose-<time>.tar.gz by
+/// default.  Returns where it went.
+pub fn run(output: Option<PathBuf>) -> io::Result<PathBuf> {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let name = format!("bitflux-diagnose-{}", log::timestamp(secs).replace([':', '-'], ""));
+    let output = output.unwrap_or_else(|| Path::new(WORKSPACE_ROOT).join(format!("{}.tar.gz", name)));
+    if runcmd::dry_run(&format!("collect the install log, journal, platform info, dmesg and the agent's journal into {}", output.display())) {
+        return Ok(output);
+    }
+    let redactions = Redactions::detect();
+    let ws = Workspace::create()?;
+    let dir = ws.join(&name);
+    for (file, data) in collect() {
+        let path = dir.join(file);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(path, redactions.apply(&data))?;
+    }
+    let out = RunCmd::args("tar", &["-czf", &output.to_string_lossy(), "-C", &ws.path().to_string_lossy(), &name]).execute_output();
+    if out.exitcode != 0 {
+        return Err(io::Error::other(format!("Failed to write {}: {}", output.display(), out.stderr.trim())));
+    }
+    // Redacted, but still logs of the host.
+    fs::set_permissions(&output, Permissions::from_mode(0o600))?;
+    log::log(Level::Info, &format!("Wrote {}, send it to support.", output.display()));
+    Ok(output)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_and_hosts_redacted() {
+        let redactions = Redactions {
+            secrets: vec![String::from("ABCD-1234-EFGH")],
+            hostnames: vec![String::from("web1"), String::from("web1.example.com"), String::from("db")],
+        };
+        assert_eq!(redactions.apply("licensekey=ABCD-1234-EFGH\ndeviceid=web1-1a2b3c4d\n"), "licensekey=[license key]\ndeviceid=[hostname]-1a2b3c4d\n");
+        assert_eq!(redactions.apply(r#"{"license_key": "OTHER-KEY-99", "device_id": null}"#), r#"{"license_key": "[license key]", "device_id": null}"#);
+        assert_eq!(redactions.apply("license_key = 'k3y-h3r3-abc'\n"), "license_key = '[license key]'\n");
+        assert_eq!(redactions.apply("ssh web1.example.com, db stays"), "ssh [hostname], db stays");
+        assert_eq!(redactions.apply("cmd: curl -d @- ABCD-1234-EFGH"), "cmd: curl -d @- [license key]");
+        let nested = Redactions { secrets: Vec::new(), hostnames: vec![String::from("host"), String::from("web.host.lan")] };
+        assert_eq!(nested.apply("web.host.lan host"), "[hostname] [hostname]");
+        assert_eq!(redactions.apply("token_expired, no token: "), "token_expired, no token: ");
+    }
+
+    #[test]
+    fn dmesg_about_the_module() {
+        let dmesg = "[    1.0] usb 1-1: new device\n[   12.3] swaphints: loading out-of-tree module taints kernel.\n[   12.4] swaphints: module verification failed\n";
+        assert_eq!(dmesg_excerpt(dmesg), "[   12.3] swaphints: loading out-of-tree module taints kernel.\n[   12.4] swaphints: module verification failed\n");
+    }
+
+}
@@ -0,0 +1,225 @@
+use std::ffi::CString;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::os::unix::fs::MetadataExt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::pkg::PackageManager;
+use crate::runcmd;
+
+/// Free space below which the next install step doesn't start, enough for dpkg/rpm to finish
+/// unpacking and configuring a kernel, its initramfs and the agent.
+pub const LOW_BOOT_MIB: u64 = 64;
+pub const LOW_USR_MIB: u64 = 100;
+pub const LOW_VAR_MIB: u64 = 200;
+const WATCHED: &[(&str, u64)] = &[("/boot", LOW_BOOT_MIB), ("/usr", LOW_USR_MIB), ("/var", LOW_VAR_MIB)];
+const WATCH_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Free MiB on the filesystem holding `path`.
+pub fn free_mib(path: &str) -> Option<u64> {
+    let path = CString::new(path).ok()?;
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return None;
+    }
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64 / (1024 * 1024))
+}
+
+/// A filesystem below its threshold.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Low {
+    pub path: &'static str,
+    /// The other directories on the same filesystem, their needs are in threshold_mib too.
+    pub with: Vec<&'static str>,
+    pub free_mib: u64,
+    pub threshold_mib: u64,
+}
+
+impl Low {
+
+    /// The directory, with the others on its filesystem.
+    pub fn paths(&self) -> String {
+        match self.with.is_empty() {
+            true => String::from(self.path),
+            false => format!("{} (with {})", self.path, self.with.join(", ")),
+        }
+    }
+
+    /// What usually frees enough space on it.
+    pub fn suggestions(&self, pm: &PackageManager) -> Vec<&'static str> {
+        let mut all = Vec::new();
+        for s in [self.path].iter().chain(&self.with).flat_map(|path| suggestions(pm, path)) {
+            if !all.contains(&s) {
+                all.push(s);
+            }
+        }
+        all
+    }
+
+}
+
+/// The directories of `space` with less than the MiB free they need, the needs of the ones on
+/// one filesystem added up.
+pub fn short(space: &[(&'static str, u64)]) -> Vec<Low> {
+    let mut filesystems: Vec<(u64, Low)> = Vec::new();
+    for &(path, need) in space {
+        let (Ok(meta), Some(free_mib)) = (fs::metadata(path), free_mib(path)) else {
+            continue;
+        };
+        match filesystems.iter_mut().find(|(dev, _)| *dev == meta.dev()) {
+            Some((_, low)) => {
+                low.with.push(path);
+                low.threshold_mib += need;
+            }
+            None => filesystems.push((meta.dev(), Low { path, with: Vec::new(), free_mib, threshold_mib: need })),
+        }
+    }
+    filesystems.into_iter().map(|(_, low)| low).filter(|l| l.free_mib < l.threshold_mib).collect()
+}
+
+/// The watched filesystems that are below their threshold now.
+pub fn low() -> Vec<Low> {
+    short(WATCHED)
+}
+
+/// What has to be left free when the install step `step` starts: where it unpacks the kernel
+/// and its modules, the agent, the package cache and the receipt.
+pub fn before(step: &str) -> &'static [(&'static str, u64)] {
+    match step {
+        "kernel" => WATCHED,
+        "agent" => &[("/usr", LOW_USR_MIB), ("/var", LOW_VAR_MIB)],
+        _ => &[("/var", LOW_VAR_MIB)],
+    }
+}
+
+/// Commands that usually free enough space on `path`.
+pub fn suggestions(pm: &PackageManager, path: &str) -> Vec<&'static str> {
+    match (path, pm) {
+        // /usr holds the old kernels' modules.
+        ("/boot" | "/usr", PackageManager::Apt) => vec!["apt-get autoremove --purge  (removes old kernels)"],
+        ("/boot" | "/usr", PackageManager::Zypper) => vec!["zypper purge-kernels"],
+        ("/boot" | "/usr", _) => vec!["dnf remove --oldinstallonly  (removes old kernels)"],
+        (_, PackageManager::Apt) => vec!["apt-get clean", "journalctl --vacuum-size=100M"],
+        (_, PackageManager::Zypper) => vec!["zypper clean --all", "journalctl --vacuum-size=100M"],
+        (_, _) => vec!["dnf clean all", "journalctl --vacuum-size=100M"],
+    }
+}
+
+fn describe(pm: &PackageManager, low: &[Low]) -> String {
+    let mut text = String::new();
+    for l in low {
+        text.push_str(&format!("{} has {} MiB free, the install needs {} MiB. To free space:\n", l.paths(), l.free_mib, l.threshold_mib));
+        for s in l.suggestions(pm) {
+            text.push_str(&format!("    {}\n", s));
+        }
+    }
+    text
+}
+
+/// Makes sure there's room for the install step `next` before it starts.  When there isn't,
+/// waits for the space to be freed on a terminal, and fails without starting the step
+/// otherwise, so a package manager never runs out of space half way through configuring.
+pub fn checkpoint(pm: &PackageManager, next: &str) -> io::Result<()> {
+    loop {
+        let low = short(before(next));
+        if low.is_empty() {
+            return Ok(());
+        }
+        eprint!("Not enough disk space to continue with the {} step.\n{}", next, describe(pm, &low));
+        if !runcmd::interactive() {
+            return Err(io::Error::other(format!(
+                "Stopped before the {} step, disk space is low. Nothing is half installed, free up space and run the install again.",
+                next
+            )));
+        }
+        eprint!("Free up space, then press Enter to continue, or type 'abort': ");
+        io::stderr().flush()?;
+        let mut answer = String::new();
+        io::stdin().lock().read_line(&mut answer)?;
+        if answer.trim().eq_ignore_ascii_case("abort") || answer.is_empty() {
+            return Err(io::Error::other(format!("Aborted before the {} step.", next)));
+        }
+    }
+}
+
+/// Samples the watched filesystems in the background while a package manager runs.
+pub struct Watch {
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<Vec<Low>>>,
+}
+
+impl Watch {
+
+    pub fn start() -> Watch {
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+        let handle = thread::spawn(move || {
+            let mut lowest: Vec<Low> = Vec::new();
+            while !stopped.load(Ordering::Relaxed) {
+                for l in low() {
+                    match lowest.iter_mut().find(|seen| seen.path == l.path) {
+                        Some(seen) => seen.free_mib = seen.free_mib.min(l.free_mib),
+                        None => lowest.push(l),
+                    }
+                }
+                thread::sleep(WATCH_INTERVAL);
+            }
+            lowest
+        });
+        Watch { stop, handle: Some(handle) }
+    }
+
+    /// Stops watching, returning the lowest free space seen on every filesystem that went below
+    /// its threshold.
+    pub fn stop(mut self) -> Vec<Low> {
+        self.stop.store(true, Ordering::Relaxed);
+        self.handle.take().and_then(|h| h.join().ok()).unwrap_or_default()
+    }
+
+}
+
+impl Drop for Watch {
+
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn suggestions_per_distro() {
+        assert!(suggestions(&PackageManager::Apt, "/boot")[0].starts_with("apt-get autoremove"));
+        assert_eq!(suggestions(&PackageManager::Dnf, "/var")[0], "dnf clean all");
+        let text = describe(&PackageManager::Zypper, &[Low { path: "/boot", with: Vec::new(), free_mib: 12, threshold_mib: LOW_BOOT_MIB }]);
+        assert_eq!(text, "/boot has 12 MiB free, the install needs 64 MiB. To free space:\n    zypper purge-kernels\n");
+        let root = Low { path: "/usr", with: vec!["/var"], free_mib: 90, threshold_mib: LOW_USR_MIB + LOW_VAR_MIB };
+        assert_eq!(root.paths(), "/usr (with /var)");
+        assert_eq!(root.suggestions(&PackageManager::Apt), ["apt-get autoremove --purge  (removes old kernels)", "apt-get clean", "journalctl --vacuum-size=100M"]);
+    }
+
+    #[test]
+    fn one_filesystem_adds_up() {
+        let free = free_mib("/").unwrap();
+        assert!(short(&[("/", free / 2)]).is_empty());
+        // The same filesystem twice needs its space twice.
+        let low = short(&[("/", free / 2 + 1), ("/.", free / 2 + 1)]);
+        assert_eq!((low[0].path, &low[0].with, low[0].threshold_mib), ("/", &vec!["/."], free / 2 * 2 + 2));
+        assert_eq!(before("receipt"), [("/var", LOW_VAR_MIB)]);
+    }
+
+    #[test]
+    fn watch_stops() {
+        let watch = Watch::start();
+        thread::sleep(Duration::from_millis(50));
+        assert!(watch.stop().iter().all(|l| l.free_mib < l.threshold_mib));
+    }
+
+}
@@ -0,0 +1,350 @@
+use std::fs;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, RwLock};
+
+use serde::Deserialize;
+
+use crate::batch::{self, Job};
+use crate::checksum::{sha256_file, sha256_hex};
+use crate::exitcode::Kind;
+use crate::log::{self, Level};
+use crate::offline;
+use crate::privsep::{unprivileged, unprivileged_cmd};
+use crate::repo::REPO_URL;
+use crate::runcmd::{self, shell_quote, RunCmd};
+use crate::script;
+use crate::tls;
+use crate::workspace::Workspace;
+
+/// Partial downloads are kept here between runs, so an interrupted one picks up where it stopped.
+pub const DOWNLOAD_DIR: &str = "/var/cache/bitflux/downloads";
+
+/// Extra tries for a download that fails, the network often recovers within a minute.
+const NETWORK_RETRIES: u32 = 3;
+/// curl's exit code when the server can't resume, the download starts over then.
+const CURL_RANGE_ERROR: i32 = 33;
+/// How long a mirror gets to answer when they're ranked.
+const PROBE_TIMEOUT_SECS: &str = "5";
+
+/// How downloads use the network, from the `[download]` table of installer.toml and the
+/// command line.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    /// Most a download takes of the link a second, curl's --limit-rate: "500K", "2M".
+    pub limit_rate: Option<String>,
+    /// Other servers with the tree of REPO_URL.  Downloads from it go to the fastest of them
+    /// all, and to the next one when that fails.
+    pub mirrors: Vec<String>,
+}
+
+/// The settings set(), none until then.
+static SETTINGS: RwLock<Settings> = RwLock::new(Settings { limit_rate: None, mirrors: Vec::new() });
+/// REPO_URL and the mirrors in the order to try them, ranked when they're first needed.
+static RANKED: Mutex<Option<Vec<String>>> = Mutex::new(None);
+
+impl Settings {
+
+    /// `self` with the command line's `limit_rate`, when it has one.
+    pub fn or_rate(mut self, limit_rate: Option<String>) -> Settings {
+        if limit_rate.is_some() {
+            self.limit_rate = limit_rate;
+        }
+        self
+    }
+
+    /// Makes these the settings of every download from now on.
+    pub fn set(self) -> io::Result<()> {
+        if let Some(rate) = self.limit_rate.as_deref().filter(|r| !valid_rate(r)) {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, format!("Invalid download rate limit '{}', use bytes a second like 500K or 2M.", rate)));
+        }
+        *RANKED.lock().unwrap_or_else(|e| e.into_inner()) = None;
+        *SETTINGS.write().unwrap_or_else(|e| e.into_inner()) = self;
+        Ok(())
+    }
+
+}
+
+/// Whether `rate` is one curl's --limit-rate takes: a number of bytes, with K, M or G.
+pub fn valid_rate(rate: &str) -> bool {
+    let digits = rate.strip_suffix(['k', 'K', 'm', 'M', 'g', 'G']).unwrap_or(rate);
+    !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()) && digits.chars().any(|c| c != '0')
+}
+
+fn settings() -> Settings {
+    SETTINGS.read().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// `bases` fastest first by their `timings`, the seconds each took to answer, the ones that
+/// didn't answer last.  Otherwise in the order they're given.
+pub fn order(bases: &[String], timings: &[Option<f64>]) -> Vec<String> {
+    let mut ranked: Vec<(&String, Option<f64>)> = bases.iter().zip(timings.iter().copied()).collect();
+    ranked.sort_by(|a, b| match (a.1, b.1) {
+        (Some(a), Some(b)) => a.total_cmp(&b),
+        (Some(_), None) => std::cmp::Ordering::Less,
+        (None, Some(_)) => std::cmp::Ordering::Greater,
+        (None, None) => std::cmp::Ordering::Equal,
+    });
+    ranked.into_iter().map(|(base, _)| base.clone()).collect()
+}
+
+/// Seconds `base` took to answer a HEAD request, None when it didn't.
+fn probe(base: &str) -> Option<f64> {
+    if offline::guard(base).is_err() {
+        return None;
+    }
+    let url = format!("{}/", base.trim_end_matches('/'));
+    let mut args: Vec<String> = ["-sS", "-I", "-o", "/dev/null", "-w", "%{time_total}", "--max-time", PROBE_TIMEOUT_SECS].map(String::from).to_vec();
+    args.extend(tls::hardened_curl_args());
+    args.extend([String::from("--"), url]);
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let out = unprivileged_cmd("curl", &args).execute_output();
+    match out.exitcode {
+        0 => out.stdout.trim().parse().ok(),
+        _ => None,
+    }
+}
+
+/// REPO_URL and the mirrors, fastest first, probed all at once the first time.
+fn ranked(mirrors: &[String]) -> Vec<String> {
+    let mut ranked = RANKED.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(bases) = ranked.as_ref() {
+        return bases.clone();
+    }
+    let bases: Vec<String> = std::iter::once(REPO_URL).chain(mirrors.iter().map(String::as_str))
+        .map(|b| String::from(b.trim_end_matches('/')))
+        .collect();
+    let jobs: Vec<Job<Option<f64>>> = bases.iter().map(|b| Box::new(move || probe(b)) as Job<Option<f64>>).collect();
+    let order = order(&bases, &batch::run(jobs));
+    log::log(Level::Debug, &format!("Mirrors fastest first: {}", order.join(", ")));
+    *ranked = Some(order.clone());
+    order
+}
+
+/// The urls to try `url` from in turn: on each of the mirrors fastest first when it's under
+/// REPO_URL and there are mirrors, only `url` otherwise.
+pub fn candidates(url: &str) -> Vec<String> {
+    let mirrors = settings().mirrors;
+    match url.strip_prefix(REPO_URL).filter(|path| path.starts_with('/')) {
+        Some(path) if !mirrors.is_empty() => ranked(&mirrors).into_iter().map(|base| format!("{}{}", base, path)).collect(),
+        _ => vec![String::from(url)],
+    }
+}
+
+/// A file to download and what it has to match before it's put in place.
+///
+/// # Examples
+///
+/// ```no_run
+/// use std::path::Path;
+/// use installer::download::Download;
+///
+/// Download::new("https://repo.bitflux.ai/repo/pgp-key.public")
+///     .sha256("5f0ce9f2b4b8c0a6d0af7d2b4d5c8e1f7a3b9c6d2e4f8a1b3c5d7e9f0a2b4c6d")
+///     .save(Path::new("/tmp/bitflux.asc"))?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct Download {
+    pub url: String,
+    pub sha256: Option<String>,
+    /// Keyring the detached `<url>.asc` signature has to verify against with gpgv.
+    pub keyring: Option<PathBuf>,
+}
+
+impl Download {
+
+    pub fn new(url: &str) -> Download {
+        Download { url: String::from(url), sha256: None, keyring: None }
+    }
+
+    /// The hex SHA-256 the download must have.
+    pub fn sha256(&mut self, sha256: &str) -> &mut Download {
+        self.sha256 = Some(sha256.trim().to_lowercase());
+        self
+    }
+
+    /// Also checks the detached GPG signature `<url>.asc` against `keyring`.
+    pub fn gpg<P: AsRef<Path>>(&mut self, keyring: P) -> &mut Download {
+        self.keyring = Some(keyring.as_ref().to_path_buf());
+        self
+    }
+
+    /// Downloads to `dest`.  curl runs unprivileged, resuming a partial download of the same
+    /// url when there is one, and only a file that passed every check is moved into place.
+    pub fn save(&self, dest: &Path) -> io::Result<()> {
+        offline::guard(&self.url)?;
+        if script::recording() {
+            let dest = shell_quote(&dest.to_string_lossy());
+            script::shell(&format!("curl -fsSL --proto '=https' --tlsv1.2 -o {} {}", dest, shell_quote(&self.url)));
+            if let Some(sha256) = &self.sha256 {
+                script::shell(&format!("echo {}' '{} | sha256sum -c -", sha256, dest));
+            }
+            return Ok(());
+        }
+        if runcmd::dry_run(&format!("download {} to {}", self.url, dest.display())) {
+            return Ok(());
+        }
+        // Without root there's no shared cache to resume from, a private workspace will do.
+        let scratch;
+        let dir = match cache_dir() {
+            Some(dir) => dir,
+            None => {
+                scratch = Workspace::create()?;
+                scratch.path().to_path_buf()
+            }
+        };
+        let partial = dir.join(format!("{}.part", &sha256_hex(self.url.as_bytes())[..16]));
+
+        let candidates = candidates(&self.url);
+        let mut failure = None;
+        for (i, url) in candidates.iter().enumerate() {
+            if let Some(e) = &failure {
+                log::log(Level::Warn, &format!("{}, trying {}.", e, candidates[i]));
+            }
+            match self.fetch_from(url, &partial, &dir) {
+                Ok(()) => return install(&partial, dest),
+                Err(e) => failure = Some(e),
+            }
+        }
+        Err(failure.unwrap_or_else(|| io::Error::other(format!("Nowhere to download '{}' from.", self.url))))
+    }
+
+    /// Downloads `url`, the url or one of its mirrors, to `partial` and checks it.
+    fn fetch_from(&self, url: &str, partial: &Path, dir: &Path) -> io::Result<()> {
+        let resumed = partial.exists();
+        let mut result = self.fetch_checked(url, partial, dir);
+        if resumed && matches!(&result, Err(e) if e.kind() == io::ErrorKind::InvalidData) {
+            // What was there before may not have been the start of this file, try once afresh.
+            let _ = fs::remove_file(partial);
+            result = self.fetch_checked(url, partial, dir);
+        }
+        if let Err(e) = &result {
+            if e.kind() == io::ErrorKind::InvalidData {
+                let _ = fs::remove_file(partial);
+            }
+        }
+        result
+    }
+
+    fn fetch_checked(&self, url: &str, partial: &Path, dir: &Path) -> io::Result<()> {
+        curl(url, partial, true)?;
+        if let Some(expected) = &self.sha256 {
+            verify_sha256(url, partial, expected)?;
+        }
+        if let Some(keyring) = &self.keyring {
+            let signature = dir.join(format!("{}.asc", partial.file_name().unwrap_or_default().to_string_lossy()));
+            curl(&format!("{}.asc", url), &signature, false)?;
+            let out = RunCmd::args("gpgv", &[
+                "--keyring", &keyring.to_string_lossy(), &signature.to_string_lossy(), &partial.to_string_lossy(),
+            ]).execute_output();
+            let _ = fs::remove_file(&signature);
+            if out.exitcode != 0 {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+                    "GPG signature of '{}' doesn't verify: {}", url, out.stderr.trim()
+                )));
+            }
+        }
+        Ok(())
+    }
+
+}
+
+/// Checks that `file`, downloaded from `url`, has the hex SHA-256 `expected`.
+pub fn verify_sha256(url: &str, file: &Path, expected: &str) -> io::Result<()> {
+    let actual = sha256_file(file)?;
+    if actual != expected {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!(
+            "Checksum mismatch for '{}': expected {}, got {}", url, expected, actual
+        )));
+    }
+    Ok(())
+}
+
+/// DOWNLOAD_DIR, made and handed to the unprivileged account, None if that can't be done.
+fn cache_dir() -> Option<PathBuf> {
+    let ids = unprivileged()?;
+    fs::create_dir_all(DOWNLOAD_DIR).ok()?;
+    fs::set_permissions(DOWNLOAD_DIR, fs::Permissions::from_mode(0o700)).ok()?;
+    std::os::unix::fs::chown(DOWNLOAD_DIR, Some(ids.uid), Some(ids.gid)).ok()?;
+    Some(PathBuf::from(DOWNLOAD_DIR))
+}
+
+/// curl arguments downloading `url` to `file`, continuing what's there when `resume`.
+pub fn curl_args(tls: &[String], url: &str, file: &str, resume: bool) -> Vec<String> {
+    let mut args: Vec<String> = ["-fsSL", "-o", file].map(String::from).to_vec();
+    if resume {
+        args.extend(["-C", "-"].map(String::from));
+    }
+    args.extend(tls.iter().cloned());
+    args.extend(["--", url].map(String::from));
+    args
+}
+
+fn curl(url: &str, file: &Path, resume: bool) -> io::Result<()> {
+    let mut tls = tls::hardened_curl_args();
+    if let Some(rate) = settings().limit_rate {
+        tls.extend([String::from("--limit-rate"), rate]);
+    }
+    let run = |resume: bool| {
+        let args = curl_args(&tls, url, &file.to_string_lossy(), resume);
+        let args: Vec<&str> = args.iter().map(String::as_str).collect();
+        let out = unprivileged_cmd("curl", &args).retries(NETWORK_RETRIES).execute_output();
+        tls::log(url, &out.stderr);
+        out
+    };
+    let mut out = run(resume);
+    if resume && out.exitcode == CURL_RANGE_ERROR {
+        let _ = fs::remove_file(file);
+        out = run(false);
+    }
+    if out.exitcode != 0 {
+        return Err(Kind::Network.error(format!("Failed to download '{}': {}", url, out.stderr.trim())));
+    }
+    Ok(())
+}
+
+/// Moves the checked download into place in one rename, so `dest` is never half written.
+fn install(partial: &Path, dest: &Path) -> io::Result<()> {
+    let tmp = dest.with_extension("download");
+    fs::copy(partial, &tmp)?;
+    fs::rename(&tmp, dest)?;
+    let _ = fs::remove_file(partial);
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn resume_args() {
+        let args = curl_args(&[String::from("--proto")], "https://repo.bitflux.ai/x.rpm", "/tmp/x.part", true);
+        assert_eq!(args, ["-fsSL", "-o", "/tmp/x.part", "-C", "-", "--proto", "--", "https://repo.bitflux.ai/x.rpm"]);
+        assert!(!curl_args(&[], "https://repo.bitflux.ai/x.rpm", "/tmp/x.part", false).contains(&String::from("-C")));
+    }
+
+    #[test]
+    fn mirrors_fastest_first() {
+        let bases = ["https://a", "https://b", "https://c"].map(String::from);
+        assert_eq!(order(&bases, &[Some(0.8), None, Some(0.2)]), ["https://c", "https://a", "https://b"]);
+        assert_eq!(order(&bases, &[None, None, None]), bases);
+        assert_eq!(candidates("https://example.com/x"), ["https://example.com/x"]);
+        assert!(valid_rate("500K") && valid_rate("2M") && valid_rate("1048576"));
+        assert!(!valid_rate("0") && !valid_rate("2MB") && !valid_rate("fast") && !valid_rate(""));
+    }
+
+    #[test]
+    fn checksum_checked() {
+        let path = std::env::temp_dir().join(format!("bitflux-download-{}", std::process::id()));
+        fs::write(&path, "payload").unwrap();
+        verify_sha256("https://repo.bitflux.ai/x", &path, &sha256_hex(b"payload")).unwrap();
+        let e = verify_sha256("https://repo.bitflux.ai/x", &path, &"0".repeat(64)).unwrap_err();
+        assert_eq!(e.kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(&path).unwrap();
+    }
+
+}
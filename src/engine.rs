@@ -0,0 +1,422 @@
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::budget::{self, Deadline, Policy};
+use crate::events;
+use crate::executor::{self, CommandExecutor};
+use crate::history;
+use crate::interrupt;
+use crate::log::{self, Level};
+use crate::profiling;
+use crate::runcmd;
+use crate::script;
+use crate::spinner::{self, Outcome};
+
+/// One part of a run that says what it needs first, whether it's already done and how to do
+/// and undo it.  `C` is what the steps of a run share, the packages they installed and such.
+pub trait Step<C> {
+
+    fn name(&self) -> &'static str;
+
+    /// What the step does, for the console.
+    fn title(&self) -> &'static str {
+        self.name()
+    }
+
+    /// Steps that have to be applied first.
+    fn after(&self) -> Vec<&'static str> {
+        Vec::new()
+    }
+
+    /// True when the host already has what apply() would make of it, the step is skipped then.
+    /// It notes in `ctx` what it found, the way apply() would have.
+    fn check(&self, _ctx: &mut C) -> io::Result<bool> {
+        Ok(false)
+    }
+
+    fn apply(&self, ctx: &mut C) -> io::Result<()>;
+
+    /// Undoes apply() when a later step failed, true when there was something to undo.  The
+    /// default leaves it to `installer rollback`, the install steps undo what they journaled.
+    fn rollback(&self, _ctx: &mut C) -> io::Result<bool> {
+        Ok(false)
+    }
+
+}
+
+/// Every step the latest run() or check() went through, for `install --output json`.
+static STEPS: Mutex<Vec<StepRecord>> = Mutex::new(Vec::new());
+/// Name and title of each step of the latest run(), in the order it applies them.
+static PLANNED: Mutex<Vec<(String, String)>> = Mutex::new(Vec::new());
+
+/// A step run() ran and how it went, or check() checked.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct StepRecord {
+    pub name: String,
+    pub title: String,
+    pub outcome: Outcome,
+    /// It changed the host, or for check() would: applied, not found done.
+    pub changed: bool,
+    pub duration_secs: f64,
+    /// CPU time of the commands the step ran.
+    pub cpu_secs: f64,
+    /// Peak RSS in KiB of the biggest command the step ran.
+    pub max_rss_kb: u64,
+    pub error: Option<String>,
+}
+
+/// The steps the latest run() ran so far, in order.
+pub fn steps() -> Vec<StepRecord> {
+    STEPS.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// Name and title of every step the latest run() applies, in order, for showing the ones to
+/// come.  Empty before it ordered them.
+pub fn planned() -> Vec<(String, String)> {
+    PLANNED.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+fn record<C>(step: &dyn Step<C>, outcome: Outcome, changed: bool, started: Instant, error: Option<&io::Error>) {
+    history::step(None);
+    let used = runcmd::take_usage();
+    let duration_secs = started.elapsed().as_secs_f64();
+    events::step_finished(step.name(), outcome, duration_secs, error.map(|e| e.to_string()));
+    if runcmd::Context::current().verbosity >= runcmd::VERBOSE_DETAILS {
+        let outcome = format!("{:?}", outcome).to_lowercase();
+        log::console(Level::Debug, &format!("step {} {} after {:.2}s, {:.2?} CPU, {} KiB peak RSS", step.name(), outcome, duration_secs, used.cpu, used.max_rss_kb));
+    }
+    STEPS.lock().unwrap_or_else(|e| e.into_inner()).push(StepRecord {
+        name: String::from(step.name()),
+        title: String::from(step.title()),
+        outcome,
+        changed,
+        duration_secs,
+        cpu_secs: used.cpu.as_secs_f64(),
+        max_rss_kb: used.max_rss_kb,
+        error: error.map(|e| e.to_string()),
+    });
+}
+
+/// What each of `steps` took, one line per step, for the end of an install.
+pub fn summary(steps: &[StepRecord]) -> String {
+    let width = steps.iter().map(|s| s.title.len()).max().unwrap_or(0);
+    let mut text = String::new();
+    for step in steps {
+        let elapsed = spinner::elapsed(Duration::from_secs_f64(step.duration_secs));
+        let took = match step.outcome {
+            Outcome::Skipped => String::from("already done"),
+            Outcome::Done => elapsed,
+            Outcome::Failed => format!("failed after {}", elapsed),
+            Outcome::Interrupted => format!("interrupted after {}", elapsed),
+        };
+        text.push_str(&format!("  {:<w$}  {}", step.title, took, w = width));
+        if step.outcome != Outcome::Skipped && step.max_rss_kb > 0 {
+            text.push_str(&format!(", {:.1}s CPU, {} MiB peak", step.cpu_secs, step.max_rss_kb / 1024));
+        }
+        text.push('\n');
+    }
+    text
+}
+
+/// Whether each of `steps` would change the host, one line per step, for `install --check`.
+pub fn changes(steps: &[StepRecord]) -> String {
+    let width = steps.iter().map(|s| s.title.len()).max().unwrap_or(0);
+    let mut text = String::new();
+    for step in steps {
+        let change = match step.changed {
+            true => "would change",
+            false => "no change",
+        };
+        text.push_str(&format!("  {:<w$}  {}\n", step.title, change, w = width));
+    }
+    text.push_str(&format!("{} of {} steps would change.\n", steps.iter().filter(|s| s.changed).count(), steps.len()));
+    text
+}
+
+/// What run() did with each step, by name.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Report {
+    pub applied: Vec<&'static str>,
+    pub skipped: Vec<&'static str>,
+}
+
+/// The order to apply `steps` in: every step after the ones it names, otherwise in the order
+/// given.  Fails on a step that comes after one that isn't there, or after itself.
+pub fn order<C>(steps: &[Box<dyn Step<C> + '_>]) -> io::Result<Vec<usize>> {
+    let names: Vec<&str> = steps.iter().map(|s| s.name()).collect();
+    let after: Vec<Vec<&str>> = steps.iter().map(|s| s.after()).collect();
+    order_names(&names, &after)
+}
+
+/// order() for anything named that comes after other things by name, `after[i]` the names
+/// `names[i]` comes after.
+pub fn order_names(names: &[&str], after: &[Vec<&str>]) -> io::Result<Vec<usize>> {
+    let mut deps = Vec::new();
+    for (name, after) in names.iter().zip(after) {
+        let mut of = Vec::new();
+        for dep in after {
+            let i = names.iter().position(|n| n == dep)
+                .ok_or_else(|| io::Error::other(format!("Step {} comes after {}, which isn't there.", name, dep)))?;
+            of.push(i);
+        }
+        deps.push(of);
+    }
+    let mut ordered: Vec<usize> = Vec::new();
+    while ordered.len() < names.len() {
+        let next = (0..names.len()).find(|i| !ordered.contains(i) && deps[*i].iter().all(|d| ordered.contains(d)));
+        match next {
+            Some(i) => ordered.push(i),
+            None => {
+                let stuck: Vec<&str> = (0..names.len()).filter(|i| !ordered.contains(i)).map(|i| names[i]).collect();
+                return Err(io::Error::other(format!("Steps {} come after each other.", stuck.join(", "))));
+            }
+        }
+    }
+    Ok(ordered)
+}
+
+/// Applies `steps` in order(), skipping the ones check() finds done unless `force`.  Each one is
+/// shown as "Step 3/6: title" and is a profiling::step, so progress and --profile-run see it.  When one fails the ones applied
+/// before it are rolled back, newest first, unless it was a dry run that changed nothing.  One
+/// that runs out of its budget::Budgets time fails, and with Policy::Abort nothing is rolled back.
+pub fn run<C>(steps: &[Box<dyn Step<C> + '_>], ctx: &mut C, force: bool) -> io::Result<Report> {
+    run_with(steps, ctx, force, executor::current())
+}
+
+/// Like run(), with every command the steps run going to `executor`, a MockExecutor to test
+/// them without touching the host.
+pub fn run_with<C>(steps: &[Box<dyn Step<C> + '_>], ctx: &mut C, force: bool, executor: Arc<dyn CommandExecutor>) -> io::Result<Report> {
+    let _using = executor::using(executor);
+    let mut report = Report::default();
+    let mut applied: Vec<usize> = Vec::new();
+    let (budgets, began) = (budget::current(), Instant::now());
+    let ordered = order(steps)?;
+    let total = ordered.len();
+    STEPS.lock().unwrap_or_else(|e| e.into_inner()).clear();
+    *PLANNED.lock().unwrap_or_else(|e| e.into_inner()) = ordered.iter()
+        .map(|i| (String::from(steps[*i].name()), String::from(steps[*i].title())))
+        .collect();
+    for (n, i) in ordered.into_iter().enumerate() {
+        interrupt::check()?;
+        let step = &steps[i];
+        let _step = profiling::step(step.name());
+        let shown = spinner::start(&format!("Step {}/{}: {}", n + 1, total, step.title()));
+        log::log(Level::Debug, &format!("step {}", step.name()));
+        history::step(Some(step.name()));
+        events::step_started(step.name(), step.title(), n + 1, total);
+        script::step(n + 1, total, step.name(), step.title());
+        let started = Instant::now();
+        // What ran between the steps isn't theirs.
+        runcmd::take_usage();
+        let deadline = Deadline::start(step.name(), budgets.of(step.name(), began.elapsed()));
+        let done = match force {
+            true => Ok(false),
+            false => step.check(ctx),
+        };
+        let done = match done {
+            Ok(false) => step.apply(ctx).map(|_| false),
+            done => done,
+        };
+        // Running out of time fails the step, whatever it made of its killed commands.
+        match deadline.finish().and(done) {
+            Ok(true) => {
+                shown.finish(Outcome::Skipped);
+                record(step.as_ref(), Outcome::Skipped, false, started, None);
+                report.skipped.push(step.name());
+                continue;
+            }
+            Ok(false) => {}
+            Err(e) => {
+                let outcome = match interrupt::received() {
+                    Some(_) => Outcome::Interrupted,
+                    None => Outcome::Failed,
+                };
+                shown.finish(outcome);
+                record(step.as_ref(), outcome, false, started, Some(&e));
+                let abort = e.kind() == io::ErrorKind::TimedOut && budgets.on_timeout == Policy::Abort;
+                if abort {
+                    log::log(Level::Warn, "Leaving the steps applied so far in place, `installer rollback` undoes them.");
+                }
+                if !runcmd::Context::current().dry_run && !abort {
+                    for done in applied.iter().rev().map(|i| &steps[*i]) {
+                        if let Err(undo) = done.rollback(ctx) {
+                            log::log(Level::Warn, &format!("Warning: failed to roll back {}: {}", done.name(), undo));
+                        }
+                    }
+                }
+                return Err(e);
+            }
+        }
+        shown.finish(Outcome::Done);
+        record(step.as_ref(), Outcome::Done, true, started, None);
+        applied.push(i);
+        report.applied.push(step.name());
+    }
+    Ok(report)
+}
+
+/// Only checks `steps` in order(), applying none, for `install --check`: the ones check()
+/// doesn't find done would change the host.  Each is recorded as skipped, changed when it would.
+/// A step whose check needs an earlier one applied is taken as one that would change.
+pub fn check<C>(steps: &[Box<dyn Step<C> + '_>], ctx: &mut C) -> io::Result<Report> {
+    let mut report = Report::default();
+    STEPS.lock().unwrap_or_else(|e| e.into_inner()).clear();
+    for i in order(steps)? {
+        let step = &steps[i];
+        let started = Instant::now();
+        runcmd::take_usage();
+        let done = step.check(ctx).inspect_err(|e| record(step.as_ref(), Outcome::Failed, false, started, Some(e)))?;
+        record(step.as_ref(), Outcome::Skipped, !done, started, None);
+        match done {
+            true => report.skipped.push(step.name()),
+            false => report.applied.push(step.name()),
+        }
+    }
+    Ok(report)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Fake {
+        name: &'static str,
+        after: Vec<&'static str>,
+        done: bool,
+        fails: bool,
+    }
+
+    impl Step<Vec<String>> for Fake {
+
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn after(&self) -> Vec<&'static str> {
+            self.after.clone()
+        }
+
+        fn check(&self, _log: &mut Vec<String>) -> io::Result<bool> {
+            Ok(self.done)
+        }
+
+        fn apply(&self, log: &mut Vec<String>) -> io::Result<()> {
+            match self.fails {
+                true => Err(io::Error::other("boom")),
+                false => {
+                    log.push(format!("apply {}", self.name));
+                    Ok(())
+                }
+            }
+        }
+
+        fn rollback(&self, log: &mut Vec<String>) -> io::Result<bool> {
+            log.push(format!("rollback {}", self.name));
+            Ok(true)
+        }
+
+    }
+
+    fn fake(name: &'static str, after: &[&'static str]) -> Box<dyn Step<Vec<String>>> {
+        Box::new(Fake { name, after: after.to_vec(), done: false, fails: false })
+    }
+
+    #[test]
+    fn dependencies_first() {
+        let steps = vec![fake("service", &["agent"]), fake("agent", &["repository"]), fake("repository", &[]), fake("receipt", &[])];
+        let mut log = Vec::new();
+        let report = run(&steps, &mut log, false).unwrap();
+        assert_eq!(report.applied, ["repository", "agent", "service", "receipt"]);
+        assert!(order(&[fake("a", &["b"]), fake("b", &["a"])]).is_err());
+        assert!(order(&[fake("a", &["missing"])]).is_err());
+    }
+
+    #[test]
+    fn step_summary() {
+        let step = |title: &str, outcome, duration_secs, max_rss_kb| StepRecord {
+            name: String::new(), title: String::from(title), outcome, changed: false, duration_secs, cpu_secs: 3.25, max_rss_kb, error: None,
+        };
+        let steps = [
+            step("Setting up the repository", Outcome::Skipped, 0.1, 0),
+            step("Installing the bitflux kernel", Outcome::Done, 42.5, 317_440),
+            step("Starting the agent", Outcome::Failed, 3.0, 0),
+        ];
+        assert_eq!(summary(&steps), concat!(
+            "  Setting up the repository      already done\n",
+            "  Installing the bitflux kernel  42s, 3.2s CPU, 310 MiB peak\n",
+            "  Starting the agent             failed after 3s\n",
+        ));
+    }
+
+    #[test]
+    fn skips_done_and_rolls_back() {
+        let steps: Vec<Box<dyn Step<Vec<String>>>> = vec![
+            fake("repository", &[]),
+            Box::new(Fake { name: "kernel", after: vec![], done: true, fails: false }),
+            fake("agent", &[]),
+            Box::new(Fake { name: "service", after: vec![], done: false, fails: true }),
+        ];
+        let mut log = Vec::new();
+        assert!(run(&steps, &mut log, false).is_err());
+        assert_eq!(log, ["apply repository", "apply agent", "rollback agent", "rollback repository"]);
+
+        let mut log = Vec::new();
+        assert!(run(&steps[..3], &mut log, true).is_ok());
+        assert_eq!(log, ["apply repository", "apply kernel", "apply agent"]);
+
+        let mut log = Vec::new();
+        let report = check(&steps, &mut log).unwrap();
+        assert_eq!((report.applied, report.skipped), (vec!["repository", "agent", "service"], vec!["kernel"]));
+        assert!(log.is_empty());
+    }
+
+    /// Installs `package` with apt-get, already done when dpkg knows it.
+    struct Package(&'static str);
+
+    impl Step<Vec<String>> for Package {
+
+        fn name(&self) -> &'static str {
+            self.0
+        }
+
+        fn check(&self, _log: &mut Vec<String>) -> io::Result<bool> {
+            Ok(runcmd::RunCmd::args("dpkg-query", &["-W", self.0]).execute_output().exitcode == 0)
+        }
+
+        fn apply(&self, _log: &mut Vec<String>) -> io::Result<()> {
+            runcmd::RunCmd::args("apt-get", &["install", "-y", self.0]).as_root().try_execute()?;
+            Ok(())
+        }
+
+        fn rollback(&self, log: &mut Vec<String>) -> io::Result<bool> {
+            log.push(format!("rollback {}", self.0));
+            Ok(true)
+        }
+
+    }
+
+    #[test]
+    fn commands_through_the_executor() {
+        let mock = Arc::new(executor::MockExecutor::new());
+        mock.on("dpkg-query -W curl", executor::Reply::ok("curl\t8.5.0"))
+            .on("dpkg-query", executor::Reply::exit(1, "no packages found"))
+            .on("apt-get install -y bitfluxcollector", executor::Reply::exit(100, "E: Unable to locate package bitfluxcollector"));
+        let steps: Vec<Box<dyn Step<Vec<String>>>> = vec![Box::new(Package("curl")), Box::new(Package("gnupg")), Box::new(Package("bitfluxcollector"))];
+        let mut log = Vec::new();
+        let e = run_with(&steps, &mut log, false, mock.clone()).unwrap_err();
+        assert!(e.to_string().contains("Unable to locate package"));
+        assert_eq!(log, ["rollback gnupg"]);
+        assert_eq!(mock.commands(), [
+            "dpkg-query -W curl",
+            "dpkg-query -W gnupg",
+            "apt-get install -y gnupg",
+            "dpkg-query -W bitfluxcollector",
+            "apt-get install -y bitfluxcollector",
+        ]);
+    }
+
+}
@@ -0,0 +1,114 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::os::fd::FromRawFd;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::log;
+use crate::runcmd::sensitive;
+use crate::spinner::Outcome;
+
+/// Where the events go, None when nobody asked for them.
+static SINK: Mutex<Option<File>> = Mutex::new(None);
+/// The step running, for the commands it runs.
+static STEP: Mutex<Option<String>> = Mutex::new(None);
+
+/// What happened during the run, one JSON object per line with the time in "at" and the kind
+/// in "event".
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    /// `index` counts from 1 up to `total`, the steps the run applies.
+    StepStarted { step: &'a str, title: &'a str, index: usize, total: usize },
+    /// An external command exited, -1 when it was killed.  Its output is in the install log.
+    CommandExecuted { step: Option<String>, cmd: &'a str, exitcode: i32, duration_secs: f64 },
+    StepFinished { step: &'a str, outcome: Outcome, duration_secs: f64, error: Option<String> },
+    Warning { message: &'a str },
+    Error { message: &'a str },
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    at: String,
+    #[serde(flatten)]
+    event: &'a Event<'a>,
+}
+
+/// `event` as the line it's written as, stamped with `secs` since the epoch.
+pub fn line(event: &Event, secs: u64) -> String {
+    let line = Line { at: log::timestamp(secs), event };
+    sensitive::mask(&serde_json::to_string(&line).unwrap_or_default()) + "\n"
+}
+
+fn open(file: File) {
+    *SINK.lock().unwrap_or_else(|e| e.into_inner()) = Some(file);
+}
+
+/// Writes the events to `fd`, inherited open for writing, from now on.  Commands don't inherit it.
+pub fn open_fd(fd: i32) -> io::Result<()> {
+    if fd <= 2 || unsafe { libc::fcntl(fd, libc::F_SETFD, libc::FD_CLOEXEC) } != 0 {
+        return Err(io::Error::other(format!("--events-fd {}: not an open file descriptor past stderr.", fd)));
+    }
+    open(unsafe { File::from_raw_fd(fd) });
+    Ok(())
+}
+
+/// Appends the events to `path`, a file or a FIFO a dashboard reads, from now on.
+pub fn open_file<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    let path = path.as_ref();
+    let file = OpenOptions::new().create(true).append(true).mode(0o600).open(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("Can't open {} for the events: {}", path.display(), e)))?;
+    open(file);
+    Ok(())
+}
+
+/// Sends `event` as it happens, when the events go somewhere.  A reader that went away
+/// doesn't stop the run, the events stop instead.
+pub fn emit(event: Event) {
+    let mut sink = SINK.lock().unwrap_or_else(|e| e.into_inner());
+    let Some(file) = sink.as_mut() else {
+        return;
+    };
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    if file.write_all(line(&event, secs).as_bytes()).and_then(|_| file.flush()).is_err() {
+        *sink = None;
+    }
+}
+
+pub fn step_started(step: &str, title: &str, index: usize, total: usize) {
+    *STEP.lock().unwrap_or_else(|e| e.into_inner()) = Some(String::from(step));
+    emit(Event::StepStarted { step, title, index, total });
+}
+
+pub fn step_finished(step: &str, outcome: Outcome, duration_secs: f64, error: Option<String>) {
+    *STEP.lock().unwrap_or_else(|e| e.into_inner()) = None;
+    emit(Event::StepFinished { step, outcome, duration_secs, error });
+}
+
+pub fn command_executed(cmd: &str, exitcode: i32, duration_secs: f64) {
+    let step = STEP.lock().unwrap_or_else(|e| e.into_inner()).clone();
+    emit(Event::CommandExecuted { step, cmd, exitcode, duration_secs });
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_as_lines() {
+        let started = Event::StepStarted { step: "agent", title: "Installing the agent", index: 3, total: 6 };
+        assert_eq!(line(&started, 1791980107),
+            "{\"at\":\"2026-10-14T12:15:07Z\",\"event\":\"step_started\",\"step\":\"agent\",\"title\":\"Installing the agent\",\"index\":3,\"total\":6}\n");
+        let finished = Event::StepFinished { step: "agent", outcome: Outcome::Failed, duration_secs: 1.5, error: Some(String::from("apt-get failed")) };
+        let doc: serde_json::Value = serde_json::from_str(&line(&finished, 0)).unwrap();
+        assert_eq!(doc["event"], "step_finished");
+        assert_eq!(doc["outcome"], serde_json::to_value(Outcome::Failed).unwrap());
+        assert_eq!(line(&Event::Warning { message: "low on disk" }, 0), "{\"at\":\"1970-01-01T00:00:00Z\",\"event\":\"warning\",\"message\":\"low on disk\"}\n");
+    }
+
+}
@@ -0,0 +1,101 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+
+use crate::interrupt;
+use crate::signature;
+
+/// Any failure without a code of its own.
+pub const FAILURE: i32 = 1;
+/// The distro, release, architecture or init system isn't one bitflux supports.
+pub const UNSUPPORTED: i32 = 10;
+/// Not root, and no sudo, doas or pkexec to get it, or root wasn't enough.
+pub const PRIVILEGES: i32 = 11;
+/// The repository, a download or the activation endpoint couldn't be reached.
+pub const NETWORK: i32 = 12;
+/// The activation endpoint turned the license key down.
+pub const LICENSE: i32 = 13;
+/// DKMS couldn't build the swaphints module for the running kernel.
+pub const MODULE_BUILD: i32 = 14;
+/// A downloaded binary or bundle failed signature verification.
+pub const SIGNATURE: i32 = 15;
+/// Another installer holds the lock.
+pub const LOCKED: i32 = 16;
+
+/// The failures scripts wrapping the installer can tell apart by the exit code.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Kind {
+    Unsupported,
+    Privileges,
+    Network,
+    License,
+    ModuleBuild,
+}
+
+impl Kind {
+
+    pub fn code(self) -> i32 {
+        match self {
+            Kind::Unsupported => UNSUPPORTED,
+            Kind::Privileges => PRIVILEGES,
+            Kind::Network => NETWORK,
+            Kind::License => LICENSE,
+            Kind::ModuleBuild => MODULE_BUILD,
+        }
+    }
+
+    /// An error with `message` that exits with this kind's code.
+    pub fn error<M: Into<String>>(self, message: M) -> io::Error {
+        io::Error::other(Classified { kind: self, message: message.into() })
+    }
+
+}
+
+/// What Kind::error() wraps, of() finds the kind in it again.
+#[derive(Debug)]
+pub struct Classified {
+    pub kind: Kind,
+    pub message: String,
+}
+
+impl fmt::Display for Classified {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)
+    }
+}
+
+impl Error for Classified {}
+
+/// The exit code for `e`.
+pub fn of(e: &io::Error) -> i32 {
+    if signature::is_signature_error(e) {
+        return SIGNATURE;
+    }
+    if let Some(classified) = e.get_ref().and_then(|inner| inner.downcast_ref::<Classified>()) {
+        return classified.kind.code();
+    }
+    match (e.kind(), interrupt::received()) {
+        // The way a shell reports a command killed by the signal.
+        (io::ErrorKind::Interrupted, Some(sig)) => 128 + sig,
+        (io::ErrorKind::PermissionDenied, _) => PRIVILEGES,
+        (io::ErrorKind::WouldBlock, _) => LOCKED,
+        _ => FAILURE,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn codes_from_errors() {
+        let e = Kind::License.error("License activation failed: 403");
+        assert_eq!(e.to_string(), "License activation failed: 403");
+        assert_eq!(of(&e), LICENSE);
+        assert_eq!(of(&io::Error::from(io::ErrorKind::PermissionDenied)), PRIVILEGES);
+        assert_eq!(of(&io::Error::new(io::ErrorKind::WouldBlock, "Another installer is already running")), LOCKED);
+        assert_eq!(of(&io::Error::other("boom")), FAILURE);
+    }
+
+}
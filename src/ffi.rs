@@ -0,0 +1,174 @@
+//! C ABI for driving the installer in-process, see include/bitflux_installer.h.
+//!
+//! Every call returns a JSON document, `{"ok": true, "result": ...}` or `{"ok": false, "error":
+//! "...", "code": ...}` with the installer's exit code, as a string the caller hands back to
+//! `bitflux_free`.  Panics never cross the boundary.
+
+use std::ffi::{c_char, CStr, CString};
+use std::io;
+use std::panic::{self, UnwindSafe};
+
+use serde::Serialize;
+use serde_json::json;
+
+use crate::audit;
+use crate::engine;
+use crate::exitcode;
+use crate::install::{self, Options};
+use crate::output::CheckResult;
+use crate::receipt::{Receipt, RECEIPT_DIR};
+use crate::repair;
+use crate::runcmd;
+use crate::state;
+use crate::verify;
+
+fn respond<T: Serialize, F: FnOnce() -> io::Result<T> + UnwindSafe>(f: F) -> *mut c_char {
+    runcmd::init();
+    let doc = match panic::catch_unwind(f) {
+        Ok(Ok(result)) => json!({ "ok": true, "result": result }),
+        Ok(Err(e)) => json!({ "ok": false, "error": e.to_string(), "code": exitcode::of(&e) }),
+        Err(_) => json!({ "ok": false, "error": "installer panicked", "code": exitcode::FAILURE }),
+    };
+    // serde_json never emits a NUL byte, it escapes them.
+    CString::new(doc.to_string()).map(CString::into_raw).unwrap_or(std::ptr::null_mut())
+}
+
+/// Version of the installer library.
+#[no_mangle]
+pub extern "C" fn bitflux_version() -> *mut c_char {
+    respond(|| Ok(env!("CARGO_PKG_VERSION")))
+}
+
+/// Drift of the system against the install receipt, what `installer verify --json` prints.
+#[no_mangle]
+pub extern "C" fn bitflux_status() -> *mut c_char {
+    respond(|| Ok(verify::verify(&Receipt::load_verified(RECEIPT_DIR)?)))
+}
+
+/// The `--audit` compliance report, changes nothing.
+#[no_mangle]
+pub extern "C" fn bitflux_audit() -> *mut c_char {
+    respond(|| Ok(audit::audit()))
+}
+
+/// Re-applies whatever drifted from the install receipt, like `installer repair`.  Fails with
+/// exitcode::LOCKED while another installer runs.
+#[no_mangle]
+pub extern "C" fn bitflux_repair() -> *mut c_char {
+    respond(|| {
+        let _lock = state::lock(None)?;
+        repair::run()
+    })
+}
+
+/// The install options in the JSON document at `options`, an `install::Options` like the
+/// answers file has; NULL or "" for the defaults.
+///
+/// # Safety
+///
+/// `options` must be NULL or a NUL terminated string.
+unsafe fn parse_options(options: *const c_char) -> io::Result<Options> {
+    if options.is_null() {
+        return Ok(Options::default());
+    }
+    let text = CStr::from_ptr(options).to_str().map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    match text.trim() {
+        "" => Ok(Options::default()),
+        text => serde_json::from_str(text).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Invalid install options: {}", e))),
+    }
+}
+
+/// What an install with `options` would change, step by step, like `installer install --check
+/// --output json`.  Changes nothing.
+///
+/// # Safety
+///
+/// `options` must be NULL or a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn bitflux_plan(options: *const c_char) -> *mut c_char {
+    // io::Error can't cross catch_unwind, its message can.
+    let options = parse_options(options).map_err(|e| e.to_string());
+    respond(move || {
+        install::check(&options.map_err(io::Error::other)?)?;
+        let steps = engine::steps();
+        Ok(CheckResult { changed: steps.iter().any(|s| s.changed), steps })
+    })
+}
+
+/// Installs with `options`, like `installer install --output json`: what each step did.  Fails
+/// with exitcode::LOCKED while another installer runs.
+///
+/// # Safety
+///
+/// `options` must be NULL or a NUL terminated string.
+#[no_mangle]
+pub unsafe extern "C" fn bitflux_apply(options: *const c_char) -> *mut c_char {
+    let options = parse_options(options).map_err(|e| e.to_string());
+    respond(move || {
+        let options = options.map_err(io::Error::other)?;
+        options.check()?;
+        let _lock = state::lock(None)?;
+        install::run(&options)?;
+        let steps = engine::steps();
+        Ok(CheckResult { changed: steps.iter().any(|s| s.changed), steps })
+    })
+}
+
+/// Frees a string returned by any bitflux_* call.
+///
+/// # Safety
+///
+/// `s` must come from a bitflux_* call and not have been freed already, or be NULL.
+#[no_mangle]
+pub unsafe extern "C" fn bitflux_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CStr;
+
+    fn parse(s: *mut c_char) -> serde_json::Value {
+        let doc = serde_json::from_str(unsafe { CStr::from_ptr(s) }.to_str().unwrap()).unwrap();
+        unsafe { bitflux_free(s) };
+        doc
+    }
+
+    #[test]
+    fn json_envelope() {
+        let doc = parse(bitflux_version());
+        assert_eq!(doc["ok"], true);
+        assert_eq!(doc["result"], env!("CARGO_PKG_VERSION"));
+
+        let doc = parse(respond(|| -> io::Result<()> { Err(io::Error::other("no receipt")) }));
+        assert_eq!(doc, json!({ "ok": false, "error": "no receipt", "code": 1 }));
+
+        let doc = parse(respond(|| -> io::Result<()> { panic!("boom") }));
+        assert_eq!(doc["ok"], false);
+        unsafe { bitflux_free(std::ptr::null_mut()) };
+    }
+
+    #[test]
+    fn plan_and_apply_take_options() {
+        let bad = CString::new("{\"profile\": \"agent\", \"colour\": \"blue\"}").unwrap();
+        for call in [bitflux_plan, bitflux_apply] {
+            let doc = parse(unsafe { call(bad.as_ptr()) });
+            assert_eq!(doc["ok"], false);
+            assert!(doc["error"].as_str().unwrap().starts_with("Invalid install options: unknown field `colour`"));
+        }
+        let invalid = CString::new("not json").unwrap();
+        assert!(parse(unsafe { bitflux_plan(invalid.as_ptr()) })["error"].as_str().unwrap().starts_with("Invalid install options"));
+
+        // Options that parse reach the install, which checks them before it looks at the host.
+        let offline = CString::new("{\"offline\": true}").unwrap();
+        for call in [bitflux_plan, bitflux_apply] {
+            let doc = parse(unsafe { call(offline.as_ptr()) });
+            assert_eq!(doc, json!({ "ok": false, "error": "--offline installs everything from a bundle, pass --bundle <PATH>.", "code": 1 }));
+        }
+    }
+
+}
@@ -0,0 +1,43 @@
+use std::fs;
+
+use crate::log::{self, Level};
+
+/// "1" when the kernel runs in FIPS mode.
+pub const FIPS_ENABLED_PATH: &str = "/proc/sys/crypto/fips_enabled";
+
+/// Parses the contents of FIPS_ENABLED_PATH.
+pub fn parse(data: &str) -> bool {
+    data.trim() == "1"
+}
+
+/// True on FIPS enabled hosts.  Verification then sticks to SHA-256 and pure Ed25519
+/// (FIPS 186-5) and FIPS builds of bitflux are preferred.
+pub fn is_enabled() -> bool {
+    fs::read_to_string(FIPS_ENABLED_PATH).map(|d| parse(&d)).unwrap_or(false)
+}
+
+/// Suffix of the release channel to pull builds from, FIPS hosts get the "-fips" builds.
+pub fn channel_suffix(fips: bool) -> &'static str {
+    if fips { "-fips" } else { "" }
+}
+
+/// Warns about a component that is going onto a FIPS host without being FIPS validated.
+pub fn warn_unvalidated(component: &str) {
+    log::log(Level::Warn, &format!("WARNING: FIPS mode is enabled but '{}' is not a FIPS validated build.", component));
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_fips_enabled() {
+        assert!(parse("1\n"));
+        assert!(!parse("0\n"));
+        assert!(!parse(""));
+        assert_eq!(channel_suffix(true), "-fips");
+        assert_eq!(channel_suffix(false), "");
+    }
+
+}
@@ -0,0 +1,158 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::journal::Journal;
+use crate::runcmd::{self, which, RunCmd};
+use crate::script;
+
+/// Where iptables-persistent and iptables-services load the IPv4 rules from at boot.
+pub const IPTABLES_RULES: &[&str] = &["/etc/iptables/rules.v4", "/etc/sysconfig/iptables"];
+/// Put on the iptables and ufw rules the installer adds.
+const COMMENT: &str = "bitflux";
+
+/// `port` as "<number>/<protocol>", tcp when it names none.
+pub fn validate_port(port: &str) -> Result<String, String> {
+    let (number, proto) = port.split_once('/').unwrap_or((port, "tcp"));
+    match (number.parse::<u16>(), proto) {
+        (Ok(n), "tcp" | "udp") if n > 0 => Ok(format!("{}/{}", n, proto)),
+        (Ok(_), _) => Err(format!("'{}' isn't tcp or udp.", proto)),
+        _ => Err(format!("'{}' isn't a port, give a number from 1 to 65535 and optionally /tcp or /udp.", number)),
+    }
+}
+
+/// The firewall that filters incoming connections.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Firewall {
+    Firewalld,
+    Ufw,
+    Iptables,
+}
+
+impl Firewall {
+
+    /// The active one: firewalld or ufw when running, plain iptables when its INPUT chain
+    /// has any rules or doesn't accept by default.  None when nothing filters.
+    pub fn detect() -> Option<Firewall> {
+        if which("firewall-cmd").is_some() && RunCmd::args("firewall-cmd", &["--state"]).execute_output().exitcode == 0 {
+            return Some(Firewall::Firewalld);
+        }
+        if which("ufw").is_some() && RunCmd::args("ufw", &["status"]).execute_output().stdout.starts_with("Status: active") {
+            return Some(Firewall::Ufw);
+        }
+        if which("iptables").is_some() {
+            let out = RunCmd::args("iptables", &["-S", "INPUT"]).execute_output();
+            if out.exitcode == 0 && out.stdout.trim() != "-P INPUT ACCEPT" {
+                return Some(Firewall::Iptables);
+            }
+        }
+        None
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Firewall::Firewalld => "firewalld",
+            Firewall::Ufw => "ufw",
+            Firewall::Iptables => "iptables",
+        }
+    }
+
+    /// The firewall called `name`, for the journal.
+    pub fn from_name(name: &str) -> Option<Firewall> {
+        [Firewall::Firewalld, Firewall::Ufw, Firewall::Iptables].into_iter().find(|f| f.name() == name)
+    }
+
+    /// True when connections to `port`, as validate_port() gives it, are let in.
+    pub fn is_open(self, port: &str) -> bool {
+        match self {
+            Firewall::Firewalld => RunCmd::args("firewall-cmd", &["--permanent", &format!("--query-port={}", port)]).execute_output().exitcode == 0,
+            Firewall::Ufw => {
+                let out = RunCmd::args("ufw", &["status"]).execute_output();
+                out.stdout.lines().any(|l| l.split_whitespace().next() == Some(port) && l.contains("ALLOW"))
+            }
+            Firewall::Iptables => RunCmd::args("iptables", &iptables_rule("-C", port)).execute_output().exitcode == 0,
+        }
+    }
+
+    /// Lets connections to `port` in, journaled in `journal` first, for good: firewalld's
+    /// permanent config, a ufw rule, or iptables rules saved where they're loaded at boot.
+    pub fn open(self, port: &str, journal: &mut Journal) -> io::Result<()> {
+        journal.firewall(self, port)?;
+        match self {
+            Firewall::Firewalld => {
+                run("firewall-cmd", &["--permanent", &format!("--add-port={}", port)])?;
+                run("firewall-cmd", &["--reload"])
+            }
+            Firewall::Ufw => run("ufw", &["allow", port, "comment", COMMENT]),
+            Firewall::Iptables => {
+                run("iptables", &iptables_rule("-I", port))?;
+                save_iptables(journal)
+            }
+        }
+    }
+
+    /// Takes back what open() did for `port`.  Saved iptables rules are put back by the
+    /// journal along with the other files.
+    pub fn close(self, port: &str) -> io::Result<()> {
+        match self {
+            Firewall::Firewalld => {
+                run("firewall-cmd", &["--permanent", &format!("--remove-port={}", port)])?;
+                run("firewall-cmd", &["--reload"])
+            }
+            Firewall::Ufw => run("ufw", &["delete", "allow", port]),
+            Firewall::Iptables => match self.is_open(port) {
+                true => run("iptables", &iptables_rule("-D", port)),
+                false => Ok(()),
+            },
+        }
+    }
+
+}
+
+/// The iptables arguments `op` ("-I", "-C" or "-D") of the rule that lets `port` in.
+fn iptables_rule<'a>(op: &'a str, port: &'a str) -> Vec<&'a str> {
+    let (number, proto) = port.split_once('/').unwrap_or((port, "tcp"));
+    vec![op, "INPUT", "-p", proto, "--dport", number, "-m", "comment", "--comment", COMMENT, "-j", "ACCEPT"]
+}
+
+/// Saves the running iptables rules to the file they're loaded from at boot, if the host has one.
+fn save_iptables(journal: &mut Journal) -> io::Result<()> {
+    let Some(path) = IPTABLES_RULES.iter().find(|p| Path::new(p).exists()) else {
+        return Ok(());
+    };
+    let out = RunCmd::args("iptables-save", &[]).execute_output();
+    if out.exitcode != 0 {
+        return Err(io::Error::other(format!("iptables-save failed: {}", out.stderr.trim())));
+    }
+    journal.file(path)?;
+    if script::shell(&format!("iptables-save > {}", path)) || runcmd::dry_run(&format!("write {}", path)) {
+        return Ok(());
+    }
+    fs::write(path, out.stdout)
+}
+
+fn run(program: &str, args: &[&str]) -> io::Result<()> {
+    let out = RunCmd::args(program, args).execute_output();
+    match out.exitcode {
+        0 => Ok(()),
+        _ => Err(io::Error::other(format!("{} {} failed: {}", program, args.join(" "), out.stderr.trim()))),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ports() {
+        assert_eq!(validate_port("9100").as_deref(), Ok("9100/tcp"));
+        assert_eq!(validate_port("514/udp").as_deref(), Ok("514/udp"));
+        assert!(validate_port("0").is_err());
+        assert!(validate_port("9100/sctp").is_err());
+        assert!(validate_port("http").is_err());
+        assert_eq!(iptables_rule("-C", "9100/tcp").join(" "), "-C INPUT -p tcp --dport 9100 -m comment --comment bitflux -j ACCEPT");
+        assert_eq!(Firewall::from_name("ufw"), Some(Firewall::Ufw));
+    }
+
+}
@@ -0,0 +1,411 @@
+use std::env;
+use std::fs;
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use serde::Serialize;
+
+use crate::arch::{self, Arch};
+use crate::batch::{self, Job};
+use crate::log::{self, Level};
+use crate::output;
+use crate::runcmd::{shell_quote, RunCmd};
+
+/// Hosts installed at once unless --parallel says otherwise.
+pub const DEFAULT_PARALLEL: usize = 10;
+/// Lines of remote output kept per host in the report.
+const OUTPUT_TAIL_LINES: usize = 20;
+/// Never prompt for passwords or host keys, a host that would ask fails instead of hanging.
+const SSH_OPTIONS: &[&str] = &["-o", "BatchMode=yes", "-o", "ConnectTimeout=15"];
+/// Install options that name a file here, copied along and pointed at the copy.
+const FILE_OPTIONS: &[&str] = &["--config", "--bundle", "--from-plan"];
+
+/// One line of the hosts file: `[user@]host[:port]`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Target {
+    /// What ssh and scp connect to, `user@host` or `host`.
+    pub destination: String,
+    pub port: Option<u16>,
+}
+
+impl Target {
+
+    pub fn parse(line: &str) -> Option<Target> {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            return None;
+        }
+        // IPv6 addresses only take a port in brackets, "[fe80::1]:2222".
+        let (destination, port) = match line.rfind("]:") {
+            Some(i) => (&line[..=i], line[i + 2..].parse().ok()),
+            None => match line.split_once(':') {
+                Some((dest, port)) if !port.contains(':') => (dest, port.parse().ok()),
+                _ => (line, None),
+            },
+        };
+        Some(Target { destination: destination.replace(['[', ']'], ""), port })
+    }
+
+    /// True unless the login is root, then the install runs through `sudo -n`.
+    fn needs_sudo(&self) -> bool {
+        match self.destination.split_once('@') {
+            Some((user, _)) => user != "root",
+            None => unsafe { libc::geteuid() != 0 },
+        }
+    }
+
+    fn ssh_args(&self, remote: &[&str]) -> Vec<String> {
+        let mut args: Vec<String> = SSH_OPTIONS.iter().map(|a| a.to_string()).collect();
+        if let Some(port) = self.port {
+            args.extend([String::from("-p"), port.to_string()]);
+        }
+        args.push(self.destination.clone());
+        // ssh hands the remote side a single string for its shell.
+        args.push(remote.iter().map(|a| shell_quote(a)).collect::<Vec<String>>().join(" "));
+        args
+    }
+
+    fn scp_args(&self, files: &[&Path], dir: &str) -> Vec<String> {
+        let mut args: Vec<String> = SSH_OPTIONS.iter().map(|a| a.to_string()).collect();
+        if let Some(port) = self.port {
+            args.extend([String::from("-P"), port.to_string()]);
+        }
+        args.push(String::from("-q"));
+        args.extend(files.iter().map(|f| f.to_string_lossy().into_owned()));
+        let (login, host) = match self.destination.rsplit_once('@') {
+            Some((user, host)) => (format!("{}@", user), host),
+            None => (String::new(), self.destination.as_str()),
+        };
+        match host.contains(':') {
+            true => args.push(format!("{}[{}]:{}/", login, host, dir)),
+            false => args.push(format!("{}{}:{}/", login, host, dir)),
+        }
+        args
+    }
+
+}
+
+/// Reads a hosts file, one `[user@]host[:port]` per line, `#` starts a comment.
+pub fn load_hosts<P: AsRef<Path>>(path: P) -> io::Result<Vec<Target>> {
+    let path = path.as_ref();
+    let data = fs::read_to_string(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("Can't read the hosts file {}: {}", path.display(), e)))?;
+    let hosts: Vec<Target> = data.lines().filter_map(Target::parse).collect();
+    if hosts.is_empty() {
+        return Err(io::Error::other(format!("No hosts in {}.", path.display())));
+    }
+    Ok(hosts)
+}
+
+#[derive(Clone, Debug)]
+pub struct Options {
+    pub hosts: PathBuf,
+    /// What to pass the remote `installer install`.  The files of FILE_OPTIONS in it are
+    /// copied to each host with the installer.
+    pub args: Vec<String>,
+    /// Installer binary to copy, this one by default.  Point it at the static build for hosts
+    /// with an older glibc, or at a directory of `installer-<arch>` builds for a mixed fleet.
+    pub installer: Option<PathBuf>,
+    pub parallel: usize,
+    /// Where to write the JSON report too.
+    pub report: Option<PathBuf>,
+    /// Show what each remote installer prints as it comes, each line led by the host.
+    pub stream: bool,
+}
+
+/// A file named on the command line, to copy to the hosts.
+#[derive(Clone, Debug, PartialEq)]
+pub struct LocalFile {
+    /// The option of FILE_OPTIONS naming it.
+    pub option: &'static str,
+    /// The index in the arguments of its path.
+    pub at: usize,
+    pub path: PathBuf,
+}
+
+/// The files of FILE_OPTIONS that `args` names.
+pub fn local_files(args: &[String]) -> io::Result<Vec<LocalFile>> {
+    let mut files = Vec::new();
+    for (i, arg) in args.iter().enumerate() {
+        let (name, value) = match arg.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (arg.as_str(), None),
+        };
+        let Some(option) = FILE_OPTIONS.iter().find(|o| **o == name) else {
+            continue;
+        };
+        let (at, path) = match value {
+            Some(path) => (i, path),
+            None => (i + 1, args.get(i + 1).map(String::as_str).ok_or_else(|| io::Error::other(format!("{} needs a path.", name)))?),
+        };
+        if !Path::new(path).is_file() {
+            return Err(io::Error::other(format!("{} {}: no such file here to copy to the hosts.", name, path)));
+        }
+        files.push(LocalFile { option, at, path: PathBuf::from(path) });
+    }
+    Ok(files)
+}
+
+/// The answers file of `files`, named by --config.
+pub fn answers_file(files: &[LocalFile]) -> Option<&Path> {
+    files.iter().find(|f| f.option == "--config").map(|f| f.path.as_path())
+}
+
+/// `args` with the `files` in them pointed at their copies in `dir`.
+pub fn remote_args(args: &[String], files: &[LocalFile], dir: &str) -> Vec<String> {
+    let mut remote = args.to_vec();
+    for file in files {
+        let copy = format!("{}/{}", dir, file.path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default());
+        remote[file.at] = match remote[file.at].split_once('=') {
+            Some((name, _)) if name.starts_with("--") => format!("{}={}", name, copy),
+            _ => copy,
+        };
+    }
+    remote
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Installed,
+    Failed,
+}
+
+/// How the install went on one host.
+#[derive(Clone, Debug, Serialize)]
+pub struct HostResult {
+    pub host: String,
+    pub outcome: Outcome,
+    /// The step that failed: connect, arch, copy, install.
+    pub failed_step: Option<String>,
+    pub exitcode: Option<i32>,
+    pub duration_secs: f64,
+    /// Last lines the remote installer printed.
+    pub output: Vec<String>,
+}
+
+fn tail(out: &str) -> Vec<String> {
+    let lines: Vec<&str> = out.lines().collect();
+    lines[lines.len().saturating_sub(OUTPUT_TAIL_LINES)..].iter().map(|l| l.to_string()).collect()
+}
+
+/// The installer builds at `path`, by architecture: the binary, or every `installer-<arch>`
+/// in the directory.
+pub fn installers(path: &Path) -> io::Result<Vec<(Arch, PathBuf)>> {
+    if path.is_dir() {
+        let builds: Vec<(Arch, PathBuf)> = arch::ALL.iter()
+            .map(|a| (*a, path.join(format!("installer-{}", a.name()))))
+            .filter(|(_, p)| p.is_file())
+            .collect();
+        if builds.is_empty() {
+            return Err(io::Error::other(format!("No installer-<arch> builds in {}.", path.display())));
+        }
+        return Ok(builds);
+    }
+    let mut header = [0; 20];
+    fs::File::open(path).and_then(|mut f| f.read_exact(&mut header))
+        .map_err(|e| io::Error::new(e.kind(), format!("Can't read {}: {}", path.display(), e)))?;
+    match Arch::of_elf(&header) {
+        Some(arch) => Ok(vec![(arch, path.to_path_buf())]),
+        None => Err(io::Error::other(format!("{} isn't an installer for {}.", path.display(), arch::ALL.map(Arch::name).join(", ")))),
+    }
+}
+
+/// The build of `installers` to run on a host whose `uname -m` is `machine`.
+pub fn pick<'a>(installers: &'a [(Arch, PathBuf)], machine: &str) -> Result<&'a Path, String> {
+    let Some(host) = Arch::from_name(machine) else {
+        return Err(arch::unsupported(machine));
+    };
+    if let Some((_, path)) = installers.iter().find(|(a, _)| *a == host) {
+        return Ok(path);
+    }
+    match installers {
+        // A 32-bit ARM userland on a 64-bit kernel.
+        [(Arch::Armhf, path)] if host == Arch::Aarch64 => Ok(path),
+        [(built, path)] => Err(format!("{} is the {} installer and the host is {}, point --installer at a directory with installer-{} in it.",
+            path.display(), built.name(), machine, host.name())),
+        _ => Err(format!("No installer-{} among the --installer builds.", host.name())),
+    }
+}
+
+fn install_host(target: &Target, installers: &[(Arch, PathBuf)], args: &[String], files: &[LocalFile], stream: bool) -> HostResult {
+    let start = Instant::now();
+    let result = |failed: Option<&str>, exitcode: Option<i32>, out: &str| HostResult {
+        host: target.destination.clone(),
+        outcome: if failed.is_none() { Outcome::Installed } else { Outcome::Failed },
+        failed_step: failed.map(String::from),
+        exitcode,
+        duration_secs: start.elapsed().as_secs_f64(),
+        output: tail(out),
+    };
+    let ssh = |remote: &[&str]| {
+        let args = target.ssh_args(remote);
+        RunCmd::args("ssh", &args.iter().map(String::as_str).collect::<Vec<&str>>()).execute_output()
+    };
+
+    let out = ssh(&["uname", "-m"]);
+    if out.exitcode != 0 {
+        return result(Some("connect"), Some(out.exitcode), &out.stderr);
+    }
+    let installer = match pick(installers, out.stdout.trim()) {
+        Ok(installer) => installer,
+        Err(e) => return result(Some("arch"), None, &e),
+    };
+    let out = ssh(&["mktemp", "-d", "-t", "bitflux-installer.XXXXXX"]);
+    if out.exitcode != 0 {
+        return result(Some("connect"), Some(out.exitcode), &out.stderr);
+    }
+    let dir = out.stdout.trim().to_string();
+
+    let installer_name = installer.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    let mut copied = vec![installer];
+    copied.extend(files.iter().map(|f| f.path.as_path()));
+    let scp = target.scp_args(&copied, &dir);
+    let out = RunCmd::args("scp", &scp.iter().map(String::as_str).collect::<Vec<&str>>()).execute_output();
+    let outcome = if out.exitcode != 0 {
+        result(Some("copy"), Some(out.exitcode), &out.stderr)
+    } else {
+        let installer = format!("{}/{}", dir, installer_name);
+        let args = remote_args(args, files, &dir);
+        let mut remote: Vec<&str> = Vec::new();
+        if target.needs_sudo() {
+            remote.extend(["sudo", "-n"]);
+        }
+        remote.extend([installer.as_str(), "--non-interactive", "install"]);
+        remote.extend(args.iter().map(String::as_str));
+        let ssh_args = target.ssh_args(&remote);
+        let mut install = RunCmd::args("ssh", &ssh_args.iter().map(String::as_str).collect::<Vec<&str>>());
+        if stream {
+            install.tee_prefixed(&format!("{}: ", target.destination));
+        }
+        let out = install.execute_output();
+        let transcript = format!("{}{}", out.stdout, out.stderr);
+        match out.exitcode {
+            0 => result(None, Some(0), &transcript),
+            code => result(Some("install"), Some(code), &transcript),
+        }
+    };
+    ssh(&["rm", "-rf", &dir]);
+    outcome
+}
+
+/// The per-host table printed at the end.
+pub fn summary(results: &[HostResult]) -> String {
+    let width = results.iter().map(|r| r.host.len()).max().unwrap_or(4).max(4);
+    let mut out = format!("{:<w$}  {:<9}  {:>8}  detail\n", "host", "outcome", "time", w = width);
+    for r in results {
+        let detail = match &r.failed_step {
+            Some(step) => format!("{} failed (exit {}): {}", step, r.exitcode.unwrap_or(-1), r.output.last().map(String::as_str).unwrap_or("")),
+            None => String::new(),
+        };
+        let outcome = if r.outcome == Outcome::Installed { "installed" } else { "FAILED" };
+        out.push_str(&format!("{:<w$}  {:<9}  {:>7.1}s  {}\n", r.host, outcome, r.duration_secs, detail, w = width));
+    }
+    let failed = results.iter().filter(|r| r.outcome == Outcome::Failed).count();
+    out.push_str(&format!("{} of {} hosts installed, {} failed.\n", results.len() - failed, results.len(), failed));
+    out
+}
+
+/// Installs bitflux on every host of the hosts file over SSH, `parallel` at a time.
+pub fn install(opts: &Options) -> io::Result<()> {
+    let hosts = load_hosts(&opts.hosts)?;
+    let installers = installers(&match &opts.installer {
+        Some(path) => path.clone(),
+        None => env::current_exe()?,
+    })?;
+    let files = local_files(&opts.args)?;
+    if let Some(answers) = answers_file(&files) {
+        // Fail here rather than on every host.
+        crate::install::Options::load(answers)?;
+    }
+
+    log::log(Level::Info, &format!("Installing on {} hosts, {} at a time.", hosts.len(), opts.parallel));
+    let jobs: Vec<Job<HostResult>> = hosts.iter()
+        .map(|target| -> Job<HostResult> {
+            let (installers, files) = (&installers, &files);
+            Box::new(move || {
+                let result = install_host(target, installers, &opts.args, files, opts.stream);
+                if opts.stream {
+                    log::log(Level::Info, &format!("{}: {} after {:.1}s", result.host, if result.outcome == Outcome::Installed { "installed" } else { "FAILED" }, result.duration_secs));
+                }
+                result
+            })
+        })
+        .collect();
+    let results = batch::run_limited(jobs, opts.parallel);
+
+    output::print(&summary(&results))?;
+    if let Some(path) = &opts.report {
+        let data = serde_json::to_string_pretty(&results).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fs::write(path, data)?;
+    }
+    let failed: Vec<&str> = results.iter().filter(|r| r.outcome == Outcome::Failed).map(|r| r.host.as_str()).collect();
+    if !failed.is_empty() {
+        return Err(io::Error::other(format!("Install failed on {}.", failed.join(", "))));
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_hosts() {
+        assert_eq!(Target::parse("  # rack 3"), None);
+        assert_eq!(Target::parse(""), None);
+        assert_eq!(Target::parse("db1.example.com"), Some(Target { destination: String::from("db1.example.com"), port: None }));
+        assert_eq!(Target::parse("ops@10.0.0.7:2222"), Some(Target { destination: String::from("ops@10.0.0.7"), port: Some(2222) }));
+        assert_eq!(Target::parse("[fe80::1]:22"), Some(Target { destination: String::from("fe80::1"), port: Some(22) }));
+    }
+
+    #[test]
+    fn remote_args_are_quoted() {
+        let target = Target { destination: String::from("ops@web1"), port: Some(2222) };
+        let args = target.ssh_args(&["sudo", "-n", "/tmp/bitflux installer/installer", "install"]);
+        assert_eq!(&args[args.len() - 4..], ["-p", "2222", "ops@web1", "sudo -n '/tmp/bitflux installer/installer' install"]);
+        assert!(target.needs_sudo());
+        assert!(!Target { destination: String::from("root@web1"), port: None }.needs_sudo());
+        let v6 = Target::parse("root@[fe80::1]:22").unwrap();
+        assert_eq!(v6.scp_args(&[Path::new("installer")], "/tmp/x").last().unwrap(), "root@[fe80::1]:/tmp/x/");
+    }
+
+    #[test]
+    fn files_go_along() {
+        let answers = env::current_exe().unwrap();
+        let args: Vec<String> = ["--license-key", "K", "--config", &answers.to_string_lossy(), &format!("--bundle={}", answers.display())].map(String::from).to_vec();
+        let files = local_files(&args).unwrap();
+        assert_eq!(files.iter().map(|f| (f.option, f.at)).collect::<Vec<_>>(), [("--config", 3), ("--bundle", 4)]);
+        assert_eq!(answers_file(&files), Some(answers.as_path()));
+        let name = answers.file_name().unwrap().to_string_lossy().into_owned();
+        let remote = remote_args(&args, &files, "/tmp/x");
+        assert_eq!(remote[..3], args[..3]);
+        assert_eq!(remote[3], format!("/tmp/x/{}", name));
+        assert_eq!(remote[4], format!("--bundle=/tmp/x/{}", name));
+        assert!(local_files(&[String::from("--bundle")]).is_err());
+        assert!(local_files(&[String::from("--bundle=/nonexistent/b.tar")]).is_err());
+
+        // A --bundle=PATH first is not taken for the answers.
+        let bundle = env::temp_dir().join(format!("bitflux-fleet-{}-b.tar", std::process::id()));
+        fs::write(&bundle, "").unwrap();
+        let files = local_files(&[format!("--bundle={}", bundle.display())]).unwrap();
+        assert_eq!(files[0].at, 0);
+        assert_eq!(answers_file(&files), None);
+        fs::remove_file(&bundle).unwrap();
+    }
+
+    #[test]
+    fn installer_per_arch() {
+        let mixed = [(Arch::X86_64, PathBuf::from("dist/installer-x86_64")), (Arch::Aarch64, PathBuf::from("dist/installer-aarch64"))];
+        assert_eq!(pick(&mixed, "aarch64"), Ok(Path::new("dist/installer-aarch64")));
+        assert_eq!(pick(&mixed, "armv7l"), Err(String::from("No installer-armhf among the --installer builds.")));
+        assert!(pick(&mixed, "s390x").unwrap_err().contains("this host is s390x"));
+        let one = [(Arch::X86_64, PathBuf::from("installer"))];
+        assert!(pick(&one, "aarch64").unwrap_err().starts_with("installer is the x86_64 installer and the host is aarch64"));
+        let current = env::current_exe().unwrap();
+        assert_eq!(installers(&current).unwrap()[0].0, Arch::current().unwrap());
+    }
+
+}
@@ -0,0 +1,104 @@
+use std::io;
+use std::path::Path;
+
+use clap::ValueEnum;
+
+use crate::install::Options;
+use crate::output;
+use crate::profile;
+use crate::selfupdate::RELEASE_URL;
+use crate::template;
+
+/// Where the snippets put the installer on the new host.
+pub const INSTALLER_PATH: &str = "/usr/local/sbin/bitflux-installer";
+/// Where they put the answers file, the one `install` picks up by itself.
+const ANSWERS_PATH: &str = crate::install::ANSWERS_PATH;
+/// Stands in for the license key when none is given, for the provisioning pipeline to fill in:
+/// Ansible, Jinja or cloud-init's own `## template: jinja`.
+pub const LICENSE_PLACEHOLDER: &str = "{{ license_key }}";
+
+/// What `installer generate` prints.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum Format {
+    /// cloud-config user-data, installing from runcmd on first boot.
+    CloudInit,
+    /// A kickstart %post section, installing from a unit on first boot.
+    Kickstart,
+}
+
+/// The install options for --profile, a built-in one or one of `profiles`, and the rest.
+pub fn options<P: AsRef<Path>>(name: Option<&str>, profiles: P, license_key: Option<String>, open_ports: Vec<String>, reboot: bool) -> io::Result<Options> {
+    let mut opts = Options { license_key, open_ports, reboot, ..Default::default() };
+    if let Some(name) = name {
+        profile::variant(name, profiles)?.apply(&mut opts);
+    }
+    Ok(opts)
+}
+
+/// `opts` as an answers file, only what differs from the defaults and the profile.
+pub fn answers(opts: &Options) -> io::Result<String> {
+    let mut opts = opts.clone();
+    opts.license_key.get_or_insert_with(|| String::from(LICENSE_PLACEHOLDER));
+    let table = |opts: &Options| toml::Table::try_from(opts).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e));
+    let defaults = table(&Options::default())?;
+    let mut answers = table(&opts)?;
+    answers.retain(|key, value| key == "profile" || defaults.get(key) != Some(value));
+    toml::to_string(&answers).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// The commands that fetch this architecture's latest installer on the new host.
+fn fetch() -> String {
+    [
+        format!("url=$(curl -fsSL --proto '=https' --retry 5 \"{}/latest-$(uname -m).json\" | sed -n 's/.*\"url\": *\"\\([^\"]*\\)\".*/\\1/p')", RELEASE_URL),
+        format!("curl -fsSL --proto '=https' --retry 5 -o {} \"$url\"", INSTALLER_PATH),
+        format!("chmod 0755 {}", INSTALLER_PATH),
+    ].join("\n")
+}
+
+/// `text` with every line indented by `width`, for a YAML block.
+fn indent(text: &str, width: usize) -> String {
+    text.lines().map(|line| format!("{:width$}{}", "", line, width = width)).collect::<Vec<String>>().join("\n")
+}
+
+/// The snippet in `format` that installs with `opts`, unattended and quiet.
+pub fn snippet(format: Format, opts: &Options) -> io::Result<String> {
+    let answers = answers(opts)?;
+    let install = format!("{} --quiet install --config {}", INSTALLER_PATH, ANSWERS_PATH);
+    match format {
+        Format::CloudInit => {
+            let script = format!("set -eu\n{}\n{}", fetch(), install);
+            template::render_named("cloud-init.yaml", &[("answers", &indent(&answers, 6)), ("script", &indent(&script, 4))])
+        }
+        Format::Kickstart => template::render_named("kickstart.ks", &[("answers", answers.trim_end()), ("fetch", &fetch()), ("install", &install)]),
+    }
+}
+
+/// `installer generate`: prints the snippet.
+pub fn run(format: Format, opts: &Options) -> io::Result<()> {
+    output::print(&snippet(format, opts)?)?;
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snippets() {
+        let opts = options(Some("agent"), "/nonexistent/profiles.toml", None, vec![String::from("9100")], false).unwrap();
+        assert_eq!(answers(&opts).unwrap(), "license_key = \"{{ license_key }}\"\nopen_ports = [\"9100\"]\nprofile = \"agent\"\n");
+
+        let cloud = snippet(Format::CloudInit, &opts).unwrap();
+        assert!(cloud.starts_with("#cloud-config\n"));
+        assert!(cloud.contains("    content: |\n      license_key = \"{{ license_key }}\"\n"));
+        assert!(cloud.ends_with("    chmod 0755 /usr/local/sbin/bitflux-installer\n    /usr/local/sbin/bitflux-installer --quiet install --config /etc/bitflux/install.toml\n"));
+
+        let opts = Options { license_key: Some(String::from("KEY-1")), reboot: true, ..Default::default() };
+        let kickstart = snippet(Format::Kickstart, &opts).unwrap();
+        assert!(kickstart.contains("<<'BITFLUX_ANSWERS'\nlicense_key = \"KEY-1\"\nprofile = \"agent-kernel\"\nreboot = true\nBITFLUX_ANSWERS\n"));
+        assert!(kickstart.contains("ExecStart=/usr/local/sbin/bitflux-installer --quiet install --config /etc/bitflux/install.toml\n"));
+        assert!(kickstart.ends_with("%end\n"));
+    }
+
+}
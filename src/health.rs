@@ -0,0 +1,118 @@
+use std::io;
+use std::path::PathBuf;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use serde::Deserialize;
+
+use crate::runcmd::{self, RunCmd};
+use crate::service::Service;
+
+/// How often to look again while the agent isn't healthy yet.
+const POLL: Duration = Duration::from_millis(500);
+
+/// When the agent counts as healthy after the install started it, the [health] table of
+/// installer.toml.  It always has to be active; with `url` that has to answer, with
+/// `ready_file` that has to exist.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Health {
+    /// How long the agent gets to become healthy before the install fails.
+    pub timeout_secs: u64,
+    /// The agent's health endpoint, a 2xx answer is healthy.
+    pub url: Option<String>,
+    /// A file the agent writes once it's ready.
+    pub ready_file: Option<PathBuf>,
+}
+
+impl Default for Health {
+
+    fn default() -> Health {
+        Health { timeout_secs: 60, url: None, ready_file: None }
+    }
+
+}
+
+impl Health {
+
+    /// Looks once whether `service` is healthy, why not when it isn't.
+    pub fn probe(&self, service: &Service) -> Result<(), String> {
+        let active = service.status().active;
+        if active != "active" {
+            return Err(format!("{} is {}", service.name, active));
+        }
+        if let Some(url) = &self.url {
+            let answer = RunCmd::args("curl", &["-fsS", "-o", "/dev/null", "--max-time", "5", url]).execute_output();
+            if answer.exitcode != 0 {
+                return Err(format!("{} doesn't answer: {}", url, answer.stderr.trim()));
+            }
+        }
+        match &self.ready_file {
+            Some(path) if !path.exists() => Err(format!("{} isn't there yet", path.display())),
+            _ => Ok(()),
+        }
+    }
+
+    /// Waits up to timeout_secs for `service` to be healthy.  Fails as soon as it has failed,
+    /// or when time's up, with why and the end of its log.  Without a running init system
+    /// there's nothing to wait for.
+    pub fn wait(&self, service: &Service) -> io::Result<()> {
+        if !service.running() || runcmd::Context::current().dry_run {
+            return Ok(());
+        }
+        let started = Instant::now();
+        let timeout = Duration::from_secs(self.timeout_secs);
+        let why = loop {
+            let why = match self.probe(service) {
+                Ok(()) => return Ok(()),
+                Err(why) => why,
+            };
+            if why.ends_with(" is failed") {
+                break format!("{} failed {}s into the {}s it had to become healthy", service.name, started.elapsed().as_secs(), self.timeout_secs);
+            }
+            if started.elapsed() >= timeout {
+                break format!("{} didn't become healthy within {}s, {}", service.name, self.timeout_secs, why);
+            }
+            thread::sleep(POLL);
+        };
+        Err(io::Error::other(format!("{}:\n{}", why, service.logs(20).trim_end())))
+    }
+
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::executor::{self, MockExecutor, Reply};
+    use crate::platform::Init;
+
+    #[test]
+    fn healthy_once_it_answers() {
+        let agent = Service::under("bitfluxcollector", Some(Init::Systemd));
+        let health = Health { url: Some(String::from("http://127.0.0.1:9100/health")), ..Default::default() };
+        let mock = Arc::new(MockExecutor::new());
+        mock.on("systemctl is-active", Reply::ok("active\n"))
+            .once("curl", Reply::exit(7, "curl: (7) Failed to connect"));
+        let _using = executor::using(mock.clone());
+        assert!(health.wait(&agent).is_ok());
+        assert_eq!(mock.commands().iter().filter(|c| c.starts_with("curl")).count(), 2);
+
+        let mock = Arc::new(MockExecutor::new());
+        mock.on("systemctl is-active", Reply::ok("failed\n"))
+            .on("journalctl", Reply::ok("bitfluxcollector: license rejected\n"));
+        let _using = executor::using(mock.clone());
+        let e = health.wait(&agent).unwrap_err();
+        assert_eq!(e.to_string(), "bitfluxcollector failed 0s into the 60s it had to become healthy:\nbitfluxcollector: license rejected");
+
+        let health = Health { timeout_secs: 0, ..health };
+        let mock = Arc::new(MockExecutor::new());
+        mock.on("systemctl is-active", Reply::ok("activating\n"))
+            .on("journalctl", Reply::ok("bitfluxcollector: starting\n"));
+        let _using = executor::using(mock.clone());
+        let e = health.wait(&agent).unwrap_err();
+        assert_eq!(e.to_string(), "bitfluxcollector didn't become healthy within 0s, bitfluxcollector is activating:\nbitfluxcollector: starting");
+    }
+
+}
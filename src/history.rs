@@ -0,0 +1,309 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::log::{self, Level};
+use crate::output;
+use crate::runcmd::sensitive::{self, MASK};
+use crate::runcmd::shell::Shell;
+use crate::runcmd::{RunCmd, RunCmdOutput};
+
+/// Where the runs that change the host keep what each of their commands did, one file of JSON
+/// lines per run, in state::STATE_DIR.
+pub const HISTORY_DIR: &str = "/var/lib/bitflux-installer/history";
+/// Output kept of each stream, its end, where the error usually is.
+pub const MAX_OUTPUT: usize = 4096;
+/// Runs kept, the oldest go first.
+pub const KEEP: usize = 20;
+
+/// The history of this run, None when it keeps none.
+static HISTORY: Mutex<Option<History>> = Mutex::new(None);
+
+/// One command of a run.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct Entry {
+    /// Its number in the run, from 1.
+    pub n: usize,
+    /// The install step it ran in, None outside of one.
+    pub step: Option<String>,
+    pub cmd: String,
+    /// The program and its arguments, None for a command line.
+    pub argv: Option<Vec<String>>,
+    /// The shell that ran the command line.
+    pub shell: Option<String>,
+    pub root: bool,
+    pub cwd: PathBuf,
+    /// The variables set for it on top of the installer's environment.
+    pub env: Vec<(String, String)>,
+    pub exitcode: i32,
+    pub duration_secs: f64,
+    /// The end of its output, empty when it wasn't `captured`: it went to the terminal or it
+    /// may echo a secret.
+    pub stdout: String,
+    pub stderr: String,
+    pub captured: bool,
+    /// Output was left out, by RunCmd or to keep MAX_OUTPUT.
+    pub truncated: bool,
+    /// It was fed a secret on stdin, or a sensitive value is masked in it, so it can't be
+    /// replayed.
+    pub secret: bool,
+}
+
+/// The last `max` bytes of `text`, and whether that left anything out.
+fn tail(text: &str, max: usize) -> (String, bool) {
+    if text.len() <= max {
+        return (String::from(text), false);
+    }
+    let mut start = text.len() - max;
+    while !text.is_char_boundary(start) {
+        start += 1;
+    }
+    (String::from(&text[start..]), true)
+}
+
+impl Entry {
+
+    /// The entry of `out`, number `n` in the run, that finished after `took` in `step`.
+    pub fn new(n: usize, step: Option<String>, out: &RunCmdOutput, took: Duration, captured: bool) -> Entry {
+        let ((stdout, cut_stdout), (stderr, cut_stderr)) = match captured {
+            true => (tail(&sensitive::mask(&out.stdout), MAX_OUTPUT), tail(&sensitive::mask(&out.stderr), MAX_OUTPUT)),
+            false => ((String::new(), false), (String::new(), false)),
+        };
+        let argv: Option<Vec<String>> = out.argv.as_ref().map(|argv| argv.iter().map(|a| sensitive::mask(a)).collect());
+        let env: Vec<(String, String)> = out.env.iter().map(|(k, v)| (k.clone(), sensitive::mask(v))).collect();
+        let masked = out.cmd.contains(MASK) || env.iter().any(|(_, v)| v.contains(MASK));
+        Entry {
+            n,
+            step,
+            cmd: out.cmd.clone(),
+            argv,
+            shell: out.shell.map(|s| String::from(s.program())),
+            root: out.root,
+            cwd: out.cwd.clone(),
+            env,
+            exitcode: out.exitcode,
+            duration_secs: took.as_secs_f64(),
+            stdout,
+            stderr,
+            captured,
+            truncated: cut_stdout || cut_stderr || out.stdout_truncated > 0 || out.stderr_truncated > 0,
+            secret: out.secret || masked,
+        }
+    }
+
+    /// The command to run it again with, as it was started.
+    pub fn command(&self) -> RunCmd {
+        let mut cmd = match (&self.argv, self.shell.as_deref().and_then(Shell::from_name)) {
+            (Some(argv), _) => {
+                let args: Vec<&str> = argv[1..].iter().map(String::as_str).collect();
+                RunCmd::args(&argv[0], &args)
+            }
+            (None, Some(shell)) => {
+                let mut cmd = RunCmd::new(&self.cmd);
+                cmd.shell_with(shell);
+                cmd
+            }
+            (None, None) => RunCmd::new(&self.cmd),
+        };
+        if self.root {
+            cmd.as_root();
+        }
+        cmd.cwd(&self.cwd);
+        for (key, value) in &self.env {
+            cmd.env(key, value);
+        }
+        cmd
+    }
+
+}
+
+/// The history file of a run.
+pub struct History {
+    file: File,
+    /// Commands recorded so far.
+    count: usize,
+    step: Option<String>,
+}
+
+impl History {
+
+    /// Starts the history of run `id` in `dir`, leaving the KEEP - 1 newest runs there.
+    pub fn create<P: AsRef<Path>>(dir: P, id: &str) -> io::Result<History> {
+        let dir = dir.as_ref();
+        fs::DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+        let runs = ids(dir)?;
+        for old in runs.iter().take((runs.len() + 1).saturating_sub(KEEP)) {
+            let _ = fs::remove_file(path(dir, old));
+        }
+        let file = OpenOptions::new().create_new(true).append(true).mode(0o600).open(path(dir, id))?;
+        Ok(History { file, count: 0, step: None })
+    }
+
+    pub fn record(&mut self, out: &RunCmdOutput, took: Duration, captured: bool) -> io::Result<()> {
+        self.count += 1;
+        let entry = Entry::new(self.count, self.step.clone(), out, took, captured);
+        let line = serde_json::to_string(&entry).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        writeln!(self.file, "{}", line)
+    }
+
+}
+
+/// The history file of run `id` in `dir`.
+pub fn path(dir: &Path, id: &str) -> PathBuf {
+    dir.join(format!("{}.jsonl", id))
+}
+
+/// Keeps the history of this run in HISTORY_DIR, named after the time it started.  Returns its
+/// id.
+pub fn start() -> io::Result<String> {
+    let id = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0).to_string();
+    *HISTORY.lock().unwrap_or_else(|e| e.into_inner()) = Some(History::create(HISTORY_DIR, &id)?);
+    Ok(id)
+}
+
+/// Commands from now on ran in the install step `name`, or outside of any with None.
+pub fn step(name: Option<&str>) {
+    if let Some(history) = HISTORY.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        history.step = name.map(String::from);
+    }
+}
+
+/// Adds `out` to the history of this run, if it keeps one.
+pub fn record(out: &RunCmdOutput, took: Duration, captured: bool) {
+    if let Some(history) = HISTORY.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        if let Err(e) = history.record(out, took, captured) {
+            log::file(log::Level::Warn, &format!("Warning: can't add {} to the command history: {}", out.cmd, e));
+        }
+    }
+}
+
+/// The runs with a history in `dir`, oldest first.
+pub fn ids<P: AsRef<Path>>(dir: P) -> io::Result<Vec<String>> {
+    let mut ids: Vec<String> = match fs::read_dir(dir) {
+        Ok(entries) => entries.filter_map(|e| e.ok())
+            .filter_map(|e| e.file_name().to_str().and_then(|n| n.strip_suffix(".jsonl")).map(String::from))
+            .collect(),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e),
+    };
+    // Ids are start times, a shorter one is older.
+    ids.sort_by(|a, b| a.len().cmp(&b.len()).then(a.cmp(b)));
+    Ok(ids)
+}
+
+/// The commands of run `id` in `dir`.
+pub fn load<P: AsRef<Path>>(dir: P, id: &str) -> io::Result<Vec<Entry>> {
+    let path = path(dir.as_ref(), id);
+    let file = File::open(&path).map_err(|e| match e.kind() {
+        io::ErrorKind::NotFound => io::Error::new(e.kind(), format!("There's no run {}, `installer history` lists them.", id)),
+        _ => io::Error::new(e.kind(), format!("Can't read {}: {}", path.display(), e)),
+    })?;
+    let mut entries = Vec::new();
+    for line in BufReader::new(file).lines() {
+        let line = line?;
+        // A run killed half way through a line leaves the rest of the file intact.
+        if let Ok(entry) = serde_json::from_str(&line) {
+            entries.push(entry);
+        }
+    }
+    Ok(entries)
+}
+
+/// Command `n` of `entries`, the last one that failed with None.
+fn pick(entries: &[Entry], n: Option<usize>) -> io::Result<&Entry> {
+    match n {
+        Some(n) => entries.iter().find(|e| e.n == n)
+            .ok_or_else(|| io::Error::other(format!("The run has commands 1 to {}, there's no {}.", entries.len(), n))),
+        None => entries.iter().rev().find(|e| e.exitcode != 0)
+            .ok_or_else(|| io::Error::other("No command of the run failed, pick one with --step N.")),
+    }
+}
+
+/// `installer history`: the runs of `dir`, the commands of run `id`, or all about its command
+/// `n`.
+pub fn show<P: AsRef<Path>>(dir: P, id: Option<&str>, n: Option<usize>) -> io::Result<()> {
+    let dir = dir.as_ref();
+    let Some(id) = id else {
+        for id in ids(dir)? {
+            let entries = load(dir, &id)?;
+            let failed = entries.iter().filter(|e| e.exitcode != 0).count();
+            let started = id.parse().map(log::timestamp).unwrap_or_default();
+            output::print(&format!("{}  {}  {} commands, {} failed\n", id, started, entries.len(), failed))?;
+        }
+        return Ok(());
+    };
+    let entries = load(dir, id)?;
+    if n.is_none() {
+        for e in &entries {
+            output::print(&format!("{:>4}  {:<10}  exit {:<3}  {:>7.2}s  {}\n", e.n, e.step.as_deref().unwrap_or("-"), e.exitcode, e.duration_secs, e.cmd))?;
+        }
+        return Ok(());
+    }
+    let e = pick(&entries, n)?;
+    output::print(&format!("cmd: {}\nstep: {}\ncwd: {}\nas root: {}\n", e.cmd, e.step.as_deref().unwrap_or("-"), e.cwd.display(), e.root))?;
+    for (key, value) in &e.env {
+        output::print(&format!("env: {}={}\n", key, value))?;
+    }
+    output::print(&format!("exitcode: {} after {:.2}s\n", e.exitcode, e.duration_secs))?;
+    match e.captured {
+        true => output::print(&format!("stdout:\n{}\nstderr:\n{}\n", e.stdout.trim_end(), e.stderr.trim_end()))?,
+        false => output::print("output not kept, it went to the terminal or may show a secret\n")?,
+    }
+    Ok(())
+}
+
+/// `installer replay`: runs command `n` of run `id` in `dir` again, the last one that failed
+/// without `n`, showing its output.  Fails when it fails again.
+pub fn replay<P: AsRef<Path>>(dir: P, id: &str, n: Option<usize>) -> io::Result<()> {
+    let entries = load(dir, id)?;
+    let entry = pick(&entries, n)?;
+    if entry.secret {
+        return Err(io::Error::other(format!("Command {} was given a secret that isn't kept, it can't be replayed.", entry.n)));
+    }
+    log::log(Level::Info, &format!("Replaying command {} of run {}, it exited with {}: {}", entry.n, id, entry.exitcode, entry.cmd));
+    let out = entry.command().tee().execute_output();
+    match out.exitcode {
+        0 => Ok(()),
+        code => Err(io::Error::other(format!("{} exited with {} again.", entry.cmd, code))),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::executor::{self, MockExecutor, Reply};
+
+    #[test]
+    fn kept_and_replayed() {
+        let dir = std::env::temp_dir().join(format!("bitflux-history-{}", std::process::id()));
+        let mut history = History::create(&dir, "100").unwrap();
+        let mock = Arc::new(MockExecutor::new());
+        mock.on("apt-get", Reply::exit(100, "E: Unable to locate package bitfluxcollector"));
+        let _using = executor::using(mock.clone());
+        let out = RunCmd::args("apt-get", &["install", "-y", "bitfluxcollector"]).as_root().env("DEBIAN_FRONTEND", "noninteractive").execute_output();
+        history.step = Some(String::from("agent"));
+        history.record(&out, Duration::from_secs(2), true).unwrap();
+        assert_eq!(ids(&dir).unwrap(), ["100"]);
+
+        let entries = load(&dir, "100").unwrap();
+        let entry = pick(&entries, None).unwrap();
+        assert_eq!((entry.n, entry.step.as_deref(), entry.root, entry.exitcode), (1, Some("agent"), true, 100));
+        assert_eq!(entry.env, [(String::from("DEBIAN_FRONTEND"), String::from("noninteractive"))]);
+        assert!(entry.stderr.starts_with("E: Unable"));
+        assert!(replay(&dir, "100", Some(1)).unwrap_err().to_string().ends_with("exited with 100 again."));
+        let again = mock.calls().pop().unwrap();
+        assert_eq!((again.cmd.as_str(), again.root), ("apt-get install -y bitfluxcollector", true));
+        assert!(pick(&entries, Some(2)).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(tail("ééé", 3), (String::from("é"), true));
+    }
+
+}
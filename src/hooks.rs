@@ -0,0 +1,153 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::engine::Step;
+use crate::runcmd::RunCmd;
+use crate::sanitize;
+
+/// Executables run before and after the whole install, pre-install and post-install.
+pub const HOOKS_DIR: &str = "/etc/bitflux/hooks.d";
+
+/// The per-step hooks of the [hooks] table of installer.toml: shell command lines run as
+/// root before and after a step is applied, by step name.  Skipped steps don't run theirs.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Hooks {
+    pub pre: BTreeMap<String, String>,
+    pub post: BTreeMap<String, String>,
+}
+
+impl Hooks {
+
+    /// Fails on a hook for a step that isn't one of `known`, a typo would never run.
+    pub fn check(&self, known: &[&str]) -> io::Result<()> {
+        match self.pre.keys().chain(self.post.keys()).find(|name| !known.contains(&name.as_str())) {
+            Some(name) => Err(io::Error::other(format!("There's a hook for step {}, the steps are {}.", name, known.join(", ")))),
+            None => Ok(()),
+        }
+    }
+
+    /// `steps` with the hooks for each wrapped around it.
+    pub fn wrap<'a, C: 'a>(&self, steps: Vec<Box<dyn Step<C> + 'a>>) -> Vec<Box<dyn Step<C> + 'a>> {
+        steps.into_iter()
+            .map(|step| {
+                let pre = self.pre.get(step.name()).cloned();
+                let post = self.post.get(step.name()).cloned();
+                match pre.is_none() && post.is_none() {
+                    true => step,
+                    false => Box::new(Hooked { step, pre, post }) as Box<dyn Step<C> + 'a>,
+                }
+            })
+            .collect()
+    }
+
+}
+
+/// A step with the hooks to run around its apply().
+struct Hooked<'a, C> {
+    step: Box<dyn Step<C> + 'a>,
+    pre: Option<String>,
+    post: Option<String>,
+}
+
+impl<C> Step<C> for Hooked<'_, C> {
+
+    fn name(&self) -> &'static str {
+        self.step.name()
+    }
+
+    fn title(&self) -> &'static str {
+        self.step.title()
+    }
+
+    fn after(&self) -> Vec<&'static str> {
+        self.step.after()
+    }
+
+    fn check(&self, ctx: &mut C) -> io::Result<bool> {
+        self.step.check(ctx)
+    }
+
+    fn apply(&self, ctx: &mut C) -> io::Result<()> {
+        if let Some(line) = &self.pre {
+            run(RunCmd::new(line).shell().env("BITFLUX_STEP", self.name()), "pre", &format!("pre hook of {}", self.name()))?;
+        }
+        self.step.apply(ctx)?;
+        if let Some(line) = &self.post {
+            run(RunCmd::new(line).shell().env("BITFLUX_STEP", self.name()), "post", &format!("post hook of {}", self.name()))?;
+        }
+        Ok(())
+    }
+
+    fn rollback(&self, ctx: &mut C) -> io::Result<bool> {
+        self.step.rollback(ctx)
+    }
+
+}
+
+/// Runs `cmd` as root with BITFLUX_HOOK set to `hook`, failing as the `what` that failed.
+fn run(cmd: &mut RunCmd, hook: &str, what: &str) -> io::Result<()> {
+    cmd.as_root().env("BITFLUX_HOOK", hook).try_execute()
+        .map(|_| ())
+        .map_err(|e| io::Error::other(format!("The {} failed: {}", what, e)))
+}
+
+/// Runs `dir`/`name`, pre-install or post-install, when it's there, with BITFLUX_PROFILE set to
+/// `profile`.  Refuses one that someone other than root could have replaced.
+pub fn global<P: AsRef<Path>>(dir: P, name: &str, profile: &str) -> io::Result<()> {
+    let path: PathBuf = dir.as_ref().join(name);
+    let Ok(meta) = path.metadata() else {
+        return Ok(());
+    };
+    if !meta.is_file() || meta.permissions().mode() & 0o111 == 0 {
+        return Err(io::Error::other(format!("The {} hook {} isn't an executable file.", name, path.display())));
+    }
+    if let Some(why) = sanitize::untrusted(&path) {
+        return Err(io::Error::other(format!("Not running the {} hook as root: {}.", name, why)));
+    }
+    run(RunCmd::args(&path.to_string_lossy(), &[]).env("BITFLUX_PROFILE", profile), name, &format!("{} hook", name))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::engine;
+    use crate::executor::{MockExecutor, Reply};
+
+    struct Noop(&'static str);
+
+    impl Step<()> for Noop {
+
+        fn name(&self) -> &'static str {
+            self.0
+        }
+
+        fn apply(&self, _ctx: &mut ()) -> io::Result<()> {
+            Ok(())
+        }
+
+    }
+
+    #[test]
+    fn hooks_around_steps() {
+        let hooks: Hooks = toml::from_str("[pre]\nagent = \"/usr/local/sbin/drain\"\n[post]\nservice = \"cmdb-register --host $(hostname)\"\n").unwrap();
+        assert!(hooks.check(&["agent", "service"]).is_ok());
+        assert!(hooks.check(&["agent"]).is_err());
+        let steps: Vec<Box<dyn Step<()>>> = vec![Box::new(Noop("agent")), Box::new(Noop("service"))];
+        let mock = Arc::new(MockExecutor::new());
+        mock.on("cmdb-register", Reply::exit(2, "CMDB unreachable"));
+        let e = engine::run_with(&hooks.wrap(steps), &mut (), false, mock.clone()).unwrap_err();
+        assert_eq!(e.to_string(), "The post hook of service failed: 'cmdb-register --host $(hostname)' failed with exit code 2: CMDB unreachable");
+        assert_eq!(mock.commands(), ["/usr/local/sbin/drain", "cmdb-register --host $(hostname)"]);
+        assert!(mock.calls()[0].root);
+        assert_eq!(mock.calls()[0].env, [("BITFLUX_STEP", "agent"), ("BITFLUX_HOOK", "pre")].map(|(k, v)| (String::from(k), String::from(v))));
+        assert!(global("/nonexistent/bitflux", "pre-install", "agent").is_ok());
+    }
+
+}
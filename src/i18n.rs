@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+use std::env;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::RwLock;
+
+/// Where translations are looked for, `<lang>.toml` in the first that has one.  Adding a file
+/// adds a language, no rebuild needed.
+pub const LOCALE_DIRS: &[&str] = &["/etc/bitflux/locale", "/usr/share/bitflux/locale"];
+
+/// The translations in use, None for English.
+static CATALOG: RwLock<Option<Catalog>> = RwLock::new(None);
+
+/// The messages of one language.  Keys are the English messages as the installer prints them,
+/// with `{}` standing for the parts that vary, each translated in turn.  A translation puts
+/// them back with `{}` in the same order, or `{1}`, `{2}` to reorder them:
+///
+/// ```toml
+/// "Please answer yes or no." = "Bitte mit ja oder nein antworten."
+/// "{}, done in {}" = "{1}, fertig nach {2}"
+/// ```
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Catalog {
+    exact: HashMap<String, String>,
+    /// The keys with `{}`, split at them, longest first so the most specific one wins.
+    patterns: Vec<(Vec<String>, String)>,
+}
+
+impl Catalog {
+
+    pub fn parse(data: &str) -> Result<Catalog, String> {
+        let entries: HashMap<String, String> = toml::from_str(data).map_err(|e| e.to_string())?;
+        let mut catalog = Catalog::default();
+        for (key, value) in entries {
+            match key.contains("{}") {
+                true => catalog.patterns.push((key.split("{}").map(String::from).collect(), value)),
+                false => {
+                    catalog.exact.insert(key, value);
+                }
+            }
+        }
+        catalog.patterns.sort_by_key(|(parts, _)| std::cmp::Reverse(parts.iter().map(String::len).sum::<usize>()));
+        Ok(catalog)
+    }
+
+    /// Reads the catalog for `lang` from the first of `dirs` with one, trying "pt_BR" before
+    /// "pt" for "pt_BR".  None when there's none.
+    pub fn load(dirs: &[&str], lang: &str) -> io::Result<Option<Catalog>> {
+        let base = lang.split('_').next().unwrap_or(lang);
+        for name in [lang, base] {
+            for dir in dirs {
+                let path = Path::new(dir).join(format!("{}.toml", name));
+                match fs::read_to_string(&path) {
+                    Ok(data) => return Catalog::parse(&data)
+                        .map(Some)
+                        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e))),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(io::Error::new(e.kind(), format!("Can't read {}: {}", path.display(), e))),
+                }
+            }
+        }
+        Ok(None)
+    }
+
+    /// `message` in this language, line by line when there's nothing for all of it, as it
+    /// is when there's no translation.
+    pub fn translate(&self, message: &str) -> String {
+        if let Some(done) = self.one(message) {
+            return done;
+        }
+        if !message.contains('\n') {
+            return String::from(message);
+        }
+        let lines: Vec<String> = message.split('\n').map(|line| {
+            let text = line.trim_start();
+            let indent = &line[..line.len() - text.len()];
+            self.one(text).map_or_else(|| String::from(line), |t| format!("{}{}", indent, t))
+        }).collect();
+        lines.join("\n")
+    }
+
+    fn one(&self, message: &str) -> Option<String> {
+        if message.is_empty() {
+            return None;
+        }
+        if let Some(done) = self.exact.get(message) {
+            return Some(done.clone());
+        }
+        self.patterns.iter().find_map(|(parts, translation)| {
+            let values = captures(parts, message)?;
+            Some(fill(translation, &values.iter().map(|v| self.translate(v)).collect::<Vec<_>>()))
+        })
+    }
+
+}
+
+/// What `message` has in place of each `{}` between `parts`, None when it doesn't fit them.
+fn captures<'a>(parts: &[String], message: &'a str) -> Option<Vec<&'a str>> {
+    let (first, rest) = parts.split_first()?;
+    let (last, middle) = rest.split_last()?;
+    let mut left = message.strip_prefix(first.as_str())?.strip_suffix(last.as_str())?;
+    let mut values = Vec::new();
+    for part in middle {
+        let at = left.find(part.as_str())?;
+        values.push(&left[..at]);
+        left = &left[at + part.len()..];
+    }
+    values.push(left);
+    Some(values)
+}
+
+/// `translation` with `values` in place of its `{}`, in order, and `{N}`, the Nth.
+fn fill(translation: &str, values: &[String]) -> String {
+    let mut out = String::new();
+    let mut rest = translation;
+    let mut next = 0;
+    while let Some(at) = rest.find('{') {
+        out.push_str(&rest[..at]);
+        rest = &rest[at..];
+        let end = rest.find('}').unwrap_or(0);
+        let value = match &rest[1..end.max(1)] {
+            _ if end == 0 => None,
+            "" => {
+                next += 1;
+                values.get(next - 1)
+            }
+            n => n.parse::<usize>().ok().and_then(|n| values.get(n.wrapping_sub(1))),
+        };
+        match value {
+            Some(value) => {
+                out.push_str(value);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                out.push('{');
+                rest = &rest[1..];
+            }
+        }
+    }
+    out.push_str(rest);
+    out
+}
+
+/// The language the locale environment asks for, LC_ALL over LC_MESSAGES over LANG as
+/// setlocale() has it: "de_DE.UTF-8" is "de_DE".  None for C, POSIX and English.
+pub fn detect() -> Option<String> {
+    let value = ["LC_ALL", "LC_MESSAGES", "LANG"].iter().find_map(|v| env::var(v).ok().filter(|v| !v.is_empty()))?;
+    normalize(&value)
+}
+
+fn normalize(locale: &str) -> Option<String> {
+    let lang = locale.split(['.', '@']).next().unwrap_or("").replace('-', "_");
+    match lang.as_str() {
+        "" | "C" | "POSIX" => None,
+        l if l == "en" || l.starts_with("en_") => None,
+        _ => Some(lang),
+    }
+}
+
+/// Translates the console output into `lang`, the locale's language when None.  A language
+/// asked for with --lang that has no translations is an error, the locale's is English then.
+pub fn set_lang(lang: Option<&str>) -> io::Result<()> {
+    let (lang, asked) = match lang {
+        Some(lang) => (normalize(lang), true),
+        None => (detect(), false),
+    };
+    let catalog = match &lang {
+        Some(lang) => match Catalog::load(LOCALE_DIRS, lang)? {
+            None if asked => return Err(io::Error::other(format!(
+                "No translations for '{}', add {}/{}.toml.", lang, LOCALE_DIRS[0], lang))),
+            catalog => catalog,
+        },
+        None => None,
+    };
+    *CATALOG.write().unwrap_or_else(|e| e.into_inner()) = catalog;
+    Ok(())
+}
+
+/// `message` in the language set, as it is in English or without a translation for it.
+pub fn tr(message: &str) -> String {
+    match CATALOG.read().unwrap_or_else(|e| e.into_inner()).as_ref() {
+        Some(catalog) => catalog.translate(message),
+        None => String::from(message),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DE: &str = r#"
+"Please answer yes or no." = "Bitte mit ja oder nein antworten."
+"Installing the bitflux kernel" = "Bitflux-Kernel wird installiert"
+"Step {}/{}: {}" = "Schritt {}/{}: {}"
+"{}, done in {}" = "{1}, fertig nach {2}"
+"{} ({})" = "{2}: {1}"
+"#;
+
+    #[test]
+    fn messages_translated() {
+        let de = Catalog::parse(DE).unwrap();
+        assert_eq!(de.translate("Please answer yes or no."), "Bitte mit ja oder nein antworten.");
+        assert_eq!(de.translate("Step 3/6: Installing the bitflux kernel, done in 42s"), "Schritt 3/6: Bitflux-Kernel wird installiert, fertig nach 42s");
+        assert_eq!(de.translate("apt-get (exit 100)"), "exit 100: apt-get");
+        assert_eq!(de.translate("Steps:\n  Installing the bitflux kernel\n  Other"), "Steps:\n  Bitflux-Kernel wird installiert\n  Other");
+        assert_eq!(de.translate("No translation."), "No translation.");
+        assert_eq!(fill("{a} {} {3}", &[String::from("x")]), "{a} x {3}");
+        assert!(Catalog::parse("key = 1").is_err());
+    }
+
+    #[test]
+    fn locales() {
+        assert_eq!(normalize("de_DE.UTF-8").as_deref(), Some("de_DE"));
+        assert_eq!(normalize("pt-BR").as_deref(), Some("pt_BR"));
+        assert_eq!(normalize("sr_RS@latin").as_deref(), Some("sr_RS"));
+        assert_eq!(normalize("C.UTF-8"), None);
+        assert_eq!(normalize("en_US.UTF-8"), None);
+    }
+
+}
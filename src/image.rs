@@ -0,0 +1,237 @@
+use std::fs;
+use std::io;
+use std::os::unix::fs::{chown, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use crate::account::{self, AGENT_USER, HOME, STATE_DIRS};
+use crate::agentconf::{self, AGENT_CONFIG};
+use crate::bundle::Bundle;
+use crate::exitcode::Kind;
+use crate::install::Options;
+use crate::license::LICENSE_PATH;
+use crate::log::{self, Level};
+use crate::perms::{group_id, FileKind, AGENT_GROUP};
+use crate::pkg::{PackageManager, AGENT_PACKAGE};
+use crate::platform::{Family, OsRelease, OS_RELEASE_PATH};
+use crate::privsep;
+use crate::repo;
+use crate::runcmd::{self, RunCmd};
+use crate::unit::{self, DROPIN_DIR, HARDENING_DROPIN};
+
+/// Where the image's systemd finds the preset that enables the agent on its first boot.
+pub const PRESET_PATH: &str = "/usr/lib/systemd/system-preset/80-bitflux.preset";
+/// Where bundled packages are put in the image for apt, which only sees the image's files.
+const STAGING_DIR: &str = "/var/tmp/bitflux-image";
+
+/// `path`, absolute on the installed system, in the image at `root`.
+pub fn rooted(root: &Path, path: &str) -> PathBuf {
+    root.join(path.trim_start_matches('/'))
+}
+
+/// The command line of `pm` doing `action` with `args` in the image at `root`, of release
+/// `os`.  apt runs in a chroot, only there do the packages' maintainer scripts see the image.
+pub fn package_argv(pm: &PackageManager, root: &Path, os: &OsRelease, action: &str, args: &[&str]) -> Vec<String> {
+    let root = root.to_string_lossy();
+    let prefix: Vec<&str> = match pm {
+        PackageManager::Apt => vec!["chroot", &root, "apt-get", action, "-y"],
+        PackageManager::Dnf | PackageManager::Yum => {
+            let tool = if *pm == PackageManager::Dnf { "dnf" } else { "yum" };
+            vec![tool, "--installroot", &root, "--releasever", &os.version_id, "-y", action]
+        }
+        PackageManager::Zypper => vec!["zypper", "--root", &root, "--non-interactive", action],
+    };
+    [prefix.as_slice(), args].concat().into_iter().map(String::from).collect()
+}
+
+/// The systemd preset enabling the agent, systemd applies presets on an image's first boot.
+pub fn preset() -> String {
+    format!("# Written by the bitflux installer.\nenable {}.service\n", AGENT_PACKAGE)
+}
+
+fn run(argv: &[String]) -> io::Result<()> {
+    let args: Vec<&str> = argv[1..].iter().map(String::as_str).collect();
+    let mut cmd = RunCmd::args(&argv[0], &args);
+    if argv.iter().any(|a| a == "apt-get") {
+        cmd.env("DEBIAN_FRONTEND", "noninteractive");
+    }
+    cmd.try_execute().map(|_| ()).map_err(io::Error::from)
+}
+
+/// Writes `data` to `path` in the image at `root` with the owner and mode of `kind`, the
+/// group as the image's /etc/group has it.
+fn write(root: &Path, path: &str, data: &[u8], kind: FileKind) -> io::Result<()> {
+    let target = rooted(root, path);
+    if runcmd::dry_run(&format!("write {}", target.display())) {
+        return Ok(());
+    }
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    fs::write(&target, data)?;
+    let gid = group_id(&fs::read_to_string(rooted(root, "/etc/group")).unwrap_or_default(), kind.group()).unwrap_or(0);
+    chown(&target, Some(0), Some(gid))?;
+    fs::set_permissions(&target, fs::Permissions::from_mode(kind.mode()))
+}
+
+/// The image's release, refusing a root that isn't one the installer supports.
+fn release(root: &Path) -> io::Result<OsRelease> {
+    if root == Path::new("/") {
+        return Err(io::Error::other("--root / is this host, leave it out to install here."));
+    }
+    let data = fs::read_to_string(rooted(root, OS_RELEASE_PATH))
+        .map_err(|e| io::Error::new(e.kind(), format!("{} isn't a root filesystem, it has no {}: {}", root.display(), OS_RELEASE_PATH, e)))?;
+    let os = OsRelease::parse(&data);
+    os.check_supported()?;
+    if !["usr/lib/systemd/systemd", "lib/systemd/systemd"].iter().any(|p| root.join(p).exists()) {
+        return Err(Kind::Unsupported.error(format!("{} has no systemd, the agent is enabled through a systemd preset.", root.display())));
+    }
+    Ok(os)
+}
+
+/// The bitflux repository in the image, its key imported and its metadata fetched.
+fn repository(pm: &PackageManager, root: &Path, os: &OsRelease) -> io::Result<()> {
+    let arch = crate::arch::Arch::current().ok_or_else(|| Kind::Unsupported.error("No bitflux packages for this architecture."))?;
+    match os.family() {
+        Some(Family::Debian) => {
+            repo::download_key(repo::APT_KEYRING_URL, &rooted(root, repo::APT_KEYRING_PATH).to_string_lossy(), false)?;
+            let deb822 = os.uses_deb822();
+            let path = if deb822 { repo::APT_SOURCES_PATH } else { repo::APT_LIST_PATH };
+            write(root, path, repo::apt_source(os, arch, deb822).as_bytes(), FileKind::Unit)?;
+            run(&package_argv(pm, root, os, "update", &[]))
+        }
+        family => {
+            let tree = os.rpm_tree().ok_or_else(|| Kind::Unsupported.error(format!("No bitflux package repository for {}.", os.pretty_name)))?;
+            let key = rooted(root, repo::RPM_GPG_KEY_PATH);
+            repo::download_key(repo::RPM_GPG_KEY_URL, &key.to_string_lossy(), true)?;
+            run(&["rpm", "--root", &root.to_string_lossy(), "--import", &key.to_string_lossy()].map(String::from))?;
+            let path = if family == Some(Family::Suse) { repo::ZYPP_REPO_PATH } else { repo::RPM_REPO_PATH };
+            write(root, path, repo::rpm_repo(&tree, arch).as_bytes(), FileKind::Unit)
+        }
+    }
+}
+
+/// The packages of `bundle` for the image, and its license activation.
+fn bundled(pm: &PackageManager, root: &Path, os: &OsRelease, bundle: &Bundle, kernel: bool) -> io::Result<()> {
+    let packages = bundle.packages(pm, kernel);
+    if !packages.iter().any(|(p, _)| p.name == AGENT_PACKAGE) {
+        return Err(io::Error::other(format!("Bundle {} has no {} package for {}.", bundle.index.version, AGENT_PACKAGE, os.pretty_name)));
+    }
+    let mut files = Vec::new();
+    for (_, path) in &packages {
+        match pm {
+            // apt in the chroot reads them from the image.
+            PackageManager::Apt => {
+                let name = path.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+                let staged = format!("{}/{}", STAGING_DIR, name);
+                if !runcmd::dry_run(&format!("copy {} to {}", path.display(), rooted(root, &staged).display())) {
+                    fs::create_dir_all(rooted(root, STAGING_DIR))?;
+                    fs::copy(path, rooted(root, &staged))?;
+                }
+                files.push(staged);
+            }
+            _ => files.push(path.to_string_lossy().into_owned()),
+        }
+    }
+    let files: Vec<&str> = files.iter().map(String::as_str).collect();
+    let installed = run(&package_argv(pm, root, os, "install", &files));
+    if *pm == PackageManager::Apt && !runcmd::dry_run(&format!("remove {}", rooted(root, STAGING_DIR).display())) {
+        let _ = fs::remove_dir_all(rooted(root, STAGING_DIR));
+    }
+    installed?;
+    if let Some(file) = &bundle.index.activation {
+        write(root, LICENSE_PATH, &fs::read(bundle.dir.join(file))?, FileKind::Secret)?;
+    }
+    Ok(())
+}
+
+/// The agent's account in the image, in its groups and owning the STATE_DIRS.
+fn provision(root: &Path) -> io::Result<()> {
+    let rootdir = root.to_string_lossy();
+    let groups = fs::read_to_string(rooted(root, "/etc/group")).unwrap_or_default();
+    if group_id(&groups, AGENT_GROUP).is_none() {
+        run(&["groupadd", "--root", &rootdir, "--system", AGENT_GROUP].map(String::from))?;
+    }
+    let passwd = || fs::read_to_string(rooted(root, "/etc/passwd")).unwrap_or_default();
+    if privsep::lookup(&passwd(), AGENT_USER).is_none() {
+        run(&["useradd", "--root", &rootdir, "--system", "--gid", AGENT_GROUP, "--home-dir", HOME, "--no-create-home", "--shell", "/usr/sbin/nologin", "--comment", "bitflux agent", AGENT_USER].map(String::from))?;
+    }
+    for group in account::missing_groups(&groups) {
+        run(&["usermod", "--root", &rootdir, "--append", "--groups", group, AGENT_USER].map(String::from))?;
+    }
+    if runcmd::dry_run(&format!("chown {} in {} to {}", STATE_DIRS.join(", "), root.display(), AGENT_USER)) {
+        return Ok(());
+    }
+    let ids = privsep::lookup(&passwd(), AGENT_USER).ok_or_else(|| io::Error::other(format!("useradd didn't add {} to {}.", AGENT_USER, root.display())))?;
+    for dir in STATE_DIRS {
+        let dir = rooted(root, dir);
+        fs::create_dir_all(&dir)?;
+        fs::set_permissions(&dir, fs::Permissions::from_mode(0o750))?;
+        chown(&dir, Some(ids.uid), Some(ids.gid))?;
+    }
+    Ok(())
+}
+
+/// `install --root`: installs bitflux into the root filesystem of an image at `root`, for image
+/// builders.  Packages go in through the image's package manager, configs are written below
+/// `root` and the agent is enabled by a systemd preset on the image's first boot, nothing is
+/// started here.
+pub fn install(opts: &Options, root: &Path) -> io::Result<()> {
+    if opts.dkms {
+        return Err(io::Error::other("--dkms builds for the running kernel, not the image's; install the bitflux kernel into an image."));
+    }
+    if opts.version.is_some() {
+        return Err(io::Error::other("--version isn't supported with --root, the image gets the latest release or the bundle's."));
+    }
+    let os = release(root)?;
+    let pm = PackageManager::for_release(&os).ok_or_else(|| Kind::Unsupported.error(format!("No supported package manager for {}.", os.pretty_name)))?;
+    let profile = opts.install_profile();
+    log::log(Level::Info, &format!("Installing bitflux {} into {} ({}).", profile.name(), root.display(), os.pretty_name));
+
+    match &opts.bundle {
+        Some(path) => bundled(&pm, root, &os, &Bundle::open(path, !opts.skip_verify)?, profile.kernel())?,
+        None => {
+            repository(&pm, root, &os)?;
+            let mut packages = vec![AGENT_PACKAGE];
+            if profile.kernel() {
+                packages.insert(0, pm.kernel_package());
+            }
+            run(&package_argv(&pm, root, &os, "install", &packages))?;
+        }
+    }
+    provision(root)?;
+
+    let config = rooted(root, AGENT_CONFIG);
+    let settings = opts.agent_settings();
+    if !settings.is_empty() && !runcmd::dry_run(&format!("set the agent settings in {}", config.display())) {
+        let data = agentconf::update(&fs::read_to_string(&config).unwrap_or_default(), &settings);
+        write(root, AGENT_CONFIG, data.as_bytes(), FileKind::Config)?;
+    }
+    let dropin = format!("{}/{}", DROPIN_DIR, HARDENING_DROPIN);
+    write(root, &dropin, unit::render(profile).as_bytes(), FileKind::Unit)?;
+    write(root, PRESET_PATH, preset().as_bytes(), FileKind::Unit)?;
+
+    if opts.device_id.is_none() {
+        log::log(Level::Info, "Every host booted from the image needs its own device id, `installer configure --device-id` sets it.");
+    }
+    log::log(Level::Info, &format!("bitflux {} installed into {}, the agent starts on the image's first boot.", profile.name(), root.display()));
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn commands_in_the_image() {
+        let root = Path::new("/mnt/image");
+        assert_eq!(rooted(root, AGENT_CONFIG), Path::new("/mnt/image/opt/bitflux/config/bitflux/bitfluxcollector.conf"));
+        let os = OsRelease::parse("ID=rocky\nVERSION_ID=\"9.4\"\n");
+        assert_eq!(package_argv(&PackageManager::Dnf, root, &os, "install", &[AGENT_PACKAGE]).join(" "), "dnf --installroot /mnt/image --releasever 9.4 -y install bitfluxcollector");
+        assert_eq!(package_argv(&PackageManager::Apt, root, &os, "install", &[AGENT_PACKAGE]).join(" "), "chroot /mnt/image apt-get install -y bitfluxcollector");
+        assert_eq!(package_argv(&PackageManager::Zypper, root, &os, "install", &[AGENT_PACKAGE]).join(" "), "zypper --root /mnt/image --non-interactive install bitfluxcollector");
+        assert_eq!(preset().lines().last(), Some("enable bitfluxcollector.service"));
+        assert!(release(Path::new("/")).is_err());
+    }
+
+}
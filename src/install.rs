@@ -0,0 +1,1286 @@
+use std::collections::BTreeMap;
+use std::env;
+use std::fmt;
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::account;
+use crate::agentconf::{self, AGENT_CONFIG};
+use crate::batch;
+use crate::bundle::Bundle;
+use crate::clock;
+use crate::cmdline::{self, RECLAIM_PARAMS};
+use crate::config::{Config, INSTALLER_CONFIG};
+use crate::device::{self, DEVICE_ID_KEY};
+use crate::diskspace;
+use crate::engine::{self, Report, Step};
+use crate::executor::{self, CommandExecutor};
+use crate::firewall::{self, Firewall};
+use crate::health::Health;
+use crate::hooks::{self, HOOKS_DIR};
+use crate::journal::{Journal, JOURNAL_DIR};
+use crate::kernel;
+use crate::kernelmatrix;
+use crate::kmod;
+use crate::license::{self, ACTIVATION_URL, LICENSE_PATH};
+use crate::log::{self, Level};
+use crate::mac;
+use crate::notify::Webhook;
+use crate::offline;
+use crate::output;
+use crate::perms::{self, FileKind, PermissionRecord};
+use crate::pkg::{PackageManager, AGENT_PACKAGE};
+use crate::plan::{self, LiveState, MachinePlan, Plan};
+use crate::platform::{Init, Platform};
+use crate::preflight;
+use crate::profiling;
+use crate::profile::Profile;
+use crate::reboot;
+use crate::prompt::Prompt;
+use crate::receipt::{Receipt, ServiceRecord, RECEIPT_DIR};
+use crate::release;
+use crate::repo;
+use crate::resume::{self, PROGRESS_PATH};
+use crate::runcmd;
+use crate::sbc::{self, SYSCTL_PATH};
+use crate::script::{self, Script};
+use crate::service::Service;
+use crate::spinner::Outcome;
+use crate::unit;
+use crate::workspace::Workspace;
+
+/// Service setup tasks run at the same time.
+const SERVICE_TASKS: usize = 3;
+
+/// Answers file used when `install` gets neither --config nor any install options.
+pub const ANSWERS_PATH: &str = "/etc/bitflux/install.toml";
+
+/// A value of the answers file's `[agent]` table.  The agent config only has strings, the
+/// others are written the way TOML spells them.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Setting {
+    Bool(bool),
+    Integer(i64),
+    Text(String),
+}
+
+impl fmt::Display for Setting {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Setting::Bool(b) => write!(f, "{}", b),
+            Setting::Integer(i) => write!(f, "{}", i),
+            Setting::Text(s) => write!(f, "{}", s),
+        }
+    }
+}
+
+/// What to install, from the command line or an answers file.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Options {
+    pub profile: Profile,
+    /// Whether to install the bitflux kernel, overriding what the profile says.
+    pub kernel: Option<bool>,
+    /// Build swaphints for the running kernel with DKMS instead of installing the bitflux kernel.
+    pub dkms: bool,
+    /// Never touch the network, everything comes from `bundle`.
+    pub offline: bool,
+    pub bundle: Option<PathBuf>,
+    /// Written to the agent config before the agent is started.
+    pub license_key: Option<String>,
+    pub device_id: Option<String>,
+    /// More agent config settings, the feature toggles, written along with the license key.
+    pub agent: BTreeMap<String, Setting>,
+    /// POST the outcome to this webhook when the install finishes, see notify.
+    pub notify_url: Option<String>,
+    pub notify_secret_file: Option<PathBuf>,
+    /// Redo every step, also the ones the host already has.
+    pub force: bool,
+    /// Install from `bundle` without checking its signatures and checksums.
+    pub skip_verify: bool,
+    /// Leave SELinux and AppArmor as they are, for hosts where the bitflux policy can't be loaded.
+    pub skip_mac_policy: bool,
+    /// Ports, "9100" or "514/udp", to let in through the active firewall for the agent.
+    pub open_ports: Vec<String>,
+    /// Reboot at the end when the install needs it, for automated runs.
+    pub reboot: bool,
+    /// Set the clock with NTP first when preflight finds it off.
+    pub fix_clock: bool,
+    /// Install on a kernel series that isn't in the compatibility matrix, see kernelmatrix.
+    pub allow_unsupported_kernel: bool,
+    /// The bitflux release to install, X.Y.Z, instead of the latest.
+    pub version: Option<String>,
+    /// Install `version`, or the bundle's, even when a newer one is installed.
+    pub allow_downgrade: bool,
+}
+
+impl Options {
+
+    /// Reads an answers file, TOML with the fields of Options.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Options> {
+        let path = path.as_ref();
+        let data = fs::read_to_string(path)
+            .map_err(|e| io::Error::new(e.kind(), format!("Can't read the answers file {}: {}", path.display(), e)))?;
+        toml::from_str(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))
+    }
+
+    /// Asks for the install options, for an install started on a terminal without any.
+    pub fn ask<R: BufRead, W: Write>(prompt: &mut Prompt<R, W>) -> io::Result<Options> {
+        let kernel = prompt.confirm("Install the bitflux swaphints kernel along with the agent?", true)?;
+        let license_key = prompt.sensitive().input_valid("License key:", None, license::validate)?;
+        // Skipping it keeps the id the host has, or gets one made from its hostname and machine id.
+        let default_id = device::current().unwrap_or_else(device::generated);
+        let device_id = prompt.input_valid("Device id, the name this host shows up as:", Some(&default_id), device::validate)?;
+        Ok(Options { kernel: Some(kernel), license_key: Some(license_key), device_id: Some(device_id), ..Default::default() })
+    }
+
+    /// The profile to install, with the kernel choice applied.
+    pub fn install_profile(&self) -> Profile {
+        match (self.kernel, self.profile) {
+            (Some(false), Profile::AgentKernel | Profile::Debug) => Profile::Agent,
+            (Some(true), Profile::Agent) => Profile::AgentKernel,
+            (_, profile) => profile,
+        }
+    }
+
+    /// Fails on options that contradict each other or don't parse, before the host is looked at.
+    pub fn check(&self) -> io::Result<()> {
+        if self.offline && self.bundle.is_none() {
+            return Err(io::Error::other("--offline installs everything from a bundle, pass --bundle <PATH>."));
+        }
+        if let Some(key) = &self.license_key {
+            license::validate(key).map_err(io::Error::other)?;
+        }
+        if let Some(id) = &self.device_id {
+            device::validate(id).map_err(io::Error::other)?;
+        }
+        self.ports()?;
+        if let Some(version) = &self.version {
+            release::validate(version).map_err(io::Error::other)?;
+        }
+        if self.dkms && self.bundle.is_some() {
+            return Err(io::Error::other("Bundles carry the bitflux kernel, --dkms needs the bitflux repository."));
+        }
+        if self.fix_clock && self.offline {
+            return Err(io::Error::other("--fix-clock sets the clock from the network, leave it out with --offline."));
+        }
+        Ok(())
+    }
+
+    /// These options with the license key shown as runcmd::sensitive::MASK, for what others get to read.
+    pub fn redacted(&self) -> Options {
+        Options { license_key: self.license_key.as_ref().map(|_| String::from(runcmd::sensitive::MASK)), ..self.clone() }
+    }
+
+    /// The ports to open, each as "<number>/<protocol>".
+    pub fn ports(&self) -> io::Result<Vec<String>> {
+        self.open_ports.iter().map(|p| firewall::validate_port(p).map_err(io::Error::other)).collect()
+    }
+
+    /// The agent config keys the options set.
+    pub fn agent_settings(&self) -> Vec<(String, String)> {
+        let mut settings: Vec<(String, String)> = self.agent.iter().map(|(k, v)| (k.clone(), v.to_string())).collect();
+        if let Some(key) = &self.license_key {
+            settings.push((String::from("licensekey"), key.clone()));
+        }
+        if let Some(id) = &self.device_id {
+            settings.push((String::from(DEVICE_ID_KEY), id.clone()));
+        }
+        settings
+    }
+
+    /// The server the clock has to agree with: the license server for an activation, the
+    /// repository otherwise.
+    pub fn time_server(&self) -> &'static str {
+        match self.license_key.is_some() && self.bundle.is_none() {
+            true => ACTIVATION_URL,
+            false => repo::REPO_URL,
+        }
+    }
+
+    pub fn webhook(&self) -> Option<Webhook> {
+        self.notify_url.as_ref().map(|url| Webhook { url: url.clone(), secret_file: self.notify_secret_file.clone() })
+    }
+
+}
+
+/// What the install steps share.
+struct Run<'a> {
+    opts: &'a Options,
+    platform: &'a Platform,
+    profile: Profile,
+    journal: &'a mut Journal,
+    /// Only checking, for --check, a check() mustn't fix anything on the way.
+    checking: bool,
+    /// The packages installed and the permissions set, for the receipt.
+    names: Vec<String>,
+    permissions: Vec<PermissionRecord>,
+    /// Which of the journal's changes each step applied made, for its rollback().
+    applied: Vec<(&'static str, Range<usize>)>,
+}
+
+impl Run<'_> {
+
+    /// Applies the step `name` with `apply`, noting the changes it journals for undo().
+    fn journaled(&mut self, name: &'static str, apply: impl FnOnce(&mut Self) -> io::Result<()>) -> io::Result<()> {
+        let from = self.journal.changes.len();
+        let result = apply(self);
+        self.applied.push((name, from..self.journal.changes.len()));
+        result
+    }
+
+    /// Rolls back the changes the step `name` journaled in this run, newest first, false when
+    /// it wasn't applied.  Ones that can't be undone stay for `installer rollback`.
+    fn undo(&mut self, name: &str) -> io::Result<bool> {
+        let Some((_, range)) = self.applied.iter().rev().find(|(n, _)| *n == name).cloned() else {
+            return Ok(false);
+        };
+        let changes = self.journal.changes.get(range).unwrap_or_default().to_vec();
+        let report = self.journal.rollback_matching(Some(&self.platform.pm), |c| changes.contains(c))?;
+        match report.failed.is_empty() {
+            true => Ok(true),
+            false => Err(io::Error::other(report.failed.join(", "))),
+        }
+    }
+
+}
+
+/// Installs the package step `name` with `run`, only once there's enough disk space for it and
+/// watching the space while it runs.
+fn package_step<T>(pm: &PackageManager, name: &str, run: impl FnOnce() -> io::Result<T>) -> io::Result<T> {
+    diskspace::checkpoint(pm, name)?;
+    let watch = diskspace::Watch::start();
+    let result = run();
+    for low in watch.stop() {
+        log::log(Level::Warn, &format!("Warning: {} got down to {} MiB free during the {} step.", low.path, low.free_mib, name));
+    }
+    result
+}
+
+/// The packages of the profile from the bundle, kernel and agent, and its license activation.
+struct BundleStep(Bundle);
+
+impl Step<Run<'_>> for BundleStep {
+
+    fn name(&self) -> &'static str {
+        "packages"
+    }
+
+    fn title(&self) -> &'static str {
+        "Installing the bundled packages"
+    }
+
+    fn check(&self, run: &mut Run) -> io::Result<bool> {
+        let pm = &run.platform.pm;
+        let packages = self.0.packages(pm, run.profile.kernel());
+        let done = !packages.is_empty()
+            && packages.iter().all(|(p, _)| pm.installed_version(&p.name).is_some())
+            && (self.0.index.activation.is_none() || Path::new(LICENSE_PATH).exists());
+        if done {
+            run.names.extend(packages.iter().map(|(p, _)| p.name.clone()));
+        }
+        Ok(done)
+    }
+
+    fn apply(&self, run: &mut Run) -> io::Result<()> {
+        let bundle = &self.0;
+        let pm = &run.platform.pm;
+        let packages = bundle.packages(pm, run.profile.kernel());
+        if !packages.iter().any(|(p, _)| p.name == AGENT_PACKAGE) {
+            return Err(io::Error::other(format!("Bundle {} has no {} package for this distro.", bundle.index.version, AGENT_PACKAGE)));
+        }
+        for (package, _) in &packages {
+            run.journal.package(pm, &package.name)?;
+        }
+        let files = |kernel: bool| -> Vec<String> {
+            packages.iter().filter(|(p, _)| p.kernel == kernel).map(|(_, f)| f.to_string_lossy().into_owned()).collect()
+        };
+
+        let kernels = files(true);
+        if !kernels.is_empty() {
+            let kernels: Vec<&str> = kernels.iter().map(String::as_str).collect();
+            package_step(pm, "kernel", || kernel::install_with_fallback(&mut pm.install_files_cmd_as(&kernels, run.platform.transactional), run.platform))?;
+        }
+        let agent = files(false);
+        let agent: Vec<&str> = agent.iter().map(String::as_str).collect();
+        package_step(pm, "agent", || match pm.install_files_cmd_as(&agent, run.platform.transactional).execute_output() {
+            out if out.exitcode != 0 => Err(io::Error::other(format!("Failed to install the bundled packages: {}", out.stderr.trim()))),
+            _ => Ok(()),
+        })?;
+
+        if bundle.index.activation.is_some() {
+            run.journal.file(LICENSE_PATH)?;
+        }
+        if bundle.install_activation()? {
+            log::log(Level::Info, "Installed the offline license activation.");
+        }
+        run.names.extend(packages.iter().map(|(p, _)| p.name.clone()));
+        Ok(())
+    }
+
+}
+
+/// The clock within preflight::MAX_SKEW_SECS of the servers', for --fix-clock.
+struct ClockStep;
+
+impl Step<Run<'_>> for ClockStep {
+
+    fn name(&self) -> &'static str {
+        "clock"
+    }
+
+    fn title(&self) -> &'static str {
+        "Setting the clock"
+    }
+
+    fn check(&self, run: &mut Run) -> io::Result<bool> {
+        Ok(preflight::skew(run.opts.time_server()).is_some_and(|skew| skew <= preflight::MAX_SKEW_SECS))
+    }
+
+    fn apply(&self, run: &mut Run) -> io::Result<()> {
+        clock::fix(run.opts.time_server())
+    }
+
+}
+
+/// The bitflux package repository, set up and with fresh metadata.
+struct RepositoryStep;
+
+impl Step<Run<'_>> for RepositoryStep {
+
+    fn name(&self) -> &'static str {
+        "repository"
+    }
+
+    fn title(&self) -> &'static str {
+        "Setting up the bitflux repository"
+    }
+
+    fn check(&self, run: &mut Run) -> io::Result<bool> {
+        Ok(repo::is_set_up(&run.platform.os, run.platform.arch))
+    }
+
+    fn apply(&self, run: &mut Run) -> io::Result<()> {
+        run.journaled(self.name(), |run| {
+            for path in repo::paths(&run.platform.os) {
+                run.journal.file(path)?;
+            }
+            repo::setup(&run.platform.os, &run.platform.pm, run.platform.arch)
+        })
+    }
+
+    fn rollback(&self, run: &mut Run) -> io::Result<bool> {
+        run.undo(self.name())
+    }
+
+}
+
+/// The bitflux kernel, or with --dkms the swaphints module built for the running one.
+struct KernelStep {
+    dkms: bool,
+}
+
+impl Step<Run<'_>> for KernelStep {
+
+    fn name(&self) -> &'static str {
+        "kernel"
+    }
+
+    fn title(&self) -> &'static str {
+        match self.dkms {
+            true => "Building the swaphints module",
+            false => "Installing the bitflux kernel",
+        }
+    }
+
+    fn after(&self) -> Vec<&'static str> {
+        vec!["repository"]
+    }
+
+    fn check(&self, run: &mut Run) -> io::Result<bool> {
+        let Some(names) = self.installed(run.platform) else {
+            return Ok(false);
+        };
+        if reclaim(run.opts) && run.platform.bootloader.is_none_or(|b| cmdline::pending(b, RECLAIM_PARAMS, &[]).unwrap_or(true)) {
+            return Ok(false);
+        }
+        run.names.extend(names);
+        Ok(true)
+    }
+
+    fn apply(&self, run: &mut Run) -> io::Result<()> {
+        run.journaled(self.name(), |run| {
+            let pm = &run.platform.pm;
+            match self.installed(run.platform) {
+                Some(names) => run.names.extend(names),
+                None if self.dkms => {
+                    let journal = &mut *run.journal;
+                    let names = package_step(pm, "kernel", || kmod::install(pm, journal))?;
+                    run.names.extend(names);
+                }
+                None => {
+                    let package = run.platform.kernel_package();
+                    kernelmatrix::check_candidate(pm, package, run.opts.allow_unsupported_kernel)?;
+                    run.journal.package(pm, package)?;
+                    package_step(pm, "kernel", || kernel::install_with_fallback(&mut pm.install_cmd_as(&[package], run.platform.transactional), run.platform))?;
+                    run.names.push(String::from(package));
+                }
+            }
+            if reclaim(run.opts) {
+                cmdline::set(RECLAIM_PARAMS, run.journal)?;
+            }
+            Ok(())
+        })
+    }
+
+    fn rollback(&self, run: &mut Run) -> io::Result<bool> {
+        run.undo(self.name())
+    }
+
+}
+
+impl KernelStep {
+
+    /// The packages of the kernel or module when installed.
+    fn installed(&self, platform: &Platform) -> Option<Vec<String>> {
+        let package = platform.kernel_package();
+        match self.dkms {
+            true => kmod::installed(&platform.pm),
+            false => platform.pm.installed_version(package).map(|_| vec![String::from(package)]),
+        }
+    }
+
+}
+
+/// True when the agent is set to reclaim, which needs RECLAIM_PARAMS on the kernel.
+fn reclaim(opts: &Options) -> bool {
+    ["reclaim", "reclaim.enabled"].iter().any(|k| matches!(opts.agent.get(*k), Some(Setting::Bool(true))))
+}
+
+/// The agent package from the bitflux repository.
+struct AgentStep;
+
+impl Step<Run<'_>> for AgentStep {
+
+    fn name(&self) -> &'static str {
+        "agent"
+    }
+
+    fn title(&self) -> &'static str {
+        "Installing the agent"
+    }
+
+    fn after(&self) -> Vec<&'static str> {
+        vec!["repository"]
+    }
+
+    /// Done when the agent is installed, in the pinned release if there's one.
+    fn check(&self, run: &mut Run) -> io::Result<bool> {
+        let installed = run.platform.pm.installed_version(AGENT_PACKAGE);
+        let done = match (&installed, &run.opts.version) {
+            (Some(installed), Some(pinned)) => release::of(installed) == pinned,
+            (installed, None) => installed.is_some(),
+            (None, Some(_)) => false,
+        };
+        if done {
+            run.names.push(String::from(AGENT_PACKAGE));
+        }
+        Ok(done)
+    }
+
+    fn apply(&self, run: &mut Run) -> io::Result<()> {
+        run.journaled(self.name(), |run| {
+            let pm = &run.platform.pm;
+            // The repository is set up by now, the pinned release is looked up in it.
+            let pinned = match &run.opts.version {
+                Some(pinned) => {
+                    let available = pm.available_versions(AGENT_PACKAGE);
+                    match release::pick(&available, pinned) {
+                        Some(version) => Some(String::from(version)),
+                        None => return Err(io::Error::other(format!("bitflux {} isn't in the repository, it has {}.", pinned, release::list(&available)))),
+                    }
+                }
+                None => None,
+            };
+            run.journal.package(pm, AGENT_PACKAGE)?;
+            let installed = match &pinned {
+                Some(version) => package_step(pm, "agent", || Ok(pm.install_version(AGENT_PACKAGE, version)))?,
+                None => package_step(pm, "agent", || Ok(pm.install_as(&[AGENT_PACKAGE], run.platform.transactional)))?,
+            };
+            if !installed {
+                let what = pinned.map_or(String::from(AGENT_PACKAGE), |version| format!("{} {}", AGENT_PACKAGE, version));
+                return Err(io::Error::other(format!("Failed to install {}.", what)));
+            }
+            run.names.push(String::from(AGENT_PACKAGE));
+            Ok(())
+        })
+    }
+
+    fn rollback(&self, run: &mut Run) -> io::Result<bool> {
+        run.undo(self.name())
+    }
+
+}
+
+/// The bitflux system account the agent runs as, owning its data, before the agent is started.
+struct AccountStep {
+    bundle: bool,
+}
+
+impl Step<Run<'_>> for AccountStep {
+
+    fn name(&self) -> &'static str {
+        "account"
+    }
+
+    fn title(&self) -> &'static str {
+        "Creating the bitflux user"
+    }
+
+    fn after(&self) -> Vec<&'static str> {
+        match self.bundle {
+            true => vec!["packages"],
+            false => vec!["agent"],
+        }
+    }
+
+    fn check(&self, _run: &mut Run) -> io::Result<bool> {
+        Ok(account::provisioned())
+    }
+
+    fn apply(&self, run: &mut Run) -> io::Result<()> {
+        run.journaled(self.name(), |run| {
+            run.journal.account()?;
+            account::provision()
+        })
+    }
+
+    fn rollback(&self, run: &mut Run) -> io::Result<bool> {
+        run.undo(self.name())
+    }
+
+}
+
+/// The SELinux policy module or AppArmor profile the agent and swaphints need, before the
+/// agent is started.
+struct MacStep {
+    bundle: bool,
+    skip: bool,
+}
+
+impl Step<Run<'_>> for MacStep {
+
+    fn name(&self) -> &'static str {
+        "mac"
+    }
+
+    fn title(&self) -> &'static str {
+        "Installing the SELinux or AppArmor policy"
+    }
+
+    fn after(&self) -> Vec<&'static str> {
+        match self.bundle {
+            true => vec!["packages"],
+            false => vec!["agent"],
+        }
+    }
+
+    fn check(&self, run: &mut Run) -> io::Result<bool> {
+        Ok(!self.skip && run.platform.mac.installed())
+    }
+
+    fn apply(&self, run: &mut Run) -> io::Result<()> {
+        run.journaled(self.name(), |run| {
+            if run.platform.virt.is_container() {
+                // The kernel enforcing it is the host's, nothing can be loaded from in here.
+                log::log(Level::Info, "In a container the host's SELinux or AppArmor policy applies, leaving it to the host.");
+                return Ok(());
+            }
+            let mac = run.platform.mac;
+            match self.skip {
+                true => mac::warn_skipped(mac),
+                false => mac.install(run.journal)?,
+            }
+            Ok(())
+        })
+    }
+
+    fn rollback(&self, run: &mut Run) -> io::Result<bool> {
+        run.undo(self.name())
+    }
+
+}
+
+/// The ports of Options::open_ports let in through firewalld, ufw or iptables, whichever filters.
+struct FirewallStep;
+
+impl Step<Run<'_>> for FirewallStep {
+
+    fn name(&self) -> &'static str {
+        "firewall"
+    }
+
+    fn title(&self) -> &'static str {
+        "Opening the agent's ports in the firewall"
+    }
+
+    fn check(&self, run: &mut Run) -> io::Result<bool> {
+        let ports = run.opts.ports()?;
+        Ok(match Firewall::detect() {
+            Some(firewall) => ports.iter().all(|p| firewall.is_open(p)),
+            None => true,
+        })
+    }
+
+    fn apply(&self, run: &mut Run) -> io::Result<()> {
+        run.journaled(self.name(), |run| {
+            let Some(firewall) = Firewall::detect() else {
+                return Ok(());
+            };
+            for port in run.opts.ports()? {
+                if !firewall.is_open(&port) {
+                    firewall.open(&port, run.journal)?;
+                }
+            }
+            Ok(())
+        })
+    }
+
+    fn rollback(&self, run: &mut Run) -> io::Result<bool> {
+        run.undo(self.name())
+    }
+
+}
+
+/// The memory defaults for boards swapping to zram or SD cards, see sbc::memory_defaults().
+struct MemoryStep;
+
+impl Step<Run<'_>> for MemoryStep {
+
+    fn name(&self) -> &'static str {
+        "memory"
+    }
+
+    fn title(&self) -> &'static str {
+        "Tuning memory for the board"
+    }
+
+    fn check(&self, run: &mut Run) -> io::Result<bool> {
+        let done = fs::read_to_string(SYSCTL_PATH).ok() == Some(sbc::memory_defaults(sbc::has_zram())?);
+        if done && !run.checking {
+            run.permissions.push(perms::apply(SYSCTL_PATH, FileKind::Unit)?);
+        }
+        Ok(done)
+    }
+
+    fn apply(&self, run: &mut Run) -> io::Result<()> {
+        run.journaled(self.name(), |run| {
+            let record = sbc::apply_memory_defaults(run.journal)?;
+            run.permissions.push(record);
+            Ok(())
+        })
+    }
+
+    fn rollback(&self, run: &mut Run) -> io::Result<bool> {
+        run.undo(self.name())
+    }
+
+}
+
+/// The agent's unit drop-in, config and license activation, and the agent enabled.
+struct ServiceStep;
+
+impl Step<Run<'_>> for ServiceStep {
+
+    fn name(&self) -> &'static str {
+        "service"
+    }
+
+    fn title(&self) -> &'static str {
+        "Configuring the agent service"
+    }
+
+    fn after(&self) -> Vec<&'static str> {
+        vec!["mac"]
+    }
+
+    fn check(&self, run: &mut Run) -> io::Result<bool> {
+        let opts = run.opts;
+        let (init, dropin) = (run.platform.init, unit::path(run.platform.init));
+        let config = agentconf::load(AGENT_CONFIG).unwrap_or_default();
+        let key = opts.license_key.is_some() && opts.bundle.is_none();
+        let done = fs::read_to_string(&dropin).is_ok_and(|unit| unit == unit::definition(init, run.profile))
+            && opts.agent_settings().iter().all(|(k, v)| config.get(k) == Some(v))
+            && config.get(DEVICE_ID_KEY).is_some_and(|id| !id.is_empty())
+            && (!key || Path::new(LICENSE_PATH).exists())
+            && Service::new(AGENT_PACKAGE).is_enabled();
+        if done && !run.checking {
+            // Nothing to write, but the receipt still records the permissions, put back if they changed.
+            let kind = if matches!(init, Some(Init::OpenRc | Init::SysV)) { FileKind::Binary } else { FileKind::Unit };
+            run.permissions.push(perms::apply(&dropin, kind)?);
+            run.permissions.push(perms::apply(AGENT_CONFIG, FileKind::Config)?);
+            if key {
+                run.permissions.push(perms::apply(LICENSE_PATH, FileKind::Secret)?);
+            }
+        }
+        Ok(done)
+    }
+
+    fn apply(&self, run: &mut Run) -> io::Result<()> {
+        run.journaled(self.name(), |run| {
+            let (opts, profile, init) = (run.opts, run.profile, run.platform.init);
+            run.journal.file(unit::path(init))?;
+            let mut settings = opts.agent_settings();
+            // Every host gets a device id, one it already has is kept.
+            let device_id = match opts.device_id.clone().or_else(device::current) {
+                Some(id) => id,
+                None => {
+                    let id = device::generated();
+                    settings.push((String::from(DEVICE_ID_KEY), id.clone()));
+                    id
+                }
+            };
+            if !settings.is_empty() {
+                run.journal.file(AGENT_CONFIG)?;
+            }
+            // Bundles bring the activation along, their hosts may not reach the activation endpoint.
+            let key = match (&opts.license_key, &opts.bundle) {
+                (Some(key), None) => {
+                    run.journal.file(LICENSE_PATH)?;
+                    Some(key)
+                }
+                _ => None,
+            };
+            // The activation only runs once the config is written, so a failed write doesn't
+            // use up the key; its round trip still overlaps the unit write.  A script has them
+            // one after the other.
+            let limit = if script::recording() { 1 } else { SERVICE_TASKS };
+            let permissions: Vec<Option<PermissionRecord>> = batch::run_tasks(vec![
+                batch::Task { name: "unit", after: vec![], job: Box::new(|| unit::install(init, profile).map(|(_, record)| Some(record))) },
+                batch::Task {
+                    name: "config",
+                    after: vec![],
+                    job: Box::new(|| match settings.is_empty() {
+                        true => Ok(None),
+                        false => agentconf::set(AGENT_CONFIG, &settings).map(Some),
+                    }),
+                },
+                batch::Task {
+                    name: "activation",
+                    after: vec!["config"],
+                    job: Box::new(|| key.map(|key| license::activate(key, Some(&device_id))).transpose()),
+                },
+            ], limit)?.into_iter().collect::<io::Result<_>>()?;
+            run.journal.service(AGENT_PACKAGE)?;
+            // A transactional install only exists in the next snapshot, starting it now can't work.
+            Service::under(AGENT_PACKAGE, init).enable(!run.platform.transactional)
+                .map_err(|e| io::Error::other(format!("Failed to enable {}: {}", AGENT_PACKAGE, e)))?;
+            run.permissions.extend(permissions.into_iter().flatten());
+            Ok(())
+        })
+    }
+
+    fn rollback(&self, run: &mut Run) -> io::Result<bool> {
+        run.undo(self.name())
+    }
+
+}
+
+/// The agent running and healthy, as installer.toml's [health] says, before the install counts.
+struct HealthStep(Health);
+
+impl Step<Run<'_>> for HealthStep {
+
+    fn name(&self) -> &'static str {
+        "health"
+    }
+
+    fn title(&self) -> &'static str {
+        "Waiting for the agent to become healthy"
+    }
+
+    fn after(&self) -> Vec<&'static str> {
+        vec!["service"]
+    }
+
+    fn check(&self, run: &mut Run) -> io::Result<bool> {
+        Ok(!self.needed(run) || self.0.probe(&Service::new(AGENT_PACKAGE)).is_ok())
+    }
+
+    fn apply(&self, run: &mut Run) -> io::Result<()> {
+        match self.needed(run) {
+            true => self.0.wait(&Service::new(AGENT_PACKAGE)),
+            false => Ok(()),
+        }
+    }
+
+}
+
+impl HealthStep {
+
+    /// Only an agent the install started can be waited for: not in a pending snapshot, nor
+    /// without a running init system, nor in a dry run.
+    fn needed(&self, run: &Run) -> bool {
+        !run.platform.transactional && run.platform.init.is_some() && !runcmd::Context::current().dry_run
+    }
+
+}
+
+/// The signed install receipt of everything the other steps did, for verify and repair.
+struct ReceiptStep;
+
+impl Step<Run<'_>> for ReceiptStep {
+
+    fn name(&self) -> &'static str {
+        "receipt"
+    }
+
+    fn title(&self) -> &'static str {
+        "Writing the install receipt"
+    }
+
+    fn after(&self) -> Vec<&'static str> {
+        vec!["health"]
+    }
+
+    /// Nothing to record when no step before changed anything and there's a receipt.
+    fn check(&self, _run: &mut Run) -> io::Result<bool> {
+        Ok(!engine::steps().iter().any(|s| s.changed) && Receipt::load_verified(RECEIPT_DIR).is_ok())
+    }
+
+    fn apply(&self, run: &mut Run) -> io::Result<()> {
+        // A dry run installed nothing to record.
+        if runcmd::dry_run(&format!("record the packages, service and permissions and sign the receipt in {}", RECEIPT_DIR)) {
+            return Ok(());
+        }
+        let pm = &run.platform.pm;
+        diskspace::checkpoint(pm, "receipt")?;
+        let mut receipt = Receipt::new();
+        // Packages installed into a pending snapshot aren't visible to rpm until the reboot.
+        if !run.platform.transactional {
+            for name in &run.names {
+                receipt.record_package(pm, name)?;
+            }
+        }
+        receipt.services.push(ServiceRecord { name: String::from(AGENT_PACKAGE), enabled: true });
+        receipt.pinned = run.opts.version.clone();
+        receipt.permissions.extend(run.permissions.iter().cloned());
+        receipt.save_signed(RECEIPT_DIR)?;
+        receipt.stash_files(RECEIPT_DIR)
+    }
+
+}
+
+/// The names of the steps an install can have, for the hooks in installer.toml.
+pub const STEPS: &[&str] = &["clock", "packages", "repository", "kernel", "agent", "memory", "account", "mac", "firewall", "service", "health", "receipt"];
+
+/// The answers file in the default place, if there is one.
+pub fn default_answers() -> Option<PathBuf> {
+    Path::new(ANSWERS_PATH).exists().then(|| PathBuf::from(ANSWERS_PATH))
+}
+
+/// Installs bitflux and writes the signed install receipt.  Every change is journaled before
+/// it's made, so `rollback` can undo an install that failed half way.
+pub fn run(opts: &Options) -> io::Result<()> {
+    let (platform, profile, bundle) = prepare(opts)?;
+    let mut journal = Journal::open(JOURNAL_DIR)?;
+    let result = install(opts, &platform, profile, bundle, &mut journal);
+    if result.is_err() && !journal.changes.is_empty() {
+        log::log(Level::Warn, "The install failed, `installer rollback` undoes the changes it made.");
+    }
+    result
+}
+
+/// `install --check`: which steps an install with `opts` would change, applying none.  Every
+/// one is in engine::steps() after, changed when it would.
+pub fn check(opts: &Options) -> io::Result<Report> {
+    let (platform, profile, bundle) = prepare(opts)?;
+    let mut journal = Journal::open(JOURNAL_DIR)?;
+    let config = Config::load_or_default(INSTALLER_CONFIG);
+    let steps = steps(opts, &platform, profile, bundle, &config);
+    let mut run = Run { opts, platform: &platform, profile, journal: &mut journal, checking: true, names: Vec::new(), permissions: Vec::new(), applied: Vec::new() };
+    engine::check(&steps, &mut run)
+}
+
+/// `install --plan`: the engine's steps of an install with `opts` on this host in order, each
+/// with whether its check finds it done and what applying it runs, recorded from a dry run.
+pub fn plan(opts: &Options) -> io::Result<Plan> {
+    let (platform, profile, bundle) = prepare(opts)?;
+    let state = Workspace::create()?;
+    let mut journal = Journal::open(state.path())?;
+    let config = Config::load_or_default(INSTALLER_CONFIG);
+    let steps = steps(opts, &platform, profile, bundle, &config);
+    let mut run = Run { opts, platform: &platform, profile, journal: &mut journal, checking: true, names: Vec::new(), permissions: Vec::new(), applied: Vec::new() };
+    engine::check(&steps, &mut run)?;
+    let pending = engine::steps().into_iter().map(|step| (step.name, step.changed)).collect();
+    run.checking = false;
+    let script = record(&mut run, &steps, executor::current())?;
+    Ok(Plan::recorded(&script, Some(&pending)))
+}
+
+/// plan() for `opts` on `platform` with every step taken as not done, whatever runs going to
+/// `executor`.  For the golden tests, which have no distro at hand.
+pub fn plan_for(opts: &Options, platform: &Platform, executor: Arc<dyn CommandExecutor>) -> io::Result<Plan> {
+    Ok(Plan::recorded(&dry_run(opts, platform, executor)?, None))
+}
+
+/// What `install --emit-script` writes for `opts` on `platform`: every step applied as a dry
+/// run, with whatever runs going to `executor`, and nothing checked on the host first.  For
+/// the golden tests, which have no distro at hand.
+pub fn script_for(opts: &Options, platform: &Platform, executor: Arc<dyn CommandExecutor>) -> io::Result<String> {
+    Ok(dry_run(opts, platform, executor)?.render())
+}
+
+fn dry_run(opts: &Options, platform: &Platform, executor: Arc<dyn CommandExecutor>) -> io::Result<Script> {
+    platform.os.check_supported()?;
+    platform.check_init()?;
+    let bundle = opts.bundle.as_deref().map(|path| Bundle::open(path, !opts.skip_verify)).transpose()?;
+    let profile = platform.virt.effective_profile(opts.install_profile());
+    let state = Workspace::create()?;
+    let mut journal = Journal::open(state.path())?;
+    let steps = steps(opts, platform, profile, bundle, &Config::default());
+    let mut run = Run { opts, platform, profile, journal: &mut journal, checking: false, names: Vec::new(), permissions: Vec::new(), applied: Vec::new() };
+    record(&mut run, &steps, executor)
+}
+
+/// Applies every one of `steps` to `run` as a dry run and returns the script of it.
+fn record<'a>(run: &mut Run<'a>, steps: &[Box<dyn Step<Run<'a>> + 'a>], executor: Arc<dyn CommandExecutor>) -> io::Result<Script> {
+    let (opts, platform, profile) = (run.opts, run.platform, run.profile);
+    let context = runcmd::Context::current();
+    runcmd::Context { dry_run: true, ..context }.set();
+    script::start(opts.license_key.iter().map(|k| (k.clone(), String::from(script::LICENSE_KEY_VAR))).collect());
+    script::title(&format!("bitflux {} on {} ({})", profile.name(), platform.os.pretty_name, platform.arch.name()));
+    let result = engine::run_with(steps, run, true, executor);
+    let script = script::take();
+    context.set();
+    result?;
+    Ok(script.unwrap_or_default())
+}
+
+/// Shows what an install with `opts` would change on this host and asks whether to go ahead,
+/// for an install on a terminal.  Fails when the answer is no, asks nothing when the install
+/// changes nothing.
+pub fn confirm<R: BufRead, W: Write>(opts: &Options, prompt: &mut Prompt<R, W>) -> io::Result<()> {
+    check(opts)?;
+    let steps = engine::steps();
+    if !steps.iter().any(|s| s.changed) {
+        return Ok(());
+    }
+    output::print(&engine::changes(&steps))?;
+    match prompt.confirm("Go ahead with the install?", true)? {
+        true => Ok(()),
+        false => Err(io::Error::other("Install cancelled, nothing was changed.")),
+    }
+}
+
+/// Checks `opts` and the host and runs preflight, for an install or a check of one.
+fn prepare(opts: &Options) -> io::Result<(Platform, Profile, Option<Bundle>)> {
+    opts.check()?;
+    if let Some(key) = &opts.license_key {
+        runcmd::sensitive::add(key);
+    }
+    offline::set_offline(opts.offline);
+
+    let platform = Platform::detect()?;
+    platform.os.check_supported()?;
+    platform.check_init()?;
+    let bundle = opts.bundle.as_deref().map(|path| Bundle::open(path, !opts.skip_verify)).transpose()?;
+    let profile = platform.virt.effective_profile(opts.install_profile());
+    script::title(&format!("bitflux {} on {} ({})", profile.name(), platform.os.pretty_name, platform.arch.name()));
+    {
+        let _step = profiling::step("preflight");
+        let mut checks = preflight::run(opts.offline, profile);
+        // Whatever would need the network fails here, before anything was changed.
+        if let (true, Some(bundle)) = (opts.offline, &bundle) {
+            checks.push(preflight::offline(bundle, &platform.pm, profile.kernel(), opts.notify_url.is_some(), opts.license_key.is_some()));
+        }
+        if opts.time_server() == ACTIVATION_URL && !opts.offline {
+            checks.push(preflight::license_clock());
+        }
+        checks.extend(preflight::kernel_support(&platform.pm, profile.kernel(), opts.dkms, bundle.as_ref()));
+        checks.extend(preflight::release(&platform.pm, opts.version.as_deref(), bundle.as_ref(), opts.allow_downgrade));
+        if opts.fix_clock {
+            preflight::fixing_clock(&mut checks);
+        }
+        if opts.allow_unsupported_kernel {
+            preflight::allowing_unsupported_kernel(&mut checks);
+        }
+        preflight::gate(&checks)?;
+    }
+    Ok((platform, profile, bundle))
+}
+
+/// The steps of an install of `profile` with `opts`, before hooks and resuming.
+fn steps<'a>(opts: &Options, platform: &Platform, profile: Profile, bundle: Option<Bundle>, config: &Config) -> Vec<Box<dyn Step<Run<'a>>>> {
+    let mut steps: Vec<Box<dyn Step<Run>>> = Vec::new();
+    if opts.fix_clock {
+        steps.push(Box::new(ClockStep));
+    }
+    if let Some(bundle) = bundle {
+        steps.push(Box::new(BundleStep(bundle)));
+    } else {
+        steps.push(Box::new(RepositoryStep));
+        if profile.kernel() {
+            steps.push(Box::new(KernelStep { dkms: opts.dkms }));
+        }
+        steps.push(Box::new(AgentStep));
+    }
+    if platform.sbc {
+        steps.push(Box::new(MemoryStep));
+    }
+    steps.push(Box::new(AccountStep { bundle: opts.bundle.is_some() }));
+    steps.push(Box::new(MacStep { bundle: opts.bundle.is_some(), skip: opts.skip_mac_policy }));
+    if !opts.open_ports.is_empty() {
+        steps.push(Box::new(FirewallStep));
+    }
+    steps.push(Box::new(ServiceStep));
+    steps.push(Box::new(HealthStep(config.health.clone())));
+    steps.push(Box::new(ReceiptStep));
+    steps
+}
+
+fn install(opts: &Options, platform: &Platform, profile: Profile, bundle: Option<Bundle>, journal: &mut Journal) -> io::Result<()> {
+    let config = Config::load(INSTALLER_CONFIG)?;
+    config.hooks.check(STEPS)?;
+    let steps = resume::track(config.hooks.wrap(steps(opts, platform, profile, bundle, &config)), PROGRESS_PATH, opts);
+    hooks::global(HOOKS_DIR, "pre-install", &profile.name())?;
+    let mut run = Run { opts, platform, profile, journal, checking: false, names: Vec::new(), permissions: Vec::new(), applied: Vec::new() };
+    let result = engine::run(&steps, &mut run, opts.force);
+    log::log(Level::Info, &format!("Steps:\n{}", engine::summary(&engine::steps()).trim_end()));
+    if let Err(e) = result {
+        // The step it stopped in, so rollback tells where it was.
+        if let Some(step) = engine::steps().last().filter(|s| s.outcome == Outcome::Interrupted) {
+            run.journal.interrupted(&step.name)?;
+        }
+        if let Some(step) = engine::steps().last().filter(|s| s.outcome != Outcome::Done && !runcmd::Context::current().dry_run) {
+            log::log(Level::Warn, &format!("`installer install --resume` picks up at the {} step.", step.name));
+        }
+        return Err(e);
+    }
+    resume::finish(PROGRESS_PATH);
+
+    log::log(Level::Info, &format!("bitflux {} installed.", profile.name()));
+    hooks::global(HOOKS_DIR, "post-install", &profile.name())?;
+    let reasons = reboot::reasons();
+    if !reasons.is_empty() {
+        log::log(Level::Warn, &reboot::notice(&reasons));
+        if opts.reboot && platform.virt.is_container() {
+            log::log(Level::Warn, "Not rebooting from inside a container, restart the host.");
+        } else if opts.reboot {
+            reboot::schedule();
+        }
+    }
+    Ok(())
+}
+
+/// `install --plan --output tfjson`: the plan of an install with `opts` on this host, with
+/// what the engine's check finds left to do in every step.
+pub fn machine_plan(opts: &Options) -> io::Result<MachinePlan> {
+    Ok(plan(opts)?.machine(opts, &LiveState { pm: PackageManager::detect() }))
+}
+
+/// Applies a plan saved with `install --plan --output tfjson`.  Refuses when the engine's check
+/// on this host now finds other steps left to do, and changes nothing when none are.
+pub fn apply_plan<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    let path = path.as_ref();
+    let data = fs::read_to_string(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("Can't read the plan {}: {}", path.display(), e)))?;
+    let saved: MachinePlan = serde_json::from_str(&data)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))?;
+    if saved.format_version != plan::FORMAT_VERSION {
+        return Err(io::Error::other(format!("{} has plan format {}, this installer reads {}.", path.display(), saved.format_version, plan::FORMAT_VERSION)));
+    }
+    // Plans don't carry the license key, it comes from the environment like for --emit-script.
+    let mut options = saved.options.clone();
+    if options.license_key.is_some() {
+        let key = env::var(script::LICENSE_KEY_VAR)
+            .map_err(|_| io::Error::other(format!("{} was made with a license key, pass it in {}.", path.display(), script::LICENSE_KEY_VAR)))?;
+        options.license_key = Some(key);
+    }
+    let current = machine_plan(&options)?;
+    let drifted = plan::drift(&saved, &current);
+    if !drifted.is_empty() {
+        return Err(io::Error::other(format!("The host changed since {} was made, plan again. Steps that differ: {}", path.display(), drifted.join(", "))));
+    }
+    if !current.steps.values().any(|&pending| pending) {
+        log::log(Level::Info, &format!("Nothing to change, the host already matches {}.", path.display()));
+        return Ok(());
+    }
+    run(&options)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn answers_file() {
+        let path = std::env::temp_dir().join(format!("bitflux-answers-{}.toml", std::process::id()));
+        fs::write(&path, "license_key = \"abc\"\ndevice_id = \"web1\"\nkernel = false\n\n[agent]\nreclaim = true\ninterval = 30\n").unwrap();
+        let opts = Options::load(&path).unwrap();
+        assert_eq!(opts.install_profile(), Profile::Agent);
+        assert_eq!(opts.agent_settings(), [("interval", "30"), ("reclaim", "true"), ("licensekey", "abc"), ("deviceid", "web1")]
+            .map(|(k, v)| (String::from(k), String::from(v))));
+        fs::write(&path, "licence_key = \"abc\"\n").unwrap();
+        assert!(Options::load(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn redacted_masks_the_license_key() {
+        let opts = Options { license_key: Some(String::from("abcd-1234")), device_id: Some(String::from("web1")), ..Default::default() };
+        let redacted = opts.redacted();
+        assert_eq!((redacted.license_key.as_deref(), redacted.device_id.as_deref()), (Some("[redacted]"), Some("web1")));
+        assert_eq!(Options::default().redacted().license_key, None);
+    }
+
+    #[test]
+    fn wizard_reasks_invalid_answers() {
+        let mut prompt = Prompt::new("n
+
+abc 123
+abcd-1234
+web#1
+web1
+".as_bytes(), Vec::new());
+        let opts = Options::ask(&mut prompt).unwrap();
+        assert_eq!(opts.install_profile(), Profile::Agent);
+        assert_eq!((opts.license_key.as_deref(), opts.device_id.as_deref()), (Some("abcd-1234"), Some("web1")));
+    }
+
+    fn jammy() -> Platform {
+        Platform {
+            os: crate::platform::OsRelease::parse("ID=ubuntu\nVERSION_ID=\"22.04\"\nVERSION_CODENAME=jammy\n"),
+            pm: PackageManager::Apt,
+            kernel: String::from("5.15.0-91-generic"),
+            arch: crate::arch::Arch::X86_64,
+            page_size: 4096,
+            init: None,
+            transactional: false,
+            virt: crate::virt::Virt::BareMetal,
+            bootloader: None,
+            mac: crate::mac::Mac::None,
+            sbc: false,
+            raspberry_pi: false,
+        }
+    }
+
+    #[test]
+    fn agent_step_with_mocked_commands() {
+        let mock = std::sync::Arc::new(crate::executor::MockExecutor::new());
+        mock.on("dpkg-query", crate::executor::Reply::exit(1, "dpkg-query: no packages found matching bitfluxcollector"));
+        let _using = crate::executor::using(mock.clone());
+        let platform = jammy();
+        let dir = std::env::temp_dir().join(format!("bitflux-install-journal-{}", std::process::id()));
+        let mut journal = Journal::open(&dir).unwrap();
+        let opts = Options::default();
+        let mut run = Run { opts: &opts, platform: &platform, profile: Profile::Agent, journal: &mut journal, checking: false, names: Vec::new(), permissions: Vec::new(), applied: Vec::new() };
+        assert!(!AgentStep.check(&mut run).unwrap());
+        AgentStep.apply(&mut run).unwrap();
+        assert_eq!(run.names, [AGENT_PACKAGE]);
+        assert_eq!(mock.commands(), [
+            "dpkg-query -W '-f=${Version}' bitfluxcollector",
+            "dpkg-query -W '-f=${Version}' bitfluxcollector",
+            "apt-get install -y bitfluxcollector",
+        ]);
+        assert_eq!(mock.calls()[2].env, [(String::from("DEBIAN_FRONTEND"), String::from("noninteractive"))]);
+        assert_eq!(run.journal.changes.len(), 1);
+        assert!(AgentStep.rollback(&mut run).unwrap());
+        assert!(journal.changes.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    /// A step named `name` after `after` that does nothing, or fails when `fails`.
+    struct Stub {
+        name: &'static str,
+        after: Vec<&'static str>,
+        fails: bool,
+    }
+
+    impl Step<Run<'_>> for Stub {
+
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn title(&self) -> &'static str {
+            self.name
+        }
+
+        fn after(&self) -> Vec<&'static str> {
+            self.after.clone()
+        }
+
+        fn check(&self, _run: &mut Run) -> io::Result<bool> {
+            Ok(false)
+        }
+
+        fn apply(&self, _run: &mut Run) -> io::Result<()> {
+            match self.fails {
+                true => Err(io::Error::other("boom")),
+                false => Ok(()),
+            }
+        }
+
+    }
+
+    #[test]
+    fn account_step_rolled_back_when_a_later_step_fails() {
+        if account::lookup().is_some() {
+            return;
+        }
+        let mock = std::sync::Arc::new(crate::executor::MockExecutor::new());
+        let platform = jammy();
+        let dir = std::env::temp_dir().join(format!("bitflux-install-account-{}", std::process::id()));
+        let mut journal = Journal::open(&dir).unwrap();
+        let opts = Options::default();
+        let mut run = Run { opts: &opts, platform: &platform, profile: Profile::Agent, journal: &mut journal, checking: false, names: Vec::new(), permissions: Vec::new(), applied: Vec::new() };
+        let steps: Vec<Box<dyn Step<Run>>> = vec![
+            Box::new(Stub { name: "agent", after: Vec::new(), fails: false }),
+            Box::new(AccountStep { bundle: false }),
+            Box::new(Stub { name: "fails", after: vec!["account"], fails: true }),
+        ];
+        assert!(engine::run_with(&steps, &mut run, false, mock.clone()).is_err());
+        assert!(mock.commands().iter().any(|c| c.starts_with("useradd --system")));
+        assert!(run.journal.changes.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn kernel_and_memory_steps_roll_back_what_they_journaled() {
+        let platform = jammy();
+        let dir = std::env::temp_dir().join(format!("bitflux-install-undo-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut journal = Journal::open(dir.join("journal")).unwrap();
+        let opts = Options::default();
+        let mut run = Run { opts: &opts, platform: &platform, profile: Profile::Agent, journal: &mut journal, checking: false, names: Vec::new(), permissions: Vec::new(), applied: Vec::new() };
+        let (sysctl, cmdline) = (dir.join("sysctl.conf"), dir.join("cmdline"));
+        for path in [&sysctl, &cmdline] {
+            fs::write(path, "before\n").unwrap();
+        }
+        assert!(!KernelStep { dkms: false }.rollback(&mut run).unwrap());
+        run.journaled("kernel", |run| {
+            run.journal.file(&cmdline)?;
+            fs::write(&cmdline, "reclaim\n")
+        }).unwrap();
+        run.journaled("memory", |run| {
+            run.journal.file(&sysctl)?;
+            fs::write(&sysctl, "vm.swappiness=100\n")
+        }).unwrap();
+        assert!(MemoryStep.rollback(&mut run).unwrap());
+        assert_eq!(fs::read_to_string(&sysctl).unwrap(), "before\n");
+        assert_eq!(run.journal.changes.len(), 1);
+        assert!(KernelStep { dkms: false }.rollback(&mut run).unwrap());
+        assert_eq!(fs::read_to_string(&cmdline).unwrap(), "before\n");
+        assert!(journal.changes.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+}
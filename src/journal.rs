@@ -0,0 +1,316 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::account::{self, AGENT_USER};
+use crate::firewall::Firewall;
+use crate::kernel::running_kernel;
+use crate::log::{self, Level};
+use crate::pkg::PackageManager;
+use crate::resume::{self, PROGRESS_PATH};
+use crate::runcmd;
+use crate::service::Service;
+use crate::template;
+
+/// Where the install journal is kept, in state::STATE_DIR.  The copies of the files it replaced
+/// go to the backups directory next to it, data::BACKUP_DIR for this one.
+pub const JOURNAL_DIR: &str = "/var/lib/bitflux-installer/journal";
+const JOURNAL_FILE: &str = "journal.json";
+
+/// One change an install made, recorded before it's made so an install that dies half way
+/// is journaled too.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum Change {
+    /// A package that wasn't installed before.
+    Package { name: String },
+    /// A file written or replaced, `backup` is a copy of what was there, None if nothing was.
+    File { path: String, backup: Option<String> },
+    /// A service that wasn't enabled before.
+    Service { name: String },
+    /// A port opened in the firewall, named the way firewall::Firewall::name() does.
+    Firewall { firewall: String, port: String },
+    /// The agent's system account and its group, which weren't there before.
+    Account { name: String },
+    /// The install was interrupted during `step`, nothing to undo but the changes before it.
+    Interrupted { step: String },
+}
+
+impl Change {
+
+    fn describe(&self) -> String {
+        match self {
+            Change::Package { name } => format!("package {}", name),
+            Change::File { path, .. } => format!("file {}", path),
+            Change::Service { name } => format!("service {}", name),
+            Change::Firewall { firewall, port } => format!("port {} opened in {}", port, firewall),
+            Change::Account { name } => format!("account {}", name),
+            Change::Interrupted { step } => format!("the install interrupted during the {} step", step),
+        }
+    }
+
+}
+
+/// What a rollback did.
+#[derive(Debug, Default, PartialEq)]
+pub struct RollbackReport {
+    pub undone: Vec<String>,
+    /// Changes that couldn't be undone and why, they stay in the journal for the next try.
+    pub failed: Vec<String>,
+}
+
+/// Every change the installs since the last rollback made to the system, oldest first.
+/// Rolling back undoes them newest first, back to how the system was before bitflux.
+///
+/// # Examples
+///
+/// ```no_run
+/// use installer::journal::{Journal, JOURNAL_DIR};
+///
+/// let mut journal = Journal::open(JOURNAL_DIR)?;
+/// journal.file("/etc/apt/sources.list.d/bitflux.list")?;
+/// // ... write the file ...
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Debug)]
+pub struct Journal {
+    dir: PathBuf,
+    backups: PathBuf,
+    pub changes: Vec<Change>,
+}
+
+impl Journal {
+
+    /// The journal in `dir`, empty if there's none yet.
+    pub fn open<P: AsRef<Path>>(dir: P) -> io::Result<Journal> {
+        let dir = dir.as_ref().to_path_buf();
+        let backups = dir.parent().unwrap_or(&dir).join("backups");
+        let changes = match fs::read_to_string(dir.join(JOURNAL_FILE)) {
+            Ok(data) => serde_json::from_str(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+            Err(e) => return Err(e),
+        };
+        Ok(Journal { dir, backups, changes })
+    }
+
+    /// Writes the journal atomically, a dry run keeps it in memory.
+    fn save(&self) -> io::Result<()> {
+        if runcmd::Context::current().dry_run {
+            return Ok(());
+        }
+        fs::create_dir_all(&self.dir)?;
+        let data = serde_json::to_string_pretty(&self.changes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let path = self.dir.join(JOURNAL_FILE);
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, path)
+    }
+
+    fn record(&mut self, change: Change) -> io::Result<()> {
+        self.changes.push(change);
+        self.save()
+    }
+
+    /// Records that `path` is about to be written, keeping a timestamped copy of what's there
+    /// now.  A file already in the journal keeps its first copy, the one from before bitflux.
+    pub fn file<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let path = path.as_ref().to_string_lossy().into_owned();
+        if self.changes.iter().any(|c| matches!(c, Change::File { path: p, .. } if *p == path)) {
+            return Ok(());
+        }
+        let backup = match Path::new(&path).exists() {
+            true => Some(template::backup(&self.backups, Path::new(&path))?.to_string_lossy().into_owned()),
+            false => None,
+        };
+        self.record(Change::File { path, backup })
+    }
+
+    /// Records that package `name` is about to be installed, unless it already is.
+    pub fn package(&mut self, pm: &PackageManager, name: &str) -> io::Result<()> {
+        let journaled = self.changes.iter().any(|c| matches!(c, Change::Package { name: n } if n == name));
+        if journaled || pm.installed_version(name).is_some() {
+            return Ok(());
+        }
+        self.record(Change::Package { name: String::from(name) })
+    }
+
+    /// Records that the agent's system account is about to be created, unless it exists.
+    pub fn account(&mut self) -> io::Result<()> {
+        let journaled = self.changes.iter().any(|c| matches!(c, Change::Account { .. }));
+        if journaled || account::lookup().is_some() {
+            return Ok(());
+        }
+        self.record(Change::Account { name: String::from(AGENT_USER) })
+    }
+
+    /// Records that the install was interrupted during `step`.
+    pub fn interrupted(&mut self, step: &str) -> io::Result<()> {
+        self.record(Change::Interrupted { step: String::from(step) })
+    }
+
+    /// Records that service `name` is about to be enabled, unless it already is.
+    pub fn service(&mut self, name: &str) -> io::Result<()> {
+        let journaled = self.changes.iter().any(|c| matches!(c, Change::Service { name: n } if n == name));
+        if journaled || Service::new(name).is_enabled() {
+            return Ok(());
+        }
+        self.record(Change::Service { name: String::from(name) })
+    }
+
+    /// Records that `port` is about to be opened in `firewall`, unless it already is.
+    pub fn firewall(&mut self, firewall: Firewall, port: &str) -> io::Result<()> {
+        let journaled = self.changes.iter().any(|c| matches!(c, Change::Firewall { firewall: f, port: p } if f == firewall.name() && p == port));
+        if journaled {
+            return Ok(());
+        }
+        self.record(Change::Firewall { firewall: String::from(firewall.name()), port: String::from(port) })
+    }
+
+    fn undo(change: &Change, pm: Option<&PackageManager>) -> Result<(), String> {
+        match change {
+            Change::Interrupted { .. } => Ok(()),
+            Change::Firewall { firewall, port } => {
+                let firewall = Firewall::from_name(firewall).ok_or_else(|| format!("unknown firewall {}", firewall))?;
+                firewall.close(port).map_err(|e| e.to_string())
+            }
+            Change::Service { name } => {
+                Service::new(name).disable(true).map_err(|e| e.to_string())
+            }
+            Change::Account { .. } => account::remove().map(|_| ()).map_err(|e| e.to_string()),
+            Change::File { path, backup: Some(backup) } => {
+                if runcmd::dry_run(&format!("restore {} from {}", path, backup)) {
+                    return Ok(());
+                }
+                fs::copy(backup, path).map(|_| ()).map_err(|e| e.to_string())
+            }
+            Change::File { path, backup: None } => {
+                if runcmd::dry_run(&format!("remove {}", path)) {
+                    return Ok(());
+                }
+                match fs::remove_file(path) {
+                    Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.to_string()),
+                    _ => Ok(()),
+                }
+            }
+            Change::Package { name } => {
+                let pm = pm.ok_or("no supported package manager found")?;
+                if pm.installed_version(name).is_none() {
+                    return Ok(());
+                }
+                // Removing the kernel we run on leaves nothing to boot the same way next time.
+                let running = running_kernel().ok().and_then(|k| pm.owner(&Path::new("/boot").join(format!("vmlinuz-{}", k))));
+                if running.as_deref() == Some(name.as_str()) {
+                    return Err(String::from("it's the running kernel, run rollback-kernel and roll back again after the reboot"));
+                }
+                match pm.remove(&[name]) {
+                    true => Ok(()),
+                    false => Err(format!("failed to remove {}", name)),
+                }
+            }
+        }
+    }
+
+    /// Undoes the journaled changes newest first.  Ones that fail stay in the journal, the
+    /// rest are dropped from it along with their copies.
+    pub fn rollback(&mut self, pm: Option<&PackageManager>) -> io::Result<RollbackReport> {
+        self.rollback_matching(pm, |_| true)
+    }
+
+    /// rollback() of only the changes `matching` picks, the others stay in the journal.
+    pub fn rollback_matching(&mut self, pm: Option<&PackageManager>, matching: impl Fn(&Change) -> bool) -> io::Result<RollbackReport> {
+        let mut report = RollbackReport::default();
+        let mut kept = Vec::new();
+        while let Some(change) = self.changes.pop() {
+            if !matching(&change) {
+                kept.push(change);
+                continue;
+            }
+            match Journal::undo(&change, pm) {
+                Ok(()) => {
+                    if let Change::File { backup: Some(backup), .. } = &change {
+                        if !runcmd::Context::current().dry_run {
+                            let _ = fs::remove_file(backup);
+                        }
+                    }
+                    report.undone.push(change.describe());
+                }
+                Err(e) => {
+                    report.failed.push(format!("{}: {}", change.describe(), e));
+                    kept.push(change);
+                }
+            }
+        }
+        kept.reverse();
+        self.changes = kept;
+        self.save()?;
+        Ok(report)
+    }
+
+}
+
+/// `installer rollback`: puts back what the journaled installs changed.
+pub fn run() -> io::Result<()> {
+    let mut journal = Journal::open(JOURNAL_DIR)?;
+    if journal.changes.is_empty() {
+        log::log(Level::Info, &format!("Nothing to roll back, no install changes are journaled in {}.", JOURNAL_DIR));
+        return Ok(());
+    }
+    let report = journal.rollback(PackageManager::detect().as_ref())?;
+    // What the failed install did is undone, there's nothing to resume.
+    resume::finish(PROGRESS_PATH);
+    for undone in &report.undone {
+        log::log(Level::Info, &format!("Undid {}", undone));
+    }
+    for failed in &report.failed {
+        log::log(Level::Error, &format!("Could not undo {}", failed));
+    }
+    match report.failed.len() {
+        0 => Ok(()),
+        n => Err(io::Error::other(format!("{} changes could not be rolled back, run rollback again once fixed.", n))),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rollback_restores_files() {
+        let dir = std::env::temp_dir().join(format!("bitflux-journal-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let existing = dir.join("existing.conf");
+        let created = dir.join("created.conf");
+        fs::write(&existing, "before").unwrap();
+
+        let mut journal = Journal::open(dir.join("journal")).unwrap();
+        journal.file(&existing).unwrap();
+        journal.file(&created).unwrap();
+        fs::write(&existing, "after").unwrap();
+        fs::write(&created, "new").unwrap();
+        // A second install doesn't lose the copy from before the first.
+        journal.file(&existing).unwrap();
+        assert_eq!(journal.changes.len(), 2);
+        let Change::File { backup: Some(backup), .. } = &journal.changes[0] else { panic!("no copy of {}", existing.display()) };
+        assert!(Path::new(backup).starts_with(dir.join("backups").join("files")));
+        assert!(backup.ends_with(&existing.to_string_lossy()[1..]));
+
+        let mut journal = Journal::open(dir.join("journal")).unwrap();
+        let report = journal.rollback(None).unwrap();
+        assert_eq!(report.undone, [format!("file {}", created.display()), format!("file {}", existing.display())]);
+        assert_eq!(fs::read_to_string(&existing).unwrap(), "before");
+        assert!(!created.exists());
+        assert!(Journal::open(dir.join("journal")).unwrap().changes.is_empty());
+
+        let mut journal = Journal::open(dir.join("journal")).unwrap();
+        journal.record(Change::Package { name: String::from("bitfluxcollector") }).unwrap();
+        let report = journal.rollback(None).unwrap();
+        assert_eq!(report.failed.len(), 1);
+        assert_eq!(Journal::open(dir.join("journal")).unwrap().changes.len(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+}
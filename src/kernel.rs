@@ -0,0 +1,444 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::journal::{Journal, JOURNAL_DIR};
+use crate::log::{self, Level};
+use crate::pkg::{self, PackageManager};
+use crate::platform::Platform;
+use crate::reboot;
+use crate::runcmd::{self, which, RunCmd};
+use crate::sbc;
+use crate::virt::Virt;
+use crate::wsl;
+
+/// Remembers which kernel was running before the bitflux kernel went in.
+pub const KERNEL_STATE_PATH: &str = "/var/lib/bitflux/kernel.json";
+pub const GRUB_CFG: &str = "/boot/grub/grub.cfg";
+pub const GRUB_DEFAULTS: &str = "/etc/default/grub";
+/// SUSE keeps grub2 under its own name.
+pub const GRUB2_CFG: &str = "/boot/grub2/grub.cfg";
+/// U-Boot distro boot menu, used by ARM boards and some ARM64 servers without UEFI.
+pub const EXTLINUX_CFG: &str = "/boot/extlinux/extlinux.conf";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct KernelState {
+    /// `uname -r` of the kernel that was running when the bitflux kernel was installed.
+    pub previous: String,
+    /// HWE kernel packages held so they don't pull in a newer default kernel, released on rollback.
+    #[serde(default)]
+    pub held: Vec<String>,
+}
+
+/// The tool used to pick the default boot entry.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Bootloader {
+    /// RHEL family, boot entries managed by grubby.
+    Grubby,
+    /// Debian family, plain grub.cfg regenerated by update-grub.  Also GRUB on ARM64 UEFI.
+    Grub,
+    /// SUSE family, grub2.cfg regenerated by grub2-mkconfig, saved default set by grub2-set-default.
+    Grub2,
+    /// Raspberry Pi firmware, boots the kernel= image named in config.txt.  No GRUB.
+    RpiFirmware,
+    /// U-Boot reading extlinux.conf, regenerated by u-boot-update where u-boot-menu is installed.
+    Extlinux,
+}
+
+impl Bootloader {
+
+    pub fn detect() -> Option<Bootloader> {
+        if which("grubby").is_some() {
+            Some(Bootloader::Grubby)
+        } else if Path::new(GRUB_CFG).exists() {
+            Some(Bootloader::Grub)
+        } else if Path::new(GRUB2_CFG).exists() {
+            Some(Bootloader::Grub2)
+        } else if sbc::firmware_config().is_some() {
+            Some(Bootloader::RpiFirmware)
+        } else if Path::new(EXTLINUX_CFG).exists() {
+            Some(Bootloader::Extlinux)
+        } else {
+            None
+        }
+    }
+
+    /// Checks the boot menu still has an entry for `version`.
+    pub fn has_entry(&self, version: &str) -> bool {
+        match self {
+            Bootloader::Grubby => {
+                RunCmd::args("grubby", &[&format!("--info=/boot/vmlinuz-{}", version)])
+                    .execute_output()
+                    .exitcode == 0
+            }
+            Bootloader::Grub => match fs::read_to_string(GRUB_CFG) {
+                Ok(cfg) => grub_entry_path(&cfg, version).is_some(),
+                Err(_) => false,
+            },
+            Bootloader::Grub2 => match fs::read_to_string(GRUB2_CFG) {
+                Ok(cfg) => grub_entry_path(&cfg, version).is_some(),
+                Err(_) => false,
+            },
+            Bootloader::Extlinux => match fs::read_to_string(EXTLINUX_CFG) {
+                Ok(cfg) => extlinux_label(&cfg, version).is_some(),
+                Err(_) => false,
+            },
+            // The firmware can boot any kernel in /boot once set_default copies it over.
+            Bootloader::RpiFirmware => Path::new(&format!("/boot/vmlinuz-{}", version)).exists(),
+        }
+    }
+
+    /// Rebuilds the boot menu from the kernels on disk.
+    pub fn regenerate(&self) -> io::Result<()> {
+        match self {
+            // grubby edits BLS entries in place, there is nothing to regenerate.
+            Bootloader::Grubby => {}
+            Bootloader::Grub => {
+                if which("update-grub").is_some() {
+                    RunCmd::args("update-grub", &[]).try_execute().map_err(io::Error::from)?;
+                } else {
+                    RunCmd::args("grub-mkconfig", &["-o", GRUB_CFG]).try_execute().map_err(io::Error::from)?;
+                }
+            }
+            Bootloader::Grub2 => {
+                RunCmd::args("grub2-mkconfig", &["-o", GRUB2_CFG]).try_execute().map_err(io::Error::from)?;
+            }
+            // config.txt names the kernel directly, there is no menu.
+            Bootloader::RpiFirmware => {}
+            Bootloader::Extlinux => {
+                if which("u-boot-update").is_some() {
+                    RunCmd::args("u-boot-update", &[]).try_execute().map_err(io::Error::from)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Makes `version` the kernel booted by default, the files it edits go in `journal` first.
+    pub fn set_default(&self, version: &str, journal: &mut Journal) -> io::Result<()> {
+        match self {
+            Bootloader::Grubby => {
+                RunCmd::args("grubby", &[&format!("--set-default=/boot/vmlinuz-{}", version)]).try_execute().map_err(io::Error::from)?;
+            }
+            Bootloader::Grub => {
+                let cfg = fs::read_to_string(GRUB_CFG)?;
+                let entry = grub_entry_path(&cfg, version)
+                    .ok_or_else(|| io::Error::other(format!("No boot entry for kernel '{}' in {}", version, GRUB_CFG)))?;
+                let defaults = fs::read_to_string(GRUB_DEFAULTS)?;
+                journal.file(GRUB_DEFAULTS)?;
+                if !runcmd::dry_run(&format!("set GRUB_DEFAULT in {}", GRUB_DEFAULTS)) {
+                    fs::write(GRUB_DEFAULTS, set_grub_default(&defaults, &entry))?;
+                }
+                self.regenerate()?;
+            }
+            Bootloader::Grub2 => {
+                let cfg = fs::read_to_string(GRUB2_CFG)?;
+                let entry = grub_entry_path(&cfg, version)
+                    .ok_or_else(|| io::Error::other(format!("No boot entry for kernel '{}' in {}", version, GRUB2_CFG)))?;
+                // SUSE ships GRUB_DEFAULT=saved, the entry goes into grubenv.
+                RunCmd::args("grub2-set-default", &[&entry]).try_execute().map_err(io::Error::from)?;
+            }
+            Bootloader::Extlinux => {
+                let cfg = fs::read_to_string(EXTLINUX_CFG)?;
+                let label = extlinux_label(&cfg, version)
+                    .ok_or_else(|| io::Error::other(format!("No boot entry for kernel '{}' in {}", version, EXTLINUX_CFG)))?;
+                journal.file(EXTLINUX_CFG)?;
+                if !runcmd::dry_run(&format!("make '{}' the default in {}", label, EXTLINUX_CFG)) {
+                    fs::write(EXTLINUX_CFG, set_extlinux_default(&cfg, &label))?;
+                }
+            }
+            Bootloader::RpiFirmware => {
+                let config = sbc::firmware_config().ok_or_else(|| io::Error::other("No Raspberry Pi config.txt found."))?;
+                let firmware = Path::new(config).parent().unwrap_or(Path::new("/boot"));
+                let (kernel, initramfs) = (format!("vmlinuz-{}", version), format!("initrd.img-{}", version));
+                journal.file(config)?;
+                if runcmd::dry_run(&format!("boot {} from {}", kernel, config)) {
+                    return Ok(());
+                }
+                // The firmware only reads its own FAT partition, mounted at /boot/firmware on Bookworm.
+                if firmware != Path::new("/boot") {
+                    fs::copy(Path::new("/boot").join(&kernel), firmware.join(&kernel))?;
+                    fs::copy(Path::new("/boot").join(&initramfs), firmware.join(&initramfs))?;
+                }
+                let cfg = fs::read_to_string(config)?;
+                fs::write(config, sbc::set_boot_kernel(&cfg, &kernel, &initramfs))?;
+            }
+        }
+        Ok(())
+    }
+
+}
+
+/// `uname -r` of the running kernel.
+pub fn running_kernel() -> io::Result<String> {
+    Ok(fs::read_to_string("/proc/sys/kernel/osrelease")?.trim().to_string())
+}
+
+/// Versions of all kernels with an image in /boot.
+pub fn installed_kernels() -> Vec<String> {
+    let mut kernels: Vec<String> = match fs::read_dir("/boot") {
+        Ok(dir) => dir
+            .filter_map(|e| e.ok())
+            .filter_map(|e| e.file_name().to_str().and_then(|n| n.strip_prefix("vmlinuz-")).map(String::from))
+            .collect(),
+        Err(_) => Vec::new(),
+    };
+    kernels.sort();
+    kernels
+}
+
+pub fn load_state<P: AsRef<Path>>(path: P) -> io::Result<KernelState> {
+    let data = fs::read_to_string(path)?;
+    serde_json::from_str(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+pub fn save_state<P: AsRef<Path>>(path: P, state: &KernelState) -> io::Result<()> {
+    let path = path.as_ref();
+    if runcmd::dry_run(&format!("write {}", path.display())) {
+        return Ok(());
+    }
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let data = serde_json::to_string_pretty(state).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    fs::write(path, data)
+}
+
+/// Stops the package manager from auto-removing the kernel we may need to fall back to.
+fn protect_kernel(pm: &PackageManager, version: &str) {
+    if *pm == PackageManager::Apt {
+        RunCmd::args("apt-mark", &["manual", &format!("linux-image-{}", version)]).execute_output();
+    }
+    // dnf never removes the running kernel and keeps installonly_limit kernels around,
+    // has_entry() below catches the case where it went anyway.
+}
+
+/// Holds the Ubuntu HWE kernel packages, otherwise the next HWE kernel a regular upgrade
+/// brings in sorts above the bitflux kernel and grub boots it instead.
+fn hold_hwe() -> Vec<String> {
+    let packages = pkg::hwe_kernel_packages();
+    if !packages.is_empty() {
+        log::log(Level::Info, &format!("Holding HWE kernel packages so they don't replace the bitflux kernel: {}", packages.join(" ")));
+        let args: Vec<&str> = ["hold"].into_iter().chain(packages.iter().map(String::as_str)).collect();
+        RunCmd::args("apt-mark", &args).execute_output();
+    }
+    packages
+}
+
+/// Kernel and bootloader changes can't work under WSL2, explain what to do instead.
+fn refuse_on_wsl2() -> io::Result<()> {
+    if wsl::is_wsl2() {
+        return Err(io::Error::other(wsl::KERNEL_HELP));
+    }
+    Ok(())
+}
+
+/// Installs the bitflux kernel by running `install` while guaranteeing the kernel `platform`
+/// runs stays installed and bootable, so `rollback-kernel` always has something to go back to.
+pub fn install_with_fallback(install: &mut RunCmd, platform: &Platform) -> io::Result<()> {
+    if platform.virt == Virt::Wsl {
+        return Err(io::Error::other(wsl::KERNEL_HELP));
+    }
+    let bootloader = platform.bootloader
+        .ok_or_else(|| io::Error::other("Can't recognize the bootloader, refusing to install a kernel."))?;
+    let previous = platform.kernel.clone();
+
+    let held = hold_hwe();
+    save_state(KERNEL_STATE_PATH, &KernelState { previous: previous.clone(), held })?;
+    protect_kernel(&platform.pm, &previous);
+
+    let before = installed_kernels();
+    install.try_execute().map_err(io::Error::from)?;
+    if let Some(new) = installed_kernels().into_iter().find(|k| !before.contains(k)) {
+        reboot::flag(&format!("kernel {} was installed, it runs after a reboot", new))?;
+    }
+    // A dry run installed nothing, the boot menu is as it was.
+    if runcmd::Context::current().dry_run {
+        return Ok(());
+    }
+
+    if !bootloader.has_entry(&previous) {
+        bootloader.regenerate()?;
+    }
+    if !bootloader.has_entry(&previous) {
+        return Err(io::Error::other(format!("Previous kernel '{}' is missing from the boot menu.", previous)));
+    }
+    Ok(())
+}
+
+/// Makes the kernel that ran before the bitflux install the default again and reboots into it.
+pub fn rollback() -> io::Result<()> {
+    refuse_on_wsl2()?;
+    let state = load_state(KERNEL_STATE_PATH)
+        .map_err(|e| io::Error::new(e.kind(), format!("No previous kernel recorded in {}: {}", KERNEL_STATE_PATH, e)))?;
+    let bootloader = Bootloader::detect()
+        .ok_or_else(|| io::Error::other("Can't recognize the bootloader."))?;
+
+    if !bootloader.has_entry(&state.previous) {
+        return Err(io::Error::other(format!("Previous kernel '{}' is no longer in the boot menu.", state.previous)));
+    }
+
+    log::log(Level::Info, &format!("Setting default kernel to '{}'", state.previous));
+    bootloader.set_default(&state.previous, &mut Journal::open(JOURNAL_DIR)?)?;
+    if !state.held.is_empty() {
+        let args: Vec<&str> = ["unhold"].into_iter().chain(state.held.iter().map(String::as_str)).collect();
+        RunCmd::args("apt-mark", &args).execute_output();
+    }
+    log::log(Level::Info, "Rebooting.");
+    RunCmd::args("reboot", &[]).try_execute().map_err(io::Error::from)?;
+    Ok(())
+}
+
+/// Finds the grub menu path ("Submenu>Entry") of the non-recovery entry booting `version`.
+pub fn grub_entry_path(cfg: &str, version: &str) -> Option<String> {
+    let mut submenu: Option<String> = None;
+
+    for line in cfg.lines() {
+        let trimmed = line.trim_start();
+        if let Some(title) = quoted_title(trimmed, "submenu") {
+            submenu = Some(title);
+        } else if let Some(title) = quoted_title(trimmed, "menuentry") {
+            if title.contains(version) && !title.contains("(recovery mode)") {
+                return Some(match &submenu {
+                    Some(parent) => format!("{}>{}", parent, title),
+                    None => title,
+                });
+            }
+        } else if line.starts_with('}') {
+            submenu = None;
+        }
+    }
+    None
+}
+
+fn quoted_title(line: &str, keyword: &str) -> Option<String> {
+    let rest = line.strip_prefix(keyword)?.trim_start();
+    let rest = rest.strip_prefix('\'')?;
+    let end = rest.find('\'')?;
+    Some(rest[..end].to_string())
+}
+
+/// Rewrites GRUB_DEFAULT in the contents of /etc/default/grub.
+pub fn set_grub_default(defaults: &str, entry: &str) -> String {
+    let line = format!("GRUB_DEFAULT=\"{}\"", entry);
+    let mut found = false;
+    let mut out: Vec<String> = defaults
+        .lines()
+        .map(|l| {
+            if l.starts_with("GRUB_DEFAULT=") {
+                found = true;
+                line.clone()
+            } else {
+                l.to_string()
+            }
+        })
+        .collect();
+    if !found {
+        out.push(line);
+    }
+    out.join("\n") + "\n"
+}
+
+/// Finds the LABEL of the extlinux.conf entry whose LINUX/KERNEL image is `version`.
+pub fn extlinux_label(cfg: &str, version: &str) -> Option<String> {
+    let mut label: Option<String> = None;
+    for line in cfg.lines() {
+        let mut words = line.split_whitespace();
+        match words.next().map(|w| w.to_ascii_uppercase()).as_deref() {
+            Some("LABEL") => label = words.next().map(String::from),
+            Some("LINUX") | Some("KERNEL") => {
+                let image = words.next().unwrap_or("");
+                if image.ends_with(&format!("-{}", version)) && !line.to_lowercase().contains("recovery") {
+                    if let Some(l) = &label {
+                        return Some(l.clone());
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Rewrites the DEFAULT line of extlinux.conf, adding it at the top when missing.
+pub fn set_extlinux_default(cfg: &str, label: &str) -> String {
+    let line = format!("DEFAULT {}", label);
+    let is_default = |l: &str| l.split_whitespace().next().is_some_and(|w| w.eq_ignore_ascii_case("DEFAULT"));
+    if !cfg.lines().any(is_default) {
+        return format!("{}\n{}", line, cfg);
+    }
+    cfg.lines().map(|l| if is_default(l) { line.clone() } else { l.to_string() }).collect::<Vec<String>>().join("\n") + "\n"
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CFG: &str = "\
+menuentry 'Ubuntu' --class ubuntu {
+	linux /boot/vmlinuz-5.15.0-100-swaphints
+}
+submenu 'Advanced options for Ubuntu' $menuentry_id_option 'gnulinux-advanced-1234' {
+	menuentry 'Ubuntu, with Linux 5.15.0-100-swaphints' --class ubuntu {
+	}
+	menuentry 'Ubuntu, with Linux 5.15.0-91-generic (recovery mode)' --class ubuntu {
+	}
+	menuentry 'Ubuntu, with Linux 5.15.0-91-generic' --class ubuntu {
+	}
+}
+menuentry 'UEFI Firmware Settings' {
+}
+";
+
+    #[test]
+    fn grub_entry_in_submenu() {
+        assert_eq!(
+            grub_entry_path(CFG, "5.15.0-91-generic"),
+            Some(String::from("Advanced options for Ubuntu>Ubuntu, with Linux 5.15.0-91-generic"))
+        );
+        assert_eq!(grub_entry_path(CFG, "4.0.0-old"), None);
+    }
+
+    #[test]
+    fn grub_default_replaced_or_added() {
+        let out = set_grub_default("GRUB_DEFAULT=0\nGRUB_TIMEOUT=2\n", "A>B");
+        assert_eq!(out, "GRUB_DEFAULT=\"A>B\"\nGRUB_TIMEOUT=2\n");
+        let out = set_grub_default("GRUB_TIMEOUT=2\n", "A>B");
+        assert_eq!(out, "GRUB_TIMEOUT=2\nGRUB_DEFAULT=\"A>B\"\n");
+    }
+
+    const EXTLINUX: &str = "\
+## /boot/extlinux/extlinux.conf
+default l0
+menu title U-Boot menu
+label l0
+	menu label Debian GNU/Linux, kernel 6.1.0-100-swaphints
+	linux /boot/vmlinuz-6.1.0-100-swaphints
+label l1
+	menu label Debian GNU/Linux, kernel 6.1.0-18-arm64
+	linux /boot/vmlinuz-6.1.0-18-arm64
+";
+
+    #[test]
+    fn extlinux_entries() {
+        assert_eq!(extlinux_label(EXTLINUX, "6.1.0-18-arm64"), Some(String::from("l1")));
+        assert_eq!(extlinux_label(EXTLINUX, "6.1.0-18"), None);
+        let out = set_extlinux_default(EXTLINUX, "l1");
+        assert!(out.contains("\nDEFAULT l1\n"));
+        assert!(!out.contains("default l0"));
+        assert!(set_extlinux_default("label l0\n", "l0").starts_with("DEFAULT l0\n"));
+    }
+
+    #[test]
+    fn state_roundtrip() {
+        let path = std::env::temp_dir().join(format!("bitflux-kernel-{}.json", std::process::id()));
+        let state = KernelState { previous: String::from("5.15.0-91-generic"), held: vec![String::from("linux-generic-hwe-22.04")] };
+        save_state(&path, &state).unwrap();
+        assert_eq!(load_state(&path).unwrap(), state);
+        fs::remove_file(&path).unwrap();
+    }
+
+}
@@ -0,0 +1,94 @@
+use std::io;
+
+use serde::Deserialize;
+
+use crate::exitcode::Kind;
+use crate::log::{self, Level};
+use crate::pkg::PackageManager;
+use crate::preflight::kernel_series;
+
+/// The compatibility matrix shipped with the installer.
+const MATRIX: &str = include_str!("kernels.toml");
+
+/// The kernel series the bitflux kernel and the swaphints module support.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Matrix {
+    /// Series of the bitflux kernel packages, "6.8" matches every 6.8.x kernel.
+    pub kernel: Vec<String>,
+    /// Series of the kernels swaphints is built for with --dkms.
+    pub module: Vec<String>,
+}
+
+impl Matrix {
+
+    /// The matrix built into the installer.
+    pub fn builtin() -> Matrix {
+        toml::from_str(MATRIX).expect("src/kernels.toml is valid")
+    }
+
+    /// None when the bitflux kernel of package `version` is of a supported series, what's
+    /// wrong with it otherwise.
+    pub fn kernel(&self, package: &str, version: &str) -> Option<String> {
+        refusal(&self.kernel, &format!("{} {}", package, version), version)
+    }
+
+    /// None when swaphints builds for the kernel of `uname -r` `release`, what's wrong with it
+    /// otherwise.
+    pub fn module(&self, release: &str) -> Option<String> {
+        refusal(&self.module, &format!("the running kernel {}", release), release)
+    }
+
+}
+
+/// Whether `version`, a `uname -r` or a package version, is of one of `series`.
+pub fn supports(series: &[String], version: &str) -> bool {
+    kernel_series(version).is_some_and(|found| series.iter().any(|s| kernel_series(s) == Some(found)))
+}
+
+fn refusal(series: &[String], what: &str, version: &str) -> Option<String> {
+    match supports(series, version) {
+        true => None,
+        false => Some(format!("{} isn't of a supported series ({}); pass --allow-unsupported-kernel to install it anyway", what, series.join(", "))),
+    }
+}
+
+/// Checks the bitflux kernel the repositories would install against the matrix, before
+/// `package` goes in.  With `allow` an unsupported one only gets a warning.
+pub fn check_candidate(pm: &PackageManager, package: &str, allow: bool) -> io::Result<()> {
+    let Some(version) = pm.candidate_version(package) else {
+        return Ok(());
+    };
+    let series = Matrix::builtin().kernel;
+    if supports(&series, &version) {
+        return Ok(());
+    }
+    let message = format!("The bitflux kernel {} {} isn't of a supported series ({})", package, version, series.join(", "));
+    match allow {
+        true => {
+            log::log(Level::Warn, &format!("{}, installing it anyway.", message));
+            Ok(())
+        }
+        false => Err(Kind::Unsupported.error(format!("{}; pass --allow-unsupported-kernel to install it anyway.", message))),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn series_in_the_matrix() {
+        let matrix = Matrix::builtin();
+        assert!(matrix.kernel("linux-image-swaphints", "6.8.0.1004").is_none());
+        assert!(matrix.module("4.18.0-513.el8.x86_64").is_none());
+        assert_eq!(
+            matrix.module("5.19.0-50-generic").as_deref(),
+            Some("the running kernel 5.19.0-50-generic isn't of a supported series (4.18, 5.4, 5.10, 5.14, 5.15, 6.1, 6.2, 6.5, 6.8); pass --allow-unsupported-kernel to install it anyway")
+        );
+        assert!(!supports(&matrix.kernel, "6.80.1"));
+        assert!(!supports(&matrix.kernel, "unknown"));
+    }
+
+}
@@ -0,0 +1,213 @@
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::detect;
+use crate::exitcode::Kind;
+use crate::journal::Journal;
+use crate::kernel::running_kernel;
+use crate::log::{self, Level};
+use crate::mok::{self, KeyState};
+use crate::perms::FileKind;
+use crate::pkg::PackageManager;
+use crate::prompt::Prompt;
+use crate::reboot;
+use crate::runcmd::{self, RunCmd};
+use crate::template;
+use crate::transcript::TRANSCRIPT_DIR;
+
+/// The swaphints module, built for the running kernel instead of installing the bitflux kernel.
+pub const MODULE: &str = "swaphints";
+/// Package with the module source and its dkms.conf, from the bitflux repository.
+pub const DKMS_PACKAGE: &str = "swaphints-dkms";
+/// Loads the module on every boot.
+pub const MODULES_LOAD_PATH: &str = "/etc/modules-load.d/swaphints.conf";
+/// The kernel log of the last load that failed, for the diagnostics bundle.
+pub const LOAD_LOG: &str = "swaphints-load.txt";
+/// Seconds before modprobe ran that kernel log lines are kept from, the clocks of dmesg and
+/// /proc/uptime don't tick quite together.
+const LOG_SLACK_SECS: f64 = 1.0;
+
+/// The package with the headers to build modules for kernel `version` (`uname -r`).  SUSE
+/// names them by flavor, the last part of the version, like kernel-default-devel.
+pub fn headers_package(pm: &PackageManager, version: &str) -> String {
+    match pm {
+        PackageManager::Apt => format!("linux-headers-{}", version),
+        PackageManager::Dnf | PackageManager::Yum => format!("kernel-devel-{}", version),
+        PackageManager::Zypper => format!("kernel-{}-devel", version.rsplit('-').next().unwrap_or("default")),
+    }
+}
+
+/// Packages needed to build and install the module for kernel `version`, and to sign it
+/// and enroll the signing key under `secure_boot`.
+pub fn packages(pm: &PackageManager, version: &str, secure_boot: bool) -> Vec<String> {
+    let mut packages = vec![headers_package(pm, version), String::from("dkms"), String::from(DKMS_PACKAGE)];
+    if secure_boot {
+        packages.extend(["mokutil", "openssl"].map(String::from));
+    }
+    packages
+}
+
+/// True when modprobe's error means module signature enforcement turned the module away.
+pub fn rejected_unsigned(stderr: &str) -> bool {
+    ["Key was rejected by service", "Required key not available"].iter().any(|m| stderr.contains(m))
+}
+
+/// The lines about `module` and lockdown in kernel log `data` from `since` seconds after boot
+/// on, by the `[    12.345678]` timestamps dmesg and `journalctl -o short-monotonic` print.
+pub fn kernel_lines<'a>(data: &'a str, module: &str, since: f64) -> Vec<&'a str> {
+    data.lines()
+        .filter(|line| {
+            let secs = line.trim_start().strip_prefix('[').and_then(|l| l.split_once(']')).and_then(|(t, _)| t.trim().parse::<f64>().ok());
+            secs.is_some_and(|secs| secs >= since - LOG_SLACK_SECS)
+        })
+        .filter(|line| line.contains(module) || line.contains("Lockdown"))
+        .collect()
+}
+
+/// Seconds since boot, 0 when /proc/uptime can't be read.
+fn uptime() -> f64 {
+    let data = fs::read_to_string("/proc/uptime").unwrap_or_default();
+    data.split_whitespace().next().and_then(|s| s.parse().ok()).unwrap_or(0.0)
+}
+
+/// What the kernel logged about the module since `since` seconds after boot, `started` the
+/// same moment on the wall clock: from dmesg, or the journal where dmesg is restricted.
+fn kernel_log(since: f64, started: u64) -> Vec<String> {
+    let dmesg = RunCmd::args("dmesg", &[]).execute_output();
+    let data = match dmesg.exitcode {
+        0 => dmesg.stdout,
+        _ => RunCmd::args("journalctl", &["-k", "--since", &format!("@{}", started), "-o", "short-monotonic", "--no-pager"]).execute_output().stdout,
+    };
+    kernel_lines(&data, MODULE, since).into_iter().map(String::from).collect()
+}
+
+/// Keeps `lines` where the diagnostics bundle picks them up.
+fn save_kernel_log(lines: &[String]) -> io::Result<()> {
+    fs::create_dir_all(TRANSCRIPT_DIR)?;
+    let mut file = OpenOptions::new().create(true).write(true).truncate(true).mode(0o600).open(Path::new(TRANSCRIPT_DIR).join(LOAD_LOG))?;
+    file.write_all(lines.iter().map(|l| format!("{}\n", l)).collect::<String>().as_bytes())
+}
+
+fn module_built(version: &str) -> bool {
+    RunCmd::args("modinfo", &["-k", version, "-n", MODULE]).execute_output().exitcode == 0
+}
+
+/// Loads the module now, explaining when Secure Boot is why it can't be.  A failure comes with
+/// what the kernel logged about the module meanwhile, which is also kept for `installer diagnose`.
+pub fn load() -> io::Result<()> {
+    let (since, started) = (uptime(), SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0));
+    let out = RunCmd::args("modprobe", &[MODULE]).execute_output();
+    if out.exitcode == 0 {
+        return Ok(());
+    }
+    let lines = kernel_log(since, started);
+    if let Err(e) = save_kernel_log(&lines) {
+        log::log(Level::Warn, &format!("Couldn't keep the kernel log of the failed load: {}", e));
+    }
+    let mut message = match rejected_unsigned(&out.stderr) || (detect::secure_boot() == Some(true) && out.stderr.contains("Operation not permitted")) {
+        true => format!(
+            "Secure Boot refused to load the {} module, its signing key isn't enrolled. Enroll it with \
+             `mokutil --import {}` and reboot, or install the signed bitflux kernel instead.",
+            MODULE, mok::MOK_CERT
+        ),
+        false => format!("Failed to load the {} module: {}", MODULE, out.stderr.trim()),
+    };
+    if !lines.is_empty() {
+        message.push_str(&format!("\nThe kernel logged:\n  {}", lines.join("\n  ")));
+    }
+    Err(io::Error::other(message))
+}
+
+/// The packages install() installs, when it already built the module for the running kernel,
+/// has it loaded on every boot and, under Secure Boot, got the signing key enrolled.  None when
+/// it still has something to do.
+pub fn installed(pm: &PackageManager) -> Option<Vec<String>> {
+    let version = running_kernel().ok()?;
+    let secure_boot = mok::enabled();
+    let names = packages(pm, &version, secure_boot);
+    let done = names.iter().all(|name| pm.installed_version(name).is_some())
+        && Path::new(MODULES_LOAD_PATH).exists()
+        && module_built(&version)
+        && (!secure_boot || mok::key_state() == KeyState::Enrolled);
+    done.then_some(names)
+}
+
+/// Builds and installs the module for the running kernel with DKMS, loads it and has it loaded
+/// on every boot.  Under Secure Boot the module is signed and a signing key that isn't enrolled
+/// yet is queued for enrollment, the module loads after the reboot that enrolls it.  Returns the
+/// packages installed for it.
+pub fn install(pm: &PackageManager, journal: &mut Journal) -> io::Result<Vec<String>> {
+    let version = running_kernel()?;
+    let secure_boot = mok::enabled();
+    let names = packages(pm, &version, secure_boot);
+    for name in &names {
+        journal.package(pm, name)?;
+    }
+    let packages: Vec<&str> = names.iter().map(String::as_str).collect();
+    if !pm.install(&packages) {
+        return Err(io::Error::other(format!(
+            "Failed to install {}, is {} still available from the distro's repositories?",
+            packages.join(" "), names[0]
+        )));
+    }
+
+    if secure_boot {
+        // Before the first build, so dkms signs with it.
+        mok::ensure_key()?;
+    }
+    // The dkms package builds for the running kernel when installed, unless it was there already.
+    if !module_built(&version) {
+        let out = RunCmd::args("dkms", &["autoinstall", "-k", &version]).execute_output();
+        if out.exitcode != 0 || !module_built(&version) {
+            return Err(Kind::ModuleBuild.error(format!("DKMS failed to build {} for {}: {}", MODULE, version, out.stderr.trim())));
+        }
+    }
+
+    journal.file(MODULES_LOAD_PATH)?;
+    template::write(MODULES_LOAD_PATH, &template::render_named("modules-load.conf", &[("module", MODULE)])?, FileKind::Unit)?;
+
+    if secure_boot {
+        mok::sign(MODULE, &version)?;
+        match mok::key_state() {
+            KeyState::Enrolled => {}
+            KeyState::NotEnrolled if !runcmd::interactive() => return Err(mok::not_enrolled()),
+            state => {
+                if state == KeyState::NotEnrolled {
+                    mok::enroll(&mut Prompt::terminal())?;
+                }
+                log::log(Level::Info, mok::ENROLL_HELP);
+                reboot::flag(&format!("the {} signing key waits to be enrolled in MokManager, the module loads after the reboot", MODULE))?;
+                return Ok(names);
+            }
+        }
+    }
+    load()?;
+    Ok(names)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn headers_for_running_kernel() {
+        assert_eq!(headers_package(&PackageManager::Apt, "5.15.0-91-generic"), "linux-headers-5.15.0-91-generic");
+        assert_eq!(headers_package(&PackageManager::Dnf, "5.14.0-362.8.1.el9_3.x86_64"), "kernel-devel-5.14.0-362.8.1.el9_3.x86_64");
+        assert_eq!(headers_package(&PackageManager::Zypper, "5.14.21-150500.55.39-default"), "kernel-default-devel");
+        assert!(rejected_unsigned("modprobe: ERROR: could not insert 'swaphints': Key was rejected by service"));
+        assert!(!rejected_unsigned("modprobe: FATAL: Module swaphints not found"));
+    }
+
+    #[test]
+    fn kernel_log_of_the_load() {
+        let dmesg = "[    3.1] swaphints: loaded at boot\n[  120.2] usb 1-1: new device\n[  120.5] swaphints: disagrees about version of symbol module_layout\n[  120.6] Lockdown: modprobe: unsigned module loading is restricted\n";
+        assert_eq!(kernel_lines(dmesg, MODULE, 120.0), ["[  120.5] swaphints: disagrees about version of symbol module_layout", "[  120.6] Lockdown: modprobe: unsigned module loading is restricted"]);
+        let journal = "[  120.5] host kernel: swaphints: Unknown symbol foo (err -2)\n-- No entries --\n";
+        assert_eq!(kernel_lines(journal, MODULE, 119.8), ["[  120.5] host kernel: swaphints: Unknown symbol foo (err -2)"]);
+    }
+
+}
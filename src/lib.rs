@@ -0,0 +1,106 @@
+/*
+ SPDX-License-Identifier: MIT
+ Copyright (c) 2022 BitFlux, Inc.
+
+ Library behind the bitflux installer, shared with the other bitflux tools.  Tools that only
+ run commands depend on bitflux-runcmd in runcmd/ instead.
+*/
+
+pub mod account;
+pub mod agentconf;
+pub mod arch;
+pub mod audit;
+pub mod batch;
+pub mod budget;
+pub mod bundle;
+pub mod checksum;
+pub mod clock;
+pub mod cloud;
+pub mod cmdline;
+pub mod compat;
+pub mod completions;
+pub mod config;
+pub mod data;
+pub mod detect;
+pub mod device;
+pub mod diagnose;
+pub mod diskspace;
+pub mod download;
+pub mod engine;
+pub mod events;
+pub mod exitcode;
+pub mod ffi;
+pub mod fips;
+pub mod firewall;
+pub mod fleet;
+pub mod generate;
+pub mod health;
+pub mod history;
+pub mod hooks;
+pub mod i18n;
+pub mod image;
+pub mod install;
+pub mod journal;
+pub mod kernel;
+pub mod kernelmatrix;
+pub mod kmod;
+pub mod license;
+pub mod lock;
+pub mod log;
+pub mod mac;
+pub mod manifest;
+pub mod metrics;
+pub mod mok;
+pub mod notify;
+pub mod offline;
+pub mod output;
+pub mod perms;
+pub mod pkg;
+pub mod plan;
+pub mod platform;
+pub mod preflight;
+pub mod privsep;
+pub mod profile;
+pub mod profiling;
+pub mod proxy;
+pub mod prompt;
+pub mod progress;
+pub mod reboot;
+pub mod receipt;
+pub mod release;
+pub mod repair;
+pub mod repo;
+pub mod resume;
+pub mod runcmd;
+pub mod sbc;
+pub mod sbom;
+pub mod script;
+#[cfg(target_os = "linux")]
+pub mod selftest;
+pub mod selfupdate;
+pub mod selinux;
+#[cfg(target_os = "linux")]
+pub mod serve;
+pub mod service;
+pub mod signature;
+pub mod spinner;
+pub mod staged;
+pub mod state;
+pub mod status;
+pub mod sudo;
+pub mod summary;
+pub mod template;
+pub mod tls;
+pub mod transcript;
+#[cfg(target_os = "linux")]
+pub mod tui;
+pub mod uninstall;
+pub mod unit;
+pub mod verify;
+pub mod virt;
+pub mod workspace;
+pub mod writable;
+pub mod wsl;
+
+// The command layer, a crate of its own for the other bitflux tools.
+pub use bitflux_runcmd::{executor, interrupt, sanitize, watchdog};
@@ -0,0 +1,184 @@
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::cloud::{self, Metadata};
+use crate::exitcode::Kind;
+use crate::offline;
+use crate::perms::{self, FileKind, PermissionRecord};
+use crate::privsep::unprivileged_cmd;
+use crate::runcmd::{self, shell_quote};
+use crate::script::{self, LICENSE_KEY_VAR};
+use crate::tls;
+
+/// Where the license activation goes, from the activation endpoint or an offline bundle.
+pub const LICENSE_PATH: &str = "/etc/bitflux/license";
+/// Trades a license key for this host's activation token.
+pub const ACTIVATION_URL: &str = "https://api.bitflux.ai/v1/activate";
+/// Points the activation at another endpoint, for a test server.
+pub const LICENSE_URL_VAR: &str = "BITFLUX_LICENSE_URL";
+/// curl's exit code for an HTTP error status with -f.
+const CURL_HTTP_ERROR: i32 = 22;
+
+/// What the activation endpoint returns, and what's kept in LICENSE_PATH.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Activation {
+    pub token: String,
+    /// RFC 3339, None for a license that doesn't expire.
+    #[serde(default)]
+    pub expires: Option<String>,
+}
+
+/// Checks the form of a license key before anything is sent anywhere: 8 to 128 letters, digits
+/// and dashes, not starting or ending with a dash.
+pub fn validate(key: &str) -> Result<(), String> {
+    if !(8..=128).contains(&key.len()) {
+        return Err(String::from("The license key has 8 to 128 characters."));
+    }
+    if let Some(c) = key.chars().find(|c| !c.is_ascii_alphanumeric() && *c != '-') {
+        return Err(format!("The license key can't contain {:?}.", c));
+    }
+    if key.starts_with('-') || key.ends_with('-') {
+        return Err(String::from("The license key can't start or end with a dash."));
+    }
+    Ok(())
+}
+
+/// ACTIVATION_URL, or the endpoint LICENSE_URL_VAR names.
+pub fn activation_url() -> String {
+    std::env::var(LICENSE_URL_VAR).ok().filter(|u| !u.is_empty()).unwrap_or_else(|| String::from(ACTIVATION_URL))
+}
+
+/// Only a test server on the loopback gets away with plain http.
+fn loopback_http(url: &str) -> bool {
+    ["http://127.0.0.1:", "http://127.0.0.1/", "http://localhost:", "http://localhost/", "http://[::1]:"].iter().any(|p| url.starts_with(p))
+}
+
+/// The request body, the key goes on curl's stdin so it never shows in `ps`.  On a cloud the
+/// instance from its metadata service registers along with the device.
+fn request(key: &str, device_id: Option<&str>, instance: Option<&Metadata>) -> io::Result<String> {
+    let body = match instance {
+        Some(instance) => serde_json::json!({ "license_key": key, "device_id": device_id, "instance": instance }),
+        None => serde_json::json!({ "license_key": key, "device_id": device_id }),
+    };
+    serde_json::to_string(&body).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Activates `key` for this host with the activation endpoint and stores the token in
+/// LICENSE_PATH, root only.
+pub fn activate(key: &str, device_id: Option<&str>) -> io::Result<PermissionRecord> {
+    validate(key).map_err(io::Error::other)?;
+    let url = activation_url();
+    offline::guard(&url)?;
+    let mut args: Vec<String> = ["-fsS", "--max-time", "30", "-X", "POST", "-H", "Content-Type: application/json"]
+        .map(String::from).to_vec();
+    if !loopback_http(&url) {
+        args.extend(tls::hardened_curl_args());
+    }
+    args.extend(["--data-binary", "@-", "--", &url].map(String::from));
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    if script::recording() {
+        script::shell(&activation_script(&args, device_id));
+        return perms::apply(LICENSE_PATH, FileKind::Secret);
+    }
+    if runcmd::dry_run(&format!("activate the license key with {} and write {}", url, LICENSE_PATH)) {
+        return perms::apply(LICENSE_PATH, FileKind::Secret);
+    }
+    let out = unprivileged_cmd("curl", &args).secret_stdin(&request(key, device_id, cloud::metadata().as_ref())?).execute_output();
+    tls::log(&url, &out.stderr);
+    match out.exitcode {
+        0 => {}
+        // -f turns an HTTP error into this, the endpoint answered and said no.
+        CURL_HTTP_ERROR => return Err(Kind::License.error(format!("License activation failed: {}", out.stderr.trim()))),
+        _ => return Err(Kind::Network.error(format!("License activation failed, {} unreachable: {}", url, out.stderr.trim()))),
+    }
+    let activation: Activation = serde_json::from_str(&out.stdout)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("Unexpected reply from {}: {}", url, e)))?;
+    store(LICENSE_PATH, &activation)
+}
+
+/// The activation for `install --emit-script`: curl with `args` posts the key read from
+/// LICENSE_KEY_VAR when the script runs, and the reply is renamed into LICENSE_PATH once complete.
+fn activation_script(args: &[&str], device_id: Option<&str>) -> String {
+    let device = serde_json::to_string(&device_id).unwrap_or_else(|_| String::from("null"));
+    let partial = shell_quote(&format!("{}.tmp", LICENSE_PATH));
+    let dir = Path::new(LICENSE_PATH).parent().map(|d| d.to_string_lossy().into_owned()).unwrap_or_default();
+    format!(
+        "mkdir -p {}\n(umask 077; printf '{{\"license_key\": \"%s\", \"device_id\": %s}}' \"${{{}}}\" {} | curl {} > {})\nmv {} {}",
+        shell_quote(&dir), LICENSE_KEY_VAR, shell_quote(&device),
+        args.iter().map(|a| shell_quote(a)).collect::<Vec<String>>().join(" "), partial, partial, shell_quote(LICENSE_PATH),
+    )
+}
+
+/// Writes `activation` to `path`, created 0600 and renamed into place so it's never readable
+/// by anyone but root on the way.
+pub fn store<P: AsRef<Path>>(path: P, activation: &Activation) -> io::Result<PermissionRecord> {
+    let path = path.as_ref();
+    let data = serde_json::to_string_pretty(activation).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let tmp = path.with_extension("tmp");
+    let _ = fs::remove_file(&tmp);
+    fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(&tmp)?.write_all(data.as_bytes())?;
+    let mut record = perms::apply(&tmp, FileKind::Secret)?;
+    fs::rename(&tmp, path)?;
+    record.path = path.to_string_lossy().into_owned();
+    Ok(record)
+}
+
+/// One line on the activation in `path`, for `installer status`.
+pub fn status<P: AsRef<Path>>(path: P) -> String {
+    match fs::read_to_string(path) {
+        Err(_) => String::from("not activated"),
+        Ok(data) => match serde_json::from_str::<Activation>(&data) {
+            Ok(Activation { expires: Some(expires), .. }) => format!("activated, expires {}", expires),
+            Ok(Activation { expires: None, .. }) => String::from("activated"),
+            // Bundles carry the activation file the portal made, in its own format.
+            Err(_) => String::from("activated offline"),
+        },
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_format() {
+        assert!(validate("BFX-2F4K-9QZ7-ABCD").is_ok());
+        assert!(validate("short").is_err());
+        assert!(validate("BFX 2F4K 9QZ7").is_err());
+        assert!(validate("-BFX2F4K9QZ7").is_err());
+        assert!(!request("BFX-2F4K-9QZ7-ABCD", Some("web1"), None).unwrap().contains('\n'));
+        let instance = Metadata { cloud: Some(cloud::Cloud::Aws), instance_id: String::from("i-0abc"), region: String::from("eu-west-1"), instance_type: String::from("m5.large") };
+        let body = request("BFX-2F4K-9QZ7-ABCD", Some("web1"), Some(&instance)).unwrap();
+        assert!(body.contains(r#""instance":{"cloud":"aws","instance_id":"i-0abc","instance_type":"m5.large","region":"eu-west-1"}"#), "{}", body);
+        assert!(loopback_http("http://127.0.0.1:8080/activate"));
+        let script = activation_script(&["-fsS", "--", ACTIVATION_URL], Some("web1"));
+        assert!(script.contains("printf '{\"license_key\": \"%s\", \"device_id\": %s}' \"${BITFLUX_LICENSE_KEY}\" '\"web1\"' | curl -fsS -- https://api.bitflux.ai/v1/activate > /etc/bitflux/license.tmp)"));
+        assert!(script.ends_with("\nmv /etc/bitflux/license.tmp /etc/bitflux/license"));
+        assert!(!loopback_http("http://127.0.0.1.example.com/activate") && !loopback_http(ACTIVATION_URL));
+    }
+
+    #[test]
+    fn stored_activation_status() {
+        // The activation gets root's owner, only root can give it away.
+        if !crate::privsep::is_root() {
+            return;
+        }
+        let path = std::env::temp_dir().join(format!("bitflux-license-{}", std::process::id()));
+        assert_eq!(status(&path), "not activated");
+        let record = store(&path, &Activation { token: String::from("tok"), expires: Some(String::from("2027-01-01T00:00:00Z")) }).unwrap();
+        assert_eq!(record.mode, 0o600);
+        assert_eq!(status(&path), "activated, expires 2027-01-01T00:00:00Z");
+        fs::write(&path, "portal activation").unwrap();
+        assert_eq!(status(&path), "activated offline");
+        fs::remove_file(&path).unwrap();
+    }
+
+}
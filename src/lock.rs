@@ -0,0 +1,212 @@
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::fd::AsRawFd;
+use std::path::Path;
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::log::{self, Level};
+
+/// Held for the whole run by every installer invocation that changes the system, in
+/// state::STATE_DIR.
+pub const LOCK_PATH: &str = "/var/lib/bitflux-installer/lock";
+/// The lock of installers before state::STATE_DIR, held as well to keep them out.
+pub const LEGACY_LOCK_PATH: &str = "/var/run/bitflux-installer.lock";
+/// How often --wait tries the lock again.
+const RETRY: Duration = Duration::from_millis(500);
+
+/// The subcommand of this run, for the lock file.  Its arguments aren't written, they can hold
+/// the license key.
+static COMMAND: Mutex<Option<String>> = Mutex::new(None);
+
+pub fn set_command(command: &str) {
+    *COMMAND.lock().unwrap_or_else(|e| e.into_inner()) = Some(String::from(command));
+}
+
+/// Who holds the lock, written into the lock file by the holder.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Holder {
+    pub pid: u32,
+    /// Unix timestamp of when the lock was taken.
+    pub since: u64,
+    pub command: String,
+}
+
+impl Holder {
+
+    fn current() -> Holder {
+        let program = env::args().next()
+            .and_then(|p| Path::new(&p).file_name().map(|n| n.to_string_lossy().into_owned()))
+            .unwrap_or_default();
+        let command = match COMMAND.lock().unwrap_or_else(|e| e.into_inner()).as_deref() {
+            Some(command) => format!("{} {}", program, command),
+            None => program,
+        };
+        Holder { pid: std::process::id(), since: now(), command }
+    }
+
+    pub fn parse(data: &str) -> Option<Holder> {
+        let mut lines = data.lines();
+        Some(Holder {
+            pid: lines.next()?.trim().parse().ok()?,
+            since: lines.next()?.trim().parse().ok()?,
+            command: lines.next().unwrap_or("").to_string(),
+        })
+    }
+
+    pub fn format(&self) -> String {
+        format!("{}\n{}\n{}\n", self.pid, self.since, self.command)
+    }
+
+    /// Whether the holder's process is still there, or at least one with its pid.
+    pub fn alive(&self) -> bool {
+        Path::new("/proc").join(self.pid.to_string()).exists()
+    }
+
+    fn describe(&self) -> String {
+        format!("pid {} ('{}') since {} ({}s ago)", self.pid, self.command, self.since, now().saturating_sub(self.since))
+    }
+
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// An exclusive flock on the lock file, released when dropped or when the process dies.
+#[derive(Debug)]
+pub struct InstanceLock {
+    file: File,
+}
+
+impl Drop for InstanceLock {
+
+    /// Empties the file before the lock goes, a holder left in it marks a run that died.
+    fn drop(&mut self) {
+        let _ = self.file.set_len(0);
+    }
+
+}
+
+fn holder(file: &mut File) -> Option<Holder> {
+    let mut data = String::new();
+    file.seek(SeekFrom::Start(0)).ok()?;
+    file.read_to_string(&mut data).ok()?;
+    Holder::parse(&data)
+}
+
+/// Takes the installer lock at `path`.  When it is taken, fails straight away with who holds
+/// it, or with `wait` waits for them to finish, forever or up to the duration given.
+pub fn acquire_wait<P: AsRef<Path>>(path: P, wait: Option<Option<Duration>>) -> io::Result<InstanceLock> {
+    let path = path.as_ref();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut file = OpenOptions::new().read(true).write(true).create(true).truncate(false).open(path)?;
+
+    let start = Instant::now();
+    let mut waiting = false;
+    while unsafe { libc::flock(file.as_raw_fd(), libc::LOCK_EX | libc::LOCK_NB) } != 0 {
+        let err = io::Error::last_os_error();
+        if err.kind() != io::ErrorKind::WouldBlock {
+            return Err(err);
+        }
+        let holder = holder(&mut file);
+        let timed_out = match wait {
+            None => true,
+            Some(limit) => limit.is_some_and(|l| start.elapsed() >= l),
+        };
+        if timed_out {
+            let msg = match &holder {
+                // The flock outlived the process, a command it started kept the file open.
+                Some(h) if !h.alive() => format!("{} is held by a process pid {} ('{}') started, which exited {}s ago. \
+                                                  Stop what it left running, then try again.", path.display(), h.pid, h.command, now().saturating_sub(h.since)),
+                Some(h) if waiting => format!("Another installer is still running after {}s: {} has held {}.", start.elapsed().as_secs(), h.describe(), path.display()),
+                Some(h) => format!("Another installer is already running: {} has held {}, --wait waits for it.", h.describe(), path.display()),
+                None => format!("Another installer is already running, {} is locked.", path.display()),
+            };
+            return Err(io::Error::new(io::ErrorKind::WouldBlock, msg));
+        }
+        if !waiting {
+            let who = holder.map_or(String::from("another installer"), |h| h.describe());
+            log::log(Level::Info, &format!("Waiting for {} to finish...", who));
+            waiting = true;
+        }
+        thread::sleep(RETRY);
+    }
+
+    // Ours now, whoever is still in the file didn't get to take themselves out.
+    if let Some(stale) = holder(&mut file).filter(|h| !h.alive()) {
+        log::log(Level::Warn, &format!("Warning: the installer run by pid {} ('{}') {}s ago didn't finish, \
+                                        check `installer status` for what it left half done.", stale.pid, stale.command, now().saturating_sub(stale.since)));
+    }
+    file.set_len(0)?;
+    file.seek(SeekFrom::Start(0))?;
+    file.write_all(Holder::current().format().as_bytes())?;
+    Ok(InstanceLock { file })
+}
+
+/// Takes the installer lock at `path`, failing straight away with who holds it when it is taken.
+pub fn acquire<P: AsRef<Path>>(path: P) -> io::Result<InstanceLock> {
+    acquire_wait(path, None)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn second_instance_sees_holder() {
+        let path = std::env::temp_dir().join(format!("bitflux-lock-{}", std::process::id()));
+        let lock = acquire(&path).unwrap();
+
+        let err = acquire(&path).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+        assert!(err.to_string().contains(&format!("pid {}", std::process::id())));
+
+        drop(lock);
+        let lock = acquire(&path).unwrap();
+        drop(lock);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn wait_for_the_holder() {
+        let path = std::env::temp_dir().join(format!("bitflux-lock-wait-{}", std::process::id()));
+        let lock = acquire(&path).unwrap();
+        let err = acquire_wait(&path, Some(Some(Duration::from_millis(600)))).unwrap_err();
+        assert!(err.to_string().starts_with("Another installer is still running after"));
+        let held = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(300));
+            drop(lock);
+        });
+        let lock = acquire_wait(&path, Some(None)).unwrap();
+        held.join().unwrap();
+        drop(lock);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+
+        // A run that died leaves itself in the file, the next one takes over.
+        fs::write(&path, Holder { pid: u32::MAX, since: 0, command: String::from("installer install") }.format()).unwrap();
+        let lock = acquire(&path).unwrap();
+        assert_eq!(Holder::parse(&fs::read_to_string(&path).unwrap()).unwrap().pid, std::process::id());
+        drop(lock);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn holder_roundtrip() {
+        let h = Holder { pid: 42, since: 1700000000, command: String::from("installer upgrade") };
+        assert_eq!(Holder::parse(&h.format()), Some(h));
+        assert_eq!(Holder::parse("garbage"), None);
+
+        // The program and subcommand only, never the arguments.
+        set_command("install");
+        let current = Holder::current();
+        assert_eq!(current.command.split(' ').nth(1), Some("install"));
+        assert_eq!(current.command.split(' ').count(), 2);
+    }
+
+}
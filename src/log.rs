@@ -0,0 +1,198 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::events::{self, Event};
+use crate::i18n;
+use crate::runcmd::{sensitive, RunCmdOutput};
+use crate::spinner;
+
+/// Every command the installer runs and what it printed, kept for support after the fact.
+/// Root only, command output can hold tokens.
+pub const LOG_PATH: &str = "/var/log/bitflux-install.log";
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+}
+
+impl Level {
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Level::Error => "ERROR",
+            Level::Warn => "WARN",
+            Level::Info => "INFO",
+            Level::Debug => "DEBUG",
+        }
+    }
+
+}
+
+/// Most detailed level shown on the console, everything goes to the file.
+static CONSOLE: AtomicU8 = AtomicU8::new(Level::Info as u8);
+static FILE: Mutex<Option<File>> = Mutex::new(None);
+/// Every warning of the run, for the summary of --quiet.
+static WARNINGS: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+pub fn set_level(level: Level) {
+    CONSOLE.store(level as u8, Ordering::Relaxed);
+}
+
+/// Appends the log to `path` from now on.
+pub fn open<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    let path = path.as_ref();
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let file = OpenOptions::new().create(true).append(true).mode(0o600).open(path)?;
+    *FILE.lock().unwrap_or_else(|e| e.into_inner()) = Some(file);
+    Ok(())
+}
+
+/// The proleptic Gregorian date `days` after 1970-01-01, from Howard Hinnant's
+/// chrono-compatible algorithms like days_from_civil().
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let doe = days.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    (yoe + era * 400 + i64::from(month <= 2), month, day)
+}
+
+/// Days since 1970-01-01 of a proleptic Gregorian date, the inverse of civil_from_days().
+pub fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let y = if month <= 2 { year - 1 } else { year };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let doy = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    era * 146097 + yoe * 365 + yoe / 4 - yoe / 100 + doy - 719468
+}
+
+/// `secs` since the epoch as an RFC 3339 UTC time.
+pub fn timestamp(secs: u64) -> String {
+    let (year, month, day) = civil_from_days((secs / 86400) as i64);
+    let time = secs % 86400;
+    format!("{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z", year, month, day, time / 3600, time / 60 % 60, time % 60)
+}
+
+/// Writes `message` to the log file only, every line stamped with the time and `level`.
+pub fn file(level: Level, message: &str) {
+    let message = sensitive::mask(message);
+    let mut file = FILE.lock().unwrap_or_else(|e| e.into_inner());
+    let Some(file) = file.as_mut() else {
+        return;
+    };
+    let now = timestamp(SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0));
+    let mut entry = String::new();
+    for line in message.lines() {
+        entry.push_str(&format!("{} {:5} {}\n", now, level.name(), line));
+    }
+    let _ = file.write_all(entry.as_bytes());
+}
+
+/// Prints `message` when the console level shows `level`, warnings and errors to stderr, in
+/// the language set.  The file keeps it in English, for support.
+pub fn console(level: Level, message: &str) {
+    let message = &sensitive::mask(message);
+    match level {
+        Level::Warn => {
+            events::emit(Event::Warning { message });
+            WARNINGS.lock().unwrap_or_else(|e| e.into_inner()).push(String::from(message));
+        }
+        Level::Error => events::emit(Event::Error { message }),
+        _ => {}
+    }
+    if level as u8 <= CONSOLE.load(Ordering::Relaxed) {
+        let message = i18n::tr(message);
+        let _held = spinner::hold();
+        match level {
+            Level::Error | Level::Warn => eprintln!("{}", message),
+            _ => println!("{}", message),
+        }
+    }
+}
+
+/// The warnings console() was given so far, shown or not.
+pub fn warnings() -> Vec<String> {
+    WARNINGS.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// Logs `message` to the file and the console.
+pub fn log(level: Level, message: &str) {
+    console(level, message);
+    file(level, message);
+}
+
+/// The log entry of a finished command.  Output that went straight to the terminal, or that
+/// may echo a secret fed on stdin, isn't in `out` and is left out.
+pub fn command(out: &RunCmdOutput, duration: Duration, output: bool) -> String {
+    let mut entry = format!("cmd: {}\ncwd: {}\nexitcode: {} after {:.2?}", out.cmd, out.cwd.display(), out.exitcode, duration);
+    if let Some(usage) = out.usage {
+        entry.push_str(&format!(", {:.2?} CPU, {} KiB peak RSS", usage.cpu, usage.max_rss_kb));
+    }
+    entry.push('\n');
+    if output {
+        for (name, data) in [("stdout", &out.stdout), ("stderr", &out.stderr)] {
+            if !data.is_empty() {
+                entry.push_str(&format!("{}:\n", name));
+                for line in data.lines() {
+                    entry.push_str(&format!("  {}\n", line));
+                }
+            }
+        }
+    }
+    entry
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn timestamps() {
+        assert_eq!(timestamp(0), "1970-01-01T00:00:00Z");
+        assert_eq!(timestamp(951782400), "2000-02-29T00:00:00Z");
+        assert_eq!(timestamp(1791980107), "2026-10-14T12:15:07Z");
+        assert_eq!(days_from_civil(2000, 2, 29), 951782400 / 86400);
+        assert_eq!(civil_from_days(days_from_civil(2026, 10, 14)), (2026, 10, 14));
+    }
+
+    #[test]
+    fn command_entry() {
+        let out = RunCmdOutput {
+            cmd: String::from("apt-get install -y bitfluxcollector"),
+            stdout: String::from("Reading package lists...\nDone\n"),
+            stderr: String::new(),
+            exitcode: 0,
+            cwd: std::path::PathBuf::from("/"),
+            attempts: Vec::new(),
+            stdout_truncated: 0,
+            stderr_truncated: 0,
+            duration: Duration::from_millis(1500),
+            usage: None,
+            argv: None,
+            shell: None,
+            root: true,
+            env: Vec::new(),
+            secret: false,
+        };
+        let entry = command(&out, Duration::from_millis(1500), true);
+        assert!(entry.starts_with("cmd: apt-get install -y bitfluxcollector\ncwd: /\nexitcode: 0 after 1.50s\n"));
+        assert!(entry.ends_with("stdout:\n  Reading package lists...\n  Done\n"));
+        assert!(!command(&out, Duration::ZERO, false).contains("stdout"));
+    }
+
+}
@@ -0,0 +1,161 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::journal::Journal;
+use crate::kernel::running_kernel;
+use crate::log::{self, Level};
+use crate::perms::FileKind;
+use crate::runcmd::{which, RunCmd};
+use crate::selinux;
+use crate::template;
+
+/// "Y" while AppArmor is enabled.
+pub const APPARMOR_ENABLED: &str = "/sys/module/apparmor/parameters/enabled";
+/// The profiles AppArmor has loaded, one "name (mode)" per line.
+pub const APPARMOR_PROFILES: &str = "/sys/kernel/security/apparmor/profiles";
+/// The agent's profile, named after the binary the way apparmor.d names them.
+pub const APPARMOR_PROFILE: &str = "/etc/apparmor.d/opt.bitflux.bin.bitfluxcollector";
+pub const AGENT_BINARY: &str = "/opt/bitflux/bin/bitfluxcollector";
+/// The SELinux policy module, from templates/bitflux.cil.
+pub const POLICY_MODULE: &str = "bitflux";
+pub const POLICY_PATH: &str = "/var/lib/bitflux/selinux/bitflux.cil";
+
+/// How the override is spelled in the errors.
+const OVERRIDE: &str = "--skip-mac-policy (skip_mac_policy = true in the answers file)";
+
+/// The mandatory access control confining services on the host.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Mac {
+    SELinux,
+    AppArmor,
+    None,
+}
+
+impl Mac {
+
+    /// The one confining services on this host.  SELinux in permissive mode denies nothing and
+    /// counts as none.
+    pub fn detect() -> Mac {
+        if selinux::is_enforcing() {
+            Mac::SELinux
+        } else if fs::read_to_string(APPARMOR_ENABLED).is_ok_and(|v| v.trim() == "Y") {
+            Mac::AppArmor
+        } else {
+            Mac::None
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Mac::SELinux => "SELinux",
+            Mac::AppArmor => "AppArmor",
+            Mac::None => "none",
+        }
+    }
+
+    /// True when what install() puts in place for this one is there.
+    pub fn installed(self) -> bool {
+        match self {
+            Mac::SELinux => policy_loaded(),
+            Mac::AppArmor => {
+                let current = fs::read_to_string(APPARMOR_PROFILE).ok();
+                current.is_some() && current == apparmor_profile().ok() && profile_loaded()
+            }
+            Mac::None => true,
+        }
+    }
+
+    /// Loads the bitflux SELinux policy module and relabels the running kernel's modules, or
+    /// the agent's AppArmor profile.  The files written go in `journal` first.  Fails naming
+    /// the override when the tools to load them are missing.
+    pub fn install(self, journal: &mut Journal) -> io::Result<()> {
+        match self {
+            Mac::SELinux => {
+                require("semodule", "policycoreutils", "the bitflux SELinux policy")?;
+                journal.file(POLICY_PATH)?;
+                template::write(POLICY_PATH, template::get("bitflux.cil")?, FileKind::Unit)?;
+                load("semodule", &["-i", POLICY_PATH])?;
+                // Modules DKMS built are copied from /var/lib/dkms with its label.
+                let modules = Path::new("/lib/modules").join(running_kernel()?);
+                load("restorecon", &["-R", &modules.to_string_lossy()])
+            }
+            Mac::AppArmor => {
+                require("apparmor_parser", "apparmor", "the agent's AppArmor profile")?;
+                journal.file(APPARMOR_PROFILE)?;
+                template::write(APPARMOR_PROFILE, &apparmor_profile()?, FileKind::Unit)?;
+                load("apparmor_parser", &["-r", APPARMOR_PROFILE])
+            }
+            Mac::None => Ok(()),
+        }
+    }
+
+    /// Unloads what install() loaded, true if there was something.  The files go with the
+    /// rest of the install.
+    pub fn remove(self) -> io::Result<bool> {
+        match self {
+            Mac::SELinux if policy_loaded() => load("semodule", &["-r", POLICY_MODULE]).map(|_| true),
+            Mac::AppArmor if Path::new(APPARMOR_PROFILE).exists() => load("apparmor_parser", &["-R", APPARMOR_PROFILE]).map(|_| true),
+            _ => Ok(false),
+        }
+    }
+
+}
+
+/// The agent's AppArmor profile.
+pub fn apparmor_profile() -> io::Result<String> {
+    template::render_named("apparmor-bitfluxcollector", &[("binary", AGENT_BINARY), ("agent_dir", "/opt/bitflux")])
+}
+
+fn policy_loaded() -> bool {
+    let out = RunCmd::args("semodule", &["-l"]).execute_output();
+    out.exitcode == 0 && out.stdout.lines().any(|l| l.split_whitespace().next() == Some(POLICY_MODULE))
+}
+
+fn profile_loaded() -> bool {
+    fs::read_to_string(APPARMOR_PROFILES).is_ok_and(|p| p.lines().any(|l| l.starts_with("bitfluxcollector ")))
+}
+
+fn require(program: &str, package: &str, what: &str) -> io::Result<()> {
+    match which(program) {
+        Some(_) => Ok(()),
+        None => Err(io::Error::other(format!(
+            "{} is missing, so {} can't be installed. Install {}, or pass {} to go on without it.",
+            program, what, package, OVERRIDE))),
+    }
+}
+
+fn load(program: &str, args: &[&str]) -> io::Result<()> {
+    let out = RunCmd::args(program, args).execute_output();
+    match out.exitcode {
+        0 => Ok(()),
+        _ => Err(io::Error::other(format!("{} {} failed: {} Pass {} to go on without it.", program, args.join(" "), out.stderr.trim(), OVERRIDE))),
+    }
+}
+
+/// Warns that `mac` stays as it is, for an install with the override.
+pub fn warn_skipped(mac: Mac) {
+    if mac == Mac::None {
+        return;
+    }
+    log::log(Level::Warn, &format!(
+        "WARNING: --skip-mac-policy, {} is enforcing and the bitflux policy is NOT installed. \
+         The agent may be denied access to /proc and swaphints may fail to load, check the audit log.",
+        mac.name()));
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apparmor_profile_for_the_agent() {
+        let profile = apparmor_profile().unwrap();
+        assert!(profile.contains("profile bitfluxcollector /opt/bitflux/bin/bitfluxcollector {\n"));
+        assert!(profile.contains("  /opt/bitflux/** rwk,\n"));
+        assert!(!profile.contains("{{"));
+        assert!(template::get("bitflux.cil").unwrap().contains("(allow kmod_t var_lib_t (system (module_load)))"));
+    }
+
+}
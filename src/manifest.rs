@@ -0,0 +1,188 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::checksum::sha256_file;
+
+/// Where the installer keeps the list of files it has put on the system.
+pub const MANIFEST_PATH: &str = "/var/lib/bitflux/manifest.json";
+
+/// A single file created by the installer.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ManifestEntry {
+    pub path: String,
+    pub sha256: String,
+    /// Name of the install step that created the file.
+    pub step: String,
+}
+
+/// Tracks every file the installer creates so uninstall removes exactly those files.
+///
+/// # Examples
+///
+/// ```no_run
+/// use installer::manifest::{Manifest, MANIFEST_PATH};
+///
+/// let mut manifest = Manifest::load(MANIFEST_PATH)?;
+/// manifest.record("/etc/modules-load.d/swaphints.conf", "kernel")?;
+/// manifest.save(MANIFEST_PATH)?;
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Manifest {
+    pub entries: Vec<ManifestEntry>,
+}
+
+/// What happened to each tracked file during removal.
+#[derive(Debug, Default, PartialEq)]
+pub struct RemovalReport {
+    pub removed: Vec<String>,
+    /// Files whose contents changed since install, left in place.
+    pub modified: Vec<String>,
+    /// Files that were already gone.
+    pub missing: Vec<String>,
+}
+
+impl Manifest {
+
+    /// Reads the manifest, a missing file is treated as an empty manifest.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Manifest> {
+        match fs::read_to_string(path) {
+            Ok(data) => serde_json::from_str(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Manifest::default()),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Writes the manifest atomically so an interrupted install never leaves it half written.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let data = serde_json::to_string_pretty(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let tmp = path.with_extension("tmp");
+        fs::write(&tmp, data)?;
+        fs::rename(&tmp, path)
+    }
+
+    /// Records a file that `step` just wrote.  Re-recording a path updates its hash.
+    pub fn record<P: AsRef<Path>>(&mut self, path: P, step: &str) -> io::Result<()> {
+        let path = path.as_ref();
+        let entry = ManifestEntry {
+            path: path.to_string_lossy().into_owned(),
+            sha256: sha256_file(path)?,
+            step: String::from(step),
+        };
+
+        match self.entries.iter_mut().find(|e| e.path == entry.path) {
+            Some(existing) => *existing = entry,
+            None => self.entries.push(entry),
+        }
+        Ok(())
+    }
+
+    /// Removes the tracked files.  Files the user modified since install are skipped unless `force`.
+    /// Skipped files stay in the manifest so a later forced run can still find them.
+    pub fn remove_files(&mut self, force: bool) -> RemovalReport {
+        let mut report = RemovalReport::default();
+        let mut kept = Vec::new();
+
+        for entry in self.entries.drain(..) {
+            let current = match sha256_file(&entry.path) {
+                Ok(hash) => hash,
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                    report.missing.push(entry.path);
+                    continue;
+                }
+                Err(_) => String::from(""),
+            };
+
+            if current != entry.sha256 && !force {
+                report.modified.push(entry.path.clone());
+                kept.push(entry);
+                continue;
+            }
+
+            match fs::remove_file(&entry.path) {
+                Ok(()) => report.removed.push(entry.path),
+                Err(_) => kept.push(entry),
+            }
+        }
+
+        self.entries = kept;
+        report
+    }
+
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn scratch(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("bitflux-manifest-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    #[test]
+    fn save_load_roundtrip() {
+        let dir = scratch("roundtrip");
+        let file = dir.join("a.conf");
+        fs::write(&file, "a").unwrap();
+
+        let mut manifest = Manifest::default();
+        manifest.record(&file, "config").unwrap();
+        manifest.record(&file, "config").unwrap();
+        manifest.save(dir.join("manifest.json")).unwrap();
+
+        let loaded = Manifest::load(dir.join("manifest.json")).unwrap();
+        assert_eq!(loaded, manifest);
+        assert_eq!(loaded.entries.len(), 1);
+        assert_eq!(loaded.entries[0].step, "config");
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn load_missing_is_empty() {
+        let manifest = Manifest::load("/nonexistent/bitflux/manifest.json").unwrap();
+        assert!(manifest.entries.is_empty());
+    }
+
+    #[test]
+    fn remove_skips_modified() {
+        let dir = scratch("modified");
+        let same = dir.join("same.conf");
+        let changed = dir.join("changed.conf");
+        let gone = dir.join("gone.conf");
+        for f in [&same, &changed, &gone] {
+            fs::write(f, "original").unwrap();
+        }
+
+        let mut manifest = Manifest::default();
+        for f in [&same, &changed, &gone] {
+            manifest.record(f, "config").unwrap();
+        }
+        fs::write(&changed, "edited by user").unwrap();
+        fs::remove_file(&gone).unwrap();
+
+        let report = manifest.remove_files(false);
+        assert_eq!(report.removed, vec![same.to_string_lossy()]);
+        assert_eq!(report.modified, vec![changed.to_string_lossy()]);
+        assert_eq!(report.missing, vec![gone.to_string_lossy()]);
+        assert!(changed.exists());
+        assert_eq!(manifest.entries.len(), 1);
+
+        let report = manifest.remove_files(true);
+        assert_eq!(report.removed, vec![changed.to_string_lossy()]);
+        assert!(manifest.entries.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+}
@@ -0,0 +1,151 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::engine::StepRecord;
+use crate::notify::{Outcome, Payload};
+use crate::profiling::Sample;
+use crate::spinner;
+
+/// The `[metrics]` section of installer.toml.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Metrics {
+    /// File in node_exporter's textfile collector directory to write the metrics of the last
+    /// install or upgrade to, like /var/lib/node_exporter/textfile_collector/bitflux_installer.prom.
+    pub textfile: Option<PathBuf>,
+}
+
+impl Metrics {
+
+    /// These metrics written to `textfile` instead when one is given, for --metrics-textfile.
+    pub fn or_textfile(self, textfile: Option<PathBuf>) -> Metrics {
+        Metrics { textfile: textfile.or(self.textfile) }
+    }
+
+}
+
+/// Escapes a label value for the Prometheus text format.
+fn label(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+fn metric(out: &mut String, name: &str, help: &str, samples: &[(String, f64)]) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} gauge", name);
+    for (labels, value) in samples {
+        let _ = writeln!(out, "{}{{{}}} {}", name, labels, value);
+    }
+}
+
+fn outcome(outcome: spinner::Outcome) -> &'static str {
+    match outcome {
+        spinner::Outcome::Done => "done",
+        spinner::Outcome::Skipped => "skipped",
+        spinner::Outcome::Failed => "failed",
+        spinner::Outcome::Interrupted => "interrupted",
+    }
+}
+
+/// The run in Prometheus text format.  `steps` are the engine's records of the run, each gets
+/// its outcome.  `samples` are the profiling samples of the run, the steps directly below its
+/// root step get a duration each.
+pub fn render(payload: &Payload, steps: &[StepRecord], samples: &[Sample]) -> String {
+    let command = format!("command=\"{}\"", label(&payload.command));
+    let mut durations: BTreeMap<&str, f64> = BTreeMap::new();
+    for sample in samples.iter().filter(|s| s.path.len() == 2 && !s.path[1].starts_with("$ ")) {
+        *durations.entry(&sample.path[1]).or_default() += sample.wall.as_secs_f64();
+    }
+    let (success, result) = match payload.outcome {
+        Outcome::Succeeded => (1.0, "succeeded"),
+        Outcome::Failed => (0.0, "failed"),
+    };
+
+    let mut out = String::new();
+    metric(&mut out, "bitflux_installer_last_run_timestamp_seconds", "When the last run finished.", &[(command.clone(), payload.finished_at as f64)]);
+    metric(&mut out, "bitflux_installer_last_run_success", "1 if the last run succeeded, 0 if it failed.", &[(command.clone(), success)]);
+    metric(&mut out, "bitflux_installer_last_run_duration_seconds", "Wall time of the last run.", &[(command.clone(), payload.duration_secs)]);
+    metric(&mut out, "bitflux_installer_last_run_exit_code", "Exit code of the last run.", &[(command.clone(), payload.error_code as f64)]);
+    metric(&mut out, "bitflux_install_duration_seconds", "Wall time of the last run, by how it ended.", &[(format!("{},outcome=\"{}\"", command, result), payload.duration_secs)]);
+    let durations: Vec<(String, f64)> = durations.iter().map(|(step, secs)| (format!("{},step=\"{}\"", command, label(step)), *secs)).collect();
+    metric(&mut out, "bitflux_installer_step_duration_seconds", "Wall time of each step of the last run.", &durations);
+    let outcomes: Vec<(String, f64)> = steps.iter()
+        .map(|s| (format!("{},step=\"{}\",outcome=\"{}\"", command, label(&s.name), outcome(s.outcome)), 1.0))
+        .collect();
+    metric(&mut out, "bitflux_installer_step_outcome", "How each step of the last run ended: done, skipped, failed or interrupted.", &outcomes);
+    let versions: Vec<(String, f64)> = payload.versions.iter()
+        .map(|(name, version)| (format!("component=\"{}\",version=\"{}\"", label(name), label(version)), 1.0))
+        .collect();
+    metric(&mut out, "bitflux_installer_version_info", "Versions of the installer and the installed bitflux packages.", &versions);
+    out
+}
+
+/// Replaces `path` with the metrics of the run, atomically so node_exporter never reads half
+/// a file.
+pub fn write<P: AsRef<Path>>(path: P, payload: &Payload, steps: &[StepRecord], samples: &[Sample]) -> io::Result<()> {
+    let path = path.as_ref();
+    let tmp = path.with_extension("prom.tmp");
+    fs::write(&tmp, render(payload, steps, samples))
+        .and_then(|_| fs::rename(&tmp, path))
+        .map_err(|e| io::Error::new(e.kind(), format!("Can't write the metrics to {}: {}", path.display(), e)))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn textfile_format() {
+        let payload = Payload {
+            host: String::from("web1"),
+            command: String::from("install"),
+            outcome: Outcome::Succeeded,
+            duration_secs: 61.5,
+            finished_at: 1700000000,
+            versions: BTreeMap::from([(String::from("installer"), String::from("0.1.0"))]),
+            error_code: 0,
+            error: None,
+        };
+        let sample = |path: &[&str], secs| Sample {
+            path: path.iter().map(|p| p.to_string()).collect(),
+            wall: Duration::from_secs(secs),
+            cpu: Duration::ZERO,
+            rx_bytes: 0,
+        };
+        let samples = [
+            sample(&["installer", "kernel", "$ apt-get install"], 30),
+            sample(&["installer", "kernel"], 40),
+            sample(&["installer", "$ uname"], 1),
+            sample(&["installer"], 61),
+        ];
+        let step = |name: &str, outcome| StepRecord {
+            name: String::from(name),
+            title: String::new(),
+            outcome,
+            changed: false,
+            duration_secs: 0.0,
+            cpu_secs: 0.0,
+            max_rss_kb: 0,
+            error: None,
+        };
+        let steps = [step("repo", spinner::Outcome::Skipped), step("kernel", spinner::Outcome::Done)];
+        let text = render(&payload, &steps, &samples);
+        assert!(text.contains("# TYPE bitflux_installer_last_run_success gauge\nbitflux_installer_last_run_success{command=\"install\"} 1\n"));
+        assert!(text.contains("bitflux_installer_last_run_timestamp_seconds{command=\"install\"} 1700000000\n"));
+        assert!(text.contains("bitflux_installer_step_duration_seconds{command=\"install\",step=\"kernel\"} 40\n"));
+        assert!(!text.contains("uname"));
+        assert!(text.contains("bitflux_install_duration_seconds{command=\"install\",outcome=\"succeeded\"} 61.5\n"));
+        assert!(text.contains("bitflux_installer_step_outcome{command=\"install\",step=\"repo\",outcome=\"skipped\"} 1\n"));
+        assert!(text.contains("bitflux_installer_step_outcome{command=\"install\",step=\"kernel\",outcome=\"done\"} 1\n"));
+        assert!(text.contains("bitflux_installer_version_info{component=\"installer\",version=\"0.1.0\"} 1\n"));
+        assert_eq!(label("a\"b\\"), "a\\\"b\\\\");
+    }
+
+}
@@ -0,0 +1,136 @@
+use std::fs;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+
+use crate::detect;
+use crate::log::{self, Level};
+use crate::prompt::Prompt;
+use crate::runcmd::{self, which, RunCmd};
+
+/// The key DKMS signs modules with, the one dkms 3 generates and uses by default.
+pub const MOK_KEY: &str = "/var/lib/dkms/mok.key";
+/// Its DER certificate, the one enrolled as a Machine Owner Key.
+pub const MOK_CERT: &str = "/var/lib/dkms/mok.pub";
+
+/// Where the signing key stands with shim.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum KeyState {
+    Enrolled,
+    /// Imported, MokManager enrolls it on the next boot once the password is entered there.
+    Pending,
+    NotEnrolled,
+}
+
+/// What to tell someone whose install queued the key for enrollment.
+pub const ENROLL_HELP: &str = "\
+Reboot now. Before the system starts the blue MokManager screen asks to \"Perform MOK management\":
+choose \"Enroll MOK\", \"Continue\", \"Yes\", type the one-time password and reboot.
+swaphints loads from then on, nothing needs to be run again.";
+
+/// True when Secure Boot is on, from efivars, or mokutil where efivarfs isn't mounted.
+pub fn enabled() -> bool {
+    match detect::secure_boot() {
+        Some(on) => on,
+        None if which("mokutil").is_some() => sb_state_enabled(&RunCmd::args("mokutil", &["--sb-state"]).execute_output().stdout),
+        None => false,
+    }
+}
+
+/// True if `mokutil --sb-state` output says Secure Boot is on.
+pub fn sb_state_enabled(output: &str) -> bool {
+    output.lines().any(|l| l.trim() == "SecureBoot enabled")
+}
+
+/// The state `mokutil --test-key` reports for a certificate.
+pub fn parse_test_key(output: &str) -> KeyState {
+    if output.contains("is already enrolled") {
+        KeyState::Enrolled
+    } else if output.contains("already in the enrollment request") {
+        KeyState::Pending
+    } else {
+        KeyState::NotEnrolled
+    }
+}
+
+pub fn key_state() -> KeyState {
+    let out = RunCmd::args("mokutil", &["--test-key", MOK_CERT]).execute_output();
+    parse_test_key(&format!("{}{}", out.stdout, out.stderr))
+}
+
+/// Makes the DKMS signing key unless there is one already.
+pub fn ensure_key() -> io::Result<()> {
+    if Path::new(MOK_CERT).exists() && Path::new(MOK_KEY).exists() {
+        return Ok(());
+    }
+    if let Some(parent) = Path::new(MOK_KEY).parent() {
+        if !runcmd::dry_run(&format!("create {}", parent.display())) {
+            fs::create_dir_all(parent)?;
+        }
+    }
+    RunCmd::args("openssl", &[
+        "req", "-new", "-x509", "-nodes", "-days", "36500", "-newkey", "rsa:2048",
+        "-subj", "/CN=bitflux DKMS module signing key/",
+        "-keyout", MOK_KEY, "-outform", "DER", "-out", MOK_CERT,
+    ]).try_execute().map_err(|e| io::Error::other(format!("Failed to make the module signing key: {}", e)))?;
+    Ok(())
+}
+
+/// Signs module `name` of kernel `version` with the DKMS key, unless it's signed already.
+/// Older dkms only signs when told to, this covers modules it built unsigned.
+pub fn sign(name: &str, version: &str) -> io::Result<()> {
+    let signer = RunCmd::args("modinfo", &["-k", version, "-F", "signer", name]).execute_output();
+    if !signer.stdout.trim().is_empty() {
+        return Ok(());
+    }
+    let module = RunCmd::args("modinfo", &["-k", version, "-n", name]).execute_output().stdout.trim().to_string();
+    if module.ends_with(".xz") || module.ends_with(".zst") || module.ends_with(".gz") {
+        return Err(io::Error::other(format!("{} is compressed and unsigned, rebuild it with dkms after setting mok_signing_key.", module)));
+    }
+    let sign_file = format!("/lib/modules/{}/build/scripts/sign-file", version);
+    RunCmd::args(&sign_file, &["sha256", MOK_KEY, MOK_CERT, &module]).try_execute()
+        .map_err(|e| io::Error::other(format!("Failed to sign {}: {}", module, e)))?;
+    Ok(())
+}
+
+/// Why an install without a terminal stops when the signing key isn't enrolled.
+pub fn not_enrolled() -> io::Error {
+    io::Error::other(format!(
+        "Secure Boot is on and the module signing key isn't enrolled. Run `mokutil --import {}`, \
+         reboot and enroll it in MokManager, then install again.", MOK_CERT
+    ))
+}
+
+/// Queues the signing key for enrollment at the next boot, asking for the one-time password
+/// MokManager wants then.
+pub fn enroll<R: BufRead, W: Write>(prompt: &mut Prompt<R, W>) -> io::Result<()> {
+    if runcmd::dry_run(&format!("ask for a password and mokutil --import {}", MOK_CERT)) {
+        return Ok(());
+    }
+    log::log(Level::Warn, "Secure Boot is on, the swaphints module only loads once its signing key is enrolled.");
+    let password = prompt.input_valid("One-time password to enroll the key with at the next boot:", None, |p| {
+        match p.chars().count() {
+            8..=256 => Ok(()),
+            _ => Err(String::from("The password needs 8 to 256 characters.")),
+        }
+    })?;
+    // mokutil asks for the password twice.
+    RunCmd::args("mokutil", &["--import", MOK_CERT]).secret_stdin(&format!("{}\n{}\n", password, password)).try_execute()
+        .map_err(|e| io::Error::other(format!("Failed to queue the key for enrollment: {}", e)))?;
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mokutil_output() {
+        assert!(sb_state_enabled("SecureBoot enabled\n"));
+        assert!(!sb_state_enabled("SecureBoot disabled\nPlatform is in Setup Mode\n"));
+        assert_eq!(parse_test_key("/var/lib/dkms/mok.pub is already enrolled\n"), KeyState::Enrolled);
+        assert_eq!(parse_test_key("/var/lib/dkms/mok.pub is already in the enrollment request\n"), KeyState::Pending);
+        assert_eq!(parse_test_key("/var/lib/dkms/mok.pub is not enrolled\n"), KeyState::NotEnrolled);
+    }
+
+}
@@ -0,0 +1,314 @@
+use std::collections::BTreeMap;
+use std::ffi::CStr;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::checksum::hmac_sha256_hex;
+use crate::offline;
+use crate::privsep::{unprivileged, unprivileged_cmd};
+use crate::receipt::{Receipt, RECEIPT_DIR};
+use crate::tls;
+use crate::workspace::{Workspace, WORKSPACE_ROOT};
+
+/// Header carrying `sha256=<hex HMAC-SHA256 of the body>` when a secret is configured.
+pub const SIGNATURE_HEADER: &str = "X-Bitflux-Signature";
+/// Delays before the retries, the webhook is given up on after the last one.
+const RETRY_DELAYS: &[Duration] = &[Duration::from_secs(2), Duration::from_secs(8), Duration::from_secs(30)];
+
+/// Where to send the result of an install or upgrade.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Webhook {
+    pub url: String,
+    /// File holding the HMAC key the payload is signed with.
+    pub secret_file: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Succeeded,
+    Failed,
+}
+
+/// The JSON body POSTed to the webhook.
+#[derive(Clone, Debug, Serialize)]
+pub struct Payload {
+    pub host: String,
+    /// install or upgrade.
+    pub command: String,
+    pub outcome: Outcome,
+    pub duration_secs: f64,
+    /// Unix timestamp, lets the receiver reject replayed notifications.
+    pub finished_at: u64,
+    /// The installer's and, once installed, every bitflux package's version.
+    pub versions: BTreeMap<String, String>,
+    /// The installer's exit code, 0 on success.
+    pub error_code: i32,
+    pub error: Option<String>,
+}
+
+fn hostname() -> String {
+    let mut buf = [0 as libc::c_char; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr(), buf.len()) } != 0 {
+        return String::from("unknown");
+    }
+    buf[buf.len() - 1] = 0;
+    unsafe { CStr::from_ptr(buf.as_ptr()) }.to_string_lossy().into_owned()
+}
+
+impl Payload {
+
+    pub fn new(command: &str, duration: Duration, result: &io::Result<()>, error_code: i32) -> Payload {
+        let mut versions = BTreeMap::from([(String::from("installer"), String::from(env!("CARGO_PKG_VERSION")))]);
+        if let Ok(receipt) = Receipt::load_verified(RECEIPT_DIR) {
+            versions.extend(receipt.packages.into_iter().map(|p| (p.name, p.version)));
+        }
+        Payload {
+            host: hostname(),
+            command: String::from(command),
+            outcome: if result.is_ok() { Outcome::Succeeded } else { Outcome::Failed },
+            duration_secs: duration.as_secs_f64(),
+            finished_at: SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0),
+            versions,
+            error_code,
+            error: result.as_ref().err().map(|e| e.to_string()),
+        }
+    }
+
+}
+
+/// curl arguments POSTing the body from stdin to `url`, signed with `signature` if there is one.
+fn curl_args(tls: &[String], url: &str, signature: Option<&str>) -> Vec<String> {
+    let mut args: Vec<String> = ["-fsS", "--max-time", "15", "-X", "POST", "-H", "Content-Type: application/json"]
+        .iter().map(|a| String::from(*a)).collect();
+    if let Some(signature) = signature {
+        args.push(String::from("-H"));
+        args.push(format!("{}: sha256={}", SIGNATURE_HEADER, signature));
+    }
+    args.extend(tls.iter().cloned());
+    args.extend([String::from("--data-binary"), String::from("@-"), String::from("--"), String::from(url)]);
+    args
+}
+
+/// Runs curl with `stdin` until it succeeds, retrying with backoff.
+fn deliver(url: &str, args: &[String], stdin: &str) -> io::Result<()> {
+    offline::guard(url)?;
+    let args: Vec<&str> = args.iter().map(String::as_str).collect();
+    let mut delays = RETRY_DELAYS.iter();
+    loop {
+        let out = unprivileged_cmd("curl", &args).secret_stdin(stdin).execute_output();
+        tls::log(url, &out.stderr);
+        if out.exitcode == 0 {
+            return Ok(());
+        }
+        match delays.next() {
+            Some(delay) => thread::sleep(*delay),
+            None => return Err(io::Error::other(format!("Failed to notify '{}': {}", url, out.stderr.trim()))),
+        }
+    }
+}
+
+fn read_secret(path: &Path, what: &str) -> io::Result<String> {
+    let secret = fs::read_to_string(path)
+        .map_err(|e| io::Error::new(e.kind(), format!("Can't read the {} {}: {}", what, path.display(), e)))?;
+    Ok(String::from(secret.trim_end()))
+}
+
+/// POSTs `payload` to the webhook, retrying with backoff when it can't be delivered.
+pub fn send(webhook: &Webhook, payload: &Payload) -> io::Result<()> {
+    let body = serde_json::to_string(payload).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let signature = match &webhook.secret_file {
+        Some(path) => Some(hmac_sha256_hex(read_secret(path, "webhook secret")?.as_bytes(), body.as_bytes())),
+        None => None,
+    };
+    deliver(&webhook.url, &curl_args(&tls::hardened_curl_args(), &webhook.url, signature.as_deref()), &body)
+}
+
+/// A Slack incoming webhook.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Slack {
+    pub webhook_url: String,
+}
+
+/// Mail through an SMTP relay.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Email {
+    /// `smtp://host:587` upgrades with STARTTLS, `smtps://host:465` is TLS from the start.
+    /// Either way the connection has to be encrypted.
+    pub smtp_url: String,
+    pub from: String,
+    pub to: Vec<String>,
+    pub username: Option<String>,
+    /// File holding the SMTP password.
+    pub password_file: Option<PathBuf>,
+}
+
+/// The `[notify]` section of installer.toml: who hears about the end of unattended runs.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Operators {
+    pub slack: Option<Slack>,
+    pub email: Option<Email>,
+}
+
+fn duration(secs: f64) -> String {
+    let secs = secs.round() as u64;
+    match secs {
+        0..=59 => format!("{}s", secs),
+        _ => format!("{}m{:02}s", secs / 60, secs % 60),
+    }
+}
+
+/// Subject and text of the message to operators.
+pub fn summary(payload: &Payload, transcript: Option<&Path>) -> (String, String) {
+    let outcome = match payload.outcome {
+        Outcome::Succeeded => "succeeded",
+        Outcome::Failed => "FAILED",
+    };
+    let subject = format!("bitflux {} {} on {}", payload.command, outcome, payload.host);
+    let mut text = format!("{} after {}", subject, duration(payload.duration_secs));
+    match &payload.error {
+        Some(error) => text.push_str(&format!(" with exit code {}: {}\n", payload.error_code, error)),
+        None => text.push_str(".\n"),
+    }
+    let versions: Vec<String> = payload.versions.iter().map(|(name, version)| format!("{} {}", name, version)).collect();
+    text.push_str(&format!("Versions: {}\n", versions.join(", ")));
+    if let Some(transcript) = transcript {
+        text.push_str(&format!("Transcript: {}\n", transcript.display()));
+    }
+    (subject, text)
+}
+
+/// The mail as curl uploads it.
+fn message(email: &Email, subject: &str, text: &str) -> String {
+    format!(
+        "From: {}\r\nTo: {}\r\nSubject: {}\r\nContent-Type: text/plain; charset=utf-8\r\n\r\n{}",
+        email.from, email.to.join(", "), subject, text.replace('\n', "\r\n")
+    )
+}
+
+fn smtp_args(email: &Email, message_file: &str) -> Vec<String> {
+    let mut args: Vec<String> = ["-fsS", "--max-time", "30", "--proto", "=smtp,smtps", "--ssl-reqd", "--tlsv1.2", "-K", "-"]
+        .iter().map(|a| String::from(*a)).collect();
+    args.extend([String::from("--mail-from"), email.from.clone()]);
+    for to in &email.to {
+        args.extend([String::from("--mail-rcpt"), to.clone()]);
+    }
+    args.extend([String::from("--upload-file"), String::from(message_file), String::from("--url"), email.smtp_url.clone()]);
+    args
+}
+
+fn send_email(email: &Email, subject: &str, text: &str) -> io::Result<()> {
+    // curl reads the message as the unprivileged account, the run's workspace only lets root in.
+    let scratch = Workspace::create_in(WORKSPACE_ROOT)?;
+    if let Some(ids) = unprivileged() {
+        scratch.chown(ids.uid, ids.gid)?;
+    }
+    let file = scratch.join("message");
+    fs::write(&file, message(email, subject, text))?;
+    // The credentials go to curl as a config file on stdin, never on its command line.
+    let quote = |s: &str| s.replace('\\', "\\\\").replace('"', "\\\"");
+    let config = match (&email.username, &email.password_file) {
+        (Some(user), Some(path)) => format!("user = \"{}:{}\"\n", quote(user), quote(&read_secret(path, "SMTP password")?)),
+        (Some(user), None) => format!("user = \"{}\"\n", quote(user)),
+        _ => String::new(),
+    };
+    deliver(&email.smtp_url, &smtp_args(email, &file.to_string_lossy()), &config)
+}
+
+impl Operators {
+
+    pub fn is_empty(&self) -> bool {
+        self.slack.is_none() && self.email.is_none()
+    }
+
+    /// Sends the summary of the run to every configured backend, trying all of them even when
+    /// one fails.
+    pub fn send(&self, payload: &Payload, transcript: Option<&Path>) -> io::Result<()> {
+        let (subject, text) = summary(payload, transcript);
+        let mut errors: Vec<String> = Vec::new();
+        if let Some(slack) = &self.slack {
+            let body = serde_json::json!({ "text": text }).to_string();
+            let args = curl_args(&tls::hardened_curl_args(), &slack.webhook_url, None);
+            if let Err(e) = deliver(&slack.webhook_url, &args, &body) {
+                errors.push(e.to_string());
+            }
+        }
+        if let Some(email) = &self.email {
+            if let Err(e) = send_email(email, &subject, &text) {
+                errors.push(e.to_string());
+            }
+        }
+        match errors.is_empty() {
+            true => Ok(()),
+            false => Err(io::Error::other(errors.join(" "))),
+        }
+    }
+
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn payload_json() {
+        let failed = Err(io::Error::other("No space left on /boot."));
+        let payload = Payload::new("install", Duration::from_millis(1500), &failed, 1);
+        assert_eq!(payload.outcome, Outcome::Failed);
+        assert_eq!(payload.versions["installer"], env!("CARGO_PKG_VERSION"));
+        let json = serde_json::to_value(&payload).unwrap();
+        assert_eq!(json["outcome"], "failed");
+        assert_eq!(json["duration_secs"], 1.5);
+        assert_eq!(json["error_code"], 1);
+        assert_eq!(json["error"], "No space left on /boot.");
+    }
+
+    #[test]
+    fn operator_summary() {
+        let payload = Payload {
+            host: String::from("web1"),
+            command: String::from("upgrade"),
+            outcome: Outcome::Failed,
+            duration_secs: 192.4,
+            finished_at: 0,
+            versions: BTreeMap::from([(String::from("installer"), String::from("0.1.0"))]),
+            error_code: 15,
+            error: Some(String::from("Bad signature.")),
+        };
+        let (subject, text) = summary(&payload, Some(Path::new("/var/log/bitflux/upgrade-1.log")));
+        assert_eq!(subject, "bitflux upgrade FAILED on web1");
+        assert_eq!(text, "bitflux upgrade FAILED on web1 after 3m12s with exit code 15: Bad signature.\n\
+                          Versions: installer 0.1.0\n\
+                          Transcript: /var/log/bitflux/upgrade-1.log\n");
+
+        let email = Email {
+            smtp_url: String::from("smtp://mail.example.com:587"),
+            from: String::from("installer@example.com"),
+            to: vec![String::from("ops@example.com"), String::from("oncall@example.com")],
+            username: None,
+            password_file: None,
+        };
+        assert!(message(&email, &subject, "a\nb\n").ends_with("Subject: bitflux upgrade FAILED on web1\r\nContent-Type: text/plain; charset=utf-8\r\n\r\na\r\nb\r\n"));
+        let args = smtp_args(&email, "/var/tmp/x/message");
+        assert_eq!(args.iter().filter(|a| *a == "--mail-rcpt").count(), 2);
+        assert!(args.windows(2).any(|w| w == ["--proto", "=smtp,smtps"]));
+    }
+
+    #[test]
+    fn signed_request() {
+        let args = curl_args(&[String::from("--tlsv1.2")], "https://hooks.example.com/bitflux", Some("ab12"));
+        assert!(args.windows(2).any(|w| w == ["-H", "X-Bitflux-Signature: sha256=ab12"]));
+        assert_eq!(&args[args.len() - 5..], ["--tlsv1.2", "--data-binary", "@-", "--", "https://hooks.example.com/bitflux"]);
+        assert!(!curl_args(&[], "https://hooks.example.com/bitflux", None).iter().any(|a| a.contains(SIGNATURE_HEADER)));
+    }
+
+}
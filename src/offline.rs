@@ -0,0 +1,38 @@
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+/// Set by `install --offline`, nothing may reach out to the network for the rest of the run.
+static OFFLINE: AtomicBool = AtomicBool::new(false);
+
+pub fn set_offline(offline: bool) {
+    OFFLINE.store(offline, Ordering::Relaxed);
+}
+
+pub fn is_offline() -> bool {
+    OFFLINE.load(Ordering::Relaxed)
+}
+
+/// Fails when offline.  Everything that opens a connection calls this first, so an offline
+/// install errors out instead of silently touching the network.
+pub fn guard(what: &str) -> io::Result<()> {
+    if is_offline() {
+        return Err(io::Error::other(format!("Refusing to access '{}', the install is --offline.", what)));
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn guard_blocks_when_offline() {
+        assert!(guard("https://mirror.bitflux.ai").is_ok());
+        set_offline(true);
+        let e = guard("https://mirror.bitflux.ai").unwrap_err();
+        set_offline(false);
+        assert!(e.to_string().contains("--offline"));
+    }
+
+}
@@ -0,0 +1,101 @@
+use std::fs::File;
+use std::io::{self, Write};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::sync::Mutex;
+
+use clap::ValueEnum;
+use serde::Serialize;
+
+use crate::engine::StepRecord;
+use crate::notify::Payload;
+
+/// The real stdout once reserve_stdout() moved it, the JSON result goes there.
+static RESERVED: Mutex<Option<File>> = Mutex::new(None);
+
+/// How `status` and `preflight` print their result.
+#[derive(Clone, Copy, Debug, Default, PartialEq, ValueEnum)]
+pub enum Format {
+    /// For people.
+    #[default]
+    Text,
+    /// One JSON document on stdout, everything else goes to stderr.
+    Json,
+}
+
+/// What `install --output json` prints.
+#[derive(Clone, Debug, Serialize)]
+pub struct InstallResult {
+    #[serde(flatten)]
+    pub outcome: Payload,
+    /// Any step changed the host.
+    pub changed: bool,
+    /// Every step in the order it ran, up to the one that failed.
+    pub steps: Vec<StepRecord>,
+    pub reboot_required: bool,
+    /// Why bitflux needs the reboot.
+    pub reboot_reasons: Vec<String>,
+}
+
+/// What `install --check --output json` prints.
+#[derive(Clone, Debug, Serialize)]
+pub struct CheckResult {
+    /// Any step would change the host.
+    pub changed: bool,
+    /// Every step in the order an install applies them.
+    pub steps: Vec<StepRecord>,
+}
+
+/// Points stdout at stderr for the rest of the run, for everything the installer and the
+/// commands it runs print, and keeps the real stdout for json().
+pub fn reserve_stdout() -> io::Result<()> {
+    io::stdout().flush()?;
+    let fd = unsafe { libc::dup(libc::STDOUT_FILENO) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    if unsafe { libc::dup2(libc::STDERR_FILENO, libc::STDOUT_FILENO) } < 0 {
+        let e = io::Error::last_os_error();
+        unsafe { libc::close(fd) };
+        return Err(e);
+    }
+    *RESERVED.lock().unwrap_or_else(|e| e.into_inner()) = Some(unsafe { File::from_raw_fd(fd) });
+    Ok(())
+}
+
+/// Sends everything the installer and its commands print to /dev/null for the rest of the
+/// run, for --quiet.  The real stdout is kept for json() and print(), the log file still gets
+/// it all.
+pub fn silence() -> io::Result<()> {
+    io::stdout().flush()?;
+    io::stderr().flush()?;
+    let mut reserved = RESERVED.lock().unwrap_or_else(|e| e.into_inner());
+    if reserved.is_none() {
+        let fd = unsafe { libc::dup(libc::STDOUT_FILENO) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        *reserved = Some(unsafe { File::from_raw_fd(fd) });
+    }
+    let null = File::options().write(true).open("/dev/null")?;
+    for fd in [libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        if unsafe { libc::dup2(null.as_raw_fd(), fd) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Prints `text` on stdout, the real one when reserve_stdout() or silence() moved it.
+pub fn print(text: &str) -> io::Result<()> {
+    match RESERVED.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+        Some(stdout) => stdout.write_all(text.as_bytes()),
+        None => io::stdout().write_all(text.as_bytes()),
+    }
+}
+
+/// Prints `value` as JSON on stdout, the real one when reserve_stdout() or silence() moved it.
+pub fn json<T: Serialize>(value: &T) -> io::Result<()> {
+    let mut data = serde_json::to_string_pretty(value).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    data.push('\n');
+    print(&data)
+}
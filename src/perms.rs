@@ -0,0 +1,158 @@
+use std::fs;
+use std::io;
+use std::os::unix::fs::{chown, MetadataExt, PermissionsExt};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::runcmd::{self, shell_quote};
+use crate::script;
+use crate::selinux;
+
+/// umask for the installer and every child it starts, nothing it creates is group or world writable.
+pub const UMASK: u32 = 0o022;
+/// Group that may read the agent config.
+pub const AGENT_GROUP: &str = "bitflux";
+
+/// The classes of file the installer puts on the system, each with a fixed owner and mode.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum FileKind {
+    /// root:bitflux 0640
+    Config,
+    /// root:root 0755
+    Binary,
+    /// root:root 0600, license keys and signing keys.
+    Secret,
+    /// root:root 0644, systemd units, drop-ins and package repo definitions.
+    Unit,
+}
+
+impl FileKind {
+
+    pub fn mode(self) -> u32 {
+        match self {
+            FileKind::Config => 0o640,
+            FileKind::Binary => 0o755,
+            FileKind::Secret => 0o600,
+            FileKind::Unit => 0o644,
+        }
+    }
+
+    /// Group name, root falls back to when the agent group doesn't exist yet.
+    pub fn group(self) -> &'static str {
+        match self {
+            FileKind::Config => AGENT_GROUP,
+            _ => "root",
+        }
+    }
+
+}
+
+/// Owner and mode a file had when the installer put it in place.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PermissionRecord {
+    pub path: String,
+    pub uid: u32,
+    pub gid: u32,
+    pub mode: u32,
+    /// SELinux type the file was labelled with, on enforcing systems.
+    #[serde(default)]
+    pub selinux_type: Option<String>,
+}
+
+impl PermissionRecord {
+
+    /// "uid:gid mode" the way verify reports it.
+    pub fn expected(&self) -> String {
+        describe(self.uid, self.gid, self.mode)
+    }
+
+}
+
+fn describe(uid: u32, gid: u32, mode: u32) -> String {
+    format!("{}:{} {:04o}", uid, gid, mode)
+}
+
+/// Finds the gid of `name` in group formatted `data`.
+pub fn group_id(data: &str, name: &str) -> Option<u32> {
+    data.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split(':').collect();
+        if fields.len() < 3 || fields[0] != name {
+            return None;
+        }
+        fields[2].parse().ok()
+    })
+}
+
+/// Sets the process umask, children inherit it.
+pub fn set_umask() {
+    unsafe {
+        libc::umask(UMASK as libc::mode_t);
+    }
+}
+
+/// Gives `path` the owner, mode and SELinux label of `kind` and returns what to record in the receipt.
+pub fn apply<P: AsRef<Path>>(path: P, kind: FileKind) -> io::Result<PermissionRecord> {
+    let groups = fs::read_to_string("/etc/group").unwrap_or_default();
+    apply_as(path, kind, 0, group_id(&groups, kind.group()).unwrap_or(0))
+}
+
+/// apply() with `uid` and `gid` for the owner instead of root and the group of `kind`.
+pub fn apply_as<P: AsRef<Path>>(path: P, kind: FileKind, uid: u32, gid: u32) -> io::Result<PermissionRecord> {
+    let path = path.as_ref();
+    let quoted = shell_quote(&path.to_string_lossy());
+    let scripted = script::shell(&format!("chown {}:{} {} && chmod {:o} {}", uid, gid, quoted, kind.mode(), quoted));
+    if scripted || runcmd::dry_run(&format!("chown {}:{} and chmod {:o} {}", uid, gid, kind.mode(), path.display())) {
+        return Ok(PermissionRecord { path: path.to_string_lossy().into_owned(), uid, gid, mode: kind.mode(), selinux_type: None });
+    }
+    chown(path, Some(uid), Some(gid))?;
+    fs::set_permissions(path, fs::Permissions::from_mode(kind.mode()))?;
+    let selinux_type = selinux::label(path)?;
+    Ok(PermissionRecord { path: path.to_string_lossy().into_owned(), uid, gid, mode: kind.mode(), selinux_type })
+}
+
+/// "uid:gid mode" of `path` as it is now, None if it doesn't exist.
+pub fn actual<P: AsRef<Path>>(path: P) -> Option<String> {
+    let meta = fs::symlink_metadata(path).ok()?;
+    Some(describe(meta.uid(), meta.gid(), meta.mode() & 0o7777))
+}
+
+/// Puts the recorded owner and mode back.
+pub fn restore(record: &PermissionRecord) -> io::Result<()> {
+    if runcmd::dry_run(&format!("chown {}:{} and chmod {:o} {}", record.uid, record.gid, record.mode, record.path)) {
+        return Ok(());
+    }
+    chown(&record.path, Some(record.uid), Some(record.gid))?;
+    fs::set_permissions(&record.path, fs::Permissions::from_mode(record.mode))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn group_lookup() {
+        let groups = "root:x:0:\nbitflux:x:998:alice\n";
+        assert_eq!(group_id(groups, "bitflux"), Some(998));
+        assert_eq!(group_id(groups, "nogroup"), None);
+    }
+
+    #[test]
+    fn apply_and_detect_drift() {
+        let file = std::env::temp_dir().join(format!("bitflux-perms-{}", std::process::id()));
+        fs::write(&file, "licensekey=abc\n").unwrap();
+
+        // Owned by whoever runs the tests, only root can give files away.
+        let meta = fs::metadata(&file).unwrap();
+        let record = apply_as(&file, FileKind::Secret, meta.uid(), meta.gid()).unwrap();
+        assert_eq!(actual(&file), Some(record.expected()));
+        fs::set_permissions(&file, fs::Permissions::from_mode(0o644)).unwrap();
+        assert_ne!(actual(&file), Some(record.expected()));
+        restore(&record).unwrap();
+        assert_eq!(actual(&file), Some(record.expected()));
+        fs::remove_file(&file).unwrap();
+    }
+
+}
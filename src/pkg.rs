@@ -0,0 +1,476 @@
+use std::path::Path;
+
+use crate::arch::{page_size, Arch};
+use crate::cloud;
+use crate::kernel::running_kernel;
+use crate::log::{self, Level};
+use crate::offline;
+use crate::sbc;
+use crate::platform::{self, Family, OsRelease};
+use crate::reboot;
+use crate::runcmd::{which, RunCmd};
+
+/// The bitflux agent package, same name on every distro.
+pub const AGENT_PACKAGE: &str = "bitfluxcollector";
+
+fn argv_cmd(argv: Vec<String>) -> RunCmd {
+    let args: Vec<&str> = argv[1..].iter().map(String::as_str).collect();
+    RunCmd::args(&argv[0], &args)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PackageManager {
+    Apt,
+    Dnf,
+    Yum,
+    Zypper,
+}
+
+impl PackageManager {
+
+    /// The package manager of the release in `os`.
+    pub fn for_release(os: &OsRelease) -> Option<PackageManager> {
+        match os.family()? {
+            Family::Debian => Some(PackageManager::Apt),
+            // EL8 and newer ship dnf, yum is only the compatibility alias there.
+            Family::Rhel if os.el_major().is_some_and(|m| m < 8) || (os.is_amazon() && os.version_id == "2") => Some(PackageManager::Yum),
+            Family::Rhel => Some(PackageManager::Dnf),
+            Family::Suse => Some(PackageManager::Zypper),
+        }
+    }
+
+    /// The package manager of the distro in /etc/os-release, falling back to whichever tool is
+    /// installed when the distro isn't recognized.
+    pub fn detect() -> Option<PackageManager> {
+        if let Some(pm) = OsRelease::load().ok().as_ref().and_then(PackageManager::for_release) {
+            if which(pm.tool()).is_some() {
+                return Some(pm);
+            }
+        }
+        if which("apt-get").is_some() {
+            Some(PackageManager::Apt)
+        } else if which("dnf").is_some() {
+            Some(PackageManager::Dnf)
+        } else if which("yum").is_some() {
+            Some(PackageManager::Yum)
+        } else if which("zypper").is_some() {
+            Some(PackageManager::Zypper)
+        } else {
+            None
+        }
+    }
+
+    /// Meta package that pulls in the current bitflux kernel for this machine.  Ubuntu cloud
+    /// images running a cloud kernel flavor get the bitflux build of that flavor, Raspberry Pis
+    /// the build with the Pi's device trees and drivers.
+    pub fn kernel_package(&self) -> &'static str {
+        let running = running_kernel().unwrap_or_default();
+        self.kernel_package_on(Arch::current().unwrap_or(Arch::X86_64), page_size(), &running, sbc::is_raspberry_pi())
+    }
+
+    /// kernel_package() for a host with the given architecture, page size, `uname -r` and board.
+    pub fn kernel_package_on(&self, arch: Arch, page_size: u64, running: &str, raspberry_pi: bool) -> &'static str {
+        if *self == PackageManager::Apt {
+            if raspberry_pi {
+                return "linux-image-swaphints-rpi";
+            }
+            if let Some(cloud) = cloud::running_flavor(running) {
+                return cloud.apt_kernel_package();
+            }
+        }
+        self.kernel_package_for(arch, page_size)
+    }
+
+    /// Kernel package for `arch` running with `page_size` pages.  RHEL family ARM64 hosts booted
+    /// into the 64K page kernel need the matching 64K build, apt resolves the architecture itself.
+    /// SUSE names kernels by flavor, like kernel-default and kernel-64kb.
+    pub fn kernel_package_for(&self, arch: Arch, page_size: u64) -> &'static str {
+        let pages_64k = arch == Arch::Aarch64 && page_size == 65536;
+        match self {
+            PackageManager::Apt => "linux-image-swaphints",
+            PackageManager::Zypper if pages_64k => "kernel-swaphints-64kb",
+            PackageManager::Zypper => "kernel-swaphints",
+            _ if pages_64k => "kernel-swaphints-64k",
+            PackageManager::Dnf | PackageManager::Yum => "kernel-swaphints",
+        }
+    }
+
+    fn tool(&self) -> &'static str {
+        match self {
+            PackageManager::Apt => "apt-get",
+            PackageManager::Dnf => "dnf",
+            PackageManager::Yum => "yum",
+            PackageManager::Zypper => "zypper",
+        }
+    }
+
+    /// True when changes go into a new snapshot through transactional-update and only take
+    /// effect after a reboot (openSUSE MicroOS, SLE Micro).
+    pub fn transactional(&self) -> bool {
+        *self == PackageManager::Zypper && platform::is_transactional()
+    }
+
+    /// Command line of a non-interactive `action` (install, remove, ...) of `args`, through
+    /// transactional-update when `transactional`.
+    pub fn command_argv(&self, action: &str, args: &[&str], transactional: bool) -> Vec<String> {
+        let prefix: &[&str] = match self {
+            _ if transactional => &["transactional-update", "--non-interactive", "pkg", action],
+            PackageManager::Zypper => &["zypper", "--non-interactive", action],
+            _ => &[self.tool(), action, "-y"],
+        };
+        [prefix, args].concat().into_iter().map(String::from).collect()
+    }
+
+    fn command(&self, action: &str, args: &[&str]) -> RunCmd {
+        argv_cmd(self.command_argv(action, args, self.transactional()))
+    }
+
+    /// Installs `names` from the configured repositories.
+    pub fn install_cmd(&self, names: &[&str]) -> RunCmd {
+        self.install_cmd_as(names, self.transactional())
+    }
+
+    /// install_cmd() through transactional-update when `transactional`, whatever this host is.
+    pub fn install_cmd_as(&self, names: &[&str], transactional: bool) -> RunCmd {
+        argv_cmd(self.command_argv("install", names, transactional))
+    }
+
+    /// Installs local package files with dpkg/rpm alone, which never resolve anything from a
+    /// repository, so all dependencies have to be among `paths` or already installed.
+    pub fn install_files_cmd(&self, paths: &[&str]) -> RunCmd {
+        self.install_files_cmd_as(paths, self.transactional())
+    }
+
+    /// install_files_cmd() through transactional-update when `transactional`.
+    pub fn install_files_cmd_as(&self, paths: &[&str], transactional: bool) -> RunCmd {
+        argv_cmd(self.install_files_argv(paths, transactional))
+    }
+
+    /// Command line of install_files_cmd().
+    pub fn install_files_argv(&self, paths: &[&str], transactional: bool) -> Vec<String> {
+        let prefix: &[&str] = match self {
+            PackageManager::Apt => &["dpkg", "-i"],
+            _ if transactional => &["transactional-update", "--non-interactive", "run", "rpm", "-Uvh", "--replacepkgs"],
+            _ => &["rpm", "-Uvh", "--replacepkgs"],
+        };
+        [prefix, paths].concat().into_iter().map(String::from).collect()
+    }
+
+    /// False, with the reason printed, when the package repositories may not be used.
+    fn online(&self) -> bool {
+        match offline::guard("the package repositories") {
+            Ok(()) => true,
+            Err(e) => {
+                log::log(Level::Error, &e.to_string());
+                false
+            }
+        }
+    }
+
+    fn changed(&self, cmd: RunCmd) -> bool {
+        self.changed_as(cmd, self.transactional())
+    }
+
+    fn changed_as(&self, mut cmd: RunCmd, transactional: bool) -> bool {
+        // Maintainer scripts must never stop at a debconf question.
+        if *self == PackageManager::Apt {
+            cmd.env("DEBIAN_FRONTEND", "noninteractive");
+        }
+        // Package installs take a while, show their progress as it happens.
+        let ok = cmd.tee().execute_output().exitcode == 0;
+        if ok && transactional {
+            log::log(Level::Info, "Changes were applied to a new snapshot, reboot to activate them.");
+            let _ = reboot::flag("the packages went into a new snapshot, it becomes active after a reboot");
+        }
+        ok
+    }
+
+    /// Installed version of package `name`, or None if it isn't installed.
+    pub fn installed_version(&self, name: &str) -> Option<String> {
+        let out = match self {
+            PackageManager::Apt => RunCmd::args("dpkg-query", &["-W", "-f=${Version}", name]).execute_output(),
+            _ => RunCmd::args("rpm", &["-q", "--qf", "%{VERSION}-%{RELEASE}", name]).execute_output(),
+        };
+
+        let version = out.stdout.trim();
+        if out.exitcode != 0 || version.is_empty() {
+            return None;
+        }
+        Some(version.to_string())
+    }
+
+    /// Version of package `name` an install from the configured repositories would get, None
+    /// when there's none.
+    pub fn candidate_version(&self, name: &str) -> Option<String> {
+        let out = match self {
+            PackageManager::Apt => RunCmd::args("apt-cache", &["policy", name]).execute_output(),
+            PackageManager::Dnf => RunCmd::args("dnf", &["repoquery", "-q", "--latest-limit", "1", "--qf", "%{version}-%{release}", name]).execute_output(),
+            PackageManager::Yum => RunCmd::args("repoquery", &["-q", "--qf", "%{version}-%{release}", name]).execute_output(),
+            PackageManager::Zypper => RunCmd::args("zypper", &["--non-interactive", "--quiet", "info", name]).execute_output(),
+        };
+        if out.exitcode != 0 {
+            return None;
+        }
+        parse_candidate(self, &out.stdout)
+    }
+
+    /// Every version of package `name` the configured repositories have.
+    pub fn available_versions(&self, name: &str) -> Vec<String> {
+        let out = match self {
+            PackageManager::Apt => RunCmd::args("apt-cache", &["madison", name]).execute_output(),
+            PackageManager::Dnf => RunCmd::args("dnf", &["repoquery", "-q", "--qf", "%{version}-%{release}", name]).execute_output(),
+            PackageManager::Yum => RunCmd::args("repoquery", &["-q", "--show-duplicates", "--qf", "%{version}-%{release}", name]).execute_output(),
+            PackageManager::Zypper => RunCmd::args("zypper", &["--non-interactive", "--quiet", "search", "-s", "--match-exact", name]).execute_output(),
+        };
+        match out.exitcode {
+            0 => parse_versions(self, name, &out.stdout),
+            _ => Vec::new(),
+        }
+    }
+
+    /// Version of the package file at `path`.
+    pub fn file_version(&self, path: &Path) -> Option<String> {
+        let path = path.to_string_lossy();
+        let out = match self {
+            PackageManager::Apt => RunCmd::args("dpkg-deb", &["-f", &path, "Version"]).execute_output(),
+            _ => RunCmd::args("rpm", &["-qp", "--qf", "%{VERSION}-%{RELEASE}", &path]).execute_output(),
+        };
+        let version = out.stdout.trim();
+        if out.exitcode != 0 || version.is_empty() {
+            return None;
+        }
+        Some(version.to_string())
+    }
+
+    /// Paths of everything package `name` installed.
+    pub fn files(&self, name: &str) -> Vec<String> {
+        let out = match self {
+            PackageManager::Apt => RunCmd::args("dpkg", &["-L", name]).execute_output(),
+            _ => RunCmd::args("rpm", &["-ql", name]).execute_output(),
+        };
+        if out.exitcode != 0 {
+            return Vec::new();
+        }
+        out.stdout.lines().map(str::trim).filter(|l| l.starts_with('/')).map(String::from).collect()
+    }
+
+    /// Package owning `path`, e.g. the kernel package for a /boot/vmlinuz-* file.
+    pub fn owner(&self, path: &Path) -> Option<String> {
+        let out = match self {
+            PackageManager::Apt => RunCmd::args("dpkg", &["-S", &path.to_string_lossy()]).execute_output(),
+            _ => RunCmd::args("rpm", &["-qf", "--qf", "%{NAME}-%{VERSION}-%{RELEASE}", &path.to_string_lossy()]).execute_output(),
+        };
+        if out.exitcode != 0 {
+            return None;
+        }
+        // dpkg -S prints "package: /path"
+        let name = out.stdout.split(':').next().unwrap_or("").trim();
+        if name.is_empty() {
+            return None;
+        }
+        Some(name.to_string())
+    }
+
+    /// Refreshes the repository metadata, needed after a repository was added.
+    pub fn refresh(&self) -> bool {
+        if !self.online() {
+            return false;
+        }
+        argv_cmd(self.refresh_argv()).execute_output().exitcode == 0
+    }
+
+    /// Command line of refresh().
+    pub fn refresh_argv(&self) -> Vec<String> {
+        let argv: &[&str] = match self {
+            PackageManager::Apt => &["apt-get", "update"],
+            PackageManager::Zypper => &["zypper", "--non-interactive", "--gpg-auto-import-keys", "refresh"],
+            _ => &[self.tool(), "makecache"],
+        };
+        argv.iter().map(|a| a.to_string()).collect()
+    }
+
+    /// Imports the armored public key at `path` into the rpm database, so packages signed with
+    /// it verify without dnf or zypper asking.  apt has no key database, its sources name their
+    /// keyring with signed-by.
+    pub fn import_key(&self, path: &Path) -> bool {
+        match self {
+            PackageManager::Apt => true,
+            _ => RunCmd::args("rpm", &["--import", &path.to_string_lossy()]).execute_output().exitcode == 0,
+        }
+    }
+
+    pub fn install(&self, names: &[&str]) -> bool {
+        self.install_as(names, self.transactional())
+    }
+
+    /// install() through transactional-update when `transactional`.
+    pub fn install_as(&self, names: &[&str], transactional: bool) -> bool {
+        if !self.online() {
+            return false;
+        }
+        self.changed_as(self.install_cmd_as(names, transactional), transactional)
+    }
+
+    /// Installs (or reinstalls) exactly `version` of package `name`.
+    pub fn install_version(&self, name: &str, version: &str) -> bool {
+        if !self.online() {
+            return false;
+        }
+        let cmd = match self {
+            PackageManager::Apt => RunCmd::args("apt-get", &["install", "-y", "--reinstall", "--allow-downgrades", &format!("{}={}", name, version)]),
+            PackageManager::Zypper => self.command("install", &["--oldpackage", &format!("{}={}", name, version)]),
+            _ => RunCmd::args(self.tool(), &["install", "-y", &format!("{}-{}", name, version)]),
+        };
+        self.changed(cmd)
+    }
+
+    /// Installs a local .deb/.rpm file.
+    pub fn install_file(&self, path: &Path) -> bool {
+        if offline::is_offline() {
+            return self.changed(self.install_files_cmd(&[&path.to_string_lossy()]));
+        }
+        self.changed(self.command("install", &[&path.to_string_lossy()]))
+    }
+
+    /// Upgrades already installed packages, never installs new ones.
+    pub fn upgrade(&self, names: &[&str]) -> bool {
+        if !self.online() {
+            return false;
+        }
+        let cmd = match self {
+            PackageManager::Apt => RunCmd::args("apt-get", &[&["install", "-y", "--only-upgrade"], names].concat()),
+            PackageManager::Zypper => self.command("update", names),
+            _ => RunCmd::args(self.tool(), &[&["upgrade", "-y"], names].concat()),
+        };
+        self.changed(cmd)
+    }
+
+    pub fn remove(&self, names: &[&str]) -> bool {
+        self.changed(self.command("remove", names))
+    }
+
+    /// Downloads the package file for `name` into `dir` without installing it.
+    pub fn download(&self, name: &str, dir: &Path) -> bool {
+        if !self.online() {
+            return false;
+        }
+        let out = match self {
+            // apt-get download always writes into the working directory.
+            PackageManager::Apt => RunCmd::args("apt-get", &["download", name]).cwd(dir).execute_output(),
+            PackageManager::Dnf => RunCmd::args("dnf", &["download", "--destdir", &dir.to_string_lossy(), name]).execute_output(),
+            PackageManager::Yum => RunCmd::args("yumdownloader", &["--destdir", &dir.to_string_lossy(), name]).execute_output(),
+            // zypper download keeps the file under <cache dir>/<repo>/<arch>/, staged looks there.
+            PackageManager::Zypper => RunCmd::args("zypper", &["--non-interactive", "--pkg-cache-dir", &dir.to_string_lossy(), "download", name]).execute_output(),
+        };
+        out.exitcode == 0
+    }
+
+}
+
+/// Installed HWE kernel packages in `dpkg-query -W -f '${Package} ${Status}\n'` output.
+pub fn parse_hwe(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter(|l| l.ends_with(" install ok installed"))
+        .filter_map(|l| l.split_whitespace().next())
+        .filter(|p| p.contains("-hwe-"))
+        .map(String::from)
+        .collect()
+}
+
+/// Ubuntu HWE kernel packages on this machine.  They follow the newer kernel series and would
+/// make one of those the default boot entry over the bitflux kernel.
+pub fn hwe_kernel_packages() -> Vec<String> {
+    if which("dpkg-query").is_none() {
+        return Vec::new();
+    }
+    let out = RunCmd::args("dpkg-query", &["-W", "-f=${Package} ${Status}\n", "linux-*-hwe-*"]).execute_output();
+    parse_hwe(&out.stdout)
+}
+
+/// The candidate version in `pm`'s answer to candidate_version(): the Candidate line of
+/// `apt-cache policy`, the Version line of `zypper info`, repoquery's output as it is.
+pub fn parse_candidate(pm: &PackageManager, output: &str) -> Option<String> {
+    let version = match pm {
+        PackageManager::Apt => output.lines().find_map(|l| l.trim().strip_prefix("Candidate:"))?,
+        PackageManager::Zypper => output.lines().find_map(|l| l.split_once(':').filter(|(k, _)| k.trim() == "Version").map(|(_, v)| v))?,
+        PackageManager::Dnf | PackageManager::Yum => output.lines().next()?,
+    };
+    let version = version.trim();
+    match version.is_empty() || version == "(none)" {
+        true => None,
+        false => Some(String::from(version)),
+    }
+}
+
+/// The versions of package `name` in `pm`'s answer to available_versions(): the version column
+/// of `apt-cache madison` and `zypper search -s`, one per line from repoquery.
+pub fn parse_versions(pm: &PackageManager, name: &str, output: &str) -> Vec<String> {
+    let column = |line: &str, package: usize, version: usize| -> Option<String> {
+        let fields: Vec<&str> = line.split('|').map(str::trim).collect();
+        (fields.len() > version && fields[package] == name).then(|| String::from(fields[version]))
+    };
+    let mut versions: Vec<String> = output.lines()
+        .filter_map(|line| match pm {
+            PackageManager::Apt => column(line, 0, 1),
+            PackageManager::Zypper => column(line, 1, 3),
+            PackageManager::Dnf | PackageManager::Yum => Some(String::from(line.trim())).filter(|v| !v.is_empty()),
+        })
+        .collect();
+    versions.dedup();
+    versions
+}
+
+/// Installed version of package `name`, or None if it isn't installed.
+pub fn installed_version(name: &str) -> Option<String> {
+    PackageManager::detect()?.installed_version(name)
+}
+
+/// Installs (or reinstalls) exactly `version` of package `name`.
+pub fn install_version(name: &str, version: &str) -> bool {
+    match PackageManager::detect() {
+        Some(pm) => pm.install_version(name, version),
+        None => false,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn hwe_packages() {
+        let out = "\
+linux-generic-hwe-22.04 install ok installed
+linux-image-generic-hwe-22.04 install ok installed
+linux-headers-generic-hwe-20.04 unknown ok not-installed
+linux-image-generic install ok installed
+";
+        assert_eq!(parse_hwe(out), vec!["linux-generic-hwe-22.04", "linux-image-generic-hwe-22.04"]);
+    }
+
+    #[test]
+    fn candidates() {
+        let policy = "linux-image-swaphints:\n  Installed: (none)\n  Candidate: 6.8.0.1004\n  Version table:\n";
+        assert_eq!(parse_candidate(&PackageManager::Apt, policy).as_deref(), Some("6.8.0.1004"));
+        assert_eq!(parse_candidate(&PackageManager::Apt, "x:\n  Installed: (none)\n  Candidate: (none)\n"), None);
+        let info = "Information for package kernel-swaphints:\n-------\nRepository     : bitflux\nVersion        : 6.4.0-150600.1\n";
+        assert_eq!(parse_candidate(&PackageManager::Zypper, info).as_deref(), Some("6.4.0-150600.1"));
+        assert_eq!(parse_candidate(&PackageManager::Dnf, "5.14.0-427.el9\n").as_deref(), Some("5.14.0-427.el9"));
+        assert_eq!(parse_candidate(&PackageManager::Dnf, ""), None);
+    }
+
+    #[test]
+    fn available_versions() {
+        let madison = "bitfluxcollector |    1.4.0-2 | https://mirror.bitflux.ai/repository/ubuntu jammy/main amd64 Packages\n\
+bitfluxcollector |    1.3.2-1 | https://mirror.bitflux.ai/repository/ubuntu jammy/main amd64 Packages\n";
+        assert_eq!(parse_versions(&PackageManager::Apt, AGENT_PACKAGE, madison), ["1.4.0-2", "1.3.2-1"]);
+        let search = "S  | Name             | Type    | Version | Arch   | Repository\n\
+---+------------------+---------+---------+--------+-----------\n\
+v  | bitfluxcollector | package | 1.4.0-1 | x86_64 | bitflux\n";
+        assert_eq!(parse_versions(&PackageManager::Zypper, AGENT_PACKAGE, search), ["1.4.0-1"]);
+        assert_eq!(parse_versions(&PackageManager::Dnf, AGENT_PACKAGE, "1.4.0-1.el9\n1.3.2-1.el9\n"), ["1.4.0-1.el9", "1.3.2-1.el9"]);
+    }
+
+}
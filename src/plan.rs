@@ -0,0 +1,378 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::arch::Arch;
+use crate::exitcode::Kind;
+use crate::install::Options;
+use crate::kernel::Bootloader;
+use crate::mac::Mac;
+use crate::pkg::PackageManager;
+use crate::platform::{Family, Init, OsRelease, Platform};
+use crate::script::Script;
+use crate::service::Service;
+use crate::virt::Virt;
+use crate::wsl;
+
+/// Everything about a host the install plan depends on.  Detected on a real host, or read
+/// from a fixture so a plan can be checked without the distro at hand.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Host {
+    pub os: OsRelease,
+    /// `uname -r`.
+    pub kernel: String,
+    pub arch: Arch,
+    pub page_size: u64,
+    pub raspberry_pi: bool,
+    /// Packages go into a transactional-update snapshot.
+    pub transactional: bool,
+    pub secure_boot: bool,
+    /// The container it is, "docker" or "lxc", None outside of one.
+    pub container: Option<String>,
+}
+
+impl Host {
+
+    /// What the install steps would see on this host: systemd its init, the distro's usual
+    /// bootloader, neither SELinux nor AppArmor enforcing.
+    pub fn platform(&self) -> io::Result<Platform> {
+        let pm = PackageManager::for_release(&self.os)
+            .ok_or_else(|| Kind::Unsupported.error("No supported package manager found."))?;
+        let virt = match (&self.container, wsl::is_wsl2_release(&self.kernel)) {
+            (Some(container), _) => Virt::Container(container.clone()),
+            (None, true) => Virt::Wsl,
+            (None, false) => Virt::BareMetal,
+        };
+        Ok(Platform {
+            transactional: self.transactional && pm == PackageManager::Zypper,
+            os: self.os.clone(),
+            pm,
+            kernel: self.kernel.clone(),
+            arch: self.arch,
+            page_size: self.page_size,
+            init: Some(Init::Systemd),
+            virt,
+            bootloader: Some(match (self.raspberry_pi, self.os.family()) {
+                (true, _) => Bootloader::RpiFirmware,
+                (false, Some(Family::Rhel)) => Bootloader::Grubby,
+                (false, Some(Family::Suse)) => Bootloader::Grub2,
+                (false, _) => Bootloader::Grub,
+            }),
+            mac: Mac::None,
+            sbc: self.raspberry_pi,
+            raspberry_pi: self.raspberry_pi,
+        })
+    }
+
+}
+
+/// One of the engine's steps of the install, in the order it applies them, and what applying
+/// it does.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Step {
+    pub name: String,
+    pub title: String,
+    /// The step's check finds it not done, the install applies it.
+    pub pending: bool,
+    pub actions: Vec<String>,
+}
+
+/// What `install` would do on a host, without doing any of it.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Plan {
+    pub title: String,
+    pub steps: Vec<Step>,
+}
+
+impl Plan {
+
+    /// The plan of what the dry run `script` recorded, with the names of the steps the
+    /// engine's check found done or not.  None takes every step as not done, for hosts that
+    /// can't be checked.
+    pub fn recorded(script: &Script, pending: Option<&BTreeMap<String, bool>>) -> Plan {
+        let steps = script.sections.iter().map(|section| Step {
+            name: section.name.clone(),
+            title: section.title.clone(),
+            pending: pending.is_none_or(|pending| pending.get(&section.name).copied().unwrap_or(true)),
+            actions: section.actions.clone(),
+        }).collect();
+        Plan { title: script.title.clone(), steps }
+    }
+
+    /// The plan as text, the same for the same host and options.
+    pub fn render(&self) -> String {
+        let mut out = format!("# {}\n", self.title);
+        for (i, step) in self.steps.iter().enumerate() {
+            let _ = writeln!(out, "\n{}. {} ({})", i + 1, step.title, step.name);
+            if !step.pending {
+                let _ = writeln!(out, "   done already, skipped");
+                continue;
+            }
+            for action in &step.actions {
+                let _ = writeln!(out, "   {}", action.replace('\n', "\n   "));
+            }
+        }
+        out
+    }
+
+}
+
+/// How `install` prints the plan or, without --plan, the outcome.
+#[derive(Clone, Copy, Debug, PartialEq, ValueEnum)]
+pub enum Format {
+    /// For people, the same for the same host and options.
+    Text,
+    /// The plan as JSON with stable action ids and the pending changes, see MachinePlan.
+    Tfjson,
+    /// The outcome as JSON, see output::InstallResult, everything else goes to stderr.  With
+    /// --plan the same as tfjson.
+    Json,
+}
+
+/// Version of the machine readable plan format, bumped on incompatible changes.
+pub const FORMAT_VERSION: &str = "2.0";
+
+/// What applying an action does to the host as it is now.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Change {
+    Create,
+    Update,
+    Delete,
+    /// Already the way the action would leave it.
+    NoOp,
+    /// Checks, refreshes and bookkeeping, they run whenever anything else changes.
+    Run,
+}
+
+impl Change {
+
+    fn changes(self) -> bool {
+        matches!(self, Change::Create | Change::Update | Change::Delete)
+    }
+
+}
+
+/// One action of a machine readable plan.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct Action {
+    /// `<step>/<kind>:<target>`, stays the same across runs and installer versions as long as
+    /// the action does the same thing.
+    pub id: String,
+    pub step: String,
+    pub description: String,
+    pub change: Change,
+}
+
+/// `install --plan --output tfjson`: the plan with an identifier and the pending change of
+/// every action, for provisioning tools that need change detection.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MachinePlan {
+    pub format_version: String,
+    pub installer_version: String,
+    pub title: String,
+    /// What to install, `install --from-plan` applies the plan with these.  The license key
+    /// is masked, --from-plan takes it from BITFLUX_LICENSE_KEY.
+    pub options: Options,
+    pub actions: Vec<Action>,
+    /// Number of actions that create, update or delete something.
+    pub changes: usize,
+    /// Every install step by name, true when the engine's check finds it not done.  None
+    /// pending means applying the plan does nothing.
+    pub steps: BTreeMap<String, bool>,
+}
+
+/// Looks at the host for change detection.
+pub trait State {
+
+    /// Contents of the file at `path`, None if there's none.
+    fn file(&self, path: &str) -> Option<String>;
+
+    fn installed(&self, package: &str) -> bool;
+
+    fn enabled(&self, unit: &str) -> bool;
+
+}
+
+/// The host the installer runs on.
+pub struct LiveState {
+    pub pm: Option<PackageManager>,
+}
+
+impl State for LiveState {
+
+    fn file(&self, path: &str) -> Option<String> {
+        fs::read_to_string(path).ok()
+    }
+
+    fn installed(&self, package: &str) -> bool {
+        self.pm.as_ref().is_some_and(|pm| pm.installed_version(package).is_some())
+    }
+
+    fn enabled(&self, unit: &str) -> bool {
+        Service::new(unit).is_enabled()
+    }
+
+}
+
+/// A host nothing was installed on, for plans of fixtures.
+pub struct EmptyState;
+
+impl State for EmptyState {
+
+    fn file(&self, _path: &str) -> Option<String> {
+        None
+    }
+
+    fn installed(&self, _package: &str) -> bool {
+        false
+    }
+
+    fn enabled(&self, _unit: &str) -> bool {
+        false
+    }
+
+}
+
+/// The id and pending change of `action` of `step`.  Checks and the like are known by their
+/// first line, never by where they are.
+fn classify(step: &str, action: &str, state: &dyn State) -> (String, Change) {
+    let first = action.lines().next().unwrap_or("");
+    let words: Vec<&str> = first.split_whitespace().collect();
+    let id = |kind: &str, target: &str| format!("{}/{}:{}", step, kind, target);
+    match words.as_slice() {
+        ["write", path] => {
+            let contents: String = action.lines().skip(1).map(|l| format!("{}\n", l.trim_start().trim_start_matches("| ").trim_start_matches('|'))).collect();
+            let change = match state.file(path) {
+                None => Change::Create,
+                Some(current) if current == contents => Change::NoOp,
+                Some(_) => Change::Update,
+            };
+            (id("file", path), change)
+        }
+        ["rm", "-f", path] => (id("absent", path), if state.file(path).is_some() { Change::Delete } else { Change::NoOp }),
+        // Downloads land next to the file and are moved over it once verified.
+        ["mv", _, path] => (id("file", path), if state.file(path).is_some() { Change::NoOp } else { Change::Create }),
+        ["systemctl", "enable", rest @ ..] => {
+            let unit = rest.iter().find(|w| !w.starts_with('-')).copied().unwrap_or_default();
+            (id("service", unit), if state.enabled(unit) { Change::NoOp } else { Change::Create })
+        }
+        [.., "install", _, package] | [.., "install", package] if !package.starts_with(['-', '<']) => {
+            (id("package", package), if state.installed(package) { Change::NoOp } else { Change::Create })
+        }
+        _ => (id("run", first.trim()), Change::Run),
+    }
+}
+
+impl Plan {
+
+    /// The plan with stable action ids and what each would change on the host `state` describes.
+    pub fn machine(&self, opts: &Options, state: &dyn State) -> MachinePlan {
+        let mut actions = Vec::new();
+        let mut seen: BTreeMap<String, usize> = BTreeMap::new();
+        for step in &self.steps {
+            for action in &step.actions {
+                let (mut id, change) = classify(&step.name, action, state);
+                // The same check twice in a step, the later ones are counted.
+                let n = seen.entry(id.clone()).or_insert(0);
+                *n += 1;
+                if *n > 1 {
+                    id = format!("{}#{}", id, n);
+                }
+                actions.push(Action { id, step: step.name.clone(), description: action.clone(), change });
+            }
+        }
+        MachinePlan {
+            format_version: String::from(FORMAT_VERSION),
+            installer_version: String::from(env!("CARGO_PKG_VERSION")),
+            title: self.title.clone(),
+            options: opts.redacted(),
+            changes: actions.iter().filter(|a| a.change.changes()).count(),
+            actions,
+            steps: self.steps.iter().map(|step| (step.name.clone(), step.pending)).collect(),
+        }
+    }
+
+}
+
+impl MachinePlan {
+
+    /// What applying the plan changes, packages, files and services, and the steps it runs, for
+    /// asking before an install like apt does.
+    pub fn summary(&self) -> String {
+        let targets = |kind: &str, change: Change| -> Vec<&str> {
+            let prefix = format!("/{}:", kind);
+            self.actions.iter()
+                .filter(|a| a.change == change)
+                .filter_map(|a| a.id.split_once(&prefix).map(|(_, target)| target))
+                .collect()
+        };
+        let mut out = String::new();
+        for (title, kind, change) in [
+            ("Packages to install", "package", Change::Create),
+            ("Files to create", "file", Change::Create),
+            ("Files to change", "file", Change::Update),
+            ("Files to remove", "absent", Change::Delete),
+            ("Services to enable", "service", Change::Create),
+        ] {
+            let targets = targets(kind, change);
+            if !targets.is_empty() {
+                let _ = writeln!(out, "{}: {}", title, targets.join(", "));
+            }
+        }
+        let mut steps: Vec<&str> = self.actions.iter().map(|a| a.step.as_str()).collect();
+        steps.dedup();
+        let _ = writeln!(out, "Steps: {}", steps.join(", "));
+        out
+    }
+
+}
+
+/// Names of the steps that differ between the `saved` and the `current` plan: added, removed,
+/// or found done by one check and not the other.
+pub fn drift(saved: &MachinePlan, current: &MachinePlan) -> Vec<String> {
+    let mut names: Vec<String> = saved.steps.iter()
+        .filter(|(name, pending)| current.steps.get(*name) != Some(pending))
+        .chain(current.steps.iter().filter(|(name, _)| !saved.steps.contains_key(*name)))
+        .map(|(name, _)| name.clone())
+        .collect();
+    names.sort();
+    names
+}
+
+/// A host described by a fixture directory: its os-release file plus `kernel`, `arch`,
+/// `page_size`, `raspberry_pi`, `transactional`, `secure_boot` and `container` from fixture.json.
+pub fn host_fixture<P: AsRef<Path>>(dir: P) -> io::Result<Host> {
+    let dir = dir.as_ref();
+    let os = OsRelease::parse(&fs::read_to_string(dir.join("os-release"))?);
+    let fixture: serde_json::Value = serde_json::from_str(&fs::read_to_string(dir.join("fixture.json"))?)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let str_field = |key: &str| fixture[key].as_str().map(String::from)
+        .ok_or_else(|| io::Error::other(format!("{}: fixture.json has no {}", dir.display(), key)));
+    let arch = str_field("arch")?;
+    Ok(Host {
+        os,
+        kernel: str_field("kernel")?,
+        arch: Arch::from_name(&arch).ok_or_else(|| io::Error::other(format!("Unknown architecture {}.", arch)))?,
+        page_size: fixture["page_size"].as_u64().unwrap_or(4096),
+        raspberry_pi: fixture["raspberry_pi"].as_bool().unwrap_or(false),
+        transactional: fixture["transactional"].as_bool().unwrap_or(false),
+        secure_boot: fixture["secure_boot"].as_bool().unwrap_or(false),
+        container: fixture["container"].as_str().map(String::from),
+    })
+}
+
+/// The install options of the "answers" in a fixture directory's fixture.json.
+pub fn answers_fixture<P: AsRef<Path>>(dir: P) -> io::Result<Options> {
+    let fixture: serde_json::Value = serde_json::from_str(&fs::read_to_string(dir.as_ref().join("fixture.json"))?)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    match fixture.get("answers") {
+        Some(answers) => serde_json::from_value(answers.clone()).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        None => Ok(Options::default()),
+    }
+}
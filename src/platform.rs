@@ -0,0 +1,365 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::arch::Arch;
+use crate::detect;
+use crate::exitcode::Kind;
+use crate::kernel::Bootloader;
+use crate::mac::Mac;
+use crate::pkg::PackageManager;
+use crate::runcmd::which;
+use crate::sbc;
+use crate::virt::Virt;
+use crate::writable::mount_of;
+
+pub const OS_RELEASE_PATH: &str = "/etc/os-release";
+
+/// Enterprise Linux major releases with bitflux packages.
+pub const SUPPORTED_EL: &[u32] = &[8, 9];
+/// Amazon Linux releases with bitflux packages, VERSION_ID of AL2 and AL2023.
+pub const SUPPORTED_AMAZON: &[&str] = &["2", "2023"];
+/// Ubuntu LTS and Debian stable releases with bitflux packages, as (ID, VERSION_ID).
+pub const SUPPORTED_DEBIAN: &[(&str, &str)] = &[
+    ("ubuntu", "20.04"),
+    ("ubuntu", "22.04"),
+    ("ubuntu", "24.04"),
+    ("debian", "11"),
+    ("debian", "12"),
+];
+
+/// Distro families that share packaging and boot tooling.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Family {
+    Debian,
+    /// RHEL and its rebuilds (Alma, Rocky, CentOS Stream, Oracle), plus Fedora and Amazon Linux.
+    Rhel,
+    /// SLES, openSUSE Leap and Tumbleweed, and the transactional MicroOS and SLE Micro.
+    Suse,
+}
+
+/// The fields of /etc/os-release the installer cares about.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct OsRelease {
+    pub id: String,
+    pub id_like: Vec<String>,
+    pub version_id: String,
+    /// "jammy", "bookworm", the apt suite.  Empty on distros without one.
+    pub codename: String,
+    pub pretty_name: String,
+}
+
+impl OsRelease {
+
+    pub fn parse(data: &str) -> OsRelease {
+        let mut os = OsRelease::default();
+        for line in data.lines() {
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            let value = value.trim().trim_matches(|c| c == '"' || c == '\'').to_string();
+            match key.trim() {
+                "ID" => os.id = value.to_lowercase(),
+                "ID_LIKE" => os.id_like = value.split_whitespace().map(str::to_lowercase).collect(),
+                "VERSION_ID" => os.version_id = value,
+                "VERSION_CODENAME" => os.codename = value,
+                // Ubuntu derivatives carry the suite they build on here.
+                "UBUNTU_CODENAME" if os.codename.is_empty() => os.codename = value,
+                "PRETTY_NAME" => os.pretty_name = value,
+                _ => {}
+            }
+        }
+        os
+    }
+
+    pub fn load() -> io::Result<OsRelease> {
+        Ok(OsRelease::parse(&fs::read_to_string(OS_RELEASE_PATH)?))
+    }
+
+    /// True if the distro is `id` or says it is like it.
+    pub fn is_like(&self, id: &str) -> bool {
+        self.id == id || self.id_like.iter().any(|l| l == id)
+    }
+
+    pub fn family(&self) -> Option<Family> {
+        if self.is_like("debian") || self.is_like("ubuntu") {
+            Some(Family::Debian)
+        } else if self.is_like("rhel") || self.is_like("fedora") || self.is_like("centos") {
+            Some(Family::Rhel)
+        } else if self.is_like("suse") || self.id.starts_with("opensuse") || self.id.starts_with("sle") {
+            Some(Family::Suse)
+        } else {
+            None
+        }
+    }
+
+    /// Major release of an Enterprise Linux, "9.3" is 9.  None for Fedora, Amazon Linux and
+    /// everything else.
+    pub fn el_major(&self) -> Option<u32> {
+        if self.family() != Some(Family::Rhel) || self.id == "fedora" || self.is_amazon() {
+            return None;
+        }
+        self.major()
+    }
+
+    pub fn is_amazon(&self) -> bool {
+        self.id == "amzn"
+    }
+
+    /// Directory of the bitflux rpm repo for this release, "el9", "amzn2023" or "sle15".
+    /// Tumbleweed and MicroOS are rolling and share one tree.
+    pub fn rpm_tree(&self) -> Option<String> {
+        if self.is_amazon() {
+            return Some(format!("amzn{}", self.version_id));
+        }
+        if self.family() == Some(Family::Suse) {
+            if self.id == "opensuse-tumbleweed" || self.id == "opensuse-microos" {
+                return Some(String::from("tumbleweed"));
+            }
+            return self.major().map(|major| format!("sle{}", major));
+        }
+        self.el_major().map(|major| format!("el{}", major))
+    }
+
+    fn major(&self) -> Option<u32> {
+        self.version_id.split('.').next()?.parse().ok()
+    }
+
+    /// Ubuntu switched to deb822 .sources files in 24.04 and Debian in 13, older releases still
+    /// use one-line .list entries.
+    pub fn uses_deb822(&self) -> bool {
+        match self.id.as_str() {
+            "ubuntu" => self.major().is_some_and(|m| m >= 24),
+            "debian" => self.major().is_some_and(|m| m >= 13),
+            _ => false,
+        }
+    }
+
+    /// Refuses releases bitflux doesn't build for: EL7 and older, non-LTS Ubuntu and Debian
+    /// oldoldstable.  Derivatives aren't in the matrix and are let through.
+    pub fn check_supported(&self) -> io::Result<()> {
+        if self.id == "ubuntu" || self.id == "debian" {
+            if SUPPORTED_DEBIAN.contains(&(self.id.as_str(), self.version_id.as_str())) {
+                return Ok(());
+            }
+            return Err(Kind::Unsupported.error(format!(
+                "{} is not supported, bitflux supports {}.",
+                self.pretty_name,
+                SUPPORTED_DEBIAN.iter().map(|(id, v)| format!("{} {}", id, v)).collect::<Vec<String>>().join(", ")
+            )));
+        }
+        if self.is_amazon() && !SUPPORTED_AMAZON.contains(&self.version_id.as_str()) {
+            return Err(Kind::Unsupported.error(format!("{} is not supported, bitflux supports Amazon Linux 2 and 2023.", self.pretty_name)));
+        }
+        match self.el_major() {
+            Some(major) if !SUPPORTED_EL.contains(&major) => Err(Kind::Unsupported.error(format!(
+                "{} is not supported, bitflux needs Enterprise Linux {}.",
+                self.pretty_name,
+                SUPPORTED_EL.iter().map(u32::to_string).collect::<Vec<String>>().join(" or ")
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+}
+
+/// What runs services on the host, PID 1 or what it hands them to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Init {
+    Systemd,
+    OpenRc,
+    /// /etc/init.d scripts run by sysvinit, busybox init and the like.
+    SysV,
+}
+
+impl Init {
+
+    /// The init system of the host whose filesystem is at `root`, "/" for this one.  systemd
+    /// and OpenRC leave a directory in /run once they're up, so a chroot or container image
+    /// that merely has them installed isn't mistaken for running them.  SysV leaves nothing, it
+    /// takes /etc/init.d with init as pid 1.
+    pub fn detect_in<P: AsRef<Path>>(root: P) -> Option<Init> {
+        let root = root.as_ref();
+        if root.join("run/systemd/system").is_dir() {
+            Some(Init::Systemd)
+        } else if root.join("run/openrc").is_dir() {
+            Some(Init::OpenRc)
+        } else if root.join("etc/init.d").is_dir() && fs::read_to_string(root.join("proc/1/comm")).is_ok_and(|c| c.trim() == "init") {
+            Some(Init::SysV)
+        } else {
+            None
+        }
+    }
+
+    pub fn detect() -> Option<Init> {
+        Init::detect_in("/")
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Init::Systemd => "systemd",
+            Init::OpenRc => "OpenRC",
+            Init::SysV => "SysV init",
+        }
+    }
+
+}
+
+/// What the install steps need to know about the host they run on.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Platform {
+    pub os: OsRelease,
+    pub pm: PackageManager,
+    /// `uname -r`.
+    pub kernel: String,
+    pub arch: Arch,
+    /// Bytes, see arch::page_size().
+    pub page_size: u64,
+    pub init: Option<Init>,
+    /// Packages go into a transactional-update snapshot, see is_transactional().
+    pub transactional: bool,
+    pub virt: Virt,
+    /// None where it's one the installer doesn't know.
+    pub bootloader: Option<Bootloader>,
+    pub mac: Mac,
+    /// A single-board computer, see sbc::is_sbc().
+    pub sbc: bool,
+    pub raspberry_pi: bool,
+}
+
+impl Platform {
+
+    /// This host, the slow parts from the detection cache when it's still valid.  Fails where
+    /// there's nothing the installer could work with.
+    pub fn detect() -> io::Result<Platform> {
+        let detected = detect::load()?;
+        let pm = PackageManager::detect().ok_or_else(|| Kind::Unsupported.error("No supported package manager found."))?;
+        Ok(Platform {
+            os: detected.os(),
+            pm,
+            kernel: detected.key.kernel.clone(),
+            arch: detected.arch(),
+            page_size: detected.page_size,
+            init: Init::detect(),
+            transactional: detected.transactional && pm == PackageManager::Zypper,
+            virt: Virt::detect(),
+            bootloader: Bootloader::detect(),
+            mac: Mac::detect(),
+            sbc: sbc::is_sbc(),
+            raspberry_pi: detected.raspberry_pi,
+        })
+    }
+
+    /// PackageManager::kernel_package() for this platform.
+    pub fn kernel_package(&self) -> &'static str {
+        self.pm.kernel_package_on(self.arch, self.page_size, &self.kernel, self.raspberry_pi)
+    }
+
+    pub fn family(&self) -> Option<Family> {
+        self.os.family()
+    }
+
+    /// The agent runs as a systemd, OpenRC or SysV init service, refuse to install it where
+    /// none of them runs.
+    pub fn check_init(&self) -> io::Result<()> {
+        if self.init.is_some() {
+            return Ok(());
+        }
+        let message = "bitflux runs as a systemd, OpenRC or SysV init service, and none of them is running.";
+        match self.virt.systemd_help() {
+            Some(help) => Err(Kind::Unsupported.error(format!("{} {}", message, help))),
+            None => Err(Kind::Unsupported.error(message)),
+        }
+    }
+
+}
+
+/// True on systems with a read-only root that is changed through transactional-update
+/// snapshots, like openSUSE MicroOS and SLE Micro.
+pub fn is_transactional() -> bool {
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo").unwrap_or_default();
+    which("transactional-update").is_some() && matches!(mount_of(&mountinfo, Path::new("/")), Some((_, _, true)))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ROCKY9: &str = "\
+NAME=\"Rocky Linux\"
+VERSION=\"9.3 (Blue Onyx)\"
+ID=\"rocky\"
+ID_LIKE=\"rhel centos fedora\"
+VERSION_ID=\"9.3\"
+PRETTY_NAME=\"Rocky Linux 9.3 (Blue Onyx)\"
+";
+
+    #[test]
+    fn el_releases() {
+        let os = OsRelease::parse(ROCKY9);
+        assert_eq!(os.family(), Some(Family::Rhel));
+        assert_eq!(os.el_major(), Some(9));
+        assert!(os.check_supported().is_ok());
+
+        let centos7 = OsRelease::parse("ID=\"centos\"\nID_LIKE=\"rhel fedora\"\nVERSION_ID=\"7\"\nPRETTY_NAME=\"CentOS Linux 7 (Core)\"\n");
+        assert_eq!(centos7.el_major(), Some(7));
+        assert!(centos7.check_supported().is_err());
+
+        let fedora = OsRelease::parse("ID=fedora\nVERSION_ID=39\n");
+        assert_eq!(fedora.family(), Some(Family::Rhel));
+        assert_eq!(fedora.el_major(), None);
+        assert_eq!(os.rpm_tree().as_deref(), Some("el9"));
+
+        let al2023 = OsRelease::parse("NAME=\"Amazon Linux\"\nID=\"amzn\"\nID_LIKE=\"fedora\"\nVERSION_ID=\"2023\"\n");
+        assert_eq!(al2023.family(), Some(Family::Rhel));
+        assert_eq!(al2023.el_major(), None);
+        assert_eq!(al2023.rpm_tree().as_deref(), Some("amzn2023"));
+        assert!(al2023.check_supported().is_ok());
+        assert!(OsRelease::parse("ID=\"amzn\"\nVERSION_ID=\"2018.03\"\n").check_supported().is_err());
+        assert_eq!(OsRelease::parse("ID=ubuntu\nID_LIKE=debian\n").family(), Some(Family::Debian));
+    }
+
+    #[test]
+    fn debian_releases() {
+        let noble = OsRelease::parse("ID=ubuntu\nID_LIKE=debian\nVERSION_ID=\"24.04\"\nVERSION_CODENAME=noble\n");
+        assert_eq!(noble.codename, "noble");
+        assert!(noble.uses_deb822());
+        assert!(noble.check_supported().is_ok());
+
+        let jammy = OsRelease::parse("ID=ubuntu\nVERSION_ID=\"22.04\"\n");
+        assert!(!jammy.uses_deb822());
+        assert!(OsRelease::parse("ID=ubuntu\nVERSION_ID=\"23.10\"\n").check_supported().is_err());
+        assert!(OsRelease::parse("ID=debian\nVERSION_ID=\"10\"\n").check_supported().is_err());
+
+        let mint = OsRelease::parse("ID=linuxmint\nID_LIKE=\"ubuntu debian\"\nVERSION_ID=\"21.3\"\nUBUNTU_CODENAME=jammy\n");
+        assert_eq!(mint.codename, "jammy");
+        assert!(mint.check_supported().is_ok());
+    }
+
+    #[test]
+    fn init_system_of_root() {
+        let root = std::env::temp_dir().join(format!("bitflux-init-{}", std::process::id()));
+        fs::create_dir_all(root.join("etc/init.d")).unwrap();
+        assert_eq!(Init::detect_in(&root), None);
+        fs::create_dir_all(root.join("proc/1")).unwrap();
+        fs::write(root.join("proc/1/comm"), "init\n").unwrap();
+        assert_eq!(Init::detect_in(&root), Some(Init::SysV));
+        fs::create_dir_all(root.join("run/openrc")).unwrap();
+        assert_eq!(Init::detect_in(&root), Some(Init::OpenRc));
+        fs::create_dir_all(root.join("run/systemd/system")).unwrap();
+        assert_eq!(Init::detect_in(&root), Some(Init::Systemd));
+        fs::remove_dir_all(&root).unwrap();
+        assert_eq!(Init::detect_in(&root), None);
+    }
+
+    #[test]
+    fn suse_releases() {
+        let micro = OsRelease::parse("NAME=\"SLE Micro\"\nID=\"sle-micro\"\nID_LIKE=\"suse\"\nVERSION_ID=\"5.5\"\n");
+        assert_eq!(micro.family(), Some(Family::Suse));
+        assert_eq!(OsRelease::parse("ID=\"opensuse-tumbleweed\"\nID_LIKE=\"opensuse suse\"\n").family(), Some(Family::Suse));
+        assert_eq!(OsRelease::parse("ID=\"sles\"\nVERSION_ID=\"15.5\"\n").rpm_tree().as_deref(), Some("sle15"));
+        assert_eq!(OsRelease::parse("ID=\"opensuse-microos\"\nVERSION_ID=\"20240301\"\n").rpm_tree().as_deref(), Some("tumbleweed"));
+    }
+
+}
@@ -0,0 +1,570 @@
+use std::fs;
+use std::io;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::arch::{self, Arch};
+use crate::batch::{self, Job};
+use crate::bundle::Bundle;
+use crate::diskspace;
+use crate::exitcode::Kind;
+use crate::kernel::running_kernel;
+use crate::kernelmatrix::Matrix;
+use crate::license;
+use crate::log;
+use crate::output::{self, Format};
+use crate::pkg::{PackageManager, AGENT_PACKAGE};
+use crate::platform::OsRelease;
+use crate::privsep::{self, unprivileged_cmd};
+use crate::profile::Profile;
+use crate::release;
+use crate::repo::REPO_URL;
+use crate::runcmd::{self, escalation_tool, which, RunCmd};
+use crate::sanitize::{self, SAFE_PATH};
+use crate::selinux;
+use crate::tls;
+use crate::virt::Virt;
+
+pub const REPO_HOST: &str = "mirror.bitflux.ai";
+/// Oldest kernel series bitflux runs on, the EL8 kernel.
+pub const MIN_KERNEL: (u32, u32) = (4, 18);
+/// Free space the packages and backups need in /var.
+pub const MIN_VAR_MIB: u64 = 500;
+/// Free space the agent takes in /usr, and the bitflux kernel's modules besides.
+pub const MIN_USR_AGENT_MIB: u64 = 100;
+pub const MIN_USR_KERNEL_MIB: u64 = 300;
+/// Clock skew beyond which TLS and signature checks start failing.
+pub const MAX_SKEW_SECS: u64 = 300;
+/// Daemons that act on memory pressure themselves and fight the agent over what to reclaim.
+pub const CONFLICTING_PACKAGES: &[&str] = &["earlyoom", "nohang", "systemd-oomd"];
+const NETWORK_TIMEOUT_SECS: &str = "5";
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Pass,
+    Warn,
+    Fail,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Check {
+    pub name: String,
+    pub status: Status,
+    pub detail: String,
+}
+
+pub(crate) fn check(name: &str, status: Status, detail: String) -> Check {
+    Check { name: String::from(name), status, detail }
+}
+
+fn privileges() -> Check {
+    if privsep::is_root() {
+        return check("root", Status::Pass, String::from("running as root"));
+    }
+    // Packages, the account and the files are put in place by the installer itself, none of
+    // that goes through an escalation tool.
+    match escalation_tool() {
+        Some(tool) => check("root", Status::Fail, format!("not root, run the installer through {}", tool.display())),
+        None => check("root", Status::Fail, String::from("not root, run the installer as root")),
+    }
+}
+
+fn distro() -> Check {
+    let Ok(os) = OsRelease::load() else {
+        return check("distro", Status::Fail, String::from("can't read /etc/os-release"));
+    };
+    if os.family().is_none() {
+        return check("distro", Status::Fail, format!("{} isn't a distro bitflux has packages for", os.pretty_name));
+    }
+    match os.check_supported() {
+        Ok(()) => check("distro", Status::Pass, os.pretty_name),
+        Err(e) => check("distro", Status::Fail, e.to_string()),
+    }
+}
+
+fn conflicts() -> Check {
+    let Some(pm) = PackageManager::detect() else {
+        return check("conflicts", Status::Warn, String::from("no package manager to ask"));
+    };
+    let installed: Vec<&str> = CONFLICTING_PACKAGES.iter().copied().filter(|p| pm.installed_version(p).is_some()).collect();
+    match installed.is_empty() {
+        true => check("conflicts", Status::Pass, String::from("no conflicting packages")),
+        false => check("conflicts", Status::Warn, format!("{} installed, it also acts on memory pressure", installed.join(", "))),
+    }
+}
+
+/// The check of running in `container` or `vm`, as named by systemd-detect-virt.  A container
+/// shares the host's kernel, so only the agent can go in.
+pub fn virtualization(container: Option<&str>, vm: Option<&str>) -> Check {
+    environment(&Virt::from_names(container, vm, false))
+}
+
+/// The check of running in `virt`, which only warns where the kernel can't be installed.
+pub fn environment(virt: &Virt) -> Check {
+    match virt {
+        Virt::Container(_) => check("virt", Status::Warn, format!("{}, only the agent profile can be installed", virt.describe())),
+        Virt::Wsl => check("virt", Status::Warn, String::from("WSL2, the kernel comes from Windows, only the agent profile can be installed")),
+        Virt::Vm(_) | Virt::BareMetal => check("virt", Status::Pass, virt.describe()),
+    }
+}
+
+fn virt() -> Check {
+    environment(&Virt::detect())
+}
+
+fn path() -> Check {
+    let untrusted = sanitize::resolve();
+    if !untrusted.is_empty() {
+        return check("path", Status::Fail, format!("not running programs others can replace as root: {}", untrusted.join(", ")));
+    }
+    let risky = sanitize::risky_entries(&std::env::var("PATH").unwrap_or_default());
+    match (risky.is_empty(), runcmd::Context::current().keep_env) {
+        (true, _) => check("path", Status::Pass, format!("commands as root run with {}", SAFE_PATH)),
+        (false, true) => check("path", Status::Warn, format!("PATH has {}, and --keep-env passes it to commands as root", risky.join(", "))),
+        (false, false) => check("path", Status::Pass, format!("PATH has {}, commands as root run with {} instead", risky.join(", "), SAFE_PATH)),
+    }
+}
+
+fn architecture() -> Check {
+    let machine = arch::machine();
+    match arch::check(&machine, Arch::current()) {
+        Ok(arch) if arch.name() == machine => check("arch", Status::Pass, machine),
+        Ok(arch) => check("arch", Status::Pass, format!("{}, installing the {} packages", machine, arch.name())),
+        Err(e) => check("arch", Status::Fail, e),
+    }
+}
+
+/// What an install of a profile needs before it starts, free space and memory.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Requirements {
+    pub profile: Profile,
+    /// MiB free per directory, the ones on one filesystem add up.
+    pub space: Vec<(&'static str, u64)>,
+    pub memory_mib: u64,
+}
+
+impl Requirements {
+
+    /// An install of `profile` on `arch`.  The agent on its own gets by with half the memory,
+    /// and without room for a kernel in /boot.
+    pub fn of(profile: Profile, arch: Arch) -> Requirements {
+        let (space, memory_mib) = match profile.kernel() {
+            true => (vec![("/boot", arch.min_boot_mib()), ("/usr", MIN_USR_AGENT_MIB + MIN_USR_KERNEL_MIB), ("/var", MIN_VAR_MIB)], arch.min_memory_mib()),
+            false => (vec![("/usr", MIN_USR_AGENT_MIB), ("/var", MIN_VAR_MIB)], arch.min_memory_mib() / 2),
+        };
+        Requirements { profile, space, memory_mib }
+    }
+
+    /// The memory check of a host with `total` MiB.
+    pub fn memory(&self, total: u64, arch: Arch) -> Check {
+        if total >= self.memory_mib {
+            return check("memory", Status::Pass, format!("{} MiB", total));
+        }
+        let mut detail = format!("{} MiB, the {} profile needs {} MiB on {}", total, self.profile.name(), self.memory_mib, arch.rpm());
+        match self.profile.kernel() {
+            true => detail.push_str(&format!(", add memory or install --profile agent, which needs {} MiB", Requirements::of(Profile::Agent, arch).memory_mib)),
+            false => detail.push_str(", add memory"),
+        }
+        check("memory", Status::Fail, detail)
+    }
+
+}
+
+fn disk(requirements: &Requirements) -> Check {
+    let low = diskspace::short(&requirements.space);
+    if low.is_empty() {
+        let paths: Vec<&str> = requirements.space.iter().map(|(path, _)| *path).collect();
+        return check("disk", Status::Pass, format!("enough free space in {}", paths.join(", ")));
+    }
+    let pm = PackageManager::detect();
+    let short: Vec<String> = low.iter()
+        .map(|l| {
+            let mut text = format!("{} has {} MiB free, needs {} MiB", l.paths(), l.free_mib, l.threshold_mib);
+            if let Some(pm) = &pm {
+                text.push_str(&format!(" (free some with: {})", l.suggestions(pm).join("; ")));
+            }
+            text
+        })
+        .collect();
+    check("disk", Status::Fail, short.join(", "))
+}
+
+/// MemTotal in MiB from /proc/meminfo formatted `data`.
+pub fn mem_total_mib(data: &str) -> Option<u64> {
+    let line = data.lines().find(|l| l.starts_with("MemTotal:"))?;
+    let kib: u64 = line.split_whitespace().nth(1)?.parse().ok()?;
+    Some(kib / 1024)
+}
+
+fn memory(requirements: &Requirements, arch: Arch) -> Check {
+    let total = fs::read_to_string("/proc/meminfo").ok().and_then(|m| mem_total_mib(&m)).unwrap_or(0);
+    requirements.memory(total, arch)
+}
+
+/// (major, minor) of a `uname -r` string.
+pub fn kernel_series(release: &str) -> Option<(u32, u32)> {
+    let mut parts = release.split(|c: char| !c.is_ascii_digit());
+    Some((parts.next()?.parse().ok()?, parts.next()?.parse().ok()?))
+}
+
+fn kernel() -> Check {
+    let release = running_kernel().unwrap_or_default();
+    match kernel_series(&release) {
+        Some(series) if series >= MIN_KERNEL => check("kernel", Status::Pass, release),
+        _ => check("kernel", Status::Fail, format!("'{}' is older than {}.{}", release, MIN_KERNEL.0, MIN_KERNEL.1)),
+    }
+}
+
+fn selinux_tools() -> Check {
+    if !selinux::is_enforcing() {
+        return check("selinux", Status::Pass, String::from("not enforcing"));
+    }
+    match which("semanage") {
+        Some(_) => check("selinux", Status::Pass, String::from("enforcing, semanage available")),
+        None => check("selinux", Status::Warn, String::from("enforcing but semanage is missing, file contexts can't be registered")),
+    }
+}
+
+fn dns() -> Check {
+    match RunCmd::args("getent", &["hosts", REPO_HOST]).execute_output().exitcode {
+        0 => check("dns", Status::Pass, format!("{} resolves", REPO_HOST)),
+        _ => check("dns", Status::Fail, format!("can't resolve {}", REPO_HOST)),
+    }
+}
+
+/// Response headers of a HEAD request to `url`, None if it can't be reached.
+fn head(url: &str) -> Option<String> {
+    let tls = tls::hardened_curl_args();
+    let mut args = vec!["-sSI", "--max-time", NETWORK_TIMEOUT_SECS];
+    args.extend(tls.iter().map(String::as_str));
+    args.extend_from_slice(&["--", url]);
+    let out = unprivileged_cmd("curl", &args).execute_output();
+    if out.exitcode != 0 {
+        return None;
+    }
+    Some(out.stdout)
+}
+
+/// Seconds since the epoch of an HTTP Date, "Tue, 15 Oct 2024 10:00:00 GMT".
+pub fn parse_http_date(date: &str) -> Option<u64> {
+    let fields: Vec<&str> = date.split_whitespace().collect();
+    let [_, day, month, year, time, _] = fields[..] else {
+        return None;
+    };
+    let months = ["Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec"];
+    let month = months.iter().position(|m| *m == month)? as i64 + 1;
+    let hms: Vec<i64> = time.split(':').map(|t| t.parse().ok()).collect::<Option<Vec<i64>>>()?;
+    let [h, m, s] = hms[..] else {
+        return None;
+    };
+    let days = log::days_from_civil(year.parse().ok()?, month, day.parse().ok()?);
+    u64::try_from(days * 86400 + h * 3600 + m * 60 + s).ok()
+}
+
+/// The time of the Date header in `headers`.
+fn date(headers: &str) -> Option<u64> {
+    headers.lines()
+        .find_map(|l| l.split_once(':').filter(|(k, _)| k.eq_ignore_ascii_case("date")).map(|(_, v)| v.trim().to_string()))
+        .and_then(|d| parse_http_date(&d))
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+/// Seconds this host's clock is off the one of the server at `url`, None when the server can't
+/// be reached or doesn't say.
+pub fn skew(url: &str) -> Option<u64> {
+    head(url).and_then(|headers| date(&headers)).map(|server| server.abs_diff(now()))
+}
+
+/// The check `name` of a clock `skew` seconds off `server`'s, None when it's unknown.  `stake`
+/// is what fails when it's off.
+pub fn clock(name: &str, skew: Option<u64>, server: &str, stake: &str) -> Check {
+    match skew {
+        Some(skew) if skew > MAX_SKEW_SECS => {
+            check(name, Status::Fail, format!("clock is {}s off {}, {}; fix NTP first or pass --fix-clock", skew, server, stake))
+        }
+        Some(_) => check(name, Status::Pass, format!("in sync with {}", server)),
+        None => check(name, Status::Warn, format!("{} sent no usable Date header", server)),
+    }
+}
+
+fn network_and_clock() -> Vec<Check> {
+    let Some(headers) = head(REPO_URL) else {
+        return vec![
+            check("network", Status::Fail, format!("{} is unreachable", REPO_URL)),
+            check("clock", Status::Warn, String::from("no server time to compare with")),
+        ];
+    };
+    let network = check("network", Status::Pass, format!("{} reachable", REPO_URL));
+    let skew = date(&headers).map(|server| server.abs_diff(now()));
+    vec![network, clock("clock", skew, "the repository", "TLS and signature checks fail")]
+}
+
+/// The clock against the license server's, which refuses an activation from a clock that's off
+/// with an error that doesn't say so.
+pub fn license_clock() -> Check {
+    let url = license::activation_url();
+    match head(&url) {
+        None => check("license", Status::Warn, format!("{} is unreachable, the license can't be activated", url)),
+        Some(headers) => clock("license", date(&headers).map(|server| server.abs_diff(now())), "the license server", "the activation is refused"),
+    }
+}
+
+/// Lets `checks` of the clock pass with a warning, for an install that sets the clock first.
+pub fn fixing_clock(checks: &mut [Check]) {
+    for c in checks.iter_mut().filter(|c| matches!(c.name.as_str(), "clock" | "license") && c.status == Status::Fail) {
+        c.status = Status::Warn;
+        c.detail = format!("{}, --fix-clock sets it first", c.detail.split(';').next().unwrap_or_default());
+    }
+}
+
+/// The check "series" of the kernel swaphints goes into against the compatibility matrix: with
+/// `dkms` the running one it's built for, otherwise the bitflux kernel in `bundle`.  The one
+/// from the repository is only known once that's set up, the kernel step checks it.  None when
+/// `kernel` is false, or there's nothing to check yet.
+pub fn kernel_support(pm: &PackageManager, kernel: bool, dkms: bool, bundle: Option<&Bundle>) -> Option<Check> {
+    let matrix = Matrix::builtin();
+    let (version, refusal) = match (kernel, dkms, bundle) {
+        (false, _, _) => return None,
+        (true, true, _) => {
+            let release = running_kernel().ok()?;
+            let refusal = matrix.module(&release);
+            (release, refusal)
+        }
+        (true, false, Some(bundle)) => {
+            let (package, path) = bundle.packages(pm, true).into_iter().find(|(p, _)| p.kernel)?;
+            let version = pm.file_version(&path)?;
+            let refusal = matrix.kernel(&package.name, &version);
+            (format!("{} {}", package.name, version), refusal)
+        }
+        (true, false, None) => return None,
+    };
+    Some(match refusal {
+        None => check("series", Status::Pass, format!("{} is supported", version)),
+        Some(refusal) => check("series", Status::Fail, refusal),
+    })
+}
+
+/// The check "version" of the bitflux release an install pinned to `version`, or the one of
+/// `bundle`, puts on: it has to be there and, unless `allow_downgrade`, not be older than the
+/// installed one.  None when neither says which.  A repository that isn't set up yet has no
+/// versions to look at, the agent step looks for the pinned one once it is.
+pub fn release(pm: &PackageManager, version: Option<&str>, bundle: Option<&Bundle>, allow_downgrade: bool) -> Option<Check> {
+    let wanted = match (version, bundle) {
+        (Some(version), Some(bundle)) if bundle.index.version != version => {
+            return Some(check("version", Status::Fail, format!("bundle {} has bitflux {}, not {}", bundle.dir.display(), bundle.index.version, version)));
+        }
+        (Some(version), _) => String::from(version),
+        (None, Some(bundle)) => bundle.index.version.clone(),
+        (None, None) => return None,
+    };
+    let installed = pm.installed_version(AGENT_PACKAGE);
+    if let Some(why) = release::downgrade(installed.as_deref(), &wanted) {
+        return Some(match allow_downgrade {
+            true => check("version", Status::Warn, format!("{}, allowed", why.split(';').next().unwrap_or_default())),
+            false => check("version", Status::Fail, why),
+        });
+    }
+    if bundle.is_none() {
+        let available = pm.available_versions(AGENT_PACKAGE);
+        if !available.is_empty() && release::pick(&available, &wanted).is_none() {
+            return Some(check("version", Status::Fail, format!("bitflux {} isn't in the repository, it has {}", wanted, release::list(&available))));
+        }
+    }
+    Some(match installed {
+        Some(installed) => check("version", Status::Pass, format!("bitflux {}, {} is installed", wanted, release::of(&installed))),
+        None => check("version", Status::Pass, format!("bitflux {}", wanted)),
+    })
+}
+
+/// Lets an unsupported kernel series pass with a warning, for --allow-unsupported-kernel.
+pub fn allowing_unsupported_kernel(checks: &mut [Check]) {
+    for c in checks.iter_mut().filter(|c| c.name == "series" && c.status == Status::Fail) {
+        c.status = Status::Warn;
+        c.detail = format!("{}, allowed", c.detail.split(';').next().unwrap_or_default());
+    }
+}
+
+/// Runs every check at once, the network ones would otherwise make the others wait for their
+/// timeouts.  Space and memory are what `profile` needs, with `offline` nothing goes over the
+/// network.
+pub fn run(offline: bool, profile: Profile) -> Vec<Check> {
+    // What to size against, the arch check fails the run on any other.
+    let arch = arch::check(&arch::machine(), Arch::current()).unwrap_or(Arch::X86_64);
+    let requirements = Requirements::of(profile, arch);
+    let needs = requirements.clone();
+    let mut jobs: Vec<Job<Vec<Check>>> = vec![
+        Box::new(|| vec![privileges()]),
+        Box::new(|| vec![distro()]),
+        Box::new(|| vec![architecture()]),
+        Box::new(|| vec![path()]),
+        Box::new(|| vec![conflicts()]),
+        Box::new(|| vec![virt()]),
+        Box::new(move || vec![disk(&requirements)]),
+        Box::new(move || vec![memory(&needs, arch)]),
+        Box::new(|| vec![kernel()]),
+        Box::new(|| vec![selinux_tools()]),
+    ];
+    if offline {
+        jobs.push(Box::new(|| {
+            ["dns", "network", "clock"].iter().map(|n| check(n, Status::Warn, String::from("skipped, --offline"))).collect()
+        }));
+    } else {
+        jobs.push(Box::new(|| vec![dns()]));
+        jobs.push(Box::new(network_and_clock));
+    }
+    batch::run(jobs).into_iter().flatten().collect()
+}
+
+/// Whether an --offline install from `bundle` through `pm` gets by without the network: the
+/// agent, and with `kernel` the bitflux kernel, have to be in it.  A webhook to `notify` can't
+/// be reached, a license key without the bundle's activation is only activated by the agent.
+pub fn offline(bundle: &Bundle, pm: &PackageManager, kernel: bool, notify: bool, license_key: bool) -> Check {
+    let packages = bundle.packages(pm, kernel);
+    let mut missing = Vec::new();
+    if !packages.iter().any(|(p, _)| p.name == AGENT_PACKAGE) {
+        missing.push(format!("no {} package for this distro", AGENT_PACKAGE));
+    }
+    if kernel && !packages.iter().any(|(p, _)| p.kernel) {
+        missing.push(String::from("no bitflux kernel for this distro, install --profile agent or use a bundle with one"));
+    }
+    if !missing.is_empty() {
+        return check("offline", Status::Fail, format!("bundle {} has {}, that needs the network", bundle.index.version, missing.join(" and ")));
+    }
+    let mut warnings = Vec::new();
+    if notify {
+        warnings.push("the webhook can't be notified");
+    }
+    if license_key && bundle.index.activation.is_none() {
+        warnings.push("the bundle has no license activation, the agent activates once it reaches the network");
+    }
+    match warnings.is_empty() {
+        true => check("offline", Status::Pass, format!("everything comes from bundle {}", bundle.index.version)),
+        false => check("offline", Status::Warn, warnings.join(", ")),
+    }
+}
+
+pub fn print(checks: &[Check]) -> io::Result<()> {
+    for c in checks {
+        let label = match c.status {
+            Status::Pass => "PASS",
+            Status::Warn => "WARN",
+            Status::Fail => "FAIL",
+        };
+        output::print(&format!("{:<5} {:<9} {}\n", label, c.name, c.detail))?;
+    }
+    Ok(())
+}
+
+/// Prints the checks and fails if any of them failed.
+pub fn gate(checks: &[Check]) -> io::Result<()> {
+    print(checks)?;
+    failures(checks)
+}
+
+fn failures(checks: &[Check]) -> io::Result<()> {
+    let failed: Vec<&str> = checks.iter().filter(|c| c.status == Status::Fail).map(|c| c.name.as_str()).collect();
+    let message = format!("Preflight failed: {}.", failed.join(", "));
+    // The exit code goes by the first check that failed, they're in the order to fix them in.
+    match failed.first() {
+        None => Ok(()),
+        Some(&"root") => Err(Kind::Privileges.error(message)),
+        Some(&("distro" | "arch" | "kernel" | "series")) => Err(Kind::Unsupported.error(message)),
+        Some(&("dns" | "network" | "offline")) => Err(Kind::Network.error(message)),
+        Some(_) => Err(io::Error::other(message)),
+    }
+}
+
+/// `installer preflight`: every check for an install of `profile`, changing nothing, as a table
+/// or as JSON.
+pub fn report(offline: bool, profile: Profile, format: Format) -> io::Result<()> {
+    let checks = run(offline, Virt::detect().effective_profile(profile));
+    if format == Format::Text {
+        return gate(&checks);
+    }
+    output::json(&checks)?;
+    failures(&checks)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_inputs() {
+        assert_eq!(parse_http_date("Thu, 01 Jan 1970 00:00:10 GMT"), Some(10));
+        assert_eq!(parse_http_date("Tue, 15 Oct 2024 10:00:00 GMT"), Some(1728986400));
+        assert_eq!(parse_http_date("yesterday"), None);
+        assert_eq!(kernel_series("5.15.0-91-generic"), Some((5, 15)));
+        assert_eq!(kernel_series("4.18.0-513.el8.x86_64"), Some((4, 18)));
+        assert!(kernel_series("3.10.0-1160.el7").unwrap() < MIN_KERNEL);
+        assert_eq!(mem_total_mib("MemTotal:        8039652 kB\nMemFree: 1 kB\n"), Some(7851));
+    }
+
+    #[test]
+    fn requirements_per_profile() {
+        let kernel = Requirements::of(Profile::AgentKernel, Arch::Aarch64);
+        assert_eq!(kernel.space, [("/boot", 300), ("/usr", 400), ("/var", MIN_VAR_MIB)]);
+        let agent = Requirements::of(Profile::Agent, Arch::Aarch64);
+        assert_eq!((agent.space.len(), agent.memory_mib), (2, 1024));
+        assert_eq!(kernel.memory(4096, Arch::Aarch64).status, Status::Pass);
+        let short = kernel.memory(1536, Arch::Aarch64);
+        assert_eq!(short.status, Status::Fail);
+        assert_eq!(short.detail, "1536 MiB, the agent-kernel profile needs 2048 MiB on aarch64, add memory or install --profile agent, which needs 1024 MiB");
+        assert_eq!(agent.memory(1536, Arch::Aarch64).status, Status::Pass);
+    }
+
+    #[test]
+    fn clock_off_the_server() {
+        let mut checks = vec![
+            clock("clock", Some(20), "the repository", "TLS and signature checks fail"),
+            clock("license", Some(3600), "the license server", "the activation is refused"),
+        ];
+        assert_eq!(checks[0].status, Status::Pass);
+        assert_eq!(checks[1], check("license", Status::Fail, String::from("clock is 3600s off the license server, the activation is refused; fix NTP first or pass --fix-clock")));
+        fixing_clock(&mut checks);
+        assert_eq!(checks[1], check("license", Status::Warn, String::from("clock is 3600s off the license server, the activation is refused, --fix-clock sets it first")));
+        assert_eq!(clock("clock", None, "the repository", "").status, Status::Warn);
+    }
+
+    #[test]
+    fn containers_only_warn() {
+        assert_eq!(virtualization(Some("docker"), Some("kvm")).status, Status::Warn);
+        assert_eq!(virtualization(None, Some("kvm")).detail, "kvm virtual machine");
+        assert_eq!(virtualization(None, None).status, Status::Pass);
+    }
+
+    #[test]
+    fn offline_needs_the_bundle_complete() {
+        let dir = std::env::temp_dir().join(format!("bitflux-offline-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(crate::bundle::INDEX), r#"{
+            "version": "1.4.0",
+            "packages": [{"name": "bitfluxcollector", "file": "deb/bitfluxcollector_1.4.0_amd64.deb", "sha256": "aa"}]
+        }"#).unwrap();
+        let bundle = Bundle::open(&dir, false).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(offline(&bundle, &PackageManager::Apt, false, false, false).status, Status::Pass);
+        let kernel = offline(&bundle, &PackageManager::Apt, true, false, false);
+        assert_eq!(kernel.status, Status::Fail);
+        assert!(kernel.detail.contains("no bitflux kernel"));
+        assert!(offline(&bundle, &PackageManager::Dnf, false, false, false).detail.contains("no bitfluxcollector package"));
+        assert_eq!(offline(&bundle, &PackageManager::Apt, false, true, true).status, Status::Warn);
+        assert_eq!(failures(&[kernel]).map_err(|e| crate::exitcode::of(&e)), Err(crate::exitcode::NETWORK));
+    }
+
+    #[test]
+    fn offline_skips_network() {
+        let checks = run(true, Profile::Agent);
+        let names: Vec<&str> = checks.iter().map(|c| c.name.as_str()).collect();
+        assert_eq!(names, ["root", "distro", "arch", "path", "conflicts", "virt", "disk", "memory", "kernel", "selinux", "dns", "network", "clock"]);
+        assert!(checks[10..].iter().all(|c| c.status == Status::Warn));
+    }
+
+}
@@ -0,0 +1,95 @@
+use std::fs;
+use std::io;
+
+use crate::offline;
+use crate::runcmd::RunCmd;
+use crate::tls;
+
+/// Accounts the network commands drop to, first one that exists wins.  Only the commands run
+/// through unprivileged_cmd() drop root: what they fetch is parsed and rendered by the
+/// installer itself, as root.
+pub const UNPRIVILEGED_USERS: &[&str] = &["bitflux", "nobody"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Ids {
+    pub uid: u32,
+    pub gid: u32,
+}
+
+pub use crate::runcmd::is_root;
+
+/// Finds `user` in passwd formatted `data`.
+pub fn lookup(data: &str, user: &str) -> Option<Ids> {
+    data.lines().find_map(|line| {
+        let fields: Vec<&str> = line.split(':').collect();
+        if fields.len() < 4 || fields[0] != user {
+            return None;
+        }
+        Some(Ids { uid: fields[2].parse().ok()?, gid: fields[3].parse().ok()? })
+    })
+}
+
+/// The account unprivileged commands run as, None when we aren't root and so have nothing to drop.
+pub fn unprivileged() -> Option<Ids> {
+    if !is_root() {
+        return None;
+    }
+    let passwd = fs::read_to_string("/etc/passwd").ok()?;
+    UNPRIVILEGED_USERS.iter().find_map(|u| lookup(&passwd, u))
+}
+
+/// Extra tries for a download that fails, the network often recovers within a minute.
+const NETWORK_RETRIES: u32 = 3;
+
+/// Builds a command that runs without root privileges when the installer has them.
+pub fn unprivileged_cmd(program: &str, args: &[&str]) -> RunCmd {
+    let mut runcmd = RunCmd::args(program, args);
+    if let Some(ids) = unprivileged() {
+        runcmd.as_user(ids.uid, ids.gid);
+    }
+    runcmd
+}
+
+/// curl arguments for `url` with the TLS hardening applied, `extra` goes before the url.
+fn curl<'a>(tls: &'a [String], extra: &[&'a str], url: &'a str) -> Vec<&'a str> {
+    let mut args = vec!["-fsSL"];
+    args.extend(tls.iter().map(String::as_str));
+    args.extend_from_slice(extra);
+    args.extend_from_slice(&["--", url]);
+    args
+}
+
+/// Fetches `url` as text, the download itself running unprivileged.
+pub fn fetch(url: &str) -> io::Result<String> {
+    offline::guard(url)?;
+    let tls = tls::hardened_curl_args();
+    let out = unprivileged_cmd("curl", &curl(&tls, &[], url)).retries(NETWORK_RETRIES).execute_output();
+    tls::log(url, &out.stderr);
+    if out.exitcode != 0 {
+        return Err(io::Error::other(format!("Failed to fetch '{}': {}", url, out.stderr.trim())));
+    }
+    Ok(out.stdout)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lookup_passwd() {
+        let passwd = "root:x:0:0:root:/root:/bin/bash\nnobody:x:65534:65534:nobody:/nonexistent:/usr/sbin/nologin\n";
+        assert_eq!(lookup(passwd, "nobody"), Some(Ids { uid: 65534, gid: 65534 }));
+        assert_eq!(lookup(passwd, "bitflux"), None);
+    }
+
+    #[test]
+    fn unprivileged_cmd_drops_root() {
+        let out = unprivileged_cmd("id", &["-u"]).execute_output();
+        match unprivileged() {
+            Some(ids) => assert_eq!(out.stdout.trim(), ids.uid.to_string()),
+            None => assert_eq!(out.exitcode, 0),
+        }
+    }
+
+}
@@ -0,0 +1,125 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use crate::install::{Options, Setting};
+
+/// More install variants, each a [profiles.<name>] table of Variant.
+pub const PROFILES_PATH: &str = "/etc/bitflux/profiles.toml";
+
+/// The deployment flavors of bitflux.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "kebab-case")]
+pub enum Profile {
+    /// Only the agent, on the stock kernel.
+    Agent,
+    /// The agent plus the swaphints kernel.
+    #[default]
+    AgentKernel,
+    /// agent-kernel with the sandbox relaxed enough to attach a debugger and dump core.
+    Debug,
+}
+
+impl Profile {
+
+    pub fn name(self) -> String {
+        self.to_possible_value().map(|v| v.get_name().to_string()).unwrap_or_default()
+    }
+
+    /// True if the profile installs the bitflux kernel.
+    pub fn kernel(self) -> bool {
+        matches!(self, Profile::AgentKernel | Profile::Debug)
+    }
+
+}
+
+/// An install variant defined in profiles.toml: the steps of a built-in profile with other
+/// defaults.  What the command line says wins.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Variant {
+    pub description: String,
+    /// The built-in profile whose steps it installs.
+    pub base: Profile,
+    pub kernel: Option<bool>,
+    pub dkms: bool,
+    pub skip_mac_policy: bool,
+    pub open_ports: Vec<String>,
+    /// Agent config settings, the feature toggles.
+    pub agent: BTreeMap<String, Setting>,
+}
+
+impl Variant {
+
+    /// `opts` with the variant's defaults where they don't say otherwise.
+    pub fn apply(&self, opts: &mut Options) {
+        opts.profile = self.base;
+        opts.kernel = opts.kernel.or(self.kernel);
+        opts.dkms |= self.dkms;
+        opts.skip_mac_policy |= self.skip_mac_policy;
+        opts.open_ports.extend(self.open_ports.iter().cloned());
+        for (key, value) in &self.agent {
+            opts.agent.entry(key.clone()).or_insert_with(|| value.clone());
+        }
+    }
+
+}
+
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Variants {
+    profiles: BTreeMap<String, Variant>,
+}
+
+/// The variants defined in `path`, none when it doesn't exist.
+pub fn variants<P: AsRef<Path>>(path: P) -> io::Result<BTreeMap<String, Variant>> {
+    let path = path.as_ref();
+    let data = match fs::read_to_string(path) {
+        Ok(data) => data,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(BTreeMap::new()),
+        Err(e) => return Err(io::Error::new(e.kind(), format!("Can't read {}: {}", path.display(), e))),
+    };
+    let variants: Variants = toml::from_str(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))?;
+    Ok(variants.profiles)
+}
+
+/// The profile called `name`, a built-in one or one defined in `path`.
+pub fn variant<P: AsRef<Path>>(name: &str, path: P) -> io::Result<Variant> {
+    if let Ok(base) = Profile::from_str(name, false) {
+        return Ok(Variant { base, ..Default::default() });
+    }
+    let mut defined = variants(&path)?;
+    match defined.remove(name) {
+        Some(variant) => Ok(variant),
+        None => {
+            let names: Vec<String> = Profile::value_variants().iter().map(|p| p.name()).chain(defined.into_keys()).collect();
+            Err(io::Error::other(format!("There's no profile {}, pick one of {}.", name, names.join(", "))))
+        }
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn variants_from_toml() {
+        let path = std::env::temp_dir().join(format!("bitflux-profiles-{}.toml", std::process::id()));
+        assert_eq!(variant("debug", &path).unwrap().base, Profile::Debug);
+        fs::write(&path, "[profiles.edge]\nbase = \"agent\"\nopen_ports = [\"9100\"]\n\n[profiles.edge.agent]\nreclaim = true\ninterval = 30\n").unwrap();
+        let edge = variant("edge", &path).unwrap();
+        let mut opts = Options { agent: BTreeMap::from([(String::from("interval"), Setting::Integer(10))]), ..Default::default() };
+        edge.apply(&mut opts);
+        assert_eq!(opts.install_profile(), Profile::Agent);
+        assert_eq!(opts.open_ports, ["9100"]);
+        assert_eq!(opts.agent_settings(), [("interval", "10"), ("reclaim", "true")].map(|(k, v)| (String::from(k), String::from(v))));
+        assert_eq!(variant("edg", &path).unwrap_err().to_string(), "There's no profile edg, pick one of agent, agent-kernel, debug, edge.");
+        fs::remove_file(&path).unwrap();
+    }
+
+}
@@ -0,0 +1,277 @@
+use std::cmp::Reverse;
+use std::fmt::Write;
+use std::fs;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::progress::{self, Phase};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static REPORT: AtomicBool = AtomicBool::new(false);
+static STATE: Mutex<State> = Mutex::new(State { stack: Vec::new(), samples: Vec::new() });
+const BAR_WIDTH: usize = 30;
+
+struct State {
+    stack: Vec<String>,
+    samples: Vec<Sample>,
+}
+
+/// One finished step or external command, `path` is the steps it ran in with its own name last.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Sample {
+    pub path: Vec<String>,
+    pub wall: Duration,
+    pub cpu: Duration,
+    pub rx_bytes: u64,
+}
+
+/// Record where the time, CPU and downloads of this run go, and report it at the end
+/// (--profile-run).
+pub fn set_enabled(enabled: bool) {
+    ENABLED.store(enabled, Ordering::Relaxed);
+    REPORT.store(enabled, Ordering::Relaxed);
+}
+
+/// Record the samples without reporting them, for the metrics.
+pub fn collect() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn is_enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Bytes received on all but the loopback interface, from /proc/net/dev formatted `data`.
+pub fn rx_bytes(data: &str) -> u64 {
+    data.lines()
+        .filter_map(|l| l.split_once(':'))
+        .filter(|(iface, _)| iface.trim() != "lo")
+        .filter_map(|(_, counters)| counters.split_whitespace().next()?.parse::<u64>().ok())
+        .sum()
+}
+
+/// CPU time of this process plus every child it waited for.
+fn cpu_time() -> Duration {
+    let usage = |who| {
+        let mut usage: libc::rusage = unsafe { std::mem::zeroed() };
+        unsafe { libc::getrusage(who, &mut usage) };
+        Duration::from_secs(usage.ru_utime.tv_sec as u64 + usage.ru_stime.tv_sec as u64)
+            + Duration::from_micros(usage.ru_utime.tv_usec as u64 + usage.ru_stime.tv_usec as u64)
+    };
+    usage(libc::RUSAGE_SELF) + usage(libc::RUSAGE_CHILDREN)
+}
+
+/// Counters at the start of a step or command.
+pub struct Mark {
+    wall: Instant,
+    cpu: Duration,
+    rx_bytes: u64,
+}
+
+/// None when profiling is off, so the counters are only read when they're wanted.
+pub fn mark() -> Option<Mark> {
+    if !is_enabled() {
+        return None;
+    }
+    let rx = fs::read_to_string(Path::new("/proc/net/dev")).map(|d| rx_bytes(&d)).unwrap_or(0);
+    Some(Mark { wall: Instant::now(), cpu: cpu_time(), rx_bytes: rx })
+}
+
+fn finish(mark: Mark, path: Vec<String>, state: &mut State) {
+    let now = self::mark().unwrap_or(Mark { wall: Instant::now(), cpu: mark.cpu, rx_bytes: mark.rx_bytes });
+    state.samples.push(Sample {
+        path,
+        wall: now.wall - mark.wall,
+        cpu: now.cpu.saturating_sub(mark.cpu),
+        // The counters are host wide, other traffic while a step runs is counted too.
+        rx_bytes: now.rx_bytes.saturating_sub(mark.rx_bytes),
+    });
+}
+
+/// Records an external command started at `mark` under the current step.
+pub fn command(name: &str, mark: Option<Mark>) {
+    let Some(mark) = mark else {
+        return;
+    };
+    let mut state = STATE.lock().unwrap_or_else(|e| e.into_inner());
+    let mut path = state.stack.clone();
+    path.push(format!("$ {}", name));
+    finish(mark, path, &mut state);
+}
+
+/// A step of the run, everything until it's dropped is accounted to it.
+pub struct Step {
+    name: String,
+    mark: Option<Mark>,
+}
+
+/// Starts the step `name` inside the current one, also reported to progress subscribers.
+pub fn step(name: &str) -> Step {
+    progress::emit(name, Phase::Started);
+    let mark = mark();
+    if mark.is_some() {
+        STATE.lock().unwrap_or_else(|e| e.into_inner()).stack.push(String::from(name));
+    }
+    Step { name: String::from(name), mark }
+}
+
+impl Drop for Step {
+
+    fn drop(&mut self) {
+        progress::emit(&self.name, Phase::Finished);
+        let Some(mark) = self.mark.take() else {
+            return;
+        };
+        let mut state = STATE.lock().unwrap_or_else(|e| e.into_inner());
+        let path = state.stack.clone();
+        state.stack.pop();
+        finish(mark, path, &mut state);
+    }
+
+}
+
+#[derive(Default)]
+struct Node {
+    name: String,
+    calls: u32,
+    wall: Duration,
+    cpu: Duration,
+    rx_bytes: u64,
+    children: Vec<Node>,
+}
+
+impl Node {
+
+    /// Adds `sample` to the node at `path` below this one, creating the nodes on the way.
+    fn add(&mut self, path: &[String], sample: &Sample) {
+        let Some((name, rest)) = path.split_first() else {
+            self.calls += 1;
+            self.wall += sample.wall;
+            self.cpu += sample.cpu;
+            self.rx_bytes += sample.rx_bytes;
+            return;
+        };
+        let i = match self.children.iter().position(|c| &c.name == name) {
+            Some(i) => i,
+            None => {
+                self.children.push(Node { name: name.clone(), ..Node::default() });
+                self.children.len() - 1
+            }
+        };
+        self.children[i].add(rest, sample);
+    }
+
+    fn render(&self, depth: usize, total: Duration, out: &mut String) {
+        let width = match total.is_zero() {
+            true => 0,
+            false => (self.wall.as_secs_f64() / total.as_secs_f64() * BAR_WIDTH as f64).round() as usize,
+        };
+        let calls = if self.calls > 1 { format!(" x{}", self.calls) } else { String::new() };
+        let _ = writeln!(
+            out,
+            "{:>9.2}s {:>8.2}s {:>10} {:<w$} {}{}{}",
+            self.wall.as_secs_f64(),
+            self.cpu.as_secs_f64(),
+            format_bytes(self.rx_bytes),
+            "#".repeat(width.min(BAR_WIDTH)),
+            "  ".repeat(depth),
+            self.name,
+            calls,
+            w = BAR_WIDTH,
+        );
+        for child in slowest_first(&self.children) {
+            child.render(depth + 1, total, out);
+        }
+    }
+
+}
+
+fn slowest_first(nodes: &[Node]) -> Vec<&Node> {
+    let mut nodes: Vec<&Node> = nodes.iter().collect();
+    nodes.sort_by_key(|n| Reverse(n.wall));
+    nodes
+}
+
+fn format_bytes(bytes: u64) -> String {
+    match bytes {
+        0..1024 => format!("{} B", bytes),
+        1024..1048576 => format!("{:.1} KiB", bytes as f64 / 1024.0),
+        _ => format!("{:.1} MiB", bytes as f64 / 1048576.0),
+    }
+}
+
+/// The breakdown of `samples`: one line per step and command with its wall time, CPU time and
+/// bytes received, nested under the step it ran in, slowest first, with a bar for its share of
+/// the wall time.  Repeated commands are summed.
+pub fn breakdown(samples: &[Sample]) -> String {
+    let mut root = Node::default();
+    for sample in samples {
+        root.add(&sample.path, sample);
+    }
+    let total = root.children.iter().map(|c| c.wall).sum();
+    let mut out = format!("{:>10} {:>9} {:>10} {:<w$} step\n", "wall", "cpu", "received", "", w = BAR_WIDTH);
+    for child in slowest_first(&root.children) {
+        child.render(0, total, &mut out);
+    }
+    out
+}
+
+/// Prints the breakdown of this run to stderr, if --profile-run asked for it.
+pub fn report() {
+    if !REPORT.load(Ordering::Relaxed) {
+        return;
+    }
+    eprint!("\n{}", breakdown(&samples()));
+}
+
+/// Everything recorded so far.
+pub fn samples() -> Vec<Sample> {
+    STATE.lock().unwrap_or_else(|e| e.into_inner()).samples.clone()
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_net_dev() {
+        let data = "\
+Inter-|   Receive                                                |  Transmit
+ face |bytes    packets errs drop fifo frame compressed multicast|bytes    packets errs drop fifo colls carrier compressed
+    lo: 76064751   10193    0    0    0     0          0         0 76064751   10193    0    0    0     0       0          0
+  eth0: 1000   12    0    0    0     0          0         0 5   1    0    0    0     0       0          0
+  eth1:   24   12    0    0    0     0          0         0 5   1    0    0    0     0       0          0
+";
+        assert_eq!(rx_bytes(data), 1024);
+    }
+
+    #[test]
+    fn breakdown_nests_and_sums() {
+        let sample = |path: &[&str], secs| Sample {
+            path: path.iter().map(|p| p.to_string()).collect(),
+            wall: Duration::from_secs(secs),
+            cpu: Duration::from_secs(1),
+            rx_bytes: 2048,
+        };
+        let samples = [
+            sample(&["install", "repository", "$ apt-get update"], 2),
+            sample(&["install", "repository", "$ apt-get update"], 2),
+            sample(&["install", "repository"], 5),
+            sample(&["install", "kernel", "$ apt-get install"], 4),
+            sample(&["install", "kernel"], 4),
+            sample(&["install"], 10),
+        ];
+        let lines: Vec<String> = breakdown(&samples).lines().map(|l| l.split_whitespace().collect::<Vec<_>>().join(" ")).collect();
+        assert_eq!(lines.len(), 6);
+        assert!(lines[1].starts_with("10.00s 1.00s 2.0 KiB ##############################"));
+        assert!(lines[1].ends_with("install"));
+        assert!(lines[2].ends_with("repository"));
+        assert!(lines[3].ends_with("$ apt-get update x2"));
+        assert!(lines[3].starts_with("4.00s 2.00s 4.0 KiB"));
+        assert!(lines[4].ends_with("kernel"));
+    }
+
+}
@@ -0,0 +1,36 @@
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+static SUBSCRIBERS: Mutex<Vec<Sender<Event>>> = Mutex::new(Vec::new());
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Phase {
+    Started,
+    Finished,
+}
+
+/// A step of the run starting or finishing, see profiling::step.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Event {
+    pub step: String,
+    pub phase: Phase,
+    /// Unix timestamp.
+    pub at: u64,
+}
+
+/// Receives every event from now on, until the receiver is dropped.
+pub fn subscribe() -> Receiver<Event> {
+    let (tx, rx) = mpsc::channel();
+    SUBSCRIBERS.lock().unwrap_or_else(|e| e.into_inner()).push(tx);
+    rx
+}
+
+pub fn emit(step: &str, phase: Phase) {
+    let at = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let event = Event { step: String::from(step), phase, at };
+    SUBSCRIBERS.lock().unwrap_or_else(|e| e.into_inner()).retain(|tx| tx.send(event.clone()).is_ok());
+}
@@ -0,0 +1,174 @@
+use std::io::{self, BufRead, Write};
+
+use crate::i18n;
+use crate::runcmd::sensitive;
+use crate::spinner;
+
+/// Asks questions and reads the answers, re-asking until an answer is valid.  Questions go to
+/// `output` and answers come from `input`, stderr and stdin for the terminal() one.  An empty
+/// answer takes the default, end of input is an error rather than an endless loop.
+///
+/// # Examples
+///
+/// ```no_run
+/// use installer::prompt::Prompt;
+///
+/// let mut prompt = Prompt::terminal();
+/// if prompt.confirm("Install the bitflux kernel?", true)? {
+///     println!("Installing the kernel.");
+/// }
+/// # Ok::<(), std::io::Error>(())
+/// ```
+pub struct Prompt<R, W> {
+    input: R,
+    output: W,
+    /// The next answer is masked in everything printed and logged, see sensitive().
+    sensitive: bool,
+}
+
+impl Prompt<io::StdinLock<'static>, io::Stderr> {
+
+    pub fn terminal() -> Prompt<io::StdinLock<'static>, io::Stderr> {
+        Prompt { input: io::stdin().lock(), output: io::stderr(), sensitive: false }
+    }
+
+}
+
+impl<R: BufRead, W: Write> Prompt<R, W> {
+
+    pub fn new(input: R, output: W) -> Prompt<R, W> {
+        Prompt { input, output, sensitive: false }
+    }
+
+    /// Masks the answer to the next question wherever it would be printed or logged, for a
+    /// license key.
+    pub fn sensitive(&mut self) -> &mut Prompt<R, W> {
+        self.sensitive = true;
+        self
+    }
+
+    /// Prints `question`, translated, and reads one trimmed line.
+    fn ask(&mut self, question: &str) -> io::Result<String> {
+        let _held = spinner::hold();
+        write!(self.output, "{} ", i18n::tr(question))?;
+        self.output.flush()?;
+        let mut answer = String::new();
+        if self.input.read_line(&mut answer)? == 0 {
+            writeln!(self.output)?;
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "No answer, the input was closed."));
+        }
+        if std::mem::take(&mut self.sensitive) {
+            sensitive::add(answer.trim());
+        }
+        Ok(answer.trim().to_string())
+    }
+
+    fn invalid(&mut self, reason: &str) -> io::Result<()> {
+        let _held = spinner::hold();
+        writeln!(self.output, "{}", i18n::tr(reason))
+    }
+
+    /// A yes or no question.
+    pub fn confirm(&mut self, question: &str, default: bool) -> io::Result<bool> {
+        let hint = if default { "[Y/n]" } else { "[y/N]" };
+        // English answers do in every language.
+        let (yes, no) = ([i18n::tr("y"), i18n::tr("yes")], [i18n::tr("n"), i18n::tr("no")]);
+        loop {
+            match self.ask(&format!("{} {}", question, hint))?.to_lowercase().as_str() {
+                "" => return Ok(default),
+                "y" | "yes" => return Ok(true),
+                "n" | "no" => return Ok(false),
+                a if yes.iter().any(|y| y.to_lowercase() == a) => return Ok(true),
+                a if no.iter().any(|n| n.to_lowercase() == a) => return Ok(false),
+                _ => self.invalid("Please answer yes or no.")?,
+            }
+        }
+    }
+
+    /// Free text, `default` when nothing is typed.
+    pub fn input_with_default(&mut self, question: &str, default: &str) -> io::Result<String> {
+        self.input_valid(question, Some(default), |_| Ok(()))
+    }
+
+    /// Free text that `validate` accepts, it says what's wrong otherwise.  Without a `default`
+    /// an empty answer is asked again, unless `validate` accepts it.
+    pub fn input_valid<F>(&mut self, question: &str, default: Option<&str>, validate: F) -> io::Result<String>
+    where
+        F: Fn(&str) -> Result<(), String>,
+    {
+        let question = match default {
+            Some(default) if !default.is_empty() => format!("{} [{}]", question, default),
+            _ => String::from(question),
+        };
+        loop {
+            let answer = match (self.ask(&question)?, default) {
+                (answer, Some(default)) if answer.is_empty() => String::from(default),
+                (answer, _) => answer,
+            };
+            match validate(&answer) {
+                Ok(()) => return Ok(answer),
+                Err(reason) => self.invalid(&reason)?,
+            }
+        }
+    }
+
+    /// One of `choices` by number, returns its index.
+    pub fn select(&mut self, question: &str, choices: &[&str], default: usize) -> io::Result<usize> {
+        writeln!(self.output, "{}", i18n::tr(question))?;
+        for (i, choice) in choices.iter().enumerate() {
+            writeln!(self.output, "  {}) {}", i + 1, i18n::tr(choice))?;
+        }
+        loop {
+            let answer = self.ask(&format!("Choice [{}]:", default + 1))?;
+            if answer.is_empty() {
+                return Ok(default);
+            }
+            match answer.parse::<usize>() {
+                Ok(n) if (1..=choices.len()).contains(&n) => return Ok(n - 1),
+                _ => self.invalid(&format!("Please pick a number from 1 to {}.", choices.len()))?,
+            }
+        }
+    }
+
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn prompt(input: &str) -> Prompt<&[u8], Vec<u8>> {
+        Prompt::new(input.as_bytes(), Vec::new())
+    }
+
+    #[test]
+    fn confirm_reasks() {
+        let mut p = prompt("maybe\nN\n\n");
+        assert!(!p.confirm("Install?", true).unwrap());
+        assert!(p.confirm("Install?", true).unwrap());
+        assert_eq!(String::from_utf8(p.output).unwrap(), "Install? [Y/n] Please answer yes or no.\nInstall? [Y/n] Install? [Y/n] ");
+        assert_eq!(prompt("").confirm("Install?", true).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn input_validated() {
+        let mut p = prompt("\nbad key\nabc\n\n");
+        let valid = |s: &str| if s.is_empty() || s.contains(' ') { Err(String::from("No spaces.")) } else { Ok(()) };
+        assert_eq!(p.input_valid("Key:", None, valid).unwrap(), "abc");
+        assert_eq!(p.input_with_default("Name:", "web1").unwrap(), "web1");
+        assert_eq!(String::from_utf8(p.output).unwrap().matches("No spaces.").count(), 2);
+
+        let mut p = prompt("PROMPT-KEY-1234\nweb1\n");
+        assert_eq!(p.sensitive().input_valid("License key:", None, |_| Ok(())).unwrap(), "PROMPT-KEY-1234");
+        p.input_with_default("Name:", "").unwrap();
+        assert_eq!(sensitive::mask("licensekey=PROMPT-KEY-1234 deviceid=web1"), "licensekey=[redacted] deviceid=web1");
+    }
+
+    #[test]
+    fn select_by_number() {
+        let mut p = prompt("3\n2\n\n");
+        assert_eq!(p.select("Profile:", &["agent", "agent-kernel"], 1).unwrap(), 1);
+        assert_eq!(p.select("Profile:", &["agent", "agent-kernel"], 0).unwrap(), 0);
+    }
+
+}
@@ -0,0 +1,83 @@
+use std::env;
+
+use serde::Deserialize;
+
+/// The proxies network access goes through, the `[proxy]` table of the installer config.
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Proxy {
+    pub http: Option<String>,
+    pub https: Option<String>,
+    /// Comma separated hosts and domains reached directly, as in no_proxy.
+    pub no_proxy: Option<String>,
+}
+
+/// First set one of `names` in the environment.
+fn var(names: &[&str]) -> Option<String> {
+    names.iter().find_map(|n| env::var(n).ok()).filter(|v| !v.is_empty())
+}
+
+impl Proxy {
+
+    /// The proxies of http_proxy, https_proxy and no_proxy, or their upper case spellings.
+    pub fn from_env() -> Proxy {
+        Proxy {
+            http: var(&["http_proxy", "HTTP_PROXY"]),
+            https: var(&["https_proxy", "HTTPS_PROXY"]),
+            no_proxy: var(&["no_proxy", "NO_PROXY"]),
+        }
+    }
+
+    /// Each setting of self, or `other`'s where self has none.
+    pub fn or(self, other: Proxy) -> Proxy {
+        Proxy {
+            http: self.http.or(other.http),
+            https: self.https.or(other.https),
+            no_proxy: self.no_proxy.or(other.no_proxy),
+        }
+    }
+
+    /// The environment variables for the proxies, in both spellings since tools differ in
+    /// which one they read.
+    pub fn vars(&self) -> Vec<(String, String)> {
+        let mut vars = Vec::new();
+        for (name, value) in [("http_proxy", &self.http), ("https_proxy", &self.https), ("no_proxy", &self.no_proxy)] {
+            if let Some(value) = value {
+                vars.push((String::from(name), value.clone()));
+                vars.push((name.to_uppercase(), value.clone()));
+            }
+        }
+        vars
+    }
+
+    /// Makes these the proxies of every child process started from now on, curl and the
+    /// package managers among them.
+    pub fn export(&self) {
+        for (name, value) in self.vars() {
+            env::set_var(name, value);
+        }
+    }
+
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn flags_win_over_config() {
+        let flags = Proxy { https: Some(String::from("http://flag:3128")), ..Default::default() };
+        let config = Proxy {
+            http: Some(String::from("http://config:3128")),
+            https: Some(String::from("http://config:3128")),
+            no_proxy: Some(String::from("localhost,.internal")),
+        };
+        let proxy = flags.or(config);
+        assert_eq!(proxy.https.as_deref(), Some("http://flag:3128"));
+        assert_eq!(proxy.http.as_deref(), Some("http://config:3128"));
+        assert!(proxy.vars().contains(&(String::from("NO_PROXY"), String::from("localhost,.internal"))));
+        assert_eq!(proxy.vars().len(), 6);
+    }
+
+}
@@ -0,0 +1,91 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::log::{self, Level};
+use crate::runcmd::{self, RunCmd};
+
+/// Debian's flag that the system wants a reboot, shown by update-motd and checked by
+/// unattended-upgrades.  The installer sets it on every distro.
+pub const REBOOT_REQUIRED: &str = "/run/reboot-required";
+/// What asked for the reboot, one package per line.
+pub const REBOOT_REQUIRED_PKGS: &str = "/run/reboot-required.pkgs";
+/// Why bitflux needs the reboot, one reason per line.  /run starts out empty on every boot,
+/// so it's gone once the reboot happened.
+pub const REASONS_PATH: &str = "/run/bitflux/reboot-required";
+
+/// Set by schedule(), for the end of the run.
+static SCHEDULED: AtomicBool = AtomicBool::new(false);
+
+/// Records that the system needs a reboot because of `reason`, for the notice at the end, status
+/// and the tools that look for Debian's flag.
+pub fn flag(reason: &str) -> io::Result<()> {
+    if runcmd::dry_run(&format!("flag a reboot in {}: {}", REBOOT_REQUIRED, reason)) {
+        return Ok(());
+    }
+    let reasons = fs::read_to_string(REASONS_PATH).unwrap_or_default();
+    if !reasons.lines().any(|l| l == reason) {
+        if let Some(dir) = Path::new(REASONS_PATH).parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(REASONS_PATH, format!("{}{}\n", reasons, reason))?;
+    }
+    fs::write(REBOOT_REQUIRED, "*** System restart required ***\n")?;
+    let pkgs = fs::read_to_string(REBOOT_REQUIRED_PKGS).unwrap_or_default();
+    if !pkgs.lines().any(|l| l == "bitflux") {
+        fs::write(REBOOT_REQUIRED_PKGS, format!("{}bitflux\n", pkgs))?;
+    }
+    Ok(())
+}
+
+/// Why bitflux needs a reboot, empty when it doesn't.
+pub fn reasons() -> Vec<String> {
+    fs::read_to_string(REASONS_PATH).unwrap_or_default().lines().map(String::from).collect()
+}
+
+/// True when bitflux, or anything else setting Debian's flag, is waiting for a reboot.
+pub fn required() -> bool {
+    Path::new(REBOOT_REQUIRED).exists() || !reasons().is_empty()
+}
+
+/// The notice that ends a run needing a reboot, framed so it stands out of the output before it.
+pub fn notice(reasons: &[String]) -> String {
+    let rule = "*".repeat(72);
+    let mut text = format!("{}\n  REBOOT REQUIRED\n", rule);
+    for reason in reasons {
+        text.push_str(&format!("  - {}\n", reason));
+    }
+    text.push_str(&format!("  Reboot to finish the install, or run it again with --reboot next time.\n{}", rule));
+    text
+}
+
+/// Has the run end in a reboot, once its outcome was reported.
+pub fn schedule() {
+    SCHEDULED.store(true, Ordering::Relaxed);
+}
+
+pub fn scheduled() -> bool {
+    SCHEDULED.load(Ordering::Relaxed)
+}
+
+/// Reboots the system.
+pub fn now() -> io::Result<()> {
+    log::log(Level::Info, "Rebooting.");
+    RunCmd::args("reboot", &[]).try_execute().map(|_| ()).map_err(io::Error::from)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notice_lists_reasons() {
+        let notice = notice(&[String::from("kernel 6.6.31-swaphints was installed")]);
+        assert!(notice.starts_with("****"));
+        assert!(notice.contains("\n  REBOOT REQUIRED\n  - kernel 6.6.31-swaphints was installed\n"));
+        assert!(notice.ends_with("****"));
+    }
+
+}
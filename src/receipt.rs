@@ -0,0 +1,220 @@
+use std::fs;
+use std::io::{self, Read, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use ed25519_dalek::{Signer, SigningKey};
+use serde::{Deserialize, Serialize};
+
+use crate::checksum::{sha256_file, to_hex};
+use crate::manifest::ManifestEntry;
+use crate::perms::{self, FileKind, PermissionRecord};
+use crate::pkg::PackageManager;
+use crate::runcmd;
+use crate::signature;
+
+/// Directory holding receipt.json, its signature and the host signing key.
+pub const RECEIPT_DIR: &str = "/var/lib/bitflux";
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PackageRecord {
+    pub name: String,
+    pub version: String,
+}
+
+/// A file put on the system by a package rather than by the installer itself.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct PackageFileRecord {
+    pub path: String,
+    pub sha256: String,
+    pub package: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ServiceRecord {
+    pub name: String,
+    pub enabled: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct ModuleParamRecord {
+    pub module: String,
+    pub param: String,
+    pub value: String,
+}
+
+/// What the installer put on the system, written at the end of an install and
+/// signed with a host key only root can read, so `verify` can detect drift later.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Receipt {
+    pub installer_version: String,
+    pub files: Vec<ManifestEntry>,
+    pub packages: Vec<PackageRecord>,
+    #[serde(default)]
+    pub package_files: Vec<PackageFileRecord>,
+    pub services: Vec<ServiceRecord>,
+    pub module_params: Vec<ModuleParamRecord>,
+    #[serde(default)]
+    pub permissions: Vec<PermissionRecord>,
+    /// The bitflux release the install was pinned to with --version.
+    #[serde(default)]
+    pub pinned: Option<String>,
+}
+
+impl Receipt {
+
+    pub fn new() -> Receipt {
+        Receipt {
+            installer_version: String::from(env!("CARGO_PKG_VERSION")),
+            ..Default::default()
+        }
+    }
+
+    /// Records an installed package along with the SHA-256 of every regular file it owns.
+    pub fn record_package(&mut self, pm: &PackageManager, name: &str) -> io::Result<()> {
+        let version = pm.installed_version(name)
+            .ok_or_else(|| io::Error::other(format!("Package '{}' is not installed.", name)))?;
+        self.packages.retain(|p| p.name != name);
+        self.packages.push(PackageRecord { name: String::from(name), version });
+
+        self.package_files.retain(|f| f.package != name);
+        for path in pm.files(name) {
+            if !Path::new(&path).is_file() {
+                continue;
+            }
+            let sha256 = sha256_file(&path)?;
+            self.package_files.push(PackageFileRecord { path, sha256, package: String::from(name) });
+        }
+        Ok(())
+    }
+
+    /// `sha256sum -c` compatible listing of every file in the receipt, for auditors.
+    pub fn sha256sums(&self) -> String {
+        let files = self.files.iter().map(|f| (&f.sha256, &f.path));
+        let package_files = self.package_files.iter().map(|f| (&f.sha256, &f.path));
+        files.chain(package_files).map(|(hash, path)| format!("{}  {}\n", hash, path)).collect()
+    }
+
+    /// Writes receipt.json, receipt.json.sig and SHA256SUMS into `dir`, creating the host key on first use.
+    pub fn save_signed<P: AsRef<Path>>(&self, dir: P) -> io::Result<()> {
+        let dir = dir.as_ref();
+        if runcmd::dry_run(&format!("write the signed receipt into {}", dir.display())) {
+            return Ok(());
+        }
+        fs::create_dir_all(dir)?;
+        let key = load_or_create_key(dir)?;
+
+        let data = serde_json::to_vec_pretty(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let sig = key.sign(&data).to_bytes();
+
+        fs::write(receipt_path(dir), &data)?;
+        fs::write(signature_path(dir), to_hex(&sig))?;
+        fs::write(dir.join("SHA256SUMS"), self.sha256sums())
+    }
+
+    /// Keeps a copy of every recorded file under `dir`/files so `repair` can put back
+    /// files that were deleted or modified after install.
+    pub fn stash_files<P: AsRef<Path>>(&self, dir: P) -> io::Result<()> {
+        let store = dir.as_ref().join("files");
+        if runcmd::dry_run(&format!("copy the installed files into {}", store.display())) {
+            return Ok(());
+        }
+        fs::create_dir_all(&store)?;
+        fs::set_permissions(&store, fs::Permissions::from_mode(0o700))?;
+        for file in &self.files {
+            fs::copy(&file.path, store.join(&file.sha256))?;
+        }
+        Ok(())
+    }
+
+    /// Reads the receipt from `dir`, failing if it doesn't match its signature.
+    pub fn load_verified<P: AsRef<Path>>(dir: P) -> io::Result<Receipt> {
+        let dir = dir.as_ref();
+        let not_installed = |e: io::Error| match e.kind() {
+            io::ErrorKind::NotFound => io::Error::new(e.kind(), format!("No install receipt in {}, is bitflux installed?", dir.display())),
+            _ => e,
+        };
+        let key = load_key(dir).map_err(not_installed)?;
+        let data = fs::read(receipt_path(dir)).map_err(not_installed)?;
+        let sig = fs::read_to_string(signature_path(dir)).map_err(not_installed)?;
+
+        let public = to_hex(key.verifying_key().as_bytes());
+        signature::verify(&public, &data, &sig)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Install receipt has been tampered with, signature mismatch."))?;
+        serde_json::from_slice(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+}
+
+/// Where `stash_files` keeps the install time copy of a file with hash `sha256`.
+pub fn stashed_file(dir: &Path, sha256: &str) -> PathBuf {
+    dir.join("files").join(sha256)
+}
+
+fn receipt_path(dir: &Path) -> PathBuf {
+    dir.join("receipt.json")
+}
+
+fn signature_path(dir: &Path) -> PathBuf {
+    dir.join("receipt.json.sig")
+}
+
+fn key_path(dir: &Path) -> PathBuf {
+    dir.join("receipt.key")
+}
+
+fn load_key(dir: &Path) -> io::Result<SigningKey> {
+    let seed: [u8; 32] = fs::read(key_path(dir))?
+        .try_into()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Receipt key is corrupt."))?;
+    Ok(SigningKey::from_bytes(&seed))
+}
+
+fn load_or_create_key(dir: &Path) -> io::Result<SigningKey> {
+    match load_key(dir) {
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            let mut seed = [0u8; 32];
+            fs::File::open("/dev/urandom")?.read_exact(&mut seed)?;
+            // Created 0600 up front, the key is never readable by anyone else even briefly.
+            fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(key_path(dir))?.write_all(&seed)?;
+            perms::apply(key_path(dir), FileKind::Secret)?;
+            Ok(SigningKey::from_bytes(&seed))
+        }
+        other => other,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signed_roundtrip_and_tamper() {
+        // The host key gets root's owner, only root can give it away.
+        if !crate::privsep::is_root() {
+            return;
+        }
+        let dir = std::env::temp_dir().join(format!("bitflux-receipt-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+
+        let mut receipt = Receipt::new();
+        receipt.packages.push(PackageRecord { name: String::from("bitfluxcollector"), version: String::from("1.0") });
+        receipt.save_signed(&dir).unwrap();
+        assert_eq!(Receipt::load_verified(&dir).unwrap(), receipt);
+
+        let tampered = fs::read_to_string(receipt_path(&dir)).unwrap().replace("1.0", "6.6");
+        fs::write(receipt_path(&dir), tampered).unwrap();
+        assert!(Receipt::load_verified(&dir).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn sha256sums_lists_all_files() {
+        let mut receipt = Receipt::new();
+        receipt.files.push(ManifestEntry { path: String::from("/etc/a"), sha256: String::from("aa"), step: String::from("s") });
+        receipt.package_files.push(PackageFileRecord { path: String::from("/usr/bin/b"), sha256: String::from("bb"), package: String::from("p") });
+        assert_eq!(receipt.sha256sums(), "aa  /etc/a\nbb  /usr/bin/b\n");
+    }
+
+}
@@ -0,0 +1,79 @@
+use std::cmp::Ordering;
+
+/// The release of a package version: without the epoch and the packaging revision,
+/// "1:1.4.0-2" is 1.4.0.
+pub fn of(version: &str) -> &str {
+    let version = version.split_once(':').map_or(version, |(_, v)| v);
+    version.split_once('-').map_or(version, |(v, _)| v)
+}
+
+/// A release to pin installs to is X.Y.Z.
+pub fn validate(release: &str) -> Result<(), String> {
+    let parts: Vec<&str> = release.split('.').collect();
+    match parts.len() == 3 && parts.iter().all(|p| !p.is_empty() && p.chars().all(|c| c.is_ascii_digit())) {
+        true => Ok(()),
+        false => Err(format!("The bitflux version is X.Y.Z, like 1.4.0, not {:?}.", release)),
+    }
+}
+
+/// Orders releases part by part, numbers as numbers: 1.10.0 is newer than 1.9.2.
+pub fn compare(a: &str, b: &str) -> Ordering {
+    let parts = |v: &str| -> Vec<u64> { of(v).split('.').map(|p| p.parse().unwrap_or(0)).collect() };
+    parts(a).cmp(&parts(b))
+}
+
+/// The newest packaging of `release` among the package versions `available`, by the numbers
+/// in their revisions: 1.4.0-10 is newer than 1.4.0-9.
+pub fn pick<'a>(available: &'a [String], release: &str) -> Option<&'a str> {
+    let numbers = |v: &str| -> Vec<u64> { v.split(|c: char| !c.is_ascii_digit()).filter_map(|n| n.parse().ok()).collect() };
+    available.iter().map(String::as_str).filter(|v| of(v) == release).max_by_key(|v| numbers(v))
+}
+
+/// The releases among the package versions `available`, newest first, for messages.
+pub fn list(available: &[String]) -> String {
+    let mut releases: Vec<&str> = available.iter().map(|v| of(v)).collect();
+    releases.sort_by(|a, b| compare(b, a));
+    releases.dedup();
+    match releases.is_empty() {
+        true => String::from("none"),
+        false => releases.join(", "),
+    }
+}
+
+/// Why installing `release` over the installed package version would be a downgrade, None
+/// when it isn't one.
+pub fn downgrade(installed: Option<&str>, release: &str) -> Option<String> {
+    let installed = installed?;
+    match compare(installed, release) {
+        Ordering::Greater => Some(format!("{} is installed, {} is older; pass --allow-downgrade to go back to it", of(installed), release)),
+        _ => None,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn releases() {
+        assert_eq!(of("1:1.4.0-2"), "1.4.0");
+        assert_eq!(of("1.4.0-1.el9"), "1.4.0");
+        assert_eq!(of("1.4.0"), "1.4.0");
+        assert!(validate("1.4.0").is_ok());
+        assert!(validate("1.4").is_err() && validate("1.4.x").is_err() && validate("v1.4.0").is_err());
+
+        assert_eq!(compare("1.10.0-1", "1.9.2"), Ordering::Greater);
+        assert_eq!(compare("1.4.0-3", "1.4.0"), Ordering::Equal);
+        let available = [String::from("1.3.2-1"), String::from("1.4.0-10"), String::from("1.4.0-9")];
+        assert_eq!(pick(&available, "1.4.0"), Some("1.4.0-10"));
+        assert_eq!(pick(&available, "1.5.0"), None);
+        assert_eq!(list(&available), "1.4.0, 1.3.2");
+        assert_eq!(list(&[]), "none");
+
+        assert!(downgrade(Some("1.5.0-1"), "1.4.0").unwrap().contains("--allow-downgrade"));
+        assert_eq!(downgrade(Some("1.4.0-1"), "1.4.0"), None);
+        assert_eq!(downgrade(None, "1.4.0"), None);
+    }
+
+}
@@ -0,0 +1,137 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::checksum::sha256_file;
+use crate::log::{self, Level};
+use crate::perms;
+use crate::pkg;
+use crate::receipt::{stashed_file, Receipt, RECEIPT_DIR};
+use crate::runcmd::{self, RunCmd};
+use crate::service::Service;
+use crate::selinux;
+use crate::verify::{verify, Drift};
+
+/// Puts a single drifted item back the way the receipt says it should be.
+fn repair_one(receipt: &Receipt, dir: &Path, drift: &Drift) -> Result<(), String> {
+    match drift.kind.as_str() {
+        "file" => {
+            let entry = receipt.files.iter().find(|f| f.path == drift.item).ok_or("not in receipt")?;
+            let stashed = stashed_file(dir, &entry.sha256);
+            if sha256_file(&stashed).map_err(|e| format!("no install time copy: {}", e))? != entry.sha256 {
+                return Err(String::from("install time copy is corrupt"));
+            }
+            if runcmd::dry_run(&format!("copy {} to {}", stashed.display(), entry.path)) {
+                return Ok(());
+            }
+            if let Some(parent) = Path::new(&entry.path).parent() {
+                fs::create_dir_all(parent).map_err(|e| e.to_string())?;
+            }
+            fs::copy(&stashed, &entry.path).map_err(|e| e.to_string())?;
+            Ok(())
+        }
+        "package" => {
+            let package = receipt.packages.iter().find(|p| p.name == drift.item).ok_or("not in receipt")?;
+            if !pkg::install_version(&package.name, &package.version) {
+                return Err(format!("failed to install {} {}", package.name, package.version));
+            }
+            Ok(())
+        }
+        "package_file" => {
+            let file = receipt.package_files.iter().find(|f| f.path == drift.item).ok_or("not in receipt")?;
+            let package = receipt.packages.iter().find(|p| p.name == file.package).ok_or("package not in receipt")?;
+            if !pkg::install_version(&package.name, &package.version) {
+                return Err(format!("failed to reinstall {} {}", package.name, package.version));
+            }
+            Ok(())
+        }
+        "service" => {
+            let service = receipt.services.iter().find(|s| s.name == drift.item).ok_or("not in receipt")?;
+            let unit = Service::new(&service.name);
+            let result = if service.enabled { unit.enable(true) } else { unit.disable(true) };
+            result.map_err(|e| e.to_string())
+        }
+        "module_param" => {
+            let param = receipt.module_params.iter()
+                .find(|p| format!("{}.{}", p.module, p.param) == drift.item)
+                .ok_or("not in receipt")?;
+            let sysfs = Path::new("/sys/module").join(&param.module).join("parameters").join(&param.param);
+            if !sysfs.exists() {
+                let out = RunCmd::args("modprobe", &[&param.module, &format!("{}={}", param.param, param.value)]).execute_output();
+                if out.exitcode != 0 {
+                    return Err(out.stderr.trim().to_string());
+                }
+                return Ok(());
+            }
+            if runcmd::dry_run(&format!("write {} to {}", param.value, sysfs.display())) {
+                return Ok(());
+            }
+            fs::write(&sysfs, &param.value).map_err(|e| format!("parameter is read only, reload {} to apply: {}", param.module, e))
+        }
+        "permissions" => {
+            let record = receipt.permissions.iter().find(|p| p.path == drift.item).ok_or("not in receipt")?;
+            perms::restore(record).map_err(|e| e.to_string())
+        }
+        "selinux" => selinux::restorecon(&drift.item).map_err(|e| e.to_string()),
+        other => Err(format!("don't know how to repair '{}'", other)),
+    }
+}
+
+/// Runs verify and re-applies only what drifted, then verifies again.
+pub fn run() -> io::Result<()> {
+    let dir = Path::new(RECEIPT_DIR);
+    let receipt = Receipt::load_verified(dir)?;
+    let report = verify(&receipt);
+
+    if report.drift.is_empty() {
+        log::log(Level::Info, &format!("Nothing to repair, {} items match the install receipt.", report.checked));
+        return Ok(());
+    }
+
+    for drift in &report.drift {
+        match repair_one(&receipt, dir, drift) {
+            Ok(()) => log::log(Level::Info, &format!("Repaired {} '{}'", drift.kind, drift.item)),
+            Err(e) => log::log(Level::Info, &format!("Could not repair {} '{}': {}", drift.kind, drift.item, e)),
+        }
+    }
+
+    let after = verify(&receipt);
+    after.print()?;
+    if !after.drift.is_empty() {
+        return Err(io::Error::other(format!("{} items still differ from the install receipt.", after.drift.len())));
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::ManifestEntry;
+
+    #[test]
+    fn restores_deleted_file_from_stash() {
+        let dir = std::env::temp_dir().join(format!("bitflux-repair-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("bitfluxcollector.conf");
+        fs::write(&file, "licensekey=abc\n").unwrap();
+
+        let mut receipt = Receipt::new();
+        receipt.files.push(ManifestEntry {
+            path: file.to_string_lossy().into_owned(),
+            sha256: sha256_file(&file).unwrap(),
+            step: String::from("configure"),
+        });
+        receipt.stash_files(&dir).unwrap();
+        fs::remove_file(&file).unwrap();
+
+        let report = verify(&receipt);
+        assert_eq!(report.drift.len(), 1);
+        repair_one(&receipt, &dir, &report.drift[0]).unwrap();
+        assert_eq!(fs::read_to_string(&file).unwrap(), "licensekey=abc\n");
+        assert!(verify(&receipt).drift.is_empty());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+}
@@ -0,0 +1,285 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::arch::Arch;
+use crate::download::Download;
+use crate::exitcode::Kind;
+use crate::perms::{self, FileKind};
+use crate::pkg::PackageManager;
+use crate::platform::{Family, OsRelease};
+use crate::runcmd::{self, shell_quote, RunCmd};
+use crate::script;
+use crate::selinux;
+use crate::workspace::Workspace;
+
+pub const REPO_URL: &str = "https://mirror.bitflux.ai/repository";
+pub const RPM_REPO_PATH: &str = "/etc/yum.repos.d/bitflux.repo";
+pub const ZYPP_REPO_PATH: &str = "/etc/zypp/repos.d/bitflux.repo";
+pub const RPM_GPG_KEY_URL: &str = "https://mirror.bitflux.ai/repository/keys/RPM-GPG-KEY-bitflux";
+/// The key the .repo file names, imported into the rpm database from here.
+pub const RPM_GPG_KEY_PATH: &str = "/etc/pki/rpm-gpg/RPM-GPG-KEY-bitflux";
+/// One-line format, Ubuntu before 24.04 and Debian before 13.
+pub const APT_LIST_PATH: &str = "/etc/apt/sources.list.d/bitflux.list";
+/// deb822 format.
+pub const APT_SOURCES_PATH: &str = "/etc/apt/sources.list.d/bitflux.sources";
+/// Only trusted for the bitflux source through signed-by, never put in trusted.gpg.d.
+pub const APT_KEYRING_PATH: &str = "/usr/share/keyrings/bitflux-archive-keyring.gpg";
+pub const APT_KEYRING_URL: &str = "https://mirror.bitflux.ai/repository/keys/bitflux-archive-keyring.gpg";
+/// The bitflux archive signing key, the only key either keyring may have.
+pub const KEY_FINGERPRINT: &str = "5E3C7A4F0B9D2E61C8A7F41D93B0E6C2A1F58D47";
+
+/// The dnf/yum repo definition for the `tree` release ("el9", "amzn2023") on `arch`.  The
+/// release and architecture are spelled out instead of $releasever/$basearch, which are "9.3"
+/// on some rebuilds and a locked, dated release like "2023.3.20240108" on Amazon Linux.  The
+/// key is the local copy setup() checked, not fetched again by dnf.
+pub fn rpm_repo(tree: &str, arch: Arch) -> String {
+    format!(
+        "[bitflux]\n\
+         name=BitFlux for {tree} - {arch}\n\
+         baseurl={url}/{tree}/{arch}\n\
+         enabled=1\n\
+         gpgcheck=1\n\
+         repo_gpgcheck=1\n\
+         gpgkey=file://{key}\n",
+        tree = tree,
+        arch = arch.rpm(),
+        url = REPO_URL,
+        key = RPM_GPG_KEY_PATH,
+    )
+}
+
+/// Writes the bitflux .repo file for this Enterprise Linux, Amazon Linux or SUSE host on
+/// `arch`, zypper reads the same format.
+pub fn write_rpm_repo<P: AsRef<Path>>(path: P, os: &OsRelease, arch: Arch) -> io::Result<()> {
+    let path = path.as_ref();
+    os.check_supported()?;
+    let tree = os.rpm_tree()
+        .ok_or_else(|| io::Error::other(format!("{} has no bitflux rpm repo.", os.pretty_name)))?;
+    let data = rpm_repo(&tree, arch);
+    if !script::file(path, &data) && !runcmd::dry_run(&format!("write {}", path.display())) {
+        fs::write(path, data)?;
+    }
+    perms::apply(path, FileKind::Unit)?;
+    // dnf only reads repo files with the policy's system_conf_t label, reset whatever an
+    // earlier copy left behind.
+    if selinux::is_enforcing() {
+        selinux::restorecon(path)?;
+    }
+    Ok(())
+}
+
+/// The apt source for the release in `os` on `arch`, in deb822 or one-line format.
+pub fn apt_source(os: &OsRelease, arch: Arch, deb822: bool) -> String {
+    let url = format!("{}/{}", REPO_URL, os.id);
+    if deb822 {
+        format!(
+            "Types: deb\nURIs: {}\nSuites: {}\nComponents: main\nArchitectures: {}\nSigned-By: {}\n",
+            url, os.codename, arch.deb(), APT_KEYRING_PATH
+        )
+    } else {
+        format!("deb [arch={} signed-by={}] {} {} main\n", arch.deb(), APT_KEYRING_PATH, url, os.codename)
+    }
+}
+
+/// Writes the bitflux apt source for `arch` in the format the release uses and removes one in
+/// the other format, apt warns about sources configured twice.  Returns the path written.
+pub fn write_apt_source(os: &OsRelease, arch: Arch) -> io::Result<PathBuf> {
+    os.check_supported()?;
+    if os.codename.is_empty() {
+        return Err(io::Error::other(format!("{} has no VERSION_CODENAME, can't pick the apt suite.", os.pretty_name)));
+    }
+    let deb822 = os.uses_deb822();
+    let (path, other) = match deb822 {
+        true => (APT_SOURCES_PATH, APT_LIST_PATH),
+        false => (APT_LIST_PATH, APT_SOURCES_PATH),
+    };
+    let data = apt_source(os, arch, deb822);
+    if script::file(path, &data) {
+        script::shell(&format!("rm -f {}", other));
+        return Ok(PathBuf::from(path));
+    }
+    if runcmd::dry_run(&format!("write {} and remove {}", path, other)) {
+        return Ok(PathBuf::from(path));
+    }
+    fs::write(path, data)?;
+    perms::apply(path, FileKind::Unit)?;
+    match fs::remove_file(other) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    Ok(PathBuf::from(path))
+}
+
+/// The files setup() writes or removes for the distro in `os`.
+pub fn paths(os: &OsRelease) -> Vec<&'static str> {
+    match os.family() {
+        Some(Family::Debian) => vec![APT_KEYRING_PATH, APT_LIST_PATH, APT_SOURCES_PATH],
+        Some(Family::Rhel) => vec![RPM_GPG_KEY_PATH, RPM_REPO_PATH],
+        Some(Family::Suse) => vec![RPM_GPG_KEY_PATH, ZYPP_REPO_PATH],
+        None => Vec::new(),
+    }
+}
+
+/// True when the bitflux repository is already configured the way setup() would configure it
+/// for `os` on `arch`.
+pub fn is_set_up(os: &OsRelease, arch: Arch) -> bool {
+    let has = |path: &str, expected: String| fs::read_to_string(path).is_ok_and(|data| data == expected);
+    match os.family() {
+        Some(Family::Debian) => {
+            let deb822 = os.uses_deb822();
+            let (path, other) = match deb822 {
+                true => (APT_SOURCES_PATH, APT_LIST_PATH),
+                false => (APT_LIST_PATH, APT_SOURCES_PATH),
+            };
+            Path::new(APT_KEYRING_PATH).exists() && !Path::new(other).exists() && has(path, apt_source(os, arch, deb822))
+        }
+        Some(Family::Rhel) => Path::new(RPM_GPG_KEY_PATH).exists() && os.rpm_tree().is_some_and(|tree| has(RPM_REPO_PATH, rpm_repo(&tree, arch))),
+        Some(Family::Suse) => Path::new(RPM_GPG_KEY_PATH).exists() && os.rpm_tree().is_some_and(|tree| has(ZYPP_REPO_PATH, rpm_repo(&tree, arch))),
+        None => false,
+    }
+}
+
+/// True when `data` is an OpenPGP public key, ASCII armored or in a binary keyring: a public
+/// key packet first, old or new packet format.
+pub fn is_public_key(data: &[u8], armored: bool) -> bool {
+    match armored {
+        true => data.starts_with(b"-----BEGIN PGP PUBLIC KEY BLOCK-----"),
+        false => matches!(data.first(), Some(0x98..=0x9b) | Some(0xc6)),
+    }
+}
+
+/// The fingerprints of the primary keys in `gpg --with-colons` output, one per "pub" record.
+pub fn primary_fingerprints(colons: &str) -> Vec<&str> {
+    let mut primary = false;
+    let mut fingerprints = Vec::new();
+    for line in colons.lines() {
+        let fields: Vec<&str> = line.split(':').collect();
+        match fields[0] {
+            "pub" => primary = true,
+            "fpr" if primary => {
+                fingerprints.push(fields.get(9).copied().unwrap_or(""));
+                primary = false;
+            }
+            _ => {}
+        }
+    }
+    fingerprints
+}
+
+/// Fails unless KEY_FINGERPRINT is the one key in the keyring at `path`, downloaded from `url`.
+fn check_fingerprint(url: &str, path: &str) -> io::Result<()> {
+    let home = Workspace::create()?;
+    let out = RunCmd::args("gpg", &["--batch", "--homedir", &home.path().to_string_lossy(), "--with-colons", "--show-keys", path])
+        .try_execute()
+        .map_err(io::Error::from)?;
+    match primary_fingerprints(&out.stdout).as_slice() {
+        [fingerprint] if fingerprint.eq_ignore_ascii_case(KEY_FINGERPRINT) => Ok(()),
+        keys => Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} has the keys [{}], not the bitflux key {}.", url, keys.join(", "), KEY_FINGERPRINT))),
+    }
+}
+
+/// Downloads the key at `url` to `path` and makes sure it's the bitflux key before anything
+/// trusts it, a captive portal's login page or a tampered mirror's key would otherwise end up
+/// as the keyring.  It's checked next to `path` and only moved there once it passes.
+pub(crate) fn download_key(url: &str, path: &str, armored: bool) -> io::Result<()> {
+    if let Some(dir) = Path::new(path).parent() {
+        if !script::shell(&format!("mkdir -p {}", shell_quote(&dir.to_string_lossy()))) && !runcmd::dry_run(&format!("create {}", dir.display())) {
+            fs::create_dir_all(dir)?;
+        }
+    }
+    let partial = format!("{}.download", path);
+    Download::new(url).save(Path::new(&partial))?;
+    if script::recording() {
+        script::shell(&format!(
+            "[ \"$(gpg --batch --with-colons --show-keys {} | awk -F: '$1 == \"pub\" {{ p = 1 }} $1 == \"fpr\" && p {{ print $10; p = 0 }}')\" = {} ]",
+            shell_quote(&partial),
+            KEY_FINGERPRINT
+        ));
+        script::shell(&format!("mv {} {}", shell_quote(&partial), shell_quote(path)));
+    } else if !runcmd::dry_run(&format!("check the fingerprint of {} and move it to {}", partial, path)) {
+        let checked = match is_public_key(&fs::read(&partial)?, armored) {
+            true => check_fingerprint(url, &partial),
+            false => Err(io::Error::new(io::ErrorKind::InvalidData, format!("{} isn't a PGP public key.", url))),
+        };
+        if let Err(e) = checked.and_then(|()| fs::rename(&partial, path)) {
+            let _ = fs::remove_file(&partial);
+            return Err(e);
+        }
+    }
+    perms::apply(path, FileKind::Unit)?;
+    Ok(())
+}
+
+/// Configures the bitflux package repository for the distro in `os` on `arch` through `pm`: the
+/// key first, then the source, then fresh metadata.
+pub fn setup(os: &OsRelease, pm: &PackageManager, arch: Arch) -> io::Result<()> {
+    match os.family() {
+        Some(Family::Debian) => {
+            download_key(APT_KEYRING_URL, APT_KEYRING_PATH, false)?;
+            write_apt_source(os, arch)?;
+        }
+        Some(family @ (Family::Rhel | Family::Suse)) => {
+            download_key(RPM_GPG_KEY_URL, RPM_GPG_KEY_PATH, true)?;
+            if !pm.import_key(Path::new(RPM_GPG_KEY_PATH)) {
+                return Err(io::Error::other(format!("Failed to import {} into the rpm database.", RPM_GPG_KEY_PATH)));
+            }
+            write_rpm_repo(if family == Family::Suse { ZYPP_REPO_PATH } else { RPM_REPO_PATH }, os, arch)?;
+        }
+        None => return Err(Kind::Unsupported.error(format!("No bitflux package repository for {}.", os.pretty_name))),
+    }
+    if !pm.refresh() {
+        return Err(Kind::Network.error("Failed to refresh the package metadata."));
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn el9_repo() {
+        let repo = rpm_repo("el9", Arch::Aarch64);
+        assert!(repo.starts_with("[bitflux]\n"));
+        assert!(repo.contains("\nbaseurl=https://mirror.bitflux.ai/repository/el9/aarch64\n"));
+        assert!(repo.contains("\ngpgcheck=1\n"));
+        assert!(repo.contains("\ngpgkey=file:///etc/pki/rpm-gpg/RPM-GPG-KEY-bitflux\n"));
+    }
+
+    #[test]
+    fn public_keys() {
+        assert!(is_public_key(b"-----BEGIN PGP PUBLIC KEY BLOCK-----\n\nmQINBF...", true));
+        assert!(!is_public_key(b"<html><body>Log in to continue</body></html>", true));
+        assert!(is_public_key(&[0x99, 0x02, 0x0d, 0x04], false));
+        assert!(is_public_key(&[0xc6, 0xc1, 0x4d, 0x04], false));
+        assert!(!is_public_key(b"<html>", false));
+        assert!(!is_public_key(b"", false));
+    }
+
+    #[test]
+    fn primary_keys_only() {
+        let colons = "pub:-:4096:1:D93B0E6C2A1F58D4:1700000000:::-:::scSC::::::23::0:\n\
+                      fpr:::::::::5E3C7A4F0B9D2E61C8A7F41D93B0E6C2A1F58D47:\n\
+                      uid:-::::1700000000::0123::bitflux archive <packages@bitflux.ai>::::::::::0:\n\
+                      sub:-:4096:1:0A1B2C3D4E5F6071:1700000000::::::e::::::23:\n\
+                      fpr:::::::::11112222333344445555666677778888AAAABBBB:\n";
+        assert_eq!(primary_fingerprints(colons), [KEY_FINGERPRINT]);
+        assert!(primary_fingerprints("").is_empty());
+    }
+
+    #[test]
+    fn apt_sources() {
+        let os = OsRelease::parse("ID=ubuntu\nVERSION_ID=\"22.04\"\nVERSION_CODENAME=jammy\n");
+        assert_eq!(
+            apt_source(&os, Arch::X86_64, false),
+            "deb [arch=amd64 signed-by=/usr/share/keyrings/bitflux-archive-keyring.gpg] https://mirror.bitflux.ai/repository/ubuntu jammy main\n"
+        );
+        let deb822 = apt_source(&os, Arch::Aarch64, true);
+        assert!(deb822.contains("\nSuites: jammy\n"));
+        assert!(deb822.contains("\nArchitectures: arm64\n"));
+        assert!(deb822.ends_with("Signed-By: /usr/share/keyrings/bitflux-archive-keyring.gpg\n"));
+    }
+
+}
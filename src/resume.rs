@@ -0,0 +1,247 @@
+use std::fs;
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use serde::{Deserialize, Serialize};
+
+use crate::engine::Step;
+use crate::install::{self, Options};
+use crate::log::{self, Level};
+use crate::runcmd;
+
+/// How far the latest install got, kept until one finishes.  It has the license key, only
+/// root can read it.
+pub const PROGRESS_PATH: &str = "/var/lib/bitflux-installer/install-progress.json";
+
+/// The steps the install being resumed completed, for the next track() to skip.
+static RESUMED: Mutex<Vec<String>> = Mutex::new(Vec::new());
+
+/// How far an install got.
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct Progress {
+    pub options: Options,
+    /// The steps applied or found done, in order.
+    pub done: Vec<String>,
+    /// The step it stopped in and why.
+    pub failed: Option<String>,
+    pub error: Option<String>,
+}
+
+impl Progress {
+
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Progress> {
+        let path = path.as_ref();
+        let data = match fs::read_to_string(path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Err(io::Error::new(e.kind(), "Nothing to resume, the last install finished or none ran."));
+            }
+            Err(e) => return Err(io::Error::new(e.kind(), format!("Can't read {}: {}", path.display(), e))),
+        };
+        serde_json::from_str(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", path.display(), e)))
+    }
+
+    /// Writes it atomically, a dry run doesn't.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        if runcmd::Context::current().dry_run {
+            return Ok(());
+        }
+        let path = path.as_ref();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let data = serde_json::to_string_pretty(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let tmp = path.with_extension("tmp");
+        let _ = fs::remove_file(&tmp);
+        fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(&tmp)
+            .and_then(|mut f| io::Write::write_all(&mut f, data.as_bytes()))?;
+        fs::rename(&tmp, path)
+    }
+
+}
+
+/// Where track() keeps the progress of an install.
+struct Tracker {
+    path: PathBuf,
+    progress: Mutex<Progress>,
+    resumed: Vec<String>,
+}
+
+impl Tracker {
+
+    fn update(&self, change: impl FnOnce(&mut Progress)) {
+        let mut progress = self.progress.lock().unwrap_or_else(|e| e.into_inner());
+        change(&mut progress);
+        if let Err(e) = progress.save(&self.path) {
+            log::log(Level::Warn, &format!("Warning: can't save the install progress to {}: {}", self.path.display(), e));
+        }
+    }
+
+    fn done(&self, step: &str) {
+        self.update(|p| {
+            p.done.push(String::from(step));
+            p.failed = None;
+            p.error = None;
+        });
+    }
+
+    /// A step rolled back is no longer done, the resumed install applies it again.
+    fn undone(&self, step: &str) {
+        self.update(|p| p.done.retain(|s| s != step));
+    }
+
+    fn failed(&self, step: &str, e: &io::Error) {
+        self.update(|p| {
+            p.failed = Some(String::from(step));
+            p.error = Some(e.to_string());
+        });
+    }
+
+}
+
+/// A step whose outcome is saved as it happens.
+struct Tracked<'a, C> {
+    step: Box<dyn Step<C> + 'a>,
+    tracker: Arc<Tracker>,
+}
+
+impl<C> Step<C> for Tracked<'_, C> {
+
+    fn name(&self) -> &'static str {
+        self.step.name()
+    }
+
+    fn title(&self) -> &'static str {
+        self.step.title()
+    }
+
+    fn after(&self) -> Vec<&'static str> {
+        self.step.after()
+    }
+
+    /// A step the resumed install completed is done, it's only checked to note what it found.
+    fn check(&self, ctx: &mut C) -> io::Result<bool> {
+        let done = self.step.check(ctx)
+            .map(|done| done || self.tracker.resumed.iter().any(|s| s == self.name()))
+            .inspect_err(|e| self.tracker.failed(self.name(), e))?;
+        if done {
+            self.tracker.done(self.name());
+        }
+        Ok(done)
+    }
+
+    fn apply(&self, ctx: &mut C) -> io::Result<()> {
+        match self.step.apply(ctx) {
+            Ok(()) => {
+                self.tracker.done(self.name());
+                Ok(())
+            }
+            Err(e) => {
+                self.tracker.failed(self.name(), &e);
+                Err(e)
+            }
+        }
+    }
+
+    fn rollback(&self, ctx: &mut C) -> io::Result<bool> {
+        let undone = self.step.rollback(ctx)?;
+        if undone {
+            self.tracker.undone(self.name());
+        }
+        Ok(undone)
+    }
+
+}
+
+/// `steps` saving their progress to `path` as they go, with the install's `opts`, so a
+/// failed install can be resumed.  The steps resuming() was given count as done.
+pub fn track<'a, C: 'a, P: AsRef<Path>>(steps: Vec<Box<dyn Step<C> + 'a>>, path: P, opts: &Options) -> Vec<Box<dyn Step<C> + 'a>> {
+    let resumed = std::mem::take(&mut *RESUMED.lock().unwrap_or_else(|e| e.into_inner()));
+    let progress = Progress { options: opts.clone(), ..Default::default() };
+    let tracker = Arc::new(Tracker { path: path.as_ref().to_path_buf(), progress: Mutex::new(progress), resumed });
+    tracker.update(|_| {});
+    steps.into_iter()
+        .map(|step| Box::new(Tracked { step, tracker: tracker.clone() }) as Box<dyn Step<C> + 'a>)
+        .collect()
+}
+
+/// Skips `done` in the next install, the steps one that failed completed.
+pub fn resuming(done: Vec<String>) {
+    *RESUMED.lock().unwrap_or_else(|e| e.into_inner()) = done;
+}
+
+/// Forgets the progress in `path`, the install finished or was rolled back.
+pub fn finish<P: AsRef<Path>>(path: P) {
+    if !runcmd::Context::current().dry_run {
+        let _ = fs::remove_file(path);
+    }
+}
+
+/// `installer install --resume`: the install that failed, with the options it had, skipping
+/// the steps it completed and retrying the one it failed in.
+pub fn run() -> io::Result<()> {
+    let progress = Progress::load(PROGRESS_PATH)?;
+    if let Some(step) = &progress.failed {
+        log::log(Level::Info, &format!("Resuming the install at the {} step, which failed: {}", step, progress.error.as_deref().unwrap_or("interrupted")));
+    }
+    resuming(progress.done);
+    install::run(&progress.options)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::engine;
+
+    struct Fake {
+        name: &'static str,
+        fails: bool,
+    }
+
+    impl Step<Vec<&'static str>> for Fake {
+
+        fn name(&self) -> &'static str {
+            self.name
+        }
+
+        fn apply(&self, applied: &mut Vec<&'static str>) -> io::Result<()> {
+            match self.fails {
+                true => Err(io::Error::other("network is unreachable")),
+                false => {
+                    applied.push(self.name);
+                    Ok(())
+                }
+            }
+        }
+
+    }
+
+    fn steps(fails: bool) -> Vec<Box<dyn Step<Vec<&'static str>>>> {
+        vec![Box::new(Fake { name: "repository", fails: false }), Box::new(Fake { name: "agent", fails }), Box::new(Fake { name: "service", fails: false })]
+    }
+
+    #[test]
+    fn resumes_at_the_failed_step() {
+        let path = std::env::temp_dir().join(format!("bitflux-progress-{}.json", std::process::id()));
+        let opts = Options { device_id: Some(String::from("web1")), ..Default::default() };
+        let mut applied = Vec::new();
+        assert!(engine::run(&track(steps(true), &path, &opts), &mut applied, false).is_err());
+        let progress = Progress::load(&path).unwrap();
+        assert_eq!(progress.options, opts);
+        assert_eq!(progress.done, ["repository"]);
+        assert_eq!((progress.failed.as_deref(), progress.error.as_deref()), (Some("agent"), Some("network is unreachable")));
+
+        resuming(progress.done);
+        let mut applied = Vec::new();
+        let report = engine::run(&track(steps(false), &path, &opts), &mut applied, false).unwrap();
+        assert_eq!(report.skipped, ["repository"]);
+        assert_eq!(applied, ["agent", "service"]);
+        assert_eq!(Progress::load(&path).unwrap().done, ["repository", "agent", "service"]);
+        finish(&path);
+        assert_eq!(Progress::load(&path).unwrap_err().kind(), io::ErrorKind::NotFound);
+    }
+
+}
@@ -1,162 +1,95 @@
-extern crate execute;
-
-use std::process::Stdio;
-
-use execute::{Execute, command, shell};
-
-/// Class to make it easy to run shell commands.
-///
-/// # Examples
-///
-/// ```
-/// use crate::runcmd::RunCmd;
-///
-/// RunCmd::new("echo \"Hello World\"").execute();
-///
-/// ```
-#[derive(Clone)]
-pub struct RunCmdOutput {
-    pub cmd: String,
-    pub stdout: String,
-    pub stderr: String,
-    pub exitcode: i32
-}
-
-pub struct RunCmd {
-    retval: RunCmdOutput,
-    verbose: bool,
-    execute: bool,
-    shell: bool
-}
-
-impl RunCmd {
-
-    pub fn new(cmd: &str) -> RunCmd {
-        RunCmd {
-            retval: RunCmdOutput { 
-                cmd: String::from(cmd),
-                stdout: String::from(""),
-                stderr: String::from(""),
-                exitcode: 0
-              },
-            verbose: false,
-            execute: false,
-            shell: false
-        }
-    }
-
-    /// Explicitly prints out stdout, stderr, and the exit code for the command run.
-    /// But it disables real time output
-    #[allow(dead_code)]
-    pub fn verbose(&mut self) -> &mut RunCmd {
-        self.verbose = true;
-        self
+//! The command layer is the bitflux-runcmd crate, this hooks the installer into it: commands
+//! go to its log and event stream, dry runs into `--emit-script`, sudo asks for its password
+//! once and `--profile-run` counts what they used.
+
+use std::any::Any;
+use std::io::{self, Write};
+use std::path::Path;
+use std::sync::Arc;
+use std::time::Duration;
+
+pub use bitflux_runcmd::*;
+use bitflux_runcmd::executor::Invocation;
+use bitflux_runcmd::hooks::Hooks;
+
+use crate::events;
+use crate::history;
+use crate::log::{self, Level};
+use crate::profiling::{self, Mark};
+use crate::proxy::Proxy;
+use crate::script;
+use crate::spinner;
+use crate::sudo;
+
+struct Installer;
+
+impl Hooks for Installer {
+
+    fn dry_run(&self, what: &str) -> bool {
+        script::note(what)
     }
 
-    /// Forces the command to run in a system shell.  Can fix some issue with complex commands.
-    #[allow(dead_code)]
-    pub fn shell(&mut self) -> &mut RunCmd {
-        self.shell = true;
-        self
+    fn dry_run_command(&self, cmd: &Invocation, may_fail: bool) -> bool {
+        let recording = script::recording();
+        if recording {
+            script::command(cmd, may_fail);
+        }
+        recording
     }
 
-    fn print(&self) {
-        println!("cmd:\n '{}'\n", self.retval.cmd);
-        println!("stdout:\n '{}'\n", self.retval.stdout);
-        println!("stderr:\n '{}'\n", self.retval.stderr);
-        println!("exitcode: '{}'\n\n", self.retval.exitcode);
+    fn hold(&self) -> Box<dyn Any> {
+        Box::new(spinner::hold())
     }
 
-    /// Standard execution.  If it doesn't succeed it will just panic.
-    pub fn execute(&mut self) {
-        self.execute = true;
-
-        let retval = self.execute_output();
-
-        if retval.exitcode != 0 {
-            panic!("Exitcode != 0")
+    fn echo(&self, stderr: bool) -> Box<dyn Write + Send> {
+        match stderr {
+            true => Box::new(spinner::Writer(io::stderr())),
+            false => Box::new(spinner::Writer(io::stdout())),
         }
     }
 
-    /// Execution returning a structure with the output: exitcode, stdout, stderr.
-    pub fn execute_output(&mut self) -> RunCmdOutput {
-        let mut executor;
-
-        if self.shell {
-            executor = shell(&self.retval.cmd)
-        } else {
-            executor = command(&self.retval.cmd)
-        }
-
-        if self.verbose || !self.execute {
-            executor.stdout(Stdio::piped());
-            executor.stderr(Stdio::piped());
-        }
-
-        let output = executor.execute_output().unwrap();
-
-        if let Some(exit_code) = output.status.code() {
-            self.retval.exitcode = exit_code;
-            self.retval.stdout =  String::from_utf8(output.stdout).unwrap();
-            self.retval.stderr =  String::from_utf8(output.stderr).unwrap();
-        } else {
-            self.retval.exitcode = -1;
-            self.retval.stderr =  String::from("Interrupted! in RunCmd");
-        }
+    fn escalation_env(&self) -> Vec<(String, String)> {
+        Proxy::from_env().vars()
+    }
 
-        if self.verbose {
-            self.print();
+    fn escalating(&self, tool: &Path) {
+        // The password is asked for once, before the first command that needs it.
+        if sudo::is_sudo(tool) && interactive() {
+            if let Err(e) = sudo::authenticate() {
+                log::log(Level::Warn, &format!("Warning: {}", e));
+            }
         }
-
-        return self.retval.clone()
     }
 
-}
-
-
-#[cfg(test)]
-mod tests {
-    use super::*;
-
-    #[test]
-    fn execute_pass() {
-        RunCmd::new("bash -c \"exit 0\"").execute();
+    fn credentials_cached(&self) -> bool {
+        sudo::cached()
     }
 
-    #[test]
-    #[should_panic]
-    fn execute_fail() {
-        RunCmd::new("bash -c \"exit -1\"").execute();
+    fn started(&self) -> Box<dyn Any> {
+        Box::new(profiling::mark())
     }
 
-    #[test]
-    fn execute_verbose() {
-        RunCmd::new("echo bar; exit 0")
-            .verbose()
-            .execute();
+    fn finished(&self, name: &str, out: &RunCmdOutput, took: Duration, captured: bool, started: Option<Box<dyn Any>>) {
+        log::file(Level::Debug, &log::command(out, took, captured));
+        history::record(out, took, captured);
+        events::command_executed(&out.cmd, out.exitcode, took.as_secs_f64());
+        if let Some(mark) = started.and_then(|m| m.downcast::<Option<Mark>>().ok()) {
+            profiling::command(name, *mark);
+        }
     }
 
-    #[test]
-    fn execute_shell() {
-        RunCmd::new("echo foobar; exit 0").shell().execute();
+    fn verbose(&self, out: &RunCmdOutput, took: Duration, output: bool) {
+        log::console(Level::Info, &log::command(out, took, output));
     }
 
-    #[test]
-    fn execute_output_pass() {
-        let retval = RunCmd::new("bash -c \"echo foo; >&2 echo bar; exit -1\"").execute_output();
-        assert_eq!(retval.exitcode, 255);
-        assert_eq!(&retval.stdout, "foo\n");
-        assert_eq!(&retval.stderr, "bar\n");
-        assert_eq!(&retval.cmd, "bash -c \"echo foo; >&2 echo bar; exit -1\"");
+    fn warn(&self, message: &str) {
+        log::log(Level::Warn, message);
     }
 
-    #[test]
-    fn execute_output_shell_pass() {
-        let retval = RunCmd::new("echo foo; >&2 echo bar; exit -1").shell().execute_output();
-        assert_eq!(retval.exitcode, 255);
-        assert_eq!(&retval.stdout, "foo\n");
-        assert_eq!(&retval.stderr, "bar\n");
-        assert_eq!(&retval.cmd, "echo foo; >&2 echo bar; exit -1");
-    }
+}
 
+/// Runs every command of the process with the installer's hooks.  main and the C API call it
+/// first thing, without it commands still run but aren't logged.
+pub fn init() {
+    hooks::set(Arc::new(Installer));
 }
@@ -0,0 +1,125 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::journal::Journal;
+use crate::perms::{FileKind, PermissionRecord};
+use crate::runcmd::RunCmd;
+use crate::template;
+
+pub const DEVICE_TREE_MODEL: &str = "/proc/device-tree/model";
+/// Where the Raspberry Pi firmware reads config.txt, Bookworm and newer first.
+pub const FIRMWARE_CONFIGS: &[&str] = &["/boot/firmware/config.txt", "/boot/config.txt"];
+pub const SYSCTL_PATH: &str = "/etc/sysctl.d/60-bitflux-sbc.conf";
+
+/// Board name from the device tree, "Raspberry Pi 4 Model B Rev 1.4".
+pub fn model() -> Option<String> {
+    let model = fs::read_to_string(DEVICE_TREE_MODEL).ok()?;
+    let model = model.trim_end_matches('\0').trim();
+    if model.is_empty() {
+        return None;
+    }
+    Some(model.to_string())
+}
+
+/// Boards booting from a device tree, the Raspberry Pi and other SBCs.
+pub fn is_sbc() -> bool {
+    model().is_some()
+}
+
+pub fn is_raspberry_pi() -> bool {
+    model().is_some_and(|m| m.starts_with("Raspberry Pi"))
+}
+
+/// The config.txt the firmware boots from, None on boards without the Pi firmware.
+pub fn firmware_config() -> Option<&'static str> {
+    if !is_raspberry_pi() {
+        return None;
+    }
+    FIRMWARE_CONFIGS.iter().copied().find(|p| Path::new(p).exists())
+}
+
+/// Points config.txt at `kernel` and `initramfs`, replacing any kernel= and initramfs lines.
+/// When there are none they go at the end in an [all] section so no board filter applies.
+pub fn set_boot_kernel(cfg: &str, kernel: &str, initramfs: &str) -> String {
+    let kernel_line = format!("kernel={}", kernel);
+    let initramfs_line = format!("initramfs {} followkernel", initramfs);
+    let mut replaced = false;
+    let mut out: Vec<String> = Vec::new();
+    for line in cfg.lines() {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("kernel=") {
+            out.push(kernel_line.clone());
+            replaced = true;
+        } else if trimmed.starts_with("initramfs ") || trimmed.starts_with("auto_initramfs=") {
+            if replaced {
+                out.push(initramfs_line.clone());
+            }
+        } else {
+            out.push(line.to_string());
+        }
+    }
+    if !replaced {
+        if out.iter().rev().find(|l| !l.trim().is_empty()).map(|l| l.trim()) != Some("[all]") {
+            out.push(String::from("[all]"));
+        }
+        out.extend([kernel_line, initramfs_line]);
+    } else if !out.contains(&initramfs_line) {
+        let at = out.iter().position(|l| *l == kernel_line).unwrap_or(out.len()) + 1;
+        out.insert(at, initramfs_line);
+    }
+    out.join("\n") + "\n"
+}
+
+/// The kernel config.txt boots, None for the firmware's default kernel*.img.
+pub fn boot_kernel(cfg: &str) -> Option<String> {
+    cfg.lines().find_map(|l| l.trim().strip_prefix("kernel=")).map(|k| k.trim().to_string())
+}
+
+/// Memory settings for boards with little RAM swapping to zram or SD cards: no swap
+/// readahead, which only wastes the little memory there is on flash, and eager swapping
+/// when the swap is compressed RAM.
+pub fn memory_defaults(zram: bool) -> io::Result<String> {
+    let swappiness = if zram { "100" } else { "60" };
+    template::render_named("sbc-sysctl.conf", &[("swappiness", swappiness)])
+}
+
+pub fn has_zram() -> bool {
+    fs::read_to_string("/proc/swaps").is_ok_and(|s| s.contains("/dev/zram"))
+}
+
+/// Installs the SBC memory defaults, journaled in `journal`, and loads them.
+pub fn apply_memory_defaults(journal: &mut Journal) -> io::Result<PermissionRecord> {
+    journal.file(SYSCTL_PATH)?;
+    let written = template::write(SYSCTL_PATH, &memory_defaults(has_zram())?, FileKind::Unit)?;
+    RunCmd::args("sysctl", &["-p", SYSCTL_PATH]).execute_output();
+    Ok(written.record)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn config_txt() {
+        let cfg = "dtparam=audio=on\n[pi4]\narm_boost=1\n[all]\nauto_initramfs=1\n";
+        let out = set_boot_kernel(cfg, "vmlinuz-6.6.31-swaphints", "initrd.img-6.6.31-swaphints");
+        assert_eq!(out.matches("[all]").count(), 1);
+        assert!(out.ends_with("[all]\nkernel=vmlinuz-6.6.31-swaphints\ninitramfs initrd.img-6.6.31-swaphints followkernel\n"));
+        assert!(!out.contains("auto_initramfs"));
+        assert_eq!(boot_kernel(&out).as_deref(), Some("vmlinuz-6.6.31-swaphints"));
+
+        let back = set_boot_kernel(&out, "vmlinuz-6.6.31+rpt-rpi-v8", "initrd.img-6.6.31+rpt-rpi-v8");
+        assert_eq!(back.matches("kernel=").count(), 1);
+        assert!(back.contains("\nkernel=vmlinuz-6.6.31+rpt-rpi-v8\ninitramfs initrd.img-6.6.31+rpt-rpi-v8 followkernel\n"));
+        assert_eq!(boot_kernel(cfg), None);
+    }
+
+    #[test]
+    fn zram_swappiness() {
+        assert!(memory_defaults(true).unwrap().contains("vm.swappiness = 100\n"));
+        assert!(memory_defaults(false).unwrap().contains("vm.page-cluster = 0\n"));
+    }
+
+}
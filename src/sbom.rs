@@ -0,0 +1,128 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde_json::{json, Value};
+
+use crate::checksum::sha256_file;
+use crate::log::{self, Level};
+use crate::receipt::{Receipt, RECEIPT_DIR};
+use crate::runcmd::RunCmd;
+
+/// Kernel modules shipped by the bitflux kernel packages.
+pub const KERNEL_MODULES: &[&str] = &["swaphints"];
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct KernelModule {
+    pub name: String,
+    pub version: String,
+    pub path: String,
+    pub sha256: String,
+}
+
+/// Looks up the installed kernel modules with modinfo, modules that aren't installed are skipped.
+pub fn kernel_modules() -> Vec<KernelModule> {
+    KERNEL_MODULES.iter().filter_map(|name| {
+        let path = RunCmd::args("modinfo", &["-n", name]).execute_output();
+        if path.exitcode != 0 {
+            return None;
+        }
+        let path = path.stdout.trim().to_string();
+        let version = RunCmd::args("modinfo", &["-F", "version", name]).execute_output().stdout.trim().to_string();
+        let sha256 = sha256_file(&path).ok()?;
+        Some(KernelModule { name: String::from(*name), version, path, sha256 })
+    }).collect()
+}
+
+fn hashes(sha256: &str) -> Value {
+    json!([{ "alg": "SHA-256", "content": sha256 }])
+}
+
+/// CycloneDX 1.5 document of every package, file and kernel module in `receipt`.
+pub fn document(receipt: &Receipt, modules: &[KernelModule]) -> Value {
+    let mut components: Vec<Value> = receipt.packages.iter().map(|package| {
+        let files: Vec<Value> = receipt.package_files.iter()
+            .filter(|f| f.package == package.name)
+            .map(|f| json!({ "type": "file", "name": f.path, "hashes": hashes(&f.sha256) }))
+            .collect();
+        json!({
+            "type": "application",
+            "bom-ref": format!("pkg:{}", package.name),
+            "name": package.name,
+            "version": package.version,
+            "components": files,
+        })
+    }).collect();
+
+    components.extend(receipt.files.iter().map(|f| json!({
+        "type": "file",
+        "name": f.path,
+        "hashes": hashes(&f.sha256),
+        "properties": [{ "name": "bitflux:step", "value": f.step }],
+    })));
+
+    components.extend(modules.iter().map(|m| json!({
+        "type": "device-driver",
+        "bom-ref": format!("kmod:{}", m.name),
+        "name": m.name,
+        "version": m.version,
+        "hashes": hashes(&m.sha256),
+        "properties": [{ "name": "bitflux:path", "value": m.path }],
+    })));
+
+    json!({
+        "bomFormat": "CycloneDX",
+        "specVersion": "1.5",
+        "version": 1,
+        "metadata": {
+            "tools": [{ "name": "bitflux-installer", "version": receipt.installer_version }],
+        },
+        "components": components,
+    })
+}
+
+/// Writes the SBOM for the current install to `path`.
+pub fn write<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    let receipt = Receipt::load_verified(RECEIPT_DIR)?;
+    let doc = document(&receipt, &kernel_modules());
+    let data = serde_json::to_string_pretty(&doc).map_err(io::Error::other)?;
+    fs::write(path.as_ref(), data)?;
+    log::log(Level::Info, &format!("Wrote SBOM to '{}'", path.as_ref().display()));
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::ManifestEntry;
+    use crate::receipt::{PackageFileRecord, PackageRecord};
+
+    #[test]
+    fn lists_packages_files_and_modules() {
+        let mut receipt = Receipt::new();
+        receipt.packages.push(PackageRecord { name: String::from("bitfluxcollector"), version: String::from("1.2.3") });
+        receipt.package_files.push(PackageFileRecord {
+            path: String::from("/opt/bitflux/bin/bitfluxcollector"),
+            sha256: String::from("aa"),
+            package: String::from("bitfluxcollector"),
+        });
+        receipt.files.push(ManifestEntry { path: String::from("/etc/modules-load.d/swaphints.conf"), sha256: String::from("bb"), step: String::from("kernel") });
+        let modules = [KernelModule {
+            name: String::from("swaphints"),
+            version: String::from("0.1"),
+            path: String::from("/lib/modules/5.15.0-100-swaphints/extra/swaphints.ko"),
+            sha256: String::from("cc"),
+        }];
+
+        let doc = document(&receipt, &modules);
+        assert_eq!(doc["bomFormat"], "CycloneDX");
+        let components = doc["components"].as_array().unwrap();
+        assert_eq!(components.len(), 3);
+        assert_eq!(components[0]["version"], "1.2.3");
+        assert_eq!(components[0]["components"][0]["hashes"][0]["content"], "aa");
+        assert_eq!(components[1]["hashes"][0]["content"], "bb");
+        assert_eq!(components[2]["type"], "device-driver");
+    }
+
+}
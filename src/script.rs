@@ -0,0 +1,307 @@
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::executor::Invocation;
+use crate::runcmd::shell_quote;
+
+/// How `install --emit-script` asks for the license key, so it isn't in the script.
+pub const LICENSE_KEY_VAR: &str = "BITFLUX_LICENSE_KEY";
+/// Ends the here documents files are written with.
+const EOF_MARKER: &str = "BITFLUX_EOF";
+/// What the installer runs only to see how the host is, left out of the script.  It applies
+/// every step instead of checking which are done.
+const QUERIES: &[&str] = &[
+    "dpkg-query", "rpm -q", "systemctl is-", "systemctl show", "systemctl status", "systemd-detect-virt",
+    "uname", "getenforce", "sestatus", "semodule -l", "aa-status", "mokutil --sb-state",
+    "firewall-cmd --state", "firewall-cmd --query", "firewall-cmd --list", "ufw status", "iptables -C", "iptables-save",
+    "dkms status", "modinfo", "lsmod", "apt-cache", "hostname", "sudo -n -v",
+];
+
+/// What the recording has so far, None when not recording.
+static SCRIPT: Mutex<Option<Script>> = Mutex::new(None);
+
+/// A bash script of what an install does, built up while it runs as a dry run.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Script {
+    /// What's installed on what, for the header.
+    pub title: String,
+    /// Values kept out of the script, each with the variable it reads it from instead.
+    pub secrets: Vec<(String, String)>,
+    pub body: String,
+    /// Every step and what it does, for plans.
+    pub sections: Vec<Section>,
+    /// Commands and changes before the first step are the installer's own, they're left out.
+    in_step: bool,
+}
+
+/// A step of the script and its actions, a command, a file written or a note each.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Section {
+    pub name: String,
+    pub title: String,
+    pub actions: Vec<String>,
+}
+
+impl Script {
+
+    pub fn new(secrets: Vec<(String, String)>) -> Script {
+        Script { secrets, ..Default::default() }
+    }
+
+    /// `text` as a shell word, with the secrets in it read from their variables.
+    pub fn quote(&self, text: &str) -> String {
+        let mut out = String::new();
+        let mut rest = text;
+        while !rest.is_empty() {
+            let next = self.secrets.iter()
+                .filter_map(|(value, var)| rest.find(value.as_str()).map(|at| (at, value, var)))
+                .filter(|(_, value, _)| !value.is_empty())
+                .min_by_key(|(at, _, _)| *at);
+            match next {
+                Some((at, value, var)) => {
+                    if at > 0 {
+                        out.push_str(&shell_quote(&rest[..at]));
+                    }
+                    out.push_str(&format!("\"${{{}}}\"", var));
+                    rest = &rest[at + value.len()..];
+                }
+                None => {
+                    out.push_str(&shell_quote(rest));
+                    rest = "";
+                }
+            }
+        }
+        if out.is_empty() {
+            out.push_str("''");
+        }
+        out
+    }
+
+    fn has_secret(&self, text: &str) -> bool {
+        self.secrets.iter().any(|(value, _)| !value.is_empty() && text.contains(value.as_str()))
+    }
+
+    /// `text` with the secrets in it shown as their variables.
+    fn hide(&self, text: &str) -> String {
+        let mut text = String::from(text);
+        for (value, var) in self.secrets.iter().filter(|(v, _)| !v.is_empty()) {
+            text = text.replace(value.as_str(), &format!("${{{}}}", var));
+        }
+        text
+    }
+
+    fn action(&mut self, action: String) {
+        if let Some(section) = self.sections.last_mut() {
+            section.actions.push(action);
+        }
+    }
+
+    pub fn step(&mut self, index: usize, total: usize, name: &str, title: &str) {
+        self.in_step = true;
+        self.body.push_str(&format!("\n# Step {}/{}: {}\n", index, total, title));
+        self.sections.push(Section { name: String::from(name), title: String::from(title), actions: Vec::new() });
+    }
+
+    /// The shell line for `cmd`, `|| true` when the installer lets it fail.  None for queries.
+    pub fn command_line(&self, cmd: &Invocation, may_fail: bool) -> Option<String> {
+        if QUERIES.iter().any(|q| cmd.cmd.starts_with(q)) {
+            return None;
+        }
+        let mut line = String::new();
+        if let Some(data) = &cmd.stdin {
+            line.push_str(&format!("printf '%s' {} | ", self.quote(data)));
+        }
+        if !cmd.env.is_empty() {
+            line.push_str("env ");
+            for (key, value) in &cmd.env {
+                line.push_str(&format!("{}={} ", key, self.quote(value)));
+            }
+        }
+        match &cmd.argv {
+            Some(argv) => line.push_str(&argv.iter().map(|a| self.quote(a)).collect::<Vec<String>>().join(" ")),
+            None => line.push_str(&self.hide(&cmd.cmd)),
+        }
+        if let Some(dir) = &cmd.cwd {
+            line = format!("(cd {} && {})", shell_quote(&dir.to_string_lossy()), line);
+        }
+        if may_fail {
+            line.push_str(" || true");
+        }
+        Some(line)
+    }
+
+    pub fn command(&mut self, cmd: &Invocation, may_fail: bool) {
+        if let Some(line) = self.command_line(cmd, may_fail).filter(|_| self.in_step) {
+            self.body.push_str(&line);
+            self.body.push('\n');
+            self.action(line);
+        }
+    }
+
+    /// A line of shell as it is, the caller quotes it.
+    pub fn shell(&mut self, line: &str) {
+        if self.in_step {
+            self.body.push_str(line);
+            self.body.push('\n');
+            self.action(String::from(line));
+        }
+    }
+
+    /// Writes `contents` to `path`, with a here document unless it holds a secret.
+    pub fn file(&mut self, path: &Path, contents: &str) {
+        if !self.in_step {
+            return;
+        }
+        let target = shell_quote(&path.to_string_lossy());
+        let heredoc = contents.ends_with('\n') && !self.has_secret(contents) && !contents.lines().any(|l| l == EOF_MARKER);
+        match heredoc {
+            true => self.body.push_str(&format!("cat > {} <<'{}'\n{}{}\n", target, EOF_MARKER, contents, EOF_MARKER)),
+            false => self.body.push_str(&format!("printf '%s' {} > {}\n", self.quote(contents), target)),
+        }
+        // What plan::classify() reads back: the path, then the contents indented.
+        let mut action = format!("write {}", path.display());
+        for line in self.hide(contents).lines() {
+            action.push_str(&format!("\n  | {}", line));
+        }
+        self.action(action);
+    }
+
+    /// Something the installer does itself that the script can't, as a comment.
+    pub fn note(&mut self, what: &str) {
+        if self.in_step {
+            self.body.push_str(&format!("# Not scripted, the installer does this itself: {}\n", what));
+            self.action(format!("installer: {}", what));
+        }
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::from("#!/bin/bash\n");
+        if !self.title.is_empty() {
+            out.push_str(&format!("# {}\n", self.title));
+        }
+        out.push_str(&format!("# Written by `installer install --emit-script` {}: every command the install runs, by\n", env!("CARGO_PKG_VERSION")));
+        out.push_str("# step. The checks for what's already done are left out. Review it, then run it as root.\n");
+        out.push_str("set -euo pipefail\n");
+        out.push_str("[ \"$(id -u)\" -eq 0 ] || { echo \"Run this script as root.\" >&2; exit 1; }\n");
+        for (_, var) in &self.secrets {
+            out.push_str(&format!(": \"${{{}:?set {} first, it isn't written into the script}}\"\n", var, var));
+        }
+        out.push_str(&self.body);
+        out
+    }
+
+}
+
+fn with<T>(record: impl FnOnce(&mut Script) -> T) -> Option<T> {
+    SCRIPT.lock().unwrap_or_else(|e| e.into_inner()).as_mut().map(record)
+}
+
+/// Records what the run does from now on instead of only printing a dry run.  The run has to
+/// be a dry run.
+pub fn start(secrets: Vec<(String, String)>) {
+    *SCRIPT.lock().unwrap_or_else(|e| e.into_inner()) = Some(Script::new(secrets));
+}
+
+pub fn recording() -> bool {
+    SCRIPT.lock().unwrap_or_else(|e| e.into_inner()).is_some()
+}
+
+pub fn title(title: &str) {
+    with(|s| s.title = String::from(title));
+}
+
+pub fn step(index: usize, total: usize, name: &str, title: &str) {
+    with(|s| s.step(index, total, name, title));
+}
+
+pub fn command(cmd: &Invocation, may_fail: bool) {
+    with(|s| s.command(cmd, may_fail));
+}
+
+/// Records `line` of shell, true when recording so the caller skips its dry run message.
+pub fn shell(line: &str) -> bool {
+    with(|s| s.shell(line)).is_some()
+}
+
+/// Records writing `contents` to `path`, true when recording.
+pub fn file<P: AsRef<Path>>(path: P, contents: &str) -> bool {
+    with(|s| s.file(path.as_ref(), contents)).is_some()
+}
+
+/// Records what a dry run says the installer would do, true when recording.
+pub fn note(what: &str) -> bool {
+    with(|s| s.note(what)).is_some()
+}
+
+/// Stops recording and returns the script, None when it wasn't recording.
+pub fn take() -> Option<Script> {
+    SCRIPT.lock().unwrap_or_else(|e| e.into_inner()).take()
+}
+
+/// Stops recording and writes the script to `path`, executable by root only.
+pub fn finish<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    let path = path.as_ref();
+    let Some(script) = take() else {
+        return Ok(());
+    };
+    let _ = fs::remove_file(path);
+    fs::OpenOptions::new().write(true).create_new(true).mode(0o700).open(path)
+        .and_then(|mut f| f.write_all(script.render().as_bytes()))
+        .map_err(|e| io::Error::new(e.kind(), format!("Can't write the script {}: {}", path.display(), e)))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn invocation(argv: &[&str]) -> Invocation {
+        Invocation {
+            cmd: argv.iter().map(|a| shell_quote(a)).collect::<Vec<String>>().join(" "),
+            argv: Some(argv.iter().map(|a| String::from(*a)).collect()),
+            root: true, cwd: None, env: Vec::new(), stdin: None,
+        }
+    }
+
+    #[test]
+    fn install_as_a_script() {
+        let mut script = Script::new(vec![(String::from("ABCD-1234"), String::from(LICENSE_KEY_VAR))]);
+        script.command(&invocation(&["systemctl", "daemon-reload"]), false);
+        script.step(1, 2, "agent", "Installing the agent");
+        script.command(&invocation(&["dpkg-query", "-W", "bitfluxcollector"]), false);
+        let mut install = invocation(&["apt-get", "install", "-y", "bitfluxcollector"]);
+        install.env.push((String::from("DEBIAN_FRONTEND"), String::from("noninteractive")));
+        script.command(&install, false);
+        script.step(2, 2, "service", "Configuring the agent service");
+        script.file(Path::new("/etc/bitflux/config"), "licensekey=ABCD-1234\n");
+        script.file(Path::new("/etc/systemd/system/bitfluxcollector.service.d/10-hardening.conf"), "[Service]\nProtectHome=yes\n");
+        let mut reload = invocation(&["systemctl", "restart", "bitfluxcollector"]);
+        reload.cwd = Some(PathBuf::from("/"));
+        script.command(&reload, true);
+        script.note("write the signed receipt into /var/lib/bitflux");
+        let rendered = script.render();
+        assert!(rendered.starts_with("#!/bin/bash\n"));
+        assert!(rendered.contains("\n: \"${BITFLUX_LICENSE_KEY:?set BITFLUX_LICENSE_KEY first, it isn't written into the script}\"\n"));
+        assert!(!rendered.contains("ABCD-1234"));
+        assert!(rendered.ends_with(concat!(
+            "\n# Step 1/2: Installing the agent\n",
+            "env DEBIAN_FRONTEND=noninteractive apt-get install -y bitfluxcollector\n",
+            "\n# Step 2/2: Configuring the agent service\n",
+            "printf '%s' licensekey=\"${BITFLUX_LICENSE_KEY}\"'\n' > /etc/bitflux/config\n",
+            "cat > /etc/systemd/system/bitfluxcollector.service.d/10-hardening.conf <<'BITFLUX_EOF'\n[Service]\nProtectHome=yes\nBITFLUX_EOF\n",
+            "(cd / && systemctl restart bitfluxcollector) || true\n",
+            "# Not scripted, the installer does this itself: write the signed receipt into /var/lib/bitflux\n",
+        )));
+        assert!(!rendered.contains("daemon-reload"));
+        assert_eq!(script.sections[1].actions[..2], [
+            String::from("write /etc/bitflux/config\n  | licensekey=${BITFLUX_LICENSE_KEY}"),
+            String::from("write /etc/systemd/system/bitfluxcollector.service.d/10-hardening.conf\n  | [Service]\n  | ProtectHome=yes"),
+        ]);
+        assert_eq!(script.sections[1].actions[3], "installer: write the signed receipt into /var/lib/bitflux");
+    }
+
+}
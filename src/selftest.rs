@@ -0,0 +1,144 @@
+use std::fs;
+use std::io;
+
+use crate::download::Download;
+use crate::offline;
+use crate::output::{self, Format};
+use crate::perms::FileKind;
+use crate::preflight::{self, check, Check, Status};
+use crate::privsep::{self, unprivileged_cmd};
+use crate::repo::RPM_GPG_KEY_URL;
+use crate::runcmd::{self, shell, RunCmd};
+use crate::template;
+use crate::workspace::Workspace;
+
+/// What the template check renders and writes.
+const TEMPLATE: &str = "[Service]\nExecStart={{ exec }} --config {{config}}\n";
+const RENDERED: &str = "[Service]\nExecStart=/bin/true --config /etc/bitflux/agent.conf\n";
+
+/// Commands run and their output and exit codes come back.
+pub fn executor() -> Check {
+    let echo = RunCmd::args("echo", &["bitflux"]).execute_output();
+    if echo.exitcode != 0 || echo.stdout != "bitflux\n" {
+        return check("executor", Status::Fail, format!("echo gave exit {} and {:?}: {}", echo.exitcode, echo.stdout, echo.stderr.trim()));
+    }
+    match RunCmd::args("false", &[]).execute_output().exitcode {
+        1 => check("executor", Status::Pass, String::from("commands run, their output and exit codes come back")),
+        code => check("executor", Status::Fail, format!("false exited {}, not 1", code)),
+    }
+}
+
+/// The shell command lines run with is there and runs a pipeline.
+pub fn shell() -> Check {
+    let shell = shell::current();
+    let Some(program) = runcmd::which(shell.program()) else {
+        return check("shell", Status::Fail, format!("{} isn't on PATH", shell.program()));
+    };
+    let out = RunCmd::new("echo selftest | tr a-z A-Z").shell().execute_output();
+    match out.stdout.trim() {
+        "SELFTEST" => check("shell", Status::Pass, format!("{} runs command lines", program.display())),
+        _ => check("shell", Status::Fail, format!("{} ran a pipeline to exit {}: {}", program.display(), out.exitcode, out.stderr.trim())),
+    }
+}
+
+/// Commands get root where they need it and drop it where they don't.
+pub fn privileges() -> Check {
+    if !privsep::is_root() {
+        let Some(tool) = runcmd::escalation_tool() else {
+            return check("root", Status::Fail, String::from("not root and there's no sudo, doas or pkexec"));
+        };
+        return match RunCmd::args("true", &[]).as_root().try_execute() {
+            Ok(_) => check("root", Status::Pass, format!("commands that need root get it through {}", tool.display())),
+            Err(e) => check("root", Status::Fail, format!("{} didn't run a command as root: {}", tool.display(), e)),
+        };
+    }
+    let Some(ids) = privsep::unprivileged() else {
+        return check("root", Status::Warn, String::from("running as root, there's no bitflux or nobody account to download as"));
+    };
+    let id = unprivileged_cmd("id", &["-u"]).execute_output();
+    match id.stdout.trim().parse::<u32>() {
+        Ok(uid) if uid == ids.uid => check("root", Status::Pass, format!("running as root, downloads run as uid {}", uid)),
+        _ => check("root", Status::Fail, format!("dropping to uid {} didn't work: {}", ids.uid, id.stderr.trim())),
+    }
+}
+
+/// A small file downloads from the repository, as releases and bundles do.
+pub fn download(offline: bool) -> Check {
+    if offline || offline::is_offline() {
+        return check("download", Status::Warn, String::from("skipped, --offline"));
+    }
+    let result = Workspace::create().and_then(|scratch| {
+        let dest = scratch.join("key");
+        Download { url: String::from(RPM_GPG_KEY_URL), sha256: None, keyring: None }.save(&dest)?;
+        fs::metadata(&dest).map(|m| m.len())
+    });
+    match result {
+        Ok(0) => check("download", Status::Fail, format!("{} came down empty", RPM_GPG_KEY_URL)),
+        Ok(size) => check("download", Status::Pass, format!("{} bytes from {}", size, RPM_GPG_KEY_URL)),
+        Err(e) => check("download", Status::Fail, e.to_string()),
+    }
+}
+
+/// Templates render, and as root are written with their owner and mode.
+pub fn templates() -> Check {
+    match template::render(TEMPLATE, &[("exec", "/bin/true"), ("config", "/etc/bitflux/agent.conf")]) {
+        Ok(unit) if unit == RENDERED => {}
+        Ok(unit) => return check("template", Status::Fail, format!("rendered {:?}", unit)),
+        Err(e) => return check("template", Status::Fail, e.to_string()),
+    }
+    if !privsep::is_root() {
+        return check("template", Status::Pass, String::from("rendered, writing them needs root"));
+    }
+    let written = Workspace::create().and_then(|scratch| {
+        let path = scratch.join("selftest.service");
+        template::write(&path, RENDERED, FileKind::Unit)?;
+        fs::read_to_string(&path)
+    });
+    match written {
+        Ok(unit) if unit == RENDERED => check("template", Status::Pass, String::from("rendered and written")),
+        Ok(_) => check("template", Status::Fail, String::from("what was written reads back different")),
+        Err(e) => check("template", Status::Fail, format!("writing failed: {}", e)),
+    }
+}
+
+/// Every check, the download skipped when `offline`.
+pub fn run(offline: bool) -> Vec<Check> {
+    vec![executor(), shell(), privileges(), download(offline), templates()]
+}
+
+/// `installer selftest`: the checks as a table or as JSON, failing when any of them failed.
+pub fn report(offline: bool, format: Format) -> io::Result<()> {
+    let checks = run(offline);
+    match format {
+        Format::Text => preflight::print(&checks)?,
+        Format::Json => output::json(&checks)?,
+    }
+    let failed: Vec<&str> = checks.iter().filter(|c| c.status == Status::Fail).map(|c| c.name.as_str()).collect();
+    match failed.is_empty() {
+        true => Ok(()),
+        false => Err(io::Error::other(format!("Self-test failed: {}.", failed.join(", ")))),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::executor::{self, MockExecutor, Reply};
+
+    #[test]
+    fn executor_round_trip() {
+        let mock = Arc::new(MockExecutor::new());
+        mock.on("echo", Reply::ok("bitflux\n")).on("false", Reply::exit(1, ""));
+        let _using = executor::using(mock.clone());
+        assert_eq!(executor().status, Status::Pass);
+
+        let mock = Arc::new(MockExecutor::new());
+        mock.on("echo", Reply::ok("bitflux\n"));
+        let _using = executor::using(mock.clone());
+        assert_eq!(executor().detail, "false exited 0, not 1");
+        assert_eq!(download(true).status, Status::Warn);
+    }
+
+}
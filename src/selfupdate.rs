@@ -0,0 +1,137 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+
+use serde::Deserialize;
+
+use crate::fips;
+use crate::log::{self, Level};
+use crate::perms::{self, FileKind};
+use crate::privsep;
+use crate::runcmd;
+use crate::signature;
+use crate::writable;
+
+/// Where the installer release metadata lives, one `latest-<target>.json` per release_target().
+pub const RELEASE_URL: &str = "https://mirror.bitflux.ai/repository/installer";
+
+/// Set on the re-executed installer so it doesn't try to update itself again.
+const UPDATED_ENV: &str = "BITFLUX_INSTALLER_UPDATED";
+
+/// Contents of `latest-<target>.json`.
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+pub struct Release {
+    pub version: String,
+    pub url: String,
+    /// Signature of the binary by the release key, hex or minisign.  When missing the
+    /// detached `<url>.minisig` is used.
+    #[serde(default)]
+    pub signature: Option<String>,
+    /// Built and validated for FIPS mode.
+    #[serde(default)]
+    pub fips: bool,
+}
+
+/// Which build to fetch: Linux builds are named by architecture alone, other platforms
+/// (the macOS management CLI) by `<os>-<arch>`.
+pub fn release_target() -> String {
+    match env::consts::OS {
+        "linux" => String::from(env::consts::ARCH),
+        os => format!("{}-{}", os, env::consts::ARCH),
+    }
+}
+
+/// Fetches the metadata for the newest installer release, from the FIPS channel on FIPS hosts.
+pub fn latest_release() -> io::Result<Release> {
+    let fips = fips::is_enabled();
+    let url = format!("{}/latest-{}{}.json", RELEASE_URL, release_target(), fips::channel_suffix(fips));
+    let data = privsep::fetch(&url)?;
+    let release: Release = serde_json::from_str(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    if fips && !release.fips {
+        fips::warn_unvalidated(&format!("installer {}", release.version));
+    }
+    Ok(release)
+}
+
+/// True if dotted version `candidate` is newer than `current`.
+pub fn is_newer(candidate: &str, current: &str) -> bool {
+    let parse = |v: &str| -> Vec<u64> {
+        v.trim_start_matches('v')
+            .split(['.', '-'])
+            .map(|p| p.parse().unwrap_or(0))
+            .collect()
+    };
+    parse(candidate) > parse(current)
+}
+
+/// Replaces the running binary with the latest release if there is a newer one.
+/// Returns true when the binary was replaced.
+pub fn self_update() -> io::Result<bool> {
+    let current = env!("CARGO_PKG_VERSION");
+    let release = latest_release()?;
+
+    if !is_newer(&release.version, current) {
+        log::log(Level::Info, &format!("Installer {} is up to date.", current));
+        return Ok(false);
+    }
+    log::log(Level::Info, &format!("Updating installer {} -> {}", current, release.version));
+
+    // Download next to the binary so the final rename stays on one filesystem and is atomic.
+    let exe = env::current_exe()?;
+    if runcmd::dry_run(&format!("replace {} with {}", exe.display(), release.url)) {
+        return Ok(false);
+    }
+    let staged = exe.with_file_name(".installer.update");
+    let _unlocked = writable::prepare(&exe)?;
+    signature::download_verified(&release.url, &staged, release.signature.as_deref())?;
+
+    perms::apply(&staged, FileKind::Binary)?;
+    fs::rename(&staged, &exe)?;
+    log::log(Level::Info, &format!("Installer updated to {}", release.version));
+    Ok(true)
+}
+
+/// Updates the installer and re-executes it with the original arguments.  Does nothing
+/// if this process is already the re-executed copy.
+pub fn auto_update() -> io::Result<()> {
+    if env::var_os(UPDATED_ENV).is_some() {
+        return Ok(());
+    }
+    if !self_update()? {
+        return Ok(());
+    }
+
+    let exe = env::current_exe()?;
+    let err = Command::new(exe)
+        .args(env::args_os().skip(1))
+        .env(UPDATED_ENV, "1")
+        .exec();
+    Err(err)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_ordering() {
+        assert!(is_newer("0.2.0", "0.1.0"));
+        assert!(is_newer("0.10.0", "0.9.3"));
+        assert!(is_newer("v1.0.0", "0.9.9"));
+        assert!(!is_newer("0.1.0", "0.1.0"));
+        assert!(!is_newer("0.0.9", "0.1.0"));
+    }
+
+    #[test]
+    fn release_parses() {
+        let release: Release = serde_json::from_str(
+            r#"{"version": "0.2.0", "url": "https://example/installer"}"#
+        ).unwrap();
+        assert_eq!(release.version, "0.2.0");
+        assert_eq!(release.signature, None);
+    }
+
+}
@@ -0,0 +1,97 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::runcmd::RunCmd;
+
+pub const ENFORCE_PATH: &str = "/sys/fs/selinux/enforce";
+
+/// File context types for everything the installer deploys, by directory.  Longest match wins.
+pub const FCONTEXTS: &[(&str, &str)] = &[
+    ("/opt/bitflux", "usr_t"),
+    ("/opt/bitflux/bin", "bin_t"),
+    ("/opt/bitflux/config", "etc_t"),
+    ("/opt/bitflux/data", "var_lib_t"),
+    ("/var/lib/bitflux", "var_lib_t"),
+    ("/etc/systemd/system/bitfluxcollector.service.d", "systemd_unit_file_t"),
+];
+
+/// True when SELinux is loaded and enforcing.
+pub fn is_enforcing() -> bool {
+    fs::read_to_string(ENFORCE_PATH).map(|v| v.trim() == "1").unwrap_or(false)
+}
+
+/// The fcontext directory and type `path` should be labelled with.
+pub fn fcontext_for(path: &Path) -> Option<(&'static str, &'static str)> {
+    FCONTEXTS.iter()
+        .filter(|(dir, _)| path.starts_with(dir))
+        .max_by_key(|(dir, _)| dir.len())
+        .copied()
+}
+
+/// The type field of an SELinux context, "system_u:object_r:bin_t:s0" gives "bin_t".
+pub fn context_type(context: &str) -> Option<String> {
+    context.trim().split(':').nth(2).map(String::from)
+}
+
+/// Current type of `path`, None without SELinux.
+pub fn actual_type<P: AsRef<Path>>(path: P) -> Option<String> {
+    let out = RunCmd::args("stat", &["-c", "%C", &path.as_ref().to_string_lossy()]).execute_output();
+    if out.exitcode != 0 {
+        return None;
+    }
+    context_type(&out.stdout)
+}
+
+/// Registers the fcontext rule for `path` and relabels it.  Returns the type applied, None when the
+/// system isn't enforcing or the path has no rule.
+pub fn label<P: AsRef<Path>>(path: P) -> io::Result<Option<String>> {
+    let path = path.as_ref();
+    if !is_enforcing() {
+        return Ok(None);
+    }
+    let Some((dir, setype)) = fcontext_for(path) else {
+        return Ok(None);
+    };
+
+    let pattern = format!("{}(/.*)?", dir);
+    // -a fails if the rule already exists, -m then updates it in place.
+    if RunCmd::args("semanage", &["fcontext", "-a", "-t", setype, &pattern]).execute_output().exitcode != 0 {
+        let out = RunCmd::args("semanage", &["fcontext", "-m", "-t", setype, &pattern]).execute_output();
+        if out.exitcode != 0 {
+            return Err(io::Error::other(format!("semanage fcontext for '{}' failed: {}", pattern, out.stderr.trim())));
+        }
+    }
+    restorecon(path)?;
+    Ok(Some(String::from(setype)))
+}
+
+/// Resets the context of `path` to what the policy says.
+pub fn restorecon<P: AsRef<Path>>(path: P) -> io::Result<()> {
+    let out = RunCmd::args("restorecon", &["-F", &path.as_ref().to_string_lossy()]).execute_output();
+    if out.exitcode != 0 {
+        return Err(io::Error::other(format!("restorecon failed: {}", out.stderr.trim())));
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fcontext_longest_match() {
+        assert_eq!(fcontext_for(Path::new("/opt/bitflux/bin/bitfluxcollector")), Some(("/opt/bitflux/bin", "bin_t")));
+        assert_eq!(fcontext_for(Path::new("/opt/bitflux/lib/x.so")), Some(("/opt/bitflux", "usr_t")));
+        assert_eq!(fcontext_for(Path::new("/opt/bitfluxer/x")), None);
+        assert_eq!(fcontext_for(Path::new("/etc/passwd")), None);
+    }
+
+    #[test]
+    fn parse_context_type() {
+        assert_eq!(context_type("system_u:object_r:bin_t:s0\n"), Some(String::from("bin_t")));
+        assert_eq!(context_type("?"), None);
+    }
+
+}
@@ -0,0 +1,321 @@
+use std::fs;
+use std::io::{self, BufRead, BufReader, Write};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::{chown, PermissionsExt};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::Path;
+use std::sync::mpsc::RecvTimeoutError;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::install::{self, Options};
+use crate::log::{self, Level};
+use crate::perms::group_id;
+use crate::plan::Plan;
+use crate::progress;
+use crate::state;
+
+/// Only root, and with --group that group's members, can connect.
+pub const SOCKET_PATH: &str = "/run/bitflux-installer.sock";
+/// Largest request body accepted, the install options are a few hundred bytes.
+const MAX_BODY: usize = 64 * 1024;
+/// How long a client gets to send its request, one that stalls doesn't hold a thread.
+const READ_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    Idle,
+    Running,
+    Succeeded,
+    Failed,
+}
+
+/// The install this server ran last, GET /v1/status.
+#[derive(Clone, Debug, Serialize)]
+pub struct Status {
+    pub state: State,
+    /// The license key masked.
+    pub options: Option<Options>,
+    /// Unix timestamps.
+    pub started_at: Option<u64>,
+    pub finished_at: Option<u64>,
+    pub error: Option<String>,
+}
+
+fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Request {
+    pub method: String,
+    pub path: String,
+    pub body: Vec<u8>,
+}
+
+/// Reads an HTTP/1.1 request, only Content-Length bodies.
+pub fn read_request<R: BufRead>(reader: &mut R) -> io::Result<Request> {
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let mut parts = line.split_whitespace();
+    let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Malformed request line."));
+    };
+    let (method, path) = (String::from(method), String::from(path));
+
+    let mut length = 0;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header)? == 0 || header.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse().map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "Bad Content-Length."))?;
+            }
+        }
+    }
+    if length > MAX_BODY {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "Request body too large."));
+    }
+    let mut body = vec![0; length];
+    reader.read_exact(&mut body)?;
+    Ok(Request { method, path, body })
+}
+
+fn respond(stream: &mut UnixStream, status: &str, body: &str) -> io::Result<()> {
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status, body.len(), body
+    )
+}
+
+fn json<T: Serialize>(value: &T) -> String {
+    serde_json::to_string(value).unwrap_or_default()
+}
+
+fn error(message: &str) -> String {
+    json(&serde_json::json!({ "error": message }))
+}
+
+/// uid, gid and pid of the process on the other end of `stream`.
+fn peer(stream: &UnixStream) -> Option<(u32, u32, i32)> {
+    let mut cred: libc::ucred = unsafe { std::mem::zeroed() };
+    let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let ret = unsafe {
+        libc::getsockopt(stream.as_raw_fd(), libc::SOL_SOCKET, libc::SO_PEERCRED, &mut cred as *mut _ as *mut libc::c_void, &mut len)
+    };
+    (ret == 0).then_some((cred.uid, cred.gid, cred.pid))
+}
+
+/// True for root, the server's own user and, when `group` is set, processes with it as
+/// primary or supplementary group.
+fn authorized(stream: &UnixStream, group: Option<u32>) -> bool {
+    let Some((uid, gid, pid)) = peer(stream) else {
+        return false;
+    };
+    if uid == 0 || uid == unsafe { libc::geteuid() } {
+        return true;
+    }
+    let Some(group) = group else {
+        return false;
+    };
+    let status = fs::read_to_string(format!("/proc/{}/status", pid)).unwrap_or_default();
+    let supplementary = status.lines()
+        .find_map(|l| l.strip_prefix("Groups:"))
+        .is_some_and(|groups| groups.split_whitespace().any(|g| g.parse() == Ok(group)));
+    gid == group || supplementary
+}
+
+#[derive(Clone)]
+struct Server {
+    status: Arc<Mutex<Status>>,
+    group: Option<u32>,
+}
+
+impl Server {
+
+    fn status(&self) -> Status {
+        self.status.lock().unwrap_or_else(|e| e.into_inner()).clone()
+    }
+
+    fn options(body: &[u8]) -> io::Result<Options> {
+        if body.iter().all(u8::is_ascii_whitespace) {
+            return Ok(Options::default());
+        }
+        serde_json::from_slice(body).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn handle(&self, mut stream: UnixStream) -> io::Result<()> {
+        if !authorized(&stream, self.group) {
+            return respond(&mut stream, "403 Forbidden", &error("Not allowed to control the installer."));
+        }
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        let request = match read_request(&mut BufReader::new(stream.try_clone()?)) {
+            Ok(request) => request,
+            Err(e) => return respond(&mut stream, "400 Bad Request", &error(&e.to_string())),
+        };
+        match (request.method.as_str(), request.path.as_str()) {
+            ("GET", "/v1/status") => respond(&mut stream, "200 OK", &json(&self.status())),
+            ("POST", "/v1/plan") => match Server::options(&request.body).and_then(|opts| Server::plan(&opts)) {
+                Ok(plan) => respond(&mut stream, "200 OK", &json(&serde_json::json!({ "plan": plan, "text": plan.render() }))),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => respond(&mut stream, "409 Conflict", &error("An install is running, plan once it's done.")),
+                Err(e) => respond(&mut stream, "422 Unprocessable Entity", &error(&e.to_string())),
+            },
+            ("POST", "/v1/apply") => match Server::options(&request.body) {
+                Ok(opts) => match self.apply(opts) {
+                    true => respond(&mut stream, "202 Accepted", &json(&self.status())),
+                    false => respond(&mut stream, "409 Conflict", &error("An install is already running.")),
+                },
+                Err(e) => respond(&mut stream, "400 Bad Request", &error(&e.to_string())),
+            },
+            ("GET", "/v1/progress") => self.progress(stream),
+            (_, "/v1/status" | "/v1/plan" | "/v1/apply" | "/v1/progress") => {
+                respond(&mut stream, "405 Method Not Allowed", &error("Method not allowed."))
+            }
+            _ => respond(&mut stream, "404 Not Found", &error("Unknown endpoint.")),
+        }
+    }
+
+    /// The plan is made from a dry run of the install, which is the process's: no install may
+    /// run alongside it.
+    fn plan(opts: &Options) -> io::Result<Plan> {
+        let _lock = state::lock(None)?;
+        install::plan(opts)
+    }
+
+    /// Starts the install in the background, false if one is running already.
+    fn apply(&self, opts: Options) -> bool {
+        {
+            let mut status = self.status.lock().unwrap_or_else(|e| e.into_inner());
+            if status.state == State::Running {
+                return false;
+            }
+            *status = Status { state: State::Running, options: Some(opts.redacted()), started_at: Some(now()), finished_at: None, error: None };
+        }
+        let status = self.status.clone();
+        thread::spawn(move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(|| {
+                let _lock = state::lock(None)?;
+                install::run(&opts)
+            }));
+            let error = match result {
+                Ok(Ok(())) => None,
+                Ok(Err(e)) => Some(e.to_string()),
+                Err(_) => Some(String::from("The install panicked, see the service log.")),
+            };
+            let mut status = status.lock().unwrap_or_else(|e| e.into_inner());
+            status.state = if error.is_none() { State::Succeeded } else { State::Failed };
+            status.finished_at = Some(now());
+            status.error = error;
+        });
+        true
+    }
+
+    /// Streams the steps of the running install as JSON lines, ending with its final status.
+    fn progress(&self, mut stream: UnixStream) -> io::Result<()> {
+        let events = progress::subscribe();
+        write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: application/x-ndjson\r\nConnection: close\r\n\r\n")?;
+        writeln!(stream, "{}", json(&self.status()))?;
+        while self.status().state == State::Running {
+            match events.recv_timeout(Duration::from_secs(1)) {
+                Ok(event) => writeln!(stream, "{}", json(&event))?,
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+        }
+        while let Ok(event) = events.try_recv() {
+            writeln!(stream, "{}", json(&event))?;
+        }
+        writeln!(stream, "{}", json(&self.status()))
+    }
+
+}
+
+/// Binds the API socket at `path`, readable by root and `group` only.
+pub fn bind<P: AsRef<Path>>(path: P, group: Option<&str>) -> io::Result<(UnixListener, Option<u32>)> {
+    let path = path.as_ref();
+    let gid = match group {
+        Some(name) => Some(group_id(&fs::read_to_string("/etc/group")?, name)
+            .ok_or_else(|| io::Error::other(format!("No group '{}'.", name)))?),
+        None => None,
+    };
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let listener = UnixListener::bind(path)?;
+    if gid.is_some() {
+        chown(path, None, gid)?;
+    }
+    fs::set_permissions(path, fs::Permissions::from_mode(if gid.is_some() { 0o660 } else { 0o600 }))?;
+    Ok((listener, gid))
+}
+
+/// Serves the API on `listener` until the process is stopped, one thread per connection.
+pub fn run(listener: UnixListener, group: Option<u32>) -> io::Result<()> {
+    let server = Server {
+        status: Arc::new(Mutex::new(Status { state: State::Idle, options: None, started_at: None, finished_at: None, error: None })),
+        group,
+    };
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let server = server.clone();
+        thread::spawn(move || {
+            if let Err(e) = server.handle(stream) {
+                log::log(Level::Error, &format!("API request failed: {}", e));
+            }
+        });
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    #[test]
+    fn parse_request() {
+        let raw = "POST /v1/apply HTTP/1.1\r\nHost: localhost\r\nContent-Length: 19\r\n\r\n{\"profile\":\"agent\"}";
+        let request = read_request(&mut raw.as_bytes()).unwrap();
+        assert_eq!(request.method, "POST");
+        assert_eq!(request.path, "/v1/apply");
+        assert_eq!(Server::options(&request.body).unwrap().profile, crate::profile::Profile::Agent);
+        assert!(read_request(&mut "\r\n".as_bytes()).is_err());
+    }
+
+    #[test]
+    fn status_over_socket() {
+        let dir = std::env::temp_dir().join(format!("bitflux-serve-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("installer.sock");
+        let (listener, group) = bind(&path, None).unwrap();
+        thread::spawn(move || run(listener, group));
+        let call = |request: &str| {
+            let mut stream = UnixStream::connect(&path).unwrap();
+            stream.write_all(request.as_bytes()).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        let status = call("GET /v1/status HTTP/1.1\r\n\r\n");
+        assert!(status.starts_with("HTTP/1.1 200 OK\r\n"));
+        assert!(status.ends_with(r#"{"state":"idle","options":null,"started_at":null,"finished_at":null,"error":null}"#));
+        assert!(call("DELETE /v1/status HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 405"));
+        assert!(call("GET /v2/anything HTTP/1.1\r\n\r\n").starts_with("HTTP/1.1 404"));
+        let progress = call("GET /v1/progress HTTP/1.1\r\n\r\n");
+        assert_eq!(progress.matches(r#""state":"idle""#).count(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+}
@@ -0,0 +1,410 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::log::{self, Level};
+use crate::perms::{FileKind, PermissionRecord};
+use crate::platform::Init;
+use crate::runcmd::{self, RunCmd};
+use crate::template;
+
+/// True when systemd runs the host.  Without it units can still be written and enabled, they
+/// take effect on the next boot under systemd, but nothing can be started or queried.
+pub fn systemd_running() -> bool {
+    Init::detect() == Some(Init::Systemd)
+}
+
+/// Whether a service is enabled and running, as the init system reports it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Status {
+    /// "enabled", "disabled", "static", "not-found", "unknown" without systemctl.
+    pub enabled: String,
+    /// "active", "inactive", "failed", "activating", "unknown" without a running init system.
+    pub active: String,
+}
+
+/// How services are enabled, started and queried under one init system.
+pub trait InitSystem: Sync {
+
+    fn name(&self) -> &'static str;
+
+    /// Enables `service` for the next boot, and starts it now too when `start`.
+    fn enable(&self, service: &str, start: bool) -> io::Result<()>;
+
+    /// Disables `service`, and stops it too when `stop`.
+    fn disable(&self, service: &str, stop: bool) -> io::Result<()>;
+
+    /// "start", "stop", "restart" or "try-restart", the last only restarting a running service.
+    fn control(&self, service: &str, action: &str) -> io::Result<()>;
+
+    fn status(&self, service: &str) -> Status;
+
+    fn is_enabled(&self, service: &str) -> bool {
+        self.status(service).enabled == "enabled"
+    }
+
+    fn is_active(&self, service: &str) -> bool {
+        self.status(service).active == "active"
+    }
+
+    /// The last `lines` `service` logged, where the init system keeps them.
+    fn logs(&self, _service: &str, _lines: usize) -> String {
+        String::new()
+    }
+
+}
+
+fn run(program: &str, args: &[&str]) -> io::Result<()> {
+    RunCmd::args(program, args).try_execute().map(|_| ()).map_err(io::Error::from)
+}
+
+fn succeeds(program: &str, args: &[&str]) -> bool {
+    RunCmd::args(program, args).execute_output().exitcode == 0
+}
+
+fn status_of(enabled: bool, active: bool) -> Status {
+    Status {
+        enabled: String::from(if enabled { "enabled" } else { "disabled" }),
+        active: String::from(if active { "active" } else { "inactive" }),
+    }
+}
+
+pub struct Systemd;
+
+impl InitSystem for Systemd {
+
+    fn name(&self) -> &'static str {
+        "systemd"
+    }
+
+    fn enable(&self, service: &str, start: bool) -> io::Result<()> {
+        match start {
+            true => run("systemctl", &["enable", "--now", service]),
+            false => run("systemctl", &["enable", service]),
+        }
+    }
+
+    fn disable(&self, service: &str, stop: bool) -> io::Result<()> {
+        match stop {
+            true => run("systemctl", &["disable", "--now", service]),
+            false => run("systemctl", &["disable", service]),
+        }
+    }
+
+    fn control(&self, service: &str, action: &str) -> io::Result<()> {
+        run("systemctl", &[action, service])
+    }
+
+    fn status(&self, service: &str) -> Status {
+        let state = |query: &str| {
+            let out = RunCmd::args("systemctl", &[query, service]).execute_output();
+            match out.stdout.trim() {
+                "" => String::from("unknown"),
+                state => String::from(state),
+            }
+        };
+        Status { enabled: state("is-enabled"), active: state("is-active") }
+    }
+
+    fn is_enabled(&self, service: &str) -> bool {
+        succeeds("systemctl", &["is-enabled", "--quiet", service])
+    }
+
+    fn is_active(&self, service: &str) -> bool {
+        succeeds("systemctl", &["is-active", "--quiet", service])
+    }
+
+    fn logs(&self, service: &str, lines: usize) -> String {
+        RunCmd::args("journalctl", &["-u", service, "-n", &lines.to_string(), "--no-pager"]).execute_output().stdout
+    }
+
+}
+
+/// OpenRC, services in the default runlevel start at boot.
+pub struct OpenRc;
+
+impl InitSystem for OpenRc {
+
+    fn name(&self) -> &'static str {
+        "OpenRC"
+    }
+
+    fn enable(&self, service: &str, start: bool) -> io::Result<()> {
+        if !self.is_enabled(service) {
+            run("rc-update", &["add", service, "default"])?;
+        }
+        match start {
+            true => run("rc-service", &[service, "start"]),
+            false => Ok(()),
+        }
+    }
+
+    fn disable(&self, service: &str, stop: bool) -> io::Result<()> {
+        if stop && self.is_active(service) {
+            run("rc-service", &[service, "stop"])?;
+        }
+        match self.is_enabled(service) {
+            true => run("rc-update", &["del", service, "default"]),
+            false => Ok(()),
+        }
+    }
+
+    fn control(&self, service: &str, action: &str) -> io::Result<()> {
+        match action {
+            "try-restart" if !self.is_active(service) => Ok(()),
+            "try-restart" => run("rc-service", &[service, "restart"]),
+            action => run("rc-service", &[service, action]),
+        }
+    }
+
+    fn status(&self, service: &str) -> Status {
+        status_of(self.is_enabled(service), self.is_active(service))
+    }
+
+    fn is_enabled(&self, service: &str) -> bool {
+        openrc_enabled(&RunCmd::args("rc-update", &["show", "default"]).execute_output().stdout, service)
+    }
+
+    fn is_active(&self, service: &str) -> bool {
+        succeeds("rc-service", &[service, "status"])
+    }
+
+}
+
+/// Whether `service` is in `rc-update show` formatted `runlevel`.
+pub fn openrc_enabled(runlevel: &str, service: &str) -> bool {
+    runlevel.lines().any(|line| line.split('|').next().is_some_and(|name| name.trim() == service))
+}
+
+/// SysV init scripts in /etc/init.d, linked into the runlevels by update-rc.d on Debian and
+/// chkconfig elsewhere.
+pub struct SysV;
+
+/// Where init scripts and their runlevel links live.
+pub const INIT_SCRIPT_DIR: &str = "/etc/init.d";
+const RUNLEVEL_DIRS: &[&str] = &["/etc/rc2.d", "/etc/rc3.d", "/etc/rc.d/rc3.d"];
+
+impl InitSystem for SysV {
+
+    fn name(&self) -> &'static str {
+        "SysV init"
+    }
+
+    fn enable(&self, service: &str, start: bool) -> io::Result<()> {
+        match runcmd::which("update-rc.d") {
+            Some(_) => run("update-rc.d", &[service, "defaults"])?,
+            None => run("chkconfig", &["--add", service])?,
+        }
+        match start {
+            true => self.control(service, "start"),
+            false => Ok(()),
+        }
+    }
+
+    fn disable(&self, service: &str, stop: bool) -> io::Result<()> {
+        if stop && self.is_active(service) {
+            self.control(service, "stop")?;
+        }
+        match runcmd::which("update-rc.d") {
+            Some(_) => run("update-rc.d", &["-f", service, "remove"]),
+            None => run("chkconfig", &["--del", service]),
+        }
+    }
+
+    fn control(&self, service: &str, action: &str) -> io::Result<()> {
+        let script = Path::new(INIT_SCRIPT_DIR).join(service).to_string_lossy().into_owned();
+        match action {
+            "try-restart" if !self.is_active(service) => Ok(()),
+            "try-restart" => run(&script, &["restart"]),
+            action => run(&script, &[action]),
+        }
+    }
+
+    fn status(&self, service: &str) -> Status {
+        status_of(self.is_enabled(service), self.is_active(service))
+    }
+
+    fn is_enabled(&self, service: &str) -> bool {
+        sysv_enabled(RUNLEVEL_DIRS, service)
+    }
+
+    fn is_active(&self, service: &str) -> bool {
+        succeeds(&Path::new(INIT_SCRIPT_DIR).join(service).to_string_lossy(), &["status"])
+    }
+
+}
+
+/// Whether any of the `runlevels` directories starts `service`, has an S<nn><service> link.
+pub fn sysv_enabled(runlevels: &[&str], service: &str) -> bool {
+    runlevels.iter().filter_map(|dir| fs::read_dir(dir).ok()).flatten().flatten().any(|entry| {
+        let name = entry.file_name().to_string_lossy().into_owned();
+        name.strip_prefix('S').is_some_and(|rest| rest.trim_start_matches(|c: char| c.is_ascii_digit()) == service)
+    })
+}
+
+/// The init system of `init`, systemd where none runs so units written now take effect once
+/// it does.
+pub fn init_system(init: Option<Init>) -> &'static dyn InitSystem {
+    match init {
+        Some(Init::OpenRc) => &OpenRc,
+        Some(Init::SysV) => &SysV,
+        Some(Init::Systemd) | None => &Systemd,
+    }
+}
+
+/// A service, by name without the `.service`, under the host's init system.
+///
+/// # Examples
+///
+/// ```no_run
+/// use installer::service::Service;
+///
+/// let agent = Service::new("bitfluxcollector");
+/// agent.enable(true)?;
+/// println!("{}", agent.status().active);
+/// # Ok::<(), std::io::Error>(())
+/// ```
+#[derive(Clone, Debug, PartialEq)]
+pub struct Service {
+    pub name: String,
+    /// The init system running the host, None when there's none.
+    pub init: Option<Init>,
+}
+
+impl Service {
+
+    pub fn new(name: &str) -> Service {
+        Service::under(name, Init::detect())
+    }
+
+    /// `name` under `init` rather than the host's.
+    pub fn under(name: &str, init: Option<Init>) -> Service {
+        Service { name: String::from(name), init }
+    }
+
+    fn system(&self) -> &'static dyn InitSystem {
+        init_system(self.init)
+    }
+
+    /// Whether an init system runs the host, to start and query the service.
+    pub fn running(&self) -> bool {
+        self.init.is_some()
+    }
+
+    /// Runs a command that needs a running init system, or says why it's skipped.
+    fn live(&self, what: &str, action: &str) -> io::Result<()> {
+        if !self.running() {
+            log::log(Level::Warn, &format!("{} isn't running, not going to {} {}.", self.system().name(), what, self.name));
+            return Ok(());
+        }
+        self.system().control(&self.name, action)
+    }
+
+    /// Enables the service, and starts it now too when `start` and the init system is running.
+    pub fn enable(&self, start: bool) -> io::Result<()> {
+        self.system().enable(&self.name, start && self.running())
+    }
+
+    /// Disables the service, and stops it too when `stop` and the init system is running.
+    pub fn disable(&self, stop: bool) -> io::Result<()> {
+        self.system().disable(&self.name, stop && self.running())
+    }
+
+    pub fn start(&self) -> io::Result<()> {
+        self.live("start", "start")
+    }
+
+    pub fn stop(&self) -> io::Result<()> {
+        self.live("stop", "stop")
+    }
+
+    pub fn restart(&self) -> io::Result<()> {
+        self.live("restart", "restart")
+    }
+
+    /// Restarts the service only if it's running, to pick up changed settings.
+    pub fn try_restart(&self) -> io::Result<()> {
+        self.live("restart", "try-restart")
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.system().is_enabled(&self.name)
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.system().is_active(&self.name)
+    }
+
+    pub fn status(&self) -> Status {
+        self.system().status(&self.name)
+    }
+
+    /// The last `lines` the service logged, empty where the init system keeps no log.
+    pub fn logs(&self, lines: usize) -> String {
+        self.system().logs(&self.name, lines)
+    }
+
+}
+
+/// Reloads systemd after unit changes, skipped when it isn't running.
+pub fn daemon_reload() -> io::Result<()> {
+    if !systemd_running() {
+        return Ok(());
+    }
+    RunCmd::args("systemctl", &["daemon-reload"]).try_execute().map(|_| ()).map_err(io::Error::from)
+}
+
+/// Writes a unit or drop-in at `path` with the Unit owner and mode and reloads systemd.
+pub fn write_unit_file(path: &Path, contents: &str) -> io::Result<(PathBuf, PermissionRecord)> {
+    let written = template::write(path, contents, FileKind::Unit)?;
+    daemon_reload()?;
+    Ok((path.to_path_buf(), written.record))
+}
+
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use crate::executor::{self, MockExecutor, Reply};
+
+    #[test]
+    fn per_init_system() {
+        let mock = Arc::new(MockExecutor::new());
+        mock.on("rc-update show", Reply::ok("            local |      default\n"))
+            .on("rc-service bitfluxcollector status", Reply::exit(3, ""));
+        let _using = executor::using(mock.clone());
+        let agent = Service::under("bitfluxcollector", Some(Init::OpenRc));
+        agent.enable(true).unwrap();
+        agent.try_restart().unwrap();
+        assert_eq!(mock.commands(), [
+            "rc-update show default",
+            "rc-update add bitfluxcollector default",
+            "rc-service bitfluxcollector start",
+            "rc-service bitfluxcollector status",
+        ]);
+        assert_eq!(agent.status(), Status { enabled: String::from("disabled"), active: String::from("inactive") });
+        assert!(openrc_enabled(" bitfluxcollector |      default\n", "bitfluxcollector"));
+
+        let mock = Arc::new(MockExecutor::new());
+        let _using = executor::using(mock.clone());
+        Service::under("bitfluxcollector", None).start().unwrap();
+        Service::under("bitfluxcollector", Some(Init::SysV)).restart().unwrap();
+        assert_eq!(mock.commands(), ["/etc/init.d/bitfluxcollector restart"]);
+    }
+
+    #[test]
+    fn sysv_runlevel_links() {
+        let dir = std::env::temp_dir().join(format!("bitflux-rc-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("K01bitfluxcollector"), "").unwrap();
+        let runlevels = [dir.to_str().unwrap()];
+        assert!(!sysv_enabled(&runlevels, "bitfluxcollector"));
+        fs::write(dir.join("S02bitfluxcollector"), "").unwrap();
+        assert!(sysv_enabled(&runlevels, "bitfluxcollector"));
+        assert!(!sysv_enabled(&runlevels, "bitflux"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+}
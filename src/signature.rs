@@ -0,0 +1,216 @@
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use blake2::{Blake2b512, Digest};
+use ed25519_dalek::{Signature, Verifier, VerifyingKey};
+
+use crate::download::Download;
+use crate::fips;
+use crate::privsep;
+
+/// Public half of the bitflux release signing key (ed25519, hex).
+/// Builds for other channels can swap it out with BITFLUX_RELEASE_PUBKEY at compile time.
+pub const RELEASE_PUBLIC_KEY: &str = match option_env!("BITFLUX_RELEASE_PUBKEY") {
+    Some(key) => key,
+    None => "6e66eb545eccb79cc50c43fa43ec86fe57b03b20bde969fda461b53fe208ba27",
+};
+
+/// A downloaded binary didn't verify.  Always a hard stop, main exits with its own code for it.
+#[derive(Debug)]
+pub struct SignatureError {
+    pub what: String,
+    pub reason: String,
+}
+
+impl fmt::Display for SignatureError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Signature verification of '{}' failed: {}", self.what, self.reason)
+    }
+}
+
+impl Error for SignatureError {}
+
+impl SignatureError {
+
+    pub fn io(what: &str, reason: &str) -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, SignatureError { what: String::from(what), reason: String::from(reason) })
+    }
+
+}
+
+/// True if `e` came from a failed signature check.
+pub fn is_signature_error(e: &io::Error) -> bool {
+    e.get_ref().is_some_and(|inner| inner.is::<SignatureError>())
+}
+
+/// Checks `signature` of `data` by the release key.  `signature` is either bare hex or the
+/// contents of a minisign .minisig file.
+pub fn verify_release(what: &str, data: &[u8], signature: &str) -> io::Result<()> {
+    verify(RELEASE_PUBLIC_KEY, data, signature).map_err(|e| SignatureError::io(what, &e.to_string()))
+}
+
+/// Checks `signature` (hex or minisign) of `data` by `public_key_hex`.
+pub fn verify(public_key_hex: &str, data: &[u8], signature: &str) -> io::Result<()> {
+    verify_mode(public_key_hex, data, signature, fips::is_enabled())
+}
+
+/// `verify`, in `fips` mode only approved algorithms are accepted.
+fn verify_mode(public_key_hex: &str, data: &[u8], signature: &str, fips: bool) -> io::Result<()> {
+    let key: [u8; 32] = decode_hex(public_key_hex)?
+        .try_into()
+        .map_err(|_| invalid("public key must be 32 bytes"))?;
+    let key = VerifyingKey::from_bytes(&key).map_err(|e| invalid(&e.to_string()))?;
+
+    if signature.trim_start().starts_with("untrusted comment:") {
+        return verify_minisign(&key, data, signature, fips);
+    }
+
+    let sig: [u8; 64] = decode_hex(signature)?
+        .try_into()
+        .map_err(|_| invalid("signature must be 64 bytes"))?;
+    key.verify(data, &Signature::from_bytes(&sig))
+        .map_err(|_| invalid("signature verification failed"))
+}
+
+/// Verifies a minisign signature file, both legacy ("Ed") and prehashed ("ED") forms,
+/// including the global signature over the trusted comment.  The prehashed form uses BLAKE2b,
+/// which isn't FIPS approved, so in `fips` mode only the legacy form is accepted.
+fn verify_minisign(key: &VerifyingKey, data: &[u8], minisig: &str, fips: bool) -> io::Result<()> {
+    let lines: Vec<&str> = minisig.lines().map(str::trim).filter(|l| !l.is_empty()).collect();
+    if lines.len() != 4 {
+        return Err(invalid("malformed minisign signature"));
+    }
+    let blob = BASE64.decode(lines[1]).map_err(|_| invalid("malformed minisign signature"))?;
+    if blob.len() != 74 {
+        return Err(invalid("malformed minisign signature"));
+    }
+    let sig: [u8; 64] = blob[10..].try_into().map_err(|_| invalid("malformed minisign signature"))?;
+
+    let ok = match &blob[..2] {
+        b"Ed" => key.verify(data, &Signature::from_bytes(&sig)).is_ok(),
+        b"ED" if fips => return Err(invalid("prehashed minisign signatures use BLAKE2b, not allowed in FIPS mode")),
+        b"ED" => key.verify(&Blake2b512::digest(data), &Signature::from_bytes(&sig)).is_ok(),
+        _ => return Err(invalid("unsupported minisign algorithm")),
+    };
+    if !ok {
+        return Err(invalid("signature verification failed"));
+    }
+
+    let comment = lines[2].strip_prefix("trusted comment: ").ok_or_else(|| invalid("missing trusted comment"))?;
+    let global: [u8; 64] = BASE64.decode(lines[3])
+        .map_err(|_| invalid("malformed minisign signature"))?
+        .try_into()
+        .map_err(|_| invalid("malformed minisign signature"))?;
+    let mut signed = sig.to_vec();
+    signed.extend_from_slice(comment.as_bytes());
+    key.verify(&signed, &Signature::from_bytes(&global))
+        .map_err(|_| invalid("trusted comment signature verification failed"))
+}
+
+/// Downloads `url` to `dest` and verifies it against the release key before returning.
+/// Without an inline `signature` the detached `<url>.minisig` is fetched.  On failure `dest` is removed.
+pub fn download_verified(url: &str, dest: &Path, signature: Option<&str>) -> io::Result<()> {
+    let signature = match signature {
+        Some(sig) => String::from(sig),
+        None => privsep::fetch(&format!("{}.minisig", url))
+            .map_err(|e| SignatureError::io(url, &format!("no signature available: {}", e)))?,
+    };
+    Download::new(url).save(dest)?;
+
+    let result = fs::read(dest).and_then(|data| verify_release(url, &data, &signature));
+    if result.is_err() {
+        let _ = fs::remove_file(dest);
+    }
+    result
+}
+
+pub fn decode_hex(data: &str) -> io::Result<Vec<u8>> {
+    let data = data.trim();
+    if !data.is_ascii() || !data.len().is_multiple_of(2) {
+        return Err(invalid("invalid hex string"));
+    }
+    (0..data.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&data[i..i + 2], 16).map_err(|_| invalid("invalid hex string")))
+        .collect()
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg.to_string())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checksum::to_hex as hex;
+    use ed25519_dalek::{Signer, SigningKey};
+
+    fn minisig(signing: &SigningKey, data: &[u8], alg: &[u8; 2]) -> String {
+        let sig = match alg {
+            b"ED" => signing.sign(&Blake2b512::digest(data)).to_bytes(),
+            _ => signing.sign(data).to_bytes(),
+        };
+        let mut blob = alg.to_vec();
+        blob.extend_from_slice(&[1u8; 8]);
+        blob.extend_from_slice(&sig);
+        let comment = "timestamp:0\tfile:installer";
+        let mut global = sig.to_vec();
+        global.extend_from_slice(comment.as_bytes());
+        format!("untrusted comment: test\n{}\ntrusted comment: {}\n{}\n",
+                BASE64.encode(blob), comment, BASE64.encode(signing.sign(&global).to_bytes()))
+    }
+
+    #[test]
+    fn verify_good_and_bad() {
+        let signing = SigningKey::from_bytes(&[7u8; 32]);
+        let public = hex(signing.verifying_key().as_bytes());
+        let sig = hex(&signing.sign(b"installer").to_bytes());
+
+        assert!(verify(&public, b"installer", &sig).is_ok());
+        assert!(verify(&public, b"tampered", &sig).is_err());
+        assert!(verify(&public, b"installer", "abcd").is_err());
+    }
+
+    #[test]
+    fn verify_minisign_forms() {
+        let signing = SigningKey::from_bytes(&[9u8; 32]);
+        let public = hex(signing.verifying_key().as_bytes());
+
+        for alg in [b"Ed", b"ED"] {
+            let sig = minisig(&signing, b"installer", alg);
+            assert!(verify(&public, b"installer", &sig).is_ok());
+            assert!(verify(&public, b"tampered", &sig).is_err());
+            let forged = sig.replace("file:installer", "file:other");
+            assert!(verify(&public, b"installer", &forged).is_err());
+        }
+    }
+
+    #[test]
+    fn fips_rejects_blake2_prehash() {
+        let signing = SigningKey::from_bytes(&[9u8; 32]);
+        let public = hex(signing.verifying_key().as_bytes());
+
+        assert!(verify_mode(&public, b"installer", &minisig(&signing, b"installer", b"Ed"), true).is_ok());
+        assert!(verify_mode(&public, b"installer", &minisig(&signing, b"installer", b"ED"), true).is_err());
+        assert!(verify_mode(&public, b"installer", &minisig(&signing, b"installer", b"ED"), false).is_ok());
+    }
+
+    #[test]
+    fn release_failure_is_signature_error() {
+        let e = verify_release("installer", b"installer", &"00".repeat(64)).unwrap_err();
+        assert!(is_signature_error(&e));
+        assert!(!is_signature_error(&io::Error::other("network")));
+    }
+
+    #[test]
+    fn release_key_is_valid() {
+        assert_eq!(decode_hex(RELEASE_PUBLIC_KEY).unwrap().len(), 32);
+    }
+
+}
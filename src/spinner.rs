@@ -0,0 +1,208 @@
+use std::io::{self, IsTerminal, Write};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+
+use crate::i18n;
+use crate::log::{self, Level};
+
+pub const FRAMES: [char; 10] = ['⠋', '⠙', '⠹', '⠸', '⠼', '⠴', '⠦', '⠧', '⠇', '⠏'];
+const TICK: Duration = Duration::from_millis(100);
+
+/// The spinner line on the terminal, None when no step shows one.
+static LINE: Mutex<Option<Line>> = Mutex::new(None);
+
+struct Line {
+    title: String,
+    started: Instant,
+    frame: usize,
+    drawn: bool,
+    /// False while output of our own ended mid-line, drawing then would run into it.
+    at_start: bool,
+}
+
+impl Line {
+
+    fn clear(&mut self) {
+        if self.drawn {
+            eprint!("\r\x1b[2K");
+            self.drawn = false;
+        }
+    }
+
+    fn draw(&mut self) {
+        if self.at_start {
+            eprint!("\r\x1b[2K{} {} ({})", FRAMES[self.frame % FRAMES.len()], self.title, elapsed(self.started.elapsed()));
+            let _ = io::stderr().flush();
+            self.drawn = true;
+        }
+    }
+
+}
+
+/// The spinner line kept off the terminal, for printing something else while a step runs.
+/// It's drawn again when this is dropped.
+pub struct Held(MutexGuard<'static, Option<Line>>);
+
+impl Drop for Held {
+
+    fn drop(&mut self) {
+        if let Some(line) = self.0.as_mut() {
+            line.draw();
+        }
+    }
+
+}
+
+/// Clears the spinner line until the returned guard is dropped.  Must not be held across
+/// log::console, which takes it too.
+pub fn hold() -> Held {
+    let mut line = LINE.lock().unwrap_or_else(|e| e.into_inner());
+    if let Some(line) = line.as_mut() {
+        line.clear();
+    }
+    Held(line)
+}
+
+/// Writes to the inner writer with the spinner line out of the way, for command output shown
+/// as it comes.
+pub struct Writer<W>(pub W);
+
+impl<W: Write> Write for Writer<W> {
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut held = hold();
+        let n = self.0.write(buf)?;
+        self.0.flush()?;
+        if let (Some(line), Some(last)) = (held.0.as_mut(), buf[..n].last()) {
+            line.at_start = *last == b'\n';
+        }
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.0.flush()
+    }
+
+}
+
+/// How a step shown with start() ended.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Outcome {
+    Done,
+    Skipped,
+    Failed,
+    Interrupted,
+}
+
+/// `d` the way the step lines show it, "42s" or "3m05s".
+pub fn elapsed(d: Duration) -> String {
+    let secs = d.as_secs();
+    match secs {
+        0..=59 => format!("{}s", secs),
+        _ => format!("{}m{:02}s", secs / 60, secs % 60),
+    }
+}
+
+/// The line left behind by a step titled `title` that ended with `outcome` after `took`.
+pub fn summary(title: &str, outcome: Outcome, took: Duration) -> String {
+    match outcome {
+        Outcome::Done => format!("{}, done in {}", title, elapsed(took)),
+        Outcome::Skipped => format!("{}, already done", title),
+        Outcome::Failed => format!("{}, failed after {}", title, elapsed(took)),
+        Outcome::Interrupted => format!("{}, interrupted after {}", title, elapsed(took)),
+    }
+}
+
+/// A step on the console, from start() until it's dropped.
+pub struct Spinner {
+    title: String,
+    started: Instant,
+    outcome: Outcome,
+    ticker: Option<(Arc<AtomicBool>, JoinHandle<()>)>,
+}
+
+/// Shows `title` with a spinner and the time so far while the step runs when stderr is a
+/// terminal, as a plain line otherwise.  Dropped without finish() it shows as failed.
+pub fn start(title: &str) -> Spinner {
+    log::file(Level::Info, title);
+    let mut spinner = Spinner { title: String::from(title), started: Instant::now(), outcome: Outcome::Failed, ticker: None };
+    let live = io::stderr().is_terminal() && std::env::var("TERM").is_ok_and(|t| t != "dumb");
+    let mut line = LINE.lock().unwrap_or_else(|e| e.into_inner());
+    // A step inside another one only gets the plain line.
+    if !live || line.is_some() {
+        drop(line);
+        log::console(Level::Info, &format!("{}...", title));
+        return spinner;
+    }
+    let mut drawn = Line { title: i18n::tr(title), started: spinner.started, frame: 0, drawn: false, at_start: true };
+    drawn.draw();
+    *line = Some(drawn);
+
+    let stop = Arc::new(AtomicBool::new(false));
+    let stopped = stop.clone();
+    let ticker = thread::spawn(move || {
+        while !stopped.load(Ordering::Relaxed) {
+            thread::sleep(TICK);
+            if let Some(line) = LINE.lock().unwrap_or_else(|e| e.into_inner()).as_mut() {
+                line.frame += 1;
+                line.clear();
+                line.draw();
+            }
+        }
+    });
+    spinner.ticker = Some((stop, ticker));
+    spinner
+}
+
+impl Spinner {
+
+    pub fn finish(mut self, outcome: Outcome) {
+        self.outcome = outcome;
+    }
+
+}
+
+impl Drop for Spinner {
+
+    fn drop(&mut self) {
+        if let Some((stop, ticker)) = self.ticker.take() {
+            stop.store(true, Ordering::Relaxed);
+            let _ = ticker.join();
+            let mut line = LINE.lock().unwrap_or_else(|e| e.into_inner());
+            if let Some(mut line) = line.take() {
+                line.clear();
+            }
+        }
+        log::log(Level::Info, &summary(&self.title, self.outcome, self.started.elapsed()));
+    }
+
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn step_lines() {
+        assert_eq!(elapsed(Duration::from_millis(42_900)), "42s");
+        assert_eq!(elapsed(Duration::from_secs(185)), "3m05s");
+        assert_eq!(summary("Step 3/6: Installing the bitflux kernel", Outcome::Done, Duration::from_secs(42)),
+            "Step 3/6: Installing the bitflux kernel, done in 42s");
+        assert_eq!(summary("Step 1/6: Setting up the repository", Outcome::Skipped, Duration::ZERO),
+            "Step 1/6: Setting up the repository, already done");
+    }
+
+    #[test]
+    fn writer_passes_output_through() {
+        let mut out = Writer(Vec::new());
+        out.write_all(b"Reading package lists...\n").unwrap();
+        assert_eq!(out.0, b"Reading package lists...\n");
+    }
+
+}
@@ -0,0 +1,263 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use crate::agentconf::{self, AGENT_CONFIG};
+use crate::compat;
+use crate::data::{self, DataPolicy};
+use crate::exitcode::Kind;
+use crate::journal::{Journal, JOURNAL_DIR};
+use crate::kernel::{installed_kernels, running_kernel, Bootloader};
+use crate::log::{self, Level};
+use crate::pkg::{PackageManager, AGENT_PACKAGE};
+use crate::runcmd;
+use crate::selfupdate::is_newer;
+use crate::service::Service;
+use crate::wsl;
+
+/// Records what a staged upgrade put alongside the running install.
+pub const STAGED_STATE_PATH: &str = "/var/lib/bitflux/staged.json";
+/// Agent packages downloaded but not yet installed.
+pub const STAGING_DIR: &str = "/var/lib/bitflux/staged";
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub struct StagedUpgrade {
+    /// Kernel installed next to the running one but not made default.
+    pub kernel: Option<String>,
+    /// Kernel that stays default until promote.
+    pub default_kernel: Option<String>,
+    /// Downloaded agent package waiting to be installed.
+    pub agent_package: Option<String>,
+    /// How to handle the agent data when the staged agent is switched to.
+    #[serde(default)]
+    pub data_policy: Option<DataPolicy>,
+}
+
+impl StagedUpgrade {
+
+    pub fn load() -> io::Result<StagedUpgrade> {
+        let data = fs::read_to_string(STAGED_STATE_PATH)
+            .map_err(|e| io::Error::new(e.kind(), format!("No staged upgrade found in {}: {}", STAGED_STATE_PATH, e)))?;
+        serde_json::from_str(&data).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    fn save(&self) -> io::Result<()> {
+        if runcmd::dry_run(&format!("write {}", STAGED_STATE_PATH)) {
+            return Ok(());
+        }
+        if let Some(parent) = Path::new(STAGED_STATE_PATH).parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let data = serde_json::to_string_pretty(self).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        fs::write(STAGED_STATE_PATH, data)
+    }
+
+}
+
+fn package_manager() -> io::Result<PackageManager> {
+    PackageManager::detect().ok_or_else(|| Kind::Unsupported.error("No supported package manager found."))
+}
+
+/// The kernel that showed up in `after` but wasn't in `before`.
+pub fn new_kernel(before: &[String], after: &[String]) -> Option<String> {
+    after.iter().find(|k| !before.contains(k)).cloned()
+}
+
+/// Finds the single package file under `dir` (what `download` just fetched).  zypper puts it a
+/// few directories down.
+fn downloaded_package(dir: &Path) -> Option<PathBuf> {
+    fs::read_dir(dir).ok()?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .find_map(|p| match p.extension().and_then(|e| e.to_str()) {
+            Some("deb") | Some("rpm") => Some(p),
+            _ if p.is_dir() => downloaded_package(&p),
+            _ => None,
+        })
+}
+
+/// The upstream part of a package version, without the epoch and the distro's release.
+pub fn upstream_version(version: &str) -> &str {
+    let version = version.split_once(':').map_or(version, |(_, v)| v);
+    version.split_once('-').map_or(version, |(v, _)| v)
+}
+
+/// `upgrade --check`: reports whether a newer bitflux release than the installed agent is out,
+/// changing nothing.
+pub fn check() -> io::Result<()> {
+    let pm = package_manager()?;
+    let installed = pm.installed_version(AGENT_PACKAGE)
+        .ok_or_else(|| io::Error::other(format!("{} isn't installed.", AGENT_PACKAGE)))?;
+    let latest = compat::fetch_requirements()?.version;
+    match is_newer(&latest, upstream_version(&installed)) {
+        true => log::log(Level::Info, &format!("bitflux {} is available, {} is installed, `installer upgrade` upgrades to it.", latest, installed)),
+        false => log::log(Level::Info, &format!("bitflux {} is up to date.", installed)),
+    }
+    Ok(())
+}
+
+/// Upgrades the bitflux kernel and agent in place, keeping the agent's settings.
+pub fn upgrade(policy: DataPolicy) -> io::Result<()> {
+    let pm = package_manager()?;
+    let from = pm.installed_version(AGENT_PACKAGE).unwrap_or_default();
+    let settings = agentconf::load(AGENT_CONFIG).unwrap_or_default();
+
+    let packages: &[&str] = if wsl::is_wsl2() {
+        log::log(Level::Info, &format!("Running under WSL2, upgrading the agent only.\n{}", wsl::KERNEL_HELP));
+        &[AGENT_PACKAGE]
+    } else {
+        &[pm.kernel_package(), AGENT_PACKAGE]
+    };
+
+    data::pre_upgrade(policy)?;
+    if !pm.upgrade(packages) {
+        return Err(io::Error::other("Failed to upgrade bitflux packages."));
+    }
+    agentconf::restore(&settings)?;
+    let to = pm.installed_version(AGENT_PACKAGE).unwrap_or_default();
+    data::post_upgrade(policy, &from, &to)?;
+
+    // The upgraded agent only exists in the new snapshot until the reboot.
+    if !pm.transactional() {
+        Service::new(AGENT_PACKAGE).restart()?;
+    }
+    Ok(())
+}
+
+/// Installs the new kernel next to the current one (which stays default) and downloads the
+/// new agent without switching to it.  Finish with `promote` or back out with `abort`.
+pub fn stage(policy: DataPolicy) -> io::Result<()> {
+    if Path::new(STAGED_STATE_PATH).exists() {
+        return Err(io::Error::other("An upgrade is already staged, promote or abort it first."));
+    }
+    let pm = package_manager()?;
+    if pm.transactional() {
+        return Err(io::Error::other(
+            "transactional-update already stages every change in a new snapshot, run a plain upgrade and reboot when ready.",
+        ));
+    }
+    let mut staged = StagedUpgrade { data_policy: Some(policy), ..Default::default() };
+
+    if wsl::is_wsl2() {
+        log::log(Level::Info, &format!("Running under WSL2, staging the agent only.\n{}", wsl::KERNEL_HELP));
+    } else if let Some(bootloader) = Bootloader::detect() {
+        let running = running_kernel()?;
+        let before = installed_kernels();
+        if !pm.install(&[pm.kernel_package()]) {
+            return Err(io::Error::other(format!("Failed to install {}.", pm.kernel_package())));
+        }
+        staged.kernel = new_kernel(&before, &installed_kernels());
+        if staged.kernel.is_some() {
+            // Package scripts usually make the newest kernel default, put the running one back.
+            bootloader.set_default(&running, &mut Journal::open(JOURNAL_DIR)?)?;
+            staged.default_kernel = Some(running);
+        }
+    }
+
+    let dir = Path::new(STAGING_DIR);
+    let _ = fs::remove_dir_all(dir);
+    fs::create_dir_all(dir)?;
+    if pm.download(AGENT_PACKAGE, dir) {
+        staged.agent_package = downloaded_package(dir).map(|p| p.to_string_lossy().into_owned());
+    }
+
+    match &staged.kernel {
+        Some(k) => log::log(Level::Info, &format!("Staged kernel '{}', default remains '{}'.", k, staged.default_kernel.as_deref().unwrap_or(""))),
+        None => log::log(Level::Info, "No new kernel to stage."),
+    }
+    match &staged.agent_package {
+        Some(p) => log::log(Level::Info, &format!("Staged agent package '{}'.", p)),
+        None => log::log(Level::Info, "No new agent package to stage."),
+    }
+    staged.save()
+}
+
+/// Switches to everything that was staged: the new kernel becomes default, the new agent is installed.
+pub fn promote() -> io::Result<()> {
+    let staged = StagedUpgrade::load()?;
+    let pm = package_manager()?;
+
+    if let Some(kernel) = &staged.kernel {
+        let bootloader = Bootloader::detect().ok_or_else(|| io::Error::other("Can't recognize the bootloader."))?;
+        bootloader.set_default(kernel, &mut Journal::open(JOURNAL_DIR)?)?;
+        log::log(Level::Info, &format!("Kernel '{}' is now the default, reboot to use it.", kernel));
+    }
+
+    if let Some(package) = &staged.agent_package {
+        let policy = staged.data_policy.unwrap_or(DataPolicy::Migrate);
+        let from = pm.installed_version(AGENT_PACKAGE).unwrap_or_default();
+        let settings = agentconf::load(AGENT_CONFIG).unwrap_or_default();
+        data::pre_upgrade(policy)?;
+        if !pm.install_file(Path::new(package)) {
+            return Err(io::Error::other(format!("Failed to install staged agent '{}'.", package)));
+        }
+        agentconf::restore(&settings)?;
+        let to = pm.installed_version(AGENT_PACKAGE).unwrap_or_default();
+        data::post_upgrade(policy, &from, &to)?;
+        Service::new(AGENT_PACKAGE).restart()?;
+        log::log(Level::Info, &format!("Switched to staged agent '{}'.", package));
+    }
+
+    finish()
+}
+
+/// Backs out a staged upgrade: removes the staged kernel and drops the downloaded agent.
+pub fn abort() -> io::Result<()> {
+    let staged = StagedUpgrade::load()?;
+    let pm = package_manager()?;
+
+    if let Some(kernel) = &staged.kernel {
+        if let Some(default) = &staged.default_kernel {
+            if let Some(bootloader) = Bootloader::detect() {
+                bootloader.set_default(default, &mut Journal::open(JOURNAL_DIR)?)?;
+            }
+        }
+        let image = PathBuf::from(format!("/boot/vmlinuz-{}", kernel));
+        match pm.owner(&image) {
+            Some(package) if pm.remove(&[&package]) => log::log(Level::Info, &format!("Removed staged kernel package '{}'.", package)),
+            _ => log::log(Level::Info, &format!("Could not remove staged kernel '{}', it stays installed but not default.", kernel)),
+        }
+    }
+
+    if staged.agent_package.is_some() {
+        log::log(Level::Info, "Discarded staged agent package.");
+    }
+    finish()
+}
+
+fn finish() -> io::Result<()> {
+    let _ = fs::remove_dir_all(STAGING_DIR);
+    fs::remove_file(STAGED_STATE_PATH)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_new_kernel() {
+        let before = vec![String::from("5.15.0-91-generic")];
+        let after = vec![String::from("5.15.0-100-swaphints"), String::from("5.15.0-91-generic")];
+        assert_eq!(new_kernel(&before, &after), Some(String::from("5.15.0-100-swaphints")));
+        assert_eq!(new_kernel(&after, &after), None);
+    }
+
+    #[test]
+    fn package_versions() {
+        assert_eq!(upstream_version("1:0.9.2-1.el9"), "0.9.2");
+        assert_eq!(upstream_version("0.9.2"), "0.9.2");
+        assert!(is_newer("0.10.0", upstream_version("0.9.2-3ubuntu1")));
+        assert!(!is_newer("0.9.2", upstream_version("0.9.2-1")));
+    }
+
+    #[test]
+    fn state_serializes() {
+        let staged = StagedUpgrade { kernel: Some(String::from("k")), data_policy: Some(DataPolicy::Backup), ..Default::default() };
+        let json = serde_json::to_string(&staged).unwrap();
+        assert_eq!(serde_json::from_str::<StagedUpgrade>(&json).unwrap(), staged);
+    }
+
+}
@@ -0,0 +1,203 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::data::BACKUP_DIR;
+use crate::history::HISTORY_DIR;
+use crate::image::rooted;
+use crate::journal::JOURNAL_DIR;
+use crate::lock::{self, InstanceLock, LEGACY_LOCK_PATH, LOCK_PATH};
+use crate::log::{self, Level};
+use crate::privsep;
+use crate::resume::PROGRESS_PATH;
+use crate::runcmd::RunCmd;
+
+/// Everything the installer keeps between runs: the change journal, the command history, the
+/// backups of what it replaced, the progress of an install to resume, and the lock.
+pub const STATE_DIR: &str = "/var/lib/bitflux-installer";
+/// The layout STATE_DIR has, written by the installer that migrated it last.
+pub const SCHEMA_PATH: &str = "/var/lib/bitflux-installer/schema.json";
+
+/// Takes the layout from one schema to the next, MIGRATIONS[n] from n to n + 1, on the system
+/// at the root given.
+type Migration = fn(&Path) -> io::Result<()>;
+
+/// Schema 0 is the layout before STATE_DIR, when there's no SCHEMA_PATH.
+const MIGRATIONS: &[Migration] = &[into_state_dir];
+
+/// The schema this installer lays STATE_DIR out in.
+pub const SCHEMA: u32 = MIGRATIONS.len() as u32;
+
+/// Where installers before STATE_DIR kept what's in it now.
+const LEGACY_PATHS: &[(&str, &str)] = &[
+    ("/var/lib/bitflux/journal", JOURNAL_DIR),
+    ("/var/lib/bitflux/backups", BACKUP_DIR),
+    ("/var/lib/bitflux/install-progress.json", PROGRESS_PATH),
+    ("/var/log/bitflux/history", HISTORY_DIR),
+];
+
+/// What SCHEMA_PATH has.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Schema {
+    pub schema: u32,
+    /// The installer version that wrote it.
+    pub installer: String,
+}
+
+/// The schema of the state at `root`, 0 before there was one.
+pub fn schema(root: &Path) -> io::Result<u32> {
+    match fs::read_to_string(rooted(root, SCHEMA_PATH)) {
+        Ok(data) => serde_json::from_str::<Schema>(&data)
+            .map(|s| s.schema)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, format!("{}: {}", SCHEMA_PATH, e))),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(0),
+        Err(e) => Err(e),
+    }
+}
+
+/// Moves `from` to `to`, copying where they are on different filesystems.  A move that was cut
+/// short is finished: `to` is only ever there complete.
+fn relocate(from: &Path, to: &Path) -> io::Result<()> {
+    if !from.exists() {
+        return Ok(());
+    }
+    if let Some(parent) = to.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if !to.exists() {
+        if let Err(e) = fs::rename(from, to) {
+            if e.kind() != io::ErrorKind::CrossesDevices {
+                return Err(e);
+            }
+            let partial = to.with_extension("migrating");
+            let _ = fs::remove_dir_all(&partial);
+            let _ = fs::remove_file(&partial);
+            RunCmd::args("cp", &["-a", &from.to_string_lossy(), &partial.to_string_lossy()]).try_execute().map_err(io::Error::from)?;
+            fs::rename(&partial, to)?;
+        }
+    }
+    match from.is_dir() {
+        true => fs::remove_dir_all(from),
+        false if from.exists() => fs::remove_file(from),
+        false => Ok(()),
+    }
+}
+
+/// Schema 1: the LEGACY_PATHS move into STATE_DIR, and the journal's copies of replaced files
+/// are found in the backups where they went.
+fn into_state_dir(root: &Path) -> io::Result<()> {
+    for (from, to) in LEGACY_PATHS {
+        relocate(&rooted(root, from), &rooted(root, to))?;
+    }
+    let journal = rooted(root, JOURNAL_DIR).join("journal.json");
+    if let Ok(data) = fs::read_to_string(&journal) {
+        let moved = data.replace("\"/var/lib/bitflux/backups/", &format!("\"{}/", BACKUP_DIR));
+        if moved != data {
+            let tmp = journal.with_extension("tmp");
+            fs::write(&tmp, moved)?;
+            fs::rename(&tmp, &journal)?;
+        }
+    }
+    Ok(())
+}
+
+fn write_schema(root: &Path, schema: u32) -> io::Result<()> {
+    let path = rooted(root, SCHEMA_PATH);
+    let data = serde_json::to_string_pretty(&Schema { schema, installer: String::from(env!("CARGO_PKG_VERSION")) }).map_err(io::Error::other)?;
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, data)?;
+    fs::rename(&tmp, path)
+}
+
+/// Brings the state at `root` to SCHEMA, one migration at a time, recording each as it's done
+/// so one cut short picks up where it stopped.  Refuses state a newer installer laid out.
+/// Only called with the lock held.
+pub fn migrate(root: &Path) -> io::Result<()> {
+    let from = schema(root)?;
+    if from > SCHEMA {
+        return Err(io::Error::other(format!(
+            "{} is laid out in schema {} by a newer installer, this one knows up to {}. Update the installer.",
+            STATE_DIR, from, SCHEMA
+        )));
+    }
+    fs::create_dir_all(rooted(root, STATE_DIR))?;
+    for (n, migration) in MIGRATIONS.iter().enumerate().skip(from as usize) {
+        migration(root)?;
+        write_schema(root, n as u32 + 1)?;
+        log::log(Level::Debug, &format!("Migrated {} to schema {}.", STATE_DIR, n + 1));
+    }
+    Ok(())
+}
+
+/// The lock on the state, held for the run: STATE_DIR's own, and the one installers before
+/// it take, so none of them runs alongside.
+#[derive(Debug)]
+pub struct Locked {
+    _legacy: InstanceLock,
+    _lock: InstanceLock,
+}
+
+/// Locks the state against other installers, waiting for them as lock::acquire_wait does,
+/// and migrates it to SCHEMA.
+pub fn lock(wait: Option<Option<Duration>>) -> io::Result<Locked> {
+    let legacy = lock::acquire_wait(LEGACY_LOCK_PATH, wait)?;
+    let locked = Locked { _legacy: legacy, _lock: lock::acquire_wait(LOCK_PATH, wait)? };
+    migrate(Path::new("/"))?;
+    Ok(locked)
+}
+
+/// For the runs that only read the state: migrates it unless another installer holds the lock,
+/// which migrates it itself.  Problems are warned about, the run goes on.
+pub fn migrate_unlocked() {
+    if !privsep::is_root() || schema(Path::new("/")).is_ok_and(|s| s == SCHEMA) {
+        return;
+    }
+    match lock::acquire(LEGACY_LOCK_PATH).and_then(|legacy| lock::acquire(LOCK_PATH).map(|lock| (legacy, lock))) {
+        Ok(_locked) => {
+            if let Err(e) = migrate(Path::new("/")) {
+                log::log(Level::Warn, &format!("Warning: {}", e));
+            }
+        }
+        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {}
+        Err(e) => log::log(Level::Warn, &format!("Warning: couldn't lock {} to migrate it: {}", STATE_DIR, e)),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn migrates_the_legacy_layout() {
+        let root = std::env::temp_dir().join(format!("bitflux-state-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        let backup = "/var/lib/bitflux/backups/files/1791980107/etc/sysctl.d/60-bitflux-sbc.conf";
+        fs::create_dir_all(rooted(&root, "/var/lib/bitflux/journal")).unwrap();
+        fs::create_dir_all(rooted(&root, backup).parent().unwrap()).unwrap();
+        fs::create_dir_all(rooted(&root, "/var/log/bitflux/history")).unwrap();
+        fs::write(rooted(&root, backup), "vm.swappiness = 60\n").unwrap();
+        fs::write(rooted(&root, "/var/lib/bitflux/journal/journal.json"), format!("[{{\"kind\": \"file\", \"path\": \"/etc/sysctl.d/60-bitflux-sbc.conf\", \"backup\": \"{}\"}}]", backup)).unwrap();
+        fs::write(rooted(&root, "/var/lib/bitflux/install-progress.json"), "{}").unwrap();
+        fs::write(rooted(&root, "/var/log/bitflux/history/20261014T101500.jsonl"), "").unwrap();
+        assert_eq!(schema(&root).unwrap(), 0);
+
+        migrate(&root).unwrap();
+        assert_eq!(schema(&root).unwrap(), SCHEMA);
+        assert!(rooted(&root, PROGRESS_PATH).exists() && rooted(&root, HISTORY_DIR).join("20261014T101500.jsonl").exists());
+        assert!(!rooted(&root, "/var/lib/bitflux/journal").exists() && !rooted(&root, "/var/log/bitflux/history").exists());
+        let journal = fs::read_to_string(rooted(&root, JOURNAL_DIR).join("journal.json")).unwrap();
+        assert!(journal.contains("\"/var/lib/bitflux-installer/backups/files/1791980107/etc/sysctl.d/60-bitflux-sbc.conf\""));
+        assert!(rooted(&root, "/var/lib/bitflux-installer/backups/files/1791980107/etc/sysctl.d/60-bitflux-sbc.conf").exists());
+
+        // Done once, a newer installer's state is left alone.
+        migrate(&root).unwrap();
+        write_schema(&root, SCHEMA + 1).unwrap();
+        assert!(migrate(&root).unwrap_err().to_string().contains("newer installer"));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+}
@@ -0,0 +1,193 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::agentconf::AGENT_CONFIG;
+use crate::config::INSTALLER_CONFIG;
+use crate::device;
+use crate::exitcode::Kind;
+use crate::install::ANSWERS_PATH;
+use crate::kmod::{self, MODULES_LOAD_PATH};
+use crate::license::{self, LICENSE_PATH};
+use crate::output::{self, Format};
+use crate::pkg::{PackageManager, AGENT_PACKAGE};
+use crate::reboot;
+use crate::receipt::{Receipt, RECEIPT_DIR};
+use crate::release;
+use crate::repo;
+use crate::service::Service;
+use crate::unit::{DROPIN_DIR, HARDENING_DROPIN};
+
+/// Where the loaded kernel modules are.
+const SYS_MODULE_DIR: &str = "/sys/module";
+
+/// What `installer status` reports.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Status {
+    /// Installed version by package, None for one that isn't installed.
+    pub packages: BTreeMap<String, Option<String>>,
+    /// The bitflux release of the installed agent.
+    pub release: Option<String>,
+    /// The release the install was pinned to with --version, None when it went with the latest.
+    pub pinned: Option<String>,
+    pub module: Module,
+    /// As systemctl is-enabled and is-active have it.
+    pub enabled: String,
+    pub active: String,
+    pub license: String,
+    pub device_id: Option<String>,
+    /// The configuration files of bitflux and the installer that are there.
+    pub config_files: Vec<String>,
+    /// Something, like a new kernel, waits for a reboot.
+    pub reboot_required: bool,
+    /// Why bitflux needs the reboot.
+    pub reboot_reasons: Vec<String>,
+}
+
+/// The swaphints module in the running kernel.
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct Module {
+    /// Loaded, or built into the kernel.
+    pub loaded: bool,
+    /// The version it reports, modules without MODULE_VERSION have none.
+    pub version: Option<String>,
+}
+
+impl Module {
+
+    /// The module as `sys`, the sysfs module directory, has it.
+    pub fn detect(sys: &Path) -> Module {
+        let dir = sys.join(kmod::MODULE);
+        let version = fs::read_to_string(dir.join("version")).ok().map(|v| String::from(v.trim())).filter(|v| !v.is_empty());
+        Module { loaded: dir.is_dir(), version }
+    }
+
+}
+
+/// The configuration files bitflux and the installer may have put on the host.
+pub fn config_paths() -> Vec<String> {
+    let mut paths = vec![
+        String::from(AGENT_CONFIG),
+        String::from(LICENSE_PATH),
+        String::from(INSTALLER_CONFIG),
+        String::from(ANSWERS_PATH),
+        Path::new(DROPIN_DIR).join(HARDENING_DROPIN).to_string_lossy().into_owned(),
+        String::from(MODULES_LOAD_PATH),
+    ];
+    paths.extend([repo::APT_SOURCES_PATH, repo::APT_LIST_PATH, repo::RPM_REPO_PATH, repo::ZYPP_REPO_PATH].map(String::from));
+    paths
+}
+
+impl Status {
+
+    pub fn detect() -> io::Result<Status> {
+        let pm = PackageManager::detect().ok_or_else(|| Kind::Unsupported.error("No supported package manager found."))?;
+        let packages: BTreeMap<String, Option<String>> = [AGENT_PACKAGE, pm.kernel_package()].iter()
+            .map(|name| (String::from(*name), pm.installed_version(name)))
+            .collect();
+        let release = packages.get(AGENT_PACKAGE).cloned().flatten().map(|v| String::from(release::of(&v)));
+        let service = Service::new(AGENT_PACKAGE).status();
+        Ok(Status {
+            packages,
+            release,
+            pinned: Receipt::load_verified(RECEIPT_DIR).ok().and_then(|r| r.pinned),
+            module: Module::detect(Path::new(SYS_MODULE_DIR)),
+            enabled: service.enabled,
+            active: service.active,
+            license: license::status(LICENSE_PATH),
+            device_id: device::current(),
+            config_files: config_paths().into_iter().filter(|p| Path::new(p).exists()).collect(),
+            reboot_required: reboot::required(),
+            reboot_reasons: reboot::reasons(),
+        })
+    }
+
+    pub fn render(&self) -> String {
+        let mut out = String::new();
+        for (name, version) in &self.packages {
+            out.push_str(&format!("{}: {}\n", name, version.as_deref().unwrap_or("not installed")));
+        }
+        match (&self.release, &self.pinned) {
+            (Some(release), Some(pinned)) if release == pinned => out.push_str(&format!("bitflux release: {}, pinned\n", release)),
+            (Some(release), Some(pinned)) => out.push_str(&format!("bitflux release: {}, pinned to {}\n", release, pinned)),
+            (Some(release), None) => out.push_str(&format!("bitflux release: {}\n", release)),
+            (None, _) => {}
+        }
+        let module = match (self.module.loaded, &self.module.version) {
+            (true, Some(version)) => format!("loaded, {}", version),
+            (true, None) => String::from("loaded"),
+            (false, _) => String::from("not loaded"),
+        };
+        out.push_str(&format!("{} module: {}\n", kmod::MODULE, module));
+        out.push_str(&format!("service: {}, {}\n", self.enabled, self.active));
+        out.push_str(&format!("license: {}\n", self.license));
+        out.push_str(&format!("device id: {}\n", self.device_id.as_deref().unwrap_or("none")));
+        out.push_str(match self.config_files.is_empty() {
+            true => "config files: none\n",
+            false => "config files:\n",
+        });
+        for path in &self.config_files {
+            out.push_str(&format!("  {}\n", path));
+        }
+        if self.reboot_required {
+            out.push_str("reboot required\n");
+        }
+        for reason in &self.reboot_reasons {
+            out.push_str(&format!("  {}\n", reason));
+        }
+        out
+    }
+
+}
+
+/// Prints what of bitflux is installed, whether the agent runs and whether its license is activated.
+pub fn run(format: Format) -> io::Result<()> {
+    let status = Status::detect()?;
+    match format {
+        Format::Text => output::print(&status.render())?,
+        Format::Json => output::json(&status)?,
+    }
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn module_from_sysfs() {
+        let sys = std::env::temp_dir().join(format!("bitflux-sysmodule-{}", std::process::id()));
+        assert_eq!(Module::detect(&sys), Module { loaded: false, version: None });
+        fs::create_dir_all(sys.join(kmod::MODULE)).unwrap();
+        assert_eq!(Module::detect(&sys), Module { loaded: true, version: None });
+        fs::write(sys.join(kmod::MODULE).join("version"), "1.4.0\n").unwrap();
+        assert_eq!(Module::detect(&sys).version.as_deref(), Some("1.4.0"));
+        fs::remove_dir_all(&sys).unwrap();
+    }
+
+    #[test]
+    fn renders() {
+        let status = Status {
+            packages: BTreeMap::from([(String::from(AGENT_PACKAGE), Some(String::from("1.4.0-2")))]),
+            release: Some(String::from("1.4.0")),
+            pinned: Some(String::from("1.4.0")),
+            module: Module { loaded: true, version: None },
+            enabled: String::from("enabled"),
+            active: String::from("active"),
+            license: String::from("activated"),
+            device_id: Some(String::from("web1")),
+            config_files: vec![String::from(AGENT_CONFIG)],
+            reboot_required: true,
+            reboot_reasons: vec![String::from("kernel 6.6.31-swaphints was installed")],
+        };
+        assert_eq!(status.render(), format!(
+            "bitfluxcollector: 1.4.0-2\nbitflux release: 1.4.0, pinned\nswaphints module: loaded\nservice: enabled, active\nlicense: activated\ndevice id: web1\nconfig files:\n  {}\nreboot required\n  kernel 6.6.31-swaphints was installed\n",
+            AGENT_CONFIG
+        ));
+    }
+
+}
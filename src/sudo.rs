@@ -0,0 +1,148 @@
+use std::fs::OpenOptions;
+use std::io::{self, BufRead, IsTerminal, Write};
+use std::os::fd::AsRawFd;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+use crate::log::{self, Level};
+use crate::privsep;
+use crate::runcmd::{self, RunCmd};
+use crate::spinner;
+
+/// How often the cached credentials are refreshed, well inside sudo's default 5 minutes.
+const KEEPALIVE: Duration = Duration::from_secs(60);
+/// Tries at the password, as sudo gives.
+const ATTEMPTS: usize = 3;
+
+/// Set once sudo has credentials cached for the run, escalated commands then never ask.
+static CACHED: AtomicBool = AtomicBool::new(false);
+/// Held while the first escalation gets the credentials, the others wait for it, then get
+/// what it came to: a refused password stays refused for the run.
+static ASKING: Mutex<Option<Result<(), (io::ErrorKind, String)>>> = Mutex::new(None);
+
+pub fn is_sudo(tool: &Path) -> bool {
+    tool.file_name().is_some_and(|n| n == "sudo")
+}
+
+/// True once authenticate() got sudo's credentials cached.
+pub fn cached() -> bool {
+    CACHED.load(Ordering::Relaxed)
+}
+
+/// Reads a line without echoing it, from the terminal, or from stdin when there's none
+/// so automation can pipe it in.
+pub fn read_password(prompt: &str) -> io::Result<String> {
+    let Ok(tty) = OpenOptions::new().read(true).write(true).open("/dev/tty") else {
+        return read_line(&mut io::stdin().lock());
+    };
+    let fd = tty.as_raw_fd();
+    let mut saved: libc::termios = unsafe { std::mem::zeroed() };
+    if !io::stdin().is_terminal() || unsafe { libc::tcgetattr(fd, &mut saved) } != 0 {
+        return read_line(&mut io::stdin().lock());
+    }
+    let mut hidden = saved;
+    hidden.c_lflag &= !libc::ECHO;
+    hidden.c_lflag |= libc::ECHONL;
+    let _held = spinner::hold();
+    let mut out = &tty;
+    write!(out, "{}", prompt)?;
+    out.flush()?;
+    unsafe { libc::tcsetattr(fd, libc::TCSAFLUSH, &hidden) };
+    let line = read_line(&mut io::BufReader::new(&tty));
+    unsafe { libc::tcsetattr(fd, libc::TCSAFLUSH, &saved) };
+    line
+}
+
+fn read_line<R: BufRead>(input: &mut R) -> io::Result<String> {
+    let mut line = String::new();
+    if input.read_line(&mut line)? == 0 {
+        return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "No password, the input was closed."));
+    }
+    Ok(String::from(line.trim_end_matches(['\r', '\n'])))
+}
+
+/// Gets sudo's credentials cached for the run, asking for the password on the terminal when it
+/// wants one, then keeps them fresh until the installer exits.  A no-op as root, or when sudo
+/// isn't the escalation tool.
+pub fn authenticate() -> io::Result<()> {
+    let mut asked = ASKING.lock().unwrap_or_else(|e| e.into_inner());
+    if cached() || privsep::is_root() {
+        return Ok(());
+    }
+    if let Some(outcome) = &*asked {
+        return outcome.clone().map_err(|(kind, message)| io::Error::new(kind, message));
+    }
+    let outcome = ask();
+    *asked = Some(outcome.as_ref().map(|_| ()).map_err(|e| (e.kind(), e.to_string())));
+    outcome
+}
+
+/// authenticate() the first time.
+fn ask() -> io::Result<()> {
+    if !runcmd::escalation_tool().is_some_and(|t| is_sudo(&t)) {
+        return Ok(());
+    }
+    if RunCmd::args("sudo", &["-n", "-v"]).execute_output().exitcode != 0 {
+        let user = std::env::var("USER").unwrap_or_default();
+        let mut tries = 0;
+        loop {
+            let password = read_password(&format!("[sudo] password for {}: ", user))?;
+            // Only masked while sudo has it, not for the rest of the run.
+            let _masked = runcmd::sensitive::scoped(&password);
+            // -S reads it from stdin, -p '' keeps sudo's own prompt off the terminal.
+            let out = RunCmd::args("sudo", &["-S", "-p", "", "-v"]).secret_stdin(&format!("{}\n", password)).execute_output();
+            if out.exitcode == 0 {
+                break;
+            }
+            tries += 1;
+            if tries == ATTEMPTS {
+                return Err(io::Error::new(io::ErrorKind::PermissionDenied, format!("sudo refused the password {} times.", ATTEMPTS)));
+            }
+            eprintln!("Sorry, try again.");
+        }
+    }
+    CACHED.store(true, Ordering::Relaxed);
+    keep_alive();
+    Ok(())
+}
+
+/// Refreshes the credentials on a thread for as long as the installer runs, the way
+/// `sudo -v` in a loop does for scripts.
+fn keep_alive() {
+    thread::spawn(|| loop {
+        thread::sleep(KEEPALIVE);
+        if RunCmd::args("sudo", &["-n", "-v"]).execute_output().exitcode != 0 {
+            log::log(Level::Warn, "Warning: sudo's cached credentials expired, the commands left may fail to get root.");
+            CACHED.store(false, Ordering::Relaxed);
+            return;
+        }
+    });
+}
+
+/// For `--ask-become-pass`: authenticates before anything runs, so an unattended run gets the
+/// password up front, piped to stdin, instead of failing halfway.
+pub fn ask_become_pass() -> io::Result<()> {
+    if privsep::is_root() {
+        return Ok(());
+    }
+    match runcmd::escalation_tool() {
+        Some(tool) if is_sudo(&tool) => authenticate(),
+        _ => Err(io::Error::other("--ask-become-pass is for sudo, which isn't installed.")),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn password_line() {
+        assert_eq!(read_line(&mut "s3cret pass\r\n".as_bytes()).unwrap(), "s3cret pass");
+        assert_eq!(read_line(&mut "".as_bytes()).unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+}
@@ -0,0 +1,91 @@
+use std::io;
+use std::time::Duration;
+
+use crate::engine::StepRecord;
+use crate::log;
+use crate::output;
+use crate::spinner::{self, Outcome};
+
+/// Warnings shown before the rest are left to the log, so the summary fits a screen.
+const MAX_WARNINGS: usize = 10;
+
+/// What `--quiet` prints when the run is over, instead of everything on the way.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Summary {
+    /// The run, "install" or "upgrade".
+    pub command: String,
+    pub duration: Duration,
+    pub steps: Vec<StepRecord>,
+    pub warnings: Vec<String>,
+    pub error: Option<String>,
+    /// Why a reboot is needed, empty when none is.
+    pub reboot_reasons: Vec<String>,
+}
+
+impl Summary {
+
+    pub fn render(&self) -> String {
+        let mut text = match &self.error {
+            Some(_) => format!("{} failed after {}.\n", self.command, spinner::elapsed(self.duration)),
+            None => format!("{} finished in {}.\n", self.command, spinner::elapsed(self.duration)),
+        };
+        for step in &self.steps {
+            let mark = match step.outcome {
+                Outcome::Done => '+',
+                Outcome::Skipped => '=',
+                Outcome::Failed | Outcome::Interrupted => '!',
+            };
+            text.push_str(&format!("  {} {}\n", mark, spinner::summary(&step.title, step.outcome, Duration::from_secs_f64(step.duration_secs))));
+        }
+        if !self.warnings.is_empty() {
+            text.push_str(&format!("{} warning(s):\n", self.warnings.len()));
+            for warning in self.warnings.iter().take(MAX_WARNINGS) {
+                text.push_str(&format!("  {}\n", warning.lines().next().unwrap_or_default()));
+            }
+            if self.warnings.len() > MAX_WARNINGS {
+                text.push_str(&format!("  and {} more, see {}\n", self.warnings.len() - MAX_WARNINGS, log::LOG_PATH));
+            }
+        }
+        if let Some(error) = &self.error {
+            text.push_str(&format!("Error: {}\n", error));
+        }
+        if !self.reboot_reasons.is_empty() {
+            text.push_str(&format!("Reboot required: {}\n", self.reboot_reasons.join(", ")));
+        }
+        text.push_str(&format!("Details are in {}.\n", log::LOG_PATH));
+        text
+    }
+
+    /// Prints it on the stdout output::silence() kept.
+    pub fn print(&self) -> io::Result<()> {
+        output::print(&self.render())
+    }
+
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn step(title: &str, outcome: Outcome) -> StepRecord {
+        StepRecord { name: String::from(title), title: String::from(title), outcome, changed: false, duration_secs: 65.0, cpu_secs: 0.0, max_rss_kb: 0, error: None }
+    }
+
+    #[test]
+    fn one_screen() {
+        let summary = Summary {
+            command: String::from("install"),
+            duration: Duration::from_secs(200),
+            steps: vec![step("Installing packages", Outcome::Skipped), step("Installing the agent", Outcome::Failed)],
+            warnings: (0..12).map(|i| format!("Warning: {}\nmore", i)).collect(),
+            error: Some(String::from("network is unreachable")),
+            reboot_reasons: vec![String::from("the bitflux kernel")],
+        };
+        let text = summary.render();
+        assert!(text.starts_with("install failed after 3m20s.\n  = Installing packages, already done\n  ! Installing the agent, failed after 1m05s\n12 warning(s):\n  Warning: 0\n"));
+        assert!(text.contains("  Warning: 9\n  and 2 more, see /var/log/bitflux-install.log\nError: network is unreachable\nReboot required: the bitflux kernel\n"));
+        assert_eq!(text.lines().count(), 18);
+    }
+
+}
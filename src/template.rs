@@ -0,0 +1,144 @@
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::data::BACKUP_DIR;
+use crate::perms::{self, FileKind, PermissionRecord};
+use crate::runcmd;
+use crate::script;
+use crate::writable;
+
+/// The templates built into the installer, by name, from templates/.
+const TEMPLATES: &[(&str, &str)] = &[
+    ("apparmor-bitfluxcollector", include_str!("../templates/apparmor-bitfluxcollector")),
+    ("bitflux.cil", include_str!("../templates/bitflux.cil")),
+    ("cloud-init.yaml", include_str!("../templates/cloud-init.yaml")),
+    ("kickstart.ks", include_str!("../templates/kickstart.ks")),
+    ("modules-load.conf", include_str!("../templates/modules-load.conf")),
+    ("sbc-sysctl.conf", include_str!("../templates/sbc-sysctl.conf")),
+];
+
+/// The built-in template `name`.
+pub fn get(name: &str) -> io::Result<&'static str> {
+    TEMPLATES.iter().find(|(n, _)| *n == name).map(|(_, t)| *t)
+        .ok_or_else(|| io::Error::other(format!("No template {}.", name)))
+}
+
+/// Fills the `{{name}}` placeholders of `template` with `vars`.  A placeholder without a value
+/// is an error, a unit with one left in would fail to load at best.
+pub fn render(template: &str, vars: &[(&str, &str)]) -> io::Result<String> {
+    let mut out = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find("{{") {
+        out.push_str(&rest[..start]);
+        let end = rest[start..].find("}}").ok_or_else(|| io::Error::other("Unclosed {{ in the template."))?;
+        let name = rest[start + 2..start + end].trim();
+        let value = vars.iter().find(|(k, _)| *k == name)
+            .ok_or_else(|| io::Error::other(format!("No value for {{{{{}}}}} in the template.", name)))?;
+        out.push_str(value.1);
+        rest = &rest[start + end + 2..];
+    }
+    out.push_str(rest);
+    Ok(out)
+}
+
+/// The built-in template `name` filled with `vars`.
+pub fn render_named(name: &str, vars: &[(&str, &str)]) -> io::Result<String> {
+    render(get(name)?, vars)
+}
+
+/// What write() did.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Written {
+    pub record: PermissionRecord,
+    /// The copy of what was there before, None when there was nothing or it was the same.
+    pub backup: Option<PathBuf>,
+    /// False when the file already had the contents.
+    pub changed: bool,
+}
+
+/// Where a copy of what was at `path` at `secs` since the epoch goes under `root`: the same
+/// path under a directory for the second.
+pub fn backup_path(root: &Path, path: &Path, secs: u64) -> PathBuf {
+    root.join("files").join(secs.to_string()).join(path.strip_prefix("/").unwrap_or(path))
+}
+
+/// Copies the file at `path` to backup_path() under `root` for now, returns where it went.  A
+/// dry run only says where.
+pub fn backup(root: &Path, path: &Path) -> io::Result<PathBuf> {
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let backup = backup_path(root, path, secs);
+    if runcmd::Context::current().dry_run {
+        return Ok(backup);
+    }
+    if let Some(dir) = backup.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    // Written twice within a second, the first copy is the one from before.
+    if !backup.exists() {
+        fs::copy(path, &backup)?;
+    }
+    Ok(backup)
+}
+
+/// Writes `contents` to `path` with the owner, mode and label of `kind`.  The file is written
+/// next to it and renamed into place, so it's never seen half written, and a different file
+/// that was there is copied under BACKUP_DIR first.  A file that already has the contents is
+/// left alone but for its permissions.
+pub fn write<P: AsRef<Path>>(path: P, contents: &str, kind: FileKind) -> io::Result<Written> {
+    let path = path.as_ref();
+    let current = match fs::read(path) {
+        Ok(data) => Some(data),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+        Err(e) => return Err(e),
+    };
+    if current.as_deref() == Some(contents.as_bytes()) {
+        return Ok(Written { record: perms::apply(path, kind)?, backup: None, changed: false });
+    }
+    let _unlocked = writable::prepare(path)?;
+    if script::file(path, contents) || runcmd::dry_run(&format!("write {}", path.display())) {
+        return Ok(Written { record: perms::apply(path, kind)?, backup: None, changed: true });
+    }
+    let backup = match current {
+        Some(_) => Some(backup(Path::new(BACKUP_DIR), path)?),
+        None => None,
+    };
+    let dir = path.parent().ok_or_else(|| io::Error::other(format!("{} has no directory.", path.display())))?;
+    fs::create_dir_all(dir)?;
+    let tmp = dir.join(format!(".{}.bitflux-tmp", path.file_name().unwrap_or_default().to_string_lossy()));
+    let _ = fs::remove_file(&tmp);
+    // Private until it has its final owner and mode.
+    let mut file = fs::OpenOptions::new().write(true).create_new(true).mode(0o600).open(&tmp)?;
+    file.write_all(contents.as_bytes()).and_then(|_| file.sync_all()).inspect_err(|_| {
+        let _ = fs::remove_file(&tmp);
+    })?;
+    fs::rename(&tmp, path)?;
+    Ok(Written { record: perms::apply(path, kind)?, backup, changed: true })
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render_placeholders() {
+        let template = "[Service]\nExecStart={{ exec }} --config {{config}}\n";
+        let unit = render(template, &[("exec", "/opt/bitflux/bin/agent"), ("config", "/etc/bitflux/agent.conf")]).unwrap();
+        assert_eq!(unit, "[Service]\nExecStart=/opt/bitflux/bin/agent --config /etc/bitflux/agent.conf\n");
+        assert!(render(template, &[("exec", "/bin/true")]).is_err());
+        assert!(render("ExecStart={{exec", &[("exec", "/bin/true")]).is_err());
+    }
+
+    #[test]
+    fn built_in_templates() {
+        assert_eq!(render_named("modules-load.conf", &[("module", "swaphints")]).unwrap(), "swaphints\n");
+        assert!(render_named("sbc-sysctl.conf", &[("swappiness", "100")]).unwrap().ends_with("vm.swappiness = 100\n"));
+        assert!(get("grub").is_err());
+        assert_eq!(backup_path(Path::new(BACKUP_DIR), Path::new("/etc/sysctl.d/60-bitflux-sbc.conf"), 1791980107),
+            PathBuf::from("/var/lib/bitflux-installer/backups/files/1791980107/etc/sysctl.d/60-bitflux-sbc.conf"));
+    }
+
+}
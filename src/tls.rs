@@ -0,0 +1,88 @@
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::agentconf::{self, AGENT_CONFIG};
+use crate::log::{self, Level};
+
+/// Agent config key holding public keys to pin backend certificates to, in curl's
+/// `sha256//<base64>;sha256//<base64>` form.
+pub const PIN_KEY: &str = "tls_pinned_pubkey";
+
+/// Set by --debug, the negotiated TLS parameters get printed.
+static DEBUG: AtomicBool = AtomicBool::new(false);
+
+pub fn set_debug(debug: bool) {
+    DEBUG.store(debug, Ordering::Relaxed);
+}
+
+/// The configured pins, if any.
+pub fn pinned_pubkey() -> Option<String> {
+    agentconf::load(AGENT_CONFIG).ok()?.get(PIN_KEY).filter(|p| !p.is_empty()).cloned()
+}
+
+/// curl options every backend request gets: https only, also across redirects, TLS 1.2 or newer
+/// and certificate validation, which is never turned off.  With a pin the server key must match it.
+pub fn curl_args(pin: Option<&str>, debug: bool) -> Vec<String> {
+    let mut args: Vec<String> = ["--proto", "=https", "--proto-redir", "=https", "--tlsv1.2"]
+        .iter().map(|a| String::from(*a)).collect();
+    if let Some(pin) = pin {
+        args.push(String::from("--pinnedpubkey"));
+        args.push(String::from(pin));
+    }
+    if debug {
+        args.push(String::from("-v"));
+    }
+    args
+}
+
+/// curl_args() with the pins from the config and the current debug setting.
+pub fn hardened_curl_args() -> Vec<String> {
+    curl_args(pinned_pubkey().as_deref(), DEBUG.load(Ordering::Relaxed))
+}
+
+/// The handshake details from `curl -v` output.
+pub fn negotiated(stderr: &str) -> Vec<&str> {
+    const KEEP: &[&str] = &["SSL connection using", "ALPN", "Server certificate", "subject:", "issuer:", "expire date:", "SSL certificate verify", "public key hash"];
+    stderr.lines()
+        .filter(|l| l.starts_with('*'))
+        .map(|l| l.trim_start_matches('*').trim())
+        .filter(|l| KEEP.iter().any(|k| l.contains(k)))
+        .collect()
+}
+
+/// Prints the negotiated TLS parameters of a request when --debug is on.
+pub fn log(url: &str, stderr: &str) {
+    if !DEBUG.load(Ordering::Relaxed) {
+        return;
+    }
+    let lines: Vec<String> = negotiated(stderr).into_iter().map(|line| format!("  {}", line)).collect();
+    log::log(Level::Info, &format!("TLS for {}:\n{}", url, lines.join("\n")));
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn curl_options() {
+        let args = curl_args(None, false);
+        assert!(args.windows(2).any(|w| w == ["--proto", "=https"]));
+        assert!(args.contains(&String::from("--tlsv1.2")));
+        assert!(!args.iter().any(|a| a == "-k" || a == "--insecure"));
+
+        let args = curl_args(Some("sha256//AAAA"), true);
+        assert!(args.windows(2).any(|w| w == ["--pinnedpubkey", "sha256//AAAA"]));
+        assert_eq!(args.last().unwrap(), "-v");
+    }
+
+    #[test]
+    fn negotiated_from_verbose_output() {
+        let stderr = "* Connected to mirror.bitflux.ai\n\
+                      * SSL connection using TLSv1.3 / TLS_AES_256_GCM_SHA384\n\
+                      * Server certificate:\n\
+                      *  subject: CN=mirror.bitflux.ai\n\
+                      > GET / HTTP/1.1\n";
+        assert_eq!(negotiated(stderr), vec!["SSL connection using TLSv1.3 / TLS_AES_256_GCM_SHA384", "Server certificate:", "subject: CN=mirror.bitflux.ai"]);
+    }
+
+}
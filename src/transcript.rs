@@ -0,0 +1,123 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::fd::{FromRawFd, RawFd};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Where unattended runs keep a copy of everything they print.
+pub const TRANSCRIPT_DIR: &str = "/var/log/bitflux";
+
+/// Copies all the installer writes to stdout and stderr, its children's output included, into
+/// a file while it still goes to wherever it went before.
+pub struct Transcript {
+    path: PathBuf,
+    /// The original stdout and stderr, put back by finish().
+    saved: Vec<(RawFd, RawFd)>,
+    copiers: Vec<JoinHandle<()>>,
+}
+
+fn check(ret: libc::c_int) -> io::Result<libc::c_int> {
+    match ret {
+        -1 => Err(io::Error::last_os_error()),
+        fd => Ok(fd),
+    }
+}
+
+/// Redirects `fd` into a pipe, returning the original descriptor and the read end.
+fn divert(fd: RawFd) -> io::Result<(RawFd, RawFd)> {
+    let saved = check(unsafe { libc::dup(fd) })?;
+    let mut pipe = [0; 2];
+    check(unsafe { libc::pipe(pipe.as_mut_ptr()) })?;
+    check(unsafe { libc::dup2(pipe[1], fd) })?;
+    unsafe { libc::close(pipe[1]) };
+    unsafe { libc::fcntl(saved, libc::F_SETFD, libc::FD_CLOEXEC) };
+    unsafe { libc::fcntl(pipe[0], libc::F_SETFD, libc::FD_CLOEXEC) };
+    Ok((saved, pipe[0]))
+}
+
+impl Transcript {
+
+    /// Starts a transcript of the `command` run in `dir`, named after it and the start time.
+    pub fn start<P: AsRef<Path>>(dir: P, command: &str) -> io::Result<Transcript> {
+        let dir = dir.as_ref();
+        fs::create_dir_all(dir)?;
+        let started = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let path = dir.join(format!("{}-{}.log", command, started));
+        let file = OpenOptions::new().create_new(true).append(true).mode(0o600).open(&path)?;
+        let file = Arc::new(Mutex::new(file));
+
+        io::stdout().flush()?;
+        io::stderr().flush()?;
+        let mut transcript = Transcript { path, saved: Vec::new(), copiers: Vec::new() };
+        for fd in [libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+            let (saved, read) = divert(fd)?;
+            transcript.saved.push((fd, saved));
+            let file = file.clone();
+            let mut original = unsafe { File::from_raw_fd(check(libc::dup(saved))?) };
+            let mut pipe = unsafe { File::from_raw_fd(read) };
+            transcript.copiers.push(thread::spawn(move || {
+                let mut buf = [0u8; 8192];
+                while let Ok(n @ 1..) = pipe.read(&mut buf) {
+                    let _ = original.write_all(&buf[..n]);
+                    let _ = file.lock().unwrap_or_else(|e| e.into_inner()).write_all(&buf[..n]);
+                }
+            }));
+        }
+        Ok(transcript)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Puts stdout and stderr back and waits for the copies to catch up.
+    pub fn finish(mut self) -> PathBuf {
+        self.restore();
+        for copier in self.copiers.drain(..) {
+            let _ = copier.join();
+        }
+        self.path.clone()
+    }
+
+    fn restore(&mut self) {
+        let _ = io::stdout().flush();
+        let _ = io::stderr().flush();
+        for (fd, saved) in self.saved.drain(..) {
+            unsafe {
+                libc::dup2(saved, fd);
+                libc::close(saved);
+            }
+        }
+    }
+
+}
+
+impl Drop for Transcript {
+
+    fn drop(&mut self) {
+        self.restore();
+    }
+
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn copies_child_output() {
+        let dir = std::env::temp_dir().join(format!("bitflux-transcript-{}", std::process::id()));
+        let transcript = Transcript::start(&dir, "install").unwrap();
+        assert!(transcript.path().starts_with(&dir));
+        std::process::Command::new("sh").args(["-c", "echo to-stdout; echo to-stderr >&2"]).status().unwrap();
+        let path = transcript.finish();
+        let copy = fs::read_to_string(&path).unwrap();
+        assert!(copy.contains("to-stdout\n") && copy.contains("to-stderr\n"));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+}
@@ -0,0 +1,438 @@
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{self, IsTerminal, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::device;
+use crate::engine::{self, StepRecord};
+use crate::i18n;
+use crate::install::{self, Options};
+use crate::interrupt;
+use crate::license;
+use crate::log::{self, Level};
+use crate::output;
+use crate::reboot;
+use crate::runcmd;
+use crate::spinner::{self, Outcome, FRAMES};
+
+/// Lines of output the log pane keeps.
+const LOG_LINES: usize = 1000;
+/// Widest the step list gets.
+const STEPS_WIDTH: usize = 44;
+
+/// A key read from the terminal.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Key {
+    Char(char),
+    Enter,
+    Backspace,
+    Esc,
+    Other,
+}
+
+/// The terminal, unbuffered, unechoed and on the alternate screen until dropped.
+struct Terminal {
+    tty: File,
+    saved: libc::termios,
+}
+
+impl Terminal {
+
+    fn open() -> io::Result<Terminal> {
+        let tty = OpenOptions::new().read(true).write(true).open("/dev/tty")?;
+        let mut saved: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(tty.as_raw_fd(), &mut saved) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut raw = saved;
+        // ISIG stays, Ctrl-C still interrupts the install the way it does without --tui.
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO);
+        // A read returns after a tenth of a second without a key, that's the redraw tick.
+        raw.c_cc[libc::VMIN] = 0;
+        raw.c_cc[libc::VTIME] = 1;
+        if unsafe { libc::tcsetattr(tty.as_raw_fd(), libc::TCSANOW, &raw) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut term = Terminal { tty, saved };
+        term.write("\x1b[?1049h\x1b[?25l")?;
+        Ok(term)
+    }
+
+    /// Rows and columns.
+    fn size(&self) -> (usize, usize) {
+        let mut size: libc::winsize = unsafe { std::mem::zeroed() };
+        match unsafe { libc::ioctl(self.tty.as_raw_fd(), libc::TIOCGWINSZ, &mut size) } {
+            0 if size.ws_row > 0 && size.ws_col > 0 => (size.ws_row as usize, size.ws_col as usize),
+            _ => (24, 80),
+        }
+    }
+
+    fn write(&mut self, text: &str) -> io::Result<()> {
+        self.tty.write_all(text.as_bytes())?;
+        self.tty.flush()
+    }
+
+    /// The next key, None when none came within the tick.
+    fn key(&mut self) -> io::Result<Option<Key>> {
+        let mut buf = [0u8; 8];
+        let n = match self.tty.read(&mut buf) {
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => 0,
+            Err(e) => return Err(e),
+        };
+        Ok(match &buf[..n] {
+            [] => None,
+            [b'\r'] | [b'\n'] => Some(Key::Enter),
+            [0x7f] | [0x08] => Some(Key::Backspace),
+            [0x1b] => Some(Key::Esc),
+            // Arrows and the other keys sending escape sequences.
+            [0x1b, ..] => Some(Key::Other),
+            bytes => Some(String::from_utf8_lossy(bytes).chars().next().filter(|c| !c.is_control()).map_or(Key::Other, Key::Char)),
+        })
+    }
+
+}
+
+impl Drop for Terminal {
+
+    fn drop(&mut self) {
+        let _ = self.write("\x1b[?25h\x1b[?1049l");
+        unsafe { libc::tcsetattr(self.tty.as_raw_fd(), libc::TCSANOW, &self.saved) };
+    }
+
+}
+
+/// What the installer and its commands printed, as the log pane shows it.
+#[derive(Debug, Default)]
+struct Pane {
+    lines: VecDeque<String>,
+    /// A carriage return came last, the line starts over unless a newline follows.
+    cr: bool,
+    /// Inside an escape sequence, which the pane leaves out.
+    esc: bool,
+}
+
+impl Pane {
+
+    fn push(&mut self, text: &str) {
+        if self.lines.is_empty() {
+            self.lines.push_back(String::new());
+        }
+        for c in text.chars() {
+            if self.esc {
+                // CSI sequences end with a letter, "\x1b[2K".
+                self.esc = !c.is_ascii_alphabetic() && c != '~';
+                continue;
+            }
+            if self.cr && c != '\n' {
+                if let Some(line) = self.lines.back_mut() {
+                    line.clear();
+                }
+            }
+            self.cr = false;
+            match c {
+                '\x1b' => self.esc = true,
+                '\r' => self.cr = true,
+                '\n' => {
+                    self.lines.push_back(String::new());
+                    if self.lines.len() > LOG_LINES {
+                        self.lines.pop_front();
+                    }
+                }
+                '\t' => self.lines.back_mut().into_iter().for_each(|l| l.push_str("    ")),
+                c if c.is_control() => {}
+                c => self.lines.back_mut().into_iter().for_each(|l| l.push(c)),
+            }
+        }
+    }
+
+    /// The last `n` lines, without the one still being written when it's empty.
+    fn tail(&self, n: usize) -> Vec<String> {
+        let mut lines: Vec<&String> = self.lines.iter().collect();
+        if lines.last().is_some_and(|l| l.is_empty()) {
+            lines.pop();
+        }
+        lines[lines.len().saturating_sub(n)..].iter().map(|l| (*l).clone()).collect()
+    }
+
+}
+
+/// Sends stdout and stderr, the installer's and its commands', to a pane until dropped.
+struct Capture {
+    saved: [i32; 2],
+}
+
+impl Capture {
+
+    fn start(pane: Arc<Mutex<Pane>>) -> io::Result<Capture> {
+        io::stdout().flush()?;
+        io::stderr().flush()?;
+        let mut fds = [0; 2];
+        if unsafe { libc::pipe2(fds.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let saved = unsafe { [libc::dup(libc::STDOUT_FILENO), libc::dup(libc::STDERR_FILENO)] };
+        unsafe {
+            libc::dup2(fds[1], libc::STDOUT_FILENO);
+            libc::dup2(fds[1], libc::STDERR_FILENO);
+            libc::close(fds[1]);
+        }
+        let mut reader = unsafe { File::from_raw_fd(fds[0]) };
+        // Not joined, a daemon a command started may hold the pipe open for good.
+        thread::spawn(move || {
+            let mut buf = [0u8; 4096];
+            while let Ok(n @ 1..) = reader.read(&mut buf) {
+                pane.lock().unwrap_or_else(|e| e.into_inner()).push(&String::from_utf8_lossy(&buf[..n]));
+            }
+        });
+        Ok(Capture { saved })
+    }
+
+}
+
+impl Drop for Capture {
+
+    fn drop(&mut self) {
+        let _ = io::stdout().flush();
+        let _ = io::stderr().flush();
+        unsafe {
+            libc::dup2(self.saved[0], libc::STDOUT_FILENO);
+            libc::dup2(self.saved[1], libc::STDERR_FILENO);
+            libc::close(self.saved[0]);
+            libc::close(self.saved[1]);
+        }
+    }
+
+}
+
+/// `text` cut or padded to `width` columns.
+fn fit(text: &str, width: usize) -> String {
+    let mut out: String = text.chars().take(width).collect();
+    let len = out.chars().count();
+    out.extend(std::iter::repeat_n(' ', width - len));
+    out
+}
+
+/// Where a step of the list is.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum State {
+    Pending,
+    Running,
+    Ended(Outcome),
+}
+
+fn step_line(title: &str, state: State, frame: usize, took: Option<Duration>) -> String {
+    let marker = match state {
+        State::Pending => '·',
+        State::Running => FRAMES[frame % FRAMES.len()],
+        State::Ended(Outcome::Done) => '✓',
+        State::Ended(Outcome::Skipped) => '-',
+        State::Ended(Outcome::Failed) => '✗',
+        State::Ended(Outcome::Interrupted) => '!',
+    };
+    let title = i18n::tr(title);
+    match (state, took) {
+        (State::Ended(Outcome::Skipped), _) => format!(" {} {} ({})", marker, title, i18n::tr("already done")),
+        (_, Some(took)) => format!(" {} {} ({})", marker, title, spinner::elapsed(took)),
+        _ => format!(" {} {}", marker, title),
+    }
+}
+
+/// The step list: preflight, then the steps engine::run() planned as `records` have them.
+/// `running_for` is how long the running one has been going, `finished` once the install is.
+fn step_lines(planned: &[(String, String)], records: &[StepRecord], finished: bool, frame: usize, running_for: Duration) -> Vec<String> {
+    let preflight = match (planned.is_empty(), finished) {
+        (false, _) => State::Ended(Outcome::Done),
+        (true, false) => State::Running,
+        (true, true) => State::Ended(Outcome::Failed),
+    };
+    let mut lines = vec![step_line("Preflight checks", preflight, frame, None)];
+    let mut running = false;
+    for (name, title) in planned {
+        let line = match records.iter().rev().find(|r| r.name == *name) {
+            Some(record) => step_line(title, State::Ended(record.outcome), frame, Some(Duration::from_secs_f64(record.duration_secs))),
+            None if !running && !finished => {
+                running = true;
+                step_line(title, State::Running, frame, Some(running_for))
+            }
+            None => step_line(title, State::Pending, frame, None),
+        };
+        lines.push(line);
+    }
+    lines
+}
+
+/// The whole screen: a title bar, the steps next to the output, and `status` at the bottom.
+fn screen(rows: usize, cols: usize, steps: &[String], log: &[String], status: &str) -> String {
+    let left = STEPS_WIDTH.min(cols / 3);
+    let right = cols.saturating_sub(left + 1);
+    let body = rows.saturating_sub(2);
+    let mut out = format!("\x1b[H\x1b[7m{}\x1b[0m", fit(" bitflux installer", cols));
+    let log = &log[log.len().saturating_sub(body)..];
+    for row in 0..body {
+        let step = steps.get(row).map(String::as_str).unwrap_or("");
+        let line = log.get(row).map(String::as_str).unwrap_or("");
+        out.push_str(&format!("\x1b[{};1H{}│{}", row + 2, fit(step, left), fit(line, right)));
+    }
+    out.push_str(&format!("\x1b[{};1H\x1b[7m{}\x1b[0m", rows, fit(&format!(" {}", status), cols)));
+    out
+}
+
+/// A box asking `question`, taking what's typed until Enter and asking again while
+/// `validate` turns it down.  `default` is the answer when nothing is typed, Esc cancels.
+fn dialog<F>(term: &mut Terminal, question: &str, default: &str, validate: F) -> io::Result<String>
+where
+    F: Fn(&str) -> Result<(), String>,
+{
+    let mut input = String::new();
+    let mut error = String::new();
+    loop {
+        interrupt::check()?;
+        let (rows, cols) = term.size();
+        let width = cols.saturating_sub(4).min(64);
+        let (top, left) = (rows.saturating_sub(7) / 2 + 1, cols.saturating_sub(width) / 2 + 1);
+        let shown = match (input.is_empty(), default.is_empty()) {
+            (true, false) => format!("[{}]", default),
+            _ => format!("{}_", input),
+        };
+        let lines = [
+            format!("┌{}┐", "─".repeat(width - 2)),
+            format!("│ {} │", fit(&i18n::tr(question), width - 4)),
+            format!("│ {} │", fit(&format!("> {}", shown), width - 4)),
+            format!("│ \x1b[31m{}\x1b[0m │", fit(&i18n::tr(&error), width - 4)),
+            format!("│ {} │", fit(&i18n::tr("Enter accepts, Esc cancels the install"), width - 4)),
+            format!("└{}┘", "─".repeat(width - 2)),
+        ];
+        let mut frame = String::from("\x1b[2J");
+        for (i, line) in lines.iter().enumerate() {
+            frame.push_str(&format!("\x1b[{};{}H{}", top + i, left, line));
+        }
+        term.write(&frame)?;
+        match term.key()? {
+            Some(Key::Char(c)) => input.push(c),
+            Some(Key::Backspace) => {
+                input.pop();
+            }
+            Some(Key::Enter) => {
+                let answer = if input.is_empty() { String::from(default) } else { input.clone() };
+                match validate(&answer) {
+                    Ok(()) => return Ok(answer),
+                    Err(e) => error = e,
+                }
+            }
+            Some(Key::Esc) => return Err(io::Error::new(io::ErrorKind::Interrupted, "Install cancelled.")),
+            Some(Key::Other) | None => {}
+        }
+    }
+}
+
+/// Asks in dialogs for what the install needs and `opts` lacks, the kernel too when it
+/// has nothing, as Options::ask() would.
+fn ask(term: &mut Terminal, mut opts: Options) -> io::Result<Options> {
+    if opts.kernel.is_none() && opts.license_key.is_none() {
+        let answer = dialog(term, "Install the bitflux swaphints kernel along with the agent? [Y/n]", "y", |a| {
+            match a.to_lowercase().as_str() {
+                "y" | "yes" | "n" | "no" => Ok(()),
+                _ => Err(String::from("Please answer yes or no.")),
+            }
+        })?;
+        opts.kernel = Some(answer.to_lowercase().starts_with('y'));
+    }
+    if opts.license_key.is_none() {
+        opts.license_key = Some(dialog(term, "License key:", "", license::validate)?);
+    }
+    if opts.device_id.is_none() {
+        let default = device::current().unwrap_or_else(device::generated);
+        opts.device_id = Some(dialog(term, "Device id, the name this host shows up as:", &default, device::validate)?);
+    }
+    Ok(opts)
+}
+
+/// `installer install --tui`: asks for what `opts` lacks in dialogs, then runs the same
+/// install as without it, showing its steps next to a live log with everything it printed.
+/// The step summary is printed once the screen is left.
+pub fn run(opts: Options) -> io::Result<()> {
+    if !io::stdin().is_terminal() || !io::stderr().is_terminal() {
+        return Err(io::Error::other("--tui needs a terminal, leave it out for unattended installs."));
+    }
+    let mut term = Terminal::open()?;
+    let opts = ask(&mut term, opts)?;
+    // Everything was asked already, nothing may prompt behind the screen.
+    runcmd::Context { non_interactive: true, ..runcmd::Context::current() }.set();
+
+    let pane = Arc::new(Mutex::new(Pane::default()));
+    let capture = Capture::start(pane.clone())?;
+    let draw = |term: &mut Terminal, finished: bool, frame: usize, running_for: Duration, status: &str| {
+        let (rows, cols) = term.size();
+        let steps = step_lines(&engine::planned(), &engine::steps(), finished, frame, running_for);
+        let log = pane.lock().unwrap_or_else(|e| e.into_inner()).tail(rows);
+        term.write(&screen(rows, cols, &steps, &log, &i18n::tr(status)))
+    };
+    term.write("\x1b[2J")?;
+    let result = thread::scope(|scope| {
+        let worker = scope.spawn(|| install::run(&opts));
+        let (mut frame, mut step, mut since) = (0, 0, Instant::now());
+        while !worker.is_finished() {
+            // The running step is the first one without a record.
+            let done = engine::steps().len();
+            if done != step {
+                (step, since) = (done, Instant::now());
+            }
+            draw(&mut term, false, frame, since.elapsed(), "Installing, Ctrl-C stops after the current step")?;
+            term.key()?;
+            frame += 1;
+        }
+        worker.join().unwrap_or_else(|_| Err(io::Error::other("The install panicked.")))
+    });
+    let status = match &result {
+        Ok(()) => String::from("bitflux installed. Press any key to exit."),
+        Err(e) => format!("{} Press any key to exit.", e),
+    };
+    draw(&mut term, true, 0, Duration::ZERO, &status)?;
+    while term.key()?.is_none() {}
+    drop(capture);
+    drop(term);
+
+    output::print(&engine::summary(&engine::steps()))?;
+    let reasons = reboot::reasons();
+    if !reasons.is_empty() {
+        log::log(Level::Warn, &reboot::notice(&reasons));
+    }
+    result
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pane_follows_the_output() {
+        let mut pane = Pane::default();
+        pane.push("Reading package lists...\r\nProgress: 10%\rProgress: 90%\rProgress: 100%\n\x1b[32mdone\x1b[0m\n");
+        assert_eq!(pane.tail(10), ["Reading package lists...", "Progress: 100%", "done"]);
+        pane.push("half");
+        pane.push(" a line\n");
+        assert_eq!(pane.tail(1), ["half a line"]);
+    }
+
+    #[test]
+    fn steps_as_they_go() {
+        let planned = [("repository", "Setting up the repository"), ("agent", "Installing the agent")]
+            .map(|(n, t)| (String::from(n), String::from(t)));
+        let records = [StepRecord {
+            name: String::from("repository"), title: String::from("Setting up the repository"), outcome: Outcome::Skipped,
+            changed: false, duration_secs: 0.1, cpu_secs: 0.0, max_rss_kb: 0, error: None,
+        }];
+        assert_eq!(step_lines(&[], &[], false, 0, Duration::ZERO), [" ⠋ Preflight checks"]);
+        assert_eq!(step_lines(&planned, &records, false, 1, Duration::from_secs(42)),
+            [" ✓ Preflight checks", " - Setting up the repository (already done)", " ⠙ Installing the agent (42s)"]);
+        assert_eq!(step_lines(&planned, &records, true, 0, Duration::ZERO)[2], " · Installing the agent");
+        assert_eq!(fit("bitflux", 4), "bitf");
+        assert_eq!(fit("ok", 4), "ok  ");
+    }
+
+}
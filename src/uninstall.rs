@@ -0,0 +1,355 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use crate::account;
+use crate::cmdline::{self, RECLAIM_PARAMS};
+use crate::data::{self, DataPolicy, BACKUP_DIR, DATA_DIRS, DATA_STATE_PATH};
+use crate::download::DOWNLOAD_DIR;
+use crate::exitcode::Kind;
+use crate::firewall::IPTABLES_RULES;
+use crate::journal::{Change, Journal, JOURNAL_DIR};
+use crate::kernel::{Bootloader, KERNEL_STATE_PATH};
+use crate::kmod::{self, DKMS_PACKAGE, MODULES_LOAD_PATH};
+use crate::license::LICENSE_PATH;
+use crate::log::{self, Level};
+use crate::mac::{Mac, APPARMOR_PROFILE};
+use crate::manifest::{Manifest, MANIFEST_PATH};
+use crate::output;
+use crate::pkg::{PackageManager, AGENT_PACKAGE};
+use crate::receipt::RECEIPT_DIR;
+use crate::resume::PROGRESS_PATH;
+use crate::runcmd::{self, RunCmd};
+use crate::sbc::SYSCTL_PATH;
+use crate::service::{self, Service, INIT_SCRIPT_DIR};
+use crate::unit::DROPIN_DIR;
+
+/// Where the agent package keeps its binaries, config and data.
+pub const AGENT_DIR: &str = "/opt/bitflux";
+/// The installer's own settings, written by the operator rather than the installer.
+pub const CONFIG_DIR: &str = "/etc/bitflux";
+/// What lsmod reads the modules and their users from.
+const PROC_MODULES: &str = "/proc/modules";
+const PROC_MOUNTS: &str = "/proc/self/mounts";
+
+/// What an uninstall removed and what it left, printed when it's done.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Summary {
+    pub removed: Vec<String>,
+    /// Each with why it stayed.
+    pub kept: Vec<String>,
+}
+
+impl Summary {
+
+    pub fn render(&self) -> String {
+        if self.removed.is_empty() && self.kept.is_empty() {
+            return String::from("bitflux isn't installed, nothing removed.\n");
+        }
+        let mut out = String::new();
+        for (title, items) in [("Removed", &self.removed), ("Kept", &self.kept)] {
+            if !items.is_empty() {
+                out.push_str(&format!("{}:\n", title));
+                for item in items {
+                    out.push_str(&format!("  {}\n", item));
+                }
+            }
+        }
+        out
+    }
+
+}
+
+/// Removes `path`, a file or a whole directory, noting it in `summary` if it was there.
+fn remove(path: &Path, summary: &mut Summary) -> io::Result<()> {
+    let Ok(meta) = fs::symlink_metadata(path) else {
+        return Ok(());
+    };
+    if !runcmd::dry_run(&format!("remove {}", path.display())) {
+        match meta.is_dir() {
+            true => fs::remove_dir_all(path)?,
+            false => fs::remove_file(path)?,
+        }
+    }
+    summary.removed.push(path.display().to_string());
+    Ok(())
+}
+
+/// Removes everything in `dir` but the entries named in `keep`, and `dir` itself once empty.
+fn remove_except(dir: &Path, keep: &[&Path], summary: &mut Summary) -> io::Result<()> {
+    if !dir.is_dir() {
+        return Ok(());
+    }
+    if !keep.iter().any(|k| k.starts_with(dir)) {
+        return remove(dir, summary);
+    }
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        match keep.iter().any(|k| k.starts_with(&path)) {
+            true if keep.contains(&path.as_path()) => {}
+            true => remove_except(&path, keep, summary)?,
+            false => remove(&path, summary)?,
+        }
+    }
+    if fs::read_dir(dir)?.next().is_none() {
+        remove(dir, summary)?;
+    }
+    Ok(())
+}
+
+/// Unloads the swaphints module and removes what builds and loads it.  A module built into the
+/// bitflux kernel can't be unloaded and goes with the kernel.
+fn remove_module(pm: &PackageManager, summary: &mut Summary) -> io::Result<()> {
+    // initstate only exists for modules that were loaded rather than built in.
+    if Path::new("/sys/module").join(kmod::MODULE).join("initstate").exists() {
+        let out = RunCmd::args("modprobe", &["-r", kmod::MODULE]).execute_output();
+        match out.exitcode {
+            0 => summary.removed.push(format!("module {} (unloaded)", kmod::MODULE)),
+            _ => summary.kept.push(format!("module {} is still loaded until the next reboot: {}", kmod::MODULE, out.stderr.trim())),
+        }
+    }
+    remove(Path::new(MODULES_LOAD_PATH), summary)?;
+    if pm.installed_version(DKMS_PACKAGE).is_some() {
+        if !pm.remove(&[DKMS_PACKAGE]) {
+            return Err(io::Error::other(format!("Failed to remove {}.", DKMS_PACKAGE)));
+        }
+        summary.removed.push(format!("package {}", DKMS_PACKAGE));
+    }
+    Ok(())
+}
+
+/// Closes the ports installs opened in the firewall, with the saved iptables rules they changed.
+/// Takes RECLAIM_PARAMS off the kernels' command lines, only warning when the bootloader
+/// can't have them taken off.
+fn remove_params(summary: &mut Summary) {
+    let Some(bootloader) = Bootloader::detect() else {
+        return;
+    };
+    let unset = Journal::open(JOURNAL_DIR).and_then(|mut journal| match cmdline::pending(bootloader, &[], RECLAIM_PARAMS)? {
+        true => cmdline::unset(RECLAIM_PARAMS, &mut journal),
+        false => Ok(false),
+    });
+    match unset {
+        Ok(true) => summary.removed.push(format!("the kernel parameters {}", RECLAIM_PARAMS.join(" "))),
+        Ok(false) => {}
+        Err(e) => log::log(Level::Warn, &format!("Warning: the kernel parameters {} stay: {}", RECLAIM_PARAMS.join(" "), e)),
+    }
+}
+
+fn close_ports(pm: &PackageManager, summary: &mut Summary) -> io::Result<()> {
+    let mut journal = Journal::open(JOURNAL_DIR)?;
+    let report = journal.rollback_matching(Some(pm), |c| match c {
+        Change::Firewall { .. } => true,
+        Change::File { path, .. } => IPTABLES_RULES.contains(&path.as_str()),
+        _ => false,
+    })?;
+    summary.removed.extend(report.undone);
+    summary.kept.extend(report.failed);
+    Ok(())
+}
+
+/// The reference count and the modules using `module` in /proc/modules formatted `data`, None
+/// while it isn't loaded.
+pub fn module_users(data: &str, module: &str) -> Option<(u32, Vec<String>)> {
+    let fields: Vec<&str> = data.lines().map(|l| l.split_whitespace().collect::<Vec<&str>>()).find(|f| f.len() >= 4 && f[0] == module)?;
+    let users = fields[3].split(',').filter(|u| !u.is_empty() && *u != "-").map(String::from).collect();
+    Some((fields[2].parse().unwrap_or(0), users))
+}
+
+/// The units in `systemctl list-dependencies --reverse --plain` output that need the service,
+/// the targets it's enabled in aside.
+pub fn dependents(listing: &str) -> Vec<String> {
+    listing.lines().skip(1)
+        .map(str::trim)
+        .filter(|u| !u.is_empty() && !u.ends_with(".target"))
+        .map(String::from)
+        .collect()
+}
+
+/// The mount points in /proc/mounts formatted `data` at or below any of `dirs`.
+pub fn mounts_under(data: &str, dirs: &[&str]) -> Vec<String> {
+    data.lines()
+        .filter_map(|l| l.split_whitespace().nth(1))
+        .map(|m| m.replace("\\040", " ").replace("\\011", "\t"))
+        .filter(|m| dirs.iter().any(|d| Path::new(m).starts_with(d)))
+        .collect()
+}
+
+/// What removing bitflux would break, each with why: other modules or, with the agent
+/// stopped, anything else holding the swaphints module, units that need the agent and
+/// filesystems mounted in what is removed.
+pub fn blockers(agent: &Service) -> Vec<String> {
+    let mut blockers = Vec::new();
+    match module_users(&fs::read_to_string(PROC_MODULES).unwrap_or_default(), kmod::MODULE) {
+        Some((_, users)) if !users.is_empty() => {
+            blockers.push(format!("module {} is used by the modules {}", kmod::MODULE, users.join(", ")));
+        }
+        // While the agent runs it holds the module itself.
+        Some((refs, _)) if refs > 0 && !agent.is_active() => {
+            blockers.push(format!("module {} is held {} times though the agent isn't running, see lsmod and lsof", kmod::MODULE, refs));
+        }
+        _ => {}
+    }
+    if service::systemd_running() {
+        let unit = format!("{}.service", agent.name);
+        let out = RunCmd::args("systemctl", &["list-dependencies", "--reverse", "--plain", "--no-pager", &unit]).execute_output();
+        let units = dependents(&out.stdout);
+        if out.exitcode == 0 && !units.is_empty() {
+            blockers.push(format!("{} need {}", units.join(", "), unit));
+        }
+    }
+    let mounts = mounts_under(&fs::read_to_string(PROC_MOUNTS).unwrap_or_default(), &[AGENT_DIR, CONFIG_DIR, RECEIPT_DIR]);
+    if !mounts.is_empty() {
+        blockers.push(format!("{} mounted in what's removed, unmount first", mounts.join(", ")));
+    }
+    blockers
+}
+
+/// Stops and removes the bitflux agent, its kernel module and everything the installer put in
+/// place: units, config and state.  The agent data is handled as `policy` says, `purge` also
+/// removes it along with its backups and the installer's own settings.  Files changed since the
+/// install are left where they are.  The bitflux kernel stays installed, it's still the default
+/// boot entry, see rollback-kernel.  Nothing is touched while blockers() finds something it
+/// would break, unless `force`.
+pub fn run(policy: DataPolicy, purge: bool, force: bool) -> io::Result<()> {
+    let pm = PackageManager::detect().ok_or_else(|| Kind::Unsupported.error("No supported package manager found."))?;
+    let policy = if purge { DataPolicy::Remove } else { policy };
+    let mut summary = Summary::default();
+
+    let agent = Service::new(AGENT_PACKAGE);
+    let blockers = blockers(&agent);
+    match (blockers.is_empty(), force) {
+        (true, _) => {}
+        (false, true) => blockers.iter().for_each(|b| log::log(Level::Warn, &format!("Uninstalling anyway: {}.", b))),
+        (false, false) => {
+            return Err(io::Error::other(format!("Not uninstalling, it would break what still uses bitflux:\n  {}\nDeal with that first, or uninstall --force.", blockers.join("\n  "))));
+        }
+    }
+    if agent.is_enabled() || agent.is_active() {
+        match agent.disable(true) {
+            Ok(()) => summary.removed.push(format!("service {} (stopped and disabled)", AGENT_PACKAGE)),
+            Err(e) => log::log(Level::Warn, &format!("Warning: failed to stop {}: {}", AGENT_PACKAGE, e)),
+        }
+    }
+    remove_module(&pm, &mut summary)?;
+    remove_params(&mut summary);
+    let mac = Mac::detect();
+    if mac.remove()? {
+        summary.removed.push(format!("the bitflux {} policy (unloaded)", mac.name()));
+    }
+    remove(Path::new(APPARMOR_PROFILE), &mut summary)?;
+    remove(Path::new(SYSCTL_PATH), &mut summary)?;
+    close_ports(&pm, &mut summary)?;
+
+    // The data is dealt with as asked even when the package went without it.
+    if data::has_data(Path::new("/")) {
+        data::pre_uninstall(policy)?;
+    }
+    if pm.installed_version(AGENT_PACKAGE).is_some() {
+        if !pm.remove(&[AGENT_PACKAGE]) {
+            return Err(io::Error::other(format!("Failed to remove {}.", AGENT_PACKAGE)));
+        }
+        summary.removed.push(format!("package {}", AGENT_PACKAGE));
+    }
+
+    let mut manifest = Manifest::load(MANIFEST_PATH)?;
+    if !runcmd::dry_run(&format!("remove the {} files listed in {}", manifest.entries.len(), MANIFEST_PATH)) {
+        let report = manifest.remove_files(false);
+        summary.removed.extend(report.removed);
+        for path in report.modified {
+            summary.kept.push(format!("{}, it changed since the install", path));
+        }
+        manifest.save(MANIFEST_PATH)?;
+    }
+
+    remove(Path::new(DROPIN_DIR), &mut summary)?;
+    remove(&Path::new(INIT_SCRIPT_DIR).join(AGENT_PACKAGE), &mut summary)?;
+    service::daemon_reload()?;
+    remove(Path::new(LICENSE_PATH), &mut summary)?;
+    remove(Path::new(DOWNLOAD_DIR), &mut summary)?;
+
+    // The data stays where the agent had it, unless it's to go.
+    let mut keep: Vec<&Path> = Vec::new();
+    if !matches!(policy, DataPolicy::Remove | DataPolicy::Backup) {
+        keep.extend(DATA_DIRS.iter().map(Path::new).filter(|d| d.exists()));
+    }
+    remove_except(Path::new(AGENT_DIR), &keep, &mut summary)?;
+
+    // State the installer still needs afterwards: how to get the old kernel back and, unless
+    // purged, the data backups and what was done with the data.  The journal and the progress
+    // of an install have nothing left to undo or resume.
+    let mut state = vec![Path::new(KERNEL_STATE_PATH)];
+    if !purge {
+        state.extend([Path::new(DATA_STATE_PATH)].into_iter().filter(|p| p.exists()));
+    }
+    remove(Path::new(JOURNAL_DIR), &mut summary)?;
+    remove(Path::new(PROGRESS_PATH), &mut summary)?;
+    if purge {
+        remove(Path::new(BACKUP_DIR), &mut summary)?;
+    }
+    if !manifest.entries.is_empty() {
+        // The files that were kept, for an uninstall after they're dealt with.
+        state.push(Path::new(MANIFEST_PATH));
+    }
+    remove_except(Path::new(RECEIPT_DIR), &state, &mut summary)?;
+
+    match purge {
+        true => {
+            remove(Path::new(CONFIG_DIR), &mut summary)?;
+            summary.removed.extend(account::remove()?);
+        }
+        false => {
+            if fs::read_dir(CONFIG_DIR).is_ok_and(|mut d| d.next().is_some()) {
+                summary.kept.push(format!("{}, --purge removes it", CONFIG_DIR));
+            }
+        }
+    }
+    summary.kept.extend(keep.iter().map(|d| format!("{}, the agent data, --purge removes it", d.display())));
+
+    output::print(&summary.render())?;
+    Ok(())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_only_what_is_asked() {
+        let dir = std::env::temp_dir().join(format!("bitflux-uninstall-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(dir.join("config")).unwrap();
+        fs::create_dir_all(dir.join("data/agent")).unwrap();
+        fs::write(dir.join("config/bitfluxcollector.conf"), "deviceid=web1\n").unwrap();
+        fs::write(dir.join("bin"), "").unwrap();
+
+        let data = dir.join("data");
+        let mut summary = Summary::default();
+        remove_except(&dir, &[data.as_path()], &mut summary).unwrap();
+        assert!(data.join("agent").is_dir());
+        assert!(!dir.join("config").exists() && !dir.join("bin").exists());
+        assert_eq!(summary.removed.len(), 2);
+
+        remove_except(&dir, &[], &mut summary).unwrap();
+        assert!(!dir.exists());
+        assert!(summary.render().starts_with("Removed:\n  "));
+        assert_eq!(Summary::default().render(), "bitflux isn't installed, nothing removed.\n");
+    }
+
+    #[test]
+    fn what_still_uses_bitflux() {
+        let modules = "swaphints 16384 2 zswap_hints, Live 0xffffffffc0a00000 (OE)\nvfat 20480 1 - Live 0xffffffffc0900000\n";
+        assert_eq!(module_users(modules, "swaphints"), Some((2, vec![String::from("zswap_hints")])));
+        assert_eq!(module_users(modules, "vfat"), Some((1, vec![])));
+        assert_eq!(module_users(modules, "ext4"), None);
+
+        let listing = "bitfluxcollector.service\n  backup.service\n  multi-user.target\n    graphical.target\n";
+        assert_eq!(dependents(listing), ["backup.service"]);
+        assert!(dependents("bitfluxcollector.service\n  multi-user.target\n").is_empty());
+
+        let mounts = "/dev/sda1 / ext4 rw 0 0\n/dev/sdb1 /opt/bitflux/data\\040b ext4 rw 0 0\n/dev/sdc1 /opt/bitfluxer xfs rw 0 0\n";
+        assert_eq!(mounts_under(mounts, &[AGENT_DIR]), ["/opt/bitflux/data b"]);
+    }
+
+}
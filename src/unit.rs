@@ -0,0 +1,162 @@
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::account::AGENT_USER;
+use crate::mac::AGENT_BINARY;
+use crate::perms::{FileKind, PermissionRecord, AGENT_GROUP};
+use crate::pkg::AGENT_PACKAGE;
+use crate::platform::Init;
+use crate::profile::Profile;
+use crate::service::{self, INIT_SCRIPT_DIR};
+use crate::template;
+
+/// Drop-in directory for the agent's service, the packaged unit itself is never edited.
+pub const DROPIN_DIR: &str = "/etc/systemd/system/bitfluxcollector.service.d";
+pub const HARDENING_DROPIN: &str = "hardening.conf";
+
+/// Sandboxing directives for the agent service under `profile`.
+pub fn hardening(profile: Profile) -> Vec<(&'static str, String)> {
+    // Reading /proc/kpageflags and other processes' pagemap needs SYS_ADMIN and SYS_PTRACE,
+    // DAC_READ_SEARCH lets it walk /proc/<pid> of every user.
+    let caps = ["CAP_SYS_ADMIN", "CAP_SYS_PTRACE", "CAP_DAC_READ_SEARCH"];
+
+    let mut directives = vec![
+        // The capabilities stand in for root, see account.
+        ("User", String::from(AGENT_USER)),
+        ("Group", String::from(AGENT_GROUP)),
+        ("NoNewPrivileges", String::from("yes")),
+        ("CapabilityBoundingSet", caps.join(" ")),
+        ("AmbientCapabilities", caps.join(" ")),
+        ("ProtectSystem", String::from(if profile == Profile::Debug { "full" } else { "strict" })),
+        ("ReadWritePaths", String::from("/opt/bitflux")),
+        ("ProtectHome", String::from("read-only")),
+        ("PrivateTmp", String::from("yes")),
+        ("PrivateDevices", String::from("yes")),
+        // swaphints is tuned through /sys/module/swaphints/parameters.
+        ("ProtectKernelTunables", String::from(if profile.kernel() { "no" } else { "yes" })),
+        ("ProtectKernelModules", String::from("yes")),
+        ("ProtectControlGroups", String::from("yes")),
+        ("ProtectClock", String::from("yes")),
+        ("ProtectHostname", String::from("yes")),
+        ("RestrictAddressFamilies", String::from("AF_UNIX AF_INET AF_INET6")),
+        ("RestrictNamespaces", String::from("yes")),
+        ("RestrictRealtime", String::from("yes")),
+        ("RestrictSUIDSGID", String::from("yes")),
+        ("LockPersonality", String::from("yes")),
+        ("SystemCallArchitectures", String::from("native")),
+    ];
+    if profile == Profile::Debug {
+        directives.push(("LimitCORE", String::from("infinity")));
+    } else {
+        directives.push(("MemoryDenyWriteExecute", String::from("yes")));
+    }
+    directives
+}
+
+/// The hardening drop-in for `profile`.
+pub fn render(profile: Profile) -> String {
+    let mut unit = format!("# Generated by the bitflux installer for the {} profile, do not edit.\n[Service]\n", profile.name());
+    for (key, value) in hardening(profile) {
+        unit.push_str(&format!("{}={}\n", key, value));
+    }
+    unit
+}
+
+/// The OpenRC script for the agent.  It gets the capabilities of the systemd unit, which takes
+/// OpenRC 0.45 or later.
+pub fn openrc_script(profile: Profile) -> String {
+    let caps = ["cap_sys_admin", "cap_sys_ptrace", "cap_dac_read_search"].map(|c| format!("^{}", c)).join(",");
+    format!(
+        "#!/sbin/openrc-run\n# Generated by the bitflux installer for the {} profile, do not edit.\n\
+         description=\"bitflux agent\"\ncommand=\"{}\"\ncommand_user=\"{}:{}\"\ncommand_background=true\n\
+         capabilities=\"{}\"\npidfile=\"/run/{}.pid\"\n\ndepend() {{\n\tneed net\n}}\n",
+        profile.name(), AGENT_BINARY, AGENT_USER, AGENT_GROUP, caps, AGENT_PACKAGE
+    )
+}
+
+/// The SysV init script for the agent.  start-stop-daemon can't hand the account the
+/// capabilities, so the agent runs as root under SysV init.
+pub fn sysv_script(profile: Profile) -> String {
+    format!(
+        "#!/bin/sh\n# Generated by the bitflux installer for the {profile} profile, do not edit.\n\
+         ### BEGIN INIT INFO\n# Provides: {name}\n# Required-Start: $network $remote_fs\n\
+         # Required-Stop: $network $remote_fs\n# Default-Start: 2 3 4 5\n# Default-Stop: 0 1 6\n\
+         # Short-Description: bitflux agent\n### END INIT INFO\n\n\
+         PIDFILE=/run/{name}.pid\nDAEMON={binary}\n\n\
+         case \"$1\" in\n\
+         start) start-stop-daemon --start --quiet --background --make-pidfile --pidfile \"$PIDFILE\" --exec \"$DAEMON\" ;;\n\
+         stop) start-stop-daemon --stop --quiet --retry 10 --pidfile \"$PIDFILE\" && rm -f \"$PIDFILE\" ;;\n\
+         restart) \"$0\" stop; \"$0\" start ;;\n\
+         status) start-stop-daemon --status --pidfile \"$PIDFILE\" ;;\n\
+         *) echo \"Usage: $0 {{start|stop|restart|status}}\" >&2; exit 3 ;;\n\
+         esac\n",
+        profile = profile.name(), name = AGENT_PACKAGE, binary = AGENT_BINARY
+    )
+}
+
+/// Where the agent's service definition goes under `init`: the hardening drop-in under
+/// systemd, the packaged unit is left alone, and an init script elsewhere, the package ships
+/// none for OpenRC or SysV init.
+pub fn path(init: Option<Init>) -> PathBuf {
+    match init {
+        Some(Init::OpenRc | Init::SysV) => Path::new(INIT_SCRIPT_DIR).join(AGENT_PACKAGE),
+        Some(Init::Systemd) | None => Path::new(DROPIN_DIR).join(HARDENING_DROPIN),
+    }
+}
+
+/// What goes at path() under `init` for `profile`.
+pub fn definition(init: Option<Init>, profile: Profile) -> String {
+    match init {
+        Some(Init::OpenRc) => openrc_script(profile),
+        Some(Init::SysV) => sysv_script(profile),
+        Some(Init::Systemd) | None => render(profile),
+    }
+}
+
+/// Writes the agent's service definition under `init`, reloading systemd after the drop-in.
+pub fn install(init: Option<Init>, profile: Profile) -> io::Result<(PathBuf, PermissionRecord)> {
+    let path = path(init);
+    match init {
+        Some(Init::OpenRc | Init::SysV) => template::write(&path, &definition(init, profile), FileKind::Binary).map(|written| (path, written.record)),
+        Some(Init::Systemd) | None => service::write_unit_file(&path, &render(profile)),
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sandbox_per_profile() {
+        let agent = render(Profile::Agent);
+        assert!(agent.starts_with("# Generated by the bitflux installer for the agent profile"));
+        assert!(agent.contains("\n[Service]\nUser=bitflux\nGroup=bitflux\nNoNewPrivileges=yes\n"));
+        assert!(agent.contains("ProtectSystem=strict\n"));
+        assert!(agent.contains("ProtectKernelTunables=yes\n"));
+        assert!(agent.contains("CapabilityBoundingSet=CAP_SYS_ADMIN CAP_SYS_PTRACE CAP_DAC_READ_SEARCH\n"));
+
+        let kernel = render(Profile::AgentKernel);
+        assert!(kernel.contains("ProtectKernelTunables=no\n"));
+        assert!(kernel.contains("MemoryDenyWriteExecute=yes\n"));
+
+        let debug = render(Profile::Debug);
+        assert!(debug.contains("ProtectSystem=full\n"));
+        assert!(debug.contains("LimitCORE=infinity\n"));
+        assert!(!debug.contains("MemoryDenyWriteExecute"));
+    }
+
+    #[test]
+    fn script_per_init_system() {
+        assert_eq!(path(None), Path::new("/etc/systemd/system/bitfluxcollector.service.d/hardening.conf"));
+        assert_eq!(path(Some(Init::OpenRc)), Path::new("/etc/init.d/bitfluxcollector"));
+        let openrc = definition(Some(Init::OpenRc), Profile::Agent);
+        assert!(openrc.starts_with("#!/sbin/openrc-run\n"));
+        assert!(openrc.contains("command=\"/opt/bitflux/bin/bitfluxcollector\"\ncommand_user=\"bitflux:bitflux\"\n"));
+        let sysv = definition(Some(Init::SysV), Profile::Agent);
+        assert!(sysv.contains("# Provides: bitfluxcollector\n"));
+        assert!(sysv.contains("DAEMON=/opt/bitflux/bin/bitfluxcollector\n"));
+        assert!(sysv.ends_with("esac\n"));
+    }
+
+}
@@ -0,0 +1,159 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::Serialize;
+
+use crate::checksum::sha256_file;
+use crate::output;
+use crate::perms;
+use crate::pkg;
+use crate::receipt::{Receipt, RECEIPT_DIR};
+use crate::selinux;
+use crate::service::Service;
+
+/// One way the system no longer matches the install receipt.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Drift {
+    /// "file", "package", "package_file", "service", "module_param", "permissions" or "selinux".
+    pub kind: String,
+    pub item: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct VerifyReport {
+    pub checked: usize,
+    pub drift: Vec<Drift>,
+}
+
+impl VerifyReport {
+
+    fn check(&mut self, kind: &str, item: &str, expected: &str, actual: &str) {
+        self.checked += 1;
+        if expected != actual {
+            self.drift.push(Drift {
+                kind: String::from(kind),
+                item: String::from(item),
+                expected: String::from(expected),
+                actual: String::from(actual),
+            });
+        }
+    }
+
+    pub fn print(&self) -> io::Result<()> {
+        let mut out = String::new();
+        for d in &self.drift {
+            out.push_str(&format!("DRIFT {} '{}': expected '{}', found '{}'\n", d.kind, d.item, d.expected, d.actual));
+        }
+        out.push_str(&format!("Checked {} items, {} drifted.\n", self.checked, self.drift.len()));
+        output::print(&out)
+    }
+
+}
+
+const MISSING: &str = "missing";
+
+/// Compares the live system against `receipt`.
+pub fn verify(receipt: &Receipt) -> VerifyReport {
+    let mut report = VerifyReport::default();
+
+    for file in &receipt.files {
+        let actual = sha256_file(&file.path).unwrap_or_else(|_| String::from(MISSING));
+        report.check("file", &file.path, &file.sha256, &actual);
+    }
+
+    for package in &receipt.packages {
+        let actual = pkg::installed_version(&package.name).unwrap_or_else(|| String::from(MISSING));
+        report.check("package", &package.name, &package.version, &actual);
+    }
+
+    for file in &receipt.package_files {
+        let actual = sha256_file(&file.path).unwrap_or_else(|_| String::from(MISSING));
+        report.check("package_file", &file.path, &file.sha256, &actual);
+    }
+
+    for service in &receipt.services {
+        let enabled = Service::new(&service.name).is_enabled();
+        report.check("service", &service.name, enabled_str(service.enabled), enabled_str(enabled));
+    }
+
+    for param in &receipt.module_params {
+        let actual = module_param(&param.module, &param.param).unwrap_or_else(|| String::from(MISSING));
+        report.check("module_param", &format!("{}.{}", param.module, param.param), &param.value, &actual);
+    }
+
+    for record in &receipt.permissions {
+        let actual = perms::actual(&record.path).unwrap_or_else(|| String::from(MISSING));
+        report.check("permissions", &record.path, &record.expected(), &actual);
+        if let Some(expected) = &record.selinux_type {
+            let actual = selinux::actual_type(&record.path).unwrap_or_else(|| String::from(MISSING));
+            report.check("selinux", &record.path, expected, &actual);
+        }
+    }
+
+    report
+}
+
+/// Verifies the system against the receipt written at install time.
+pub fn run(json: bool) -> io::Result<()> {
+    let receipt = Receipt::load_verified(RECEIPT_DIR)?;
+    let report = verify(&receipt);
+
+    if json {
+        output::json(&report)?;
+    } else {
+        report.print()?;
+    }
+
+    if !report.drift.is_empty() {
+        return Err(io::Error::other(format!("{} items differ from the install receipt.", report.drift.len())));
+    }
+    Ok(())
+}
+
+fn enabled_str(enabled: bool) -> &'static str {
+    if enabled { "enabled" } else { "disabled" }
+}
+
+/// Current value of a loaded module's parameter from sysfs.
+pub fn module_param(module: &str, param: &str) -> Option<String> {
+    let path = Path::new("/sys/module").join(module).join("parameters").join(param);
+    fs::read_to_string(path).ok().map(|v| v.trim().to_string())
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::manifest::ManifestEntry;
+    use crate::receipt::ModuleParamRecord;
+
+    #[test]
+    fn detects_file_drift() {
+        let path = std::env::temp_dir().join(format!("bitflux-verify-{}", std::process::id()));
+        fs::write(&path, "ok").unwrap();
+        let path_str = path.to_string_lossy().into_owned();
+
+        let mut receipt = Receipt::new();
+        receipt.files.push(ManifestEntry { path: path_str.clone(), sha256: sha256_file(&path).unwrap(), step: String::from("t") });
+        receipt.files.push(ManifestEntry { path: String::from("/nonexistent/bitflux"), sha256: String::from("00"), step: String::from("t") });
+        receipt.module_params.push(ModuleParamRecord {
+            module: String::from("bitflux_no_such_module"),
+            param: String::from("p"),
+            value: String::from("1"),
+        });
+
+        let report = verify(&receipt);
+        assert_eq!(report.checked, 3);
+        assert_eq!(report.drift.len(), 2);
+        assert_eq!(report.drift[0].item, "/nonexistent/bitflux");
+        assert_eq!(report.drift[0].actual, MISSING);
+
+        fs::write(&path, "changed").unwrap();
+        assert_eq!(verify(&receipt).drift.len(), 3);
+        fs::remove_file(&path).unwrap();
+    }
+
+}
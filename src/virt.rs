@@ -0,0 +1,121 @@
+use std::fs;
+
+use crate::log::{self, Level};
+use crate::profile::Profile;
+use crate::runcmd::{which, RunCmd};
+use crate::wsl;
+
+/// Set by systemd-nspawn, LXC and podman in the container, naming the manager.
+pub const SYSTEMD_CONTAINER: &str = "/run/systemd/container";
+/// The marker files docker and podman leave in every container.
+pub const CONTAINER_MARKERS: &[(&str, &str)] = &[("/.dockerenv", "docker"), ("/run/.containerenv", "podman")];
+
+/// Where the installer runs, as far as it changes what can be installed.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Virt {
+    BareMetal,
+    /// A virtual machine, named the way systemd-detect-virt names the hypervisor: "kvm", "vmware".
+    Vm(String),
+    /// A container sharing the host's kernel: "docker", "podman", "lxc".
+    Container(String),
+    /// WSL2, a VM whose kernel comes from Windows.
+    Wsl,
+}
+
+impl Virt {
+
+    /// From what systemd-detect-virt reports for `--container` and `--vm`, and whether the
+    /// kernel is WSL2's.
+    pub fn from_names(container: Option<&str>, vm: Option<&str>, wsl: bool) -> Virt {
+        match (container, vm) {
+            _ if wsl => Virt::Wsl,
+            (Some("wsl"), _) => Virt::Wsl,
+            (Some(container), _) => Virt::Container(String::from(container)),
+            (None, Some(vm)) => Virt::Vm(String::from(vm)),
+            (None, None) => Virt::BareMetal,
+        }
+    }
+
+    /// This host, by systemd-detect-virt, or the container markers and the CPU's hypervisor
+    /// flag where it's missing, as in most container images.
+    pub fn detect() -> Virt {
+        let wsl = wsl::is_wsl2();
+        if which("systemd-detect-virt").is_some() {
+            return Virt::from_names(detect_virt("--container").as_deref(), detect_virt("--vm").as_deref(), wsl);
+        }
+        let container = fs::read_to_string(SYSTEMD_CONTAINER).ok().map(|c| String::from(c.trim())).filter(|c| !c.is_empty())
+            .or_else(|| CONTAINER_MARKERS.iter().find(|(p, _)| fs::metadata(p).is_ok()).map(|(_, name)| String::from(*name)));
+        let hypervisor = fs::read_to_string("/proc/cpuinfo").is_ok_and(|c| c.lines().any(|l| l.starts_with("flags") && l.contains(" hypervisor")));
+        Virt::from_names(container.as_deref(), hypervisor.then_some("unknown"), wsl)
+    }
+
+    pub fn is_container(&self) -> bool {
+        matches!(self, Virt::Container(_))
+    }
+
+    pub fn describe(&self) -> String {
+        match self {
+            Virt::BareMetal => String::from("bare metal"),
+            Virt::Vm(vm) => format!("{} virtual machine", vm),
+            Virt::Container(container) => format!("{} container", container),
+            Virt::Wsl => String::from("WSL2"),
+        }
+    }
+
+    /// The profile to actually install: the agent only where the kernel isn't the distro's to
+    /// replace, a container's being the host's and WSL2's Windows'.
+    pub fn effective_profile(&self, requested: Profile) -> Profile {
+        if !requested.kernel() {
+            return requested;
+        }
+        match self {
+            Virt::Container(container) => {
+                log::log(Level::Info, &format!("Running in a {} container, which shares the host's kernel: installing the agent only. \
+                          Install the bitflux kernel on the host.", container));
+                Profile::Agent
+            }
+            Virt::Wsl => {
+                log::log(Level::Info, &format!("Running under WSL2, installing the agent only.\n{}", wsl::KERNEL_HELP));
+                Profile::Agent
+            }
+            Virt::BareMetal | Virt::Vm(_) => requested,
+        }
+    }
+
+    /// What to do about systemd not running here, for the error refusing to install.
+    pub fn systemd_help(&self) -> Option<&'static str> {
+        match self {
+            Virt::Container(_) => Some("Run the container with systemd as its init (podman run --systemd=always, or LXC's default), or install bitflux on the host."),
+            Virt::Wsl => Some("Turn systemd on in /etc/wsl.conf with \"[boot]\" and \"systemd=true\", then run `wsl --shutdown` from Windows."),
+            Virt::BareMetal | Virt::Vm(_) => None,
+        }
+    }
+
+}
+
+fn detect_virt(kind: &str) -> Option<String> {
+    let out = RunCmd::args("systemd-detect-virt", &[kind]).execute_output();
+    let name = out.stdout.trim();
+    match out.exitcode == 0 && !name.is_empty() && name != "none" {
+        true => Some(name.to_string()),
+        false => None,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn environments() {
+        assert_eq!(Virt::from_names(Some("docker"), None, false), Virt::Container(String::from("docker")));
+        assert_eq!(Virt::from_names(Some("lxc"), Some("kvm"), false), Virt::Container(String::from("lxc")));
+        assert_eq!(Virt::from_names(Some("wsl"), None, false), Virt::Wsl);
+        assert_eq!(Virt::from_names(None, Some("microsoft"), true), Virt::Wsl);
+        assert_eq!(Virt::from_names(None, Some("kvm"), false).describe(), "kvm virtual machine");
+        assert_eq!(Virt::Container(String::from("podman")).effective_profile(Profile::AgentKernel), Profile::Agent);
+        assert_eq!(Virt::Vm(String::from("kvm")).effective_profile(Profile::AgentKernel), Profile::AgentKernel);
+    }
+
+}
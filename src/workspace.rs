@@ -0,0 +1,216 @@
+use std::env;
+use std::fs::{self, DirBuilder, File};
+use std::io::{self, Read};
+use std::os::unix::fs::{chown, DirBuilderExt, MetadataExt};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::checksum::to_hex;
+
+/// Where per-run scratch directories are created.  /var/tmp rather than /tmp since /tmp is
+/// often a small tmpfs and package files can be large.
+pub const WORKSPACE_ROOT: &str = "/var/tmp";
+
+/// The pid of the run a workspace belongs to, in the workspace, for prune().
+const OWNER_FILE: &str = ".pid";
+/// A workspace without an owner yet is left alone this long, it may be being created.
+const UNOWNED_GRACE: Duration = Duration::from_secs(3600);
+
+/// Workspaces that still exist, so `cleanup()` can remove them on paths that skip Drop.
+static LIVE: Mutex<Vec<PathBuf>> = Mutex::new(Vec::new());
+/// The run's workspace, the one export() was called on.
+static CURRENT: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// A private scratch directory, mode 0700 with an unpredictable name, removed when dropped.
+#[derive(Debug)]
+pub struct Workspace {
+    path: PathBuf,
+}
+
+impl Workspace {
+
+    /// Creates a new workspace in the run's, so it goes with the run's at the latest, or under
+    /// WORKSPACE_ROOT when there's none.  One handed to another account with chown() has to
+    /// be created under WORKSPACE_ROOT, the run's only lets root in.
+    pub fn create() -> io::Result<Workspace> {
+        Workspace::create_from(current())
+    }
+
+    fn create_from(run: Option<PathBuf>) -> io::Result<Workspace> {
+        if let Some(run) = run {
+            match Workspace::create_in(&run) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                created => return created,
+            }
+        }
+        Workspace::create_in(WORKSPACE_ROOT)
+    }
+
+    /// Creates a new workspace under `root`.  The directory is created exclusively so an existing
+    /// file or symlink planted at the name is never reused.
+    pub fn create_in<P: AsRef<Path>>(root: P) -> io::Result<Workspace> {
+        for _ in 0..8 {
+            let path = root.as_ref().join(format!("bitflux-{}", random_name()?));
+            match DirBuilder::new().mode(0o700).create(&path) {
+                Ok(()) => {
+                    LIVE.lock().unwrap_or_else(|e| e.into_inner()).push(path.clone());
+                    let ws = Workspace { path };
+                    fs::write(ws.join(OWNER_FILE), process::id().to_string())?;
+                    return Ok(ws);
+                }
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Err(io::Error::other(format!("Failed to create a workspace in '{}'", root.as_ref().display())))
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    pub fn join<P: AsRef<Path>>(&self, name: P) -> PathBuf {
+        self.path.join(name)
+    }
+
+    /// Hands the workspace to another account, used for steps that run unprivileged.
+    pub fn chown(&self, uid: u32, gid: u32) -> io::Result<()> {
+        chown(&self.path, Some(uid), Some(gid))
+    }
+
+    /// Makes this the run's workspace: create() and dir() put what they make in it, and every
+    /// child process started from now on gets it as TMPDIR.
+    pub fn export(&self) {
+        *CURRENT.lock().unwrap_or_else(|e| e.into_inner()) = Some(self.path.clone());
+        env::set_var("TMPDIR", &self.path);
+    }
+
+}
+
+impl Drop for Workspace {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.path);
+        LIVE.lock().unwrap_or_else(|e| e.into_inner()).retain(|p| *p != self.path);
+        let mut current = CURRENT.lock().unwrap_or_else(|e| e.into_inner());
+        if current.as_ref() == Some(&self.path) {
+            *current = None;
+        }
+    }
+}
+
+/// The run's workspace, None before one was exported.
+pub fn current() -> Option<PathBuf> {
+    CURRENT.lock().unwrap_or_else(|e| e.into_inner()).clone()
+}
+
+/// The directory `name` in the run's workspace, created 0700 the first time, for a step's
+/// downloads, unpacked files and build artifacts.  Removed with the run's workspace.
+pub fn dir(name: &str) -> io::Result<PathBuf> {
+    dir_in(&current().ok_or_else(|| io::Error::other("No workspace for this run."))?, name)
+}
+
+fn dir_in(run: &Path, name: &str) -> io::Result<PathBuf> {
+    let path = run.join(name);
+    DirBuilder::new().mode(0o700).recursive(true).create(&path)?;
+    Ok(path)
+}
+
+/// Removes the workspaces under `root` of runs that ended without cleaning up, killed or
+/// interrupted twice.  The ones of runs still going, this one's too, are left alone.
+pub fn prune_in<P: AsRef<Path>>(root: P) {
+    let Ok(entries) = fs::read_dir(root) else {
+        return;
+    };
+    let uid = unsafe { libc::geteuid() };
+    for entry in entries.flatten() {
+        let name = entry.file_name();
+        let Some(id) = name.to_str().and_then(|n| n.strip_prefix("bitflux-")) else {
+            continue;
+        };
+        let path = entry.path();
+        // Only directories of ours named the way create_in() names them, never a symlink.
+        let ours = fs::symlink_metadata(&path).is_ok_and(|m| m.is_dir() && m.uid() == uid);
+        if id.len() != 32 || !id.chars().all(|c| c.is_ascii_hexdigit()) || !ours {
+            continue;
+        }
+        let alive = match fs::read_to_string(path.join(OWNER_FILE)).ok().and_then(|p| p.trim().parse::<u32>().ok()) {
+            Some(pid) => Path::new(&format!("/proc/{}", pid)).exists(),
+            None => fs::metadata(&path).and_then(|m| m.modified()).is_ok_and(|t| t.elapsed().unwrap_or_default() < UNOWNED_GRACE),
+        };
+        if !alive {
+            let _ = fs::remove_dir_all(&path);
+        }
+    }
+}
+
+/// Removes every workspace that is still around, for exits that don't unwind.
+pub fn cleanup() {
+    for path in LIVE.lock().unwrap_or_else(|e| e.into_inner()).drain(..) {
+        let _ = fs::remove_dir_all(path);
+    }
+}
+
+fn random_name() -> io::Result<String> {
+    let mut bytes = [0u8; 16];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(to_hex(&bytes))
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn private_and_removed_on_drop() {
+        let a = Workspace::create_in(env::temp_dir()).unwrap();
+        let b = Workspace::create_in(env::temp_dir()).unwrap();
+        assert_ne!(a.path(), b.path());
+        assert_eq!(fs::metadata(a.path()).unwrap().permissions().mode() & 0o777, 0o700);
+
+        fs::write(a.join("download"), "data").unwrap();
+        let path = a.path().to_path_buf();
+        drop(a);
+        assert!(!path.exists());
+        assert!(b.path().exists());
+    }
+
+    #[test]
+    fn removed_on_panic() {
+        let path = std::panic::catch_unwind(|| {
+            let ws = Workspace::create_in(env::temp_dir()).unwrap();
+            let path = ws.path().to_path_buf();
+            std::panic::panic_any(path);
+        }).unwrap_err();
+        assert!(!path.downcast_ref::<PathBuf>().unwrap().exists());
+    }
+
+    #[test]
+    fn nested_in_the_run_and_stale_pruned() {
+        let root = env::temp_dir().join(format!("bitflux-workspaces-{}", process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let run = Workspace::create_in(&root).unwrap();
+        let step = Workspace::create_from(Some(run.path().to_path_buf())).unwrap();
+        assert_eq!(step.path().parent(), Some(run.path()));
+        let downloads = dir_in(run.path(), "downloads").unwrap();
+        assert_eq!(downloads, run.join("downloads"));
+        assert_eq!(fs::metadata(&downloads).unwrap().permissions().mode() & 0o777, 0o700);
+
+        let dead = root.join(format!("bitflux-{}", "0".repeat(32)));
+        fs::create_dir(&dead).unwrap();
+        fs::write(dead.join(OWNER_FILE), "4194305").unwrap();
+        let young = root.join(format!("bitflux-{}", "1".repeat(32)));
+        fs::create_dir(&young).unwrap();
+        prune_in(&root);
+        assert!(!dead.exists());
+        assert!(young.exists() && run.path().exists());
+
+        drop(run);
+        assert!(!step.path().exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+}
@@ -0,0 +1,206 @@
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::log::{self, Level};
+use crate::runcmd::RunCmd;
+
+/// Filesystems that are read-only by construction, remounting them rw can't work.
+const NEVER_WRITABLE: &[&str] = &["squashfs", "iso9660", "erofs", "cramfs"];
+
+/// Set by --unlock, the user agreed to immutable attributes being cleared and read-only
+/// filesystems being remounted rw for the duration of a write.
+static UNLOCK: AtomicBool = AtomicBool::new(false);
+
+pub fn allow_unlock(allow: bool) {
+    UNLOCK.store(allow, Ordering::Relaxed);
+}
+
+/// Why a target path can't be written as is.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Blocker {
+    /// chattr +i
+    Immutable(PathBuf),
+    /// chattr +a
+    AppendOnly(PathBuf),
+    ReadOnlyMount { mount: String, fstype: String },
+}
+
+impl fmt::Display for Blocker {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Blocker::Immutable(p) => write!(f, "'{}' has the immutable attribute set (chattr +i)", p.display()),
+            Blocker::AppendOnly(p) => write!(f, "'{}' has the append-only attribute set (chattr +a)", p.display()),
+            Blocker::ReadOnlyMount { mount, fstype } => write!(f, "'{}' is a read-only {} mount", mount, fstype),
+        }
+    }
+}
+
+/// Mount point, filesystem type and whether it is mounted read-only, for the mount holding `path`,
+/// from /proc/self/mountinfo formatted `data`.
+pub fn mount_of(data: &str, path: &Path) -> Option<(String, String, bool)> {
+    data.lines()
+        .filter_map(|line| {
+            let (mount, fs) = line.split_once(" - ")?;
+            let fields: Vec<&str> = mount.split(' ').collect();
+            let point = unescape(fields.get(4)?);
+            let ro = fields.get(5)?.split(',').any(|o| o == "ro");
+            let fstype = fs.split(' ').next()?.to_string();
+            Some((point, fstype, ro))
+        })
+        .filter(|(point, _, _)| path.starts_with(point))
+        // Later lines are mounted on top of earlier ones, so the last longest match wins.
+        .fold(None, |best: Option<(String, String, bool)>, m| match &best {
+            Some(b) if b.0.len() > m.0.len() => best,
+            _ => Some(m),
+        })
+}
+
+/// mountinfo escapes space, tab, newline and backslash as octal.
+fn unescape(field: &str) -> String {
+    field.replace("\\040", " ").replace("\\011", "\t").replace("\\012", "\n").replace("\\134", "\\")
+}
+
+/// Immutable and append-only flags from one line of `lsattr -d` output.
+pub fn parse_attrs(line: &str) -> (bool, bool) {
+    let flags = line.split_whitespace().next().unwrap_or("");
+    (flags.contains('i'), flags.contains('a'))
+}
+
+fn attrs(path: &Path) -> (bool, bool) {
+    let out = RunCmd::args("lsattr", &["-d", &path.to_string_lossy()]).execute_output();
+    if out.exitcode != 0 {
+        return (false, false);
+    }
+    parse_attrs(&out.stdout)
+}
+
+/// Everything that would stop `path` from being created or replaced.
+pub fn blockers(path: &Path) -> Vec<Blocker> {
+    let mut found = Vec::new();
+    // Replacing a file needs both the file and its directory to be writable.
+    for p in [Some(path), path.parent()].into_iter().flatten().filter(|p| p.exists()) {
+        let (immutable, append) = attrs(p);
+        if immutable {
+            found.push(Blocker::Immutable(p.to_path_buf()));
+        }
+        if append {
+            found.push(Blocker::AppendOnly(p.to_path_buf()));
+        }
+    }
+
+    let existing = path.ancestors().find(|p| p.exists()).unwrap_or(Path::new("/"));
+    let mountinfo = fs::read_to_string("/proc/self/mountinfo").unwrap_or_default();
+    if let Some((mount, fstype, true)) = mount_of(&mountinfo, existing) {
+        found.push(Blocker::ReadOnlyMount { mount, fstype });
+    }
+    found
+}
+
+/// Undoes what `prepare` unlocked when dropped: attributes go back on, mounts go back to ro.
+#[derive(Default)]
+pub struct Unlocked {
+    relock: Vec<RunCmd>,
+}
+
+impl Drop for Unlocked {
+    fn drop(&mut self) {
+        for cmd in self.relock.iter_mut().rev() {
+            let out = cmd.execute_output();
+            if out.exitcode != 0 {
+                log::log(Level::Warn, &format!("Failed to restore '{}': {}", out.cmd, out.stderr.trim()));
+            }
+        }
+    }
+}
+
+fn run(unlocked: &mut Unlocked, blocker: &Blocker, mut unlock: RunCmd, relock: RunCmd) -> io::Result<()> {
+    let out = unlock.execute_output();
+    if out.exitcode != 0 {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                                  format!("Can't lift the write block, {} and '{}' failed: {}", blocker, out.cmd, out.stderr.trim())));
+    }
+    unlocked.relock.push(relock);
+    Ok(())
+}
+
+/// Makes sure `path` can be written before a step touches it.  Without --unlock a blocked path fails
+/// with a precise explanation instead of an EPERM halfway through the step, with it the blockers are
+/// lifted until the returned guard is dropped.
+pub fn prepare<P: AsRef<Path>>(path: P) -> io::Result<Unlocked> {
+    let path = path.as_ref();
+    let found = blockers(path);
+    let mut unlocked = Unlocked::default();
+    if found.is_empty() {
+        return Ok(unlocked);
+    }
+
+    if !UNLOCK.load(Ordering::Relaxed) {
+        let reasons: Vec<String> = found.iter().map(|b| b.to_string()).collect();
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                                  format!("Can't write '{}': {}.  Rerun with --unlock to lift this temporarily.", path.display(), reasons.join(", "))));
+    }
+
+    for blocker in &found {
+        match blocker {
+            Blocker::Immutable(p) | Blocker::AppendOnly(p) => {
+                let flag = if matches!(blocker, Blocker::Immutable(_)) { "i" } else { "a" };
+                let p = p.to_string_lossy();
+                run(&mut unlocked, blocker,
+                    RunCmd::args("chattr", &[&format!("-{}", flag), &p]),
+                    RunCmd::args("chattr", &[&format!("+{}", flag), &p]))?;
+            }
+            Blocker::ReadOnlyMount { mount, fstype } => {
+                if NEVER_WRITABLE.contains(&fstype.as_str()) {
+                    return Err(io::Error::new(io::ErrorKind::PermissionDenied,
+                                              format!("Can't write '{}', {} and {} filesystems can't be remounted read-write.", path.display(), blocker, fstype)));
+                }
+                run(&mut unlocked, blocker,
+                    RunCmd::args("mount", &["-o", "remount,rw", mount]),
+                    RunCmd::args("mount", &["-o", "remount,ro", mount]))?;
+            }
+        }
+    }
+    Ok(unlocked)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MOUNTINFO: &str = "\
+22 1 8:1 / / rw,relatime shared:1 - ext4 /dev/sda1 rw
+30 22 0:26 / /usr ro,relatime shared:2 - squashfs /dev/loop0 ro
+31 22 8:2 / /opt/bit\\040flux rw,relatime shared:3 - xfs /dev/sda2 rw
+32 31 0:27 / /opt/bit\\040flux/ro ro,relatime - overlay overlay ro,lowerdir=/a
+";
+
+    #[test]
+    fn finds_mount_for_path() {
+        assert_eq!(mount_of(MOUNTINFO, Path::new("/etc/default/grub")), Some((String::from("/"), String::from("ext4"), false)));
+        assert_eq!(mount_of(MOUNTINFO, Path::new("/usr/bin/installer")), Some((String::from("/usr"), String::from("squashfs"), true)));
+        assert_eq!(mount_of(MOUNTINFO, Path::new("/opt/bit flux/config")), Some((String::from("/opt/bit flux"), String::from("xfs"), false)));
+        assert_eq!(mount_of(MOUNTINFO, Path::new("/opt/bit flux/ro/x")), Some((String::from("/opt/bit flux/ro"), String::from("overlay"), true)));
+        // Path components, not string prefixes.
+        assert_eq!(mount_of(MOUNTINFO, Path::new("/usrlocal")).unwrap().0, "/");
+    }
+
+    #[test]
+    fn lsattr_flags() {
+        assert_eq!(parse_attrs("----i---------e------- /etc/bitflux.conf"), (true, false));
+        assert_eq!(parse_attrs("-----a--------e------- /var/log/bitflux.log"), (false, true));
+        assert_eq!(parse_attrs("--------------e------- /etc"), (false, false));
+    }
+
+    #[test]
+    fn writable_path_needs_nothing() {
+        let dir = std::env::temp_dir();
+        if blockers(&dir).is_empty() {
+            assert!(prepare(dir.join("bitflux-writable")).unwrap().relock.is_empty());
+        }
+    }
+
+}
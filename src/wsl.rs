@@ -0,0 +1,39 @@
+use crate::kernel::running_kernel;
+
+/// How to get the swaphints kernel under WSL2, where the kernel comes from Windows and
+/// there is no grub or package managed kernel to install.
+pub const KERNEL_HELP: &str = "\
+WSL2 boots a kernel supplied by Windows, the bitflux kernel can't be installed from inside the distro.
+To use it:
+  1. Copy the bitflux WSL2 kernel image (bzImage) to a Windows path, e.g. C:\\bitflux\\bzImage
+  2. Add to %UserProfile%\\.wslconfig:
+       [wsl2]
+       kernel=C:\\\\bitflux\\\\bzImage
+  3. Run `wsl --shutdown` from Windows and start the distro again.
+See https://wiki.bitflux.ai for details.";
+
+/// True if `osrelease` is a WSL2 kernel ("5.15.90.1-microsoft-standard-WSL2").  WSL1 reports
+/// "4.4.0-19041-Microsoft" and has no Linux kernel at all, it isn't supported.
+pub fn is_wsl2_release(osrelease: &str) -> bool {
+    let release = osrelease.to_lowercase();
+    release.contains("microsoft") && (release.contains("wsl2") || release.contains("microsoft-standard"))
+}
+
+pub fn is_wsl2() -> bool {
+    running_kernel().map(|r| is_wsl2_release(&r)).unwrap_or(false)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detect_wsl2_release() {
+        assert!(is_wsl2_release("5.15.90.1-microsoft-standard-WSL2"));
+        assert!(is_wsl2_release("5.10.16.3-microsoft-standard"));
+        assert!(!is_wsl2_release("4.4.0-19041-Microsoft"));
+        assert!(!is_wsl2_release("5.15.0-91-generic"));
+    }
+
+}
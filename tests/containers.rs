@@ -0,0 +1,82 @@
+//! End-to-end installs in containers, see tests/support.  They need podman or docker, network
+//! access to the package mirrors and a few minutes per distro, so they only run on request:
+//!
+//! ```bash
+//! cargo test --test containers -- --ignored
+//! ```
+
+mod support;
+
+use std::sync::atomic::Ordering;
+
+use support::{Container, MockLicenseServer, INSTALLER};
+
+/// Installs the agent twice, the second run must succeed and change nothing, then uninstalls and
+/// checks nothing of bitflux is left behind.  Containers can't boot another kernel, so only the
+/// agent profile is exercised.
+fn install_verify_uninstall(distro: &str) {
+    let license = MockLicenseServer::start();
+    let container = Container::start(distro);
+    let url = format!("{}/activate", license.url);
+    let env = [("BITFLUX_LICENSE_URL", url.as_str())];
+    let install = [INSTALLER, "install", "--profile", "agent", "--license-key", "BFX-TEST-0000-0001"];
+
+    container.must(&env, &install);
+    let receipt = container.must(&[], &["cat", "/var/lib/bitflux/receipt.json"]);
+    container.must(&[], &["systemctl", "is-enabled", "bitfluxcollector"]);
+    assert_eq!(license.activations.load(Ordering::SeqCst), 1, "{}: the install didn't activate the license", distro);
+
+    container.must(&env, &install);
+    container.must(&[], &[INSTALLER, "verify"]);
+    let again = container.must(&[], &["cat", "/var/lib/bitflux/receipt.json"]);
+    assert_eq!(receipt, again, "{}: second install changed the receipt", distro);
+    assert_eq!(license.activations.load(Ordering::SeqCst), 1, "{}: second install activated the license again", distro);
+
+    container.must(&env, &[INSTALLER, "uninstall"]);
+    assert!(!container.exec(&["systemctl", "cat", "bitfluxcollector"]).status.success(), "{}: service left behind", distro);
+    for path in ["/opt/bitflux", "/etc/systemd/system/bitfluxcollector.service.d", "/var/lib/bitflux/receipt.json", "/etc/bitflux/license"] {
+        assert!(!container.exists(path), "{}: {} left behind", distro, path);
+    }
+}
+
+#[test]
+#[ignore]
+fn ubuntu_22_04() {
+    install_verify_uninstall("ubuntu-22.04");
+}
+
+#[test]
+#[ignore]
+fn ubuntu_24_04() {
+    install_verify_uninstall("ubuntu-24.04");
+}
+
+#[test]
+#[ignore]
+fn debian_12() {
+    install_verify_uninstall("debian-12");
+}
+
+#[test]
+#[ignore]
+fn rocky_9() {
+    install_verify_uninstall("rocky-9");
+}
+
+#[test]
+#[ignore]
+fn alma_8() {
+    install_verify_uninstall("alma-8");
+}
+
+#[test]
+#[ignore]
+fn amazon_linux_2023() {
+    install_verify_uninstall("amzn-2023");
+}
+
+#[test]
+#[ignore]
+fn opensuse_leap_15() {
+    install_verify_uninstall("leap-15");
+}
@@ -0,0 +1,182 @@
+//! Golden install scripts: every directory under tests/golden describes a host (os-release plus
+//! fixture.json with the kernel, architecture and install answers) and holds the install.sh
+//! the install's steps make for it, applied as a dry run the way `install --emit-script` does.
+//! A change that alters what gets installed on some distro shows up here as a diff.  After
+//! reviewing an intended change, rewrite the goldens with:
+//!
+//! ```bash
+//! BITFLUX_UPDATE_GOLDEN=1 cargo test --test golden
+//! ```
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+use installer::executor::MockExecutor;
+use installer::install::{self, Options};
+use installer::plan::{answers_fixture, drift, host_fixture, Change, EmptyState, Plan, State};
+use installer::runcmd;
+
+/// The dry run and the script it records are the process's, one fixture at a time.
+static DRY_RUN: Mutex<()> = Mutex::new(());
+
+fn cases() -> Vec<PathBuf> {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+    let mut cases: Vec<PathBuf> = fs::read_dir(&root).expect("tests/golden is missing")
+        .map(|e| e.unwrap().path())
+        .filter(|p| p.is_dir())
+        .collect();
+    cases.sort();
+    cases
+}
+
+/// The install answers of the fixture in `dir`.  A bundle in them is a directory next to
+/// fixture.json.
+fn answers(dir: &Path) -> Options {
+    let mut answers = answers_fixture(dir).unwrap_or_else(|e| panic!("{}: bad answers: {}", dir.display(), e));
+    answers.bundle = answers.bundle.map(|bundle| dir.join(bundle));
+    // One made up would be the build host's hostname and machine id.
+    answers.device_id.get_or_insert_with(|| String::from("golden-host"));
+    answers
+}
+
+/// The plan for the fixture in `dir`, made the way the goldens are.
+fn plan(dir: &Path) -> io::Result<Plan> {
+    let platform = host_fixture(dir)?.platform()?;
+    let _dry_run = DRY_RUN.lock().unwrap_or_else(|e| e.into_inner());
+    runcmd::init();
+    install::plan_for(&answers(dir), &platform, Arc::new(MockExecutor::new()))
+}
+
+/// The script for the fixture in `dir`, or the error the install would stop with.
+fn render(dir: &Path) -> String {
+    let host = host_fixture(dir).unwrap_or_else(|e| panic!("{}: bad fixture: {}", dir.display(), e));
+    let answers = answers(dir);
+    let _dry_run = DRY_RUN.lock().unwrap_or_else(|e| e.into_inner());
+    runcmd::init();
+    let mock = Arc::new(MockExecutor::new());
+    let script = host.platform().and_then(|platform| install::script_for(&answers, &platform, mock.clone()));
+    assert!(mock.commands().is_empty(), "{}: the dry run ran {:?}", dir.display(), mock.commands());
+    let script = match script {
+        Ok(script) => script,
+        Err(e) => format!("error: {}\n", e),
+    };
+    script.replace(&dir.to_string_lossy().into_owned(), "<fixture>")
+        .replace(&format!("--emit-script` {}:", env!("CARGO_PKG_VERSION")), "--emit-script` <version>:")
+}
+
+#[test]
+fn scripts_match_golden() {
+    let update = env::var_os("BITFLUX_UPDATE_GOLDEN").is_some();
+    let mut mismatched = Vec::new();
+    for dir in cases() {
+        let script = render(&dir);
+        let golden = dir.join("install.sh");
+        if update {
+            fs::write(&golden, &script).unwrap();
+            continue;
+        }
+        let expected = fs::read_to_string(&golden).unwrap_or_default();
+        if script != expected {
+            eprintln!("--- {}\n{}", golden.display(), script);
+            mismatched.push(dir.file_name().unwrap().to_string_lossy().into_owned());
+        }
+    }
+    assert!(mismatched.is_empty(), "scripts differ from the goldens for {}, see above", mismatched.join(", "));
+}
+
+#[test]
+fn scripts_are_deterministic() {
+    for dir in cases() {
+        assert_eq!(render(&dir), render(&dir), "{}", dir.display());
+    }
+}
+
+/// A host with the packages installed, the service enabled and the apt source in place.
+struct Installed;
+
+impl State for Installed {
+
+    fn file(&self, path: &str) -> Option<String> {
+        let source = "deb [arch=amd64 signed-by=/usr/share/keyrings/bitflux-archive-keyring.gpg] https://mirror.bitflux.ai/repository/ubuntu jammy main\n";
+        (path == "/etc/apt/sources.list.d/bitflux.list").then(|| String::from(source))
+    }
+
+    fn installed(&self, _package: &str) -> bool {
+        true
+    }
+
+    fn enabled(&self, _unit: &str) -> bool {
+        true
+    }
+
+}
+
+#[test]
+fn machine_plans_have_stable_ids() {
+    for dir in cases() {
+        let answers = answers(&dir);
+        let Ok(mut plan) = plan(&dir) else {
+            continue;
+        };
+        let machine = plan.machine(&answers, &EmptyState);
+        let mut ids: Vec<&str> = machine.actions.iter().map(|a| a.id.as_str()).collect();
+        ids.sort();
+        ids.dedup();
+        assert_eq!(ids.len(), machine.actions.len(), "{}: duplicate action ids", dir.display());
+
+        // An action more up front moves none of the others' ids.
+        plan.steps[0].actions.insert(0, String::from("true"));
+        let moved = plan.machine(&answers, &EmptyState);
+        assert!(machine.actions.iter().all(|a| moved.actions.iter().any(|m| m.id == a.id)), "{}: ids depend on position", dir.display());
+    }
+
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden/ubuntu-22.04");
+    let plan = plan(&dir).unwrap();
+    let fresh = plan.machine(&Default::default(), &EmptyState);
+    let ids: Vec<(&str, Change)> = fresh.actions.iter().map(|a| (a.id.as_str(), a.change)).collect();
+    assert!(ids.contains(&("repository/file:/etc/apt/sources.list.d/bitflux.list", Change::Create)));
+    assert!(ids.contains(&("repository/absent:/etc/apt/sources.list.d/bitflux.sources", Change::NoOp)));
+    assert!(ids.contains(&("repository/run:apt-get update", Change::Run)));
+    assert!(ids.contains(&("kernel/package:linux-image-swaphints", Change::Create)));
+    assert!(ids.contains(&("service/service:bitfluxcollector", Change::Create)));
+    assert_eq!(fresh.changes, 7);
+    assert_eq!(plan.machine(&Default::default(), &Installed).changes, 3);
+    // The engine's own steps, those without anything to script too.
+    let names: Vec<&str> = plan.steps.iter().map(|s| s.name.as_str()).collect();
+    assert_eq!(names, ["repository", "kernel", "agent", "account", "mac", "service", "health", "receipt"]);
+}
+
+#[test]
+fn drift_is_by_step() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden/ubuntu-22.04");
+    let plan = plan(&dir).unwrap();
+    let checked = |pending: &[(&str, bool)]| {
+        let mut machine = plan.machine(&Default::default(), &EmptyState);
+        machine.steps = pending.iter().map(|(name, pending)| (name.to_string(), *pending)).collect();
+        machine
+    };
+    let saved = checked(&[("repository", true), ("kernel", true), ("agent", true), ("service", true)]);
+    assert!(drift(&saved, &saved).is_empty());
+    // The actions are the plan's, only what the engine's check found counts.
+    let mut reworded = saved.clone();
+    reworded.actions.clear();
+    assert!(drift(&saved, &reworded).is_empty());
+    let current = checked(&[("repository", false), ("kernel", true), ("agent", true), ("service", true), ("mac", true)]);
+    assert_eq!(drift(&saved, &current), ["mac", "repository"]);
+}
+
+#[test]
+fn summary_lists_the_changes() {
+    let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden/ubuntu-22.04");
+    let plan = plan(&dir).unwrap();
+    assert_eq!(plan.machine(&Default::default(), &EmptyState).summary(), "\
+Packages to install: linux-image-swaphints, bitfluxcollector
+Files to create: /usr/share/keyrings/bitflux-archive-keyring.gpg, /etc/apt/sources.list.d/bitflux.list, /etc/systemd/system/bitfluxcollector.service.d/hardening.conf, /opt/bitflux/config/bitflux/bitfluxcollector.conf
+Services to enable: bitfluxcollector
+Steps: repository, kernel, agent, account, service, receipt
+");
+    assert!(!plan.machine(&Default::default(), &Installed).summary().contains("Packages to install"));
+}
@@ -0,0 +1,55 @@
+#!/bin/bash
+# bitflux agent-kernel on AlmaLinux 8.9 (Midnight Oncilla) (x86_64)
+# Written by `installer install --emit-script` <version>: every command the install runs, by
+# step. The checks for what's already done are left out. Review it, then run it as root.
+set -euo pipefail
+[ "$(id -u)" -eq 0 ] || { echo "Run this script as root." >&2; exit 1; }
+
+# Step 1/6: Installing the bundled packages
+# Not scripted, the installer does this itself: write /var/lib/bitflux/kernel.json
+rpm -Uvh --replacepkgs <fixture>/bundle/kernel-swaphints.rpm
+rpm -Uvh --replacepkgs <fixture>/bundle/bitfluxcollector.rpm
+
+# Step 2/6: Creating the bitflux user
+# Not scripted, the installer does this itself: create the bitflux system user and group, add it to systemd-journal and chown /opt/bitflux/data to it
+
+# Step 3/6: Installing the SELinux or AppArmor policy
+
+# Step 4/6: Configuring the agent service
+cat > /etc/systemd/system/bitfluxcollector.service.d/hardening.conf <<'BITFLUX_EOF'
+# Generated by the bitflux installer for the agent-kernel profile, do not edit.
+[Service]
+User=bitflux
+Group=bitflux
+NoNewPrivileges=yes
+CapabilityBoundingSet=CAP_SYS_ADMIN CAP_SYS_PTRACE CAP_DAC_READ_SEARCH
+AmbientCapabilities=CAP_SYS_ADMIN CAP_SYS_PTRACE CAP_DAC_READ_SEARCH
+ProtectSystem=strict
+ReadWritePaths=/opt/bitflux
+ProtectHome=read-only
+PrivateTmp=yes
+PrivateDevices=yes
+ProtectKernelTunables=no
+ProtectKernelModules=yes
+ProtectControlGroups=yes
+ProtectClock=yes
+ProtectHostname=yes
+RestrictAddressFamilies=AF_UNIX AF_INET AF_INET6
+RestrictNamespaces=yes
+RestrictRealtime=yes
+RestrictSUIDSGID=yes
+LockPersonality=yes
+SystemCallArchitectures=native
+MemoryDenyWriteExecute=yes
+BITFLUX_EOF
+chown 0:0 /etc/systemd/system/bitfluxcollector.service.d/hardening.conf && chmod 644 /etc/systemd/system/bitfluxcollector.service.d/hardening.conf
+cat > /opt/bitflux/config/bitflux/bitfluxcollector.conf <<'BITFLUX_EOF'
+deviceid=golden-host
+BITFLUX_EOF
+chown 0:0 /opt/bitflux/config/bitflux/bitfluxcollector.conf && chmod 640 /opt/bitflux/config/bitflux/bitfluxcollector.conf
+systemctl enable --now bitfluxcollector
+
+# Step 5/6: Waiting for the agent to become healthy
+
+# Step 6/6: Writing the install receipt
+# Not scripted, the installer does this itself: record the packages, service and permissions and sign the receipt in /var/lib/bitflux
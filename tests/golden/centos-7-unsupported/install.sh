@@ -0,0 +1 @@
+error: CentOS Linux 7 (Core) is not supported, bitflux needs Enterprise Linux 8 or 9.
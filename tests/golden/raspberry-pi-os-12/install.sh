@@ -0,0 +1,80 @@
+#!/bin/bash
+# bitflux agent-kernel on Debian GNU/Linux 12 (bookworm) (aarch64)
+# Written by `installer install --emit-script` <version>: every command the install runs, by
+# step. The checks for what's already done are left out. Review it, then run it as root.
+set -euo pipefail
+[ "$(id -u)" -eq 0 ] || { echo "Run this script as root." >&2; exit 1; }
+
+# Step 1/9: Setting up the bitflux repository
+mkdir -p /usr/share/keyrings
+curl -fsSL --proto '=https' --tlsv1.2 -o /usr/share/keyrings/bitflux-archive-keyring.gpg.download https://mirror.bitflux.ai/repository/keys/bitflux-archive-keyring.gpg
+[ "$(gpg --batch --with-colons --show-keys /usr/share/keyrings/bitflux-archive-keyring.gpg.download | awk -F: '$1 == "pub" { p = 1 } $1 == "fpr" && p { print $10; p = 0 }')" = 5E3C7A4F0B9D2E61C8A7F41D93B0E6C2A1F58D47 ]
+mv /usr/share/keyrings/bitflux-archive-keyring.gpg.download /usr/share/keyrings/bitflux-archive-keyring.gpg
+chown 0:0 /usr/share/keyrings/bitflux-archive-keyring.gpg && chmod 644 /usr/share/keyrings/bitflux-archive-keyring.gpg
+cat > /etc/apt/sources.list.d/bitflux.list <<'BITFLUX_EOF'
+deb [arch=arm64 signed-by=/usr/share/keyrings/bitflux-archive-keyring.gpg] https://mirror.bitflux.ai/repository/debian bookworm main
+BITFLUX_EOF
+rm -f /etc/apt/sources.list.d/bitflux.sources
+apt-get update
+
+# Step 2/9: Installing the bitflux kernel
+# Not scripted, the installer does this itself: write /var/lib/bitflux/kernel.json
+apt-mark manual linux-image-6.6.20+rpt-rpi-v8
+apt-get install -y linux-image-swaphints-rpi
+
+# Step 3/9: Installing the agent
+env DEBIAN_FRONTEND=noninteractive apt-get install -y bitfluxcollector
+
+# Step 4/9: Tuning memory for the board
+lsattr -d /etc/sysctl.d
+cat > /etc/sysctl.d/60-bitflux-sbc.conf <<'BITFLUX_EOF'
+# Written by the bitflux installer for single-board computers.
+vm.page-cluster = 0
+vm.swappiness = 60
+BITFLUX_EOF
+chown 0:0 /etc/sysctl.d/60-bitflux-sbc.conf && chmod 644 /etc/sysctl.d/60-bitflux-sbc.conf
+sysctl -p /etc/sysctl.d/60-bitflux-sbc.conf
+
+# Step 5/9: Creating the bitflux user
+# Not scripted, the installer does this itself: create the bitflux system user and group, add it to systemd-journal and chown /opt/bitflux/data to it
+
+# Step 6/9: Installing the SELinux or AppArmor policy
+
+# Step 7/9: Configuring the agent service
+cat > /etc/systemd/system/bitfluxcollector.service.d/hardening.conf <<'BITFLUX_EOF'
+# Generated by the bitflux installer for the agent-kernel profile, do not edit.
+[Service]
+User=bitflux
+Group=bitflux
+NoNewPrivileges=yes
+CapabilityBoundingSet=CAP_SYS_ADMIN CAP_SYS_PTRACE CAP_DAC_READ_SEARCH
+AmbientCapabilities=CAP_SYS_ADMIN CAP_SYS_PTRACE CAP_DAC_READ_SEARCH
+ProtectSystem=strict
+ReadWritePaths=/opt/bitflux
+ProtectHome=read-only
+PrivateTmp=yes
+PrivateDevices=yes
+ProtectKernelTunables=no
+ProtectKernelModules=yes
+ProtectControlGroups=yes
+ProtectClock=yes
+ProtectHostname=yes
+RestrictAddressFamilies=AF_UNIX AF_INET AF_INET6
+RestrictNamespaces=yes
+RestrictRealtime=yes
+RestrictSUIDSGID=yes
+LockPersonality=yes
+SystemCallArchitectures=native
+MemoryDenyWriteExecute=yes
+BITFLUX_EOF
+chown 0:0 /etc/systemd/system/bitfluxcollector.service.d/hardening.conf && chmod 644 /etc/systemd/system/bitfluxcollector.service.d/hardening.conf
+cat > /opt/bitflux/config/bitflux/bitfluxcollector.conf <<'BITFLUX_EOF'
+deviceid=golden-host
+BITFLUX_EOF
+chown 0:0 /opt/bitflux/config/bitflux/bitfluxcollector.conf && chmod 640 /opt/bitflux/config/bitflux/bitfluxcollector.conf
+systemctl enable --now bitfluxcollector
+
+# Step 8/9: Waiting for the agent to become healthy
+
+# Step 9/9: Writing the install receipt
+# Not scripted, the installer does this itself: record the packages, service and permissions and sign the receipt in /var/lib/bitflux
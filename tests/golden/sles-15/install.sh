@@ -0,0 +1,77 @@
+#!/bin/bash
+# bitflux agent-kernel on SUSE Linux Enterprise Server 15 SP5 (x86_64)
+# Written by `installer install --emit-script` <version>: every command the install runs, by
+# step. The checks for what's already done are left out. Review it, then run it as root.
+set -euo pipefail
+[ "$(id -u)" -eq 0 ] || { echo "Run this script as root." >&2; exit 1; }
+
+# Step 1/8: Setting up the bitflux repository
+mkdir -p /etc/pki/rpm-gpg
+curl -fsSL --proto '=https' --tlsv1.2 -o /etc/pki/rpm-gpg/RPM-GPG-KEY-bitflux.download https://mirror.bitflux.ai/repository/keys/RPM-GPG-KEY-bitflux
+[ "$(gpg --batch --with-colons --show-keys /etc/pki/rpm-gpg/RPM-GPG-KEY-bitflux.download | awk -F: '$1 == "pub" { p = 1 } $1 == "fpr" && p { print $10; p = 0 }')" = 5E3C7A4F0B9D2E61C8A7F41D93B0E6C2A1F58D47 ]
+mv /etc/pki/rpm-gpg/RPM-GPG-KEY-bitflux.download /etc/pki/rpm-gpg/RPM-GPG-KEY-bitflux
+chown 0:0 /etc/pki/rpm-gpg/RPM-GPG-KEY-bitflux && chmod 644 /etc/pki/rpm-gpg/RPM-GPG-KEY-bitflux
+rpm --import /etc/pki/rpm-gpg/RPM-GPG-KEY-bitflux
+cat > /etc/zypp/repos.d/bitflux.repo <<'BITFLUX_EOF'
+[bitflux]
+name=BitFlux for sle15 - x86_64
+baseurl=https://mirror.bitflux.ai/repository/sle15/x86_64
+enabled=1
+gpgcheck=1
+repo_gpgcheck=1
+gpgkey=file:///etc/pki/rpm-gpg/RPM-GPG-KEY-bitflux
+BITFLUX_EOF
+chown 0:0 /etc/zypp/repos.d/bitflux.repo && chmod 644 /etc/zypp/repos.d/bitflux.repo
+zypper --non-interactive --gpg-auto-import-keys refresh
+
+# Step 2/8: Installing the bitflux kernel
+zypper --non-interactive --quiet info kernel-swaphints
+# Not scripted, the installer does this itself: write /var/lib/bitflux/kernel.json
+zypper --non-interactive install kernel-swaphints
+
+# Step 3/8: Installing the agent
+zypper --non-interactive install bitfluxcollector
+
+# Step 4/8: Creating the bitflux user
+# Not scripted, the installer does this itself: create the bitflux system user and group, add it to systemd-journal and chown /opt/bitflux/data to it
+
+# Step 5/8: Installing the SELinux or AppArmor policy
+
+# Step 6/8: Configuring the agent service
+cat > /etc/systemd/system/bitfluxcollector.service.d/hardening.conf <<'BITFLUX_EOF'
+# Generated by the bitflux installer for the agent-kernel profile, do not edit.
+[Service]
+User=bitflux
+Group=bitflux
+NoNewPrivileges=yes
+CapabilityBoundingSet=CAP_SYS_ADMIN CAP_SYS_PTRACE CAP_DAC_READ_SEARCH
+AmbientCapabilities=CAP_SYS_ADMIN CAP_SYS_PTRACE CAP_DAC_READ_SEARCH
+ProtectSystem=strict
+ReadWritePaths=/opt/bitflux
+ProtectHome=read-only
+PrivateTmp=yes
+PrivateDevices=yes
+ProtectKernelTunables=no
+ProtectKernelModules=yes
+ProtectControlGroups=yes
+ProtectClock=yes
+ProtectHostname=yes
+RestrictAddressFamilies=AF_UNIX AF_INET AF_INET6
+RestrictNamespaces=yes
+RestrictRealtime=yes
+RestrictSUIDSGID=yes
+LockPersonality=yes
+SystemCallArchitectures=native
+MemoryDenyWriteExecute=yes
+BITFLUX_EOF
+chown 0:0 /etc/systemd/system/bitfluxcollector.service.d/hardening.conf && chmod 644 /etc/systemd/system/bitfluxcollector.service.d/hardening.conf
+cat > /opt/bitflux/config/bitflux/bitfluxcollector.conf <<'BITFLUX_EOF'
+deviceid=golden-host
+BITFLUX_EOF
+chown 0:0 /opt/bitflux/config/bitflux/bitfluxcollector.conf && chmod 640 /opt/bitflux/config/bitflux/bitfluxcollector.conf
+systemctl enable --now bitfluxcollector
+
+# Step 7/8: Waiting for the agent to become healthy
+
+# Step 8/8: Writing the install receipt
+# Not scripted, the installer does this itself: record the packages, service and permissions and sign the receipt in /var/lib/bitflux
@@ -0,0 +1,169 @@
+//! Runs the installer inside throwaway systemd containers, one per supported distro.
+//!
+//! The images are built from tests/containers/<distro>/Containerfile with podman, or docker when
+//! podman isn't installed.  Containers run privileged so systemd can boot as PID 1.  The
+//! installer binary is mounted in read-only: BITFLUX_TEST_INSTALLER if set, otherwise the one
+//! cargo built for the test, which only runs in images with a glibc at least as new as the
+//! host's.  Use `cargo build-static` and point BITFLUX_TEST_INSTALLER at it for the old ones.
+
+use std::env;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Where the installer is mounted inside the containers.
+pub const INSTALLER: &str = "/usr/local/bin/installer";
+
+/// podman or docker, whichever is installed.
+pub fn runtime() -> Option<&'static str> {
+    ["podman", "docker"].into_iter().find(|rt| {
+        Command::new(rt).arg("--version").output().is_ok_and(|o| o.status.success())
+    })
+}
+
+fn installer_binary() -> PathBuf {
+    match env::var_os("BITFLUX_TEST_INSTALLER") {
+        Some(path) => PathBuf::from(path),
+        None => PathBuf::from(env!("CARGO_BIN_EXE_installer")),
+    }
+}
+
+fn run(rt: &str, args: &[&str]) -> Output {
+    let out = Command::new(rt).args(args).output().unwrap_or_else(|e| panic!("can't run {}: {}", rt, e));
+    if !out.status.success() {
+        panic!("{} {} failed:\n{}", rt, args.join(" "), String::from_utf8_lossy(&out.stderr));
+    }
+    out
+}
+
+/// A running container, removed on drop.
+pub struct Container {
+    rt: &'static str,
+    id: String,
+}
+
+impl Container {
+
+    /// Builds the image for `distro` and boots it.
+    pub fn start(distro: &str) -> Container {
+        let rt = runtime().expect("integration tests need podman or docker");
+        let context = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/containers").join(distro);
+        let image = format!("bitflux-installer-test-{}", distro);
+        run(rt, &["build", "-t", &image, &context.to_string_lossy()]);
+
+        let mount = format!("{}:{}:ro", installer_binary().display(), INSTALLER);
+        // Host networking so the containers reach the mock servers on the host's loopback.
+        let out = run(rt, &["run", "-d", "--rm", "--privileged", "--network", "host", "-v", &mount, &image]);
+        let container = Container { rt, id: String::from_utf8_lossy(&out.stdout).trim().to_string() };
+        container.wait_for_systemd();
+        container
+    }
+
+    fn wait_for_systemd(&self) {
+        for _ in 0..60 {
+            let state = self.exec(&["systemctl", "is-system-running"]);
+            let state = String::from_utf8_lossy(&state.stdout);
+            if state.starts_with("running") || state.starts_with("degraded") {
+                return;
+            }
+            thread::sleep(Duration::from_secs(1));
+        }
+        panic!("systemd didn't come up in container {}", self.id);
+    }
+
+    /// Runs `args` in the container, whatever the outcome.
+    pub fn exec(&self, args: &[&str]) -> Output {
+        Command::new(self.rt).args([&["exec", &self.id], args].concat()).output().expect("container exec failed")
+    }
+
+    /// Runs `args` in the container with `envs` set, panicking on failure.
+    pub fn must(&self, envs: &[(&str, &str)], args: &[&str]) -> String {
+        let env_args: Vec<String> = envs.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+        let mut argv: Vec<&str> = vec!["exec"];
+        for env in &env_args {
+            argv.extend(["-e", env.as_str()]);
+        }
+        argv.push(&self.id);
+        argv.extend_from_slice(args);
+        let out = Command::new(self.rt).args(&argv).output().expect("container exec failed");
+        let stdout = String::from_utf8_lossy(&out.stdout).into_owned();
+        if !out.status.success() {
+            panic!("'{}' failed in {}:\n{}\n{}", args.join(" "), self.id, stdout, String::from_utf8_lossy(&out.stderr));
+        }
+        stdout
+    }
+
+    /// True if `path` exists in the container.
+    pub fn exists(&self, path: &str) -> bool {
+        self.exec(&["test", "-e", path]).status.success()
+    }
+
+}
+
+impl Drop for Container {
+
+    fn drop(&mut self) {
+        let _ = Command::new(self.rt).args(["rm", "-f", &self.id]).output();
+    }
+
+}
+
+/// Stands in for the BitFlux licensing backend: every activation succeeds with a fixed token.
+pub struct MockLicenseServer {
+    pub url: String,
+    pub activations: Arc<AtomicUsize>,
+}
+
+pub const MOCK_TOKEN: &str = "mock-activation-token";
+
+impl MockLicenseServer {
+
+    pub fn start() -> MockLicenseServer {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("can't bind the mock license server");
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let activations = Arc::new(AtomicUsize::new(0));
+        let counter = activations.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                respond(stream, &counter);
+            }
+        });
+        MockLicenseServer { url, activations }
+    }
+
+}
+
+fn respond(mut stream: TcpStream, activations: &AtomicUsize) {
+    let mut reader = BufReader::new(stream.try_clone().unwrap());
+    let mut request = String::new();
+    if reader.read_line(&mut request).is_err() {
+        return;
+    }
+    let mut length = 0;
+    loop {
+        let mut header = String::new();
+        if reader.read_line(&mut header).is_err() || header.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("content-length") {
+                length = value.trim().parse().unwrap_or(0);
+            }
+        }
+    }
+    let mut body = vec![0; length];
+    let _ = reader.read_exact(&mut body);
+
+    let (status, reply) = if request.starts_with("POST /activate") {
+        activations.fetch_add(1, Ordering::SeqCst);
+        ("200 OK", format!(r#"{{"status": "active", "token": "{}"}}"#, MOCK_TOKEN))
+    } else {
+        ("404 Not Found", String::from(r#"{"status": "unknown endpoint"}"#))
+    };
+    let _ = write!(stream, "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", status, reply.len(), reply);
+}